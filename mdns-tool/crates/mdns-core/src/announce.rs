@@ -1,50 +1,109 @@
 use crate::model::{ServiceAnnouncement, TxtRecord};
 use anyhow::Result;
 use mdns_sd::{ServiceDaemon, ServiceInfo};
+use std::net::IpAddr;
 
 /// Handle so the service stays registered while this is alive.
 pub struct Announcer {
-    _daemon: ServiceDaemon,  // Keep daemon alive
-    fullname: String,
+    daemon: ServiceDaemon,  // Keep daemon alive
+    anns: Vec<ServiceAnnouncement>,
+    fullnames: Vec<String>,
 }
 
 impl Announcer {
     pub fn register(ann: ServiceAnnouncement) -> Result<Self> {
+        Self::register_types(ann, &[])
+    }
+
+    /// Register `ann` under its own service type plus each of `extra_types`
+    /// (e.g. `_opentab._tcp`), sharing one daemon. The primary type is
+    /// always registered first.
+    pub fn register_types(ann: ServiceAnnouncement, extra_types: &[String]) -> Result<Self> {
         let daemon = ServiceDaemon::new()?;
 
-        let txt_kv = ann
-            .txt
-            .unwrap_or(TxtRecord(vec![]))
-            .0
-            .into_iter()
-            .map(|(k, v)| (k, v))
-            .collect::<Vec<_>>();
-
-        // Ensure trailing dots as mdns-sd expects FQDNs.
-        let service_type = ensure_dot(&ann.service_type);
-        let host_name = ensure_dot(&ann.host_name);
-
-        let info = ServiceInfo::new(
-            &service_type,
-            &ann.instance_name,
-            &host_name,
-            &ann.ip_addr,
-            ann.port,
-            &*txt_kv,
-        )?;
-
-        daemon.register(info.clone())?;
-        Ok(Self {
-            _daemon: daemon,
-            fullname: info.get_fullname().to_string(),
-        })
+        let primary = normalize_service_type(&ann.service_type);
+        let mut anns = vec![ann.clone()];
+        for ty in extra_types {
+            let ty = normalize_service_type(ty);
+            if ty != primary && !anns.iter().any(|a| normalize_service_type(&a.service_type) == ty) {
+                anns.push(ServiceAnnouncement { service_type: ty, ..ann.clone() });
+            }
+        }
+
+        let mut fullnames = Vec::with_capacity(anns.len());
+        for a in &anns {
+            let info = build_info(a)?;
+            daemon.register(info.clone())?;
+            fullnames.push(info.get_fullname().to_string());
+        }
+
+        Ok(Self { daemon, anns, fullnames })
+    }
+
+    /// Withdraw the current records and register them again on
+    /// `addresses`. Used when the announced interfaces change address.
+    pub fn reregister(&mut self, addresses: &[IpAddr]) -> Result<()> {
+        for (ann, fullname) in self.anns.iter_mut().zip(self.fullnames.iter_mut()) {
+            // Best effort: the old record may already be gone with the interface.
+            let _ = self.daemon.unregister(fullname);
+
+            ann.addresses = addresses.to_vec();
+            let info = build_info(ann)?;
+            self.daemon.register(info.clone())?;
+            *fullname = info.get_fullname().to_string();
+        }
+        Ok(())
     }
 
+    /// Full name of the record under the primary service type.
     pub fn fullname(&self) -> &str {
-        &self.fullname
+        &self.fullnames[0]
+    }
+
+    /// Full names of all registered records, primary first.
+    pub fn fullnames(&self) -> &[String] {
+        &self.fullnames
+    }
+
+    /// Addresses currently being advertised.
+    pub fn addresses(&self) -> &[IpAddr] {
+        &self.anns[0].addresses
     }
 }
 
+/// Expand a short service type such as `_opentab._tcp` to the fully
+/// qualified `_opentab._tcp.local.` form.
+pub fn normalize_service_type(s: &str) -> String {
+    let s = s.trim_end_matches('.');
+    if s.ends_with(".local") {
+        format!("{}.", s)
+    } else {
+        format!("{}.local.", s)
+    }
+}
+
+fn build_info(ann: &ServiceAnnouncement) -> Result<ServiceInfo> {
+    let txt_kv = ann
+        .txt
+        .clone()
+        .unwrap_or(TxtRecord(vec![]))
+        .0;
+
+    // Ensure trailing dots as mdns-sd expects FQDNs.
+    let service_type = ensure_dot(&ann.service_type);
+    let host_name = ensure_dot(&ann.host_name);
+
+    let info = ServiceInfo::new(
+        &service_type,
+        &ann.instance_name,
+        &host_name,
+        &ann.addresses[..],
+        ann.port,
+        &*txt_kv,
+    )?;
+    Ok(info)
+}
+
 fn ensure_dot(s: &str) -> String {
     if s.ends_with('.') {
         s.to_string()
@@ -58,6 +117,13 @@ mod tests {
     use super::*;
     #[test]
     fn test_ensure_dot() {
-        assert_eq!(ensure_dot("test_case").contains("."), true );
+        assert!(ensure_dot("test_case").contains('.'));
+    }
+
+    #[test]
+    fn test_normalize_service_type() {
+        assert_eq!(normalize_service_type("_opentab._tcp"), "_opentab._tcp.local.");
+        assert_eq!(normalize_service_type("_openshare._tcp.local."), "_openshare._tcp.local.");
+        assert_eq!(normalize_service_type("_openshare._tcp.local"), "_openshare._tcp.local.");
     }
 }
\ No newline at end of file
@@ -1,15 +1,12 @@
-use crate::model::DiscoveredService;
+use crate::model::{BrowseEvent, DiscoveredService};
 use anyhow::Result;
-use mdns_sd::{ServiceDaemon, ServiceEvent};
-use std::time::Duration;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 pub fn browse_blocking(service_type: &str, timeout: Duration, _interface: &str) -> Result<Vec<DiscoveredService>> {
     let daemon = ServiceDaemon::new()?;
-    let service_type = if service_type.ends_with('.') {
-        service_type.to_string()
-    } else {
-        format!("{}.", service_type)
-    };
+    let service_type = fully_qualified(service_type);
 
     let receiver = daemon.browse(&service_type)?;
     let mut out = Vec::new();
@@ -19,21 +16,7 @@ pub fn browse_blocking(service_type: &str, timeout: Duration, _interface: &str)
         if let Ok(event) = receiver.recv_timeout(Duration::from_millis(2000)) {
             match event {
                 ServiceEvent::ServiceResolved(info) => {
-                    let txt = info
-                        .get_properties()
-                        .iter()
-                        .map(|prop| (prop.key().to_string(), prop.val_str().to_string()))
-                        .collect::<Vec<_>>();
-
-                    out.push(DiscoveredService {
-                        fullname: info.get_fullname().to_string(),
-                        instance_name: info.get_hostname().to_string(),
-                        service_type: service_type.clone(),
-                        host_name: info.get_hostname().to_string(),
-                        port: info.get_port(),
-                        addresses: info.get_addresses().iter().copied().collect(),
-                        txt,
-                    });
+                    out.push(discovered(&info, &service_type));
                 }
                 ServiceEvent::ServiceFound(service_name, full_name) => {
                     println!("Found service with name of {} and type of {}", full_name, service_name);
@@ -43,4 +26,120 @@ pub fn browse_blocking(service_type: &str, timeout: Duration, _interface: &str)
         }
     }
     Ok(out)
-}
\ No newline at end of file
+}
+
+/// Browse several service types concurrently and merge the results. A
+/// record resolved more than once (e.g. as its IPv6 address arrives after
+/// the IPv4 one) is reported once, with the addresses of each.
+pub fn browse_many_blocking(service_types: &[String], timeout: Duration, interface: &str) -> Result<Vec<DiscoveredService>> {
+    let results = std::thread::scope(|scope| {
+        let handles: Vec<_> = service_types
+            .iter()
+            .map(|ty| scope.spawn(move || browse_blocking(ty, timeout, interface)))
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap_or_else(|_| Err(anyhow::anyhow!("browse thread panicked"))))
+            .collect::<Vec<_>>()
+    });
+
+    let mut out: Vec<DiscoveredService> = Vec::new();
+    for result in results {
+        for svc in result? {
+            match out.iter_mut().find(|seen| seen.fullname == svc.fullname) {
+                Some(seen) => {
+                    for addr in svc.addresses {
+                        if !seen.addresses.contains(&addr) {
+                            seen.addresses.push(addr);
+                        }
+                    }
+                }
+                None => out.push(svc),
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Browse several service types until `deadline`, or indefinitely, and
+/// hand each resolved or removed service to `on_event` as it happens.
+/// Returns once `on_event` returns `false`. A slow `on_event` holds up
+/// browsing instead of piling up results.
+pub fn watch_many_blocking(
+    service_types: &[String],
+    deadline: Option<Instant>,
+    _interface: &str,
+    on_event: &(dyn Fn(BrowseEvent) -> bool + Sync),
+) -> Result<()> {
+    let daemon = ServiceDaemon::new()?;
+    let stop = AtomicBool::new(false);
+    let receivers = service_types
+        .iter()
+        .map(|ty| {
+            let ty = fully_qualified(ty);
+            Ok((daemon.browse(&ty)?, ty))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    std::thread::scope(|scope| {
+        for (receiver, service_type) in &receivers {
+            let stop = &stop;
+            scope.spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let wait = match deadline {
+                        Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                            Some(left) => left.min(Duration::from_millis(250)),
+                            None => break,
+                        },
+                        None => Duration::from_millis(250),
+                    };
+                    let event = match receiver.recv_timeout(wait) {
+                        Ok(ServiceEvent::ServiceResolved(info)) => {
+                            BrowseEvent::Resolved(discovered(&info, service_type))
+                        }
+                        Ok(ServiceEvent::ServiceRemoved(_, fullname)) => BrowseEvent::Removed { fullname },
+                        _ => continue,
+                    };
+                    if !on_event(event) {
+                        stop.store(true, Ordering::Relaxed);
+                    }
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Start an mDNS daemon and stop it again, to check that its multicast
+/// sockets can be opened on this machine.
+pub fn check_daemon() -> Result<()> {
+    let daemon = ServiceDaemon::new()?;
+    let _ = daemon.shutdown()?.recv_timeout(Duration::from_secs(1));
+    Ok(())
+}
+
+fn fully_qualified(service_type: &str) -> String {
+    if service_type.ends_with('.') {
+        service_type.to_string()
+    } else {
+        format!("{}.", service_type)
+    }
+}
+
+fn discovered(info: &ServiceInfo, service_type: &str) -> DiscoveredService {
+    let txt = info
+        .get_properties()
+        .iter()
+        .map(|prop| (prop.key().to_string(), prop.val_str().to_string()))
+        .collect::<Vec<_>>();
+
+    DiscoveredService {
+        fullname: info.get_fullname().to_string(),
+        instance_name: info.get_hostname().to_string(),
+        service_type: service_type.to_string(),
+        host_name: info.get_hostname().to_string(),
+        port: info.get_port(),
+        addresses: info.get_addresses().iter().copied().collect(),
+        txt,
+    }
+}
@@ -12,7 +12,8 @@ pub struct ServiceAnnouncement {
     pub instance_name: String,
     /// e.g. "myhost.local."
     pub host_name: String,
-    pub ip_addr: String,
+    /// Addresses to advertise, IPv4 and IPv6 alike
+    pub addresses: Vec<IpAddr>,
     pub port: u16,
     pub txt: Option<TxtRecord>,
 }
@@ -28,10 +29,76 @@ pub struct DiscoveredService {
     pub txt: Vec<(String, String)>,
 }
 
+/// A change seen while watching service types.
+#[derive(Debug, Clone)]
+pub enum BrowseEvent {
+    /// A service was resolved, or its records changed
+    Resolved(DiscoveredService),
+    /// The service with this full name went away
+    Removed { fullname: String },
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct InterfaceIp {
     pub name: String,
     pub ip: IpAddr,
     pub family: &'static str,  //ipv4 or ipv6
     pub is_loopback: bool,
+    pub kind: InterfaceKind,
+}
+
+/// Coarse classification of a local interface, used for address ranking.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InterfaceKind {
+    /// Physical or virtual LAN (Ethernet, Wi-Fi, bridges)
+    Lan,
+    /// VPN or overlay network (WireGuard, Tailscale, ZeroTier, OpenVPN)
+    Overlay,
+    Loopback,
+}
+
+/// How overlay addresses are treated when announcing and dialing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverlayPolicy {
+    /// Rank overlay addresses above LAN addresses
+    Prefer,
+    /// Use overlay addresses, but after LAN addresses
+    #[default]
+    Allow,
+    /// Never announce on or dial overlay addresses
+    Exclude,
+}
+
+/// Difference between two interface snapshots, as reported by
+/// [`crate::net::InterfaceWatcher`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InterfaceChange {
+    pub added: Vec<InterfaceIp>,
+    pub removed: Vec<InterfaceIp>,
+}
+
+impl InterfaceChange {
+    /// Compute the entries present in only one of `old` / `new`.
+    pub fn between(old: &[InterfaceIp], new: &[InterfaceIp]) -> Self {
+        let added = new.iter().filter(|i| !old.contains(i)).cloned().collect();
+        let removed = old.iter().filter(|i| !new.contains(i)).cloned().collect();
+        Self { added, removed }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+
+    /// Whether any address on the named interface was added or removed.
+    pub fn touches(&self, interface: &str) -> bool {
+        self.added.iter().chain(&self.removed).any(|i| i.name == interface)
+    }
+
+    /// Whether `ip` is no longer bound locally; cached peer addresses reached
+    /// through it should be dropped.
+    pub fn removed_ip(&self, ip: &IpAddr) -> bool {
+        self.removed.iter().any(|i| &i.ip == ip)
+    }
 }
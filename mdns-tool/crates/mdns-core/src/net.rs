@@ -1,26 +1,204 @@
 use std::io::Error;
-use crate::model::InterfaceIp;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::net::IpAddr;
+use crate::model::{InterfaceChange, InterfaceIp, InterfaceKind, OverlayPolicy};
 
 pub fn list_interface_ips_result() -> Result<Vec<InterfaceIp>, Error> {
     let ifs = if_addrs::get_if_addrs()?;
 
     let mut out: Vec<InterfaceIp> = ifs
         .into_iter()
-        .filter_map(|ifa| {
+        .map(|ifa| {
             let ip = ifa.ip();
             // ip() returns std::net::IpAddr
             let family = if ip.is_ipv4() {"ipv4"} else {"ipv6"};
             let is_loopback = ip.is_loopback();
+            let kind = classify_interface(&ifa.name, &ip);
 
-            Some(InterfaceIp {
+            InterfaceIp {
                 name: ifa.name,
                 ip,
                 family,
                 is_loopback,
-            })
+                kind,
+            }
         }).collect();
 
     out.sort_by(|a, b| (&a.name, &a.ip).cmp(&(&b.name, &b.ip)));
     out.dedup_by(|a, b| a.name == b.name && a.ip == b.ip);
     Ok(out)
-}
\ No newline at end of file
+}
+
+/// Interface name prefixes used by common VPN and overlay drivers.
+const OVERLAY_PREFIXES: &[&str] = &["tun", "tap", "wg", "utun", "tailscale", "zt", "ppp", "ipsec", "nordlynx"];
+
+/// Classify a local interface from its name and address.
+pub fn classify_interface(name: &str, ip: &IpAddr) -> InterfaceKind {
+    if ip.is_loopback() {
+        return InterfaceKind::Loopback;
+    }
+    let lower = name.to_ascii_lowercase();
+    if OVERLAY_PREFIXES.iter().any(|p| lower.starts_with(p)) || is_overlay_addr(ip) {
+        InterfaceKind::Overlay
+    } else {
+        InterfaceKind::Lan
+    }
+}
+
+/// Classify a remote address where no interface name is available.
+pub fn classify_addr(ip: &IpAddr) -> InterfaceKind {
+    if ip.is_loopback() {
+        InterfaceKind::Loopback
+    } else if is_overlay_addr(ip) {
+        InterfaceKind::Overlay
+    } else {
+        InterfaceKind::Lan
+    }
+}
+
+/// Tailscale hands out CGNAT (100.64.0.0/10) and fd7a:115c:a1e0::/48 addresses.
+fn is_overlay_addr(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let o = v4.octets();
+            o[0] == 100 && (o[1] & 0xc0) == 64
+        }
+        IpAddr::V6(v6) => {
+            let s = v6.segments();
+            s[0] == 0xfd7a && s[1] == 0x115c && s[2] == 0xa1e0
+        }
+    }
+}
+
+/// Sort key for a kind under `policy`; `None` means the kind is excluded.
+fn kind_rank(kind: InterfaceKind, policy: OverlayPolicy) -> Option<u8> {
+    match (kind, policy) {
+        (InterfaceKind::Overlay, OverlayPolicy::Exclude) => None,
+        (InterfaceKind::Overlay, OverlayPolicy::Prefer) => Some(0),
+        (InterfaceKind::Lan, _) => Some(1),
+        (InterfaceKind::Overlay, OverlayPolicy::Allow) => Some(2),
+        (InterfaceKind::Loopback, _) => Some(3),
+    }
+}
+
+/// Whether an interface of this kind may be used under `policy`.
+pub fn is_permitted(kind: InterfaceKind, policy: OverlayPolicy) -> bool {
+    kind_rank(kind, policy).is_some()
+}
+
+/// Order local interfaces for announcing, dropping excluded ones.
+pub fn rank_interfaces(ifaces: &[InterfaceIp], policy: OverlayPolicy) -> Vec<InterfaceIp> {
+    let mut out: Vec<(u8, InterfaceIp)> = ifaces
+        .iter()
+        .filter_map(|i| kind_rank(i.kind, policy).map(|r| (r, i.clone())))
+        .collect();
+    out.sort_by_key(|(r, _)| *r);
+    out.into_iter().map(|(_, i)| i).collect()
+}
+
+/// Order peer addresses for dialing, dropping excluded ones. IPv4 is tried
+/// before IPv6 within the same kind.
+pub fn rank_addresses(addrs: &[IpAddr], policy: OverlayPolicy) -> Vec<IpAddr> {
+    let mut out: Vec<(u8, IpAddr)> = addrs
+        .iter()
+        .filter_map(|ip| kind_rank(classify_addr(ip), policy).map(|r| (r, *ip)))
+        .collect();
+    out.sort_by_key(|(r, ip)| (*r, ip.is_ipv6()));
+    out.into_iter().map(|(_, ip)| ip).collect()
+}
+
+/// Polls the interface table and reports address changes (Wi-Fi roam,
+/// VPN up/down, DHCP renew) over a channel.
+///
+/// The background thread stops when the watcher is dropped.
+pub struct InterfaceWatcher {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl InterfaceWatcher {
+    /// Take an initial snapshot and start polling every `interval`.
+    /// Only non-empty changes are sent.
+    pub fn spawn(interval: Duration) -> Result<(Self, Receiver<InterfaceChange>), Error> {
+        let mut current = list_interface_ips_result()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let (tx, rx) = mpsc::channel();
+
+        let stop_flag = stop.clone();
+        let handle = std::thread::spawn(move || {
+            while !stop_flag.load(Ordering::Relaxed) {
+                std::thread::sleep(interval);
+                let next = match list_interface_ips_result() {
+                    Ok(next) => next,
+                    Err(_) => continue,
+                };
+                let change = InterfaceChange::between(&current, &next);
+                current = next;
+                if !change.is_empty() && tx.send(change).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok((Self { stop, handle: Some(handle) }, rx))
+    }
+}
+
+impl Drop for InterfaceWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn iface(name: &str, ip: &str) -> InterfaceIp {
+        let ip: std::net::IpAddr = ip.parse().unwrap();
+        InterfaceIp {
+            name: name.into(),
+            ip,
+            family: "ipv4",
+            is_loopback: ip.is_loopback(),
+            kind: classify_interface(name, &ip),
+        }
+    }
+
+    #[test]
+    fn test_interface_change_between() {
+        let old = vec![iface("lo", "127.0.0.1"), iface("wlan0", "192.168.1.10")];
+        let new = vec![iface("lo", "127.0.0.1"), iface("wlan0", "10.0.0.5")];
+
+        let change = InterfaceChange::between(&old, &new);
+        assert_eq!(change.added, vec![iface("wlan0", "10.0.0.5")]);
+        assert_eq!(change.removed, vec![iface("wlan0", "192.168.1.10")]);
+        assert!(change.touches("wlan0"));
+        assert!(!change.touches("lo"));
+        assert!(change.removed_ip(&"192.168.1.10".parse().unwrap()));
+
+        assert!(InterfaceChange::between(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn test_rank_addresses_policy() {
+        let lan: IpAddr = "192.168.1.20".parse().unwrap();
+        let ts: IpAddr = "100.101.102.103".parse().unwrap();
+        let addrs = [ts, lan];
+
+        assert_eq!(classify_interface("tailscale0", &ts), InterfaceKind::Overlay);
+        assert_eq!(classify_interface("wg0", &"10.8.0.2".parse().unwrap()), InterfaceKind::Overlay);
+        assert_eq!(classify_interface("eth0", &lan), InterfaceKind::Lan);
+
+        assert_eq!(rank_addresses(&addrs, OverlayPolicy::Allow), vec![lan, ts]);
+        assert_eq!(rank_addresses(&addrs, OverlayPolicy::Prefer), vec![ts, lan]);
+        assert_eq!(rank_addresses(&addrs, OverlayPolicy::Exclude), vec![lan]);
+    }
+}
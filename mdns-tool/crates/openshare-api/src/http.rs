@@ -0,0 +1,203 @@
+//! Just enough HTTP/1.1 for the API: one request per connection, with a
+//! body sized by `Content-Length`, answered with a JSON body or an event
+//! stream and then closed.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Longest request line or header accepted, and most headers.
+const MAX_LINE: usize = 8 * 1024;
+const MAX_HEADERS: usize = 64;
+
+/// Largest request body accepted.
+pub const MAX_BODY: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    /// Without the query
+    pub path: String,
+    /// Decoded query parameters, in order
+    pub query: Vec<(String, String)>,
+    /// Names in lower case
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.query.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    /// The path split at `/`, without empty segments.
+    pub fn segments(&self) -> Vec<&str> {
+        self.path.split('/').filter(|s| !s.is_empty()).collect()
+    }
+}
+
+/// Read one request; `None` if the connection closed before it started.
+pub async fn read_request<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Request>> {
+    let Some(line) = read_line(reader).await? else { return Ok(None) };
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        anyhow::bail!("Malformed request line");
+    };
+    if !version.starts_with("HTTP/1.") {
+        anyhow::bail!("Unsupported version {}", version);
+    }
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader).await?.context("Connection closed in the headers")?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            anyhow::bail!("Too many headers");
+        }
+        let (name, value) = line.split_once(':').context("Malformed header")?;
+        headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+    }
+    let mut request = Request {
+        method: method.to_string(),
+        path: percent_decode(path),
+        query: query.split('&')
+            .filter(|p| !p.is_empty())
+            .map(|p| {
+                let (name, value) = p.split_once('=').unwrap_or((p, ""));
+                (percent_decode(name), percent_decode(value))
+            })
+            .collect(),
+        headers,
+        body: Vec::new(),
+    };
+    if request.header("transfer-encoding").is_some() {
+        anyhow::bail!("Chunked request bodies are not supported");
+    }
+    let length: usize = request.header("content-length").map_or(Ok(0), str::parse).context("Invalid Content-Length")?;
+    if length > MAX_BODY {
+        anyhow::bail!("Request body of {} bytes is over the limit of {}", length, MAX_BODY);
+    }
+    request.body = vec![0; length];
+    reader.read_exact(&mut request.body).await?;
+    Ok(Some(request))
+}
+
+/// A line without its CRLF; `None` at the end of the stream.
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<String>> {
+    let mut line = Vec::new();
+    let n = (&mut *reader).take(MAX_LINE as u64 + 1).read_until(b'\n', &mut line).await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        anyhow::bail!("Line too long, or cut off");
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(Some(String::from_utf8(line).context("Request is not UTF-8")?))
+}
+
+/// `%XX` escapes and `+` (as a space) decoded; invalid escapes are kept.
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => match s.get(i + 1..i + 3).and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                Some(byte) => {
+                    out.push(byte);
+                    i += 3;
+                    continue;
+                }
+                None => out.push(b'%'),
+            },
+            b'+' => out.push(b' '),
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Write a complete response with `body` as JSON.
+pub async fn write_json<W: AsyncWrite + Unpin, T: Serialize>(writer: &mut W, status: u16, body: &T) -> Result<()> {
+    let body = serde_json::to_vec(body)?;
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status, reason(status), body.len(),
+    );
+    if status == 401 {
+        head.push_str("WWW-Authenticate: Bearer\r\n");
+    }
+    head.push_str("\r\n");
+    writer.write_all(head.as_bytes()).await?;
+    writer.write_all(&body).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Start a `text/event-stream` response; events follow with
+/// [`write_event`].
+pub async fn start_events<W: AsyncWrite + Unpin>(writer: &mut W) -> Result<()> {
+    writer.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n").await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Write one server-sent event named `event` with `data` as JSON, or a
+/// comment to keep the connection alive if `event` is `None`.
+pub async fn write_event<W: AsyncWrite + Unpin, T: Serialize>(writer: &mut W, event: Option<&str>, data: &T) -> Result<()> {
+    let message = match event {
+        // JSON has no raw newlines, so the data fits one line.
+        Some(event) => format!("event: {}\ndata: {}\n\n", event, serde_json::to_string(data)?),
+        None => ":\n\n".to_string(),
+    };
+    writer.write_all(message.as_bytes()).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_request() {
+        let raw = b"POST /v1/jobs%2Fx?peer=a%20b&min_size=5 HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2\r\n\r\n{}";
+        let request = read_request(&mut &raw[..]).await.unwrap().unwrap();
+        assert_eq!((request.method.as_str(), request.path.as_str()), ("POST", "/v1/jobs/x"));
+        assert_eq!(request.segments(), ["v1", "jobs", "x"]);
+        assert_eq!(request.param("peer"), Some("a b"));
+        assert_eq!(request.header("content-length"), Some("2"));
+        assert_eq!(request.body, b"{}");
+
+        assert!(read_request(&mut &b""[..]).await.unwrap().is_none());
+        assert!(read_request(&mut &b"GET /\r\n\r\n"[..]).await.is_err());
+        let big = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY + 1);
+        assert!(read_request(&mut big.as_bytes()).await.is_err());
+    }
+}
@@ -0,0 +1,397 @@
+//! An HTTP control API for an OpenShare [`Daemon`], served on localhost.
+//!
+//! It offers what the daemon's JSON-RPC control socket does, for programs
+//! and scripts that speak HTTP rather than Unix sockets. Every request
+//! carries `Authorization: Bearer <token>`, with the token from
+//! [`load_or_create_token`], since any local user can reach a TCP port.
+//! Bodies are JSON, and so are errors, as `{"error": message}`.
+//!
+//! - `GET /v1/peers`: the peers found by discovery
+//! - `GET /v1/jobs`: queued, running and recently finished jobs
+//! - `POST /v1/jobs` with `{"path", "peer"}` and optionally `"priority"`:
+//!   queue a send, as the control socket's `send` does; answers `201` with
+//!   the [`Job`]
+//! - `GET /v1/jobs/{id}`: one job
+//! - `POST /v1/jobs/{id}/pause`, `.../resume` and `.../cancel`: control a
+//!   job; answers with the [`Job`], or `409` if it is in no state to be
+//! - `POST /v1/jobs/{id}/priority` with `{"priority"}`: reprioritize a
+//!   queued job, likewise
+//! - `GET /v1/scheduler`: how many jobs run at once and in what order, as
+//!   [`SchedulerLimits`]; `POST` with any of its fields changes them
+//! - `GET /v1/events`: a stream of server-sent events, `job` with a [`Job`]
+//!   whenever one changes state or makes progress, and `transfer` with a
+//!   [`TransferRecord`](openshare_core::history::TransferRecord) for each
+//!   finished transfer. The query parameters `peer`, `direction` and
+//!   `min_size` filter transfers as a [`NotificationFilter`] does
+//!
+//! [`Job`]: openshare_core::daemon::Job
+//! [`SchedulerLimits`]: openshare_core::scheduler::SchedulerLimits
+
+pub mod http;
+
+use anyhow::{Context, Result};
+use http::Request;
+use openshare_core::daemon::{Connector, Daemon};
+use openshare_core::encrypted::write_private;
+use openshare_core::notify::NotificationFilter;
+use openshare_core::scheduler::{Priority, SchedulerLimits};
+use rand_core::{OsRng, RngCore};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use storage::Storage;
+use tokio::io::{AsyncRead, AsyncWrite, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+use tracing::Instrument;
+
+/// File in the data directory holding the API token.
+pub const TOKEN_FILE: &str = "api-token";
+
+/// How often an idle event stream gets a comment, so that proxies and
+/// clients with read timeouts keep it open.
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// The API token kept in `data_dir`, created on first use. Only the owner
+/// can read the file.
+pub fn load_or_create_token(data_dir: &Path) -> Result<String> {
+    let path = data_dir.join(TOKEN_FILE);
+    match std::fs::read_to_string(&path) {
+        Ok(token) => return parse_token(&path, &token),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+
+    // Written in full under a name of its own, then linked into place, so
+    // that another process never reads half a token. A link, unlike a
+    // rename, fails if that process created one first; then its token is
+    // the one to use.
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let token = hex::encode(bytes);
+    let temp = data_dir.join(format!("{}.{}.tmp", TOKEN_FILE, &token[..16]));
+    write_private(&temp, token.as_bytes()).with_context(|| format!("Failed to write {}", temp.display()))?;
+    let linked = std::fs::hard_link(&temp, &path);
+    let _ = std::fs::remove_file(&temp);
+    match linked {
+        Ok(()) => Ok(token),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            let token = std::fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+            parse_token(&path, &token)
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to write {}", path.display())),
+    }
+}
+
+fn parse_token(path: &Path, token: &str) -> Result<String> {
+    let token = token.trim();
+    anyhow::ensure!(!token.is_empty(), "{} is empty", path.display());
+    Ok(token.to_string())
+}
+
+/// Serves the API for one daemon.
+pub struct Api<S, C> {
+    daemon: Arc<Daemon<S, C>>,
+    token: String,
+}
+
+impl<S, C> Api<S, C>
+where
+    S: Storage + Send + Sync + 'static,
+    C: Connector + 'static,
+{
+    /// Answer requests bearing `token` with `daemon`.
+    pub fn new(daemon: Arc<Daemon<S, C>>, token: impl Into<String>) -> Self {
+        Self { daemon, token: token.into() }
+    }
+
+    /// Accept connections on `listener` forever, each in its own task.
+    /// Bind it to a loopback address: the token is sent in the clear.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let api = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = api.handle(stream).await {
+                    tracing::debug!("API connection from {} failed: {:#}", peer, e);
+                }
+            }.in_current_span());
+        }
+    }
+
+    /// Answer the one request on `stream`.
+    pub async fn handle<T: AsyncRead + AsyncWrite + Unpin>(&self, stream: T) -> Result<()> {
+        let (read, mut write) = tokio::io::split(stream);
+        let request = match http::read_request(&mut BufReader::new(read)).await {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(e) => return http::write_json(&mut write, 400, &error(e)).await,
+        };
+        if !self.authorized(&request) {
+            return http::write_json(&mut write, 401, &error("Missing or wrong API token")).await;
+        }
+        tracing::debug!("API request {} {}", request.method, request.path);
+        if request.segments() == ["v1", "events"] {
+            if request.method != "GET" {
+                return http::write_json(&mut write, 405, &error("Method not allowed")).await;
+            }
+            return match filter(&request) {
+                Ok(filter) => self.events(filter, write).await,
+                Err(e) => http::write_json(&mut write, 400, &error(e)).await,
+            };
+        }
+        let (status, body) = self.route(&request);
+        http::write_json(&mut write, status, &body).await
+    }
+
+    fn authorized(&self, request: &Request) -> bool {
+        let Some(token) = request.header("authorization").and_then(|v| v.strip_prefix("Bearer ")) else {
+            return false;
+        };
+        // In constant time, so the token cannot be guessed a byte at a time
+        token.len() == self.token.len()
+            && token.bytes().zip(self.token.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+    }
+
+    fn route(&self, request: &Request) -> (u16, Value) {
+        let method = request.method.as_str();
+        match request.segments()[..] {
+            ["v1", "peers"] if method == "GET" => (200, json!(self.daemon.peers())),
+            ["v1", "jobs"] if method == "GET" => (200, json!(self.daemon.jobs())),
+            ["v1", "jobs"] if method == "POST" => {
+                #[derive(Deserialize)]
+                struct SendParams {
+                    path: PathBuf,
+                    peer: String,
+                    #[serde(default)]
+                    priority: Priority,
+                }
+                let params: SendParams = match serde_json::from_slice(&request.body) {
+                    Ok(params) => params,
+                    Err(e) => return (400, error(format!("Invalid body: {}", e))),
+                };
+                match self.daemon.enqueue(params.path, &params.peer, params.priority) {
+                    Ok(job) => (201, json!(job)),
+                    Err(e) => (400, error(e)),
+                }
+            }
+            ["v1", "jobs", id] if method == "GET" => match id.parse().ok().and_then(|id| self.daemon.job(id)) {
+                Some(job) => (200, json!(job)),
+                None => (404, error(format!("No job {}", id))),
+            },
+            ["v1", "jobs", id, action @ ("pause" | "resume" | "cancel")] if method == "POST" => {
+                let Some(id) = id.parse().ok().filter(|&id| self.daemon.job(id).is_some()) else {
+                    return (404, error(format!("No job {}", id)));
+                };
+                let result = match action {
+                    "pause" => self.daemon.pause(id),
+                    "resume" => self.daemon.resume(id),
+                    _ => self.daemon.cancel(id),
+                };
+                match result {
+                    Ok(job) => (200, json!(job)),
+                    Err(e) => (409, error(e)),
+                }
+            }
+            ["v1", "jobs", id, "priority"] if method == "POST" => {
+                #[derive(Deserialize)]
+                struct PriorityParams {
+                    priority: Priority,
+                }
+                let Some(id) = id.parse().ok().filter(|&id| self.daemon.job(id).is_some()) else {
+                    return (404, error(format!("No job {}", id)));
+                };
+                let params: PriorityParams = match serde_json::from_slice(&request.body) {
+                    Ok(params) => params,
+                    Err(e) => return (400, error(format!("Invalid body: {}", e))),
+                };
+                match self.daemon.prioritize(id, params.priority) {
+                    Ok(job) => (200, json!(job)),
+                    Err(e) => (409, error(e)),
+                }
+            }
+            ["v1", "scheduler"] if method == "GET" => (200, json!(self.daemon.scheduling())),
+            ["v1", "scheduler"] if method == "POST" => {
+                // Fields left out keep their current values.
+                let mut limits = json!(self.daemon.scheduling());
+                let changes: serde_json::Map<String, Value> = match serde_json::from_slice(&request.body) {
+                    Ok(changes) => changes,
+                    Err(e) => return (400, error(format!("Invalid body: {}", e))),
+                };
+                limits.as_object_mut().expect("limits are an object").extend(changes);
+                let limits: SchedulerLimits = match serde_json::from_value(limits) {
+                    Ok(limits) => limits,
+                    Err(e) => return (400, error(format!("Invalid body: {}", e))),
+                };
+                match self.daemon.set_scheduling(limits) {
+                    Ok(()) => (200, json!(limits)),
+                    Err(e) => (500, error(e)),
+                }
+            }
+            ["v1", "peers"]
+            | ["v1", "jobs"]
+            | ["v1", "jobs", _]
+            | ["v1", "jobs", _, "pause" | "resume" | "cancel" | "priority"]
+            | ["v1", "scheduler"] => (405, error("Method not allowed")),
+            _ => (404, error(format!("No such endpoint: {}", request.path))),
+        }
+    }
+
+    /// Stream job updates and finished transfers until the client goes.
+    async fn events<W: AsyncWrite + Unpin>(&self, filter: NotificationFilter, mut write: W) -> Result<()> {
+        let mut jobs = self.daemon.job_updates();
+        let mut transfers = self.daemon.hub().subscribe(filter);
+        http::start_events(&mut write).await?;
+        let mut keepalive = tokio::time::interval_at(tokio::time::Instant::now() + KEEPALIVE, KEEPALIVE);
+        loop {
+            tokio::select! {
+                job = jobs.recv() => match job {
+                    Ok(job) => http::write_event(&mut write, Some("job"), &job).await?,
+                    // Progress is superseded by the next update anyway
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+                record = transfers.recv() => match record {
+                    Some(record) => http::write_event(&mut write, Some("transfer"), &record).await?,
+                    None => return Ok(()),
+                },
+                _ = keepalive.tick() => http::write_event(&mut write, None, &()).await?,
+            }
+        }
+    }
+}
+
+/// The transfer filter given in the query of an events request.
+fn filter(request: &Request) -> Result<NotificationFilter> {
+    let direction = request.param("direction")
+        .map(|d| serde_json::from_value(Value::String(d.to_string())))
+        .transpose()
+        .context("Invalid direction")?;
+    let min_size = request.param("min_size").map_or(Ok(0), str::parse).context("Invalid min_size")?;
+    Ok(NotificationFilter { peer: request.param("peer").map(str::to_string), direction, min_size })
+}
+
+fn error(e: impl std::fmt::Display) -> Value {
+    json!({ "error": format!("{:#}", e) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use openshare_core::{Client, ClientConfig, Discovery, Identity};
+    use storage::LocalStorage;
+    use tempfile::TempDir;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+    /// Reaches no one; the tests never run the queue.
+    struct NoConnector;
+
+    #[async_trait]
+    impl Connector for NoConnector {
+        type Transport = tokio::io::DuplexStream;
+
+        async fn connect(&self, address: &str) -> Result<Self::Transport> {
+            anyhow::bail!("{} is unreachable", address)
+        }
+    }
+
+    fn api(dir: &TempDir) -> Arc<Api<LocalStorage, NoConnector>> {
+        let identity = Identity::generate_and_store(&dir.path().join("identity.key")).unwrap();
+        let storage = LocalStorage::new(dir.path().join("chunks")).unwrap();
+        let cfg = ClientConfig { data_dir: dir.path().to_path_buf(), device_id: "dev1".into(), ..ClientConfig::default() };
+        let discovery = Discovery::new(cfg.clone(), &identity);
+        let daemon = Daemon::new(Client::new(identity, storage, cfg), discovery, NoConnector);
+        Arc::new(Api::new(Arc::new(daemon), "secret"))
+    }
+
+    /// Send `method path` with `body`, and return the status and body.
+    async fn call(api: &Arc<Api<LocalStorage, NoConnector>>, method: &str, path: &str, body: &str) -> (u16, Value) {
+        let (mut local, remote) = tokio::io::duplex(1 << 16);
+        let server = api.clone();
+        tokio::spawn(async move { server.handle(remote).await });
+        let request = format!(
+            "{} {} HTTP/1.1\r\nAuthorization: Bearer secret\r\nContent-Length: {}\r\n\r\n{}",
+            method, path, body.len(), body,
+        );
+        local.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        local.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head[9..12].parse().unwrap(), serde_json::from_str(body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_jobs_and_events() {
+        let dir = TempDir::new().unwrap();
+        let api = api(&dir);
+        let input = dir.path().join("input.bin");
+        std::fs::write(&input, b"hello").unwrap();
+
+        let (mut local, remote) = tokio::io::duplex(1 << 16);
+        let server = api.clone();
+        tokio::spawn(async move { server.handle(remote).await });
+        local.write_all(b"GET /v1/peers HTTP/1.1\r\nAuthorization: Bearer guess\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        local.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 401 "));
+
+        let (mut local, remote) = tokio::io::duplex(1 << 16);
+        let server = api.clone();
+        tokio::spawn(async move { server.handle(remote).await });
+        local.write_all(b"GET /v1/events?direction=sent HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n").await.unwrap();
+        let mut events = tokio::io::BufReader::new(local).lines();
+        assert_eq!(events.next_line().await.unwrap().unwrap(), "HTTP/1.1 200 OK");
+        while !events.next_line().await.unwrap().unwrap().is_empty() {}
+
+        assert_eq!(call(&api, "GET", "/v1/peers", "").await, (200, json!([])));
+        let send = json!({ "path": input, "peer": "receiver:9876" }).to_string();
+        let (status, job) = call(&api, "POST", "/v1/jobs", &send).await;
+        assert_eq!((status, &job["state"]), (201, &json!("queued")));
+        let (status, _) = call(&api, "POST", "/v1/jobs", r#"{"path": "input.bin", "peer": "receiver:9876"}"#).await;
+        assert_eq!(status, 400);
+
+        let path = format!("/v1/jobs/{}", job["id"]);
+        assert_eq!(call(&api, "GET", &path, "").await, (200, job.clone()));
+        assert_eq!(call(&api, "POST", &format!("{}/pause", path), "").await.0, 409);
+        let (status, cancelled) = call(&api, "POST", &format!("{}/cancel", path), "").await;
+        assert_eq!((status, &cancelled["state"]), (200, &json!("cancelled")));
+        assert_eq!(call(&api, "GET", "/v1/jobs", "").await, (200, json!([cancelled])));
+        assert_eq!(call(&api, "POST", "/v1/jobs/99/cancel", "").await.0, 404);
+        assert_eq!(call(&api, "DELETE", "/v1/jobs", "").await.0, 405);
+
+        let send = json!({ "path": input, "peer": "receiver:9876", "priority": "low" }).to_string();
+        let (status, low) = call(&api, "POST", "/v1/jobs", &send).await;
+        assert_eq!((status, &low["priority"]), (201, &json!("low")));
+        let (status, high) = call(&api, "POST", &format!("/v1/jobs/{}/priority", low["id"]), r#"{"priority": "high"}"#).await;
+        assert_eq!((status, &high["priority"]), (200, &json!("high")));
+        let (status, limits) = call(&api, "POST", "/v1/scheduler", r#"{"max_concurrent_jobs": 1}"#).await;
+        assert_eq!((status, &limits["max_concurrent_jobs"], &limits["max_jobs_per_peer"]), (200, &json!(1), &json!(1)));
+        assert_eq!(call(&api, "GET", "/v1/scheduler", "").await, (200, limits));
+        assert_eq!(call(&api, "POST", "/v1/scheduler", r#"{"queue_order": "random"}"#).await.0, 400);
+        assert_eq!(call(&api, "GET", "/v1/events?min_size=lots", "").await.0, 400);
+
+        assert_eq!(events.next_line().await.unwrap().unwrap(), "event: job");
+        let data = events.next_line().await.unwrap().unwrap();
+        assert_eq!(serde_json::from_str::<Value>(data.strip_prefix("data: ").unwrap()).unwrap(), job);
+    }
+
+    #[test]
+    fn test_token_is_kept() {
+        let dir = TempDir::new().unwrap();
+        let token = load_or_create_token(dir.path()).unwrap();
+        assert_eq!(token.len(), 64);
+        assert_eq!(load_or_create_token(dir.path()).unwrap(), token);
+
+        // Processes starting together all end up with the same token.
+        let dir = TempDir::new().unwrap();
+        let tokens: Vec<String> = std::thread::scope(|scope| {
+            let racers: Vec<_> = (0..8).map(|_| scope.spawn(|| load_or_create_token(dir.path()).unwrap())).collect();
+            racers.into_iter().map(|r| r.join().unwrap()).collect()
+        });
+        assert!(tokens.iter().all(|t| *t == tokens[0]));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
@@ -1,11 +1,52 @@
 use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use clap::{Args, Parser, Subcommand};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 use tracing_subscriber::{fmt, EnvFilter};
 
-use openshare_core::{ClientConfig, Identity, Manifest, Client};
-use storage::{LocalStorage, Storage};
+use openshare_core::{Accepted, ClientConfig, OpenShareError, Identity, Manifest, Client, Discovery, TransferEvent, TrustStore};
+use openshare_core::account::{self, AccountRoot, DeviceCertificate};
+use openshare_core::backup::IdentityBundle;
+use openshare_core::handshake;
+use openshare_core::history::{Direction, History, TransferRecord, UsageStats};
+use openshare_core::metrics::{MetricsLog, Trends};
+use openshare_core::index::{ContentIndex, DeviceIndex, INDEX_FILE};
+use openshare_core::integrity::{self, CorruptChunks};
+use openshare_core::keys::{KeyBackend, MIN_FINGERPRINT_LEN};
+use openshare_core::notify::{NotificationFilter, NotificationHub};
+use openshare_core::offers::{address_hints, OfferRecord, Offers, ShareCode};
+use openshare_core::client::PeerChunkFetcher;
+use openshare_core::chunking::MAX_FIXED_SIZE;
+use openshare_core::codec::Codec;
+use openshare_core::config::{StorageConfig, Subscription};
+use openshare_core::daemon::{Connector, Daemon, Job, JobState};
+use openshare_core::encrypted::{EncryptedStorage, StorageEncryption};
+use openshare_core::keyed::ChunkKey;
+use openshare_core::manifests::ManifestStore;
+use openshare_core::placeholder::Placeholder;
+use openshare_core::profiles::{validate_name as validate_profile_name, Profiles, DEFAULT_PROFILE};
+use openshare_core::replicate::{Replica, ReplicaState, Replicator};
+use openshare_core::scheduler::{Priority, QueueOrder, SchedulerLimits};
+use openshare_core::service::{self, RotatingLog, ServiceSpec};
+use openshare_core::sidecar::Sidecar;
+use openshare_core::swarm::SwarmFetcher;
+use openshare_core::vfs::{ChunkFetcher, NodeKind, ShareView};
+use openshare_core::watcher::{Watcher, POLL_INTERVAL};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use openshare_core::trash::{Retention, Trash};
+use openshare_core::policy::{Limits, Offer, TransferPolicy, Verdict};
+use openshare_core::transfer::{TransferHandle, TransferId, TransferOutcome};
+use openshare_core::trust::{parse_public_key, TrustSource};
+use storage::Storage;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::Instrument;
+use transport_quic::{DynStream, QuicConnection, QuicListener};
+use transport_ws::WsConnection;
 
 #[derive(Parser, Debug)]
 #[command(name = "openshare", version, about = "OpenShare P2P File Transfer")]
@@ -14,10 +55,21 @@ struct Cli {
     #[arg(long, global = true, default_value = "info")]
     log_level: String,
 
-    /// Data directory for storage
+    /// Data directory for storage; named profiles are kept inside it
     #[arg(long, global = true)]
     data_dir: Option<PathBuf>,
 
+    /// Profile to use: a name given to 'profile create', or the data
+    /// directory of one (anything with a '/'). 'daemon' serves every
+    /// profile given [default: the one chosen with 'profile switch']
+    #[arg(long = "profile", global = true, value_name = "NAME|DIR")]
+    profiles: Vec<String>,
+
+    /// Speak an older protocol version, offering and using only its
+    /// features, while other devices still run the release that had it
+    #[arg(long, global = true, value_name = "VERSION")]
+    compat: Option<u16>,
+
     #[command(subcommand)]
     cmd: Commands,
 }
@@ -26,23 +78,48 @@ struct Cli {
 enum Commands {
     /// Initialize a new device identity
     Init {
-        /// Device name/identifier
-        #[arg(long)]
-        device_id: String,
+        #[command(flatten)]
+        init: InitArgs,
+    },
 
-        /// Account identifier (will be hashed for discovery)
-        #[arg(long)]
-        account: String,
+    /// Keep separate identities, each with its own config and trust store,
+    /// e.g. for work and personal accounts
+    Profile {
+        #[command(subcommand)]
+        action: ProfileAction,
     },
 
     /// Show device information
     Info,
 
+    /// Check this device's setup and say what to fix: identity, config,
+    /// data directory permissions, a sample of stored chunks, mDNS and
+    /// whether the listen port can be reached
+    Doctor {
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// List the protocol versions, ciphers, transports, codecs and features
+    /// this build supports; with --peer, also what a peer offers and what
+    /// the two have in common
+    Capabilities {
+        /// Peer address (host:port) to exchange hellos with
+        #[arg(long)]
+        peer: Option<String>,
+
+        /// Use QUIC instead of TCP
+        #[arg(long, requires = "peer")]
+        quic: bool,
+    },
+
     /// Announce this device on the local network
     Announce {
-        /// Network interface to use
-        #[arg(long)]
-        interface: String,
+        /// Network interface to announce on (repeatable) [default: all
+        /// but loopback]
+        #[arg(long = "interface")]
+        interfaces: Vec<String>,
 
         /// Port to listen on
         #[arg(long, default_value_t = 9876)]
@@ -51,6 +128,10 @@ enum Commands {
         /// Keep announcing (0 = forever)
         #[arg(long, default_value_t = 0)]
         ttl: u64,
+
+        /// Also announce under this service type (repeatable)
+        #[arg(long = "service-type")]
+        service_types: Vec<String>,
     },
 
     /// Discover devices on the local network
@@ -66,11 +147,44 @@ enum Commands {
         /// Output as JSON
         #[arg(long)]
         json: bool,
+
+        /// Print each device as a line of JSON as soon as it resolves,
+        /// instead of all of them at the end
+        #[arg(long)]
+        stream: bool,
+
+        /// With --stream, keep watching until interrupted and also report
+        /// devices that go away
+        #[arg(long, requires = "stream")]
+        watch: bool,
+
+        /// Also browse this service type (repeatable)
+        #[arg(long = "service-type")]
+        service_types: Vec<String>,
+    },
+
+    /// List this account's devices on the network, one per line
+    Peers {
+        /// Network interface to use
+        #[arg(long)]
+        interface: String,
+
+        /// How long to browse, in seconds
+        #[arg(long, default_value_t = 3)]
+        timeout: u64,
+
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+
+        /// Also browse this service type (repeatable)
+        #[arg(long = "service-type")]
+        service_types: Vec<String>,
     },
 
     /// Create a manifest from a file
     CreateManifest {
-        /// File to create manifest for
+        /// File or directory to create manifest for
         #[arg(long)]
         file: PathBuf,
 
@@ -86,380 +200,4951 @@ enum Commands {
         manifest: PathBuf,
     },
 
+    /// Check a received file against its .oshare.json sidecar
+    VerifySidecar {
+        /// Received file or directory (not the sidecar itself)
+        #[arg(long)]
+        file: PathBuf,
+    },
+
     /// Send a file to a peer
     Send {
-        /// File to send
+        /// File or directory to send; repeat to send several in one
+        /// session
+        #[arg(long, required_unless_present = "stdin")]
+        file: Vec<PathBuf>,
+
+        /// Peer address (host:port, or a ws:// or wss:// URL to go over a
+        /// WebSocket); with --replicate, the first device to try. With
+        /// --stdin, also a device ID from the content index
+        #[arg(long, visible_alias = "to", required_unless_present = "replicate")]
+        peer: Option<String>,
+
+        /// Send standard input as it is read, e.g. from a pipe, without
+        /// knowing its size up front; the receiver checks it against the
+        /// signed manifest sent at the end
+        #[arg(long, conflicts_with_all = ["file", "replicate", "keep_chunks", "fingerprint"])]
+        stdin: bool,
+
+        /// Name the receiver gives what --stdin sends
+        #[arg(long, default_value = "stdin", requires = "stdin")]
+        name: String,
+
+        /// Use QUIC instead of TCP
+        #[arg(long)]
+        quic: bool,
+
+        /// Also keep the chunks in local storage (e.g. to publish later)
+        #[arg(long)]
+        keep_chunks: bool,
+
+        /// Only send if the peer's key has this fingerprint, e.g. the one
+        /// shown by 'discover' or the full one from 'openshare info'
+        #[arg(long, conflicts_with = "replicate")]
+        fingerprint: Option<String>,
+
+        /// Deliver to this many of your devices: --peer, then the trusted
+        /// devices in the content index (see 'index sync'). Done once that
+        /// many hold it; a device that fails is replaced by the next
+        #[arg(long, value_name = "N", conflicts_with_all = ["quic", "keep_chunks"])]
+        replicate: Option<usize>,
+
+        /// Cut the content into chunks of this many bytes, instead of the
+        /// configured size (or the adaptive one)
+        #[arg(long, value_name = "BYTES")]
+        chunk_size: Option<usize>,
+    },
+
+    /// Send a new version of a file or directory the peer already has;
+    /// only the chunks that changed are transferred
+    Sync {
+        /// File or directory to sync; it lands in the peer's output
+        /// directory under the same name
         #[arg(long)]
         file: PathBuf,
 
         /// Peer address (host:port)
         #[arg(long)]
         peer: String,
-    },
-
-    /// Listen for incoming transfers
-    Listen {
-        /// Port to listen on
-        #[arg(long, default_value_t = 9876)]
-        port: u16,
 
-        /// Output directory for received files
+        /// Use QUIC instead of TCP
         #[arg(long)]
-        output: Option<PathBuf>,
+        quic: bool,
     },
-}
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let cli = Cli::parse();
+    /// Send a short text, such as a link, without making a file of it;
+    /// the peer prints it (or copies it, see 'listen --clipboard')
+    SendText {
+        /// Text to send [default: the clipboard with --clipboard, else
+        /// standard input]
+        text: Option<String>,
 
-    // Initialize logging
-    fmt()
-        .with_env_filter(EnvFilter::new(&cli.log_level))
-        .with_target(false)
-        .init();
+        /// Device to send it to: host:port, or a device ID from the
+        /// content index ('openshare index sync')
+        #[arg(long)]
+        to: String,
 
-    // Determine data directory
-    let data_dir = cli.data_dir.unwrap_or_else(|| {
-        dirs::home_dir()
-            .unwrap_or_else(|| PathBuf::from("."))
-            .join(".openshare")
-    });
+        /// Send what is on the clipboard (builds with the clipboard
+        /// feature)
+        #[arg(long, conflicts_with = "text")]
+        clipboard: bool,
 
-    let identity_path = data_dir.join("identity.key");
+        /// Use QUIC instead of TCP
+        #[arg(long)]
+        quic: bool,
+    },
 
-    match cli.cmd {
-        Commands::Init { device_id, account } => {
-            std::fs::create_dir_all(&data_dir)?;
+    /// Make a file available for peers to fetch while listening
+    Publish {
+        /// File or directory to publish
+        #[arg(long)]
+        file: PathBuf,
 
-            let identity = Identity::generate_and_store(&identity_path)?;
+        /// Tell the trusted devices in the content index (see 'index
+        /// sync') that the share is available
+        #[arg(long)]
+        announce: bool,
 
-            // Create config with account hash
-            let account_hash = compute_account_hash(&account);
-            let mut cfg = ClientConfig::default();
-            cfg.data_dir = data_dir.clone();
-            cfg.device_id = device_id.clone();
-            cfg.account_hash = account_hash.clone();
+        /// Also tell the device at this address (host:port); repeatable
+        #[arg(long = "announce-to")]
+        announce_to: Vec<String>,
 
-            cfg.ensure_data_dir()?;
+        /// Cut the content into chunks of this many bytes, instead of the
+        /// configured size (or the adaptive one)
+        #[arg(long, value_name = "BYTES")]
+        chunk_size: Option<usize>,
+    },
 
-            // Save config
-            let cfg_path = data_dir.join("config.json");
-            let cfg_json = serde_json::to_string_pretty(&cfg)?;
-            std::fs::write(cfg_path, cfg_json)?;
+    /// Fetch a published file from a peer
+    Fetch {
+        /// Peer address (host:port)
+        #[arg(long)]
+        peer: String,
 
-            println!("✓ Device initialized");
-            println!("  Device ID: {}", device_id);
-            println!("  Account: {}", account);
-            println!("  Fingerprint: {}", identity.fingerprint());
-            println!("  Full fingerprint: {}", identity.full_fingerprint());
-            println!("  Data directory: {}", data_dir.display());
-        }
+        /// Manifest ID printed by 'openshare publish' on the peer
+        #[arg(long)]
+        manifest_id: String,
 
-        Commands::Info => {
-            if !identity_path.exists() {
-                anyhow::bail!("Device not initialized. Run 'openshare init' first.");
-            }
+        /// Output directory for the fetched file
+        #[arg(long)]
+        output: Option<PathBuf>,
 
-            let identity = Identity::load(&identity_path)?;
-            let cfg = load_config(&data_dir)?;
+        /// Use QUIC instead of TCP
+        #[arg(long)]
+        quic: bool,
 
-            println!("Device Information:");
-            println!("  Device ID: {}", cfg.device_id);
-            println!("  Account hash: {}", cfg.account_hash);
-            println!("  Fingerprint: {}", identity.fingerprint());
-            println!("  Full fingerprint: {}", identity.full_fingerprint());
-            println!("  Data directory: {}", data_dir.display());
-            println!("  Listen port: {}", cfg.listen_port);
-        }
+        /// Write a <file>.oshare.json checksum sidecar (see 'verify-sidecar')
+        #[arg(long)]
+        sidecar: bool,
 
-        Commands::Announce { interface, port, ttl } => {
-            let identity = Identity::load(&identity_path)
-                .context("Device not initialized. Run 'openshare init' first.")?;
-            let cfg = load_config(&data_dir)?;
+        /// Only create a placeholder; content is fetched on read or by 'pin'
+        #[arg(long)]
+        placeholder: bool,
+    },
 
-            announce_device(&cfg, &identity, &interface, port, ttl).await?;
-        }
+    /// Offer a file to anyone given the share code printed, devices of
+    /// other accounts included; it is served while this device listens
+    Offer {
+        /// File or directory to offer
+        #[arg(long)]
+        file: PathBuf,
 
-        Commands::Discover { interface, timeout, json } => {
-            let cfg = load_config(&data_dir)?;
-            discover_devices(&cfg, &interface, timeout, json).await?;
-        }
+        /// How long the code works, e.g. 1d or 12h; 0 for as long as the
+        /// offer is kept
+        #[arg(long, default_value = "1d")]
+        expires: String,
 
-        Commands::CreateManifest { file, output } => {
-            let identity = Identity::load(&identity_path)
-                .context("Device not initialized. Run 'openshare init' first.")?;
-            let cfg = load_config(&data_dir)?;
+        /// Listen for the fetch right away, as the daemon would
+        #[arg(long)]
+        serve: bool,
+    },
 
-            let mut manifest = Manifest::from_file(
-                file.to_str().unwrap(),
-                cfg.chunk_size
-            )?;
+    /// Fetch a file offered with 'openshare offer'
+    Get {
+        /// Share code printed by 'openshare offer'
+        code: String,
 
-            manifest.sign(&identity)?;
+        /// Output directory for the fetched file
+        #[arg(long)]
+        output: Option<PathBuf>,
 
-            let manifest_json = serde_json::to_string_pretty(&manifest)?;
-            std::fs::write(&output, manifest_json)?;
+        /// Look the offering device up over mDNS on this network interface
+        /// before trying the addresses in the code
+        #[arg(long)]
+        interface: Option<String>,
 
-            println!("✓ Manifest created: {}", output.display());
-            println!("  {}", manifest.summary());
-        }
+        /// Use QUIC instead of TCP
+        #[arg(long)]
+        quic: bool,
+    },
 
-        Commands::VerifyManifest { manifest: manifest_path } => {
-            let manifest_json = std::fs::read_to_string(&manifest_path)?;
-            let manifest: Manifest = serde_json::from_str(&manifest_json)?;
+    /// Download a placeholder's content in full and replace the stub
+    Pin {
+        /// Placeholder stub, or the path it stands in for
+        #[arg(long)]
+        path: PathBuf,
 
-            match manifest.verify() {
-                Ok(_) => println!("✓ Manifest signature is valid"),
-                Err(e) => {
-                    println!("✗ Manifest signature is invalid: {}", e);
-                    std::process::exit(1);
-                }
-            }
-        }
+        /// Use QUIC instead of TCP
+        #[arg(long)]
+        quic: bool,
+    },
 
-        Commands::Send { file, peer } => {
-            let identity = Identity::load(&identity_path)
-                .context("Device not initialized. Run 'openshare init' first.")?;
-            let cfg = load_config(&data_dir)?;
-            let storage = LocalStorage::new(data_dir.clone())?;
+    /// Print a placeholder's content, fetching chunks on demand
+    Cat {
+        /// Placeholder stub, or the path it stands in for
+        #[arg(long)]
+        path: PathBuf,
 
-            send_file(&identity, &cfg, &storage, &file, &peer).await?;
-        }
+        /// File inside a directory share (lists it if it is a directory)
+        #[arg(long)]
+        entry: Option<String>,
+    },
 
-        Commands::Listen { port, output } => {
-            let identity = Identity::load(&identity_path)
-                .context("Device not initialized. Run 'openshare init' first.")?;
-            let mut cfg = load_config(&data_dir)?;
-            cfg.listen_port = port;
-            let storage = LocalStorage::new(data_dir.clone())?;
+    /// Mount a share read-only, reading files from stored chunks and
+    /// fetching missing ones from the peer as they are read (Linux, builds
+    /// with the fuse feature). Runs until Ctrl+C or 'fusermount3 -u'.
+    Mount {
+        /// Placeholder stub, or the path it stands in for
+        #[arg(long, conflicts_with = "manifest", required_unless_present = "manifest")]
+        path: Option<PathBuf>,
 
-            let output_dir = output.unwrap_or_else(|| std::env::current_dir().unwrap());
+        /// ID of a manifest published on this device
+        #[arg(long)]
+        manifest: Option<String>,
 
-            listen_for_transfers(&identity, &cfg, &storage, &output_dir).await?;
-        }
-    }
+        /// Existing directory to mount on
+        #[arg(long)]
+        mountpoint: PathBuf,
+    },
 
-    Ok(())
-}
+    /// Listen for incoming transfers and fetch requests
+    Listen {
+        /// Port to listen on
+        #[arg(long, default_value_t = 9876)]
+        port: u16,
 
-fn compute_account_hash(account: &str) -> String {
-    use sha2::{Digest, Sha256};
-    let mut hasher = Sha256::new();
-    hasher.update(account.as_bytes());
-    hex::encode(&hasher.finalize()[..8]) // Use first 8 bytes for compact hash
-}
+        /// Output directory for received files
+        #[arg(long)]
+        output: Option<PathBuf>,
 
-fn load_config(data_dir: &PathBuf) -> Result<ClientConfig> {
-    let cfg_path = data_dir.join("config.json");
-    if !cfg_path.exists() {
-        anyhow::bail!("Device not initialized. Run 'openshare init' first.");
-    }
+        /// Accept QUIC (UDP) instead of TCP connections
+        #[arg(long)]
+        quic: bool,
 
-    let cfg_json = std::fs::read_to_string(cfg_path)?;
-    let cfg: ClientConfig = serde_json::from_str(&cfg_json)?;
-    Ok(cfg)
-}
+        /// Accept WebSocket connections instead of plain TCP, for peers
+        /// behind HTTP-only networks or coming through a relay
+        #[arg(long, conflicts_with = "quic")]
+        ws: bool,
 
-async fn announce_device(
-    cfg: &ClientConfig,
-    identity: &Identity,
-    interface: &str,
-    port: u16,
-    ttl: u64,
-) -> Result<()> {
-    use mdns_core::{announce::Announcer, model::{ServiceAnnouncement, TxtRecord}, net::list_interface_ips_result};
+        /// Serve the control API on this Unix socket (see 'watch' and
+        /// 'control')
+        #[arg(long)]
+        control_socket: Option<PathBuf>,
 
-    let interface_ips = list_interface_ips_result()?;
-    let ip = interface_ips
-        .iter()
-        .find(|item| item.name == interface)
-        .ok_or_else(|| anyhow::anyhow!("No matching interface found: {}", interface))?
-        .ip;
+        /// Write a <file>.oshare.json checksum sidecar for each received file
+        #[arg(long)]
+        sidecar: bool,
 
-    let txt = vec![
-        ("acct_hash".to_string(), cfg.account_hash.clone()),
-        ("dev_id".to_string(), cfg.device_id.clone()),
-        ("fp".to_string(), identity.fingerprint()),
-    ];
+        /// Address to listen on, e.g. an interface IP, "::" or "127.0.0.1"
+        /// (repeatable) [default: listen_addresses from the config, or
+        /// "::", which takes IPv4 as well]
+        #[arg(long)]
+        bind: Vec<std::net::IpAddr>,
 
-    let ann = ServiceAnnouncement {
-        service_type: cfg.service_type.clone(),
-        instance_name: cfg.device_id.clone(),
-        host_name: format!("{}.local.", cfg.device_id),
-        ip_addr: ip.to_string(),
-        port,
-        txt: Some(TxtRecord(txt)),
-    };
+        /// Take every pushed transfer within the limits of the config
+        /// (max_incoming_bytes, blocked_file_types, verified_peers_only)
+        /// instead of asking on the terminal
+        #[arg(long)]
+        auto_accept: bool,
 
-    let announcer = Announcer::register(ann)?;
-    tracing::info!("Announcing: {}", announcer.fullname());
-    println!("✓ Announcing device on {}:{}", ip, port);
-    println!("  Service: {}", announcer.fullname());
+        /// Copy received texts to the clipboard as well as printing them
+        /// (builds with the clipboard feature)
+        #[arg(long)]
+        clipboard: bool,
 
-    if ttl == 0 {
-        println!("  Press Ctrl+C to stop");
-        std::thread::park();
-    } else {
-        tokio::time::sleep(Duration::from_secs(ttl)).await;
-    }
+        /// Take one stream ('send --stdin') or file and write it to
+        /// standard output, then exit; messages go to standard error, and
+        /// the limits of the config decide instead of a question
+        #[arg(long, conflicts_with_all = ["output", "quic", "ws", "control_socket", "sidecar", "clipboard"])]
+        stdout: bool,
+    },
 
-    Ok(())
-}
+    /// Keep listening, announcing and discovering in the background, and
+    /// take requests on a control socket; serves every profile given with
+    /// --profile, each listening on its configured port, writing to its
+    /// configured output_dir and serving its control API on control.sock
+    /// in its data directory. 'daemon install' runs it as a system service
+    /// instead
+    #[command(args_conflicts_with_subcommands = true)]
+    Daemon {
+        #[command(subcommand)]
+        action: Option<DaemonAction>,
 
-async fn discover_devices(
-    cfg: &ClientConfig,
-    interface: &str,
-    timeout: u64,
-    json: bool,
-) -> Result<()> {
-    use mdns_core::{discover::browse_blocking, net::list_interface_ips_result};
+        #[command(flatten)]
+        run: DaemonArgs,
+    },
 
-    let interface_ips = list_interface_ips_result()?;
-    interface_ips
-        .iter()
-        .find(|item| item.name == interface)
-        .ok_or_else(|| anyhow::anyhow!("No matching interface found: {}", interface))?;
+    /// Call a method of the control API of a running 'daemon' and print
+    /// the JSON reply, e.g. 'control send --params {"path": ..., "peer": ...}'
+    Control {
+        /// peers, send, jobs, pause, resume, cancel, prioritize, scheduler
+        /// or watch
+        method: String,
 
-    let results = browse_blocking(&cfg.service_type, Duration::from_secs(timeout), interface)?;
+        /// Parameters as a JSON object
+        #[arg(long)]
+        params: Option<String>,
 
-    if json {
-        println!("{}", serde_json::to_string_pretty(&results)?);
-    } else {
-        println!("Discovered {} device(s):", results.len());
-        for svc in results {
-            println!("\n  {} @ {}:{}", svc.instance_name, svc.host_name, svc.port);
-            println!("    Addresses:");
-            for addr in &svc.addresses {
-                println!("      - {}", addr);
-            }
-            println!("    TXT records:");
-            for (k, v) in &svc.txt {
-                println!("      {} = {}", k, v);
-            }
-        }
-    }
+        /// Control socket [default: control.sock in the data directory]
+        #[arg(long)]
+        socket: Option<PathBuf>,
+    },
 
-    Ok(())
-}
+    /// Show and arrange the jobs of a running 'daemon': what is queued and
+    /// running, their priorities, and how many run at once
+    Queue {
+        #[command(subcommand)]
+        action: Option<QueueAction>,
 
-async fn send_file(
-    identity: &Identity,
-    cfg: &ClientConfig,
-    storage: &LocalStorage,
-    file: &PathBuf,
-    peer: &str,
-) -> Result<()> {
-    use tokio::net::TcpStream;
+        /// Control socket [default: control.sock in the data directory]
+        #[arg(long, global = true)]
+        socket: Option<PathBuf>,
+    },
 
-    println!("Preparing to send: {}", file.display());
+    /// Call a method of a headless peer's daemon over the encrypted
+    /// channel; the peer must trust this device as an admin ('openshare
+    /// trust admin' there)
+    Admin {
+        /// peers, jobs, pause, resume, cancel, prioritize, scheduler, gc or
+        /// limits
+        method: String,
 
-    // Create manifest
-    let mut manifest = Manifest::from_file(file.to_str().unwrap(), cfg.chunk_size)?;
-    manifest.sign(identity)?;
-    println!("  {}", manifest.summary());
+        /// Peer address (host:port)
+        #[arg(long)]
+        peer: String,
 
-    // Store chunks locally first
-    println!("Chunking file...");
-    let mut f = std::fs::File::open(file)?;
-    use std::io::Read;
-    let mut buf = vec![0u8; cfg.chunk_size];
-    let mut stored_chunks = 0;
+        /// Parameters as a JSON object
+        #[arg(long)]
+        params: Option<String>,
 
-    loop {
-        let n = f.read(&mut buf)?;
-        if n == 0 { break; }
-        storage.put_chunk(&buf[..n]).await?;
-        stored_chunks += 1;
-    }
-    println!("  Stored {} chunks locally", stored_chunks);
+        /// Use QUIC instead of TCP
+        #[arg(long)]
+        quic: bool,
+    },
 
-    // Connect to peer
-    println!("Connecting to {}...", peer);
-    let stream = TcpStream::connect(peer).await
-        .context("Failed to connect to peer")?;
-    println!("✓ Connected");
+    /// Print notifications of finished transfers and queued sends from a
+    /// running 'listen' or 'daemon'; with --dir, send new and changed files
+    /// in a folder to a device instead, until interrupted
+    Watch {
+        /// Control socket of the listener [default: control.sock in the
+        /// data directory, as served by 'daemon']
+        #[arg(long, conflicts_with = "dir")]
+        socket: Option<PathBuf>,
 
-    // Create client and send
-    let client = Client::new(identity.clone(), storage.clone(), cfg.clone());
-    client.send_manifest_over(stream, manifest).await?;
+        /// Folder to send new and changed files from
+        #[arg(long, requires = "to")]
+        dir: Option<PathBuf>,
 
-    println!("✓ File sent successfully");
-    Ok(())
-}
+        /// Device to send them to: host:port, or a device ID from the
+        /// content index ('openshare index sync')
+        #[arg(long, requires = "dir")]
+        to: Option<String>,
 
-async fn listen_for_transfers(
-    identity: &Identity,
-    cfg: &ClientConfig,
-    storage: &LocalStorage,
-    output_dir: &PathBuf,
-) -> Result<()> {
-    use tokio::net::TcpListener;
+        /// Skip files whose name or path matches this glob (repeatable)
+        /// [default: hidden files and editor/browser temporary files]
+        #[arg(long, requires = "dir")]
+        ignore: Vec<String>,
 
-    let addr = format!("0.0.0.0:{}", cfg.listen_port);
-    let listener = TcpListener::bind(&addr).await?;
+        /// Seconds a file has to stay unchanged before it is sent
+        #[arg(long, default_value_t = 2, requires = "dir")]
+        debounce: u64,
 
-    println!("✓ Listening on {}", addr);
-    println!("  Output directory: {}", output_dir.display());
-    println!("  Press Ctrl+C to stop");
+        /// Also send the files already in the folder
+        #[arg(long, requires = "dir")]
+        existing: bool,
 
-    loop {
-        let (stream, peer_addr) = listener.accept().await?;
-        println!("\n← Incoming connection from {}", peer_addr);
+        /// Walk the folder every second instead of following change
+        /// notifications, e.g. on a network share
+        #[arg(long, requires = "dir")]
+        poll: bool,
 
-        let identity = identity.clone();
-        let cfg = cfg.clone();
-        let storage = storage.clone();
-        let output_dir = output_dir.clone();
+        /// Use QUIC instead of TCP
+        #[arg(long, requires = "dir")]
+        quic: bool,
 
-        tokio::spawn(async move {
-            if let Err(e) = handle_transfer(identity, cfg, storage, stream, output_dir).await {
-                tracing::error!("Transfer failed: {}", e);
-                println!("✗ Transfer failed: {}", e);
-            }
-        });
-    }
-}
+        /// Only transfers with this peer device ID
+        #[arg(long)]
+        peer: Option<String>,
 
-async fn handle_transfer(
+        /// Only this direction: sent, received, served or fetched
+        #[arg(long)]
+        direction: Option<String>,
+
+        /// Only transfers of at least this many bytes
+        #[arg(long, default_value_t = 0)]
+        min_size: u64,
+
+        /// Output raw JSON lines
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Pair with a new device by comparing a short code on both screens
+    Pair {
+        /// Peer address (host:port); without it, wait for the peer to connect
+        #[arg(long)]
+        peer: Option<String>,
+
+        /// Port to wait on when no --peer is given
+        #[arg(long, default_value_t = 9876)]
+        port: u16,
+    },
+
+    /// Sync content indexes with your other devices (see 'find')
+    Index {
+        #[command(subcommand)]
+        action: IndexAction,
+    },
+
+    /// Find which of your devices holds a file
+    Find {
+        /// File name (or part of it), manifest ID or content hash
+        query: String,
+
+        /// Fetch the first match held by another device, from every
+        /// device holding it at once if there are several
+        #[arg(long)]
+        fetch: bool,
+
+        /// Output directory for --fetch
+        #[arg(long)]
+        output: Option<PathBuf>,
+
+        /// Use QUIC instead of TCP
+        #[arg(long)]
+        quic: bool,
+    },
+
+    /// Fetch shares announced by your devices automatically when they
+    /// match a pattern, e.g. 'subscribe nas:backups/*'; lists the
+    /// subscriptions when no pattern is given
+    Subscribe {
+        /// [peer:]pattern; '*' and '?' match any characters, anything
+        /// else matches names containing it
+        pattern: Option<String>,
+
+        /// Skip shares larger than this many bytes
+        #[arg(long)]
+        max_size: Option<u64>,
+
+        /// Only fetch between these times of day (UTC), e.g. 01:00-06:00;
+        /// shares announced outside the window are fetched once it opens
+        #[arg(long)]
+        window: Option<String>,
+    },
+
+    /// Remove a subscription
+    Unsubscribe {
+        /// [peer:]pattern, as given to 'subscribe'
+        pattern: String,
+    },
+
+    /// Manage pinned peer keys
+    Trust {
+        #[command(subcommand)]
+        action: TrustAction,
+    },
+
+    /// Certify the devices of an account with an account root key, so that
+    /// peers check membership rather than trust a copied account hash
+    Account {
+        #[command(subcommand)]
+        action: AccountAction,
+    },
+
+    /// Summarize lifetime usage from the local transfer history
+    Stats {
+        /// Instead, show trends over this long (e.g. 7d, 12h) from the
+        /// metrics snapshots a running daemon saves: bytes per day, failure
+        /// rate and speed per peer
+        #[arg(long, value_name = "AGE")]
+        since: Option<String>,
+
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// List recorded transfers, completed and failed, newest first
+    History {
+        /// Show at most this many transfers
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+
+        /// Only the transfer with this ID, or a prefix of it, as shown on
+        /// either side
+        #[arg(long = "transfer", value_name = "ID")]
+        transfer_id: Option<String>,
+
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// List, show and delete the manifests this device published
+    Manifest {
+        #[command(subcommand)]
+        action: ManifestAction,
+    },
+
+    /// Inspect and clean up the local chunk store
+    Storage {
+        #[command(subcommand)]
+        action: StorageAction,
+    },
+
+    /// Browse and restore files replaced by received transfers
+    Trash {
+        #[command(subcommand)]
+        action: TrashAction,
+    },
+
+    /// Manage the configuration file
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+
+    /// Manage the identity key
+    Key {
+        #[command(subcommand)]
+        action: KeyAction,
+    },
+
+    /// Move this device's identity, config and trust store to another
+    /// machine
+    Identity {
+        #[command(subcommand)]
+        action: IdentityAction,
+    },
+}
+
+/// A new device identity.
+#[derive(Args, Debug)]
+struct InitArgs {
+    /// Device name/identifier
+    #[arg(long)]
+    device_id: String,
+
+    /// Account identifier (will be hashed for discovery)
+    #[arg(long)]
+    account: String,
+
+    /// Trust unknown peers on first contact
+    #[arg(long)]
+    trust_on_first_use: bool,
+
+    /// Keep the identity key in the OS keystore instead of a file
+    #[arg(long)]
+    keystore: bool,
+
+    /// Key chunk ids with this hex account secret so the chunk store
+    /// does not reveal which files it holds; "new" generates one for
+    /// the first device of the account
+    #[arg(long, value_name = "HEX|new")]
+    chunk_key: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum ProfileAction {
+    /// List profiles; the current one is marked with '*'
+    List,
+
+    /// Create a profile with a new identity, listening on a port no other
+    /// profile uses
+    Create {
+        /// Letters, digits, '-' and '_'
+        name: String,
+
+        #[command(flatten)]
+        init: InitArgs,
+
+        /// Also make it the current profile
+        #[arg(long)]
+        switch: bool,
+    },
+
+    /// Use a profile from now on when none is given with --profile
+    Switch {
+        name: String,
+    },
+}
+
+/// How to run the daemon, whether now or as a service.
+#[derive(Args, Debug, Clone)]
+struct DaemonArgs {
+    /// Announce the profiles and browse for peers on this interface
+    #[arg(long)]
+    interface: Option<String>,
+
+    /// Accept QUIC (UDP) instead of TCP connections; queued sends and
+    /// subscription fetches still connect over TCP
+    #[arg(long)]
+    quic: bool,
+
+    /// Log to this file rather than the terminal, starting a new one when
+    /// it reaches log_max_bytes (see "service" in config.json)
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+
+    /// Run under the Windows service control manager, as 'daemon install'
+    /// sets up
+    #[arg(long, hide = true)]
+    windows_service: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum DaemonAction {
+    /// Run the daemon as a service that starts with the session (or the
+    /// system, on Windows) and restarts as "service" in config.json says:
+    /// a systemd user unit on Linux, a launchd agent on macOS, a Windows
+    /// service on Windows
+    Install {
+        #[command(flatten)]
+        run: DaemonArgs,
+    },
+
+    /// Stop the service and remove it
+    Uninstall,
+
+    /// Show whether the service is installed and running
+    Status,
+}
+
+#[derive(Subcommand, Debug)]
+enum QueueAction {
+    /// List queued, running and recently finished jobs (the default)
+    List,
+
+    /// Queue a file or directory to be sent
+    Add {
+        path: PathBuf,
+
+        /// Device to send it to: a discovered device ID, or host:port
+        #[arg(long)]
+        to: String,
+
+        /// low, normal or high
+        #[arg(long, default_value_t = Priority::Normal)]
+        priority: Priority,
+    },
+
+    /// Move a queued job ahead of or behind the others
+    Priority {
+        job: u64,
+
+        /// low, normal or high
+        priority: Priority,
+    },
+
+    /// Show how many jobs run at once and in what order they start, or
+    /// change it (saved to config.json)
+    Limits {
+        /// Jobs run at once; 0 for no limit
+        #[arg(long)]
+        max_jobs: Option<usize>,
+
+        /// Jobs run at once for any one peer; 0 for no limit
+        #[arg(long)]
+        per_peer: Option<usize>,
+
+        /// fifo, or fair_share to take turns between peers
+        #[arg(long)]
+        order: Option<QueueOrder>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum KeyAction {
+    /// Replace the identity key with a new one, signed by the old key so
+    /// that devices trusting this one follow it when they next connect
+    Rotate,
+}
+
+#[derive(Subcommand, Debug)]
+enum IdentityAction {
+    /// Write the identity key, config and trust store to a file encrypted
+    /// with a passphrase (asked for, or taken from OPENSHARE_PASSPHRASE)
+    Export {
+        /// Bundle to create
+        #[arg(long, short)]
+        output: PathBuf,
+    },
+
+    /// Make this data directory the device in a bundle from 'identity export'
+    Import {
+        /// Bundle to read
+        input: PathBuf,
+
+        /// Replace the identity already here, which is lost unless exported
+        #[arg(long)]
+        force: bool,
+
+        /// Keep the imported key in the OS keystore rather than a file
+        #[arg(long, conflicts_with = "key_file")]
+        keystore: bool,
+
+        /// Keep the imported key in identity.key, even if it was in the
+        /// keystore on the old machine
+        #[arg(long)]
+        key_file: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigAction {
+    /// Upgrade config.json to the current format, keeping a backup
+    Migrate {
+        /// Show the changes without writing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Move the identity key from identity.key into the OS keystore
+    UseKeystore,
+}
+
+#[derive(Subcommand, Debug)]
+enum IndexAction {
+    /// Fetch a device's index of published files
+    Sync {
+        /// Peer address (host:port)
+        #[arg(long)]
+        peer: String,
+
+        /// Use QUIC instead of TCP
+        #[arg(long)]
+        quic: bool,
+    },
+
+    /// List the devices whose indexes are cached
+    List,
+}
+
+#[derive(Subcommand, Debug)]
+enum StorageAction {
+    /// Delete all chunks not needed by published files
+    Gc,
+
+    /// Show how much the chunk store holds
+    Stats,
+
+    /// List stored chunks in id order
+    Ls {
+        /// Start after this chunk id (the last one of the previous page)
+        #[arg(long)]
+        after: Option<String>,
+
+        /// Maximum number of chunks to list
+        #[arg(long, default_value_t = 100)]
+        limit: usize,
+    },
+
+    /// Re-hash every stored chunk and report those that do not match their
+    /// id; exits with 1 if any are found
+    Verify {
+        /// Move corrupt chunks out of the store, into corrupt-chunks/ in
+        /// the data directory
+        #[arg(long, conflicts_with = "delete")]
+        quarantine: bool,
+
+        /// Delete corrupt chunks
+        #[arg(long)]
+        delete: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ManifestAction {
+    /// List published manifests in id order
+    Ls,
+
+    /// Show a published manifest
+    Show {
+        /// Manifest ID, or enough of its start to be unique
+        id: String,
+
+        /// Print the whole manifest as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Stop publishing a manifest; its chunks go at the next 'storage gc'
+    Rm {
+        /// Manifest ID, or enough of its start to be unique
+        id: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum TrashAction {
+    /// List trashed files, oldest first
+    List {
+        /// Output directory the files were received into
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Move a trashed file back to its original place
+    Restore {
+        /// Entry number from 'trash list'
+        id: u64,
+
+        /// Output directory the file was received into
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum AccountAction {
+    /// Create the account root key on this device and certify this device
+    /// with it; from then on only certified peers are accepted
+    Init {
+        /// Days the certificate is valid
+        #[arg(long, default_value_t = 365)]
+        days: u64,
+    },
+
+    /// Certify another device of the account (on the device holding the
+    /// root key); pass the certificate to 'account join' there
+    Certify {
+        #[arg(long)]
+        device_id: String,
+
+        /// The device's full fingerprint (hex public key from 'openshare info')
+        #[arg(long)]
+        key: String,
+
+        /// Days the certificate is valid
+        #[arg(long, default_value_t = 365)]
+        days: u64,
+
+        /// Write the certificate to this file instead of printing it
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+
+    /// Install this device's certificate; from then on only certified
+    /// peers are accepted
+    Join {
+        /// Certificate file from 'account certify'
+        #[arg(long)]
+        cert: PathBuf,
+
+        /// Account root fingerprint, as 'account init' showed it
+        #[arg(long)]
+        root: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum TrustAction {
+    /// Pin a device ID to a public key
+    Add {
+        /// Peer device ID
+        #[arg(long)]
+        device_id: String,
+
+        /// Peer full fingerprint (hex public key from 'openshare info')
+        #[arg(long)]
+        key: String,
+    },
+
+    /// List pinned peers
+    List,
+
+    /// Remove a pinned peer
+    Remove {
+        /// Peer device ID
+        #[arg(long)]
+        device_id: String,
+    },
+
+    /// Let a pinned peer manage this device's daemon remotely ('openshare
+    /// admin'), or with --revoke no longer
+    Admin {
+        /// Peer device ID
+        #[arg(long)]
+        device_id: String,
+
+        #[arg(long)]
+        revoke: bool,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Started again to make a preview (see `openshare_core::preview`)
+    if let Some(code) = openshare_core::preview::run_child() {
+        std::process::exit(code);
+    }
+    let cli = Cli::parse();
+
+    // Determine data directory: that of the profile given, or of the
+    // current one
+    let profiles = Profiles::new(cli.data_dir.unwrap_or_else(|| {
+        dirs::home_dir()
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join(".openshare")
+    }));
+    let profile_dirs = cli.profiles.iter().map(|p| profiles.resolve(p)).collect::<Result<Vec<_>>>()?;
+    let (data_dir, profile_name) = match cli.profiles.as_slice() {
+        [] => {
+            let name = profiles.current()?;
+            (profiles.data_dir(&name)?, Some(name))
+        }
+        [spec, ..] if cli.profiles.len() == 1 || matches!(cli.cmd, Commands::Daemon { .. }) => {
+            (profile_dirs[0].clone(), validate_profile_name(spec).is_ok().then(|| spec.clone()))
+        }
+        _ => anyhow::bail!("Only 'daemon' takes several profiles"),
+    };
+
+    // Initialize logging, to a file for a daemon run as a service
+    match &cli.cmd {
+        Commands::Daemon { action: None, run: DaemonArgs { log_file: Some(path), .. } } => {
+            let service = load_config(&data_dir).map(|cfg| cfg.service).unwrap_or_default();
+            let log = RotatingLog::open(path, &service)
+                .with_context(|| format!("Failed to open log file {}", path.display()))?;
+            fmt()
+                .with_env_filter(EnvFilter::new(&cli.log_level))
+                .with_target(false)
+                .with_ansi(false)
+                .with_writer(Mutex::new(log))
+                .init();
+        }
+        // Standard output carries the stream
+        Commands::Listen { stdout: true, .. } => fmt()
+            .with_env_filter(EnvFilter::new(&cli.log_level))
+            .with_target(false)
+            .with_writer(std::io::stderr)
+            .init(),
+        _ => fmt()
+            .with_env_filter(EnvFilter::new(&cli.log_level))
+            .with_target(false)
+            .with_writer(|| LogWriter)
+            .init(),
+    }
+
+    if let Some(version) = cli.compat {
+        anyhow::ensure!(
+            version <= handshake::PROTOCOL_VERSION,
+            "This build speaks protocol {} at most", handshake::PROTOCOL_VERSION
+        );
+        let _ = COMPAT_VERSION.set(version);
+    }
+
+    let identity_path = data_dir.join("identity.key");
+
+    match cli.cmd {
+        Commands::Init { init } => {
+            let defaults = match &profile_name {
+                Some(name) => profiles.new_config(name)?,
+                None => ClientConfig { data_dir: data_dir.clone(), ..ClientConfig::default() },
+            };
+            init_device(defaults, init)?;
+        }
+
+        Commands::Profile { action: ProfileAction::List } => {
+            for profile in profiles.list()? {
+                let marker = if profile.current { "*" } else { " " };
+                let device = match &profile.config {
+                    Some(cfg) => format!("{} on port {}", cfg.device_id, cfg.listen_port),
+                    None => "not initialized".to_string(),
+                };
+                println!("{} {:<16} {:<28} {}", marker, profile.name, device, profile.data_dir.display());
+            }
+        }
+
+        Commands::Profile { action: ProfileAction::Create { name, init, switch } } => {
+            anyhow::ensure!(!profiles.exists(&name), "Profile {} already exists", name);
+            init_device(profiles.new_config(&name)?, init)?;
+            if switch {
+                profiles.switch(&name)?;
+                println!("✓ Now using profile {}", name);
+            }
+        }
+
+        Commands::Profile { action: ProfileAction::Switch { name } } => {
+            profiles.switch(&name)?;
+            println!("✓ Now using profile {} ({})", name, profiles.data_dir(&name)?.display());
+        }
+
+        Commands::Info => {
+            if !data_dir.join("config.json").exists() {
+                anyhow::bail!("Device not initialized. Run 'openshare init' first.");
+            }
+
+            let identity = load_identity(&data_dir)?;
+            let cfg = load_config(&data_dir)?;
+
+            println!("Device Information:");
+            if let Some(profile) = &cfg.profile {
+                println!("  Profile: {}", profile);
+            }
+            println!("  Device ID: {}", cfg.device_id);
+            println!("  Account hash: {}", cfg.account_hash);
+            println!("  Fingerprint: {}", identity.fingerprint());
+            println!("  Full fingerprint: {}", identity.full_fingerprint());
+            println!("  Data directory: {}", data_dir.display());
+            println!("  Listen port: {}", cfg.listen_port);
+            if !cfg.listen_addresses.is_empty() {
+                let addresses: Vec<String> = cfg.listen_addresses.iter().map(|ip| ip.to_string()).collect();
+                println!("  Listen addresses: {}", addresses.join(", "));
+            }
+            if cfg.api_port != 0 {
+                println!("  HTTP API: 127.0.0.1:{}", cfg.api_port);
+            }
+            println!("  Trust on first use: {}", cfg.trust_on_first_use);
+            println!("  Preserve file metadata: {}", cfg.preserve_metadata);
+            println!("  Key storage: {}", match cfg.key_backend {
+                KeyBackend::File => "file",
+                KeyBackend::Keystore => "OS keystore",
+            });
+            println!("  Chunk encryption: {}", match cfg.storage_encryption {
+                StorageEncryption::Off => "off",
+                StorageEncryption::Identity => "on (identity key)",
+                StorageEncryption::KeyFile => "on (storage.key)",
+            });
+            println!("  Chunk ids: {}", match &cfg.chunk_key {
+                Some(key) => format!("keyed (key {})", key.key_id()),
+                None => "plain SHA-256".to_string(),
+            });
+            println!("  Chunk storage: {}", match &cfg.storage {
+                StorageConfig::Local => "local".to_string(),
+                StorageConfig::Memory => "memory (not persisted)".to_string(),
+                StorageConfig::S3 { endpoint, bucket, prefix, .. } => format!("s3 {}/{}/{}", endpoint.trim_end_matches('/'), bucket, prefix),
+            });
+            println!("  Protocol: {} (v{}, features: {})",
+                handshake::PROTOCOL_VERSION, env!("CARGO_PKG_VERSION"), handshake::FEATURES.join(", "));
+            println!("  Compression: {}", match cfg.compression {
+                Codec::None => "off",
+                Codec::Lz4 => "lz4 (peers that decode it)",
+                #[cfg(feature = "codec-zstd")]
+                Codec::Zstd => "zstd (peers that decode it)",
+            });
+            println!("  Compute threads: {}", if cfg.compute_threads == 0 { "auto".to_string() } else { cfg.compute_threads.to_string() });
+        }
+
+        Commands::Doctor { json } => {
+            let findings = run_doctor(&data_dir).await;
+            if json {
+                let findings: Vec<serde_json::Value> = findings.iter().map(Finding::to_json).collect();
+                println!("{}", serde_json::to_string_pretty(&findings)?);
+            } else {
+                print_findings(&findings);
+            }
+            if findings.iter().any(|f| f.severity == Severity::Fail) {
+                std::process::exit(1);
+            }
+        }
+
+        Commands::Capabilities { peer, quic } => {
+            print_capabilities();
+            if let Some(peer) = peer {
+                let identity = load_identity(&data_dir)
+                    .context("Device not initialized. Run 'openshare init' first.")?;
+                let cfg = load_config(&data_dir)?;
+                let storage = open_storage(&data_dir, &identity, &cfg)?;
+                probe_peer(&identity, &cfg, &storage, &peer, quic).await?;
+            }
+        }
+
+        Commands::Announce { interfaces, port, ttl, service_types } => {
+            let identity = load_identity(&data_dir)
+                .context("Device not initialized. Run 'openshare init' first.")?;
+            let cfg = load_config(&data_dir)?;
+
+            announce_device(&cfg, &identity, &interfaces, port, ttl, &service_types).await?;
+        }
+
+        Commands::Discover { interface, timeout, json, stream, watch, service_types } => {
+            let identity = load_identity(&data_dir)
+                .context("Device not initialized. Run 'openshare init' first.")?;
+            let cfg = load_config(&data_dir)?;
+            if stream {
+                let timeout = (!watch).then(|| Duration::from_secs(timeout));
+                stream_devices(&cfg, &identity, &interface, timeout, &service_types).await?;
+            } else {
+                discover_devices(&cfg, &identity, &interface, timeout, json, &service_types).await?;
+            }
+        }
+
+        Commands::Peers { interface, timeout, json, service_types } => {
+            let identity = load_identity(&data_dir)
+                .context("Device not initialized. Run 'openshare init' first.")?;
+            let cfg = load_config(&data_dir)?;
+            list_peers(&cfg, &identity, &interface, timeout, json, &service_types).await?;
+        }
+
+        Commands::CreateManifest { file, output } => {
+            let identity = load_identity(&data_dir)
+                .context("Device not initialized. Run 'openshare init' first.")?;
+            let cfg = load_config(&data_dir)?;
+
+            let mut manifest = if file.is_dir() {
+                Manifest::from_dir(&file, cfg.chunk_size)?
+            } else {
+                Manifest::from_file(file.to_str().unwrap(), cfg.chunk_size)?
+            };
+
+            manifest.sign(&identity)?;
+
+            let manifest_json = serde_json::to_string_pretty(&manifest)?;
+            std::fs::write(&output, manifest_json)?;
+
+            println!("✓ Manifest created: {}", output.display());
+            println!("  {}", manifest.summary());
+        }
+
+        Commands::VerifyManifest { manifest: manifest_path } => {
+            let manifest_json = std::fs::read_to_string(&manifest_path)?;
+            let manifest: Manifest = serde_json::from_str(&manifest_json)?;
+
+            match manifest.verify() {
+                Ok(_) => println!("✓ Manifest signature is valid"),
+                Err(e) => {
+                    println!("✗ Manifest signature is invalid: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        Commands::VerifySidecar { file } => {
+            let sidecar = Sidecar::load(&file)?;
+            match sidecar.verify(&file) {
+                Ok(()) => {
+                    println!("✓ {} matches its sidecar", file.display());
+                    println!("  {}", sidecar.manifest.summary());
+                    if let Some(fingerprint) = &sidecar.sender_fingerprint {
+                        println!("  Signed by: {}", fingerprint);
+                    }
+                }
+                Err(e) => {
+                    println!("✗ {}: {:#}", file.display(), e);
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        Commands::Send { file, peer, stdin, name, quic, keep_chunks, fingerprint, replicate, chunk_size } => {
+            let identity = load_identity(&data_dir)
+                .context("Device not initialized. Run 'openshare init' first.")?;
+            let mut cfg = load_config(&data_dir)?;
+            override_chunk_size(&mut cfg, chunk_size)?;
+            let storage = open_storage(&data_dir, &identity, &cfg)?;
+
+            if stdin {
+                let peer = peer.expect("clap requires --peer without --replicate");
+                return send_stdin(&identity, &cfg, &storage, &name, &peer, quic).await;
+            }
+
+            if let [_, _, ..] = &file[..] {
+                if replicate.is_some() || keep_chunks || fingerprint.is_some() {
+                    anyhow::bail!("--replicate, --keep-chunks and --fingerprint take a single --file");
+                }
+                let peer = peer.expect("clap requires --peer without --replicate");
+                return send_batch(&identity, &cfg, &storage, &file, &peer, quic).await;
+            }
+            let file = &file[0];
+            match (replicate, peer) {
+                (Some(copies), peer) => replicate_file(&identity, &cfg, &storage, file, peer.as_deref(), copies).await?,
+                (None, Some(peer)) => {
+                    send_file(&identity, &cfg, &storage, file, &peer, quic, keep_chunks, fingerprint.as_deref()).await?
+                }
+                (None, None) => unreachable!("clap requires --peer without --replicate"),
+            }
+        }
+
+        Commands::Sync { file, peer, quic } => {
+            let identity = load_identity(&data_dir)
+                .context("Device not initialized. Run 'openshare init' first.")?;
+            let cfg = load_config(&data_dir)?;
+            let storage = open_storage(&data_dir, &identity, &cfg)?;
+            sync_file(&identity, &cfg, &storage, &file, &peer, quic).await?;
+        }
+
+        Commands::SendText { text, to, clipboard, quic } => {
+            let identity = load_identity(&data_dir)
+                .context("Device not initialized. Run 'openshare init' first.")?;
+            let cfg = load_config(&data_dir)?;
+            let storage = open_storage(&data_dir, &identity, &cfg)?;
+            let text = match text {
+                Some(text) => text,
+                None if clipboard => read_clipboard()?,
+                None => {
+                    let mut text = String::new();
+                    std::io::Read::read_to_string(&mut std::io::stdin(), &mut text)
+                        .context("Failed to read text from standard input")?;
+                    text
+                }
+            };
+            send_text(&identity, &cfg, &storage, &text, &to, quic).await?;
+        }
+
+        Commands::Publish { file, announce, announce_to, chunk_size } => {
+            let identity = load_identity(&data_dir)
+                .context("Device not initialized. Run 'openshare init' first.")?;
+            let mut cfg = load_config(&data_dir)?;
+            override_chunk_size(&mut cfg, chunk_size)?;
+            let storage = open_storage(&data_dir, &identity, &cfg)?;
+
+            let client = Client::new(identity, storage, cfg);
+            let manifest = client.import_file(&file).await?;
+            let id = client.publish(&manifest)?;
+            println!("✓ Published: {}", file.display());
+            println!("  {}", manifest.summary());
+            println!("  Manifest ID: {}", id);
+            if announce || !announce_to.is_empty() {
+                announce_share(&client, &manifest, announce, &announce_to).await?;
+            }
+        }
+
+        Commands::Fetch { peer, manifest_id, output, quic, sidecar, placeholder } => {
+            let identity = load_identity(&data_dir)
+                .context("Device not initialized. Run 'openshare init' first.")?;
+            let mut cfg = load_config(&data_dir)?;
+            cfg.write_sidecars |= sidecar;
+            let storage = open_storage(&data_dir, &identity, &cfg)?;
+
+            let output_dir = output.unwrap_or_else(|| std::env::current_dir().unwrap());
+
+            fetch_file(&identity, &cfg, &storage, &peer, &manifest_id, &output_dir, quic, placeholder).await?;
+        }
+
+        Commands::Offer { file, expires, serve } => {
+            let identity = load_identity(&data_dir)
+                .context("Device not initialized. Run 'openshare init' first.")?;
+            let cfg = load_config(&data_dir)?;
+            let storage = open_storage(&data_dir, &identity, &cfg)?;
+            let expires = parse_age(&expires)?;
+
+            let client = Client::new(identity.clone(), storage, cfg.clone());
+            let manifest = client.import_file(&file).await?;
+            let id = client.publish(&manifest)?;
+            let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs();
+            let mut offers = Offers::load(&data_dir)?;
+            offers.prune(now);
+            offers.add(OfferRecord {
+                manifest_id: id.clone(),
+                filename: manifest.filename.clone(),
+                created_at: now,
+                expires_at: (expires > 0).then(|| now + expires),
+            });
+            offers.save()?;
+            let code = ShareCode::new(&id, &identity.public_key_bytes(), address_hints(&cfg, cfg.listen_port))?;
+
+            println!("✓ Offered: {}", file.display());
+            println!("  {}", manifest.summary());
+            if expires > 0 {
+                println!("  Expires: {} UTC", format_date(now + expires));
+            }
+            println!();
+            println!("  Share code: {}", code);
+            println!();
+            println!("  On the other device: openshare get {}", code);
+            if serve {
+                let profile = Profile::open(&data_dir)?;
+                let daemon = start_daemon(&profile, None)?;
+                println!("Listening on port {}; Ctrl+C to stop", profile.cfg.listen_port);
+                listen_for_transfers(Arc::new(profile), daemon, Transport::Tcp).await?;
+            } else {
+                println!("  Served while the daemon or 'openshare listen' runs on port {}", cfg.listen_port);
+            }
+        }
+
+        Commands::Get { code, output, interface, quic } => {
+            let identity = load_identity(&data_dir)
+                .context("Device not initialized. Run 'openshare init' first.")?;
+            let cfg = load_config(&data_dir)?;
+            let storage = open_storage(&data_dir, &identity, &cfg)?;
+            let code: ShareCode = code.parse()?;
+            let output_dir = output.unwrap_or_else(|| std::env::current_dir().unwrap());
+            get_offer(&identity, &cfg, &storage, &code, &output_dir, interface.as_deref(), quic).await?;
+        }
+
+        Commands::Pin { path, quic } => {
+            let identity = load_identity(&data_dir)
+                .context("Device not initialized. Run 'openshare init' first.")?;
+            let cfg = load_config(&data_dir)?;
+            let storage = open_storage(&data_dir, &identity, &cfg)?;
+            pin_placeholder(&identity, &cfg, &storage, &path, quic).await?;
+        }
+
+        Commands::Cat { path, entry } => {
+            let identity = load_identity(&data_dir)
+                .context("Device not initialized. Run 'openshare init' first.")?;
+            let cfg = load_config(&data_dir)?;
+            let storage = open_storage(&data_dir, &identity, &cfg)?;
+            cat_placeholder(&identity, &cfg, &storage, &path, entry.as_deref()).await?;
+        }
+
+        Commands::Mount { path, manifest, mountpoint } => {
+            let identity = load_identity(&data_dir)
+                .context("Device not initialized. Run 'openshare init' first.")?;
+            let cfg = load_config(&data_dir)?;
+            let storage = open_storage(&data_dir, &identity, &cfg)?;
+            let client = Arc::new(Client::new(identity, storage, cfg.clone()));
+            let view = match (path, manifest) {
+                (Some(path), _) => placeholder_view(&client, &cfg, &path)?,
+                (None, Some(id)) => {
+                    let manifest = client.published(&id)?
+                        .with_context(|| format!("No published manifest {}", id))?;
+                    ShareView::new(manifest, client.storage.clone())
+                }
+                (None, None) => anyhow::bail!("Give --path or --manifest"),
+            };
+            mount_share(view, &mountpoint).await?;
+        }
+
+        Commands::Listen { port, bind, stdout: true, .. } => {
+            let mut profile = Profile::open(&data_dir)?;
+            profile.cfg.listen_port = port;
+            if !bind.is_empty() {
+                profile.cfg.listen_addresses = bind;
+            }
+            receive_to_stdout(&profile).await?;
+        }
+
+        Commands::Listen { port, output, quic, ws, control_socket, sidecar, bind, auto_accept, clipboard, .. } => {
+            let mut profile = Profile::open(&data_dir)?;
+            if !auto_accept {
+                profile.policy = Some(Arc::new(Prompt::new(&profile.cfg)?));
+            }
+            profile.cfg.listen_port = port;
+            if !bind.is_empty() {
+                profile.cfg.listen_addresses = bind;
+            }
+            profile.cfg.write_sidecars |= sidecar;
+            profile.cfg.copy_received_text |= clipboard;
+            if profile.cfg.copy_received_text {
+                // Fail now rather than at the first text
+                check_clipboard()?;
+            }
+            profile.output_dir = output
+                .or_else(|| profile.cfg.output_dir.clone())
+                .unwrap_or_else(|| std::env::current_dir().unwrap());
+
+            let daemon = start_daemon(&profile, control_socket.as_deref())?;
+            if let Some(path) = &control_socket {
+                println!("✓ Control socket: {}", path.display());
+            }
+            if profile.cfg.api_port != 0 {
+                println!("✓ HTTP API: http://127.0.0.1:{}", profile.cfg.api_port);
+            }
+
+            let transport = if quic {
+                Transport::Quic
+            } else if ws {
+                Transport::WebSocket
+            } else {
+                Transport::Tcp
+            };
+            listen_for_transfers(Arc::new(profile), daemon, transport).await?;
+        }
+
+        Commands::Daemon { action: None, run } => {
+            let profiles = if profile_dirs.is_empty() { vec![data_dir] } else { profile_dirs };
+            #[cfg(windows)]
+            if run.windows_service {
+                return win_service::run(profiles, run.interface, run.quic);
+            }
+            #[cfg(not(windows))]
+            anyhow::ensure!(!run.windows_service, "--windows-service only works on Windows");
+            run_daemon(&profiles, run.interface.as_deref(), run.quic).await?;
+        }
+
+        Commands::Daemon { action: Some(DaemonAction::Install { run }), .. } => {
+            install_service(&data_dir, &profile_dirs, &cli.log_level, &run)?;
+        }
+
+        Commands::Daemon { action: Some(DaemonAction::Uninstall), .. } => {
+            uninstall_service()?;
+            println!("✓ Service removed");
+        }
+
+        Commands::Daemon { action: Some(DaemonAction::Status), .. } => {
+            service_status(&data_dir)?;
+        }
+
+        Commands::Control { method, params, socket } => {
+            let socket = socket.unwrap_or_else(|| data_dir.join(CONTROL_SOCKET));
+            let params: serde_json::Value = match params {
+                Some(params) => serde_json::from_str(&params).context("--params is not valid JSON")?,
+                None => serde_json::Value::Null,
+            };
+            call_control(&socket, &method, params).await?;
+        }
+
+        Commands::Queue { action, socket } => {
+            let socket = socket.unwrap_or_else(|| data_dir.join(CONTROL_SOCKET));
+            match action.unwrap_or(QueueAction::List) {
+                QueueAction::List => {
+                    let limits: SchedulerLimits = serde_json::from_value(request_control(&socket, "scheduler", serde_json::Value::Null).await?)?;
+                    let jobs: Vec<Job> = serde_json::from_value(request_control(&socket, "jobs", serde_json::Value::Null).await?)?;
+                    println!("{}", describe_scheduling(&limits));
+                    if jobs.is_empty() {
+                        println!("No jobs");
+                    }
+                    for job in jobs {
+                        let progress = match job.chunks_total {
+                            0 => String::new(),
+                            total => format!("{}/{} chunks", job.chunks_done, total),
+                        };
+                        let state = format!("{:?}", job.state).to_lowercase();
+                        println!("  #{:<4} {:<9} {:<6} {:>14}  {}", job.id, state, job.priority, progress, job);
+                        if let Some(error) = &job.error {
+                            println!("         {}", error);
+                        }
+                    }
+                }
+                QueueAction::Add { path, to, priority } => {
+                    let path = std::path::absolute(&path)
+                        .with_context(|| format!("Failed to resolve {}", path.display()))?;
+                    let params = serde_json::json!({ "path": path, "peer": to, "priority": priority });
+                    let reply = request_control(&socket, "send", params).await?;
+                    println!("✓ Queued job {}", reply["job"]);
+                }
+                QueueAction::Priority { job, priority } => {
+                    let params = serde_json::json!({ "job": job, "priority": priority });
+                    let job: Job = serde_json::from_value(request_control(&socket, "prioritize", params).await?)?;
+                    println!("✓ Job {} ({}) is now {} priority", job.id, job, job.priority);
+                }
+                QueueAction::Limits { max_jobs, per_peer, order } => {
+                    let mut params = serde_json::Map::new();
+                    if let Some(max_jobs) = max_jobs {
+                        params.insert("max_concurrent_jobs".into(), max_jobs.into());
+                    }
+                    if let Some(per_peer) = per_peer {
+                        params.insert("max_jobs_per_peer".into(), per_peer.into());
+                    }
+                    if let Some(order) = order {
+                        params.insert("queue_order".into(), serde_json::to_value(order)?);
+                    }
+                    let changed = !params.is_empty();
+                    let reply = request_control(&socket, "scheduler", params.into()).await?;
+                    let limits: SchedulerLimits = serde_json::from_value(reply)?;
+                    if changed {
+                        print!("✓ ");
+                    }
+                    println!("{}", describe_scheduling(&limits));
+                }
+            }
+        }
+
+        Commands::Watch { dir: Some(dir), to: Some(to), ignore, debounce, existing, poll, quic, .. } => {
+            let identity = load_identity(&data_dir)?;
+            let cfg = load_config(&data_dir)?;
+            let storage = open_storage(&data_dir, &identity, &cfg)?;
+            let mut watcher = Watcher::new(&dir)?.with_debounce(Duration::from_secs(debounce));
+            if !ignore.is_empty() {
+                watcher = watcher.with_ignore(ignore);
+            }
+            if existing {
+                watcher = watcher.with_existing_files();
+            }
+            if poll {
+                watcher = watcher.with_polling();
+            }
+            watch_folder(&identity, &cfg, &storage, watcher, &to, quic).await?;
+        }
+
+        Commands::Watch { socket, peer, direction, min_size, json, .. } => {
+            let socket = socket.unwrap_or_else(|| data_dir.join(CONTROL_SOCKET));
+            let direction = direction
+                .map(|d| serde_json::from_value(serde_json::Value::String(d.clone()))
+                    .with_context(|| format!("Unknown direction '{}'", d)))
+                .transpose()?;
+            let filter = NotificationFilter { peer, direction, min_size };
+            watch_notifications(&socket, &filter, json).await?;
+        }
+
+        Commands::Pair { peer, port } => {
+            let identity = load_identity(&data_dir)
+                .context("Device not initialized. Run 'openshare init' first.")?;
+            let cfg = load_config(&data_dir)?;
+            let storage = open_storage(&data_dir, &identity, &cfg)?;
+            pair_device(&identity, &cfg, &storage, peer.as_deref(), port).await?;
+        }
+
+        Commands::Index { action } => {
+            let identity = load_identity(&data_dir)
+                .context("Device not initialized. Run 'openshare init' first.")?;
+            let cfg = load_config(&data_dir)?;
+            match action {
+                IndexAction::Sync { peer, quic } => {
+                    let storage = open_storage(&data_dir, &identity, &cfg)?;
+                    let device = sync_index(&identity, &cfg, &storage, &peer, quic).await?;
+                    println!("✓ {} shares {} file(s)", device.device_id, device.entries.len());
+                }
+                IndexAction::List => {
+                    let index = ContentIndex::load(&data_dir, &identity)?;
+                    if index.devices.is_empty() {
+                        println!("No indexes yet; run 'openshare index sync --peer <address>'");
+                    }
+                    for device in index.devices.values() {
+                        println!("  {} @ {}  {} file(s), synced {}", device.device_id,
+                            device.address.as_deref().unwrap_or("?"), device.entries.len(),
+                            format_age(device.updated_at));
+                    }
+                }
+            }
+        }
+
+        Commands::Find { query, fetch, output, quic } => {
+            let identity = load_identity(&data_dir)
+                .context("Device not initialized. Run 'openshare init' first.")?;
+            let cfg = load_config(&data_dir)?;
+            let storage = open_storage(&data_dir, &identity, &cfg)?;
+            let output_dir = output.unwrap_or_else(|| std::env::current_dir().unwrap());
+            find_file(&identity, &cfg, &storage, &query, fetch.then_some(output_dir.as_path()), quic).await?;
+        }
+
+        Commands::Trust { action } => {
+            let mut store = TrustStore::load(&data_dir)?;
+            match action {
+                TrustAction::Add { device_id, key } => {
+                    let key = parse_public_key(&key)?;
+                    store.add(&device_id, &key, TrustSource::Manual);
+                    store.save()?;
+                    println!("✓ Trusted {} ({})", device_id, hex::encode(&key[..4]));
+                }
+                TrustAction::List => {
+                    println!("Trusted peers:");
+                    for peer in store.list() {
+                        let admin = if peer.admin { ", admin" } else { "" };
+                        println!("  {} {} ({:?}{})", peer.device_id, peer.public_key, peer.source, admin);
+                    }
+                }
+                TrustAction::Remove { device_id } => {
+                    if store.remove(&device_id) {
+                        store.save()?;
+                        println!("✓ Removed {}", device_id);
+                    } else {
+                        anyhow::bail!("No trusted peer named {}", device_id);
+                    }
+                }
+                TrustAction::Admin { device_id, revoke } => {
+                    if !store.set_admin(&device_id, !revoke) {
+                        anyhow::bail!("No trusted peer named {}; trust it first", device_id);
+                    }
+                    store.save()?;
+                    if revoke {
+                        println!("✓ {} can no longer manage this device", device_id);
+                    } else {
+                        println!("✓ {} may manage this device's daemon", device_id);
+                    }
+                }
+            }
+        }
+
+        Commands::Admin { peer, method, params, quic } => {
+            let identity = load_identity(&data_dir)
+                .context("Device not initialized. Run 'openshare init' first.")?;
+            let cfg = load_config(&data_dir)?;
+            let storage = open_storage(&data_dir, &identity, &cfg)?;
+            let params: serde_json::Value = match params {
+                Some(params) => serde_json::from_str(&params).context("--params is not valid JSON")?,
+                None => serde_json::Value::Null,
+            };
+            let result = administer(&identity, &cfg, &storage, &peer, &method, params, quic).await?;
+            println!("{}", serde_json::to_string_pretty(&result)?);
+        }
+
+        Commands::Subscribe { pattern, max_size, window } => {
+            let cfg_path = data_dir.join("config.json");
+            let mut cfg = load_config(&data_dir)?;
+            let Some(pattern) = pattern else {
+                if cfg.subscriptions.is_empty() {
+                    println!("No subscriptions");
+                }
+                for subscription in &cfg.subscriptions {
+                    print_subscription(subscription);
+                }
+                return Ok(());
+            };
+            let subscription = Subscription {
+                max_size: max_size.unwrap_or(0),
+                window: window.as_deref().map(str::parse).transpose()?,
+                ..Subscription::parse(&pattern)?
+            };
+            // Subscribing again to the same pattern replaces its limits.
+            cfg.subscriptions.retain(|s| s.peer != subscription.peer || s.query != subscription.query);
+            cfg.subscriptions.push(subscription.clone());
+            cfg.save(&cfg_path)?;
+            print!("✓ Subscribed: ");
+            print_subscription(&subscription);
+        }
+
+        Commands::Unsubscribe { pattern } => {
+            let cfg_path = data_dir.join("config.json");
+            let mut cfg = load_config(&data_dir)?;
+            let removed = Subscription::parse(&pattern)?;
+            let before = cfg.subscriptions.len();
+            cfg.subscriptions.retain(|s| s.peer != removed.peer || s.query != removed.query);
+            if cfg.subscriptions.len() == before {
+                anyhow::bail!("No subscription to {}", removed);
+            }
+            cfg.save(&cfg_path)?;
+            println!("✓ Unsubscribed from {}", removed);
+        }
+
+        Commands::Stats { since: Some(age), json } => {
+            let cfg = load_config(&data_dir)?;
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            let since = now.saturating_sub(parse_age(&age)?);
+            let snapshots = MetricsLog::new(&data_dir).load_since(since)?;
+            let trends = Trends::from_snapshots(&snapshots, since, now);
+            if json {
+                println!("{}", serde_json::to_string_pretty(&trends)?);
+            } else {
+                print_trends(&trends, &age, cfg.metrics_snapshot_secs);
+            }
+        }
+
+        Commands::Stats { since: None, json } => {
+            let cfg = load_config(&data_dir)?;
+            let records = History::new(&data_dir).load()?;
+            let stats = UsageStats::from_records(&records);
+            if json {
+                println!("{}", serde_json::to_string_pretty(&stats)?);
+            } else {
+                print_stats(&stats, cfg.record_history);
+            }
+        }
+
+        Commands::History { limit, transfer_id, json } => {
+            let cfg = load_config(&data_dir)?;
+            let mut records = History::new(&data_dir).load()?;
+            if let Some(prefix) = &transfer_id {
+                records.retain(|r| r.transfer_id.is_some_and(|id| id.to_string().starts_with(prefix.as_str())));
+            }
+            records.reverse();
+            records.truncate(limit);
+            if json {
+                println!("{}", serde_json::to_string_pretty(&records)?);
+            } else {
+                if !cfg.record_history {
+                    println!("History recording is off (record_history in config.json)");
+                }
+                if records.is_empty() && transfer_id.is_some() {
+                    println!("No transfer with that ID");
+                } else if records.is_empty() {
+                    println!("No transfers recorded yet");
+                }
+                for record in &records {
+                    print_transfer(record);
+                }
+            }
+        }
+
+        Commands::Storage { action } => {
+            let identity = load_identity(&data_dir)?;
+            let cfg = load_config(&data_dir)?;
+            let storage = open_storage(&data_dir, &identity, &cfg)?;
+            let client = Client::new(identity, storage, cfg);
+            match action {
+                StorageAction::Gc => {
+                    let freed = client.collect_garbage().await?;
+                    println!("✓ Removed {} chunks ({})", freed.chunks, format_bytes(freed.bytes));
+                }
+                StorageAction::Stats => {
+                    let stats = client.storage.stats().await?;
+                    println!("Storage: {}", data_dir.display());
+                    println!("  Chunks:    {} ({})", stats.chunks, format_bytes(stats.bytes));
+                    println!("  Manifests: {} recorded, {} published", stats.manifests, client.published_manifests()?.len());
+                    if stats.referenced_bytes > stats.bytes {
+                        println!("  Dedup:     {} referenced, {} saved", format_bytes(stats.referenced_bytes),
+                            format_bytes(stats.referenced_bytes - stats.bytes));
+                    }
+                    match client.cfg.storage_quota_bytes {
+                        0 => println!("  Quota:     none"),
+                        quota => println!("  Quota:     {} ({:.0}% used)", format_bytes(quota),
+                            stats.bytes as f64 * 100.0 / quota as f64),
+                    }
+                }
+                StorageAction::Ls { after, limit } => {
+                    let chunks = client.storage.describe_chunks(after.as_deref(), limit).await?;
+                    for chunk in &chunks {
+                        println!("{}  {:>10}  {:>8}  {} manifest(s)", chunk.id, format_bytes(chunk.size),
+                            format_age(chunk.created), chunk.manifests);
+                    }
+                    if chunks.len() == limit {
+                        if let Some(last) = chunks.last() {
+                            println!("More: --after {}", last.id);
+                        }
+                    }
+                }
+                StorageAction::Verify { quarantine, delete } => {
+                    let action = match (quarantine, delete) {
+                        (true, _) => CorruptChunks::Quarantine,
+                        (_, true) => CorruptChunks::Delete,
+                        _ => CorruptChunks::Keep,
+                    };
+                    let check = client.verify_storage(action).await?;
+                    for id in &check.corrupt {
+                        println!("✗ Corrupt: {}", id);
+                    }
+                    for (id, reason) in &check.unreadable {
+                        println!("✗ Unreadable: {} ({})", id, reason);
+                    }
+                    if check.is_clean() {
+                        println!("✓ All {} chunks verified", check.checked);
+                        return Ok(());
+                    }
+                    println!("{} of {} chunks corrupt, {} unreadable", check.corrupt.len(), check.checked, check.unreadable.len());
+                    match action {
+                        CorruptChunks::Keep if !check.corrupt.is_empty() =>
+                            println!("  Run again with --quarantine or --delete to take them out of the store"),
+                        CorruptChunks::Quarantine => println!("  Moved {} to {}", check.removed,
+                            data_dir.join(integrity::QUARANTINE_DIR).display()),
+                        CorruptChunks::Delete => println!("  Deleted {}", check.removed),
+                        CorruptChunks::Keep => {}
+                    }
+                    if check.removed > 0 {
+                        println!("  Publish the files using them again, or fetch them, to restore them");
+                    }
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        Commands::Manifest { action } => {
+            let store = ManifestStore::new(&data_dir);
+            match action {
+                ManifestAction::Ls => {
+                    let manifests = store.list()?;
+                    if manifests.is_empty() {
+                        println!("No published manifests");
+                    }
+                    for (id, manifest) in manifests {
+                        println!("  {}  {:>10}  {}", id, format_bytes(manifest.size), manifest.filename);
+                    }
+                }
+                ManifestAction::Show { id, json } => {
+                    let id = store.resolve(&id)?;
+                    let manifest = store.load(&id)?
+                        .with_context(|| format!("Manifest {} was just deleted", id))?;
+                    if json {
+                        println!("{}", serde_json::to_string_pretty(&manifest)?);
+                        return Ok(());
+                    }
+                    println!("Manifest {}", id);
+                    println!("  {}", manifest.summary());
+                    if !manifest.file_hash.is_empty() {
+                        println!("  Content hash: {}", manifest.file_hash);
+                    }
+                    if let Some(key) = &manifest.sender_pubkey {
+                        println!("  Signed by: {}", hex::encode(key));
+                    }
+                    for entry in manifest.files.iter().filter(|e| !e.is_dir) {
+                        println!("  {:>10}  {}", format_bytes(entry.size), entry.path);
+                    }
+                }
+                ManifestAction::Rm { id } => {
+                    let id = store.resolve(&id)?;
+                    store.delete(&id)?;
+                    println!("✓ Deleted manifest {}", id);
+                    println!("  Its chunks go at the next 'openshare storage gc'");
+                }
+            }
+        }
+
+        Commands::Trash { action } => {
+            let cfg = load_config(&data_dir)?;
+            // With the trash turned off, restoring must still not purge anything.
+            let retention = cfg.trash_retention()
+                .unwrap_or(Retention { max_age: Duration::MAX, max_bytes: u64::MAX });
+            match action {
+                TrashAction::List { output } => {
+                    let output_dir = output.unwrap_or_else(|| std::env::current_dir().unwrap());
+                    let entries = Trash::new(&output_dir, retention).list()?;
+                    if entries.is_empty() {
+                        println!("Trash is empty");
+                    }
+                    for entry in entries {
+                        println!("  #{:<4} {:>10}  {}  {}", entry.id, format_bytes(entry.size),
+                            format_age(entry.trashed_at), entry.original.display());
+                    }
+                }
+                TrashAction::Restore { id, output } => {
+                    let output_dir = output.unwrap_or_else(|| std::env::current_dir().unwrap());
+                    let restored = Trash::new(&output_dir, retention).restore(id)?;
+                    println!("✓ Restored {}", restored.display());
+                }
+            }
+        }
+
+        Commands::Config { action } => {
+            let cfg_path = data_dir.join("config.json");
+            if !cfg_path.exists() {
+                anyhow::bail!("Device not initialized. Run 'openshare init' first.");
+            }
+            match action {
+                ConfigAction::Migrate { dry_run } => {
+                    let report = openshare_core::config::migrate_file(&cfg_path, dry_run)?;
+                    if report.from_version == report.to_version {
+                        println!("✓ Config is up to date (version {})", report.to_version);
+                    } else {
+                        println!("Config version {} → {}:", report.from_version, report.to_version);
+                        for change in &report.changes {
+                            println!("  {}", change);
+                        }
+                        match report.backup {
+                            Some(backup) => println!("✓ Migrated (previous file saved as {})", backup.display()),
+                            None => println!("  Dry run, nothing written"),
+                        }
+                    }
+                }
+                ConfigAction::UseKeystore => {
+                    let mut cfg = ClientConfig::load(&cfg_path)?;
+                    if cfg.key_backend == KeyBackend::Keystore {
+                        println!("✓ Identity key is already in the OS keystore");
+                        return Ok(());
+                    }
+                    let identity = Identity::move_to_keystore(&identity_path)?;
+                    cfg.key_backend = KeyBackend::Keystore;
+                    cfg.save(&cfg_path)?;
+                    println!("✓ Identity key moved to the OS keystore ({})", identity.fingerprint());
+                }
+            }
+        }
+
+        Commands::Account { action } => {
+            let cfg_path = data_dir.join("config.json");
+            if !cfg_path.exists() {
+                anyhow::bail!("Device not initialized. Run 'openshare init' first.");
+            }
+            let mut cfg = ClientConfig::load(&cfg_path)?;
+            let root_path = data_dir.join(account::ROOT_KEY_FILE);
+            match action {
+                AccountAction::Init { days } => {
+                    if let Some(root) = &cfg.account_root {
+                        anyhow::bail!("This device already belongs to account {}", root);
+                    }
+                    let root = AccountRoot::generate_and_store(&root_path)?;
+                    let mut identity = load_identity(&data_dir)?;
+                    let cert = root.certify(&cfg.device_id, &identity.public_key_bytes(), Duration::from_secs(days * 86_400));
+                    identity.store_certificate(&identity_path, cert)?;
+                    cfg.account_root = Some(hex::encode(root.public_key_bytes()));
+                    cfg.save(&cfg_path)?;
+                    println!("✓ Created account root {}", hex::encode(root.public_key_bytes()));
+                    println!("  Root key: {} (only needed to certify devices)", root_path.display());
+                    println!("  Certify the other devices here with 'openshare account certify',");
+                    println!("  then run 'openshare account join' on each.");
+                }
+                AccountAction::Certify { device_id, key, days, out } => {
+                    let root = AccountRoot::load(&root_path)
+                        .context("No account root key here; certify on the device that ran 'account init'")?;
+                    let cert = root.certify(&device_id, &parse_public_key(&key)?, Duration::from_secs(days * 86_400));
+                    let json = serde_json::to_string_pretty(&cert)?;
+                    match out {
+                        Some(path) => {
+                            std::fs::write(&path, json)?;
+                            println!("✓ Certified {} until {} ({})", device_id, format_date(cert.expires_at), path.display());
+                        }
+                        None => println!("{}", json),
+                    }
+                }
+                AccountAction::Join { cert, root } => {
+                    let root = parse_public_key(&root)?;
+                    let cert: DeviceCertificate = serde_json::from_slice(&std::fs::read(&cert)?)
+                        .context("Invalid certificate file")?;
+                    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs();
+                    cert.verify(&root, &cfg.device_id, now)?;
+                    let mut identity = load_identity(&data_dir)?;
+                    let expires_at = cert.expires_at;
+                    identity.store_certificate(&identity_path, cert)?;
+                    cfg.account_root = Some(hex::encode(root));
+                    cfg.save(&cfg_path)?;
+                    println!("✓ Joined account {} (certified until {})", hex::encode(root), format_date(expires_at));
+                    println!("  Only peers certified by this account are accepted now.");
+                }
+            }
+        }
+
+        Commands::Key { action } => {
+            let cfg_path = data_dir.join("config.json");
+            if !cfg_path.exists() {
+                anyhow::bail!("Device not initialized. Run 'openshare init' first.");
+            }
+            match action {
+                KeyAction::Rotate => {
+                    let cfg = ClientConfig::load(&cfg_path)?;
+                    if cfg.storage_encryption == StorageEncryption::Identity {
+                        anyhow::bail!(
+                            "Stored chunks are encrypted under the identity key and could not be read after rotating it"
+                        );
+                    }
+                    let identity = Identity::rotate_with(cfg.key_backend, &identity_path)?;
+                    // Sealed under the old key; 'index sync' fetches it again
+                    match std::fs::remove_file(data_dir.join(INDEX_FILE)) {
+                        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                            tracing::warn!("Failed to remove the content index: {}", e);
+                        }
+                        _ => {}
+                    }
+                    let rotation = identity.history.last().expect("a rotated identity has history");
+                    println!("✓ Identity key rotated");
+                    println!("  Old: {}", hex::encode(rotation.old_key));
+                    println!("  New: {}", identity.full_fingerprint());
+                    println!("  Devices that trust this one move their pin when they next connect.");
+                    println!("  Restart a running daemon to use the new key.");
+                }
+            }
+        }
+
+        Commands::Identity { action: IdentityAction::Export { output } } => {
+            let cfg = load_config(&data_dir)?;
+            let bundle = IdentityBundle::collect(&cfg)?;
+            let passphrase = read_passphrase(true)?;
+            let sealed = bundle.seal(&passphrase)?;
+
+            let mut options = std::fs::File::options();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            options.open(&output)
+                .and_then(|mut file| file.write_all(&sealed))
+                .with_context(|| format!("Failed to write {}", output.display()))?;
+
+            println!("✓ Exported {} ({}) to {}", cfg.device_id, bundle.identity().fingerprint(), output.display());
+            println!("  Trusted peers: {}", bundle.trusted_peers.len());
+            println!("  Anyone with this file and the passphrase can act as this device; delete it once imported.");
+            println!("  Import it with 'openshare identity import {}' on the new machine.", output.display());
+        }
+
+        Commands::Identity { action: IdentityAction::Import { input, force, keystore, key_file } } => {
+            if data_dir.join("config.json").exists() && !force {
+                anyhow::bail!(
+                    "{} already holds a device; pass --force to replace it (its identity is lost unless exported)",
+                    data_dir.display()
+                );
+            }
+            let sealed = std::fs::read(&input).with_context(|| format!("Failed to read {}", input.display()))?;
+            let passphrase = read_passphrase(false)?;
+            let mut bundle = IdentityBundle::open(&sealed, &passphrase)?;
+            let backend = match (keystore, key_file) {
+                (true, _) => KeyBackend::Keystore,
+                (_, true) => KeyBackend::File,
+                _ => bundle.config.key_backend,
+            };
+            bundle.config.profile = profile_name.filter(|name| name != DEFAULT_PROFILE);
+            let identity = bundle.identity();
+            let peers = bundle.trusted_peers.len();
+            let cfg = bundle.restore(&data_dir, backend)?;
+            // Sealed under whatever key was here; 'index sync' fetches it again
+            match std::fs::remove_file(data_dir.join(INDEX_FILE)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    tracing::warn!("Failed to remove the content index: {}", e);
+                }
+                _ => {}
+            }
+
+            println!("✓ Imported {} ({})", cfg.device_id, identity.fingerprint());
+            println!("  Data directory: {}", data_dir.display());
+            println!("  Trusted peers: {}", peers);
+            if backend == KeyBackend::Keystore {
+                println!("  Key kept in the OS keystore");
+            }
+            println!("  Stop the daemon on the old machine: peers cannot tell the two apart.");
+        }
+    }
+
+    Ok(())
+}
+
+fn print_subscription(subscription: &Subscription) {
+    let mut limits = Vec::new();
+    if subscription.max_size > 0 {
+        limits.push(format!("up to {}", format_bytes(subscription.max_size)));
+    }
+    if let Some(window) = &subscription.window {
+        limits.push(format!("{} UTC", window));
+    }
+    if limits.is_empty() {
+        println!("{}", subscription);
+    } else {
+        println!("{} ({})", subscription, limits.join(", "));
+    }
+}
+
+fn print_stats(stats: &UsageStats, recording: bool) {
+    println!("Usage (local history only, never reported):");
+    if !recording {
+        println!("  History recording is off (record_history in config.json)");
+    }
+    if stats.transfers == 0 {
+        println!("  No transfers recorded yet");
+        return;
+    }
+    println!("  Transfers: {}", stats.transfers);
+    if stats.failed > 0 {
+        println!("  Failed: {}", stats.failed);
+    }
+    println!("  Sent: {}", format_bytes(stats.bytes_sent));
+    println!("  Received: {}", format_bytes(stats.bytes_received));
+    println!("  Dedup savings: {}", format_bytes(stats.reused_bytes));
+    println!("  Compression savings: {}", format_bytes(stats.compression_saved_bytes));
+    if let Some(speed) = stats.average_speed {
+        println!("  Average speed: {}/s", format_bytes(speed as u64));
+    }
+    println!("  Top peers:");
+    for (peer, bytes) in &stats.top_peers {
+        println!("    {} ({})", peer, format_bytes(*bytes));
+    }
+}
+
+fn print_trends(trends: &Trends, age: &str, snapshot_secs: u64) {
+    println!("Trends over the last {} (daemon metrics snapshots):", age);
+    if snapshot_secs == 0 {
+        println!("  Snapshots are off (metrics_snapshot_secs in config.json)");
+    }
+    let Some(failure_rate) = trends.failure_rate else {
+        println!("  No transfers recorded in that time");
+        return;
+    };
+    println!("  Failure rate: {:.1}%", failure_rate * 100.0);
+    println!("  Per day (UTC):");
+    for day in &trends.days {
+        let date = format_date(day.day);
+        match day.failed {
+            0 => println!("    {}  {:>10}  {} transfers", date, format_bytes(day.bytes), day.transfers),
+            failed => println!("    {}  {:>10}  {} transfers, {} failed", date, format_bytes(day.bytes), day.transfers, failed),
+        }
+    }
+    println!("  Peers:");
+    for peer in &trends.peers {
+        let speed = peer.average_speed.map_or("-".to_string(), |s| format!("{}/s", format_bytes(s as u64)));
+        println!("    {} ({}, {} transfers, {:.1}% failed, {})",
+            peer.peer, format_bytes(peer.bytes), peer.transfers, peer.failure_rate * 100.0, speed);
+        if let Some((first, last)) = peer.speed_change {
+            println!("      speed {}/s → {}/s", format_bytes(first as u64), format_bytes(last as u64));
+        }
+    }
+}
+
+/// One line per transfer, plus the reason for a failed one.
+fn print_transfer(record: &TransferRecord) {
+    let peer = match &record.peer_fingerprint {
+        Some(key) => format!("{} ({})", record.peer, &key[..8.min(key.len())]),
+        None => record.peer.clone(),
+    };
+    println!("  {} {:<8} {:>10}  {:>7}  {}  {}",
+        if record.succeeded() { "✓" } else { "✗" },
+        format!("{:?}", record.direction).to_lowercase(), format_bytes(record.size),
+        format_age(record.finished_at), record.filename, peer);
+    if let Some(id) = &record.transfer_id {
+        println!("      transfer {}", id);
+    }
+    if let Some(error) = &record.error {
+        println!("      {}", error);
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// How long ago a Unix timestamp was, e.g. "3h ago".
+fn format_age(timestamp: u64) -> String {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let secs = now.saturating_sub(timestamp);
+    match secs {
+        0..=59 => format!("{}s ago", secs),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+/// A Unix timestamp as its UTC date, e.g. "2025-03-14".
+fn format_date(timestamp: u64) -> String {
+    // Civil date from days since the epoch (Howard Hinnant's algorithm),
+    // as for S3 request dates
+    let z = (timestamp / 86_400) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Seconds in an age like "7d", "12h", "30m" or "90s".
+fn parse_age(age: &str) -> Result<u64> {
+    let age = age.trim();
+    let (digits, unit) = age.split_at(age.find(|c: char| !c.is_ascii_digit()).unwrap_or(age.len()));
+    let n: u64 = digits.parse().with_context(|| format!("Invalid age {:?}; try 7d or 12h", age))?;
+    let unit = match unit {
+        "d" => 86400,
+        "h" => 3600,
+        "m" => 60,
+        "s" | "" => 1,
+        _ => anyhow::bail!("Invalid age {:?}; try 7d or 12h", age),
+    };
+    Ok(n.saturating_mul(unit))
+}
+
+/// Environment variable holding the passphrase of an identity bundle, for
+/// scripts.
+const PASSPHRASE_ENV: &str = "OPENSHARE_PASSPHRASE";
+
+/// Shortest passphrase accepted for a new bundle.
+const MIN_PASSPHRASE_LEN: usize = 8;
+
+/// The identity bundle passphrase, from [`PASSPHRASE_ENV`] or the
+/// terminal; a new one (`confirm`) is asked for twice.
+fn read_passphrase(confirm: bool) -> Result<String> {
+    let passphrase = match std::env::var(PASSPHRASE_ENV) {
+        Ok(passphrase) => passphrase,
+        Err(_) => {
+            let passphrase = rpassword::prompt_password("Passphrase: ")
+                .with_context(|| format!("Failed to read the passphrase (or set {})", PASSPHRASE_ENV))?;
+            if confirm && rpassword::prompt_password("Repeat passphrase: ")? != passphrase {
+                anyhow::bail!("The passphrases do not match");
+            }
+            passphrase
+        }
+    };
+    if confirm && passphrase.chars().count() < MIN_PASSPHRASE_LEN {
+        anyhow::bail!("Use a passphrase of at least {} characters", MIN_PASSPHRASE_LEN);
+    }
+    Ok(passphrase)
+}
+
+/// Load the identity from wherever the config says it is kept. Without a
+/// config only a key file can hold it; a config that fails to load is an
+/// error rather than a silent fallback to the key file.
+fn load_identity(data_dir: &Path) -> Result<Identity> {
+    let backend = if data_dir.join("config.json").exists() {
+        load_config(data_dir)?.key_backend
+    } else {
+        KeyBackend::File
+    };
+    Identity::load_with(backend, &data_dir.join("identity.key"))
+}
+
+/// The configured chunk store, encrypted at rest if the config asks for it.
+type Store = Arc<dyn Storage>;
+
+fn open_storage(data_dir: &Path, identity: &Identity, cfg: &ClientConfig) -> Result<Store> {
+    let store = cfg.storage.open(data_dir)?;
+    Ok(match cfg.storage_encryption.key(data_dir, identity)? {
+        Some(key) => Arc::new(EncryptedStorage::new(store, &key)),
+        None => store,
+    })
+}
+
+/// Protocol version given with `--compat`, applied to every loaded config.
+static COMPAT_VERSION: std::sync::OnceLock<u16> = std::sync::OnceLock::new();
+
+/// Create an identity and a config from `defaults` in its data directory.
+fn init_device(defaults: ClientConfig, args: InitArgs) -> Result<()> {
+    let InitArgs { device_id, account, trust_on_first_use, keystore, chunk_key } = args;
+    let chunk_key = match chunk_key.as_deref() {
+        None => None,
+        Some("new") => Some(ChunkKey::generate()),
+        Some(hex) => Some(ChunkKey::from_hex(hex)?),
+    };
+    let data_dir = defaults.data_dir.clone();
+    std::fs::create_dir_all(&data_dir)?;
+
+    let key_backend = if keystore { KeyBackend::Keystore } else { KeyBackend::File };
+    let identity = Identity::generate_with(key_backend, &data_dir.join("identity.key"))?;
+
+    // Create config with account hash
+    let account_hash = openshare_core::config::account_hash(&account);
+    let cfg = ClientConfig {
+        device_id: device_id.clone(),
+        account_hash: account_hash.clone(),
+        trust_on_first_use,
+        key_backend,
+        chunk_key,
+        ..defaults
+    };
+
+    cfg.ensure_data_dir()?;
+
+    // Save config
+    cfg.save(&data_dir.join("config.json"))?;
+
+    println!("✓ Device initialized");
+    if let Some(profile) = &cfg.profile {
+        println!("  Profile: {} (listening on port {})", profile, cfg.listen_port);
+    }
+    println!("  Device ID: {}", device_id);
+    println!("  Account: {}", account);
+    println!("  Fingerprint: {}", identity.fingerprint());
+    println!("  Full fingerprint: {}", identity.full_fingerprint());
+    println!("  Data directory: {}", data_dir.display());
+    if let Some(key) = &cfg.chunk_key {
+        println!("  Chunk key: {} (pass it as --chunk-key on the account's other devices)", key.to_hex());
+    }
+    Ok(())
+}
+
+fn load_config(data_dir: &Path) -> Result<ClientConfig> {
+    let cfg_path = data_dir.join("config.json");
+    if !cfg_path.exists() {
+        anyhow::bail!("Device not initialized. Run 'openshare init' first.");
+    }
+
+    let mut cfg = ClientConfig::load(&cfg_path)?;
+    cfg.compat_version = COMPAT_VERSION.get().copied();
+    Ok(cfg)
+}
+
+/// Apply a `--chunk-size` given for one transfer.
+fn override_chunk_size(cfg: &mut ClientConfig, chunk_size: Option<usize>) -> Result<()> {
+    let Some(size) = chunk_size else { return Ok(()) };
+    if !(1..=MAX_FIXED_SIZE).contains(&size) {
+        anyhow::bail!("--chunk-size must be between 1 and {} bytes", MAX_FIXED_SIZE);
+    }
+    cfg.chunk_size = size;
+    cfg.adaptive_chunk_size = false;
+    Ok(())
+}
+
+async fn announce_device(
+    cfg: &ClientConfig,
+    identity: &Identity,
+    interfaces: &[String],
+    port: u16,
+    ttl: u64,
+    extra_types: &[String],
+) -> Result<()> {
+    use mdns_core::net::InterfaceWatcher;
+    use std::sync::mpsc::RecvTimeoutError;
+    use std::time::Instant;
+
+    let discovery = Discovery::new(cfg.clone(), identity);
+    let mut announcement = discovery.announce(interfaces, port, extra_types)?;
+    tracing::info!("Announcing: {}", announcement.fullnames().join(", "));
+    println!("✓ Announcing device on port {}", port);
+    for ip in announcement.addresses() {
+        println!("  Address: {}", ip);
+    }
+    for fullname in announcement.fullnames() {
+        println!("  Service: {}", fullname);
+    }
+
+    if ttl == 0 {
+        println!("  Press Ctrl+C to stop");
+    }
+
+    // Re-register whenever an announced interface changes address so we
+    // never keep advertising a stale IP after a roam or VPN toggle.
+    let (_watcher, changes) = InterfaceWatcher::spawn(Duration::from_secs(5))?;
+    let deadline = (ttl > 0).then(|| Instant::now() + Duration::from_secs(ttl));
+
+    loop {
+        let wait = match deadline {
+            Some(d) => match d.checked_duration_since(Instant::now()) {
+                Some(left) => left,
+                None => break,
+            },
+            None => Duration::from_secs(3600),
+        };
+
+        let change = match changes.recv_timeout(wait) {
+            Ok(change) => change,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        if let Some(addresses) = announcement.apply_change(&change)? {
+            let addresses: Vec<String> = addresses.iter().map(ToString::to_string).collect();
+            tracing::info!("Interfaces changed, re-announced on {}", addresses.join(", "));
+            println!("↻ Addresses changed, announcing on {}", addresses.join(", "));
+        }
+    }
+
+    Ok(())
+}
+
+async fn discover_devices(
+    cfg: &ClientConfig,
+    identity: &Identity,
+    interface: &str,
+    timeout: u64,
+    json: bool,
+    extra_types: &[String],
+) -> Result<()> {
+    check_interface(interface)?;
+    let discovery = Discovery::new(cfg.clone(), identity);
+    discovery.browse(interface, Duration::from_secs(timeout), extra_types).await?;
+    let results = discovery.registry().peers();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&results)?);
+    } else {
+        println!("Discovered {} device(s):", results.len());
+        for peer in results {
+            println!("\n  {} @ {}:{}", peer.device_id(), peer.host_name, peer.port);
+            println!("    Fingerprint: {}", peer.advertisement.fingerprint);
+            println!("    Addresses:");
+            for addr in &peer.addresses {
+                println!("      - {}", addr);
+            }
+            println!("    TXT records:");
+            for (k, v) in &peer.txt {
+                println!("      {} = {}", k, v);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn list_peers(
+    cfg: &ClientConfig,
+    identity: &Identity,
+    interface: &str,
+    timeout: u64,
+    json: bool,
+    extra_types: &[String],
+) -> Result<()> {
+    check_interface(interface)?;
+    let discovery = Discovery::new(cfg.clone(), identity);
+    discovery.browse(interface, Duration::from_secs(timeout), extra_types).await?;
+    let peers = discovery.registry().peers();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&peers)?);
+        return Ok(());
+    }
+    if peers.is_empty() {
+        println!("No devices of this account found on {}", interface);
+    }
+    for peer in peers {
+        let addresses: Vec<String> = peer.addresses.iter()
+            .map(|ip| std::net::SocketAddr::new(*ip, peer.port).to_string())
+            .collect();
+        println!("{:<20} {:<10} {}", peer.device_id(), peer.advertisement.fingerprint, addresses.join(", "));
+    }
+    Ok(())
+}
+
+/// Print a JSON line per device found (and lost) until `timeout`, or
+/// forever. A reader that falls behind slows browsing down rather than
+/// having events buffered for it.
+async fn stream_devices(
+    cfg: &ClientConfig,
+    identity: &Identity,
+    interface: &str,
+    timeout: Option<Duration>,
+    extra_types: &[String],
+) -> Result<()> {
+    check_interface(interface)?;
+    let discovery = Discovery::new(cfg.clone(), identity);
+    let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+    let printer = tokio::spawn(async move {
+        while let Some(event) = rx.recv().await {
+            println!("{}", serde_json::to_string(&event)?);
+        }
+        anyhow::Ok(())
+    });
+    discovery.watch(interface, timeout, extra_types, tx).await?;
+    printer.await?
+}
+
+fn check_interface(interface: &str) -> Result<()> {
+    use mdns_core::net::list_interface_ips_result;
+
+    let interface_ips = list_interface_ips_result()?;
+    interface_ips
+        .iter()
+        .find(|item| item.name == interface)
+        .ok_or_else(|| anyhow::anyhow!("No matching interface found: {}", interface))?;
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn send_file(
+    identity: &Identity,
+    cfg: &ClientConfig,
+    storage: &Store,
+    file: &Path,
+    peer: &str,
+    quic: bool,
+    keep_chunks: bool,
+    fingerprint: Option<&str>,
+) -> Result<()> {
+    println!("Preparing to send: {}", file.display());
+    let client = Client::new(identity.clone(), storage.clone(), cfg.clone())
+        .with_observer(print_transfer_event);
+
+    let handle = cancel_on_ctrl_c();
+
+    // Connect to peer, trying resolved addresses in preference order. Chunks
+    // are streamed straight from the file rather than staged in storage.
+    println!("Connecting to {}...", peer);
+    let (manifest, outcome) = if quic {
+        let mut conn = connect_quic_ranked(identity, peer, cfg.overlay_policy).await
+            .context("Failed to connect to peer")?;
+        println!("✓ Connected (QUIC)");
+        let sent = match fingerprint {
+            Some(fingerprint) => client.send_file_streaming_pinned(&mut conn, file, keep_chunks, fingerprint, &handle).await?,
+            None => client.send_file_streaming_with(&mut conn, file, keep_chunks, &handle).await?,
+        };
+        // Wait for the peer to acknowledge everything before closing.
+        conn.finish().await?;
+        sent
+    } else {
+        let stream = connect_ranked(peer, cfg.overlay_policy).await
+            .context("Failed to connect to peer")?;
+        println!("✓ Connected");
+        match fingerprint {
+            Some(fingerprint) => client.send_file_streaming_pinned(stream, file, keep_chunks, fingerprint, &handle).await?,
+            None => client.send_file_streaming_with(stream, file, keep_chunks, &handle).await?,
+        }
+    };
+
+    println!("  {}", manifest.summary());
+    if let TransferOutcome::Cancelled(cancelled) = outcome {
+        println!("✗ {}", cancelled);
+        println!("  Send it again to continue where it stopped");
+        std::process::exit(130);
+    }
+    if keep_chunks {
+        println!("  Stored {} chunks locally", manifest.chunk_hashes.len());
+    }
+    println!("✓ File sent successfully");
+    Ok(())
+}
+
+/// Send standard input to `to` as it is read, named `name`; see
+/// [`Client::send_stream`].
+async fn send_stdin(identity: &Identity, cfg: &ClientConfig, storage: &Store, name: &str, to: &str, quic: bool) -> Result<()> {
+    let client = Client::new(identity.clone(), storage.clone(), cfg.clone())
+        .with_observer(print_transfer_event);
+    let peer = resolve_device(&client, to)?;
+    let handle = cancel_on_ctrl_c();
+
+    println!("Connecting to {}...", peer);
+    let (manifest, outcome) = if quic {
+        let mut conn = connect_quic_ranked(identity, &peer, cfg.overlay_policy).await
+            .context("Failed to connect to peer")?;
+        println!("✓ Connected (QUIC)");
+        let sent = client.send_stream(&mut conn, tokio::io::stdin(), name, &handle).await?;
+        conn.finish().await?;
+        sent
+    } else {
+        let stream = connect_ranked(&peer, cfg.overlay_policy).await
+            .context("Failed to connect to peer")?;
+        println!("✓ Connected");
+        client.send_stream(stream, tokio::io::stdin(), name, &handle).await?
+    };
+
+    if let TransferOutcome::Cancelled(cancelled) = outcome {
+        println!("✗ {} after {}", cancelled, format_bytes(manifest.size));
+        std::process::exit(130);
+    }
+    println!("  {}", manifest.summary());
+    println!("✓ Stream sent");
+    Ok(())
+}
+
+/// Send `text` inline to `to`; see [`Client::send_text`].
+async fn send_text(identity: &Identity, cfg: &ClientConfig, storage: &Store, text: &str, to: &str, quic: bool) -> Result<()> {
+    let client = Client::new(identity.clone(), storage.clone(), cfg.clone());
+    let peer = resolve_device(&client, to)?;
+    if quic {
+        let mut conn = connect_quic_ranked(identity, &peer, cfg.overlay_policy).await
+            .context("Failed to connect to peer")?;
+        client.send_text(&mut conn, text).await?;
+        conn.finish().await?;
+    } else {
+        let stream = connect_ranked(&peer, cfg.overlay_policy).await
+            .context("Failed to connect to peer")?;
+        client.send_text(stream, text).await?;
+    }
+    println!("✓ Text sent to {} ({})", to, format_bytes(text.len() as u64));
+    Ok(())
+}
+
+#[cfg(feature = "clipboard")]
+fn read_clipboard() -> Result<String> {
+    arboard::Clipboard::new()
+        .and_then(|mut clipboard| clipboard.get_text())
+        .context("Failed to read the clipboard")
+}
+
+#[cfg(feature = "clipboard")]
+fn write_clipboard(text: &str) -> Result<()> {
+    arboard::Clipboard::new()
+        .and_then(|mut clipboard| clipboard.set_text(text))
+        .context("Failed to write the clipboard")
+}
+
+#[cfg(feature = "clipboard")]
+fn check_clipboard() -> Result<()> {
+    arboard::Clipboard::new().map(drop).context("Clipboard unavailable")
+}
+
+#[cfg(not(feature = "clipboard"))]
+fn read_clipboard() -> Result<String> {
+    check_clipboard().map(|()| String::new())
+}
+
+#[cfg(not(feature = "clipboard"))]
+fn write_clipboard(_text: &str) -> Result<()> {
+    check_clipboard()
+}
+
+#[cfg(not(feature = "clipboard"))]
+fn check_clipboard() -> Result<()> {
+    anyhow::bail!("This build has no clipboard support; rebuild with --features clipboard")
+}
+
+/// Send `files` to `peer` in one session; see [`Client::send_batch`].
+async fn send_batch(identity: &Identity, cfg: &ClientConfig, storage: &Store, files: &[PathBuf], peer: &str, quic: bool) -> Result<()> {
+    println!("Preparing to send {} files", files.len());
+    let client = Client::new(identity.clone(), storage.clone(), cfg.clone())
+        .with_observer(print_transfer_event);
+
+    println!("Connecting to {}...", peer);
+    let sent = if quic {
+        let mut conn = connect_quic_ranked(identity, peer, cfg.overlay_policy).await
+            .context("Failed to connect to peer")?;
+        println!("✓ Connected (QUIC)");
+        let sent = client.send_batch(&mut conn, files.to_vec()).await?;
+        conn.finish().await?;
+        sent
+    } else {
+        let stream = connect_ranked(peer, cfg.overlay_policy).await
+            .context("Failed to connect to peer")?;
+        println!("✓ Connected");
+        client.send_batch(stream, files.to_vec()).await?
+    };
+
+    for manifest in &sent {
+        println!("  {}", manifest.summary());
+    }
+    println!("✓ Sent {} of {} files", sent.len(), files.len());
+    Ok(())
+}
+
+/// Send `file` as a delta sync; see [`Client::sync_file`].
+async fn sync_file(identity: &Identity, cfg: &ClientConfig, storage: &Store, file: &Path, peer: &str, quic: bool) -> Result<()> {
+    println!("Preparing to sync: {}", file.display());
+    let client = Client::new(identity.clone(), storage.clone(), cfg.clone())
+        .with_observer(print_transfer_event);
+
+    let handle = cancel_on_ctrl_c();
+
+    println!("Connecting to {}...", peer);
+    let (manifest, outcome) = if quic {
+        let mut conn = connect_quic_ranked(identity, peer, cfg.overlay_policy).await
+            .context("Failed to connect to peer")?;
+        println!("✓ Connected (QUIC)");
+        let synced = client.sync_file(&mut conn, file, &handle).await?;
+        conn.finish().await?;
+        synced
+    } else {
+        let stream = connect_ranked(peer, cfg.overlay_policy).await
+            .context("Failed to connect to peer")?;
+        println!("✓ Connected");
+        client.sync_file(stream, file, &handle).await?
+    };
+
+    println!("  {}", manifest.summary());
+    if let TransferOutcome::Cancelled(cancelled) = outcome {
+        println!("✗ {}", cancelled);
+        println!("  Sync it again to continue where it stopped");
+        std::process::exit(130);
+    }
+    println!("✓ Synced");
+    Ok(())
+}
+
+/// Send `file` to `copies` of the devices of the account, trying `peer`
+/// first; see [`Replicator`].
+async fn replicate_file(
+    identity: &Identity,
+    cfg: &ClientConfig,
+    storage: &Store,
+    file: &Path,
+    peer: Option<&str>,
+    copies: usize,
+) -> Result<()> {
+    println!("Preparing to replicate: {}", file.display());
+    let client = Client::new(identity.clone(), storage.clone(), cfg.clone())
+        .with_observer(print_transfer_event);
+
+    let mut targets: Vec<(String, String)> = peer.map(|p| (p.to_string(), p.to_string())).into_iter().collect();
+    for (device_id, address) in indexed_devices(&client)? {
+        if !targets.iter().any(|(_, a)| *a == address) {
+            targets.push((device_id, address));
+        }
+    }
+    if targets.len() < copies {
+        anyhow::bail!(
+            "{} copies asked for, but only {} device(s) to send to; run 'openshare index sync' or pass --peer",
+            copies, targets.len(),
+        );
+    }
+    println!("Replicating to {} of: {}", copies, targets.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join(", "));
+
+    let policy = cfg.overlay_policy;
+    let targets = targets.into_iter()
+        .map(|(name, address)| (name, move || {
+            let address = address.clone();
+            async move { connect_ranked(&address, policy).await }
+        }))
+        .collect();
+    let handle = cancel_on_ctrl_c();
+    let replication = Replicator::new(Arc::new(client), targets, copies)
+        .with_observer(print_replica)
+        .replicate(file, &handle).await?;
+
+    println!("  {}", replication.manifest.summary());
+    if !replication.is_complete() {
+        println!("✗ Delivered {} of {} copies", replication.copies(), copies);
+        std::process::exit(1);
+    }
+    println!("✓ Replicated to {} devices", replication.copies());
+    Ok(())
+}
+
+/// Send the files `watcher` reports to `to` as they appear or change,
+/// until interrupted. A send that fails is retried with a backoff, so the
+/// peer may come and go; one the peer rejects waits for the file to
+/// change. Files in subfolders are sent by name, as by 'send'.
+async fn watch_folder(
+    identity: &Identity,
+    cfg: &ClientConfig,
+    storage: &Store,
+    mut watcher: Watcher,
+    to: &str,
+    quic: bool,
+) -> Result<()> {
+    let client = Client::new(identity.clone(), storage.clone(), cfg.clone())
+        .with_observer(print_transfer_event);
+    let peer = resolve_device(&client, to)?;
+    println!("Watching {} for files to send to {}; Ctrl-C stops", watcher.root().display(), to);
+
+    let handle = cancel_on_ctrl_c();
+    while !handle.is_aborted() {
+        for path in watcher.poll(std::time::Instant::now())? {
+            if handle.is_aborted() {
+                break;
+            }
+            let name = path.strip_prefix(watcher.root()).unwrap_or(&path).display().to_string();
+            let sent = if quic {
+                async {
+                    let mut conn = connect_quic_ranked(identity, &peer, cfg.overlay_policy).await
+                        .context("Failed to connect to peer")?;
+                    let (_, outcome) = client.send_file_streaming_with(&mut conn, &path, false, &handle).await?;
+                    conn.finish().await?;
+                    anyhow::Ok(outcome)
+                }.await
+            } else {
+                async {
+                    let stream = connect_ranked(&peer, cfg.overlay_policy).await
+                        .context("Failed to connect to peer")?;
+                    let (_, outcome) = client.send_file_streaming_with(stream, &path, false, &handle).await?;
+                    anyhow::Ok(outcome)
+                }.await
+            };
+            match sent {
+                Ok(TransferOutcome::Completed) => {
+                    watcher.sent(&path);
+                    println!("✓ Sent {}", name);
+                }
+                Ok(TransferOutcome::Cancelled(cancelled)) => println!("✗ {}: {}", name, cancelled),
+                Err(e) if matches!(e.downcast_ref(), Some(OpenShareError::Rejected(_))) => {
+                    watcher.sent(&path);
+                    println!("✗ {}: {:#}; sent again once it changes", name, e);
+                }
+                Err(e) => {
+                    let retry = watcher.failed(&path, std::time::Instant::now());
+                    println!("✗ {}: {:#}; retrying in {}s", name, e, retry.as_secs());
+                }
+            }
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+    Ok(())
+}
+
+/// Address of `device`: a trusted device ID from the content index, or
+/// `device` itself as a host:port or WebSocket URL.
+fn resolve_device(client: &Client<Store>, device: &str) -> Result<String> {
+    if let Some((_, address)) = indexed_devices(client)?.into_iter().find(|(id, _)| id == device) {
+        return Ok(address);
+    }
+    let has_port = device.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+    if has_port || device.starts_with("ws://") || device.starts_with("wss://") {
+        return Ok(device.to_string());
+    }
+    anyhow::bail!("{} is not in the content index; run 'openshare index sync' or give host:port", device)
+}
+
+fn print_replica(replica: &Replica) {
+    let error = replica.error.as_deref().map(|e| format!(": {}", e)).unwrap_or_default();
+    match replica.state {
+        ReplicaState::Waiting => {}
+        ReplicaState::Sending => println!("  → {} sending", replica.target),
+        ReplicaState::Done => println!("  ✓ {} has it", replica.target),
+        ReplicaState::Failed => println!("  ✗ {} failed{}", replica.target, error),
+        ReplicaState::Cancelled => println!("  ✗ {} cancelled{}", replica.target, error),
+        ReplicaState::NotNeeded => println!("  - {} not needed", replica.target),
+    }
+}
+
+/// A handle that Ctrl-C aborts, so a send stops cleanly: the receiver is
+/// told and keeps what it has.
+fn cancel_on_ctrl_c() -> TransferHandle {
+    let handle = TransferHandle::new();
+    let abort = handle.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            println!("\nCancelling...");
+            abort.abort();
+        }
+    });
+    handle
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn fetch_file(
+    identity: &Identity,
+    cfg: &ClientConfig,
+    storage: &Store,
+    peer: &str,
+    manifest_id: &str,
+    output_dir: &Path,
+    quic: bool,
+    placeholder: bool,
+) -> Result<()> {
+    let client = Client::new(identity.clone(), storage.clone(), cfg.clone())
+        .with_output_dir(output_dir)
+        .with_observer(print_transfer_event);
+
+    // A placeholder needs only the manifest; chunks come on first read or 'pin'.
+    let indices = placeholder.then(Vec::new);
+    let manifest = pull_from_peer(&client, identity, cfg, peer, manifest_id, indices, quic).await?;
+    println!("  {}", manifest.summary());
+
+    let output_path = manifest.output_path(output_dir)?;
+    if placeholder {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Placeholder::new(peer, manifest, now).write(&output_path)?;
+        println!("✓ Placeholder created: {}", Placeholder::path_for(&output_path).display());
+        println!("  Run 'openshare pin --path {}' to download it", output_path.display());
+        return Ok(());
+    }
+    println!("  Writing to: {}", output_path.display());
+    client.write_file(&manifest, &output_path).await?;
+
+    println!("✓ File fetched: {}", output_path.display());
+    Ok(())
+}
+
+/// Fetch the file behind a share code from the device holding the key in
+/// it: found over mDNS on `interface`, if given, or else at the addresses
+/// in the code.
+async fn get_offer(
+    identity: &Identity,
+    cfg: &ClientConfig,
+    storage: &Store,
+    code: &ShareCode,
+    output_dir: &Path,
+    interface: Option<&str>,
+    quic: bool,
+) -> Result<()> {
+    let fingerprint = code.fingerprint();
+    let mut addresses: Vec<String> = Vec::new();
+    if let Some(interface) = interface {
+        check_interface(interface)?;
+        println!("Looking for {} on {}...", &fingerprint[..MIN_FINGERPRINT_LEN], interface);
+        let discovery = Discovery::new(cfg.clone(), identity);
+        for peer in discovery.browse(interface, Duration::from_secs(3), &[]).await? {
+            if fingerprint.starts_with(&peer.advertisement.fingerprint) {
+                addresses.extend(peer.addresses.iter().map(|ip| SocketAddr::new(*ip, peer.port).to_string()));
+            }
+        }
+    }
+    for hint in &code.hints {
+        let hint = hint.to_string();
+        if !addresses.contains(&hint) {
+            addresses.push(hint);
+        }
+    }
+    if addresses.is_empty() {
+        anyhow::bail!("The code holds no addresses; pass --interface to look the device up over mDNS");
+    }
+
+    let client = Client::new(identity.clone(), storage.clone(), cfg.clone())
+        .with_output_dir(output_dir)
+        .with_observer(print_transfer_event);
+    // Only an address that cannot be reached moves on to the next; once
+    // the device answers, its answer stands.
+    let mut manifest = None;
+    for address in &addresses {
+        println!("Connecting to {}...", address);
+        if quic {
+            let mut conn = match connect_quic_ranked(identity, address, cfg.overlay_policy).await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    println!("  {:#}", e);
+                    continue;
+                }
+            };
+            println!("✓ Connected (QUIC)");
+            manifest = Some(client.request_file_pinned(&mut conn, &code.manifest_id, &fingerprint).await?);
+            conn.finish().await?;
+        } else {
+            let stream = match connect_ranked(address, cfg.overlay_policy).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    println!("  {:#}", e);
+                    continue;
+                }
+            };
+            println!("✓ Connected");
+            manifest = Some(client.request_file_pinned(stream, &code.manifest_id, &fingerprint).await?);
+        }
+        break;
+    }
+    let manifest = manifest.context("The offering device could not be reached; is it listening?")?;
+    println!("  {}", manifest.summary());
+
+    let output_path = manifest.output_path(output_dir)?;
+    println!("  Writing to: {}", output_path.display());
+    client.write_file(&manifest, &output_path).await?;
+    println!("✓ File fetched: {}", output_path.display());
+    Ok(())
+}
+
+/// Pull `manifest_id` (or just `indices` of its chunks) from `peer`.
+async fn pull_from_peer(
+    client: &Client<Store>,
+    identity: &Identity,
+    cfg: &ClientConfig,
+    peer: &str,
+    manifest_id: &str,
+    indices: Option<Vec<u32>>,
+    quic: bool,
+) -> Result<Manifest> {
+    println!("Connecting to {}...", peer);
+    if quic {
+        let mut conn = connect_quic_ranked(identity, peer, cfg.overlay_policy).await
+            .context("Failed to connect to peer")?;
+        println!("✓ Connected (QUIC)");
+        let manifest = match indices {
+            Some(indices) => client.request_chunks(&mut conn, manifest_id, indices).await?,
+            None => client.request_file(&mut conn, manifest_id).await?,
+        };
+        conn.finish().await?;
+        Ok(manifest)
+    } else {
+        let stream = connect_ranked(peer, cfg.overlay_policy).await
+            .context("Failed to connect to peer")?;
+        println!("✓ Connected");
+        match indices {
+            Some(indices) => Ok(client.request_chunks(stream, manifest_id, indices).await?),
+            None => Ok(client.request_file(stream, manifest_id).await?),
+        }
+    }
+}
+
+/// Download whatever a placeholder still lacks and write the share out.
+async fn pin_placeholder(
+    identity: &Identity,
+    cfg: &ClientConfig,
+    storage: &Store,
+    path: &Path,
+    quic: bool,
+) -> Result<()> {
+    let (placeholder, target) = Placeholder::load(path)?;
+    let client = Client::new(identity.clone(), storage.clone(), cfg.clone())
+        .with_observer(print_transfer_event);
+
+    let mut manifest = placeholder.manifest;
+    let missing = client.missing_chunks(&manifest).await?;
+    if !missing.is_empty() {
+        println!("Fetching {} of {} chunks", missing.len(), manifest.chunk_hashes.len());
+        manifest = pull_from_peer(&client, identity, cfg, &placeholder.peer, &placeholder.manifest_id, Some(missing), quic).await?;
+    }
+
+    println!("  Writing to: {}", target.display());
+    client.write_file(&manifest, &target).await?;
+    Placeholder::remove(&target)?;
+    println!("✓ Pinned: {}", target.display());
+    Ok(())
+}
+
+/// Stream a placeholder's content (or one file of it) to stdout, fetching
+/// chunks from the peer as they are read.
+async fn cat_placeholder(
+    identity: &Identity,
+    cfg: &ClientConfig,
+    storage: &Store,
+    path: &Path,
+    entry: Option<&str>,
+) -> Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    let client = Arc::new(Client::new(identity.clone(), storage.clone(), cfg.clone()));
+    let view = placeholder_view(&client, cfg, path)?;
+    let node = match entry {
+        Some(entry) => view.lookup(entry).with_context(|| format!("No entry {} in the share", entry))?,
+        None => view.root(),
+    };
+    if node.kind == NodeKind::Dir {
+        for child in view.read_dir(&node.path)? {
+            println!("{}{}", child.path, if child.kind == NodeKind::Dir { "/" } else { "" });
+        }
+        return Ok(());
+    }
+
+    const READ_SIZE: usize = 1024 * 1024;
+    let mut stdout = tokio::io::stdout();
+    let mut offset = 0;
+    while offset < node.size {
+        let data = view.read(&node.path, offset, READ_SIZE).await?;
+        stdout.write_all(&data).await?;
+        offset += data.len() as u64;
+    }
+    stdout.flush().await?;
+    Ok(())
+}
+
+/// A view of a placeholder's content that fetches missing chunks from its
+/// peer.
+fn placeholder_view(client: &Arc<Client<Store>>, cfg: &ClientConfig, path: &Path) -> Result<ShareView> {
+    let (placeholder, _) = Placeholder::load(path)?;
+    let connect = {
+        let (peer, policy) = (placeholder.peer.clone(), cfg.overlay_policy);
+        move || {
+            let peer = peer.clone();
+            async move { connect_ranked(&peer, policy).await }
+        }
+    };
+    Ok(ShareView::new(placeholder.manifest, client.storage.clone())
+        .with_fetcher(Arc::new(PeerChunkFetcher::new(client.clone(), connect))))
+}
+
+/// Serve `view` on `mountpoint` until Ctrl+C or until it is unmounted.
+#[cfg(all(feature = "fuse", target_os = "linux"))]
+async fn mount_share(view: ShareView, mountpoint: &Path) -> Result<()> {
+    use openshare_core::fuse::Mount;
+
+    let mount = Arc::new(Mount::new(mountpoint)?);
+    println!("✓ Mounted {} on {}; Ctrl+C to unmount", view.manifest().filename, mount.mountpoint().display());
+    let served = tokio::task::spawn_blocking({
+        let (mount, runtime) = (mount.clone(), tokio::runtime::Handle::current());
+        move || mount.serve(&view, &runtime)
+    });
+    tokio::pin!(served);
+    tokio::select! {
+        result = &mut served => result??,
+        _ = tokio::signal::ctrl_c() => {
+            mount.unmount()?;
+            served.await??;
+        }
+    }
+    println!("✓ Unmounted {}", mount.mountpoint().display());
+    Ok(())
+}
+
+#[cfg(not(all(feature = "fuse", target_os = "linux")))]
+async fn mount_share(_view: ShareView, _mountpoint: &Path) -> Result<()> {
+    anyhow::bail!("Mounting needs Linux and a build with the fuse feature")
+}
+
+/// Print what this build supports.
+fn print_capabilities() {
+    let codecs: Vec<&str> = Codec::ALL.iter().map(|c| c.name()).collect();
+    println!("This build (openshare {}):", env!("CARGO_PKG_VERSION"));
+    println!("  Protocol: {} (peers from {} up)", handshake::PROTOCOL_VERSION, handshake::MIN_PROTOCOL_VERSION);
+    if let Some(version) = COMPAT_VERSION.get() {
+        println!("  Speaking: {} (--compat)", version);
+    }
+    println!("  Ciphers: {}", handshake::CIPHER_SUITE.join(", "));
+    println!("  Transports: tcp, quic, websocket");
+    println!("  Compression: {}", codecs.join(", "));
+    println!("  Features: {}", handshake::FEATURES.join(", "));
+}
+
+/// Run `method` on the daemon of `peer`; see [`Client::administer`].
+async fn administer(
+    identity: &Identity,
+    cfg: &ClientConfig,
+    storage: &Store,
+    peer: &str,
+    method: &str,
+    params: serde_json::Value,
+    quic: bool,
+) -> Result<serde_json::Value> {
+    let client = Client::new(identity.clone(), storage.clone(), cfg.clone());
+    if quic {
+        let mut conn = connect_quic_ranked(identity, peer, cfg.overlay_policy).await
+            .context("Failed to connect to peer")?;
+        let result = client.administer(&mut conn, method, params).await?;
+        conn.finish().await?;
+        Ok(result)
+    } else {
+        let stream = connect_ranked(peer, cfg.overlay_policy).await
+            .context("Failed to connect to peer")?;
+        Ok(client.administer(stream, method, params).await?)
+    }
+}
+
+/// Exchange hellos with `peer` and print what it offers next to what the
+/// two builds have in common.
+async fn probe_peer(identity: &Identity, cfg: &ClientConfig, storage: &Store, peer: &str, quic: bool) -> Result<()> {
+    let client = Client::new(identity.clone(), storage.clone(), cfg.clone());
+
+    let info = if quic {
+        let mut conn = connect_quic_ranked(identity, peer, cfg.overlay_policy).await
+            .context("Failed to connect to peer")?;
+        // The probe already finished the stream and saw the peer's end.
+        client.probe(&mut conn).await?
+    } else {
+        let stream = connect_ranked(peer, cfg.overlay_policy).await
+            .context("Failed to connect to peer")?;
+        client.probe(stream).await?
+    };
+
+    // Codecs are only used towards peers that take packed chunk frames.
+    let codecs: Vec<&str> = Codec::ALL.iter()
+        .filter(|c| c.feature().is_none_or(|f| info.supports(handshake::FEATURE_COMPRESSION) && info.supports(f)))
+        .map(|c| c.name())
+        .collect();
+    let unknown: Vec<&str> = info.features.iter()
+        .map(String::as_str)
+        .filter(|f| !handshake::FEATURES.contains(f))
+        .collect();
+    let none = |list: Vec<&str>| if list.is_empty() { "none".to_string() } else { list.join(", ") };
+
+    println!("{} at {} (openshare {}):", info.device_id, peer, info.app_version.as_deref().unwrap_or("unknown"));
+    println!("  Fingerprint: {}", hex::encode(&info.public_key[..4]));
+    println!("  Protocol: {}", info.protocol_version);
+    println!("  Clock: {}", match info.clock_skew_secs {
+        None => "unknown".to_string(),
+        Some(skew) if info.clock_skew_beyond(cfg.clock_skew_tolerance_secs).is_some() => format!(
+            "{}s {} this device (over the {}s tolerance; check the time settings)",
+            skew.unsigned_abs(), if skew > 0 { "ahead of" } else { "behind" }, cfg.clock_skew_tolerance_secs,
+        ),
+        Some(skew) => format!("in sync ({:+}s)", skew),
+    });
+    println!("  Features: {}", none(info.features.iter().map(String::as_str).collect()));
+    println!("In common:");
+    let ours = cfg.compat_version.unwrap_or(handshake::PROTOCOL_VERSION);
+    println!("  Protocol: {}", info.protocol_version.min(ours));
+    println!("  Transport: {}", if quic { "quic" } else { "tcp" });
+    println!("  Compression: {}", codecs.join(", "));
+    println!("  Features: {}", none(info.shared_features()));
+    let (held_back, lacking): (Vec<&str>, Vec<&str>) = info.disabled_features().into_iter()
+        .partition(|f| !handshake::features_at(ours).contains(f));
+    println!("  Off (peer lacks them): {}", none(lacking));
+    if cfg.compat_version.is_some() {
+        println!("  Off (--compat {}): {}", ours, none(held_back));
+    }
+    println!("  Off (unknown to this build): {}", none(unknown));
+    Ok(())
+}
+
+/// How many stored chunks `openshare doctor` reads back and re-hashes.
+const DOCTOR_SAMPLE_CHUNKS: usize = 32;
+
+/// Certificates expiring sooner than this are reported by `doctor`.
+const CERTIFICATE_WARN_SECS: u64 = 14 * 24 * 3600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Severity {
+    Ok,
+    Warn,
+    Fail,
+}
+
+/// One result of `openshare doctor`, with what to do about it.
+#[derive(Debug)]
+struct Finding {
+    check: &'static str,
+    severity: Severity,
+    message: String,
+    fix: Option<String>,
+}
+
+impl Finding {
+    fn ok(check: &'static str, message: impl Into<String>) -> Self {
+        Self { check, severity: Severity::Ok, message: message.into(), fix: None }
+    }
+
+    fn warn(check: &'static str, message: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self { check, severity: Severity::Warn, message: message.into(), fix: Some(fix.into()) }
+    }
+
+    fn fail(check: &'static str, message: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self { check, severity: Severity::Fail, message: message.into(), fix: Some(fix.into()) }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "check": self.check,
+            "status": match self.severity {
+                Severity::Ok => "ok",
+                Severity::Warn => "warn",
+                Severity::Fail => "fail",
+            },
+            "message": self.message,
+            "fix": self.fix,
+        })
+    }
+}
+
+fn print_findings(findings: &[Finding]) {
+    for finding in findings {
+        let mark = match finding.severity {
+            Severity::Ok => "✓",
+            Severity::Warn => "!",
+            Severity::Fail => "✗",
+        };
+        println!("{} {:<9} {}", mark, finding.check, finding.message);
+        if let Some(fix) = &finding.fix {
+            println!("  {:<9}   → {}", "", fix);
+        }
+    }
+    let count = |severity| findings.iter().filter(|f| f.severity == severity).count();
+    match (count(Severity::Fail), count(Severity::Warn)) {
+        (0, 0) => println!("\nAll checks passed"),
+        (failed, warnings) => println!("\n{} problem(s), {} warning(s)", failed, warnings),
+    }
+}
+
+/// Run every `doctor` check that the state of the data directory allows;
+/// checks that need the identity or config are left out when those do
+/// not load.
+async fn run_doctor(data_dir: &Path) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    if !check_data_dir(data_dir, &mut findings) {
+        return findings;
+    }
+    let cfg = check_config(data_dir, &mut findings);
+    let identity = cfg.as_ref().and_then(|cfg| check_identity(data_dir, cfg, &mut findings));
+    if let (Some(cfg), Some(identity)) = (&cfg, &identity) {
+        check_storage(data_dir, identity, cfg, &mut findings).await;
+    }
+    check_mdns(cfg.as_ref(), &mut findings);
+    if let (Some(cfg), Some(identity)) = (&cfg, &identity) {
+        check_listen_port(identity, cfg, &mut findings).await;
+    }
+    findings
+}
+
+/// Whether the data directory exists and is writable, and that neither it
+/// nor the keys in it are open to other users.
+fn check_data_dir(data_dir: &Path, findings: &mut Vec<Finding>) -> bool {
+    const CHECK: &str = "data-dir";
+    if !data_dir.join("config.json").exists() {
+        findings.push(Finding::fail(CHECK, format!("{} holds no device", data_dir.display()),
+            "Run 'openshare init', or pass the right --data-dir"));
+        return false;
+    }
+    let probe = data_dir.join(format!(".doctor-{}", std::process::id()));
+    if let Err(e) = std::fs::write(&probe, b"").and_then(|_| std::fs::remove_file(&probe)) {
+        findings.push(Finding::fail(CHECK, format!("{} is not writable: {}", data_dir.display(), e),
+            format!("Make {} owned and writable by this user", data_dir.display())));
+        return true;
+    }
+
+    let mut private = true;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = |path: &Path| std::fs::metadata(path).ok().map(|m| m.permissions().mode() & 0o777);
+        if let Some(mode) = mode(data_dir).filter(|mode| mode & 0o077 != 0) {
+            private = false;
+            findings.push(Finding::warn(CHECK, format!("{} is open to other users (mode {:o})", data_dir.display(), mode),
+                format!("chmod 700 {}", data_dir.display())));
+        }
+        for name in ["identity.key", openshare_core::encrypted::KEY_FILE] {
+            let path = data_dir.join(name);
+            if let Some(mode) = mode(&path).filter(|mode| mode & 0o077 != 0) {
+                private = false;
+                findings.push(Finding::fail(CHECK, format!("{} is readable by other users (mode {:o})", name, mode),
+                    format!("chmod 600 {}", path.display())));
+            }
+        }
+    }
+    if private {
+        findings.push(Finding::ok(CHECK, format!("{} is writable and private", data_dir.display())));
+    }
+    true
+}
+
+/// Load the config and look for settings that contradict each other.
+fn check_config(data_dir: &Path, findings: &mut Vec<Finding>) -> Option<ClientConfig> {
+    const CHECK: &str = "config";
+    let path = data_dir.join("config.json");
+    let cfg = match load_config(data_dir) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            findings.push(Finding::fail(CHECK, format!("config.json does not load: {:#}", e),
+                format!("Fix {} by hand, or restore config.json.bak next to it", path.display())));
+            return None;
+        }
+    };
+
+    let before = findings.len();
+    match openshare_core::config::migrate_file(&path, true) {
+        Ok(report) if report.from_version < report.to_version => findings.push(Finding::warn(CHECK,
+            format!("config.json is in format {}, this build writes {}", report.from_version, report.to_version),
+            "Run 'openshare config migrate'")),
+        Ok(_) => {}
+        Err(e) => findings.push(Finding::fail(CHECK, format!("{:#}", e), "Run the openshare release that wrote it")),
+    }
+    let canonical = |path: &Path| std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    if canonical(&cfg.data_dir) != canonical(data_dir) {
+        findings.push(Finding::warn(CHECK,
+            format!("data_dir is {}, but the config was found in {}", cfg.data_dir.display(), data_dir.display()),
+            format!("Set data_dir in config.json to {}", data_dir.display())));
+    }
+    if !(1..=MAX_FIXED_SIZE).contains(&cfg.chunk_size) {
+        findings.push(Finding::fail(CHECK, format!("chunk_size {} is out of range", cfg.chunk_size),
+            format!("Set chunk_size in config.json to between 1 and {} (262144 is the default)", MAX_FIXED_SIZE)));
+    }
+    if let Some(root) = &cfg.account_root {
+        if let Err(e) = parse_public_key(root) {
+            findings.push(Finding::fail(CHECK, format!("account_root is invalid: {:#}", e),
+                "Set account_root to the root fingerprint from 'openshare account init', or remove it"));
+        }
+    }
+    if !cfg.subscriptions.is_empty() && !cfg.accept_announcements {
+        findings.push(Finding::warn(CHECK, "Subscriptions are set but accept_announcements is off, so none will fetch",
+            "Set accept_announcements to true, or remove the subscriptions"));
+    }
+    if findings.len() == before {
+        findings.push(Finding::ok(CHECK, "config.json is consistent"));
+    }
+    Some(cfg)
+}
+
+/// Load the identity and check its key history and account certificate.
+fn check_identity(data_dir: &Path, cfg: &ClientConfig, findings: &mut Vec<Finding>) -> Option<Identity> {
+    const CHECK: &str = "identity";
+    let identity = match Identity::load_with(cfg.key_backend, &data_dir.join("identity.key")) {
+        Ok(identity) => identity,
+        Err(e) => {
+            findings.push(Finding::fail(CHECK, format!("The identity key does not load: {:#}", e), match cfg.key_backend {
+                KeyBackend::File => "Restore identity.key from a backup; 'openshare init' makes a new identity that peers must trust again",
+                KeyBackend::Keystore => "Unlock the OS keystore, or set key_backend to \"file\" if the key was moved back to identity.key",
+            }));
+            return None;
+        }
+    };
+
+    let public_key = identity.public_key_bytes();
+    let before = findings.len();
+    if let Err(e) = openshare_core::keys::verify_history(&identity.history, &public_key) {
+        findings.push(Finding::fail(CHECK, format!("The key rotation history is invalid: {:#}", e),
+            "Restore identity.history.json from a backup; peers will not follow the rotation without it"));
+    }
+    if let Some(root) = cfg.account_root.as_deref().and_then(|root| parse_public_key(root).ok()) {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let recertify = "Certify this device again with 'openshare account certify' on the device holding the root key, then 'openshare account join' here";
+        match &identity.certificate {
+            None => findings.push(Finding::fail(CHECK, "account_root is set but this device has no certificate", recertify)),
+            Some(cert) => match cert.verify(&root, &cfg.device_id, now) {
+                Err(e) => findings.push(Finding::fail(CHECK, format!("{:#}", e), recertify)),
+                Ok(()) if !cert.covers(&public_key, &identity.history) => findings.push(Finding::fail(CHECK,
+                    "The account certificate is for another key", recertify)),
+                Ok(()) if cert.expires_at - now < CERTIFICATE_WARN_SECS => findings.push(Finding::warn(CHECK,
+                    format!("The account certificate expires on {}", format_date(cert.expires_at)), recertify)),
+                Ok(()) => {}
+            },
+        }
+    }
+    if findings.len() == before {
+        findings.push(Finding::ok(CHECK, format!("{} ({}) loads from the {}", cfg.device_id, identity.fingerprint(), match cfg.key_backend {
+            KeyBackend::File => "key file",
+            KeyBackend::Keystore => "OS keystore",
+        })));
+    }
+    Some(identity)
+}
+
+/// Read back a sample of stored chunks, starting at a different place
+/// each run, and check that they still hash to their ids.
+async fn check_storage(data_dir: &Path, identity: &Identity, cfg: &ClientConfig, findings: &mut Vec<Finding>) {
+    const CHECK: &str = "storage";
+    let storage = match open_storage(data_dir, identity, cfg) {
+        Ok(storage) => storage,
+        Err(e) => {
+            findings.push(Finding::fail(CHECK, format!("The chunk store does not open: {:#}", e),
+                "Check the storage settings in config.json and that the store is reachable"));
+            return;
+        }
+    };
+    let start = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| format!("{:016x}", d.subsec_nanos() as u64 * 0x9e37_79b9))
+        .unwrap_or_default();
+    let sample = async {
+        let mut ids = storage.list_chunks(Some(&start), DOCTOR_SAMPLE_CHUNKS).await?;
+        if ids.len() < DOCTOR_SAMPLE_CHUNKS {
+            let wrapped = storage.list_chunks(None, DOCTOR_SAMPLE_CHUNKS - ids.len()).await?;
+            ids.extend(wrapped.into_iter().filter(|id| id.as_str() <= start.as_str()));
+        }
+        anyhow::Ok(ids)
+    };
+    let ids = match sample.await {
+        Ok(ids) => ids,
+        Err(e) => {
+            findings.push(Finding::fail(CHECK, format!("The chunk store cannot be listed: {:#}", e),
+                "Check that the store is reachable and readable by this user"));
+            return;
+        }
+    };
+
+    let (mut corrupt, mut unreadable) = (Vec::new(), Vec::new());
+    for id in &ids {
+        match integrity::verify_chunk(&*storage, cfg.chunk_key.as_ref(), id).await {
+            Ok(storage::ChunkHealth::Corrupt { .. }) => corrupt.push(id.as_str()),
+            // Collected since it was listed
+            Ok(_) => {}
+            Err(_) => unreadable.push(id.as_str()),
+        }
+    }
+    let short = |ids: &[&str]| ids.iter().map(|id| &id[..id.len().min(12)]).collect::<Vec<_>>().join(", ");
+    if !corrupt.is_empty() {
+        findings.push(Finding::fail(CHECK,
+            format!("{} of {} sampled chunks do not match their ids: {}", corrupt.len(), ids.len(), short(&corrupt)),
+            "Check the disk for errors, run 'openshare storage verify --quarantine', then publish the files using them again"));
+    }
+    if !unreadable.is_empty() {
+        findings.push(Finding::fail(CHECK,
+            format!("{} of {} sampled chunks cannot be read: {}", unreadable.len(), ids.len(), short(&unreadable)),
+            "If storage_encryption or storage.key changed since they were stored, restore the old setting; otherwise check the disk"));
+    }
+    if corrupt.is_empty() && unreadable.is_empty() {
+        findings.push(Finding::ok(CHECK, match ids.len() {
+            0 => "The chunk store is empty".to_string(),
+            n => format!("{} sampled chunks match their ids", n),
+        }));
+    }
+}
+
+/// Whether an mDNS daemon can start, and there is a network to announce on.
+fn check_mdns(cfg: Option<&ClientConfig>, findings: &mut Vec<Finding>) {
+    const CHECK: &str = "mdns";
+    if let Err(e) = mdns_core::discover::check_daemon() {
+        findings.push(Finding::fail(CHECK, format!("The mDNS daemon does not start: {:#}", e),
+            "Allow multicast DNS (UDP port 5353) on this machine; discovery needs it, direct --peer addresses do not"));
+        return;
+    }
+    let policy = cfg.map(|cfg| cfg.overlay_policy).unwrap_or_default();
+    let interfaces = mdns_core::net::list_interface_ips_result().unwrap_or_default();
+    let mut names: Vec<String> = mdns_core::net::rank_interfaces(&interfaces, policy).into_iter()
+        .filter(|iface| !iface.is_loopback)
+        .map(|iface| iface.name)
+        .collect();
+    names.dedup();
+    if names.is_empty() {
+        findings.push(Finding::warn(CHECK, "The mDNS daemon starts, but there is no network interface to announce on",
+            "Connect to a network (or allow overlay interfaces with overlay_policy) so peers can discover this device"));
+    } else {
+        findings.push(Finding::ok(CHECK, format!("The mDNS daemon starts; announcing on {}", names.join(", "))));
+    }
+}
+
+/// Whether the listen port can be bound and reached on this device's own
+/// addresses. A port already in use is fine if openshare answers on it.
+async fn check_listen_port(identity: &Identity, cfg: &ClientConfig, findings: &mut Vec<Finding>) {
+    const CHECK: &str = "port";
+    let port = cfg.listen_port;
+    let mut listeners = Vec::new();
+    for endpoint in cfg.listen_endpoints() {
+        match tokio::net::TcpListener::bind(endpoint).await {
+            Ok(listener) => listeners.push(listener),
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+                let local = match endpoint.ip() {
+                    ip if ip.is_unspecified() => SocketAddr::from(([127, 0, 0, 1], port)),
+                    _ => endpoint,
+                };
+                let client = Client::new(identity.clone(), Arc::new(storage::MemoryStorage::new()), cfg.clone());
+                let answer = async {
+                    let stream = tokio::net::TcpStream::connect(local).await?;
+                    client.probe(stream).await
+                };
+                match tokio::time::timeout(Duration::from_secs(5), answer).await {
+                    Ok(Ok(info)) => findings.push(Finding::ok(CHECK, format!("Port {} is served by openshare ({})", port, info.device_id))),
+                    _ => findings.push(Finding::fail(CHECK, format!("Port {} is taken by another program", port),
+                        "Stop that program, or set listen_port in config.json to a free port")),
+                }
+                return;
+            }
+            Err(e) => {
+                findings.push(Finding::fail(CHECK, format!("Cannot listen on {}: {}", endpoint, e),
+                    "Set listen_port to a port above 1024, and listen_addresses to addresses of this machine"));
+                return;
+            }
+        }
+    }
+    let accepting: Vec<_> = listeners.into_iter()
+        .map(|listener| tokio::spawn(async move { while listener.accept().await.is_ok() {} }))
+        .collect();
+
+    let interfaces = mdns_core::net::list_interface_ips_result().unwrap_or_default();
+    let targets: Vec<SocketAddr> = mdns_core::net::rank_interfaces(&interfaces, cfg.overlay_policy).into_iter()
+        .map(|iface| iface.ip)
+        // Link-local IPv6 addresses need a scope to dial.
+        .filter(|ip| !ip.is_loopback() && !matches!(ip, std::net::IpAddr::V6(v6) if v6.segments()[0] & 0xffc0 == 0xfe80))
+        .filter(|&ip| cfg.listens_on(ip))
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect();
+    let mut unreachable = Vec::new();
+    for &target in &targets {
+        let connected = tokio::time::timeout(Duration::from_secs(2), tokio::net::TcpStream::connect(target)).await;
+        if !matches!(connected, Ok(Ok(_))) {
+            unreachable.push(target.to_string());
+        }
+    }
+    for task in accepting {
+        task.abort();
+    }
+
+    if targets.is_empty() {
+        findings.push(Finding::warn(CHECK, format!("Port {} is free, but only listened on for this device itself", port),
+            "Add an address of a network interface (or \"::\") to listen_addresses so peers can connect"));
+    } else if !unreachable.is_empty() {
+        findings.push(Finding::fail(CHECK, format!("Port {} cannot be reached on {}", port, unreachable.join(", ")),
+            format!("Allow incoming TCP (and UDP, for --quic) on port {} in the firewall", port)));
+    } else {
+        findings.push(Finding::ok(CHECK, format!("Port {} is free and reachable on {} address(es)", port, targets.len())));
+    }
+}
+
+async fn pair_device(
+    identity: &Identity,
+    cfg: &ClientConfig,
+    storage: &Store,
+    peer: Option<&str>,
+    port: u16,
+) -> Result<()> {
+    let client = Client::new(identity.clone(), storage.clone(), cfg.clone());
+    let pairing = match peer {
+        Some(peer) => {
+            println!("Connecting to {}...", peer);
+            let stream = connect_ranked(peer, cfg.overlay_policy).await
+                .context("Failed to connect to peer")?;
+            client.pair(stream, true).await?
+        }
+        None => {
+            let listener = tokio::net::TcpListener::bind(("0.0.0.0", port)).await?;
+            println!("Waiting for the other device on port {}...", port);
+            println!("  Run there: openshare pair --peer <this-address>:{}", port);
+            let (stream, addr) = listener.accept().await?;
+            println!("✓ Connection from {}", addr);
+            client.pair(Box::pin(stream) as DynStream, false).await?
+        }
+    };
+
+    println!();
+    println!("  Device:      {}", pairing.peer.device_id);
+    println!("  Fingerprint: {}", hex::encode(pairing.peer.public_key));
+    if let Some(pinned) = client.trust.lock().unwrap().get(&pairing.peer.device_id) {
+        if pinned.public_key != hex::encode(pairing.peer.public_key) {
+            println!("⚠ {} is currently pinned to a different key ({})", pairing.peer.device_id, pinned.public_key);
+        }
+    }
+    println!();
+    println!("  Pairing code: {}", pairing.code);
+    println!();
+    print!("Does the other device show the same code? [y/N] ");
+    std::io::stdout().flush()?;
+    let answer = tokio::task::spawn_blocking(|| {
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line).map(|_| line)
+    }).await??;
+    let confirmed = matches!(answer.trim(), "y" | "Y" | "yes");
+
+    let device_id = pairing.peer.device_id.clone();
+    if client.complete_pairing(pairing, confirmed).await? {
+        println!("✓ Paired with {}", device_id);
+    } else if confirmed {
+        anyhow::bail!("Pairing declined on the other device");
+    } else {
+        anyhow::bail!("Pairing cancelled; nothing was saved");
+    }
+    Ok(())
+}
+
+/// Fetch `peer`'s content index and cache it.
+async fn sync_index(
+    identity: &Identity,
+    cfg: &ClientConfig,
+    storage: &Store,
+    peer: &str,
+    quic: bool,
+) -> Result<DeviceIndex> {
+    let client = Client::new(identity.clone(), storage.clone(), cfg.clone())
+        .with_observer(print_transfer_event);
+
+    println!("Connecting to {}...", peer);
+    let mut device = if quic {
+        let mut conn = connect_quic_ranked(identity, peer, cfg.overlay_policy).await
+            .context("Failed to connect to peer")?;
+        let device = client.request_index(&mut conn).await?;
+        conn.finish().await?;
+        device
+    } else {
+        let stream = connect_ranked(peer, cfg.overlay_policy).await
+            .context("Failed to connect to peer")?;
+        client.request_index(stream).await?
+    };
+    device.address = Some(peer.to_string());
+
+    let mut index = ContentIndex::load(&cfg.data_dir, identity)?;
+    index.update(device.clone());
+    index.save()?;
+    Ok(device)
+}
+
+/// Search this device's published files and the cached indexes for
+/// `query`; with `fetch_to`, fetch the first match held elsewhere.
+async fn find_file(
+    identity: &Identity,
+    cfg: &ClientConfig,
+    storage: &Store,
+    query: &str,
+    fetch_to: Option<&Path>,
+    quic: bool,
+) -> Result<()> {
+    let client = Client::new(identity.clone(), storage.clone(), cfg.clone());
+    let local = client.local_index()?;
+    let index = ContentIndex::load(&cfg.data_dir, identity)?;
+
+    let mut found = false;
+    for entry in local.iter().filter(|e| e.matches(query)) {
+        println!("  {} ({})  on this device", entry.filename, format_bytes(entry.size));
+        found = true;
+    }
+    // The first match held elsewhere, and every device holding it
+    let mut remote: Option<(String, Vec<String>)> = None;
+    for (device, entry) in index.search(query).filter(|(d, _)| d.device_id != cfg.device_id) {
+        println!("  {} ({})  on {}, synced {}", entry.filename, format_bytes(entry.size),
+            device.device_id, format_age(device.updated_at));
+        if let Some(address) = &device.address {
+            println!("    openshare fetch --peer {} --manifest-id {}", address, entry.manifest_id);
+            let (manifest_id, holders) = remote.get_or_insert_with(|| (entry.manifest_id.clone(), Vec::new()));
+            if *manifest_id == entry.manifest_id {
+                holders.push(address.clone());
+            }
+        }
+        found = true;
+    }
+    if !found {
+        anyhow::bail!("No device holds '{}' (as of the last 'openshare index sync')", query);
+    }
+
+    if let Some(output_dir) = fetch_to {
+        let (manifest_id, holders) = remote.context("No other device holds a match")?;
+        println!();
+        if holders.len() > 1 && !quic {
+            swarm_fetch(identity, cfg, storage, &holders, &manifest_id, output_dir).await?;
+        } else {
+            fetch_file(identity, cfg, storage, &holders[0], &manifest_id, output_dir, quic, false).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Fetch `manifest_id` from all of `peers` at once, then print how each
+/// of them did.
+async fn swarm_fetch(
+    identity: &Identity,
+    cfg: &ClientConfig,
+    storage: &Store,
+    peers: &[String],
+    manifest_id: &str,
+    output_dir: &Path,
+) -> Result<()> {
+    let client = Arc::new(Client::new(identity.clone(), storage.clone(), cfg.clone())
+        .with_observer(print_transfer_event));
+    let manifest = pull_from_peer(&client, identity, cfg, &peers[0], manifest_id, Some(Vec::new()), false).await?;
+    println!("  {}", manifest.summary());
+
+    let missing: Vec<String> = client.missing_chunks(&manifest).await?.into_iter()
+        .map(|i| manifest.chunk_hashes[i as usize].clone())
+        .collect();
+    let sources = peers.iter().map(|peer| {
+        let (address, policy) = (peer.clone(), cfg.overlay_policy);
+        let connect = move || {
+            let address = address.clone();
+            async move { connect_ranked(&address, policy).await }
+        };
+        (peer.clone(), connect)
+    }).collect();
+    let swarm = SwarmFetcher::new(client.clone(), sources);
+    println!("Fetching {} chunks from {} devices", missing.len(), peers.len());
+    let fetched = swarm.fetch(&manifest, &missing).await;
+    for (peer, score) in swarm.stats() {
+        println!("  {}: {} chunks, {}/s, {:.0}% errors, connect {:.0} ms{}", peer, score.chunks,
+            format_bytes(score.throughput as u64), score.error_rate * 100.0, score.rtt_ms,
+            if score.dropped { " (dropped)" } else { "" });
+    }
+    fetched?;
+
+    let output_path = manifest.output_path(output_dir)?;
+    println!("  Writing to: {}", output_path.display());
+    client.write_file(&manifest, &output_path).await?;
+    println!("✓ File fetched: {}", output_path.display());
+    Ok(())
+}
+
+/// How long to try reaching a device to announce a share to.
+const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Announce a published share to `peers` and, with `indexed`, to every
+/// trusted device with an address in the content index. Devices that are
+/// offline or refuse are reported and skipped.
+async fn announce_share(client: &Client<Store>, manifest: &Manifest, indexed: bool, peers: &[String]) -> Result<()> {
+    // (name to report, address)
+    let mut targets: Vec<(String, String)> = peers.iter().map(|p| (p.clone(), p.clone())).collect();
+    if indexed {
+        for (device_id, address) in indexed_devices(client)? {
+            if !targets.iter().any(|(_, a)| *a == address) {
+                targets.push((device_id, address));
+            }
+        }
+    }
+    if targets.is_empty() {
+        println!("  No devices to announce to; run 'openshare index sync' or pass --announce-to");
+        return Ok(());
+    }
+
+    for (name, address) in targets {
+        let result = async {
+            let stream = tokio::time::timeout(ANNOUNCE_TIMEOUT, connect_ranked(&address, client.cfg.overlay_policy)).await
+                .context("not reachable")??;
+            client.announce(stream, manifest).await
+        }.await;
+        match result {
+            Ok(()) => println!("  ✓ Announced to {}", name),
+            Err(e) => println!("  ✗ {} not notified: {:#}", name, e),
+        }
+    }
+    Ok(())
+}
+
+/// Trusted devices in the content index with a known address, as
+/// (device ID, address).
+fn indexed_devices(client: &Client<Store>) -> Result<Vec<(String, String)>> {
+    let index = ContentIndex::load(&client.cfg.data_dir, &client.identity)?;
+    let trust = client.trust.lock().unwrap();
+    Ok(index.devices.values()
+        .filter(|d| trust.get(&d.device_id).is_some())
+        .filter_map(|d| Some((d.device_id.clone(), d.address.clone()?)))
+        .collect())
+}
+
+/// Show the events of a transfer worth showing: its ID, to look up in
+/// the peer's logs and history, compatibility warnings, and its progress
+/// (see [`Progress`]).
+fn print_transfer_event(event: &TransferEvent) {
+    let progress = progress();
+    match event {
+        TransferEvent::HandshakeComplete { transfer_id, peer, .. } => {
+            let device_id = peer.as_ref().map_or("unknown device", |p| p.device_id.as_str());
+            progress.println(format!("  Transfer {} with {}", transfer_id, device_id));
+        }
+        TransferEvent::CompatibilityWarning { device_id, protocol_version, app_version, disabled_features, .. } => {
+            progress.println(format!(
+                "⚠ {} runs an older OpenShare (protocol {}, app {}); disabled: {}",
+                device_id,
+                protocol_version,
+                app_version.as_deref().unwrap_or("unknown"),
+                disabled_features.join(", "),
+            ));
+        }
+        TransferEvent::ManifestSent { transfer_id, filename, size, .. } => progress.start(*transfer_id, "↑", filename, *size),
+        TransferEvent::ManifestReceived { transfer_id, filename, size, .. } => progress.start(*transfer_id, "↓", filename, *size),
+        TransferEvent::ChunkSent { transfer_id, total, bytes, .. } => progress.advance(*transfer_id, "Sent", *total, *bytes),
+        TransferEvent::ChunkReceived { transfer_id, total, bytes, .. } => progress.advance(*transfer_id, "Received", *total, *bytes),
+        TransferEvent::TransferComplete { transfer_id, .. } => progress.finish(*transfer_id, true),
+        TransferEvent::TransferFailed { transfer_id: Some(transfer_id), .. } => progress.finish(*transfer_id, false),
+        TransferEvent::TransferFailed { transfer_id: None, .. } => {}
+    }
+}
+
+/// Progress bars of running transfers, with percent, throughput and ETA,
+/// drawn on stderr if it is a terminal. Elsewhere they stay hidden and
+/// progress is logged every [`PROGRESS_LOG_CHUNKS`] chunks instead.
+struct Progress {
+    bars: MultiProgress,
+    transfers: Mutex<HashMap<TransferId, TransferBar>>,
+}
+
+/// Chunks between progress lines when there are no bars.
+const PROGRESS_LOG_CHUNKS: usize = 10;
+
+struct TransferBar {
+    bar: ProgressBar,
+    /// Chunks and bytes moved so far
+    chunks: usize,
+    bytes: u64,
+}
+
+fn progress() -> &'static Progress {
+    static PROGRESS: std::sync::OnceLock<Progress> = std::sync::OnceLock::new();
+    PROGRESS.get_or_init(|| Progress {
+        bars: MultiProgress::with_draw_target(ProgressDrawTarget::stderr()),
+        transfers: Mutex::new(HashMap::new()),
+    })
+}
+
+impl Progress {
+    /// Print `line` on stdout without tearing the bars.
+    fn println(&self, line: String) {
+        self.bars.suspend(|| println!("{}", line));
+    }
+
+    /// Show a bar for the file of a transfer (for each file of a batch).
+    fn start(&self, transfer_id: TransferId, arrow: &str, filename: &str, size: u64) {
+        self.finish(transfer_id, false);
+        let bar = self.bars.add(ProgressBar::new(size));
+        bar.set_style(ProgressStyle::with_template(
+            "{msg:24!} [{bar:30}] {percent:>3}% {bytes:>10}/{total_bytes:<10} {bytes_per_sec:>12} ETA {eta}",
+        ).expect("valid template").progress_chars("=> "));
+        bar.set_message(format!("{} {}", arrow, filename));
+        self.transfers.lock().unwrap().insert(transfer_id, TransferBar { bar, chunks: 0, bytes: 0 });
+    }
+
+    /// A chunk of `bytes` went out or came in, of `total` to move.
+    fn advance(&self, transfer_id: TransferId, verb: &str, total: usize, bytes: usize) {
+        let mut transfers = self.transfers.lock().unwrap();
+        let Some(transfer) = transfers.get_mut(&transfer_id) else { return };
+        transfer.chunks += 1;
+        transfer.bytes += bytes as u64;
+        if self.bars.is_hidden() {
+            if transfer.chunks.is_multiple_of(PROGRESS_LOG_CHUNKS) {
+                tracing::info!("{} {}/{} chunks", verb, transfer.chunks, transfer.chunks.max(total));
+            }
+            return;
+        }
+        // The size includes chunks the peer already holds, which are not
+        // moved; the average chunk so far gives what is.
+        let length = match transfer.chunks >= total {
+            true => transfer.bytes,
+            false => transfer.bytes / transfer.chunks as u64 * total as u64,
+        };
+        transfer.bar.set_length(length.max(transfer.bytes));
+        transfer.bar.set_position(transfer.bytes);
+    }
+
+    /// Take down the bar of a transfer, printing its rate if `done`.
+    fn finish(&self, transfer_id: TransferId, done: bool) {
+        let Some(transfer) = self.transfers.lock().unwrap().remove(&transfer_id) else { return };
+        transfer.bar.finish_and_clear();
+        self.bars.remove(&transfer.bar);
+        let secs = transfer.bar.elapsed().as_secs_f64();
+        if done && transfer.bytes > 0 {
+            self.println(format!("  {} in {:.1}s ({}/s)", format_bytes(transfer.bytes), secs,
+                format_bytes((transfer.bytes as f64 / secs.max(0.001)) as u64)));
+        }
+    }
+}
+
+/// Log output on stdout that makes way for the progress bars.
+struct LogWriter;
+
+impl std::io::Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        progress().bars.suspend(|| std::io::stdout().write(buf))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stdout().flush()
+    }
+}
+
+/// Resolve `peer` and order its addresses by the overlay policy.
+async fn resolve_ranked(
+    peer: &str,
+    policy: mdns_core::model::OverlayPolicy,
+) -> Result<Vec<std::net::SocketAddr>> {
+    use mdns_core::net::rank_addresses;
+    use std::net::SocketAddr;
+    use tokio::net::lookup_host;
+
+    let resolved: Vec<SocketAddr> = lookup_host(peer).await?.collect();
+    let port = resolved.first().map(|a| a.port())
+        .ok_or_else(|| anyhow::anyhow!("Could not resolve {}", peer))?;
+    let ips: Vec<_> = resolved.iter().map(|a| a.ip()).collect();
+    let ranked = rank_addresses(&ips, policy);
+    if ranked.is_empty() {
+        anyhow::bail!("All addresses for {} are excluded by overlay policy {:?}", peer, policy);
+    }
+    Ok(ranked.into_iter().map(|ip| SocketAddr::new(ip, port)).collect())
+}
+
+/// Connect to `peer`: a `host:port` over TCP, trying its addresses in
+/// overlay policy order, or a `ws://` or `wss://` URL (e.g. of a relay)
+/// over a WebSocket.
+async fn connect_ranked(
+    peer: &str,
+    policy: mdns_core::model::OverlayPolicy,
+) -> Result<DynStream> {
+    use tokio::net::TcpStream;
+
+    if peer.starts_with("ws://") || peer.starts_with("wss://") {
+        return Ok(Box::pin(WsConnection::connect(peer).await?));
+    }
+    let mut last_err = None;
+    for addr in resolve_ranked(peer, policy).await? {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => return Ok(Box::pin(stream)),
+            Err(e) => {
+                tracing::debug!("Connect to {} failed: {}", addr, e);
+                last_err = Some(e);
+            }
+        }
+    }
+    Err(last_err.expect("at least one address attempted").into())
+}
+
+async fn connect_quic_ranked(
+    identity: &Identity,
+    peer: &str,
+    policy: mdns_core::model::OverlayPolicy,
+) -> Result<QuicConnection> {
+    let secret = identity.secret_bytes();
+
+    let mut last_err = None;
+    for addr in resolve_ranked(peer, policy).await? {
+        match QuicConnection::connect(addr, &secret, None).await {
+            Ok(conn) => return Ok(conn),
+            Err(e) => {
+                tracing::debug!("QUIC connect to {} failed: {}", addr, e);
+                last_err = Some(e);
+            }
+        }
+    }
+    Err(last_err.expect("at least one address attempted"))
+}
+
+/// Default control socket of a profile, inside its data directory.
+const CONTROL_SOCKET: &str = "control.sock";
+
+/// Everything a listener needs to accept transfers for one identity. The
+/// trust store, history and sync records live in `cfg.data_dir`, so
+/// profiles with different data directories share nothing.
+struct Profile {
+    data_dir: PathBuf,
     identity: Identity,
     cfg: ClientConfig,
-    storage: LocalStorage,
-    stream: tokio::net::TcpStream,
+    storage: Store,
     output_dir: PathBuf,
-) -> Result<()> {
-    let client = Client::new(identity, storage.clone(), cfg.clone());
+    hub: NotificationHub,
+    /// Decides on pushed transfers instead of the limits in `cfg`
+    policy: Option<Arc<dyn TransferPolicy>>,
+}
+
+impl Profile {
+    fn open(data_dir: &Path) -> Result<Self> {
+        let identity = load_identity(data_dir)
+            .with_context(|| format!("{} is not initialized. Run 'openshare init' first.", data_dir.display()))?;
+        let cfg = load_config(data_dir)?;
+        let storage = open_storage(data_dir, &identity, &cfg)?;
+        let output_dir = cfg.output_dir.clone().unwrap_or_else(|| data_dir.join("received"));
+        Ok(Self { data_dir: data_dir.to_path_buf(), identity, cfg, storage, output_dir, hub: NotificationHub::new(), policy: None })
+    }
+
+    /// Queue, discovery and control API for this profile, reporting to the
+    /// same hub as its listener. Subscriptions are re-read from its
+    /// config.json, so 'subscribe' applies to a running listener.
+    fn daemon(&self) -> Arc<ProfileDaemon> {
+        let client = Client::new(self.identity.clone(), self.storage.clone(), self.cfg.clone())
+            .with_notifications(self.hub.clone())
+            .with_output_dir(&self.output_dir);
+        let discovery = Discovery::new(self.cfg.clone(), &self.identity);
+        let daemon = Daemon::new(client, discovery, TcpConnector { policy: self.cfg.overlay_policy })
+            .with_config_file(self.data_dir.join("config.json"));
+        Arc::new(daemon)
+    }
+}
+
+type ProfileDaemon = Daemon<Store, TcpConnector>;
+
+/// Dials queued jobs, trying resolved addresses in overlay policy order.
+struct TcpConnector {
+    policy: mdns_core::model::OverlayPolicy,
+}
+
+#[async_trait::async_trait]
+impl Connector for TcpConnector {
+    type Transport = DynStream;
+
+    async fn connect(&self, address: &str) -> Result<DynStream> {
+        connect_ranked(address, self.policy).await
+    }
+}
+
+/// Run the job queue of `profile`, save its metrics snapshots and, with a
+/// `socket`, serve its control API there.
+fn start_daemon(profile: &Profile, socket: Option<&Path>) -> Result<Arc<ProfileDaemon>> {
+    let daemon = profile.daemon();
+    if let Some(socket) = socket {
+        serve_control_socket(socket, daemon.clone())?;
+    }
+    if profile.cfg.api_port != 0 {
+        serve_api(profile, daemon.clone())?;
+    }
+    let jobs = daemon.clone();
+    tokio::spawn(async move { jobs.run_jobs().await }.in_current_span());
+    let metrics = daemon.clone();
+    tokio::spawn(async move { metrics.run_metrics().await }.in_current_span());
+    Ok(daemon)
+}
+
+/// Serve every profile until all of them have stopped. A profile that
+/// fails (say, because its port is taken) is reported and the others keep
+/// running. With an `interface`, each is also announced there and peers
+/// are browsed for, so sends can be queued by device ID.
+async fn run_daemon(data_dirs: &[PathBuf], interface: Option<&str>, quic: bool) -> Result<()> {
+    let mut profiles: Vec<Profile> = Vec::new();
+    for data_dir in data_dirs {
+        let profile = Profile::open(data_dir)?;
+        let clash = profiles.iter().find(|p| {
+            p.cfg.data_dir == profile.cfg.data_dir
+                || p.cfg.listen_port == profile.cfg.listen_port
+                || p.identity.full_fingerprint() == profile.identity.full_fingerprint()
+        });
+        if let Some(other) = clash {
+            anyhow::bail!(
+                "Profiles {} and {} share a data directory, port or identity",
+                other.cfg.data_dir.display(), data_dir.display()
+            );
+        }
+        std::fs::create_dir_all(&profile.output_dir)
+            .with_context(|| format!("Failed to create {}", profile.output_dir.display()))?;
+        profiles.push(profile);
+    }
 
-    println!("  Receiving manifest...");
-    let manifest = client.accept_and_receive(stream).await?;
+    let mut listeners = tokio::task::JoinSet::new();
+    for profile in profiles {
+        let device_id = profile.cfg.device_id.clone();
+        let span = tracing::info_span!("profile", device = %device_id);
+        let socket = profile.cfg.data_dir.join(CONTROL_SOCKET);
+        let daemon = start_daemon(&profile, Some(&socket))?;
+        println!("✓ Profile {} ({})", profile.cfg.device_id, profile.identity.fingerprint());
+        println!("  Control socket: {}", socket.display());
+        if profile.cfg.api_port != 0 {
+            println!("  HTTP API: http://127.0.0.1:{}", profile.cfg.api_port);
+        }
 
-    println!("  {}", manifest.summary());
+        if let Some(interface) = interface {
+            let interface = interface.to_string();
+            let port = profile.cfg.listen_port;
+            let daemon = daemon.clone();
+            println!("  Announcing and discovering on {}", interface);
+            tokio::spawn(
+                async move {
+                    // Transfers by address still work without discovery.
+                    if let Err(e) = daemon.run_discovery(&interface, port).await {
+                        tracing::error!("Discovery stopped: {:#}", e);
+                        println!("✗ Discovery stopped: {:#}", e);
+                    }
+                }
+                .instrument(span.clone()),
+            );
+        }
 
-    // Verify manifest signature
-    manifest.verify().context("Invalid manifest signature")?;
-    println!("  ✓ Signature verified");
+        let transport = if quic { Transport::Quic } else { Transport::Tcp };
+        listeners.spawn(
+            async move { (device_id, listen_for_transfers(Arc::new(profile), daemon, transport).await) }
+                .instrument(span),
+        );
+    }
 
-    // Reconstruct file from chunks
-    let output_path = output_dir.join(&manifest.filename);
-    println!("  Writing to: {}", output_path.display());
+    let mut failed = 0;
+    while let Some(joined) = listeners.join_next().await {
+        let (device_id, result) = joined?;
+        if let Err(e) = result {
+            tracing::error!("Profile {} stopped: {:#}", device_id, e);
+            println!("✗ Profile {} stopped: {:#}", device_id, e);
+            failed += 1;
+        }
+    }
+    // Profiles stop cleanly only on Ctrl+C.
+    if failed > 0 {
+        anyhow::bail!("All profiles stopped ({} failed)", failed);
+    }
+    Ok(())
+}
 
-    use tokio::fs::File;
+/// What a listener accepts connections over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transport {
+    Tcp,
+    Quic,
+    WebSocket,
+}
+
+/// Accept transfers on every listen endpoint of `profile` until one fails
+/// or Ctrl+C, then print how many connections each address family made.
+async fn listen_for_transfers(profile: Arc<Profile>, daemon: Arc<ProfileDaemon>, transport: Transport) -> Result<()> {
+    use socket2::Type;
+
+    // Bind every endpoint before accepting on any, so that one taken
+    // address fails the whole listener.
+    let stats = Arc::new(ListenerStats::default());
+    let mut accepting = tokio::task::JoinSet::new();
+    for endpoint in profile.cfg.listen_endpoints() {
+        let ty = if transport == Transport::Quic { Type::DGRAM } else { Type::STREAM };
+        for (addr, socket) in bind_dual_stack(endpoint, ty)? {
+            let (profile, daemon, stats) = (profile.clone(), daemon.clone(), stats.clone());
+            let families = if is_dual_stack(&socket)? { "IPv4 and IPv6" } else if addr.is_ipv6() { "IPv6" } else { "IPv4" };
+            match transport {
+                Transport::Quic => {
+                    let listener = QuicListener::from_socket(socket.into(), &profile.identity.secret_bytes())?;
+                    println!("✓ Listening on {} (QUIC, {}) as {}", addr, families, profile.cfg.device_id);
+                    accepting.spawn(accept_quic(listener, profile, daemon, stats).in_current_span());
+                }
+                Transport::WebSocket => {
+                    let listener = tokio::net::TcpListener::from_std(socket.into())?;
+                    println!("✓ Listening on {} (WebSocket, {}) as {}", addr, families, profile.cfg.device_id);
+                    accepting.spawn(accept_ws(listener, profile, daemon, stats).in_current_span());
+                }
+                Transport::Tcp => {
+                    let listener = tokio::net::TcpListener::from_std(socket.into())?;
+                    println!("✓ Listening on {} ({}) as {}", addr, families, profile.cfg.device_id);
+                    accepting.spawn(accept_tcp(listener, profile, daemon, stats).in_current_span());
+                }
+            }
+        }
+    }
+    println!("  Output directory: {}", profile.output_dir.display());
+    println!("  Press Ctrl+C to stop");
+
+    // Listeners only stop on an error, which stops the others too.
+    let result = tokio::select! {
+        joined = accepting.join_next() => joined.expect("there is an endpoint")?,
+        _ = shutdown_signal() => Ok(()),
+    };
+    println!("\n{} connections: {}", profile.cfg.device_id, stats);
+    result
+}
+
+/// Take one connection on the endpoints of `profile` and write what it
+/// sends to standard output: a stream as it arrives, or a pushed file
+/// once it is in. Everything else goes to standard error.
+async fn receive_to_stdout(profile: &Profile) -> Result<()> {
     use tokio::io::AsyncWriteExt;
-    let mut outfile = File::create(&output_path).await?;
 
-    for (i, chunk_hash) in manifest.chunk_hashes.iter().enumerate() {
-        if let Some(chunk_data) = storage.get_chunk(chunk_hash).await? {
-            outfile.write_all(&chunk_data).await?;
-            if (i + 1) % 10 == 0 || i + 1 == manifest.chunk_hashes.len() {
-                println!("    Progress: {}/{} chunks", i + 1, manifest.chunk_hashes.len());
+    let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+    let mut accepting = tokio::task::JoinSet::new();
+    for endpoint in profile.cfg.listen_endpoints() {
+        for (addr, socket) in bind_dual_stack(endpoint, socket2::Type::STREAM)? {
+            let listener = tokio::net::TcpListener::from_std(socket.into())?;
+            eprintln!("✓ Listening on {} as {}; the next transfer goes to standard output", addr, profile.cfg.device_id);
+            let tx = tx.clone();
+            accepting.spawn(async move {
+                let accepted = listener.accept().await;
+                let _ = tx.send(accepted).await;
+            });
+        }
+    }
+    drop(tx);
+    let (stream, remote) = rx.recv().await.context("No endpoint to listen on")??;
+    accepting.abort_all();
+    eprintln!("  Connection from {}", remote);
+
+    let client = Client::new(profile.identity.clone(), profile.storage.clone(), profile.cfg.clone())
+        .with_stream_output(tokio::io::stdout());
+    match client.accept(stream).await? {
+        Accepted::Streamed(manifest) => {
+            eprintln!("✓ Streamed {} ({})", manifest.filename, format_bytes(manifest.size));
+        }
+        Accepted::Received(manifest) => {
+            manifest.verify().context("Invalid manifest signature")?;
+            if manifest.is_directory() {
+                anyhow::bail!("{} is a directory; receive it without --stdout", manifest.filename);
             }
-        } else {
-            anyhow::bail!("Missing chunk: {}", chunk_hash);
+            let mut stdout = tokio::io::stdout();
+            for hash in &manifest.chunk_hashes {
+                let data = client.storage.get_chunk(hash).await?
+                    .with_context(|| format!("Chunk {} missing locally", hash))?;
+                stdout.write_all(&data).await?;
+            }
+            stdout.flush().await?;
+            eprintln!("✓ Received {} ({})", manifest.filename, format_bytes(manifest.size));
+        }
+        _ => anyhow::bail!("{} sent nothing to write out", remote),
+    }
+    Ok(())
+}
+
+/// Sockets listening on `addr`. "::" takes IPv4 as well, on one socket
+/// where the system can map IPv4 onto IPv6 (this is off by default on
+/// Windows and some BSDs), and on a second one on 0.0.0.0 where it
+/// cannot or has no IPv6 at all.
+fn bind_dual_stack(addr: SocketAddr, ty: socket2::Type) -> Result<Vec<(SocketAddr, socket2::Socket)>> {
+    if !(addr.is_ipv6() && addr.ip().is_unspecified()) {
+        return Ok(vec![(addr, bind_socket(addr, ty, None)?)]);
+    }
+    match bind_socket(addr, ty, Some(false)) {
+        Ok(socket) => return Ok(vec![(addr, socket)]),
+        Err(e) => tracing::debug!("No dual-stack socket on {}: {:#}", addr, e),
+    }
+    let ipv4 = SocketAddr::new(std::net::Ipv4Addr::UNSPECIFIED.into(), addr.port());
+    let mut sockets = vec![(ipv4, bind_socket(ipv4, ty, None)?)];
+    match bind_socket(addr, ty, Some(true)) {
+        Ok(socket) => sockets.push((addr, socket)),
+        Err(e) => tracing::warn!("Not listening on IPv6: {:#}", e),
+    }
+    Ok(sockets)
+}
+
+/// A non-blocking socket bound to `addr`, listening if it is TCP, with
+/// `IPV6_V6ONLY` set to `only_v6` if given.
+fn bind_socket(addr: SocketAddr, ty: socket2::Type, only_v6: Option<bool>) -> Result<socket2::Socket> {
+    use socket2::{Domain, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), ty, None)?;
+    if let Some(only_v6) = only_v6 {
+        socket.set_only_v6(only_v6)?;
+    }
+    if ty == Type::STREAM {
+        // As std and tokio do, so that a restarted listener can rebind
+        // while old connections linger.
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())
+        .with_context(|| format!("Failed to listen on {}", addr))?;
+    if ty == Type::STREAM {
+        socket.listen(1024)?;
+    }
+    Ok(socket)
+}
+
+fn is_dual_stack(socket: &socket2::Socket) -> Result<bool> {
+    Ok(socket.local_addr()?.is_ipv6() && !socket.only_v6()?)
+}
+
+/// Connections a listener accepted, by the address family of the peer.
+#[derive(Default)]
+struct ListenerStats {
+    ipv4: FamilyStats,
+    ipv6: FamilyStats,
+}
+
+#[derive(Default)]
+struct FamilyStats {
+    accepted: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl ListenerStats {
+    fn family(&self, remote: SocketAddr) -> &FamilyStats {
+        if remote.is_ipv4() { &self.ipv4 } else { &self.ipv6 }
+    }
+}
+
+impl std::fmt::Display for ListenerStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let count = |family: &FamilyStats| {
+            (family.accepted.load(Ordering::Relaxed), family.failed.load(Ordering::Relaxed))
+        };
+        let ((v4, v4_failed), (v6, v6_failed)) = (count(&self.ipv4), count(&self.ipv6));
+        write!(f, "IPv4 {} ({} failed), IPv6 {} ({} failed)", v4, v4_failed, v6, v6_failed)
+    }
+}
+
+async fn accept_tcp(
+    listener: tokio::net::TcpListener,
+    profile: Arc<Profile>,
+    daemon: Arc<ProfileDaemon>,
+    stats: Arc<ListenerStats>,
+) -> Result<()> {
+    loop {
+        let (stream, remote) = listener.accept().await?;
+        spawn_transfer(&profile, &daemon, &stats, stream, remote);
+    }
+}
+
+async fn accept_quic(
+    listener: QuicListener,
+    profile: Arc<Profile>,
+    daemon: Arc<ProfileDaemon>,
+    stats: Arc<ListenerStats>,
+) -> Result<()> {
+    loop {
+        let conn = listener.accept().await?;
+        let remote = conn.remote_address();
+        spawn_transfer(&profile, &daemon, &stats, conn, remote);
+    }
+}
+
+/// Accept WebSocket upgrades, each in a task of its own so that a client
+/// slow to upgrade holds up no one else.
+async fn accept_ws(
+    listener: tokio::net::TcpListener,
+    profile: Arc<Profile>,
+    daemon: Arc<ProfileDaemon>,
+    stats: Arc<ListenerStats>,
+) -> Result<()> {
+    loop {
+        let (stream, remote) = listener.accept().await?;
+        let (profile, daemon, stats) = (profile.clone(), daemon.clone(), stats.clone());
+        tokio::spawn(
+            async move {
+                match WsConnection::accept(stream).await {
+                    Ok(conn) => spawn_transfer(&profile, &daemon, &stats, conn, remote),
+                    Err(e) => tracing::debug!("WebSocket upgrade from {} failed: {:#}", remote, e),
+                }
+            }
+            .in_current_span(),
+        );
+    }
+}
+
+/// Hand a connection from `remote` to [`handle_transfer`], counting it
+/// in `stats`.
+fn spawn_transfer<T>(
+    profile: &Arc<Profile>,
+    daemon: &Arc<ProfileDaemon>,
+    stats: &Arc<ListenerStats>,
+    stream: T,
+    remote: SocketAddr,
+) where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // IPv4 peers of a dual-stack socket show as mapped IPv6 addresses.
+    let remote = SocketAddr::new(remote.ip().to_canonical(), remote.port());
+    println!("\n← Incoming connection from {}", remote);
+    stats.family(remote).accepted.fetch_add(1, Ordering::Relaxed);
+    let (profile, daemon, stats) = (profile.clone(), daemon.clone(), stats.clone());
+    tokio::spawn(
+        async move {
+            match handle_transfer(&profile, &daemon, stream, remote).await {
+                Ok(()) => {}
+                Err(e) if matches!(e.downcast_ref(), Some(OpenShareError::Cancelled(_) | OpenShareError::Rejected(_))) => {
+                    println!("✗ {:#}", e)
+                }
+                Err(e) => {
+                    stats.family(remote).failed.fetch_add(1, Ordering::Relaxed);
+                    tracing::error!("Transfer failed: {:#}", e);
+                    println!("✗ Transfer failed: {:#}", e);
+                }
+            }
+        }
+        .in_current_span(),
+    );
+}
+
+/// Handle one incoming connection from `remote`. Announced shares are
+/// offered to `daemon`, which queues a fetch from the announcing device
+/// (over TCP) if they match a subscription.
+async fn handle_transfer<T>(profile: &Profile, daemon: &Arc<ProfileDaemon>, stream: T, remote: SocketAddr) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let mut client = Client::new(profile.identity.clone(), profile.storage.clone(), profile.cfg.clone())
+        .with_observer(print_transfer_event)
+        .with_notifications(profile.hub.clone())
+        .with_output_dir(&profile.output_dir)
+        .with_admin(daemon.clone());
+    client.policy = match &profile.policy {
+        Some(policy) => policy.clone(),
+        // Re-read, as an admin may have changed them
+        None => Arc::new(daemon.limits()),
+    };
+
+    println!("  Waiting for manifest or fetch request...");
+    let manifests = match client.accept(stream).await? {
+        Accepted::Received(manifest) => vec![manifest],
+        Accepted::ReceivedBatch(manifests) => manifests,
+        Accepted::Served(manifest) => {
+            println!("✓ File served: {}", manifest.filename);
+            return Ok(());
+        }
+        // Only with a stream output, which listeners do not set
+        Accepted::Streamed(manifest) => {
+            println!("✓ Streamed {}", manifest.filename);
+            return Ok(());
+        }
+        Accepted::IndexShared { device_id, entries } => {
+            println!("✓ Index shared with {} ({} entries)", device_id, entries);
+            return Ok(());
+        }
+        Accepted::Probed { device_id } => {
+            println!("✓ {} checked our capabilities", device_id);
+            return Ok(());
+        }
+        Accepted::Administered { device_id, method } => {
+            println!("✓ {} ran '{}' remotely", device_id, method);
+            return Ok(());
+        }
+        Accepted::Text { device_id, text } => {
+            println!("✓ Text from {}:", device_id);
+            println!("{}", text);
+            if profile.cfg.copy_received_text {
+                match write_clipboard(&text) {
+                    Ok(()) => println!("  Copied to the clipboard"),
+                    Err(e) => println!("✗ Not copied: {:#}", e),
+                }
+            }
+            return Ok(());
+        }
+        Accepted::Announced { device_id, announcement } => {
+            let entry = &announcement.entry;
+            println!("✓ {} published {} ({})", device_id, entry.filename, format_bytes(entry.size));
+            let address = SocketAddr::new(remote.ip(), announcement.port).to_string();
+            if let Some(job) = daemon.offer(&device_id, &address, entry) {
+                match job.not_before {
+                    Some(at) => println!(
+                        "  Subscribed; job {} waits until {:02}:{:02} UTC to fetch it",
+                        job.id, at % 86400 / 3600, at % 3600 / 60
+                    ),
+                    None => println!("  Subscribed; job {} fetches it from {}", job.id, address),
+                }
+            }
+            return Ok(());
+        }
+    };
+
+    for manifest in manifests {
+        println!("  {}", manifest.summary());
+
+        // Verify manifest signature
+        manifest.verify().context("Invalid manifest signature")?;
+        println!("  ✓ Signature verified");
+
+        // Reconstruct file (or directory tree) from chunks
+        let output_path = manifest.output_path(&profile.output_dir)?;
+        println!("  Writing to: {}", output_path.display());
+        client.write_file(&manifest, &output_path).await?;
+
+        println!("✓ File received: {}", output_path.display());
+    }
+
+    Ok(())
+}
+
+/// Asks on the terminal about each pushed transfer within the limits of
+/// the config, one at a time.
+struct Prompt {
+    limits: Limits,
+    /// Lines typed on stdin, read by a thread of their own so that an
+    /// unanswered question leaves no read behind to take the next answer
+    answers: tokio::sync::Mutex<tokio::sync::mpsc::Receiver<String>>,
+}
+
+/// How long a question waits for an answer before the transfer is rejected.
+const PROMPT_TIMEOUT: Duration = Duration::from_secs(120);
+
+impl Prompt {
+    fn new(cfg: &ClientConfig) -> Result<Self> {
+        use std::io::{BufRead, IsTerminal};
+
+        if !std::io::stdin().is_terminal() {
+            anyhow::bail!("No terminal to ask about transfers on; run with --auto-accept");
+        }
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lock().lines() {
+                let Ok(line) = line else { break };
+                if tx.blocking_send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Self { limits: Limits::from_config(cfg), answers: tokio::sync::Mutex::new(rx) })
+    }
+}
+
+#[async_trait::async_trait]
+impl TransferPolicy for Prompt {
+    async fn review(&self, offer: &Offer) -> Verdict {
+        if let Some(reason) = self.limits.check(offer) {
+            return Verdict::Reject(reason);
+        }
+        let mut answers = self.answers.lock().await;
+        // Drop whatever was typed before the question.
+        while answers.try_recv().is_ok() {}
+
+        let trust = match offer.trust {
+            Some(TrustSource::Paired) => "paired",
+            Some(TrustSource::Manual) => "added by hand",
+            Some(TrustSource::FirstUse) => "trusted on first use",
+            Some(TrustSource::Account) => "certified by the account",
+            None => "not pinned",
+        };
+        let files = match offer.files.len() {
+            0 => String::new(),
+            n => format!(", {} files", n),
+        };
+        let preview = match &offer.preview {
+            Some(p) => format!(", {} {}x{}", p.media_type, p.width, p.height),
+            None => String::new(),
+        };
+        println!("\n? {} ({}) wants to send {} ({}{}{})", offer.peer, trust, offer.filename, format_bytes(offer.size), files, preview);
+        print!("  Accept? [y/N] ");
+        let _ = std::io::Write::flush(&mut std::io::stdout());
+        match tokio::time::timeout(PROMPT_TIMEOUT, answers.recv()).await {
+            Ok(Some(answer)) if matches!(answer.trim(), "y" | "Y" | "yes") => Verdict::Accept,
+            Ok(Some(_)) => Verdict::Reject("Declined".into()),
+            Ok(None) => Verdict::Reject("No one to ask".into()),
+            Err(_) => {
+                println!();
+                Verdict::Reject("No answer in time".into())
+            }
+        }
+    }
+}
+
+/// Accept control API clients on a Unix socket; see
+/// [`openshare_core::daemon`] for the protocol.
+#[cfg(unix)]
+fn serve_control_socket(path: &Path, daemon: Arc<ProfileDaemon>) -> Result<()> {
+    use tokio::net::UnixListener;
+
+    // A socket left behind by a previous run would make bind fail.
+    if path.exists() {
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove stale socket {}", path.display()))?;
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Failed to bind control socket {}", path.display()))?;
+
+    tokio::spawn(
+        async move {
+            loop {
+                let (stream, _) = match listener.accept().await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        tracing::warn!("Control socket accept failed: {}", e);
+                        continue;
+                    }
+                };
+                let daemon = daemon.clone();
+                tokio::spawn(
+                    async move {
+                        let (read, write) = stream.into_split();
+                        if let Err(e) = daemon.serve_control(read, write).await {
+                            tracing::debug!("Control client failed: {:#}", e);
+                        }
+                    }
+                    .in_current_span(),
+                );
+            }
+        }
+        .in_current_span(),
+    );
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn serve_control_socket(_path: &Path, _daemon: Arc<ProfileDaemon>) -> Result<()> {
+    anyhow::bail!("Control sockets are only supported on Unix")
+}
+
+/// Wait for Ctrl+C, or for a service manager to stop the process: with
+/// SIGTERM on Unix, with a stop request on Windows.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let stop = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                tracing::warn!("Cannot handle SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(windows)]
+    let stop = async {
+        let _ = win_service::STOP.subscribe().wait_for(|&stop| stop).await;
+    };
+    #[cfg(not(any(unix, windows)))]
+    let stop = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = stop => tracing::info!("Stopping"),
+    }
+}
+
+/// Serve the HTTP control API on localhost at the profile's `api_port`,
+/// for requests bearing the token in its data directory.
+fn serve_api(profile: &Profile, daemon: Arc<ProfileDaemon>) -> Result<()> {
+    let token = openshare_api::load_or_create_token(&profile.data_dir)?;
+    let listener = std::net::TcpListener::bind(("127.0.0.1", profile.cfg.api_port))
+        .with_context(|| format!("Failed to bind the HTTP API to port {}", profile.cfg.api_port))?;
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+    let api = Arc::new(openshare_api::Api::new(daemon, token));
+    tokio::spawn(
+        async move {
+            if let Err(e) = api.serve(listener).await {
+                tracing::warn!("HTTP API stopped: {:#}", e);
+            }
+        }
+        .in_current_span(),
+    );
+    Ok(())
+}
+
+/// Register the daemon, serving `profiles` (data directories) as `run`
+/// says, with the system's service manager and start it.
+fn install_service(data_dir: &Path, profiles: &[PathBuf], log_level: &str, run: &DaemonArgs) -> Result<()> {
+    let cfg = load_config(data_dir)?;
+    // The service starts elsewhere, so every path has to be absolute.
+    let data_dir = std::fs::canonicalize(data_dir)?;
+    let program = std::env::current_exe().context("Cannot find the openshare executable")?;
+    let log_file = std::path::absolute(run.log_file.clone().unwrap_or_else(|| cfg.service.log_path(&data_dir)))?;
+
+    let mut args = vec![
+        "--data-dir".to_string(), data_dir.display().to_string(),
+        "--log-level".to_string(), log_level.to_string(),
+        "daemon".to_string(),
+    ];
+    for profile in profiles {
+        args.extend(["--profile".to_string(), std::fs::canonicalize(profile)?.display().to_string()]);
+    }
+    if let Some(interface) = &run.interface {
+        args.extend(["--interface".to_string(), interface.clone()]);
+    }
+    if run.quic {
+        args.push("--quic".to_string());
+    }
+    args.extend(["--log-file".to_string(), log_file.display().to_string()]);
+    if cfg!(windows) {
+        args.push("--windows-service".to_string());
+    }
+
+    let spec = ServiceSpec::new(program, args, &cfg.service, &data_dir);
+    let installed = install_platform_service(&spec)?;
+    println!("✓ Installed and started {}", installed.display());
+    println!("  Restart: {:?} after {}s", cfg.service.restart, cfg.service.restart_delay_secs);
+    println!("  Log file: {}", log_file.display());
+    Ok(())
+}
+
+/// Run `program` with `args`, failing if it does.
+fn run_service_manager(program: &str, args: &[&str]) -> Result<()> {
+    let status = std::process::Command::new(program)
+        .args(args)
+        .status()
+        .with_context(|| format!("Failed to run {}", program))?;
+    anyhow::ensure!(status.success(), "'{} {}' failed ({})", program, args.join(" "), status);
+    Ok(())
+}
+
+/// Print whether the service is installed and what its manager says of it.
+fn service_status(data_dir: &Path) -> Result<()> {
+    let Some(installed) = installed_service()? else {
+        println!("Not installed; run 'openshare daemon install'");
+        return Ok(());
+    };
+    println!("Installed: {}", installed.display());
+    if let Ok(cfg) = load_config(data_dir) {
+        println!("Log file: {}", cfg.service.log_path(data_dir).display());
+    }
+    println!();
+    // The manager's own report; it exits non-zero for a stopped service.
+    let _ = show_platform_status();
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn systemd_unit_path() -> Result<PathBuf> {
+    let config = dirs::config_dir().context("No config directory to install the unit in")?;
+    Ok(config.join("systemd/user").join(service::SYSTEMD_UNIT))
+}
+
+#[cfg(target_os = "linux")]
+fn install_platform_service(spec: &ServiceSpec) -> Result<PathBuf> {
+    let path = systemd_unit_path()?;
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    std::fs::write(&path, spec.systemd_unit()).with_context(|| format!("Failed to write {}", path.display()))?;
+    run_service_manager("systemctl", &["--user", "daemon-reload"])?;
+    run_service_manager("systemctl", &["--user", "enable", service::SYSTEMD_UNIT])?;
+    run_service_manager("systemctl", &["--user", "restart", service::SYSTEMD_UNIT])?;
+    println!("  To keep it running while logged out: loginctl enable-linger");
+    Ok(path)
+}
+
+#[cfg(target_os = "linux")]
+fn uninstall_service() -> Result<()> {
+    let path = systemd_unit_path()?;
+    anyhow::ensure!(path.exists(), "No service installed at {}", path.display());
+    // Removed even if systemd cannot be reached, so a broken install can
+    // be cleaned up.
+    let stopped = run_service_manager("systemctl", &["--user", "disable", "--now", service::SYSTEMD_UNIT]);
+    std::fs::remove_file(&path)?;
+    let _ = run_service_manager("systemctl", &["--user", "daemon-reload"]);
+    stopped
+}
+
+#[cfg(target_os = "linux")]
+fn installed_service() -> Result<Option<PathBuf>> {
+    let path = systemd_unit_path()?;
+    Ok(path.exists().then_some(path))
+}
+
+#[cfg(target_os = "linux")]
+fn show_platform_status() -> Result<()> {
+    run_service_manager("systemctl", &["--user", "status", "--no-pager", service::SYSTEMD_UNIT])
+}
+
+#[cfg(target_os = "macos")]
+fn launchd_plist_path() -> Result<PathBuf> {
+    let home = dirs::home_dir().context("No home directory to install the agent in")?;
+    Ok(home.join("Library/LaunchAgents").join(format!("{}.plist", service::LAUNCHD_LABEL)))
+}
+
+#[cfg(target_os = "macos")]
+fn install_platform_service(spec: &ServiceSpec) -> Result<PathBuf> {
+    let path = launchd_plist_path()?;
+    if path.exists() {
+        // Reinstalling: stop the agent loaded from the old definition.
+        let _ = run_service_manager("launchctl", &["unload", &path.to_string_lossy()]);
+    }
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    std::fs::write(&path, spec.launchd_plist()).with_context(|| format!("Failed to write {}", path.display()))?;
+    run_service_manager("launchctl", &["load", "-w", &path.to_string_lossy()])?;
+    Ok(path)
+}
+
+#[cfg(target_os = "macos")]
+fn uninstall_service() -> Result<()> {
+    let path = launchd_plist_path()?;
+    anyhow::ensure!(path.exists(), "No service installed at {}", path.display());
+    run_service_manager("launchctl", &["unload", "-w", &path.to_string_lossy()])?;
+    Ok(std::fs::remove_file(&path)?)
+}
+
+#[cfg(target_os = "macos")]
+fn installed_service() -> Result<Option<PathBuf>> {
+    let path = launchd_plist_path()?;
+    Ok(path.exists().then_some(path))
+}
+
+#[cfg(target_os = "macos")]
+fn show_platform_status() -> Result<()> {
+    run_service_manager("launchctl", &["list", service::LAUNCHD_LABEL])
+}
+
+#[cfg(windows)]
+fn install_platform_service(spec: &ServiceSpec) -> Result<PathBuf> {
+    use openshare_core::config::RestartPolicy;
+
+    let name = service::WINDOWS_SERVICE;
+    let command = spec.windows_command_line();
+    run_service_manager("sc.exe", &["create", name, "binPath=", &command, "start=", "auto", "DisplayName=", "OpenShare daemon"])
+        .context("Installing a Windows service needs an administrator prompt")?;
+    if spec.restart != RestartPolicy::Never {
+        // The daemon only exits cleanly when stopped, so failures are all
+        // there is to restart after; the flag counts an error exit as one.
+        let delay = spec.restart_delay.as_millis().to_string();
+        let actions = [delay.as_str(); 3].map(|d| format!("restart/{}", d)).join("/");
+        run_service_manager("sc.exe", &["failure", name, "reset=", "86400", "actions=", &actions])?;
+        run_service_manager("sc.exe", &["failureflag", name, "1"])?;
+    }
+    run_service_manager("sc.exe", &["start", name])?;
+    Ok(PathBuf::from(name))
+}
+
+#[cfg(windows)]
+fn uninstall_service() -> Result<()> {
+    // Stopping fails if it is not running, which is fine.
+    let _ = run_service_manager("sc.exe", &["stop", service::WINDOWS_SERVICE]);
+    run_service_manager("sc.exe", &["delete", service::WINDOWS_SERVICE])
+}
+
+#[cfg(windows)]
+fn installed_service() -> Result<Option<PathBuf>> {
+    let installed = std::process::Command::new("sc.exe")
+        .args(["qc", service::WINDOWS_SERVICE])
+        .output()
+        .context("Failed to run sc.exe")?
+        .status
+        .success();
+    Ok(installed.then(|| PathBuf::from(service::WINDOWS_SERVICE)))
+}
+
+#[cfg(windows)]
+fn show_platform_status() -> Result<()> {
+    run_service_manager("sc.exe", &["query", service::WINDOWS_SERVICE])
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn install_platform_service(_spec: &ServiceSpec) -> Result<PathBuf> {
+    anyhow::bail!("Installing a service is only supported with systemd, launchd and Windows")
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn uninstall_service() -> Result<()> {
+    anyhow::bail!("Installing a service is only supported with systemd, launchd and Windows")
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn installed_service() -> Result<Option<PathBuf>> {
+    Ok(None)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn show_platform_status() -> Result<()> {
+    Ok(())
+}
+
+/// Running under the Windows service control manager, which starts the
+/// daemon as 'daemon install' registered it and stops it with a request
+/// rather than a signal.
+#[cfg(windows)]
+mod win_service {
+    use super::*;
+    use openshare_core::service::WINDOWS_SERVICE;
+    use std::ffi::OsString;
+    use std::sync::{LazyLock, OnceLock};
+    use tokio::sync::watch;
+    use windows_service::service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus, ServiceType,
+    };
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+    use windows_service::{define_windows_service, service_dispatcher};
+
+    /// Set once the service is asked to stop; see [`shutdown_signal`].
+    pub static STOP: LazyLock<watch::Sender<bool>> = LazyLock::new(|| watch::channel(false).0);
+
+    /// What the service runs, handed over from `main`.
+    struct Run {
+        profiles: Vec<PathBuf>,
+        interface: Option<String>,
+        quic: bool,
+        runtime: tokio::runtime::Handle,
+    }
+
+    static RUN: OnceLock<Run> = OnceLock::new();
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    /// Hand this thread to the service control manager, which runs the
+    /// daemon on one of its own until the service is stopped.
+    pub fn run(profiles: Vec<PathBuf>, interface: Option<String>, quic: bool) -> Result<()> {
+        let _ = RUN.set(Run { profiles, interface, quic, runtime: tokio::runtime::Handle::current() });
+        tokio::task::block_in_place(|| service_dispatcher::start(WINDOWS_SERVICE, ffi_service_main))
+            .context("Not started by the service control manager; run 'openshare daemon' instead")
+    }
+
+    fn service_main(_arguments: Vec<OsString>) {
+        if let Err(e) = run_service() {
+            tracing::error!("Service stopped: {:#}", e);
         }
     }
 
-    outfile.flush().await?;
-    println!("✓ File received: {}", output_path.display());
+    fn run_service() -> Result<()> {
+        let run = RUN.get().context("Service started without its arguments")?;
+        let status = service_control_handler::register(WINDOWS_SERVICE, |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                STOP.send_replace(true);
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        })?;
+        let report = |current_state: ServiceState, exit_code: ServiceExitCode| {
+            let controls_accepted = if current_state == ServiceState::Running {
+                ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN
+            } else {
+                ServiceControlAccept::empty()
+            };
+            status.set_service_status(ServiceStatus {
+                service_type: ServiceType::OWN_PROCESS,
+                current_state,
+                controls_accepted,
+                exit_code,
+                checkpoint: 0,
+                wait_hint: Duration::default(),
+                process_id: None,
+            })
+        };
+
+        report(ServiceState::Running, ServiceExitCode::Win32(0))?;
+        let result = run.runtime.block_on(run_daemon(&run.profiles, run.interface.as_deref(), run.quic));
+        // An error exit has the failure actions restart the service.
+        let exit_code = if result.is_ok() { ServiceExitCode::Win32(0) } else { ServiceExitCode::ServiceSpecific(1) };
+        report(ServiceState::Stopped, exit_code)?;
+        result
+    }
+}
+
+/// Send one request to a control socket and print every line that comes
+/// back until the daemon closes the connection (after the reply, or never
+/// for 'watch').
+#[cfg(unix)]
+async fn call_control(socket: &Path, method: &str, params: serde_json::Value) -> Result<()> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let stream = tokio::net::UnixStream::connect(socket).await
+        .with_context(|| format!("Failed to connect to {}", socket.display()))?;
+    let (read, mut write) = stream.into_split();
+    let request = serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+    write.write_all(format!("{}\n", request).as_bytes()).await?;
+    write.shutdown().await?;
+
+    let mut lines = BufReader::new(read).lines();
+    let mut failed = false;
+    while let Some(line) = lines.next_line().await? {
+        let message: serde_json::Value = serde_json::from_str(&line)
+            .with_context(|| format!("Unexpected message: {}", line))?;
+        failed |= message.get("error").is_some();
+        println!("{}", serde_json::to_string_pretty(&message)?);
+    }
+    if failed {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn call_control(_socket: &Path, _method: &str, _params: serde_json::Value) -> Result<()> {
+    anyhow::bail!("Control sockets are only supported on Unix")
+}
+
+/// Call `method` on a control socket and return its result, or fail with
+/// its error.
+#[cfg(unix)]
+async fn request_control(socket: &Path, method: &str, params: serde_json::Value) -> Result<serde_json::Value> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let stream = tokio::net::UnixStream::connect(socket).await
+        .with_context(|| format!("Failed to connect to {}; is the daemon running?", socket.display()))?;
+    let (read, mut write) = stream.into_split();
+    let request = serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+    write.write_all(format!("{}\n", request).as_bytes()).await?;
+    write.shutdown().await?;
+
+    let line = BufReader::new(read).lines().next_line().await?
+        .context("The daemon closed the connection without replying")?;
+    let mut reply: serde_json::Value = serde_json::from_str(&line)
+        .with_context(|| format!("Unexpected message: {}", line))?;
+    if let Some(error) = reply.get("error") {
+        anyhow::bail!("{}", error["message"].as_str().unwrap_or("Request failed"));
+    }
+    Ok(reply["result"].take())
+}
+
+#[cfg(not(unix))]
+async fn request_control(_socket: &Path, _method: &str, _params: serde_json::Value) -> Result<serde_json::Value> {
+    anyhow::bail!("Control sockets are only supported on Unix")
+}
+
+/// How many jobs run at once and in what order, for 'queue'.
+fn describe_scheduling(limits: &SchedulerLimits) -> String {
+    let limit = |n: usize| if n == 0 { "any number of".to_string() } else { n.to_string() };
+    format!(
+        "Running {} job(s) at once, {} per peer; equal priorities start {}",
+        limit(limits.max_concurrent_jobs),
+        limit(limits.max_jobs_per_peer),
+        match limits.queue_order {
+            QueueOrder::Fifo => "oldest first",
+            QueueOrder::FairShare => "taking turns between peers",
+        },
+    )
+}
+
+#[cfg(unix)]
+async fn watch_notifications(socket: &Path, filter: &NotificationFilter, json: bool) -> Result<()> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let mut stream = tokio::net::UnixStream::connect(socket).await
+        .with_context(|| format!("Failed to connect to {}", socket.display()))?;
+    let request = serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": "watch", "params": filter });
+    stream.write_all(format!("{}\n", request).as_bytes()).await?;
 
+    let mut lines = BufReader::new(stream).lines();
+    // Job updates come with every chunk; only state changes are printed.
+    let mut job_states: HashMap<u64, JobState> = HashMap::new();
+    while let Some(line) = lines.next_line().await? {
+        if json {
+            println!("{}", line);
+            continue;
+        }
+        let message: serde_json::Value = serde_json::from_str(&line)
+            .with_context(|| format!("Unexpected message: {}", line))?;
+        if let Some(error) = message.get("error") {
+            anyhow::bail!("Watch refused: {}", error["message"]);
+        }
+        let params = message["params"].clone();
+        match message["method"].as_str() {
+            Some("job") => {
+                let job: Job = serde_json::from_value(params)?;
+                if job_states.insert(job.id, job.state) == Some(job.state) {
+                    continue;
+                }
+                match &job.error {
+                    Some(error) => println!("Job {} ({}) failed: {}", job.id, job, error),
+                    None => println!("Job {} ({}): {:?}", job.id, job, job.state),
+                }
+                continue;
+            }
+            Some("transfer") => {}
+            _ => continue,
+        }
+        let record: TransferRecord = serde_json::from_value(params)?;
+        if record.direction == Direction::Announced {
+            println!("{} published {} ({})", record.peer, record.filename, format_bytes(record.size));
+            continue;
+        }
+        match &record.error {
+            Some(error) => println!("{:?} {} {} failed: {}", record.direction, record.filename, record.peer, error),
+            None => println!("{:?} {} {} ({})", record.direction, record.filename, record.peer, format_bytes(record.size)),
+        }
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(not(unix))]
+async fn watch_notifications(_socket: &Path, _filter: &NotificationFilter, _json: bool) -> Result<()> {
+    anyhow::bail!("Control sockets are only supported on Unix")
+}
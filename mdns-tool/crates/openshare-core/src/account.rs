@@ -0,0 +1,218 @@
+//! Account root key and device certificates.
+//!
+//! The account hash advertised over mDNS only tells the devices of an
+//! account apart from others on the network; anyone can copy it. An
+//! account root key makes membership something a peer can check: it signs
+//! a [`DeviceCertificate`] for each device, binding the device ID to its
+//! identity key until an expiry.
+//!
+//! A device with `account_root` in its config sends its certificate in the
+//! hello (`device-certs`) and only accepts peers presenting a certificate
+//! from the same root. Certified peers need no `openshare trust add`: an
+//! unknown one is pinned as [`TrustSource::Account`](crate::trust::TrustSource::Account).
+//! A certificate stays good for a key rotated from the certified one, since
+//! the rotation is signed by that key.
+//!
+//! The root's secret key is only needed to certify devices. It is kept in
+//! [`ROOT_KEY_FILE`] on the device that created the account and can be
+//! moved offline between certifications.
+
+use crate::handshake::PeerInfo;
+use crate::keys::{Identity, KeyRotation};
+use anyhow::Result;
+use ed25519_dalek::Signature;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// File name of the account root key in the data directory of the device
+/// that created the account.
+pub const ROOT_KEY_FILE: &str = "account_root.key";
+
+/// Signature context of a [`DeviceCertificate`].
+const CERTIFICATE_CONTEXT: &[u8] = b"openshare device certificate v1";
+
+/// The account root key, able to certify devices.
+pub struct AccountRoot {
+    key: Identity,
+}
+
+impl AccountRoot {
+    /// Generate a root key and keep it at `path`, which must not exist.
+    pub fn generate_and_store(path: &Path) -> Result<Self> {
+        if path.exists() {
+            anyhow::bail!("{} already exists", path.display());
+        }
+        Ok(Self { key: Identity::generate_and_store(path)? })
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(Self { key: Identity::load(path)? })
+    }
+
+    pub fn public_key_bytes(&self) -> [u8; 32] {
+        self.key.public_key_bytes()
+    }
+
+    /// Certify that `device_id` holds `public_key`, for `lifetime` from now.
+    pub fn certify(&self, device_id: &str, public_key: &[u8; 32], lifetime: Duration) -> DeviceCertificate {
+        let expires_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+            + lifetime.as_secs();
+        let root_key = self.public_key_bytes();
+        let signed = DeviceCertificate::signed_bytes(device_id, public_key, expires_at, &root_key);
+        DeviceCertificate {
+            device_id: device_id.to_string(),
+            public_key: *public_key,
+            expires_at,
+            root_key,
+            signature: self.key.sign(&signed).to_bytes().to_vec(),
+        }
+    }
+}
+
+/// A device's membership of an account: the root key's signature over the
+/// device ID and identity key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceCertificate {
+    pub device_id: String,
+    pub public_key: [u8; 32],
+    /// Unix timestamp (seconds) after which the certificate is not accepted
+    pub expires_at: u64,
+    /// The account root key that signed it
+    pub root_key: [u8; 32],
+    pub signature: Vec<u8>,
+}
+
+impl DeviceCertificate {
+    fn signed_bytes(device_id: &str, public_key: &[u8; 32], expires_at: u64, root_key: &[u8; 32]) -> Vec<u8> {
+        [
+            CERTIFICATE_CONTEXT,
+            &(device_id.len() as u32).to_be_bytes(),
+            device_id.as_bytes(),
+            public_key,
+            &expires_at.to_be_bytes(),
+            root_key,
+        ]
+        .concat()
+    }
+
+    /// Check that `root` issued the certificate to `device_id` and that it
+    /// has not expired at `now` (Unix seconds).
+    pub fn verify(&self, root: &[u8; 32], device_id: &str, now: u64) -> Result<()> {
+        if self.root_key != *root {
+            anyhow::bail!("Certificate of {} is from another account ({})", self.device_id, hex::encode(self.root_key));
+        }
+        if self.device_id != device_id {
+            anyhow::bail!("Certificate is for {}, not {}", self.device_id, device_id);
+        }
+        let signed = Self::signed_bytes(&self.device_id, &self.public_key, self.expires_at, &self.root_key);
+        let sig = Signature::from_slice(&self.signature)
+            .map_err(|_| anyhow::anyhow!("Malformed signature on the certificate of {}", device_id))?;
+        Identity::verify_with_pubkey(root, &signed, &sig)
+            .map_err(|_| anyhow::anyhow!("Invalid signature on the certificate of {}", device_id))?;
+        if now >= self.expires_at {
+            anyhow::bail!("Certificate of {} expired", device_id);
+        }
+        Ok(())
+    }
+
+    /// Whether the certificate covers an identity with `public_key` and
+    /// key `history`: it names that key or one the identity rotated from.
+    pub fn covers(&self, public_key: &[u8; 32], history: &[KeyRotation]) -> bool {
+        self.public_key == *public_key || history.iter().any(|r| r.old_key == self.public_key)
+    }
+}
+
+/// Check that `peer` presented a certificate from `root` for its device ID
+/// and key. The handshake already checked the peer's key history.
+pub fn verify_peer(peer: &PeerInfo, root: &[u8; 32], now: u64) -> Result<()> {
+    let Some(cert) = &peer.certificate else {
+        anyhow::bail!("{} presented no account certificate", peer.device_id);
+    };
+    cert.verify(root, &peer.device_id, now)?;
+    if !cert.covers(&peer.public_key, &peer.key_history) {
+        anyhow::bail!("Certificate of {} is for another key", peer.device_id);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Client;
+    use crate::sim::fixture::client;
+    use crate::sim::{pair, LinkConfig};
+    use std::sync::Arc;
+    use storage::LocalStorage;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_certificate() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let root = AccountRoot::generate_and_store(&tmp.path().join(ROOT_KEY_FILE))?;
+        assert!(AccountRoot::generate_and_store(&tmp.path().join(ROOT_KEY_FILE)).is_err());
+        let root_key = root.public_key_bytes();
+        let device = Identity::from(ed25519_dalek::SigningKey::from_bytes(&[7; 32]));
+
+        let cert = root.certify("laptop", &device.public_key_bytes(), Duration::from_secs(3600));
+        cert.verify(&root_key, "laptop", cert.expires_at - 1)?;
+        assert!(cert.verify(&root_key, "laptop", cert.expires_at).is_err());
+        assert!(cert.verify(&root_key, "phone", 0).is_err());
+        assert!(cert.verify(&[9; 32], "laptop", 0).is_err());
+        let mut forged = cert.clone();
+        forged.expires_at += 1;
+        assert!(forged.verify(&root_key, "laptop", 0).is_err());
+
+        let rotated = device.rotate();
+        assert!(cert.covers(&rotated.public_key_bytes(), &rotated.history));
+        assert!(!cert.covers(&rotated.public_key_bytes(), &[]));
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_account_certificates() {
+        use crate::trust::TrustSource;
+
+        let (root_dir, src, dst) = (TempDir::new().unwrap(), TempDir::new().unwrap(), TempDir::new().unwrap());
+        let root = AccountRoot::generate_and_store(&root_dir.path().join("root.key")).unwrap();
+        let other_root = AccountRoot::generate_and_store(&root_dir.path().join("other.key")).unwrap();
+        let input = src.path().join("input.bin");
+        std::fs::write(&input, vec![6u8; 10_000]).unwrap();
+
+        let mut sender = client(&src, 1);
+        let mut receiver = client(&dst, 2);
+        receiver.cfg.trust_on_first_use = false;
+        receiver.cfg.account_root = Some(hex::encode(root.public_key_bytes()));
+        let manifest = sender.import_file(&input).await.unwrap();
+        async fn send(sender: &Client<LocalStorage>, receiver: &Client<LocalStorage>, manifest: &crate::Manifest) -> anyhow::Result<crate::Manifest> {
+            let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+            Ok(tokio::join!(sender.send_manifest_over(a, manifest.clone()), receiver.accept_and_receive(b)).1?)
+        }
+
+        // Without a certificate, or with one from another account, the
+        // sender is refused even though it would otherwise be pinned.
+        receiver.trust.lock().unwrap().add("dev1", &sender.identity.public_key_bytes(), TrustSource::Manual);
+        assert!(format!("{:#}", send(&sender, &receiver, &manifest).await.unwrap_err()).contains("no account certificate"));
+        let lifetime = Duration::from_secs(3600);
+        let mut identity = (*sender.identity).clone();
+        identity.certificate = Some(other_root.certify("dev1", &identity.public_key_bytes(), lifetime));
+        sender.identity = Arc::new(identity.clone());
+        assert!(format!("{:#}", send(&sender, &receiver, &manifest).await.unwrap_err()).contains("another account"));
+
+        // Certified, it needs no pin.
+        receiver.trust.lock().unwrap().remove("dev1");
+        identity.certificate = Some(root.certify("dev1", &identity.public_key_bytes(), lifetime));
+        sender.identity = Arc::new(identity);
+        send(&sender, &receiver, &manifest).await.unwrap();
+        assert_eq!(receiver.trust.lock().unwrap().get("dev1").unwrap().source, TrustSource::Account);
+
+        // The certificate is for dev1 only.
+        let mut impostor = client(&TempDir::new().unwrap(), 1);
+        impostor.cfg.device_id = "dev3".into();
+        impostor.identity = sender.identity.clone();
+        assert!(format!("{:#}", send(&impostor, &receiver, &manifest).await.unwrap_err()).contains("not dev3"));
+    }
+}
@@ -0,0 +1,32 @@
+//! Remote management of a headless daemon.
+//!
+//! A NAS or a Raspberry Pi running `openshare daemon` may have no shell to
+//! reach its control socket from. A device pinned in its trust store with
+//! the admin permission (`openshare trust admin`) can instead connect like
+//! any peer and send an [`AdminRequest`](crate::protocol::AdminRequest):
+//! one method of the control API, signed with its identity over the
+//! session transcript. The listener checks the permission and the
+//! signature, then hands the method to its [`AdminHandler`], normally the
+//! [`Daemon`](crate::daemon::Daemon), and sends back the result.
+//!
+//! Only the methods in [`ADMIN_METHODS`] are offered this way: listing
+//! peers and jobs, pausing, resuming, cancelling and reprioritizing jobs,
+//! collecting garbage, and reading or changing the limits on incoming
+//! pushes and on how many jobs run at once.
+//! Queueing sends and watching stay local, as they name paths on the
+//! daemon's machine or hold the connection open.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde_json::Value;
+
+/// Methods a remote admin may call.
+pub const ADMIN_METHODS: &[&str] = &["peers", "jobs", "pause", "resume", "cancel", "prioritize", "scheduler", "gc", "limits"];
+
+/// Runs admin requests for a listening [`Client`](crate::Client); see
+/// [`Client::with_admin`](crate::Client::with_admin).
+#[async_trait]
+pub trait AdminHandler: Send + Sync {
+    /// Run `method`, one of [`ADMIN_METHODS`], for `device_id`.
+    async fn call(&self, device_id: &str, method: &str, params: Value) -> Result<Value>;
+}
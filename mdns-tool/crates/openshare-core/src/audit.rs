@@ -0,0 +1,76 @@
+//! Local audit log of peer misbehaviour.
+//!
+//! Where the transfer history records what moved, the audit log records
+//! what a peer did wrong: chunks that failed verification and the
+//! quarantines that followed (see [`crate::quarantine`]). Each event is one
+//! JSON line in `audit.jsonl` under the data directory.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// File name of the audit log inside the data directory.
+pub const AUDIT_FILE: &str = "audit.jsonl";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// A chunk from `peer` did not decompress or hash to its ID
+    VerificationFailed {
+        peer: String,
+        chunk: String,
+        reason: String,
+        /// Unix timestamp (seconds)
+        at: u64,
+    },
+    /// `peer` will not be asked for `chunk` again before `until`
+    Quarantined {
+        peer: String,
+        chunk: String,
+        until: u64,
+        at: u64,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new(data_dir: &Path) -> Self {
+        Self { path: data_dir.join(AUDIT_FILE) }
+    }
+
+    pub fn append(&self, event: &AuditEvent) -> Result<()> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut f| f.write_all(&line))
+            .with_context(|| format!("writing {}", self.path.display()))
+    }
+
+    /// All events, oldest first; a missing file is an empty log.
+    /// Unreadable lines (e.g. a torn write) are skipped.
+    pub fn load(&self) -> Result<Vec<AuditEvent>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let text = std::fs::read_to_string(&self.path)
+            .with_context(|| format!("reading {}", self.path.display()))?;
+        Ok(text.lines()
+            .filter(|l| !l.trim().is_empty())
+            .filter_map(|l| match serde_json::from_str(l) {
+                Ok(event) => Some(event),
+                Err(e) => {
+                    tracing::warn!("Skipping bad audit line: {}", e);
+                    None
+                }
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,236 @@
+//! Moving a device identity to a replacement machine.
+//!
+//! An [`IdentityBundle`] holds what makes a device itself to its peers:
+//! the identity key with its rotation history and account certificate,
+//! the config, the trust store and the separate storage key, if any.
+//! `openshare identity export` seals one under a passphrase and
+//! `openshare identity import` restores it into another data directory,
+//! after which peers see the same device. Chunks, manifests and the
+//! transfer history stay behind, and so does an account root key, which
+//! is moved on its own.
+//!
+//! A sealed bundle is [`MAGIC`], a version byte, the scrypt parameters
+//! (`log_n`, then `r` and `p` as big-endian `u32`), a 16-byte salt and a
+//! 24-byte nonce, followed by the bundle as JSON encrypted with
+//! XChaCha20-Poly1305 under the key scrypt derives from the passphrase.
+//! The header is authenticated along with it.
+
+use crate::account::DeviceCertificate;
+use crate::config::ClientConfig;
+use crate::encrypted::KEY_FILE;
+use crate::keys::{verify_history, Identity, KeyBackend, KeyRotation};
+use crate::trust::{TrustStore, TrustedPeer};
+use anyhow::{Context, Result};
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305, XNonce};
+use ed25519_dalek::SigningKey;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use zeroize::Zeroizing;
+
+/// First bytes of a sealed bundle.
+pub const MAGIC: &[u8; 4] = b"OSID";
+
+/// Usual extension of a sealed bundle.
+pub const EXTENSION: &str = "osid";
+
+const VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 1 + 4 + 4 + SALT_LEN + NONCE_LEN;
+
+/// scrypt cost of new bundles: 128 MiB of memory and about half a second.
+const LOG_N: u8 = 17;
+const R: u32 = 8;
+const P: u32 = 1;
+
+/// Highest `log_n` accepted from a bundle, so a crafted one cannot ask
+/// for more than 1 GiB.
+const MAX_LOG_N: u8 = 20;
+
+/// Everything exported from a device.
+#[derive(Serialize, Deserialize)]
+pub struct IdentityBundle {
+    /// Unix timestamp (seconds) of the export
+    pub exported_at: u64,
+    secret_key: Zeroizing<[u8; 32]>,
+    pub history: Vec<KeyRotation>,
+    pub certificate: Option<DeviceCertificate>,
+    pub config: ClientConfig,
+    pub trusted_peers: Vec<TrustedPeer>,
+    /// Contents of [`KEY_FILE`], for chunks encrypted with it
+    storage_key: Option<Zeroizing<[u8; 32]>>,
+}
+
+impl IdentityBundle {
+    /// Gather the bundle of the device with `config`.
+    pub fn collect(config: &ClientConfig) -> Result<Self> {
+        let data_dir = &config.data_dir;
+        let identity = Identity::load_with(config.key_backend, &data_dir.join("identity.key"))?;
+        let storage_key = match std::fs::read(data_dir.join(KEY_FILE)) {
+            Ok(data) => Some(Zeroizing::new(Zeroizing::new(data).as_slice().try_into()
+                .map_err(|_| anyhow::anyhow!("Invalid storage key in {}", data_dir.join(KEY_FILE).display()))?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", data_dir.join(KEY_FILE).display())),
+        };
+        Ok(Self {
+            exported_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            secret_key: identity.secret_bytes(),
+            history: identity.history,
+            certificate: identity.certificate,
+            config: config.clone(),
+            trusted_peers: TrustStore::load(data_dir)?.list().cloned().collect(),
+            storage_key,
+        })
+    }
+
+    /// The identity in the bundle.
+    pub fn identity(&self) -> Identity {
+        Identity {
+            signing_key: SigningKey::from_bytes(&self.secret_key),
+            history: self.history.clone(),
+            certificate: self.certificate.clone(),
+        }
+    }
+
+    /// Encrypt the bundle under `passphrase`.
+    pub fn seal(&self, passphrase: &str) -> Result<Vec<u8>> {
+        self.seal_with(passphrase, LOG_N)
+    }
+
+    fn seal_with(&self, passphrase: &str, log_n: u8) -> Result<Vec<u8>> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let header = [MAGIC.as_slice(), &[VERSION, log_n], &R.to_be_bytes(), &P.to_be_bytes(), &salt, &nonce].concat();
+
+        let key = derive_key(passphrase, &salt, log_n, R, P)?;
+        let json = Zeroizing::new(serde_json::to_vec(self)?);
+        let sealed = XChaCha20Poly1305::new((&*key).into())
+            .encrypt(XNonce::from_slice(&nonce), Payload { msg: &json, aad: &header })
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt the identity bundle"))?;
+        Ok([header, sealed].concat())
+    }
+
+    /// Decrypt a bundle sealed under `passphrase`, and check that its key
+    /// history and certificate belong to its key.
+    pub fn open(sealed: &[u8], passphrase: &str) -> Result<Self> {
+        if sealed.len() < HEADER_LEN || !sealed.starts_with(MAGIC) {
+            anyhow::bail!("Not an identity bundle");
+        }
+        let (header, ciphertext) = sealed.split_at(HEADER_LEN);
+        let version = header[4];
+        if version != VERSION {
+            anyhow::bail!("Identity bundle version {} is not supported (this build reads {})", version, VERSION);
+        }
+        let log_n = header[5];
+        let r = u32::from_be_bytes(header[6..10].try_into().unwrap());
+        let p = u32::from_be_bytes(header[10..14].try_into().unwrap());
+        if log_n > MAX_LOG_N || r > 64 || p > 16 {
+            anyhow::bail!("Identity bundle asks for too much work to decrypt");
+        }
+        let salt = &header[14..14 + SALT_LEN];
+        let nonce = &header[14 + SALT_LEN..];
+
+        let key = derive_key(passphrase, salt, log_n, r, p)?;
+        let json = Zeroizing::new(XChaCha20Poly1305::new((&*key).into())
+            .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad: header })
+            .map_err(|_| anyhow::anyhow!("Wrong passphrase, or the bundle is damaged"))?);
+        let bundle: Self = serde_json::from_slice(&json).context("Invalid identity bundle")?;
+
+        let identity = bundle.identity();
+        verify_history(&bundle.history, &identity.public_key_bytes()).context("Invalid key history in the bundle")?;
+        if bundle.certificate.as_ref().is_some_and(|cert| !cert.covers(&identity.public_key_bytes(), &bundle.history)) {
+            anyhow::bail!("The certificate in the bundle is for another key");
+        }
+        Ok(bundle)
+    }
+
+    /// Make `data_dir` the device in the bundle, keeping its key in
+    /// `backend`. Whatever identity, config and trust store are there are
+    /// replaced. Returns the config as saved.
+    pub fn restore(self, data_dir: &Path, backend: KeyBackend) -> Result<ClientConfig> {
+        let config = ClientConfig { data_dir: data_dir.to_path_buf(), key_backend: backend, ..self.config.clone() };
+        config.ensure_data_dir()?;
+        self.identity().store_with(backend, &data_dir.join("identity.key"))?;
+
+        let mut trust = TrustStore::empty(data_dir);
+        for peer in self.trusted_peers {
+            trust.insert(peer);
+        }
+        trust.save()?;
+
+        if let Some(key) = &self.storage_key {
+            let path = data_dir.join(KEY_FILE);
+            match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(e).with_context(|| format!("Failed to replace {}", path.display()));
+                }
+                _ => {}
+            }
+            crate::encrypted::write_private(&path, &**key).with_context(|| format!("Failed to write {}", path.display()))?;
+        }
+
+        // Last, since a config is what makes the directory initialized
+        config.save(&data_dir.join("config.json"))?;
+        Ok(config)
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], log_n: u8, r: u32, p: u32) -> Result<Zeroizing<[u8; 32]>> {
+    let params = scrypt::Params::new(log_n, r, p, 32)
+        .map_err(|e| anyhow::anyhow!("Invalid scrypt parameters in the identity bundle: {}", e))?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    scrypt::scrypt(passphrase.as_bytes(), salt, &params, &mut *key)
+        .map_err(|e| anyhow::anyhow!("Failed to derive the bundle key: {}", e))?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encrypted::StorageEncryption;
+    use crate::trust::TrustSource;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_export_and_import() -> Result<()> {
+        let old = TempDir::new()?;
+        let config = ClientConfig {
+            device_id: "laptop".into(),
+            data_dir: old.path().to_path_buf(),
+            storage_encryption: StorageEncryption::KeyFile,
+            ..ClientConfig::default()
+        };
+        config.save(&old.path().join("config.json"))?;
+        Identity::generate_with(KeyBackend::File, &old.path().join("identity.key"))?;
+        let identity = Identity::rotate_with(KeyBackend::File, &old.path().join("identity.key"))?;
+        let mut trust = TrustStore::empty(old.path());
+        trust.add("phone", &[7; 32], TrustSource::Paired);
+        trust.set_admin("phone", true);
+        trust.save()?;
+        std::fs::write(old.path().join(KEY_FILE), [9; 32])?;
+
+        let sealed = IdentityBundle::collect(&config)?.seal_with("correct horse", 10)?;
+        assert!(sealed.starts_with(MAGIC));
+        assert!(IdentityBundle::open(&sealed, "wrong horse").is_err());
+        let mut tampered = sealed.clone();
+        tampered[5] += 1;
+        assert!(IdentityBundle::open(&tampered, "correct horse").is_err());
+
+        let new = TempDir::new()?;
+        let restored = IdentityBundle::open(&sealed, "correct horse")?.restore(new.path(), KeyBackend::File)?;
+        assert_eq!((restored.device_id.as_str(), restored.data_dir.as_path()), ("laptop", new.path()));
+        let loaded = Identity::load(&new.path().join("identity.key"))?;
+        assert_eq!(loaded.public_key_bytes(), identity.public_key_bytes());
+        assert_eq!(loaded.history, identity.history);
+        let phone = TrustStore::load(new.path())?.get("phone").cloned().unwrap();
+        assert!(phone.admin && phone.source == TrustSource::Paired);
+        assert_eq!(std::fs::read(new.path().join(KEY_FILE))?, [9; 32]);
+        assert_eq!(ClientConfig::load(&new.path().join("config.json"))?.data_dir, new.path());
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+//! Synchronous facade over the async Client and Discovery APIs.
+//!
+//! Each `BlockingClient` owns a small multi-threaded tokio runtime and
+//! drives the async calls to completion, so callers that are not running
+//! inside tokio (scripts, FFI, GUI event loops) can use plain function
+//! calls. Do not call these from within an async context.
+
+use crate::discovery::{Discovery, Peer};
+use crate::{Client, ClientConfig, Identity, Manifest};
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use storage::Storage;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+
+pub struct BlockingClient<S> {
+    rt: Runtime,
+    client: Client<S>,
+    discovery: Discovery,
+}
+
+impl<S> BlockingClient<S>
+where
+    S: Storage + Send + Sync + 'static,
+{
+    pub fn new(identity: Identity, storage: S, cfg: ClientConfig) -> Result<Self> {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .context("Failed to start runtime")?;
+        let discovery = Discovery::new(cfg.clone(), &identity);
+        let client = Client::new(identity, storage, cfg);
+        Ok(Self { rt, client, discovery })
+    }
+
+    /// Chunk `path`, connect to `peer` (host:port) and send it.
+    pub fn send_file(&self, path: &Path, peer: &str) -> Result<Manifest> {
+        self.rt.block_on(async {
+            let manifest = self.client.import_file(path).await?;
+            let stream = TcpStream::connect(peer).await
+                .with_context(|| format!("Failed to connect to {}", peer))?;
+            self.client.send_manifest_over(stream, manifest.clone()).await?;
+            Ok(manifest)
+        })
+    }
+
+    /// Listen on `bind` (e.g. "0.0.0.0:9876"), accept a single transfer,
+    /// verify it and write the file into `output_dir`.
+    pub fn receive_one(&self, bind: &str, output_dir: &Path) -> Result<(Manifest, PathBuf)> {
+        self.rt.block_on(async {
+            let listener = TcpListener::bind(bind).await?;
+            let (stream, peer_addr) = listener.accept().await?;
+            tracing::info!("Incoming transfer from {}", peer_addr);
+
+            let manifest = self.client.accept_and_receive(stream).await?;
+            manifest.verify().context("Invalid manifest signature")?;
+
+            let output_path = manifest.output_path(output_dir)?;
+            self.client.write_file(&manifest, &output_path).await?;
+            Ok((manifest, output_path))
+        })
+    }
+
+    /// Browse for same-account peers on `interface` for `timeout`.
+    pub fn discover(&self, interface: &str, timeout: Duration) -> Result<Vec<Peer>> {
+        self.rt.block_on(self.discovery.browse(interface, timeout, &[]))
+    }
+
+    /// Access the underlying async client.
+    pub fn client(&self) -> &Client<S> {
+        &self.client
+    }
+}
@@ -0,0 +1,227 @@
+//! How files are cut into chunks.
+//!
+//! Chunks are normally a fixed size, so a byte inserted near the start of
+//! a file shifts every later boundary and no chunk of the previous version
+//! matches any more. [`Chunking::Cdc`] instead cuts with FastCDC: a Gear
+//! rolling hash over the last 64 bytes picks boundaries by content, so an
+//! edit only changes the chunks around it. Cuts are normalised towards the
+//! average size (a stricter mask before it, a looser one after) and kept
+//! between a quarter and four times the average.
+//!
+//! A reader is cut by filling a window of [`Chunking::max_len`] bytes (less
+//! only at the end of the file), taking [`Chunking::cut`] bytes off the
+//! front and topping the window up again. Both sides of a delta sync must
+//! cut the same way to find common chunks, so the Gear table is fixed; a
+//! mismatch only costs the savings, as every chunk is still verified by
+//! its hash.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// Smallest average chunk size accepted for content-defined chunking.
+pub const MIN_CDC_SIZE: usize = 1024;
+
+/// Largest average chunk size accepted for content-defined chunking; its
+/// largest chunks are still within [`crate::codec::MAX_RAW_CHUNK`].
+pub const MAX_CDC_SIZE: usize = 16 * 1024 * 1024;
+
+/// Largest fixed chunk size [`Chunking::sized_for`] picks; a chunk frame
+/// stays well inside the 10 MiB frame limit.
+pub const MAX_FIXED_SIZE: usize = 8 * 1024 * 1024;
+
+/// Chunks per transfer [`Chunking::sized_for`] aims to stay under.
+const TARGET_CHUNKS: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Chunking {
+    /// Chunks of this many bytes, the last of each file shorter
+    Fixed(usize),
+    /// FastCDC with this average chunk size
+    Cdc(usize),
+}
+
+impl Chunking {
+    /// Refuse parameters that are out of range, e.g. from a peer.
+    pub fn check(self) -> Result<Self> {
+        match self {
+            Chunking::Fixed(0) => anyhow::bail!("Chunk size must not be zero"),
+            Chunking::Cdc(avg) if !(MIN_CDC_SIZE..=MAX_CDC_SIZE).contains(&avg) => {
+                anyhow::bail!("Average chunk size {} is outside {}..={}", avg, MIN_CDC_SIZE, MAX_CDC_SIZE)
+            }
+            _ => Ok(self),
+        }
+    }
+
+    /// Fixed chunks for `total` bytes of content: `base` bytes, doubled
+    /// while that makes more than 65 536 chunks, up to [`MAX_FIXED_SIZE`].
+    /// A multi-GB file thus gets a listing of a few megabytes at most.
+    pub fn sized_for(base: usize, total: u64) -> Self {
+        let mut size = base.max(1);
+        while total.div_ceil(size as u64) > TARGET_CHUNKS && size * 2 <= MAX_FIXED_SIZE {
+            size *= 2;
+        }
+        Chunking::Fixed(size)
+    }
+
+    /// The chunk size, for fixed chunks that fit a manifest's record of it.
+    pub fn fixed_size(self) -> Option<u32> {
+        match self {
+            Chunking::Fixed(size) => u32::try_from(size).ok(),
+            Chunking::Cdc(_) => None,
+        }
+    }
+
+    /// Most bytes one chunk can hold.
+    pub fn max_len(self) -> usize {
+        match self {
+            Chunking::Fixed(size) => size,
+            Chunking::Cdc(avg) => avg * 4,
+        }
+    }
+
+    /// Length of the chunk at the start of `data`, which holds
+    /// [`max_len`](Self::max_len) bytes unless the file ends sooner.
+    pub fn cut(self, data: &[u8]) -> usize {
+        let len = data.len().min(self.max_len());
+        let avg = match self {
+            Chunking::Fixed(_) => return len,
+            Chunking::Cdc(avg) => avg,
+        };
+        let min = avg / 4;
+        if len <= min {
+            return len;
+        }
+        let bits = avg.ilog2();
+        let (strict, loose) = (high_bits(bits + 2), high_bits(bits.saturating_sub(2)));
+        let normal = avg.min(len);
+        let mut hash = 0u64;
+        for (i, &byte) in data.iter().enumerate().take(len).skip(min) {
+            hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+            let mask = if i < normal { strict } else { loose };
+            if hash & mask == 0 {
+                return i + 1;
+            }
+        }
+        len
+    }
+}
+
+/// Mask of the top `n` bits: those mix in all 64 bytes of the window.
+fn high_bits(n: u32) -> u64 {
+    match n {
+        0 => 0,
+        n => u64::MAX << (64 - n.min(64)),
+    }
+}
+
+/// Gear table: 256 pseudo-random values from SplitMix64 with a fixed seed.
+static GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x6f70_656e_7368_6172; // "openshar"
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::fixture::client;
+    use crate::sim::{pair, LinkConfig};
+    use tempfile::TempDir;
+
+    fn split(chunking: Chunking, mut data: &[u8]) -> Vec<&[u8]> {
+        let mut chunks = Vec::new();
+        while !data.is_empty() {
+            let (chunk, rest) = data.split_at(chunking.cut(data));
+            chunks.push(chunk);
+            data = rest;
+        }
+        chunks
+    }
+
+    #[test]
+    fn test_cdc_survives_insertions() {
+        let mut state = 1u64;
+        let data: Vec<u8> = (0..200_000).map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 56) as u8
+        }).collect();
+        let chunking = Chunking::Cdc(4096);
+        let before = split(chunking, &data);
+        assert!(before.iter().all(|c| c.len() <= 16384));
+        assert!(before[..before.len() - 1].iter().all(|c| c.len() > 1024));
+        assert!((20..100).contains(&before.len()), "{} chunks", before.len());
+
+        // A few bytes inserted early only change the chunks around them.
+        let mut edited = data.clone();
+        edited.splice(5000..5000, *b"inserted");
+        let after = split(chunking, &edited);
+        let common = after.iter().filter(|c| before.contains(c)).count();
+        assert!(common >= before.len() - 3, "{} of {} chunks kept", common, before.len());
+
+        // Fixed chunks all shift.
+        let fixed = Chunking::Fixed(4096);
+        let before = split(fixed, &data);
+        assert!(split(fixed, &edited).iter().skip(2).all(|c| !before.contains(c)));
+
+        assert!(Chunking::Cdc(100).check().is_err());
+        assert!(Chunking::Fixed(0).check().is_err());
+    }
+
+    #[test]
+    fn test_sized_for() {
+        const KIB: usize = 1024;
+        const GB: u64 = 1_000_000_000;
+        assert_eq!(Chunking::sized_for(256 * KIB, 0), Chunking::Fixed(256 * KIB));
+        assert_eq!(Chunking::sized_for(256 * KIB, 10 * GB), Chunking::Fixed(256 * KIB));
+        assert_eq!(Chunking::sized_for(256 * KIB, 100 * GB), Chunking::Fixed(2048 * KIB));
+        assert_eq!(Chunking::sized_for(256 * KIB, 10_000 * GB), Chunking::Fixed(MAX_FIXED_SIZE));
+        // A larger base is kept as it is.
+        assert_eq!(Chunking::sized_for(16 * MAX_FIXED_SIZE, GB), Chunking::Fixed(16 * MAX_FIXED_SIZE));
+        assert_eq!(Chunking::Cdc(4096).fixed_size(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_chunk_size_in_manifest() {
+        use crate::handshake::{introduced_in, FEATURE_CHUNK_SIZE};
+
+        let (src, dst, out) = (TempDir::new().unwrap(), TempDir::new().unwrap(), TempDir::new().unwrap());
+        let input = src.path().join("input.bin");
+        let payload: Vec<u8> = (0..600_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&input, &payload).unwrap();
+
+        // The receiver learns the size the sender cut with; an older one
+        // gets the manifest without it.
+        let mut sender = client(&src, 1);
+        let mut receiver = client(&dst, 2).with_output_dir(out.path());
+        for expected in [Some(64 * 1024), None] {
+            if expected.is_none() {
+                receiver.env = receiver.env.clone().with_protocol_version(introduced_in(FEATURE_CHUNK_SIZE).unwrap() - 1);
+            }
+            let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+            let (sent, received) = tokio::join!(
+                sender.send_file_streaming(a, &input, false),
+                receiver.accept_and_receive(b),
+            );
+            assert_eq!(sent.unwrap().chunk_size, Some(64 * 1024));
+            assert_eq!(received.unwrap().chunk_size, expected);
+        }
+
+        // Adaptive sizing grows the chunks of large content.
+        sender.cfg.chunk_size = 4;
+        assert_eq!(sender.chunking_for(&input).await.unwrap(), Chunking::Fixed(4));
+        sender.cfg.adaptive_chunk_size = true;
+        assert_eq!(sender.chunking_for(&input).await.unwrap(), Chunking::Fixed(16));
+        assert_eq!(sender.chunking_for(src.path()).await.unwrap(), Chunking::Fixed(16));
+    }
+}
@@ -3,17 +3,130 @@
 //! The client is generic over a Storage implementation and expects a connected
 //! transport stream (TCP or QUIC) that implements AsyncRead + AsyncWrite.
 
-use crate::{Identity, Manifest, config::ClientConfig, handshake};
-use storage::Storage;
-use anyhow::Result;
-use tokio::io::{AsyncRead, AsyncWrite};
-use std::sync::Arc;
+use crate::{Env, Identity, Manifest, config::{ClientConfig, StorageConfig}, error::{self, OpenShareError}, handshake, manifest, pool::ComputePool};
+use crate::chunking::{Chunking, MAX_CDC_SIZE, MIN_CDC_SIZE};
+use crate::codec::{compressible_chunks, Codec};
+use crate::events::{TransferEvent, TransferObserver};
+use crate::history::{Direction, History, TransferRecord};
+use crate::notify::NotificationHub;
+use crate::merkle::{self, ManifestRoot};
+use crate::pages::{self, ManifestHeader, ManifestPage, PageAssembler, PagedManifest};
+use crate::sidecar::Sidecar;
+use crate::stream::{StreamFrame, StreamHasher, StreamHeader, StreamReply, MAX_STREAM_CHUNKS};
+use crate::space::{self, InsufficientSpace};
+use crate::sync::SyncRecord;
+use crate::trash::Trash;
+use crate::vfs::ChunkFetcher;
+use crate::handshake::{PeerInfo, Session, FEATURE_ABORT_REASON, FEATURE_ADMIN, FEATURE_BATCH, FEATURE_CANCEL, FEATURE_CHUNK_ACKS, FEATURE_CHUNK_PROBE, FEATURE_CHUNK_PROOFS, FEATURE_CHUNK_RETRY, FEATURE_CHUNK_SIZE, FEATURE_COMPRESSION, FEATURE_CONTENT_INDEX, FEATURE_DELTA_SYNC, FEATURE_FILE_HASH, FEATURE_FILE_METADATA, FEATURE_KEYED_CHUNKS, FEATURE_MANIFEST_PAGES, FEATURE_NOISE, FEATURE_PARTIAL_PULL, FEATURE_PREHASHED_MANIFESTS, FEATURE_PREVIEWS, FEATURE_PULL, FEATURE_RESUMPTION, FEATURE_SHARE_ANNOUNCE, FEATURE_STREAM, FEATURE_TEXT, FEATURE_TRANSFER_CONSENT};
+use crate::index::{DeviceIndex, IndexEntry};
+use crate::integrity::{self, CorruptChunks, StorageCheck};
+use crate::keyed::{self, ChunkKey};
+use crate::manifests::ManifestStore;
+use crate::quarantine::Quarantine;
+use crate::protocol::{Abort, AbortCode, AdminReply, AdminRequest, AnnounceReply, BatchHeader, Cancel, ChunkAck, ChunkKeyHeader, ChunkFrame, HaveChunks, IndexReply, IndexRequest, PackedChunkFrame, ProvenChunk, PullReply, PullRequest, ShareAnnouncement, Stage, SyncHeader, TextMessage, TextReply, Verdict, MAX_TEXT_LEN};
+use crate::noise;
+use crate::resumption::{self, NewTicket, ResumptionTicket, TicketKey};
+use crate::admin::{AdminHandler, ADMIN_METHODS};
+use crate::offers::Offers;
+use crate::policy::{Limits, Offer, TransferPolicy};
+use crate::preview::Extractor;
+use crate::transfer::{Cancelled, CancelledBy, PeerAborted, Rejected, ResumeState, TransferHandle, TransferId, TransferOutcome};
+use crate::trust::{TrustSource, TrustStatus, TrustStore};
+use storage::{GcStats, Storage};
+use anyhow::{Context, Result};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tracing::Instrument;
+
+/// How long a receiver waits for the sender to close after a transfer.
+const LINGER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Linger until the peer hangs up, so the last frames are delivered before
+/// the transport is dropped (QUIC discards unsent data when a connection
+/// closes). Anything the peer still sends is discarded.
+async fn linger<T: AsyncRead + AsyncWrite + Unpin>(transport: &mut T) {
+    let _ = transport.shutdown().await;
+    let mut buf = [0u8; 16 * 1024];
+    let _ = tokio::time::timeout(LINGER_TIMEOUT, async {
+        while transport.read(&mut buf).await.is_ok_and(|n| n > 0) {}
+    }).await;
+}
 
-#[derive(Clone)]
 pub struct Client<S> {
     pub identity: Arc<Identity>,
     pub storage: Arc<S>,
     pub cfg: ClientConfig,
+    /// Randomness and clock used by the protocol
+    pub env: Env,
+    /// Pool for hashing and other CPU-bound work
+    pub pool: ComputePool,
+    /// Pinned peer keys consulted before accepting a transfer
+    pub trust: Arc<Mutex<TrustStore>>,
+    /// Receives progress events for sends and receives
+    pub observer: Option<Arc<dyn TransferObserver>>,
+    /// Resumption tickets received from peers, keyed by device ID
+    pub tickets: Arc<Mutex<HashMap<String, ResumptionTicket>>>,
+    /// Device IDs of peers last seen offering `noise-xx`; pushes to them
+    /// run the Noise handshake (see [`crate::noise`])
+    pub noise_peers: Arc<Mutex<HashSet<String>>>,
+    /// Local log of completed transfers; `None` when `record_history` is off
+    pub history: Option<History>,
+    /// Published manifests, by id
+    pub manifests: ManifestStore,
+    /// Receives a record of every completed transfer
+    pub notifications: Option<NotificationHub>,
+    /// Peers' chunks that failed verification, and the pairs no longer
+    /// requested because of it
+    pub quarantine: Arc<Mutex<Quarantine>>,
+    /// Where received files are written, if known up front; a directory
+    /// received again is then synced against the copy already there
+    pub output_dir: Option<PathBuf>,
+    /// Decides which pushed transfers to take; see [`crate::policy`]
+    pub policy: Arc<dyn TransferPolicy>,
+    /// Runs requests from admin devices; `None` refuses them. See
+    /// [`crate::admin`]
+    pub admin: Option<Arc<dyn AdminHandler>>,
+    /// Where the content of incoming streams is written as it arrives;
+    /// `None` stores it like a pushed file. See [`crate::stream`]
+    pub stream_output: Option<Arc<tokio::sync::Mutex<StreamOutput>>>,
+    /// Makes the previews of images sent; `None` sends none. See
+    /// [`crate::preview`]
+    pub previews: Option<Extractor>,
+    /// Seals the tickets this client issues when receiving
+    ticket_key: TicketKey,
+}
+
+// Not derived, which would need `S: Clone`; the storage is shared.
+impl<S> Clone for Client<S> {
+    fn clone(&self) -> Self {
+        Self {
+            identity: self.identity.clone(),
+            storage: self.storage.clone(),
+            cfg: self.cfg.clone(),
+            env: self.env.clone(),
+            pool: self.pool.clone(),
+            trust: self.trust.clone(),
+            observer: self.observer.clone(),
+            tickets: self.tickets.clone(),
+            noise_peers: self.noise_peers.clone(),
+            history: self.history.clone(),
+            manifests: self.manifests.clone(),
+            notifications: self.notifications.clone(),
+            quarantine: self.quarantine.clone(),
+            output_dir: self.output_dir.clone(),
+            policy: self.policy.clone(),
+            admin: self.admin.clone(),
+            stream_output: self.stream_output.clone(),
+            previews: self.previews.clone(),
+            ticket_key: self.ticket_key.clone(),
+        }
+    }
 }
 
 impl<S> Client<S>
@@ -21,106 +134,3731 @@ where
     S: Storage + Send + Sync + 'static,
 {
     pub fn new(identity: Identity, storage: S, cfg: ClientConfig) -> Self {
+        let trust = TrustStore::load(&cfg.data_dir).unwrap_or_else(|e| {
+            // Refuse-by-default still applies: an empty store trusts no one.
+            tracing::warn!("Failed to load trust store: {:#}", e);
+            TrustStore::empty(&cfg.data_dir)
+        });
+        let env = compat(Env::system(), &cfg);
+        let history = cfg.record_history.then(|| History::new(&cfg.data_dir));
+        let manifests = ManifestStore::new(&cfg.data_dir);
+        let quarantine = Arc::new(Mutex::new(Quarantine::load(&cfg.data_dir)));
+        let policy = Arc::new(Limits::from_config(&cfg));
+        let previews = if cfg.previews {
+            Extractor::new(Duration::from_secs(cfg.preview_timeout_secs), cfg.preview_memory_bytes)
+                .inspect_err(|e| tracing::warn!("Previews are off: {:#}", e))
+                .ok()
+        } else {
+            None
+        };
         Self {
             identity: Arc::new(identity),
             storage: Arc::new(storage),
+            pool: ComputePool::new(cfg.compute_threads),
             cfg,
+            ticket_key: TicketKey::generate(&env),
+            env,
+            trust: Arc::new(Mutex::new(trust)),
+            observer: None,
+            tickets: Arc::new(Mutex::new(HashMap::new())),
+            noise_peers: Arc::new(Mutex::new(HashSet::new())),
+            history,
+            manifests,
+            notifications: None,
+            quarantine,
+            output_dir: None,
+            policy,
+            admin: None,
+            stream_output: None,
+            previews,
+        }
+    }
+
+    /// Replace the RNG and clock, e.g. with a seeded `Env` in tests. A
+    /// [`compat_version`](ClientConfig::compat_version) still applies.
+    pub fn with_env(mut self, env: Env) -> Self {
+        let env = compat(env, &self.cfg);
+        self.ticket_key = TicketKey::generate(&env);
+        self.env = env;
+        self
+    }
+
+    /// Publish completed transfers to `hub`.
+    pub fn with_notifications(mut self, hub: NotificationHub) -> Self {
+        self.notifications = Some(hub);
+        self
+    }
+
+    /// Receive into `dir`, so that files of a directory already there from
+    /// an earlier transfer need not be sent again. See [`crate::sync`].
+    pub fn with_output_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.output_dir = Some(dir.into());
+        self
+    }
+
+    /// Decide on pushed transfers with `policy` instead of the limits in
+    /// the config.
+    pub fn with_policy(mut self, policy: impl TransferPolicy + 'static) -> Self {
+        self.policy = Arc::new(policy);
+        self
+    }
+
+    /// Take requests from devices trusted as admins and run them with
+    /// `handler`, usually the daemon.
+    pub fn with_admin(mut self, handler: Arc<dyn AdminHandler>) -> Self {
+        self.admin = Some(handler);
+        self
+    }
+
+    /// Write the content of incoming streams to `output` as it arrives,
+    /// e.g. standard output, instead of storing it. Streams are written
+    /// one at a time.
+    pub fn with_stream_output(mut self, output: impl AsyncWrite + Send + Unpin + 'static) -> Self {
+        self.stream_output = Some(Arc::new(tokio::sync::Mutex::new(Box::new(output))));
+        self
+    }
+
+    /// Attach previews made by `extractor` to images sent, whatever the
+    /// config says.
+    pub fn with_previews(mut self, extractor: Extractor) -> Self {
+        self.previews = Some(extractor);
+        self
+    }
+
+    /// Report transfer progress to `observer`.
+    pub fn with_observer(mut self, observer: impl TransferObserver + 'static) -> Self {
+        self.observer = Some(Arc::new(observer));
+        self
+    }
+
+    /// Chunk a file or directory tree into storage and return its
+    /// (unsigned) manifest.
+    pub async fn import_file(&self, path: &Path) -> Result<Manifest, OpenShareError> {
+        let chunking = self.chunking_for(path).await?;
+        let manifest = self.build_manifest(path, chunking).await?;
+        for file in source_files(&manifest, path) {
+            self.store_chunks(&file, chunking.max_len()).await?;
+        }
+        self.storage.add_manifest(&manifest.id(), &manifest.chunk_hashes).await?;
+
+        tracing::debug!("Imported {} ({} chunks)", manifest.filename, manifest.chunk_hashes.len());
+        Ok(manifest)
+    }
+
+    /// Fixed chunks to cut `path` into: `chunk_size`, or with
+    /// `adaptive_chunk_size` larger ones for large content.
+    pub async fn chunking_for(&self, path: &Path) -> Result<Chunking, OpenShareError> {
+        if !self.cfg.adaptive_chunk_size {
+            return Ok(Chunking::Fixed(self.cfg.chunk_size));
+        }
+        let path = path.to_path_buf();
+        let total = self.pool.run(move || manifest::content_size(&path)).await??;
+        Ok(Chunking::sized_for(self.cfg.chunk_size, total))
+    }
+
+    /// Hash a file or directory tree into an (unsigned) manifest on the
+    /// compute pool. A single image gets a preview if previews are on; one
+    /// that cannot be made is left out.
+    pub(crate) async fn build_manifest(&self, path: &Path, chunking: Chunking) -> Result<Manifest> {
+        let key = self.cfg.chunk_key.clone();
+        if path.is_dir() {
+            let root = path.to_path_buf();
+            return Ok(self.pool.run(move || Manifest::from_dir_chunked(&root, chunking, key.as_ref())).await??);
+        }
+        let path_str = path.to_str()
+            .ok_or_else(|| anyhow::anyhow!("Non UTF-8 path: {}", path.display()))?
+            .to_string();
+        let mut manifest = self.pool.run(move || Manifest::from_file_chunked(&path_str, chunking, key.as_ref())).await??;
+        if let Some(extractor) = self.previews.clone() {
+            let path = path.to_path_buf();
+            match tokio::task::spawn_blocking(move || extractor.extract(&path)).await? {
+                Ok(preview) => manifest.preview = preview,
+                Err(e) => tracing::warn!("Sending without a preview: {:#}", e),
+            }
+        }
+        Ok(manifest)
+    }
+
+    async fn store_chunks(&self, path: &Path, chunk_size: usize) -> Result<()> {
+        let mut f = tokio::fs::File::open(path).await?;
+        let size = f.metadata().await?.len();
+        let chunk_size = chunk_size as u64;
+        for _ in 0..size.div_ceil(chunk_size) {
+            let mut chunk = (&mut f).take(chunk_size);
+            match &self.cfg.chunk_key {
+                None => { self.storage.put_chunk_stream(&mut chunk).await?; }
+                Some(key) => {
+                    let mut buf = Vec::new();
+                    chunk.read_to_end(&mut buf).await?;
+                    self.storage.put_chunk_as(&key.chunk_id(&buf), &buf).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reassemble a received file from stored chunks into `output_path`.
+    /// For directory manifests `output_path` is the root of the tree.
+    /// Files it replaces go to the trash next to `output_path` unless the
+    /// trash is disabled, and with `write_sidecars` a checksum sidecar is
+    /// written next to it. See [`Manifest::assemble_to`].
+    pub async fn write_file(&self, manifest: &Manifest, output_path: &Path) -> Result<(), OpenShareError> {
+        let dir = output_path.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        let trash = self.cfg.trash_retention().map(|retention| Trash::new(dir, retention));
+        if manifest.is_directory() {
+            // Files of an earlier version of the tree are reused in place.
+            let moves = self.sync_record(output_path).map_or_else(Vec::new, |r| r.plan(manifest));
+            let renamed = moves.iter().filter(|m| m.from != m.to).count();
+            if renamed > 0 {
+                tracing::info!("Moving {} file(s) already in {} instead of writing them", renamed, output_path.display());
+            }
+            manifest.sync_to(output_path, &*self.storage, trash.as_ref(), &moves, self.cfg.chunk_key.as_ref()).await?;
+            // The record keeps modification times, so they are set first.
+            self.apply_metadata(manifest, output_path).await?;
+            if let Err(e) = SyncRecord::capture(manifest, output_path).and_then(|r| r.save(&self.cfg.data_dir)) {
+                tracing::warn!("Failed to record {} for syncing: {:#}", output_path.display(), e);
+            }
+        } else {
+            manifest.sync_to(output_path, &*self.storage, trash.as_ref(), &[], self.cfg.chunk_key.as_ref()).await?;
+            self.apply_metadata(manifest, output_path).await?;
+        }
+        if self.cfg.write_sidecars {
+            let (manifest, path, now) = (manifest.clone(), output_path.to_path_buf(), self.env.now());
+            tokio::task::spawn_blocking(move || Sidecar::new(&manifest, &path, now)?.write(&path))
+                .await
+                .map_err(anyhow::Error::from)??;
+        }
+        // The chunks are on disk as a file now, so this is when the store
+        // can shrink.
+        if let Err(e) = self.enforce_quota().await {
+            tracing::warn!("Failed to enforce the storage quota: {:#}", e);
+        }
+        Ok(())
+    }
+
+    /// Apply the manifest's metadata to what was written at `path`, unless
+    /// [`preserve_metadata`](ClientConfig::preserve_metadata) is off.
+    async fn apply_metadata(&self, manifest: &Manifest, path: &Path) -> Result<()> {
+        if !self.cfg.preserve_metadata || manifest.metadata.is_empty() {
+            return Ok(());
+        }
+        let (manifest, path) = (manifest.clone(), path.to_path_buf());
+        tokio::task::spawn_blocking(move || manifest.apply_metadata(&path)).await?;
+        Ok(())
+    }
+
+    /// The sync record of the tree at `path`; a record that cannot be
+    /// read only costs a full write.
+    fn sync_record(&self, path: &Path) -> Option<SyncRecord> {
+        SyncRecord::load(&self.cfg.data_dir, path).unwrap_or_else(|e| {
+            tracing::warn!("Ignoring sync record of {}: {:#}", path.display(), e);
+            None
+        })
+    }
+
+    /// Per chunk of a received directory manifest, whether a file already
+    /// in [`output_dir`](Self::output_dir) can supply it.
+    fn synced_chunks(&self, manifest: &Manifest) -> Vec<bool> {
+        let mut synced = vec![false; manifest.chunk_hashes.len()];
+        let target = self.output_dir.as_ref()
+            .filter(|_| manifest.is_directory())
+            .and_then(|dir| manifest.output_path(dir).ok());
+        let Some(record) = target.and_then(|path| self.sync_record(&path)) else {
+            return synced;
+        };
+        for m in record.plan(manifest) {
+            if let Some(entry) = manifest.files.iter().find(|e| e.path == m.to) {
+                let end = (entry.first_chunk + entry.chunk_count).min(synced.len());
+                synced[entry.first_chunk.min(end)..end].fill(true);
+            }
+        }
+        synced
+    }
+
+    /// Cut the file or tree that `listing` would replace in
+    /// [`output_dir`](Self::output_dir) by `chunking`, as the sender of a
+    /// delta sync cut its own, and store the chunks, so the chunk probe
+    /// counts them as held. Of a whole manifest only the chunks it lists
+    /// are kept; a paged one is not known yet, so all are. Chunk ids are
+    /// keyed with `key` if the push is. Returns how many chunks were
+    /// stored.
+    async fn seed_chunks(&self, listing: &Listing, chunking: Chunking, key: Option<&ChunkKey>) -> Result<usize> {
+        let Some(dir) = &self.output_dir else { return Ok(0) };
+        let target = manifest::output_path(listing.filename(), dir)?;
+        let files = if target.is_dir() {
+            let root = target.clone();
+            tokio::task::spawn_blocking(move || manifest::tree_files(&root)).await??
+        } else if target.is_file() {
+            vec![target]
+        } else {
+            return Ok(0);
+        };
+        let listed: Option<HashSet<&str>> = match listing {
+            Listing::Whole(manifest) => Some(manifest.chunk_hashes.iter().map(String::as_str).collect()),
+            Listing::Paged(_) => None,
+        };
+
+        let mut reader = FileChunks::new(files, chunking);
+        let mut seeded = 0;
+        while let Some((_, chunk)) = reader.next().await? {
+            let hash = keyed::chunk_id(key, &chunk);
+            if listed.as_ref().is_some_and(|l| !l.contains(hash.as_str())) || self.storage.has_chunk(&hash).await? {
+                continue;
+            }
+            store_chunk(&*self.storage, &hash, &chunk, key.is_some()).await?;
+            seeded += 1;
+        }
+        Ok(seeded)
+    }
+
+    /// Delete every stored chunk not used by a published manifest.
+    pub async fn collect_garbage(&self) -> Result<GcStats, OpenShareError> {
+        let retain = self.record_published().await?;
+        Ok(self.storage.gc(&retain).await?)
+    }
+
+    /// Read back every stored chunk and check it against its id, dealing
+    /// with corrupt ones as `action` says; see [`crate::integrity`].
+    pub async fn verify_storage(&self, action: CorruptChunks) -> Result<StorageCheck, OpenShareError> {
+        Ok(integrity::verify_storage(&*self.storage, self.cfg.chunk_key.as_ref(), &self.cfg.data_dir, action).await?)
+    }
+
+    /// Evict least recently used chunks of unpublished manifests until
+    /// storage fits `storage_quota_bytes` (if set).
+    pub async fn enforce_quota(&self) -> Result<GcStats, OpenShareError> {
+        if self.cfg.storage_quota_bytes == 0 {
+            return Ok(GcStats::default());
+        }
+        let retain = self.record_published().await?;
+        let stats = self.storage.evict(self.cfg.storage_quota_bytes, &retain).await?;
+        if stats.chunks > 0 {
+            tracing::info!("Evicted {} chunks ({} bytes) to stay within the storage quota", stats.chunks, stats.bytes);
+        }
+        Ok(stats)
+    }
+
+    /// Make sure storage knows the chunks of every published manifest
+    /// (publishing itself does not touch storage), returning their IDs.
+    async fn record_published(&self) -> Result<Vec<String>> {
+        let mut ids = Vec::new();
+        for manifest in self.published_manifests()? {
+            let id = manifest.id();
+            self.storage.add_manifest(&id, &manifest.chunk_hashes).await?;
+            ids.push(id);
         }
+        Ok(ids)
     }
 
     /// Send a manifest and its chunks to a connected peer transport.
     /// The transport must be already connected. The handshake is performed
     /// over the transport, returning an encrypted session.
-    pub async fn send_manifest_over<T>(&self, mut transport: T, manifest: Manifest) -> Result<()>
+    pub async fn send_manifest_over<T>(&self, transport: T, manifest: Manifest) -> Result<(), OpenShareError>
     where
         T: AsyncRead + AsyncWrite + Unpin + Send,
     {
-        tracing::info!("Starting send: {}", manifest.filename);
+        let mut attempt = Attempt::new(Some(Direction::Sent));
+        let result = self.send_inner(transport, manifest, None, None, ChunkSource::Storage, None, &mut attempt).instrument(transfer_span()).await;
+        self.report(result, &attempt)
+    }
 
-        // 1) Sign manifest
-        let mut manifest = manifest;
-        manifest.sign(&self.identity)?;
+    /// Like [`send_manifest_over`](Self::send_manifest_over), but only to
+    /// a peer whose identity key has `fingerprint`, such as the one it
+    /// advertised over mDNS; anyone else is refused right after the
+    /// handshake, before the manifest goes out. See
+    /// [`Identity::fingerprint_matches`](crate::keys::Identity::fingerprint_matches).
+    pub async fn send_manifest_pinned<T>(&self, transport: T, manifest: Manifest, fingerprint: &str) -> Result<(), OpenShareError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let mut attempt = Attempt::new(Some(Direction::Sent));
+        let result = self.send_inner(transport, manifest, None, Some(fingerprint), ChunkSource::Storage, None, &mut attempt).instrument(transfer_span()).await;
+        self.report(result, &attempt)
+    }
 
-        // 2) Perform initiator handshake over transport -> Session (AEAD)
-        tracing::debug!("Performing handshake...");
-        let session = handshake::initiator_handshake(&self.identity, &mut transport).await?;
-        tracing::debug!("Handshake complete");
+    /// Like [`send_manifest_over`](Self::send_manifest_over), paused and
+    /// cancelled through `handle`. See [`crate::transfer`].
+    pub async fn send_manifest_with<T>(&self, transport: T, manifest: Manifest, handle: &TransferHandle) -> Result<TransferOutcome, OpenShareError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let mut attempt = Attempt::new(Some(Direction::Sent));
+        let result = self.send_inner(transport, manifest, None, None, ChunkSource::Storage, Some(handle), &mut attempt).instrument(transfer_span()).await;
+        outcome(self.report(result, &attempt))
+    }
+
+    /// Like [`send_manifest_over`](Self::send_manifest_over), but resumes
+    /// the session with a ticket previously received from `device_id`
+    /// when one is cached, skipping the full handshake. Without one, the
+    /// full handshake runs over Noise if `device_id` was seen offering it.
+    pub async fn send_manifest_to<T>(&self, transport: T, manifest: Manifest, device_id: &str) -> Result<(), OpenShareError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let mut attempt = Attempt::new(Some(Direction::Sent));
+        let result = self.send_inner(transport, manifest, Some(device_id), None, ChunkSource::Storage, None, &mut attempt).instrument(transfer_span()).await;
+        self.report(result, &attempt)
+    }
+
+    /// Send a file or directory tree without importing it first: chunks are
+    /// read straight from `path` and encrypted as they go, instead of
+    /// being written to storage and read back. The manifest must precede
+    /// the chunks, so the source is still read twice (once to hash it).
+    /// With `persist_chunks` the chunks are also stored on the way out.
+    pub async fn send_file_streaming<T>(&self, transport: T, path: &Path, persist_chunks: bool) -> Result<Manifest, OpenShareError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let mut attempt = Attempt::new(Some(Direction::Sent));
+        let result = async {
+            let manifest = self.build_manifest(path, self.chunking_for(path).await?).await?;
+            self.send_streamed(transport, &manifest, path, persist_chunks, None, None, None, &mut attempt).instrument(transfer_span()).await?;
+            Ok(manifest)
+        }.await;
+        self.report(result, &attempt)
+    }
+
+    /// Like [`send_file_streaming`](Self::send_file_streaming), paused and
+    /// cancelled through `handle`. Returns the manifest either way, so a
+    /// cancelled send can be recognised later. See [`crate::transfer`].
+    pub async fn send_file_streaming_with<T>(
+        &self,
+        transport: T,
+        path: &Path,
+        persist_chunks: bool,
+        handle: &TransferHandle,
+    ) -> Result<(Manifest, TransferOutcome), OpenShareError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        self.stream_file(transport, path, persist_chunks, None, handle).await
+    }
+
+    /// Like [`send_file_streaming_with`](Self::send_file_streaming_with),
+    /// but only to a peer whose identity key has `fingerprint`, as with
+    /// [`send_manifest_pinned`](Self::send_manifest_pinned).
+    pub async fn send_file_streaming_pinned<T>(
+        &self,
+        transport: T,
+        path: &Path,
+        persist_chunks: bool,
+        fingerprint: &str,
+        handle: &TransferHandle,
+    ) -> Result<(Manifest, TransferOutcome), OpenShareError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        self.stream_file(transport, path, persist_chunks, Some(fingerprint), handle).await
+    }
+
+    async fn stream_file<T>(
+        &self,
+        transport: T,
+        path: &Path,
+        persist_chunks: bool,
+        fingerprint: Option<&str>,
+        handle: &TransferHandle,
+    ) -> Result<(Manifest, TransferOutcome), OpenShareError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let mut attempt = Attempt::new(Some(Direction::Sent));
+        let built = async { self.build_manifest(path, self.chunking_for(path).await?).await };
+        let manifest = match built.await {
+            Ok(manifest) => manifest,
+            Err(e) => return self.report(Err(e), &attempt),
+        };
+        let result = self.send_streamed(transport, &manifest, path, persist_chunks, fingerprint, None, Some(handle), &mut attempt).instrument(transfer_span()).await;
+        Ok((manifest, outcome(self.report(result, &attempt))?))
+    }
+
+    /// Send a file or directory tree as a delta sync: chunks are cut by
+    /// content (FastCDC, averaging `chunk_size`), and a peer with
+    /// `delta-sync` reuses what it holds where the transfer lands, so
+    /// sending an edited file again only transfers the chunks around
+    /// the edits. Older peers get an ordinary push. Streams from `path`
+    /// like [`send_file_streaming_with`](Self::send_file_streaming_with).
+    pub async fn sync_file<T>(&self, transport: T, path: &Path, handle: &TransferHandle) -> Result<(Manifest, TransferOutcome), OpenShareError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let mut attempt = Attempt::new(Some(Direction::Sent));
+        let chunking = Chunking::Cdc(self.cfg.chunk_size.clamp(MIN_CDC_SIZE, MAX_CDC_SIZE));
+        let manifest = match self.build_manifest(path, chunking).await {
+            Ok(manifest) => manifest,
+            Err(e) => return self.report(Err(e), &attempt),
+        };
+        let result = self.send_streamed(transport, &manifest, path, false, None, Some(chunking), Some(handle), &mut attempt).instrument(transfer_span()).await;
+        Ok((manifest, outcome(self.report(result, &attempt))?))
+    }
+
+    /// Send what `reader` yields as a stream named `filename`, for content
+    /// whose size is not known up front such as a pipe: chunks go out as
+    /// they are read and hashed, and the signed manifest after the last
+    /// (see [`crate::stream`]). Nothing is stored. Cancelled through
+    /// `handle`, returning the manifest of what was sent, but a stream
+    /// cannot be resumed. Needs a peer with `stream`.
+    pub async fn send_stream<T, R>(
+        &self,
+        transport: T,
+        reader: R,
+        filename: &str,
+        handle: &TransferHandle,
+    ) -> Result<(Manifest, TransferOutcome), OpenShareError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+        R: AsyncRead + Unpin + Send,
+    {
+        let mut attempt = Attempt::new(Some(Direction::Sent));
+        attempt.filename = Some(filename.to_string());
+        let header = StreamHeader { filename: filename.to_string(), chunk_size: self.cfg.chunk_size.try_into().unwrap_or(u32::MAX) };
+        let mut hasher = StreamHasher::new(&header);
+        let result = async {
+            header.check()?;
+            self.send_stream_inner(transport, reader, &header, &mut hasher, handle, &mut attempt).await
+        }.instrument(transfer_span()).await;
+        match self.report(result, &attempt) {
+            Ok(manifest) => Ok((manifest, TransferOutcome::Completed)),
+            Err(OpenShareError::Cancelled(cancelled)) => Ok((hasher.manifest(), TransferOutcome::Cancelled(cancelled))),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn send_stream_inner<T, R>(
+        &self,
+        mut transport: T,
+        mut reader: R,
+        header: &StreamHeader,
+        hasher: &mut StreamHasher,
+        handle: &TransferHandle,
+        attempt: &mut Attempt,
+    ) -> Result<Manifest>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+        R: AsyncRead + Unpin + Send,
+    {
+        tracing::info!("Starting stream: {}", header.filename);
+        let session = self.full_handshake(&mut transport, None).await?;
+        self.emit(TransferEvent::HandshakeComplete {
+            transfer_id: session.transfer_id(),
+            peer: session.peer.clone(),
+            resumed: session.resumed,
+        });
+        attempt.session(&session);
+        let peer = session.peer.clone()
+            .ok_or_else(|| anyhow::anyhow!("Peer did not identify itself; refusing to stream"))?;
+        self.warn_if_outdated(&session);
+        self.warn_if_skewed(&session);
+        self.note_noise(&session);
+        if !peer.supports(FEATURE_STREAM) {
+            anyhow::bail!(
+                "{} cannot take streams (protocol {}); save the content to a file and send that",
+                peer.device_id, peer.protocol_version
+            );
+        }
+
+        let streamed = self.push_stream(&session, &mut transport, &mut reader, header, hasher, handle, attempt).await;
+        let (manifest, traffic) = self.abort_if_failed(&session, &mut transport, streamed, attempt).await?;
+        if peer.supports(FEATURE_RESUMPTION) {
+            self.collect_ticket(&session, &mut transport).await;
+        }
 
-        // 3) Send manifest as bincode over encrypted frame
-        tracing::debug!("Sending manifest...");
-        let manifest_bytes = bincode::serialize(&manifest)?;
-        session.send_encrypted_frame(&mut transport, &manifest_bytes).await?;
-        tracing::info!("Manifest sent, {} chunks to transfer", manifest.chunk_hashes.len());
+        self.record(Direction::Sent, Some(&peer), &manifest, manifest.size, traffic, attempt);
+        tracing::info!("Transfer complete: {} ({} bytes)", manifest.filename, manifest.size);
+        self.emit(TransferEvent::TransferComplete {
+            transfer_id: session.transfer_id(),
+            filename: manifest.filename.clone(),
+            size: manifest.size,
+        });
+        Ok(manifest)
+    }
 
-        // 4) For each chunk hash in manifest, fetch from storage and send
-        for (i, chunk_hash) in manifest.chunk_hashes.iter().enumerate() {
-            if let Some(data) = self.storage.get_chunk(chunk_hash).await? {
-                session.send_encrypted_frame(&mut transport, &data).await?;
+    /// Open a stream with `header` and send `reader` in chunks, keeping up
+    /// to `max_parallel_chunks` unacknowledged, then the signed manifest.
+    /// Returns it and the bytes sent.
+    #[allow(clippy::too_many_arguments)]
+    async fn push_stream<T, R>(
+        &self,
+        session: &Session,
+        transport: &mut T,
+        reader: &mut R,
+        header: &StreamHeader,
+        hasher: &mut StreamHasher,
+        handle: &TransferHandle,
+        attempt: &mut Attempt,
+    ) -> Result<(Manifest, Traffic)>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+        R: AsyncRead + Unpin + Send,
+    {
+        let device_id = session.peer.as_ref().map_or("peer", |p| p.device_id.as_str());
+        session.send_encrypted_frame(transport, &header.to_frame()?).await?;
+        read_verdict(session, transport, device_id).await?;
+        self.emit(TransferEvent::ManifestSent {
+            transfer_id: session.transfer_id(),
+            filename: header.filename.clone(),
+            size: 0,
+            total_chunks: 0,
+        });
 
-                if (i + 1) % 10 == 0 {
-                    tracing::info!("Sent {}/{} chunks", i + 1, manifest.chunk_hashes.len());
+        let window = self.cfg.max_parallel_chunks.max(1);
+        let (mut unacked, mut traffic) = (0, Traffic::default());
+        let mut buf = vec![0u8; header.chunk_size as usize];
+        loop {
+            if handle.checkpoint().await {
+                let cancel = Cancel { reason: "cancelled by the sender".into() };
+                let sent = async {
+                    session.send_encrypted_frame(transport, &cancel.to_frame()?).await?;
+                    for _ in 0..unacked {
+                        read_stream_ack(session, transport).await?;
+                    }
+                    anyhow::Ok(())
+                }.await;
+                if let Err(e) = sent {
+                    tracing::debug!("Failed to finish cancelling: {:#}", e);
                 }
-            } else {
-                // If chunk missing, warn and skip (or could abort)
-                tracing::warn!("Chunk {} missing locally; skipping", chunk_hash);
+                tracing::info!("Cancelled stream {} after {} chunks", header.filename, hasher.chunks());
+                let done = hasher.chunks();
+                return Err(Cancelled { by: CancelledBy::Local, chunks_done: done, chunks_total: done }.into());
+            }
+            let len = read_full(reader, &mut buf).await.context("Failed to read the stream")?;
+            if len == 0 {
+                break;
+            }
+            if hasher.chunks() == MAX_STREAM_CHUNKS {
+                anyhow::bail!(
+                    "Stream is longer than {} chunks, more than one manifest lists; send it with a larger chunk size",
+                    MAX_STREAM_CHUNKS
+                );
+            }
+            if unacked == window {
+                read_stream_ack(session, transport).await?;
+                unacked -= 1;
+            }
+            let data = buf[..len].to_vec();
+            hasher.add(&data);
+            let n = hasher.chunks();
+            attempt.chunk(n, n);
+            traffic.bytes += len as u64;
+            session.send_encrypted_frame(transport, &bincode::serialize(&StreamFrame::Chunk(data))?).await?;
+            unacked += 1;
+            self.emit(TransferEvent::ChunkSent { transfer_id: session.transfer_id(), index: n - 1, total: n, bytes: len });
+            // Only the last chunk is short
+            if len < buf.len() {
+                break;
+            }
+        }
+        for _ in 0..unacked {
+            read_stream_ack(session, transport).await?;
+        }
+
+        attempt.stage = Stage::Finishing;
+        let mut manifest = hasher.manifest();
+        manifest.sign_prehashed(&self.identity)?;
+        let end = StreamFrame::End(manifest.to_bytes()?);
+        session.send_encrypted_frame(transport, &bincode::serialize(&end)?).await?;
+        let frame = session.read_encrypted_frame(transport).await?;
+        check_abort(session, &frame)?;
+        match bincode::deserialize(&frame)? {
+            StreamReply::Received => Ok((manifest, traffic)),
+            StreamReply::Refused(reason) => anyhow::bail!("{} refused the stream: {}", device_id, reason),
+        }
+    }
+
+    /// Send `manifest`, built from `path` by [`Self::build_manifest`],
+    /// streaming its chunks from the files; for sending one build to
+    /// several peers.
+    pub(crate) async fn send_built<T>(
+        &self,
+        transport: T,
+        manifest: &Manifest,
+        path: &Path,
+        handle: &TransferHandle,
+    ) -> Result<TransferOutcome, OpenShareError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let mut attempt = Attempt::new(Some(Direction::Sent));
+        let result = self.send_streamed(transport, manifest, path, false, None, None, Some(handle), &mut attempt).instrument(transfer_span()).await;
+        outcome(self.report(result, &attempt))
+    }
+
+    /// Send several files or directory trees in one session, after a
+    /// single handshake: a [`BatchHeader`] says how many pushes follow,
+    /// each as [`send_file_streaming`](Self::send_file_streaming) would
+    /// make it. Each is recorded on its own, and one the receiver rejects
+    /// is skipped; any other failure ends the batch. Returns the manifests
+    /// delivered, in order. Needs a peer with `batch`.
+    pub async fn send_batch<T>(&self, transport: T, paths: Vec<PathBuf>) -> Result<Vec<Manifest>, OpenShareError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let mut attempt = Attempt::new(Some(Direction::Sent));
+        let result = self.send_batch_inner(transport, &paths, &mut attempt).instrument(transfer_span()).await;
+        self.report(result, &attempt)
+    }
+
+    async fn send_batch_inner<T>(&self, mut transport: T, paths: &[PathBuf], attempt: &mut Attempt) -> Result<Vec<Manifest>>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        if paths.is_empty() {
+            anyhow::bail!("Nothing to send");
+        }
+        let mut manifests = Vec::with_capacity(paths.len());
+        for path in paths {
+            manifests.push(self.build_manifest(path, self.chunking_for(path).await?).await?);
+        }
+        tracing::info!("Starting batch send: {} transfers", manifests.len());
+
+        let session = handshake::initiator_handshake_with(&self.identity, &self.cfg.device_id, &mut transport, &self.env).await?;
+        self.emit(TransferEvent::HandshakeComplete {
+            transfer_id: session.transfer_id(),
+            peer: session.peer.clone(),
+            resumed: session.resumed,
+        });
+        attempt.session(&session);
+        let peer = session.peer.as_ref();
+        self.warn_if_outdated(&session);
+        self.warn_if_skewed(&session);
+        self.note_noise(&session);
+        if !peer.is_some_and(|p| p.supports(FEATURE_BATCH)) {
+            anyhow::bail!("Peer cannot take several transfers in one session");
+        }
+        let header = BatchHeader { count: manifests.len() as u32 };
+        session.send_encrypted_frame(&mut transport, &header.to_frame()?).await?;
+
+        let mut sent = Vec::with_capacity(manifests.len());
+        for (manifest, path) in manifests.into_iter().zip(paths) {
+            let mut item = Attempt::new(Some(Direction::Sent));
+            item.session(&session);
+            item.manifest(&manifest);
+            let chunking = manifest.chunking().unwrap_or(Chunking::Fixed(self.cfg.chunk_size));
+            let mut source = ChunkSource::Files {
+                root: path.clone(),
+                reader: Box::new(FileChunks::new(source_files(&manifest, path), chunking)),
+                persist: false,
+                sync: false,
+            };
+            let pushed = self.push(&session, &mut transport, manifest, None, &mut source, None, &mut item).await;
+            match self.abort_if_failed(&session, &mut transport, pushed, &item).await {
+                Ok((manifest, traffic)) => {
+                    if let Some(peer) = peer {
+                        self.forget_resume_state(&manifest, &peer.device_id);
+                    }
+                    self.record(Direction::Sent, peer, &manifest, manifest.size, traffic, &item);
+                    tracing::info!("Transfer complete: {}", manifest.filename);
+                    self.emit(TransferEvent::TransferComplete {
+                        transfer_id: session.transfer_id(),
+                        filename: manifest.filename.clone(),
+                        size: manifest.size,
+                    });
+                    sent.push(manifest);
+                }
+                // The receiver said no to this one and waits for the next
+                Err(e) if error::find::<Rejected>(&e).is_some() && peer.is_some_and(|p| p.supports(FEATURE_TRANSFER_CONSENT)) => {
+                    if let Err(e) = self.report::<()>(Err(e), &item) {
+                        tracing::info!("Skipping: {:#}", e);
+                    }
+                }
+                Err(e) => {
+                    *attempt = item;
+                    return Err(e);
+                }
+            }
+        }
+
+        if peer.is_some_and(|p| p.supports(FEATURE_RESUMPTION)) {
+            self.collect_ticket(&session, &mut transport).await;
+        }
+        Ok(sent)
+    }
+
+    /// Stream `manifest`'s chunks from `path`. With `sync`, the manifest
+    /// was cut that way for a delta sync; otherwise in fixed chunks of the
+    /// size it records.
+    #[allow(clippy::too_many_arguments)]
+    async fn send_streamed<T>(
+        &self,
+        transport: T,
+        manifest: &Manifest,
+        path: &Path,
+        persist_chunks: bool,
+        fingerprint: Option<&str>,
+        sync: Option<Chunking>,
+        handle: Option<&TransferHandle>,
+        attempt: &mut Attempt,
+    ) -> Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let chunking = sync.or(manifest.chunking()).unwrap_or(Chunking::Fixed(self.cfg.chunk_size));
+        let source = ChunkSource::Files {
+            root: path.to_path_buf(),
+            reader: Box::new(FileChunks::new(source_files(manifest, path), chunking)),
+            persist: persist_chunks,
+            sync: sync.is_some(),
+        };
+        self.send_inner(transport, manifest.clone(), None, fingerprint, source, handle, attempt).await
+    }
+
+    /// Send to the peer on `transport`, resuming a session with `device_id`
+    /// if there is a ticket for it, and refusing a peer without
+    /// `fingerprint` if given.
+    #[allow(clippy::too_many_arguments)]
+    async fn send_inner<T>(
+        &self,
+        mut transport: T,
+        manifest: Manifest,
+        device_id: Option<&str>,
+        fingerprint: Option<&str>,
+        mut source: ChunkSource,
+        handle: Option<&TransferHandle>,
+        attempt: &mut Attempt,
+    ) -> Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        tracing::info!("Starting send: {}", manifest.filename);
+        attempt.manifest(&manifest);
+
+        // 1) Perform initiator handshake over transport -> Session (AEAD)
+        tracing::debug!("Performing handshake...");
+        let (session, early) = match device_id.and_then(|id| self.take_ticket(id)) {
+            // Offer the manifest as 0-RTT data, signed for the peer version
+            // the ticket remembers; it is only delivered if the ticket is
+            // accepted. A manifest sent in pages does not fit, nor one that
+            // must follow a chunk key header.
+            Some(ticket) => {
+                let early = match self.cfg.chunk_key {
+                    Some(_) => None,
+                    None => match self.seal_manifest(manifest.clone(), Some(&ticket.peer))? {
+                        (manifest, Sealed::Whole(bytes)) => Some((manifest, bytes)),
+                        (_, Sealed::Paged(_)) => None,
+                    },
+                };
+                let session = resumption::initiator_resume(
+                    &ticket, early.as_ref().map(|(_, bytes)| &bytes[..]), &self.identity, &self.cfg.device_id, &mut transport, &self.env,
+                ).await?;
+                (session, early)
             }
+            None => (self.full_handshake(&mut transport, device_id).await?, None),
+        };
+        tracing::debug!("Handshake complete (resumed: {})", session.resumed);
+        self.emit(TransferEvent::HandshakeComplete {
+            transfer_id: session.transfer_id(),
+            peer: session.peer.clone(),
+            resumed: session.resumed,
+        });
+        attempt.session(&session);
+        let peer = session.peer.as_ref();
+        if let Some(fingerprint) = fingerprint {
+            handshake::expect_fingerprint(peer, fingerprint)?;
+        }
+        self.warn_if_outdated(&session);
+        self.warn_if_skewed(&session);
+        self.note_noise(&session);
+
+        let sent = self.push(&session, &mut transport, manifest, early, &mut source, handle, attempt).await;
+        let (manifest, traffic) = self.abort_if_failed(&session, &mut transport, sent, attempt).await?;
+
+        if peer.is_some_and(|p| p.supports(FEATURE_RESUMPTION)) {
+            self.collect_ticket(&session, &mut transport).await;
+        }
+        if let Some(peer) = peer {
+            self.forget_resume_state(&manifest, &peer.device_id);
         }
 
+        self.record(Direction::Sent, peer, &manifest, manifest.size, traffic, attempt);
         tracing::info!("Transfer complete: {}", manifest.filename);
+        self.emit(TransferEvent::TransferComplete {
+            transfer_id: session.transfer_id(),
+            filename: manifest.filename.clone(),
+            size: manifest.size,
+        });
         Ok(())
     }
 
-    /// Accept an incoming transport, run responder handshake and receive
-    /// an incoming manifest followed by chunks; store chunks into storage.
-    pub async fn accept_and_receive<T>(&self, mut transport: T) -> Result<Manifest>
+    /// Push `manifest` over an established session: any chunk key and sync
+    /// headers, then the manifest, signed for the peer unless it went out
+    /// as `early` 0-RTT data, and its chunks from `source`. Returns the
+    /// manifest as signed and the chunk bytes sent.
+    #[allow(clippy::too_many_arguments)]
+    async fn push<T>(
+        &self,
+        session: &Session,
+        transport: &mut T,
+        manifest: Manifest,
+        early: Option<(Manifest, Vec<u8>)>,
+        source: &mut ChunkSource,
+        handle: Option<&TransferHandle>,
+        attempt: &mut Attempt,
+    ) -> Result<(Manifest, Traffic)>
     where
         T: AsyncRead + AsyncWrite + Unpin + Send,
     {
-        tracing::info!("Starting receive...");
+        let peer = session.peer.as_ref();
+        if let Some(key) = &self.cfg.chunk_key {
+            match peer {
+                Some(p) if p.supports(FEATURE_KEYED_CHUNKS) => {
+                    let header = ChunkKeyHeader { key_id: key.key_id() };
+                    session.send_encrypted_frame(transport, &header.to_frame()?).await?;
+                }
+                _ => anyhow::bail!("Peer cannot take chunk ids keyed with the account secret"),
+            }
+        }
+        if let ChunkSource::Files { reader, sync: true, .. } = &source {
+            match peer {
+                Some(p) if p.supports(FEATURE_DELTA_SYNC) && p.supports(FEATURE_CHUNK_PROBE) => {
+                    let header = SyncHeader { chunking: reader.chunking };
+                    session.send_encrypted_frame(transport, &header.to_frame()?).await?;
+                }
+                _ => tracing::info!("Peer cannot sync against what it holds; sending {} in full", manifest.filename),
+            }
+        }
 
-        // Run responder handshake
-        tracing::debug!("Performing handshake...");
-        let session = handshake::responder_handshake(&self.identity, &mut transport).await?;
-        tracing::debug!("Handshake complete");
+        // 2) Sign manifest in the encoding the peer understands
+        let (manifest, sealed, delivered) = match early {
+            Some((manifest, bytes)) if session.resumed => (manifest, Sealed::Whole(bytes), true),
+            _ => {
+                let (manifest, sealed) = self.seal_manifest(manifest, peer)?;
+                (manifest, sealed, false)
+            }
+        };
 
-        // Read manifest
-        tracing::debug!("Receiving manifest...");
-        let manifest_bytes = session.read_encrypted_frame(&mut transport).await?;
-        let manifest: Manifest = bincode::deserialize(&manifest_bytes)?;
+        // 3) Send manifest and chunks; the manifest already went out as
+        //    0-RTT data if the session was resumed with it
+        let selection = match peer {
+            Some(p) if p.supports(FEATURE_CHUNK_PROBE) => ChunkSelection::Missing,
+            _ => ChunkSelection::All,
+        };
+        let traffic = self.send_payload(
+            session, transport, &manifest, &sealed, peer, !delivered, source, selection, handle, attempt,
+        ).await?;
+        Ok((manifest, traffic))
+    }
 
-        tracing::info!("Receiving: {} ({} chunks)",
-            manifest.filename, manifest.chunk_hashes.len());
+    /// Send `manifest` (signed and encoded as `sealed`) and the chunks in
+    /// `selection` from `source` over an established session, checking
+    /// `handle` before each chunk. A paged manifest goes out a page at a
+    /// time, each page ahead of its chunks. Returns the chunk bytes sent.
+    #[allow(clippy::too_many_arguments)]
+    async fn send_payload<T>(
+        &self,
+        session: &Session,
+        transport: &mut T,
+        manifest: &Manifest,
+        sealed: &Sealed,
+        peer: Option<&PeerInfo>,
+        send_manifest: bool,
+        source: &mut ChunkSource,
+        selection: ChunkSelection<'_>,
+        handle: Option<&TransferHandle>,
+        attempt: &mut Attempt,
+    ) -> Result<Traffic>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let acks = peer.is_some_and(|p| p.supports(FEATURE_CHUNK_ACKS));
+        let packed = peer.is_some_and(|p| p.supports(FEATURE_COMPRESSION));
+        let codec = match self.cfg.compression.feature() {
+            Some(feature) if packed && peer.is_some_and(|p| p.supports(feature)) => self.cfg.compression,
+            _ => Codec::None,
+        };
+        let compressible = compressible_chunks(manifest);
 
-        // Store incoming chunks (we store all chunks sequentially)
-        for (i, chunk_hash) in manifest.chunk_hashes.iter().enumerate() {
-            let chunk = session.read_encrypted_frame(&mut transport).await?;
+        // Manifest as bincode over an encrypted frame, or the header of one
+        // sent in pages
+        let paged = match sealed {
+            Sealed::Whole(bytes) => {
+                if send_manifest {
+                    tracing::debug!("Sending manifest...");
+                    session.send_encrypted_frame(transport, bytes).await?;
+                }
+                None
+            }
+            Sealed::Paged(paged) => {
+                tracing::debug!("Sending manifest in {} pages...", paged.header.pages);
+                session.send_encrypted_frame(transport, &paged.header.to_frame()?).await?;
+                Some(paged.as_ref())
+            }
+        };
+        let push = attempt.direction == Some(Direction::Sent);
+        if let Some(peer) = peer.filter(|p| push && p.supports(FEATURE_TRANSFER_CONSENT)) {
+            read_verdict(session, transport, &peer.device_id).await?;
+        }
+        let count = manifest.chunk_hashes.len();
+        let mut skip = vec![false; count];
+        let probe = matches!(selection, ChunkSelection::Missing);
+        let order: Vec<usize> = match selection {
+            ChunkSelection::Only(indices) => indices.iter().map(|&i| i as usize).collect(),
+            ChunkSelection::All | ChunkSelection::Missing => (0..count).collect(),
+        };
+        if probe && paged.is_none() {
+            read_have(session, transport, 0..count, &mut skip).await?;
+        }
+        if order.iter().any(|&i| i >= count) {
+            anyhow::bail!("Requested chunk index out of range");
+        }
+        // With pages, chunks the receiver holds only show up page by page.
+        let mut total = order.iter().filter(|&&i| !skip[i]).count();
+        tracing::info!("Manifest sent, {} chunks to transfer ({} already at the receiver)", total, order.len() - total);
+        self.emit(TransferEvent::ManifestSent {
+            transfer_id: session.transfer_id(),
+            filename: manifest.filename.clone(),
+            size: manifest.size,
+            total_chunks: total,
+        });
 
-            // Verify chunk hash matches expected
-            use sha2::{Digest, Sha256};
-            let mut hasher = Sha256::new();
-            hasher.update(&chunk);
-            let computed = hasher.finalize();
-            let hex = hex::encode(computed);
+        // Stream chunks, keeping up to `max_parallel_chunks` unacknowledged.
+        // Peers without chunk acks get bare, strictly ordered frames.
+        let window = self.cfg.max_parallel_chunks.max(1);
+        let mut unacked = 0;
+        let mut traffic = Traffic::default();
+        let mut n = 0;
+        let retries = match peer {
+            Some(p) if push && acks && p.supports(FEATURE_CHUNK_RETRY) => self.cfg.chunk_retries,
+            _ => 0,
+        };
+        let mut resend = Resend::new(retries, push, matches!(source, ChunkSource::Files { .. }));
+        // Chunks the receiver stored, for a resume state
+        let mut acked = Vec::new();
+        // Pages sent, and the chunks they list
+        let (mut pages, mut listed) = (0, 0);
+        for &i in &order {
+            while let Some(paged) = paged.filter(|p| i >= listed && pages < p.header.pages as usize) {
+                total -= self.send_page(session, transport, manifest, paged, pages, probe, &mut skip, &mut acked, &mut unacked, &mut resend).await?;
+                listed = paged.chunks(pages).end;
+                pages += 1;
+            }
+            let chunk_hash = &manifest.chunk_hashes[i];
+            if skip[i] {
+                // Streamed sources are read in manifest order, so a skipped
+                // chunk must still be consumed.
+                if let ChunkSource::Files { .. } = source {
+                    source.next_chunk(&*self.storage, chunk_hash, self.cfg.chunk_key.as_ref()).await?;
+                }
+                continue;
+            }
+            if let Some(handle) = handle {
+                if handle.checkpoint().await {
+                    let acked = self.cancel(session, transport, manifest, peer, source, acked, unacked, &mut resend).await;
+                    return Err(Cancelled { by: CancelledBy::Local, chunks_done: acked, chunks_total: total }.into());
+                }
+            }
+            n += 1;
+            attempt.chunk(n, total);
+            if acks && unacked == window {
+                acked.extend(self.read_ack(session, transport, manifest.chunk_hashes.len(), &mut resend).await?);
+                unacked -= 1;
+            }
 
-            if &hex != chunk_hash {
-                tracing::warn!("Chunk hash mismatch: expected {} got {}", chunk_hash, hex);
-                // Continue or handle error - here we continue
+            let data = source.next_chunk(&*self.storage, chunk_hash, self.cfg.chunk_key.as_ref()).await?;
+            resend.sent(i as u32, &data);
+            let bytes = data.len();
+            traffic.bytes += bytes as u64;
+            if packed {
+                let compressible = compressible[i];
+                let (codec, data) = self.pool.run(move || codec.pack(data, compressible)).await?;
+                traffic.saved += (bytes - data.len()) as u64;
+                let frame = PackedChunkFrame { index: i as u32, codec, raw_len: bytes as u32, data };
+                session.send_encrypted_frame(transport, &bincode::serialize(&frame)?).await?;
+                unacked += 1;
+            } else if acks {
+                let frame = bincode::serialize(&ChunkFrame { index: i as u32, data })?;
+                session.send_encrypted_frame(transport, &frame).await?;
+                unacked += 1;
             } else {
-                // Store chunk
-                let stored_id = self.storage.put_chunk(&chunk).await?;
+                session.send_encrypted_frame(transport, &data).await?;
+                acked.push(i as u32);
+            }
+            self.emit(TransferEvent::ChunkSent { transfer_id: session.transfer_id(), index: i, total, bytes });
 
-                // Verify stored ID matches expected
-                if stored_id != *chunk_hash {
-                    tracing::warn!("Stored chunk ID mismatch: {} vs {}", stored_id, chunk_hash);
-                }
+            if n % 10 == 0 {
+                tracing::debug!("Sent {}/{} chunks", n, total);
+            }
+        }
+        // Pages listing no chunks that were sent: the rest of the files
+        if let Some(paged) = paged {
+            for page in pages..paged.header.pages as usize {
+                self.send_page(session, transport, manifest, paged, page, probe, &mut skip, &mut acked, &mut unacked, &mut resend).await?;
+            }
+        }
+        // Then send rejected chunks again, uncompressed and read afresh
+        // where possible, until all are stored
+        loop {
+            for _ in 0..std::mem::take(&mut unacked) {
+                acked.extend(self.read_ack(session, transport, manifest.chunk_hashes.len(), &mut resend).await?);
+            }
+            let again = resend.take_rejected()?;
+            if again.is_empty() {
+                break;
+            }
+            for (index, kept) in again {
+                tracing::info!("Sending chunk {} again", index);
+                let data = match kept {
+                    Some(data) => data,
+                    None => {
+                        let hash = &manifest.chunk_hashes[index as usize];
+                        self.storage.get_chunk(hash).await?
+                            .with_context(|| format!("Chunk {} missing locally", hash))?
+                    }
+                };
+                traffic.bytes += data.len() as u64;
+                let frame = if packed {
+                    let raw_len = data.len() as u32;
+                    bincode::serialize(&PackedChunkFrame { index, codec: Codec::None, raw_len, data })?
+                } else {
+                    bincode::serialize(&ChunkFrame { index, data })?
+                };
+                session.send_encrypted_frame(transport, &frame).await?;
+                unacked += 1;
+            }
+        }
+        if traffic.saved > 0 {
+            tracing::info!("Compression saved {} of {} bytes", traffic.saved, traffic.bytes);
+        }
+        Ok(traffic)
+    }
+
+    /// Send page `index` of `paged`. With `probe`, then collect the
+    /// acknowledgments still due, so the receiver's [`HaveChunks`] for the
+    /// page is next, and mark the chunks it lists in `skip`. Returns how
+    /// many that were.
+    #[allow(clippy::too_many_arguments)]
+    async fn send_page<T>(
+        &self,
+        session: &Session,
+        transport: &mut T,
+        manifest: &Manifest,
+        paged: &PagedManifest,
+        index: usize,
+        probe: bool,
+        skip: &mut [bool],
+        acked: &mut Vec<u32>,
+        unacked: &mut usize,
+        resend: &mut Resend,
+    ) -> Result<usize>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        tracing::debug!("Sending manifest page {}/{}", index + 1, paged.header.pages);
+        session.send_encrypted_frame(transport, &paged.page(manifest, index).to_frame()?).await?;
+        if !probe {
+            return Ok(0);
+        }
+        for _ in 0..std::mem::take(unacked) {
+            acked.extend(self.read_ack(session, transport, manifest.chunk_hashes.len(), resend).await?);
+        }
+        read_have(session, transport, paged.chunks(index), skip).await
+    }
 
-                if (i + 1) % 10 == 0 {
-                    tracing::info!("Received {}/{} chunks", i + 1, manifest.chunk_hashes.len());
+    /// Sign `manifest` and encode it for `peer`. Peers without whole-file
+    /// hashes get it without one, in the layout they can verify; peers
+    /// with `manifest-ph` get an Ed25519ph signature, and peers with
+    /// `manifest-pages` get a large one in pages.
+    fn seal_manifest(&self, mut manifest: Manifest, peer: Option<&PeerInfo>) -> Result<(Manifest, Sealed)> {
+        if !peer.is_some_and(|p| p.supports(FEATURE_FILE_HASH)) {
+            manifest.file_hash.clear();
+        }
+        if !peer.is_some_and(|p| p.supports(FEATURE_FILE_METADATA)) {
+            manifest.metadata.clear();
+        }
+        if !peer.is_some_and(|p| p.supports(FEATURE_CHUNK_SIZE)) {
+            manifest.chunk_size = None;
+        }
+        if !peer.is_some_and(|p| p.supports(FEATURE_PREVIEWS)) {
+            manifest.preview = None;
+        }
+        let sign = |manifest: &mut Manifest| if peer.is_some_and(|p| p.supports(FEATURE_PREHASHED_MANIFESTS)) {
+            manifest.sign_prehashed(&self.identity)
+        } else {
+            manifest.sign(&self.identity)
+        };
+        sign(&mut manifest)?;
+        // Page frames are told apart from chunk frames by their prefix,
+        // which bare chunk frames could carry.
+        if peer.is_some_and(|p| p.supports(FEATURE_MANIFEST_PAGES) && p.supports(FEATURE_CHUNK_ACKS)) {
+            if let Some(mut paged) = PagedManifest::new(&manifest, &self.identity, self.cfg.manifest_page_size)? {
+                // Pages have no room for metadata, the chunk size or a
+                // preview; how the manifest splits does not depend on them.
+                if !manifest.metadata.is_empty() || manifest.chunk_size.is_some() || manifest.preview.is_some() {
+                    manifest.metadata.clear();
+                    manifest.chunk_size = None;
+                    manifest.preview = None;
+                    sign(&mut manifest)?;
+                    paged = PagedManifest::new(&manifest, &self.identity, self.cfg.manifest_page_size)?
+                        .context("Manifest no longer splits into pages")?;
                 }
+                return Ok((manifest, Sealed::Paged(Box::new(paged))));
             }
         }
+        let bytes = manifest.to_bytes()?;
+        pages::check_whole(&manifest, &bytes)?;
+        Ok((manifest, Sealed::Whole(bytes)))
+    }
 
-        tracing::info!("Transfer complete: {}", manifest.filename);
-        Ok(manifest)
+    /// Warn (log and event) when the peer lacks features this build offers.
+    fn warn_if_outdated(&self, session: &Session) {
+        let Some(peer) = &session.peer else { return };
+        // Features held back by compat_version are off with every peer.
+        let offered = handshake::features_at(self.env.protocol_version());
+        let disabled: Vec<&str> = peer.disabled_features().into_iter().filter(|f| offered.contains(f)).collect();
+        if disabled.is_empty() {
+            return;
+        }
+        tracing::warn!(
+            "{} runs an older version (protocol {}, app {}); disabled: {}",
+            peer.device_id,
+            peer.protocol_version,
+            peer.app_version.as_deref().unwrap_or("unknown"),
+            disabled.join(", "),
+        );
+        self.emit(TransferEvent::CompatibilityWarning {
+            transfer_id: session.transfer_id(),
+            device_id: peer.device_id.clone(),
+            protocol_version: peer.protocol_version,
+            app_version: peer.app_version.clone(),
+            disabled_features: disabled.iter().map(|f| f.to_string()).collect(),
+        });
     }
-}
\ No newline at end of file
+
+    /// Warn when the peer's clock is off from ours by more than
+    /// [`clock_skew_tolerance_secs`](ClientConfig::clock_skew_tolerance_secs).
+    fn warn_if_skewed(&self, session: &Session) {
+        let Some(peer) = &session.peer else { return };
+        if let Some(skew) = peer.clock_skew_beyond(self.cfg.clock_skew_tolerance_secs) {
+            tracing::warn!(
+                "The clock of {} is {}s {} ours; check the time settings of both devices",
+                peer.device_id,
+                skew.unsigned_abs(),
+                if skew > 0 { "ahead of" } else { "behind" },
+            );
+        }
+    }
+
+    /// Remember whether the peer of `session` offers Noise, for the next
+    /// full handshake pushing to it.
+    fn note_noise(&self, session: &Session) {
+        let Some(peer) = &session.peer else { return };
+        let mut peers = self.noise_peers.lock().unwrap();
+        if peer.supports(FEATURE_NOISE) {
+            peers.insert(peer.device_id.clone());
+        } else {
+            peers.remove(&peer.device_id);
+        }
+    }
+
+    /// Run the full initiator handshake, over Noise if `device_id` was seen
+    /// offering it. A peer failing the Noise handshake is forgotten, so the
+    /// next attempt (after a downgrade, say) is classic.
+    async fn full_handshake<T>(&self, transport: &mut T, device_id: Option<&str>) -> Result<Session>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let Some(id) = device_id.filter(|id| self.noise_peers.lock().unwrap().contains(*id)) else {
+            return Ok(handshake::initiator_handshake_with(&self.identity, &self.cfg.device_id, transport, &self.env).await?);
+        };
+        noise::initiator_handshake(&self.identity, &self.cfg.device_id, transport, &self.env).await.map_err(|e| {
+            self.noise_peers.lock().unwrap().remove(id);
+            e.into()
+        })
+    }
+
+    /// Read one acknowledgment and note it in `resend`; the chunk index if
+    /// it was stored.
+    async fn read_ack<T>(&self, session: &Session, transport: &mut T, total: usize, resend: &mut Resend) -> Result<Option<u32>>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let frame = session.read_encrypted_frame(transport).await?;
+        check_abort(session, &frame)?;
+        let ack: ChunkAck = bincode::deserialize(&frame)?;
+        if ack.index as usize >= total {
+            anyhow::bail!("Acknowledgment for unknown chunk {}", ack.index);
+        }
+        if !ack.stored {
+            tracing::warn!("Receiver rejected chunk {}", ack.index);
+        }
+        resend.acked(ack)?;
+        Ok(ack.stored.then_some(ack.index))
+    }
+
+    /// Tell the receiver a send was cancelled, if it understands, collect
+    /// the `unacked` acknowledgments still due and save a resume state.
+    /// Returns the number of chunks the receiver stored. Failures are only
+    /// logged: the transfer is over either way.
+    #[allow(clippy::too_many_arguments)]
+    async fn cancel<T>(
+        &self,
+        session: &Session,
+        transport: &mut T,
+        manifest: &Manifest,
+        peer: Option<&PeerInfo>,
+        source: &ChunkSource,
+        mut acked: Vec<u32>,
+        unacked: usize,
+        resend: &mut Resend,
+    ) -> usize
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        if peer.is_some_and(|p| p.supports(FEATURE_CANCEL)) {
+            let cancel = Cancel { reason: "cancelled by the sender".into() };
+            let sent = async {
+                session.send_encrypted_frame(transport, &cancel.to_frame()?).await?;
+                for _ in 0..unacked {
+                    acked.extend(self.read_ack(session, transport, manifest.chunk_hashes.len(), resend).await?);
+                }
+                Ok::<_, anyhow::Error>(())
+            }.await;
+            if let Err(e) = sent {
+                tracing::debug!("Failed to finish cancelling: {:#}", e);
+            }
+        }
+        tracing::info!("Cancelled send of {} after {} chunks", manifest.filename, acked.len());
+        let done = acked.len();
+        let Some(peer) = peer else { return done };
+        let state = ResumeState {
+            manifest_id: manifest.id(),
+            filename: manifest.filename.clone(),
+            peer: peer.device_id.clone(),
+            source: match source {
+                ChunkSource::Files { root, .. } => Some(root.clone()),
+                ChunkSource::Storage => None,
+            },
+            acked,
+            chunks_total: manifest.chunk_hashes.len(),
+            saved_at: self.unix_now(),
+        };
+        if let Err(e) = state.save(&self.cfg.data_dir) {
+            tracing::warn!("Failed to save resume state for {}: {:#}", manifest.filename, e);
+        }
+        done
+    }
+
+    /// Pass `result` on; if it failed, first drop the part file reserved
+    /// for it, if any, and tell a peer with `abort-reason` why and linger
+    /// until it hangs up. Cancellations have their own frame, and a peer
+    /// that aborted itself needs no answer.
+    async fn abort_if_failed<T, R>(&self, session: &Session, transport: &mut T, result: Result<R>, attempt: &Attempt) -> Result<R>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let Err(e) = &result else { return result };
+        if let Some(part) = &attempt.reserved {
+            let _ = std::fs::remove_file(part);
+        }
+        if !session.peer.as_ref().is_some_and(|p| p.supports(FEATURE_ABORT_REASON))
+            || error::find::<Cancelled>(e).is_some()
+            || error::find::<PeerAborted>(e).is_some()
+            || error::find::<Rejected>(e).is_some() && session.peer.as_ref().is_some_and(|p| p.supports(FEATURE_TRANSFER_CONSENT))
+        {
+            return result;
+        }
+        let abort = attempt.abort(e);
+        tracing::debug!("Aborting: {}", abort);
+        match abort.to_frame() {
+            Ok(frame) => match session.send_encrypted_frame(transport, &frame).await {
+                Ok(()) => linger(transport).await,
+                Err(e) => tracing::debug!("Failed to send abort: {:#}", e),
+            },
+            Err(e) => tracing::debug!("Failed to encode abort: {:#}", e),
+        }
+        result
+    }
+
+    /// Drop the resume state of an earlier, cancelled send of `manifest`
+    /// to `device_id`, now that it has been sent in full.
+    fn forget_resume_state(&self, manifest: &Manifest, device_id: &str) {
+        let id = manifest.id();
+        match ResumeState::load(&self.cfg.data_dir, &id) {
+            Ok(Some(state)) if state.peer == device_id => {
+                if let Err(e) = ResumeState::remove(&self.cfg.data_dir, &id) {
+                    tracing::warn!("Failed to remove resume state for {}: {:#}", manifest.filename, e);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => tracing::debug!("Ignoring resume state for {}: {:#}", manifest.filename, e),
+        }
+    }
+
+    /// Remove and return the cached ticket for `device_id` if unexpired.
+    /// Tickets are single use; the receiver issues a fresh one per transfer.
+    fn take_ticket(&self, device_id: &str) -> Option<ResumptionTicket> {
+        let ticket = self.tickets.lock().unwrap().remove(device_id)?;
+        (!ticket.is_expired(&self.env)).then_some(ticket)
+    }
+
+    /// Wait for the receiver to close the transfer, caching the resumption
+    /// ticket it sends if any. Receivers that issue none just hang up.
+    async fn collect_ticket<T>(&self, session: &Session, transport: &mut T)
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let new: NewTicket = match session.read_encrypted_frame(transport).await {
+            Ok(bytes) => match bincode::deserialize(&bytes) {
+                Ok(new) => new,
+                Err(e) => {
+                    tracing::warn!("Ignoring malformed resumption ticket: {}", e);
+                    return;
+                }
+            },
+            Err(e) => {
+                tracing::debug!("No resumption ticket: {}", e);
+                return;
+            }
+        };
+        match resumption::accept_ticket(session, new, &self.env) {
+            Ok(ticket) => {
+                let device_id = ticket.peer.device_id.clone();
+                self.tickets.lock().unwrap().insert(device_id, ticket);
+            }
+            Err(e) => tracing::debug!("Cannot use resumption ticket: {}", e),
+        }
+    }
+
+    /// Check a peer against the trust store, pinning it on first use when
+    /// `trust_on_first_use` is enabled.
+    pub fn authorize_peer(&self, peer: &PeerInfo) -> Result<(), OpenShareError> {
+        // With an account root, only devices it certified are accepted, and
+        // those need no pin of their own.
+        let certified = match &self.cfg.account_root {
+            Some(root) => {
+                let root = crate::trust::parse_public_key(root).context("Invalid account_root in config")?;
+                crate::account::verify_peer(peer, &root, self.unix_now())?;
+                true
+            }
+            None => false,
+        };
+        let mut trust = self.trust.lock().unwrap();
+        match trust.check_with_history(&peer.device_id, &peer.public_key, &peer.key_history) {
+            TrustStatus::Trusted => Ok(()),
+            TrustStatus::Rotated { pinned } => {
+                trust.follow_rotation(&peer.device_id, &peer.public_key);
+                trust.save()?;
+                tracing::info!("Peer {} rotated its key from {} to {}", peer.device_id, pinned, hex::encode(peer.public_key));
+                Ok(())
+            }
+            TrustStatus::Mismatch { pinned } => Err(anyhow::anyhow!(
+                "Key mismatch for {}: pinned {}, got {}",
+                peer.device_id, pinned, hex::encode(peer.public_key)
+            ).into()),
+            TrustStatus::Unknown if certified => {
+                trust.add(&peer.device_id, &peer.public_key, TrustSource::Account);
+                trust.save()?;
+                tracing::info!("Trusted new peer {} certified by the account", peer.device_id);
+                Ok(())
+            }
+            TrustStatus::Unknown if self.cfg.trust_on_first_use => {
+                trust.add(&peer.device_id, &peer.public_key, TrustSource::FirstUse);
+                trust.save()?;
+                tracing::info!("Trusted new peer {} on first use", peer.device_id);
+                Ok(())
+            }
+            TrustStatus::Unknown => Err(anyhow::anyhow!(
+                "Unknown peer {} ({}); add it with 'openshare trust add' or enable trust_on_first_use",
+                peer.device_id, hex::encode(peer.public_key)
+            ).into()),
+        }
+    }
+
+    /// Start pairing with a peer over a fresh connection: run the full
+    /// handshake (as `initiator` or responder) and return the short
+    /// authentication string both users must compare out of band.
+    pub async fn pair<T>(&self, mut transport: T, initiator: bool) -> Result<Pairing<T>, OpenShareError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let session = if initiator {
+            handshake::initiator_handshake_with(&self.identity, &self.cfg.device_id, &mut transport, &self.env).await?
+        } else {
+            handshake::responder_handshake_with(&self.identity, &self.cfg.device_id, &mut transport, &self.env).await?
+        };
+        let peer = session.peer.clone()
+            .ok_or_else(|| anyhow::anyhow!("Peer did not identify itself; cannot pair"))?;
+        let code = session.sas_code()?;
+        Ok(Pairing { peer, code, session, transport })
+    }
+
+    /// Finish pairing: tell the peer whether our user confirmed the code
+    /// and learn its answer. Only if both confirmed is the peer pinned
+    /// (replacing any earlier pin); returns whether that happened.
+    pub async fn complete_pairing<T>(&self, pairing: Pairing<T>, confirmed: bool) -> Result<bool, OpenShareError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let Pairing { peer, session, mut transport, .. } = pairing;
+        session.send_encrypted_frame(&mut transport, &[confirmed as u8]).await?;
+        let reply = session.read_encrypted_frame(&mut transport).await?;
+        if !confirmed || reply != [1] {
+            tracing::info!("Pairing with {} not confirmed", peer.device_id);
+            return Ok(false);
+        }
+        let mut trust = self.trust.lock().unwrap();
+        trust.add(&peer.device_id, &peer.public_key, TrustSource::Paired);
+        trust.save()?;
+        tracing::info!("Paired with {}", peer.device_id);
+        Ok(true)
+    }
+
+    /// Accept an incoming transport, run responder handshake and receive
+    /// an incoming manifest followed by chunks; store chunks into storage.
+    /// Pull requests are refused; use [`accept`](Self::accept) to serve them.
+    pub async fn accept_and_receive<T>(&self, transport: T) -> Result<Manifest, OpenShareError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        self.receive_only(transport, None).await
+    }
+
+    /// Like [`accept_and_receive`](Self::accept_and_receive), but only from
+    /// a peer whose identity key has `fingerprint`, such as the one it
+    /// advertised over mDNS; anyone else is refused right after the
+    /// handshake, before its manifest is read.
+    pub async fn accept_and_receive_pinned<T>(&self, transport: T, fingerprint: &str) -> Result<Manifest, OpenShareError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        self.receive_only(transport, Some(fingerprint)).await
+    }
+
+    async fn receive_only<T>(&self, transport: T, fingerprint: Option<&str>) -> Result<Manifest, OpenShareError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let mut attempt = Attempt::new(None);
+        let result = match self.accept_inner(transport, false, fingerprint, &mut attempt).instrument(transfer_span()).await {
+            Ok(Accepted::Received(manifest) | Accepted::Streamed(manifest)) => Ok(manifest),
+            Ok(Accepted::ReceivedBatch(manifests)) => Err(anyhow::anyhow!("Received a batch of {} transfers; use accept to get them all", manifests.len())),
+            Ok(Accepted::Served(manifest)) => Err(anyhow::anyhow!("Unexpectedly served {}", manifest.filename)),
+            Ok(Accepted::IndexShared { .. }) => Err(anyhow::anyhow!("Unexpectedly shared the content index")),
+            Ok(Accepted::Announced { .. }) => Err(anyhow::anyhow!("Unexpectedly received a share announcement")),
+            Ok(Accepted::Administered { method, .. }) => Err(anyhow::anyhow!("Unexpectedly ran {} for a remote admin", method)),
+            Ok(Accepted::Text { device_id, .. }) => Err(anyhow::anyhow!("{} sent a text instead of a file", device_id)),
+            Ok(Accepted::Probed { device_id }) => Err(anyhow::anyhow!("{} hung up after the handshake", device_id)),
+            Err(e) => Err(e),
+        };
+        self.report(result, &attempt)
+    }
+
+    /// Accept an incoming transport and either receive a pushed transfer or
+    /// serve a pull request for a [published](Self::publish) manifest.
+    pub async fn accept<T>(&self, transport: T) -> Result<Accepted, OpenShareError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let mut attempt = Attempt::new(None);
+        let result = self.accept_inner(transport, true, None, &mut attempt).instrument(transfer_span()).await;
+        self.report(result, &attempt)
+    }
+
+    async fn accept_inner<T>(
+        &self,
+        mut transport: T,
+        allow_pull: bool,
+        fingerprint: Option<&str>,
+        attempt: &mut Attempt,
+    ) -> Result<Accepted>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        tracing::info!("Starting receive...");
+
+        // Run responder handshake
+        tracing::debug!("Performing handshake...");
+        let (session, early_data) = resumption::responder_accept(
+            &self.identity, &self.cfg.device_id, &mut transport, &self.env, Some(&self.ticket_key),
+        ).await?;
+        tracing::debug!("Handshake complete (resumed: {})", session.resumed);
+        self.emit(TransferEvent::HandshakeComplete {
+            transfer_id: session.transfer_id(),
+            peer: session.peer.clone(),
+            resumed: session.resumed,
+        });
+
+        let peer = session.peer.clone()
+            .ok_or_else(|| anyhow::anyhow!("Peer did not identify itself; refusing transfer"))?;
+        attempt.session(&session);
+        if let Some(fingerprint) = fingerprint {
+            handshake::expect_fingerprint(Some(&peer), fingerprint)?;
+        }
+        // Checked once the request is known: an offered share may be
+        // pulled by anyone holding its code (see crate::offers).
+        let authorized = self.authorize_peer(&peer);
+        self.warn_if_outdated(&session);
+        self.warn_if_skewed(&session);
+        self.note_noise(&session);
+
+        // The first frame is either a pushed manifest or a pull request
+        let first = match early_data {
+            Some(bytes) => bytes,
+            None => {
+                tracing::debug!("Receiving manifest...");
+                match session.read_encrypted_frame(&mut transport).await {
+                    Ok(frame) => frame,
+                    // A probe hangs up right after the handshake
+                    Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof && authorized.is_ok() => {
+                        return Ok(Accepted::Probed { device_id: peer.device_id });
+                    }
+                    Err(e) => return Err(authorized.err().map(anyhow::Error::from).unwrap_or_else(|| e.into())),
+                }
+            }
+        };
+        if let Err(e) = authorized {
+            let offered = match PullRequest::from_frame(&first) {
+                Some(Ok(request)) if allow_pull => self.offered(&request.manifest_id)?,
+                _ => false,
+            };
+            if !offered {
+                return Err(e.into());
+            }
+            tracing::info!("Serving an offered share to {}, which is not trusted", peer.device_id);
+        }
+        if let Some(request) = IndexRequest::from_frame(&first) {
+            let entries = self.serve_index(&session, &mut transport, &peer, request?, allow_pull).await?;
+            linger(&mut transport).await;
+            return Ok(Accepted::IndexShared { device_id: peer.device_id, entries });
+        }
+        if let Some(announcement) = ShareAnnouncement::from_frame(&first) {
+            let announcement = announcement?;
+            self.note_announcement(&session, &mut transport, &peer, &announcement).await?;
+            linger(&mut transport).await;
+            return Ok(Accepted::Announced { device_id: peer.device_id, announcement });
+        }
+        if let Some(request) = AdminRequest::from_frame(&first) {
+            let method = self.serve_admin(&session, &mut transport, &peer, request?).await?;
+            linger(&mut transport).await;
+            return Ok(Accepted::Administered { device_id: peer.device_id, method });
+        }
+        if let Some(message) = TextMessage::from_frame(&first) {
+            let text = self.take_text(&session, &mut transport, &peer, message?).await?;
+            linger(&mut transport).await;
+            return Ok(Accepted::Text { device_id: peer.device_id, text });
+        }
+        let transferred = match PullRequest::from_frame(&first) {
+            Some(request) => {
+                attempt.direction = Some(Direction::Served);
+                let served = async {
+                    self.serve_pull(&session, &mut transport, &peer, request?, allow_pull, attempt).await
+                }.await;
+                self.abort_if_failed(&session, &mut transport, served, attempt).await
+                    .map(|(manifest, traffic)| (Accepted::Served(manifest), traffic))
+            }
+            None if peer.supports(FEATURE_STREAM) && StreamHeader::from_frame(&first).is_some() => {
+                attempt.direction = Some(Direction::Received);
+                let header = StreamHeader::from_frame(&first).expect("checked above")?;
+                let received = self.receive_stream(&session, &mut transport, &peer, header, attempt).await;
+                let output = self.stream_output.is_some();
+                self.abort_if_failed(&session, &mut transport, received, attempt).await
+                    .map(|(manifest, traffic)| match output {
+                        true => (Accepted::Streamed(manifest), traffic),
+                        false => (Accepted::Received(manifest), traffic),
+                    })
+            }
+            None if peer.supports(FEATURE_BATCH) && BatchHeader::from_frame(&first).is_some() => {
+                attempt.direction = Some(Direction::Received);
+                let header = BatchHeader::from_frame(&first).expect("checked above")?;
+                self.receive_batch(&session, &mut transport, &peer, header, attempt).await
+                    .map(|manifests| (Accepted::ReceivedBatch(manifests), Traffic::default()))
+            }
+            None => {
+                attempt.direction = Some(Direction::Received);
+                let received = self.receive_push(&session, &mut transport, &peer, first, attempt).await;
+                self.abort_if_failed(&session, &mut transport, received, attempt).await
+                    .map(|(manifest, traffic)| (Accepted::Received(manifest), traffic))
+            }
+        };
+        let (accepted, traffic) = transferred?;
+
+        if self.cfg.ticket_lifetime_secs > 0 && peer.supports(FEATURE_RESUMPTION) {
+            // The transfer already succeeded; a lost ticket only costs the
+            // peer a full handshake next time.
+            if let Err(e) = self.send_ticket(&session, &mut transport).await {
+                tracing::debug!("Failed to issue resumption ticket: {:#}", e);
+            }
+        }
+
+        linger(&mut transport).await;
+
+        let (direction, manifest) = match &accepted {
+            Accepted::Received(m) | Accepted::Streamed(m) => (Direction::Received, m),
+            Accepted::Served(m) => (Direction::Served, m),
+            // Each push of a batch is recorded as it lands
+            Accepted::ReceivedBatch(_) => return Ok(accepted),
+            Accepted::IndexShared { .. }
+            | Accepted::Announced { .. }
+            | Accepted::Administered { .. }
+            | Accepted::Text { .. }
+            | Accepted::Probed { .. } => {
+                unreachable!("returned above")
+            }
+        };
+        self.record(direction, Some(&peer), manifest, traffic.bytes, traffic, attempt);
+        tracing::info!("Transfer complete: {}", manifest.filename);
+        self.emit(TransferEvent::TransferComplete {
+            transfer_id: session.transfer_id(),
+            filename: manifest.filename.clone(),
+            size: manifest.size,
+        });
+        Ok(accepted)
+    }
+
+    /// Receive a push whose first frame is `first`: a keyed push names its
+    /// key, and a delta sync its chunking, ahead of the manifest.
+    async fn receive_push<T>(
+        &self,
+        session: &Session,
+        transport: &mut T,
+        peer: &PeerInfo,
+        first: Vec<u8>,
+        attempt: &mut Attempt,
+    ) -> Result<(Manifest, Traffic)>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let selection = if peer.supports(FEATURE_CHUNK_PROBE) { ChunkSelection::Missing } else { ChunkSelection::All };
+        let (first, keyed) = match ChunkKeyHeader::from_frame(&first) {
+            Some(header) => {
+                check_chunk_key(self.cfg.chunk_key.as_ref(), peer, &header?)?;
+                (session.read_encrypted_frame(transport).await?, true)
+            }
+            None => (first, false),
+        };
+        let (first, sync) = match SyncHeader::from_frame(&first) {
+            Some(header) => {
+                let chunking = header?.chunking.check()?;
+                (session.read_encrypted_frame(transport).await?, Some(chunking))
+            }
+            None => (first, None),
+        };
+        self.receive_payload(session, transport, peer, first, None, selection, sync, keyed, attempt).await
+    }
+
+    /// Take a stream opened with `header`: hash each chunk as it arrives
+    /// and write it to the stream output, or store it, then check the
+    /// sender's manifest against what arrived. See [`crate::stream`].
+    async fn receive_stream<T>(
+        &self,
+        session: &Session,
+        transport: &mut T,
+        peer: &PeerInfo,
+        header: StreamHeader,
+        attempt: &mut Attempt,
+    ) -> Result<(Manifest, Traffic)>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        header.check()?;
+        attempt.filename = Some(header.filename.clone());
+        let mut hasher = StreamHasher::new(&header);
+        // Its size is not known yet; the limit is applied as it grows.
+        self.review(session, transport, peer, &Listing::Whole(hasher.manifest()), None).await?;
+        self.emit(TransferEvent::ManifestReceived {
+            transfer_id: session.transfer_id(),
+            filename: header.filename.clone(),
+            size: 0,
+            total_chunks: 0,
+        });
+
+        let mut output = match &self.stream_output {
+            Some(output) => Some(output.lock().await),
+            None => None,
+        };
+        let mut traffic = Traffic::default();
+        let mut last = false;
+        loop {
+            let bytes = session.read_encrypted_frame(transport).await?;
+            check_abort(session, &bytes)?;
+            if let Some(cancel) = Cancel::from_frame(&bytes) {
+                tracing::info!("{} cancelled the stream: {}", peer.device_id, cancel?.reason);
+                let done = hasher.chunks();
+                return Err(Cancelled { by: CancelledBy::Peer(peer.device_id.clone()), chunks_done: done, chunks_total: done }.into());
+            }
+            let data = match bincode::deserialize(&bytes).context("Malformed stream frame")? {
+                StreamFrame::Chunk(data) => data,
+                StreamFrame::End(bytes) => {
+                    attempt.stage = Stage::Finishing;
+                    let manifest = self.open_manifest(peer, &bytes, None)?;
+                    if let Err(e) = hasher.check(&manifest) {
+                        let reply = StreamReply::Refused(format!("{:#}", e));
+                        session.send_encrypted_frame(transport, &bincode::serialize(&reply)?).await?;
+                        return Err(e);
+                    }
+                    match &mut output {
+                        Some(output) => output.flush().await.context("Failed to write the stream")?,
+                        None => self.storage.add_manifest(&manifest.id(), &manifest.chunk_hashes).await?,
+                    }
+                    attempt.manifest(&manifest);
+                    session.send_encrypted_frame(transport, &bincode::serialize(&StreamReply::Received)?).await?;
+                    return Ok((manifest, traffic));
+                }
+            };
+            if last || data.is_empty() || data.len() > header.chunk_size as usize || hasher.chunks() == MAX_STREAM_CHUNKS {
+                anyhow::bail!("Unexpected stream chunk {} of {} bytes", hasher.chunks(), data.len());
+            }
+            last = data.len() < header.chunk_size as usize;
+            let size = hasher.size() + data.len() as u64;
+            if self.cfg.max_incoming_bytes > 0 && size > self.cfg.max_incoming_bytes {
+                anyhow::bail!("Stream is over the limit of {} bytes", self.cfg.max_incoming_bytes);
+            }
+
+            let id = hasher.add(&data);
+            let n = hasher.chunks();
+            attempt.chunk(n, n);
+            traffic.bytes += data.len() as u64;
+            match &mut output {
+                Some(output) => output.write_all(&data).await.context("Failed to write the stream")?,
+                None if self.storage.has_chunk(&id).await? => traffic.reused += data.len() as u64,
+                None => store_chunk(&*self.storage, &id, &data, false).await?,
+            }
+            let ack = ChunkAck { index: (n - 1) as u32, stored: true };
+            session.send_encrypted_frame(transport, &bincode::serialize(&ack)?).await?;
+            self.emit(TransferEvent::ChunkReceived { transfer_id: session.transfer_id(), index: n - 1, total: n, bytes: data.len() });
+        }
+    }
+
+    /// Receive the pushes announced by a batch `header`, recording each as
+    /// it lands. Rejected ones are skipped, as the sender skips them; any
+    /// other failure ends the batch and becomes `attempt`.
+    async fn receive_batch<T>(
+        &self,
+        session: &Session,
+        transport: &mut T,
+        peer: &PeerInfo,
+        header: BatchHeader,
+        attempt: &mut Attempt,
+    ) -> Result<Vec<Manifest>>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        tracing::info!("Receiving a batch of {} from {}", header.count, peer.device_id);
+        let mut received = Vec::new();
+        for _ in 0..header.count {
+            let mut item = Attempt::new(Some(Direction::Received));
+            item.session(session);
+            let result = async {
+                let first = session.read_encrypted_frame(transport).await?;
+                self.receive_push(session, transport, peer, first, &mut item).await
+            }.await;
+            match self.abort_if_failed(session, transport, result, &item).await {
+                Ok((manifest, traffic)) => {
+                    self.record(Direction::Received, Some(peer), &manifest, traffic.bytes, traffic, &item);
+                    tracing::info!("Transfer complete: {}", manifest.filename);
+                    self.emit(TransferEvent::TransferComplete {
+                        transfer_id: session.transfer_id(),
+                        filename: manifest.filename.clone(),
+                        size: manifest.size,
+                    });
+                    received.push(manifest);
+                }
+                Err(e) if error::find::<Rejected>(&e).is_some() && peer.supports(FEATURE_TRANSFER_CONSENT) => {
+                    if let Err(e) = self.report::<()>(Err(e), &item) {
+                        tracing::info!("Skipping: {:#}", e);
+                    }
+                }
+                Err(e) => {
+                    *attempt = item;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(received)
+    }
+
+    /// Decode and verify a manifest from `peer`, then receive the chunks in
+    /// `selection` into storage. A manifest sent in pages is checked page
+    /// by page, and its chunks are taken as their pages arrive. With
+    /// `expected_id`, a different manifest is refused, before any chunk is
+    /// accepted unless it came in pages. Also returns the chunk bytes
+    /// received and how many bytes were already stored (including chunks
+    /// the sender skipped). With `sync`, chunks of what the transfer would
+    /// replace are stored first (see [`seed_chunks`](Self::seed_chunks)),
+    /// under ids keyed with the chunk key if the push is `keyed`.
+    #[allow(clippy::too_many_arguments)]
+    async fn receive_payload<T>(
+        &self,
+        session: &Session,
+        transport: &mut T,
+        peer: &PeerInfo,
+        manifest_bytes: Vec<u8>,
+        expected_id: Option<&str>,
+        selection: ChunkSelection<'_>,
+        sync: Option<Chunking>,
+        keyed: bool,
+        attempt: &mut Attempt,
+    ) -> Result<(Manifest, Traffic)>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        check_abort(session, &manifest_bytes)?;
+        let acks = peer.supports(FEATURE_CHUNK_ACKS);
+        let packed = peer.supports(FEATURE_COMPRESSION);
+        let mut listing = match ManifestHeader::from_frame(&manifest_bytes) {
+            Some(_) if !acks => anyhow::bail!("{} sent a paged manifest without chunk acknowledgments", peer.device_id),
+            Some(header) => Listing::Paged(PageAssembler::new(header?, &peer.public_key)?),
+            None => Listing::Whole(self.open_manifest(peer, &manifest_bytes, expected_id)?),
+        };
+        attempt.filename = Some(listing.filename().to_string());
+        attempt.size = listing.size();
+        let shortfall = self.space_shortfall(&listing, selection);
+        if attempt.direction == Some(Direction::Received) {
+            self.review(session, transport, peer, &listing, shortfall).await?;
+        } else if let Some(shortfall) = shortfall {
+            return Err(shortfall.into());
+        }
+        attempt.reserved = self.reserve_output(&listing, selection);
+        if let (Some(chunking), ChunkSelection::Missing) = (sync, selection) {
+            // Reuse is an optimisation; without it the chunks are sent.
+            let key = self.cfg.chunk_key.as_ref().filter(|_| keyed);
+            match self.seed_chunks(&listing, chunking, key).await {
+                Ok(0) => {}
+                Ok(seeded) => tracing::info!("Reusing {} chunks already at the destination", seeded),
+                Err(e) => tracing::warn!("Cannot reuse what is at the destination: {:#}", e),
+            }
+        }
+
+        // Which chunks to take, known page by page for a paged manifest
+        let mut wanted = Vec::new();
+        let mut total = 0;
+        let announced = match &listing {
+            Listing::Whole(manifest) => {
+                let synced = match selection {
+                    ChunkSelection::Missing => self.synced_chunks(manifest),
+                    _ => Vec::new(),
+                };
+                let range = 0..manifest.chunk_hashes.len();
+                total = self.want_chunks(session, transport, &manifest.chunk_hashes, range, selection, &synced, &mut wanted).await?;
+                check_selection(selection, total)?;
+                total
+            }
+            Listing::Paged(pages) => match selection {
+                ChunkSelection::Only(indices) => indices.len(),
+                ChunkSelection::All | ChunkSelection::Missing => pages.header().chunk_count as usize,
+            },
+        };
+        tracing::info!("Receiving: {} ({} chunks)", listing.filename(), announced);
+        self.emit(TransferEvent::ManifestReceived {
+            transfer_id: session.transfer_id(),
+            filename: listing.filename().to_string(),
+            size: listing.size(),
+            total_chunks: announced,
+        });
+
+        // Verify and store incoming chunks, up to `max_parallel_chunks` at once.
+        // Peers without chunk acks send bare frames in manifest order. A
+        // pushing peer with `chunk-retry` sends rejected chunks again.
+        let window = self.cfg.max_parallel_chunks.max(1);
+        let retry = acks && attempt.direction == Some(Direction::Received) && peer.supports(FEATURE_CHUNK_RETRY);
+        let mut seen = Vec::new();
+        let mut in_flight = JoinSet::new();
+        let mut done = 0;
+        let mut n = 0;
+        // Chunks rejected, and how many of them are due again
+        let (mut rejected, mut resends) = (Vec::new(), 0);
+        let mut traffic = Traffic::default();
+        let reused = Arc::new(AtomicU64::new(0));
+        loop {
+            for index in rejected.drain(..) {
+                if retry {
+                    seen[index] = false;
+                    resends += 1;
+                }
+            }
+            if listing.is_complete() && n >= total {
+                // Every chunk came once: the sender waits for all acks
+                // before sending rejected ones again.
+                if let Some(result) = in_flight.join_next().await {
+                    self.ack_chunk(session, transport, result??, acks, total, &mut done, &mut rejected).await?;
+                    continue;
+                }
+                if n >= total + resends {
+                    break;
+                }
+            }
+            if in_flight.len() == window {
+                let result = in_flight.join_next().await.expect("window is non-empty")?;
+                self.ack_chunk(session, transport, result?, acks, total, &mut done, &mut rejected).await?;
+            }
+
+            let bytes = session.read_encrypted_frame(transport).await?;
+            check_abort(session, &bytes)?;
+            if peer.supports(FEATURE_CANCEL) {
+                if let Some(cancel) = Cancel::from_frame(&bytes) {
+                    tracing::info!("{} cancelled {}: {}", peer.device_id, listing.filename(), cancel?.reason);
+                    // Keep what is already verified, so a later send can
+                    // skip it, and acknowledge it: the sender waits for that.
+                    while let Some(result) = in_flight.join_next().await {
+                        self.ack_chunk(session, transport, result??, acks, total, &mut done, &mut rejected).await?;
+                    }
+                    return Err(Cancelled {
+                        by: CancelledBy::Peer(peer.device_id.clone()),
+                        chunks_done: done,
+                        chunks_total: total,
+                    }.into());
+                }
+            }
+            if let Listing::Paged(pages) = &mut listing {
+                if let Some(page) = ManifestPage::from_frame(&bytes) {
+                    let range = pages.add(page?)?;
+                    if let ChunkSelection::Missing = selection {
+                        // The sender reads the acknowledgments still due
+                        // before our answer to the page.
+                        while let Some(result) = in_flight.join_next().await {
+                            self.ack_chunk(session, transport, result??, acks, total, &mut done, &mut rejected).await?;
+                        }
+                    }
+                    total += self.want_chunks(session, transport, pages.chunk_hashes(), range, selection, &[], &mut wanted).await?;
+                    if pages.is_complete() {
+                        check_selection(selection, total)?;
+                    }
+                    continue;
+                }
+            }
+            let frame = if packed {
+                bincode::deserialize(&bytes)?
+            } else if acks {
+                let frame: ChunkFrame = bincode::deserialize(&bytes)?;
+                PackedChunkFrame { index: frame.index, codec: Codec::None, raw_len: frame.data.len() as u32, data: frame.data }
+            } else {
+                PackedChunkFrame { index: n as u32, codec: Codec::None, raw_len: bytes.len() as u32, data: bytes }
+            };
+            n += 1;
+            attempt.chunk(n - resends, total);
+            let index = frame.index as usize;
+            seen.resize(wanted.len(), false);
+            if index >= wanted.len() || !wanted[index] || std::mem::replace(&mut seen[index], true) {
+                anyhow::bail!("Unexpected chunk index {}", frame.index);
+            }
+            let bytes = frame.raw_len as usize;
+            traffic.bytes += bytes as u64;
+            traffic.saved += (bytes as u64).saturating_sub(frame.data.len() as u64);
+
+            let expected = listing.chunk_hashes()[index].clone();
+            let (expected_id, key) = (expected.clone(), self.cfg.chunk_key.clone());
+            let (storage, pool, reused) = (self.storage.clone(), self.pool.clone(), reused.clone());
+            let (quarantine, env, peer_id) = (self.quarantine.clone(), self.env.clone(), peer.device_id.clone());
+            let reject = move |chunk: &str, reason: String| {
+                quarantine.lock().unwrap().record_failure(&peer_id, chunk, &reason, env.now());
+            };
+            in_flight.spawn(async move {
+                // Decode and verify chunk hash matches expected
+                let decoded = pool.run(move || {
+                    let chunk = frame.codec.decompress(frame.data, bytes)?;
+                    let hex = keyed::chunk_id(None, &chunk);
+                    let keyed = hex != expected_id && key.as_ref().is_some_and(|k| k.chunk_id(&chunk) == expected_id);
+                    Ok::<_, anyhow::Error>((chunk, hex, keyed))
+                }).await?;
+                let (chunk, hex, keyed) = match decoded {
+                    Ok(decoded) => decoded,
+                    Err(e) => {
+                        tracing::warn!("Cannot decode chunk {}: {:#}", index, e);
+                        reject(&expected, format!("{:#}", e));
+                        return Ok((index, bytes, false));
+                    }
+                };
+
+                if hex != expected && !keyed {
+                    tracing::warn!("Chunk hash mismatch: expected {} got {}", expected, hex);
+                    reject(&expected, format!("hash mismatch: got {}", hex));
+                    return Ok::<_, anyhow::Error>((index, bytes, false));
+                }
+
+                // Storage is content addressed, so a chunk we already hold
+                // need not be written again.
+                if storage.has_chunk(&expected).await? {
+                    reused.fetch_add(bytes as u64, Ordering::Relaxed);
+                    return Ok((index, bytes, true));
+                }
+                store_chunk(&*storage, &expected, &chunk, keyed).await?;
+                Ok((index, bytes, true))
+            });
+
+            while let Some(result) = in_flight.try_join_next() {
+                self.ack_chunk(session, transport, result??, acks, total, &mut done, &mut rejected).await?;
+            }
+        }
+        attempt.stage = Stage::Finishing;
+        let manifest = match listing {
+            Listing::Whole(manifest) => manifest,
+            Listing::Paged(pages) => {
+                let manifest = pages.finish()?;
+                check_id(&manifest, peer, expected_id)?;
+                manifest
+            }
+        };
+        self.storage.add_manifest(&manifest.id(), &manifest.chunk_hashes).await?;
+        traffic.reused = reused.load(Ordering::Relaxed);
+        if let ChunkSelection::Missing = selection {
+            traffic.reused += manifest.size.saturating_sub(traffic.bytes);
+        }
+        Ok((manifest, traffic))
+    }
+
+    /// Decode a manifest sent in one frame by `peer` and check that peer
+    /// signed it (and that it is `expected_id`, if given).
+    fn open_manifest(&self, peer: &PeerInfo, bytes: &[u8], expected_id: Option<&str>) -> Result<Manifest> {
+        let manifest = Manifest::from_bytes(bytes, peer.supports(FEATURE_FILE_HASH)).with_context(|| format!(
+            "Failed to decode manifest from {} (protocol {}, app {})",
+            peer.device_id, peer.protocol_version, peer.app_version.as_deref().unwrap_or("unknown"),
+        ))?;
+
+        // The manifest must be signed by the key that authenticated the channel.
+        if manifest.sender_pubkey.as_deref() != Some(&peer.public_key[..]) {
+            anyhow::bail!("Manifest sender key does not match authenticated peer {}", peer.device_id);
+        }
+        manifest.verify_with_pubkey(&peer.public_key)?;
+        check_id(&manifest, peer, expected_id)?;
+        Ok(manifest)
+    }
+
+    /// Extend `wanted` over the chunks at `range` of `chunk_hashes`, the
+    /// next ones listed, according to `selection`; for
+    /// [`ChunkSelection::Missing`], tell the sender which of them we hold
+    /// already, here or (per `synced`) in the output directory. Returns how
+    /// many are wanted.
+    #[allow(clippy::too_many_arguments)]
+    async fn want_chunks<T>(
+        &self,
+        session: &Session,
+        transport: &mut T,
+        chunk_hashes: &[String],
+        range: std::ops::Range<usize>,
+        selection: ChunkSelection<'_>,
+        synced: &[bool],
+        wanted: &mut Vec<bool>,
+    ) -> Result<usize>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        match selection {
+            ChunkSelection::All => {
+                wanted.resize(range.end, true);
+                Ok(range.len())
+            }
+            ChunkSelection::Only(indices) => {
+                wanted.resize(range.end, false);
+                let mut count = 0;
+                for &i in indices.iter().filter(|&&i| range.contains(&(i as usize))) {
+                    wanted[i as usize] = true;
+                    count += 1;
+                }
+                Ok(count)
+            }
+            ChunkSelection::Missing => {
+                // Tell the sender what we already hold so it only sends the rest.
+                let mut have = HaveChunks::default();
+                for i in range.clone() {
+                    let held = synced.get(i).copied().unwrap_or(false) || self.storage.has_chunk(&chunk_hashes[i]).await?;
+                    wanted.push(!held);
+                    if held {
+                        have.indices.push(i as u32);
+                    }
+                }
+                session.send_encrypted_frame(transport, &bincode::serialize(&have)?).await?;
+                Ok(range.len() - have.indices.len())
+            }
+        }
+    }
+
+    /// Ask a connected peer for the published manifest `manifest_id` and
+    /// receive it into storage, as the initiator of the connection.
+    pub async fn request_file<T>(&self, transport: T, manifest_id: &str) -> Result<Manifest, OpenShareError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let mut attempt = Attempt::new(Some(Direction::Fetched));
+        let result = self.request_inner(transport, manifest_id, None, None, None, &mut attempt).instrument(transfer_span()).await;
+        self.report(result, &attempt).map(|(manifest, _)| manifest)
+    }
+
+    /// Like [`request_file`](Self::request_file), from a peer whose
+    /// identity key has `fingerprint`, such as the one in a share code
+    /// (see [`crate::offers`]). The fingerprint vouches for the peer, which
+    /// need not be trusted.
+    pub async fn request_file_pinned<T>(&self, transport: T, manifest_id: &str, fingerprint: &str) -> Result<Manifest, OpenShareError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let mut attempt = Attempt::new(Some(Direction::Fetched));
+        let result = self.request_inner(transport, manifest_id, None, None, Some(fingerprint), &mut attempt).instrument(transfer_span()).await;
+        self.report(result, &attempt).map(|(manifest, _)| manifest)
+    }
+
+    /// Like [`request_file`](Self::request_file), but receive only the
+    /// chunks at `indices`; with none, only the manifest is fetched. Needs
+    /// a peer with partial pulls.
+    pub async fn request_chunks<T>(&self, transport: T, manifest_id: &str, indices: Vec<u32>) -> Result<Manifest, OpenShareError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let mut attempt = Attempt::new(Some(Direction::Fetched));
+        let result = self.request_inner(transport, manifest_id, Some(indices), None, None, &mut attempt).instrument(transfer_span()).await;
+        self.report(result, &attempt).map(|(manifest, _)| manifest)
+    }
+
+    /// Like [`request_chunks`](Self::request_chunks) for chunks of a
+    /// manifest we already have, leaving out those quarantined for the
+    /// peer on the other end. Fails without requesting anything if that
+    /// is all of them.
+    pub async fn request_unquarantined<T>(&self, transport: T, manifest: &Manifest, indices: Vec<u32>) -> Result<Manifest, OpenShareError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        self.request_known(transport, manifest, indices).await.map(|(manifest, _)| manifest)
+    }
+
+    /// [`request_unquarantined`](Self::request_unquarantined), also
+    /// returning the chunk bytes received.
+    pub(crate) async fn request_known<T>(&self, transport: T, manifest: &Manifest, indices: Vec<u32>) -> Result<(Manifest, u64), OpenShareError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let mut attempt = Attempt::new(Some(Direction::Fetched));
+        let result = self.request_inner(transport, &manifest.id(), Some(indices), Some(manifest), None, &mut attempt).instrument(transfer_span()).await;
+        self.report(result, &attempt).map(|(manifest, traffic)| (manifest, traffic.bytes))
+    }
+
+    async fn request_inner<T>(
+        &self,
+        mut transport: T,
+        manifest_id: &str,
+        indices: Option<Vec<u32>>,
+        known: Option<&Manifest>,
+        fingerprint: Option<&str>,
+        attempt: &mut Attempt,
+    ) -> Result<(Manifest, Traffic)>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        tracing::info!("Requesting manifest {}", manifest_id);
+        match known {
+            Some(manifest) => attempt.manifest(manifest),
+            None => attempt.filename = Some(manifest_id.to_string()),
+        }
+
+        tracing::debug!("Performing handshake...");
+        let session = handshake::initiator_handshake_with(&self.identity, &self.cfg.device_id, &mut transport, &self.env).await?;
+        tracing::debug!("Handshake complete");
+        self.emit(TransferEvent::HandshakeComplete {
+            transfer_id: session.transfer_id(),
+            peer: session.peer.clone(),
+            resumed: session.resumed,
+        });
+
+        // We are about to accept data from this peer, so it must be trusted.
+        let peer = session.peer.clone()
+            .ok_or_else(|| anyhow::anyhow!("Peer did not identify itself; refusing transfer"))?;
+        attempt.session(&session);
+        match fingerprint {
+            Some(fingerprint) => handshake::expect_fingerprint(Some(&peer), fingerprint)?,
+            None => self.authorize_peer(&peer)?,
+        }
+        self.warn_if_outdated(&session);
+        self.warn_if_skewed(&session);
+        self.note_noise(&session);
+        if !peer.supports(FEATURE_PULL) {
+            anyhow::bail!(
+                "{} does not support pull requests (protocol {})",
+                peer.device_id, peer.protocol_version
+            );
+        }
+        if indices.is_some() && !peer.supports(FEATURE_PARTIAL_PULL) {
+            anyhow::bail!(
+                "{} does not support partial pulls (protocol {})",
+                peer.device_id, peer.protocol_version
+            );
+        }
+        let indices = match (indices, known) {
+            (Some(indices), Some(known)) if !indices.is_empty() => {
+                let quarantine = self.quarantine.lock().unwrap();
+                let now = self.env.now();
+                let allowed: Vec<u32> = indices.iter().copied()
+                    .filter(|&i| known.chunk_hashes.get(i as usize)
+                        .is_none_or(|hash| !quarantine.is_quarantined(&peer.device_id, hash, now)))
+                    .collect();
+                if allowed.is_empty() {
+                    anyhow::bail!("All {} requested chunks are quarantined for {}", indices.len(), peer.device_id);
+                }
+                Some(allowed)
+            }
+            (indices, _) => indices,
+        };
+
+        // Chunks of a manifest we hold are checked against its root, so
+        // the peer need not send the listing again.
+        let proven = known.filter(|_| peer.supports(FEATURE_CHUNK_PROOFS)).zip(indices.as_deref());
+        let request = match proven {
+            Some((_, indices)) => PullRequest::proven(&self.identity, &session.transcript_hash, manifest_id, indices.to_vec()),
+            None => PullRequest::new(&self.identity, &session.transcript_hash, manifest_id, indices.clone()),
+        };
+        session.send_encrypted_frame(&mut transport, &request.to_frame()?).await?;
+        let reply = session.read_encrypted_frame(&mut transport).await?;
+        check_abort(&session, &reply)?;
+        if let PullReply::Unavailable(reason) = bincode::deserialize(&reply)? {
+            anyhow::bail!("{} refused the request: {}", peer.device_id, reason);
+        }
+
+        let received = async {
+            if let Some((known, indices)) = proven {
+                let traffic = self.receive_proven(&session, &mut transport, &peer, known, indices, attempt).await?;
+                return Ok((known.clone(), traffic));
+            }
+            let manifest_bytes = session.read_encrypted_frame(&mut transport).await?;
+            self.receive_payload(
+                &session, &mut transport, &peer, manifest_bytes, Some(manifest_id),
+                indices.as_deref().map_or(ChunkSelection::All, ChunkSelection::Only), None, false, attempt,
+            ).await
+        }.await;
+        let (manifest, traffic) = self.abort_if_failed(&session, &mut transport, received, attempt).await?;
+
+        if peer.supports(FEATURE_RESUMPTION) {
+            self.collect_ticket(&session, &mut transport).await;
+        }
+
+        self.record(Direction::Fetched, Some(&peer), &manifest, traffic.bytes, traffic, attempt);
+        tracing::info!("Transfer complete: {}", manifest.filename);
+        self.emit(TransferEvent::TransferComplete {
+            transfer_id: session.transfer_id(),
+            filename: manifest.filename.clone(),
+            size: manifest.size,
+        });
+        Ok((manifest, traffic))
+    }
+
+    /// Answer a pull request: send the published manifest and the requested
+    /// chunks, or tell the peer why not. Returns the chunk traffic.
+    async fn serve_pull<T>(
+        &self,
+        session: &Session,
+        transport: &mut T,
+        peer: &PeerInfo,
+        request: PullRequest,
+        allow_pull: bool,
+        attempt: &mut Attempt,
+    ) -> Result<(Manifest, Traffic)>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        request.verify(&peer.public_key, &session.transcript_hash)?;
+        tracing::info!("{} requested manifest {}", peer.device_id, request.manifest_id);
+        attempt.filename = Some(request.manifest_id.clone());
+
+        // Keyed chunk ids only verify at devices holding the key.
+        let keyed = self.cfg.chunk_key.is_some() && !peer.supports(FEATURE_KEYED_CHUNKS);
+        let found = if allow_pull && !keyed { self.published(&request.manifest_id)? } else { None };
+        let Some(manifest) = found else {
+            let reason = match (allow_pull, keyed) {
+                (false, _) => "pull requests are not accepted",
+                (true, true) => "chunk ids are keyed with the account secret",
+                (true, false) => "unknown manifest",
+            };
+            let reply = PullReply::Unavailable(reason.to_string());
+            session.send_encrypted_frame(transport, &bincode::serialize(&reply)?).await?;
+            anyhow::bail!("Refused pull of {} by {}: {}", request.manifest_id, peer.device_id, reason);
+        };
+        session.send_encrypted_frame(transport, &bincode::serialize(&PullReply::Serving)?).await?;
+        attempt.manifest(&manifest);
+        if request.proofs {
+            let traffic = self.send_proven(session, transport, &manifest, request.chunks.as_deref().unwrap_or_default(), attempt).await?;
+            return Ok((manifest, traffic));
+        }
+
+        let (manifest, sealed) = self.seal_manifest(manifest, Some(peer))?;
+        let traffic = self.send_payload(
+            session, transport, &manifest, &sealed, Some(peer), true, &mut ChunkSource::Storage,
+            request.chunks.as_deref().map_or(ChunkSelection::All, ChunkSelection::Only), None, attempt,
+        ).await?;
+        Ok((manifest, traffic))
+    }
+
+    /// Answer a proof pull: send the signed root of `manifest`, then the
+    /// chunks at `indices` with their proofs.
+    async fn send_proven<T>(
+        &self,
+        session: &Session,
+        transport: &mut T,
+        manifest: &Manifest,
+        indices: &[u32],
+        attempt: &mut Attempt,
+    ) -> Result<Traffic>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let root = ManifestRoot::new(manifest, &self.identity)?;
+        session.send_encrypted_frame(transport, &bincode::serialize(&root)?).await?;
+        let tree = merkle::Tree::over_chunks(&manifest.chunk_hashes);
+        let mut traffic = Traffic::default();
+        for (n, &index) in indices.iter().enumerate() {
+            let hash = manifest.chunk_hashes.get(index as usize)
+                .with_context(|| format!("Chunk {} is beyond the {} chunks of {}", index, manifest.chunk_hashes.len(), manifest.filename))?;
+            let data = self.storage.get_chunk(hash).await?
+                .ok_or_else(|| anyhow::anyhow!("Chunk {} missing locally", hash))?;
+            traffic.bytes += data.len() as u64;
+            let chunk = ProvenChunk { index, hash: hash.clone(), proof: tree.proof(index as usize), data };
+            session.send_encrypted_frame(transport, &bincode::serialize(&chunk)?).await?;
+            attempt.chunk(n + 1, indices.len());
+        }
+        Ok(traffic)
+    }
+
+    /// Receive the answer to a proof pull of `indices` from `known`: its
+    /// root, which must match ours, then each chunk, checked against the
+    /// root by its proof and against its id by its content. Chunks that
+    /// fail are quarantined for the peer rather than failing the pull.
+    async fn receive_proven<T>(
+        &self,
+        session: &Session,
+        transport: &mut T,
+        peer: &PeerInfo,
+        known: &Manifest,
+        indices: &[u32],
+        attempt: &mut Attempt,
+    ) -> Result<Traffic>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let bytes = session.read_encrypted_frame(transport).await?;
+        check_abort(session, &bytes)?;
+        let root: ManifestRoot = bincode::deserialize(&bytes).context("Malformed manifest root")?;
+        root.verify(&peer.public_key)?;
+        if root.manifest_id != known.id() || root.root != merkle::chunk_root(known) {
+            anyhow::bail!("{} serves a different listing of {}", peer.device_id, known.filename);
+        }
+
+        let mut traffic = Traffic::default();
+        let mut done = 0;
+        for (n, &index) in indices.iter().enumerate() {
+            let bytes = session.read_encrypted_frame(transport).await?;
+            check_abort(session, &bytes)?;
+            let chunk: ProvenChunk = bincode::deserialize(&bytes)?;
+            if chunk.index != index {
+                anyhow::bail!("Unexpected chunk index {}", chunk.index);
+            }
+            root.check_chunk(index, &chunk.hash, &chunk.proof)?;
+            attempt.chunk(n + 1, indices.len());
+            let bytes = chunk.data.len();
+            traffic.bytes += bytes as u64;
+
+            let (data, expected, key) = (chunk.data, chunk.hash.clone(), self.cfg.chunk_key.clone());
+            let (data, hex, keyed) = self.pool.run(move || {
+                let hex = keyed::chunk_id(None, &data);
+                let keyed = hex != expected && key.is_some_and(|k| k.chunk_id(&data) == expected);
+                (data, hex, keyed)
+            }).await?;
+            if hex != chunk.hash && !keyed {
+                tracing::warn!("Chunk hash mismatch: expected {} got {}", chunk.hash, hex);
+                let reason = format!("hash mismatch: got {}", hex);
+                self.quarantine.lock().unwrap().record_failure(&peer.device_id, &chunk.hash, &reason, self.env.now());
+                continue;
+            }
+            if self.storage.has_chunk(&chunk.hash).await? {
+                traffic.reused += bytes as u64;
+            } else {
+                store_chunk(&*self.storage, &chunk.hash, &data, keyed).await?;
+            }
+            self.emit(TransferEvent::ChunkReceived { transfer_id: session.transfer_id(), index: index as usize, total: indices.len(), bytes });
+            done += 1;
+        }
+        tracing::info!("Received {}/{} proven chunks", done, indices.len());
+        self.storage.add_manifest(&known.id(), &known.chunk_hashes).await?;
+        Ok(traffic)
+    }
+
+    /// Make an imported manifest available to pull requests from trusted
+    /// peers. Returns its [`Manifest::id`].
+    pub fn publish(&self, manifest: &Manifest) -> Result<String, OpenShareError> {
+        Ok(self.manifests.save(manifest)?)
+    }
+
+    /// Exchange hellos with a connected peer and hang up, as the initiator
+    /// of the connection, to learn its protocol version and features. The
+    /// peer need not be trusted; nothing but the handshake is sent.
+    pub async fn probe<T>(&self, mut transport: T) -> Result<PeerInfo, OpenShareError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let session = handshake::initiator_handshake_with(&self.identity, &self.cfg.device_id, &mut transport, &self.env).await?;
+        self.emit(TransferEvent::HandshakeComplete {
+            transfer_id: session.transfer_id(),
+            peer: session.peer.clone(),
+            resumed: session.resumed,
+        });
+        let peer = session.peer.clone()
+            .ok_or_else(|| anyhow::anyhow!("Peer did not identify itself"))?;
+        linger(&mut transport).await;
+        Ok(peer)
+    }
+
+    /// Ask a connected peer for its content index, as the initiator of the
+    /// connection. The peer must be trusted, on our account, and sharing.
+    pub async fn request_index<T>(&self, mut transport: T) -> Result<DeviceIndex, OpenShareError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let session = handshake::initiator_handshake_with(&self.identity, &self.cfg.device_id, &mut transport, &self.env).await?;
+        self.emit(TransferEvent::HandshakeComplete {
+            transfer_id: session.transfer_id(),
+            peer: session.peer.clone(),
+            resumed: session.resumed,
+        });
+        let peer = session.peer.clone()
+            .ok_or_else(|| anyhow::anyhow!("Peer did not identify itself; refusing index"))?;
+        self.authorize_peer(&peer)?;
+        if !peer.supports(FEATURE_CONTENT_INDEX) {
+            return Err(anyhow::anyhow!(
+                "{} does not share a content index (protocol {})",
+                peer.device_id, peer.protocol_version
+            ).into());
+        }
+
+        let request = IndexRequest { account_hash: self.cfg.account_hash.clone() };
+        session.send_encrypted_frame(&mut transport, &request.to_frame()?).await?;
+        match bincode::deserialize(&session.read_encrypted_frame(&mut transport).await?)? {
+            IndexReply::Entries(entries) => Ok(DeviceIndex {
+                device_id: peer.device_id,
+                address: None,
+                updated_at: self.unix_now(),
+                entries,
+            }),
+            IndexReply::Unavailable(reason) => Err(anyhow::anyhow!("{} refused the index request: {}", peer.device_id, reason).into()),
+        }
+    }
+
+    /// Answer an index request with our published shares, or tell the
+    /// peer why not. Returns how many entries were shared.
+    async fn serve_index<T>(
+        &self,
+        session: &Session,
+        transport: &mut T,
+        peer: &PeerInfo,
+        request: IndexRequest,
+        allow: bool,
+    ) -> Result<usize>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let refusal = if !allow || !self.cfg.share_index {
+            Some("index sharing is off")
+        } else if self.cfg.account_hash.is_empty() || request.account_hash != self.cfg.account_hash {
+            Some("different account")
+        } else {
+            None
+        };
+        if let Some(reason) = refusal {
+            let reply = IndexReply::Unavailable(reason.to_string());
+            session.send_encrypted_frame(transport, &bincode::serialize(&reply)?).await?;
+            anyhow::bail!("Refused index request by {}: {}", peer.device_id, reason);
+        }
+
+        let entries = self.local_index()?;
+        let count = entries.len();
+        session.send_encrypted_frame(transport, &bincode::serialize(&IndexReply::Entries(entries))?).await?;
+        tracing::info!("Shared content index ({} entries) with {}", count, peer.device_id);
+        Ok(count)
+    }
+
+    /// Tell a connected peer that we published `manifest`, as the
+    /// initiator of the connection. The peer must be trusted, on our
+    /// account, and accepting announcements.
+    pub async fn announce<T>(&self, mut transport: T, manifest: &Manifest) -> Result<(), OpenShareError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let session = handshake::initiator_handshake_with(&self.identity, &self.cfg.device_id, &mut transport, &self.env).await?;
+        self.emit(TransferEvent::HandshakeComplete {
+            transfer_id: session.transfer_id(),
+            peer: session.peer.clone(),
+            resumed: session.resumed,
+        });
+        let peer = session.peer.clone()
+            .ok_or_else(|| anyhow::anyhow!("Peer did not identify itself; refusing announcement"))?;
+        self.authorize_peer(&peer)?;
+        if !peer.supports(FEATURE_SHARE_ANNOUNCE) {
+            return Err(anyhow::anyhow!(
+                "{} does not take share announcements (protocol {})",
+                peer.device_id, peer.protocol_version
+            ).into());
+        }
+
+        let announcement = ShareAnnouncement::new(
+            &self.identity, &session.transcript_hash, &self.cfg.account_hash,
+            IndexEntry::from_manifest(manifest), self.cfg.listen_port,
+        )?;
+        session.send_encrypted_frame(&mut transport, &announcement.to_frame()?).await?;
+        match bincode::deserialize(&session.read_encrypted_frame(&mut transport).await?)? {
+            AnnounceReply::Noted => Ok(()),
+            AnnounceReply::Unavailable(reason) => Err(anyhow::anyhow!("{} refused the announcement: {}", peer.device_id, reason).into()),
+        }
+    }
+
+    /// Check a share announcement and pass it on to notification
+    /// subscribers, or tell the peer why not.
+    async fn note_announcement<T>(
+        &self,
+        session: &Session,
+        transport: &mut T,
+        peer: &PeerInfo,
+        announcement: &ShareAnnouncement,
+    ) -> Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        announcement.verify(&peer.public_key, &session.transcript_hash)?;
+        let refusal = if !self.cfg.accept_announcements {
+            Some("announcements are not accepted")
+        } else if self.cfg.account_hash.is_empty() || announcement.account_hash != self.cfg.account_hash {
+            Some("different account")
+        } else {
+            None
+        };
+        if let Some(reason) = refusal {
+            let reply = AnnounceReply::Unavailable(reason.to_string());
+            session.send_encrypted_frame(transport, &bincode::serialize(&reply)?).await?;
+            anyhow::bail!("Refused announcement by {}: {}", peer.device_id, reason);
+        }
+        session.send_encrypted_frame(transport, &bincode::serialize(&AnnounceReply::Noted)?).await?;
+
+        let entry = &announcement.entry;
+        tracing::info!("{} published {} ({})", peer.device_id, entry.filename, entry.manifest_id);
+        if let Some(hub) = &self.notifications {
+            hub.publish(&TransferRecord {
+                transfer_id: Some(session.transfer_id()),
+                direction: Direction::Announced,
+                peer: peer.device_id.clone(),
+                peer_fingerprint: Some(hex::encode(peer.public_key)),
+                filename: entry.filename.clone(),
+                size: entry.size,
+                reused_bytes: 0,
+                compression_saved_bytes: 0,
+                finished_at: self.unix_now(),
+                duration_ms: 0,
+                error: None,
+            });
+        }
+        Ok(())
+    }
+
+    /// Send `text` inline to a connected peer, as the initiator of the
+    /// connection. Nothing is stored on either side; the peer must be
+    /// trusted and take texts.
+    pub async fn send_text<T>(&self, mut transport: T, text: &str) -> Result<(), OpenShareError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        if text.len() > MAX_TEXT_LEN {
+            return Err(anyhow::anyhow!(
+                "Text is {} bytes, over the {} byte limit; send it as a file",
+                text.len(), MAX_TEXT_LEN
+            ).into());
+        }
+        let session = handshake::initiator_handshake_with(&self.identity, &self.cfg.device_id, &mut transport, &self.env).await?;
+        self.emit(TransferEvent::HandshakeComplete {
+            transfer_id: session.transfer_id(),
+            peer: session.peer.clone(),
+            resumed: session.resumed,
+        });
+        let peer = session.peer.clone()
+            .ok_or_else(|| anyhow::anyhow!("Peer did not identify itself; refusing to send text"))?;
+        self.authorize_peer(&peer)?;
+        if !peer.supports(FEATURE_TEXT) {
+            return Err(anyhow::anyhow!(
+                "{} does not take texts (protocol {}); send a file instead",
+                peer.device_id, peer.protocol_version
+            ).into());
+        }
+
+        let message = TextMessage { text: text.to_string() };
+        session.send_encrypted_frame(&mut transport, &message.to_frame()?).await?;
+        match bincode::deserialize(&session.read_encrypted_frame(&mut transport).await?)? {
+            TextReply::Received => Ok(()),
+            TextReply::Refused(reason) => Err(anyhow::anyhow!("{} refused the text: {}", peer.device_id, reason).into()),
+        }
+    }
+
+    /// Check a text message and acknowledge it, or tell the peer why not.
+    async fn take_text<T>(
+        &self,
+        session: &Session,
+        transport: &mut T,
+        peer: &PeerInfo,
+        message: TextMessage,
+    ) -> Result<String>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        if message.text.len() > MAX_TEXT_LEN {
+            let reason = format!("texts are limited to {} bytes", MAX_TEXT_LEN);
+            session.send_encrypted_frame(transport, &bincode::serialize(&TextReply::Refused(reason.clone()))?).await?;
+            anyhow::bail!("Refused text from {}: {}", peer.device_id, reason);
+        }
+        session.send_encrypted_frame(transport, &bincode::serialize(&TextReply::Received)?).await?;
+        tracing::info!("Received {} bytes of text from {}", message.text.len(), peer.device_id);
+        Ok(message.text)
+    }
+
+    /// Run management `method` with JSON `params` on the daemon of the
+    /// peer on `transport`, which must trust this device as an admin.
+    /// Returns the method's result. See [`crate::admin`].
+    pub async fn administer<T>(&self, mut transport: T, method: &str, params: serde_json::Value) -> Result<serde_json::Value, OpenShareError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let session = handshake::initiator_handshake_with(&self.identity, &self.cfg.device_id, &mut transport, &self.env).await?;
+        let peer = session.peer.clone()
+            .ok_or_else(|| anyhow::anyhow!("Peer did not identify itself; refusing to manage it"))?;
+        self.authorize_peer(&peer)?;
+        if !peer.supports(FEATURE_ADMIN) {
+            return Err(anyhow::anyhow!("{} cannot be managed remotely (protocol {})", peer.device_id, peer.protocol_version).into());
+        }
+
+        let request = AdminRequest::new(&self.identity, &session.transcript_hash, method, params.to_string())?;
+        session.send_encrypted_frame(&mut transport, &request.to_frame()?).await?;
+        match bincode::deserialize(&session.read_encrypted_frame(&mut transport).await?)? {
+            AdminReply::Done(result) => Ok(serde_json::from_str(&result).context("Malformed admin result")?),
+            AdminReply::Refused(reason) => Err(anyhow::anyhow!("{} refused {}: {}", peer.device_id, method, reason).into()),
+        }
+    }
+
+    /// Check an admin request against the trust store and run it with the
+    /// admin handler, or tell the peer why not. Returns the method.
+    async fn serve_admin<T>(&self, session: &Session, transport: &mut T, peer: &PeerInfo, request: AdminRequest) -> Result<String>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        request.verify(&peer.public_key, &session.transcript_hash)?;
+        let is_admin = self.trust.lock().unwrap().get(&peer.device_id).is_some_and(|p| p.admin);
+        let outcome = match &self.admin {
+            _ if !is_admin => Err(anyhow::anyhow!("{} is not trusted as an admin", peer.device_id)),
+            _ if !ADMIN_METHODS.contains(&request.method.as_str()) => {
+                Err(anyhow::anyhow!("'{}' is not available remotely", request.method))
+            }
+            None => Err(anyhow::anyhow!("no daemon to manage")),
+            Some(handler) => match serde_json::from_str(&request.params) {
+                Ok(params) => handler.call(&peer.device_id, &request.method, params).await,
+                Err(e) => Err(anyhow::anyhow!("invalid params: {}", e)),
+            },
+        };
+        let reply = match &outcome {
+            Ok(result) => AdminReply::Done(result.to_string()),
+            Err(e) => AdminReply::Refused(format!("{:#}", e)),
+        };
+        session.send_encrypted_frame(transport, &bincode::serialize(&reply)?).await?;
+        match outcome {
+            Ok(_) => {
+                tracing::info!("{} ran {} remotely", peer.device_id, request.method);
+                Ok(request.method)
+            }
+            Err(e) => Err(e.context(format!("Refused {} from {}", request.method, peer.device_id))),
+        }
+    }
+
+    /// Index entries for everything this device has published.
+    pub fn local_index(&self) -> Result<Vec<IndexEntry>, OpenShareError> {
+        let mut entries: Vec<_> = self.published_manifests()?.iter().map(IndexEntry::from_manifest).collect();
+        entries.sort_by(|a, b| a.filename.cmp(&b.filename));
+        Ok(entries)
+    }
+
+    /// Every manifest this device has published.
+    pub fn published_manifests(&self) -> Result<Vec<Manifest>, OpenShareError> {
+        Ok(self.manifests.list()?.into_iter().map(|(_, manifest)| manifest).collect())
+    }
+
+    /// Indices of the chunks of `manifest` not yet in storage, one per
+    /// distinct chunk.
+    pub async fn missing_chunks(&self, manifest: &Manifest) -> Result<Vec<u32>, OpenShareError> {
+        let mut seen = std::collections::HashSet::new();
+        let mut missing = Vec::new();
+        for (i, hash) in manifest.chunk_hashes.iter().enumerate() {
+            if seen.insert(hash) && !self.storage.has_chunk(hash).await? {
+                missing.push(i as u32);
+            }
+        }
+        Ok(missing)
+    }
+
+    /// Look up a published manifest by ID.
+    pub fn published(&self, manifest_id: &str) -> Result<Option<Manifest>, OpenShareError> {
+        Ok(self.manifests.load(manifest_id)?)
+    }
+
+    /// Whether `manifest_id` is offered with a share code that still
+    /// works (see [`crate::offers`]).
+    pub fn offered(&self, manifest_id: &str) -> Result<bool, OpenShareError> {
+        Ok(Offers::load(&self.cfg.data_dir)?.active(manifest_id, self.unix_now()).is_some())
+    }
+}
+
+/// Rounds of requests a [`PeerChunkFetcher`] makes for chunks that do not
+/// arrive intact, and the pause before the second (doubling after that).
+pub(crate) const FETCH_ROUNDS: u32 = 3;
+pub(crate) const FETCH_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Fetches missing chunks of a published manifest from a peer with
+/// partial pulls, opening a connection through `connect` for each batch.
+/// Plugged into a [`ShareView`](crate::vfs::ShareView) it gives
+/// fetch-on-read for placeholders.
+///
+/// Chunks that fail verification are asked for again, a few times and
+/// with a growing pause, until they arrive or the peer's copy is
+/// quarantined (see [`crate::quarantine`]).
+pub struct PeerChunkFetcher<S, C> {
+    client: Arc<Client<S>>,
+    connect: C,
+}
+
+impl<S, C> PeerChunkFetcher<S, C> {
+    pub fn new(client: Arc<Client<S>>, connect: C) -> Self {
+        Self { client, connect }
+    }
+}
+
+#[async_trait::async_trait]
+impl<S, C, F, T> ChunkFetcher for PeerChunkFetcher<S, C>
+where
+    S: Storage + Send + Sync + 'static,
+    C: Fn() -> F + Send + Sync,
+    F: std::future::Future<Output = Result<T>> + Send,
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    async fn fetch(&self, manifest: &Manifest, missing: &[String]) -> Result<()> {
+        let mut pending = Vec::new();
+        for hash in missing {
+            let index = manifest.chunk_hashes.iter().position(|h| h == hash)
+                .with_context(|| format!("Chunk {} is not part of the manifest", hash))?;
+            pending.push(index as u32);
+        }
+
+        for round in 0..FETCH_ROUNDS {
+            if round > 0 {
+                tracing::info!("Retrying {} chunk(s) that did not arrive intact", pending.len());
+                tokio::time::sleep(FETCH_RETRY_DELAY * 2u32.pow(round - 1)).await;
+            }
+            let transport = (self.connect)().await?;
+            self.client.request_unquarantined(transport, manifest, pending.clone()).await?;
+
+            let mut still_missing = Vec::new();
+            for index in pending {
+                if !self.client.storage.has_chunk(&manifest.chunk_hashes[index as usize]).await? {
+                    still_missing.push(index);
+                }
+            }
+            if still_missing.is_empty() {
+                return Ok(());
+            }
+            pending = still_missing;
+        }
+        anyhow::bail!("{} chunk(s) failed verification {} times", pending.len(), FETCH_ROUNDS)
+    }
+}
+
+/// A pairing handshake waiting for the users to compare `code`; see
+/// [`Client::pair`].
+pub struct Pairing<T> {
+    pub peer: PeerInfo,
+    /// Short authentication string, e.g. "042 917"
+    pub code: String,
+    session: Session,
+    transport: T,
+}
+
+/// Outcome of [`Client::accept`].
+#[derive(Debug, Clone)]
+pub enum Accepted {
+    /// The peer pushed a transfer, now in storage
+    Received(Manifest),
+    /// The peer streamed content, now written to the
+    /// [stream output](Client::with_stream_output)
+    Streamed(Manifest),
+    /// The peer pushed several transfers in one session; the ones that
+    /// are now in storage, in order
+    ReceivedBatch(Vec<Manifest>),
+    /// The peer pulled a published manifest from us
+    Served(Manifest),
+    /// The peer fetched our content index
+    IndexShared { device_id: String, entries: usize },
+    /// The peer announced a share it published
+    Announced { device_id: String, announcement: ShareAnnouncement },
+    /// The peer ran a management method on our daemon
+    Administered { device_id: String, method: String },
+    /// The peer sent a short text inline
+    Text { device_id: String, text: String },
+    /// The peer only exchanged hellos (see [`Client::probe`])
+    Probed { device_id: String },
+}
+
+impl Accepted {
+    pub fn manifest(&self) -> Option<&Manifest> {
+        match self {
+            Accepted::Received(m) | Accepted::Streamed(m) | Accepted::Served(m) => Some(m),
+            Accepted::ReceivedBatch(_)
+            | Accepted::IndexShared { .. }
+            | Accepted::Announced { .. }
+            | Accepted::Administered { .. }
+            | Accepted::Text { .. }
+            | Accepted::Probed { .. } => None,
+        }
+    }
+}
+
+impl<S> Client<S> {
+    /// Append a completed transfer to the local history and notify
+    /// subscribers, if either is enabled.
+    fn record(&self, direction: Direction, peer: Option<&PeerInfo>, manifest: &Manifest, size: u64, traffic: Traffic, attempt: &Attempt) {
+        self.log(TransferRecord {
+            transfer_id: attempt.transfer_id,
+            direction,
+            peer: peer.map_or("unknown", |p| p.device_id.as_str()).to_string(),
+            peer_fingerprint: peer.map(|p| hex::encode(p.public_key)),
+            filename: manifest.filename.clone(),
+            size,
+            reused_bytes: traffic.reused,
+            compression_saved_bytes: traffic.saved,
+            finished_at: self.unix_now(),
+            duration_ms: attempt.started.elapsed().as_millis() as u64,
+            error: None,
+        });
+    }
+
+    /// Like [`record`](Self::record) for a transfer that failed. Nothing is
+    /// recorded for incoming connections that failed before saying what
+    /// they wanted, or for sends that failed before reaching the network.
+    fn record_failure(&self, attempt: &Attempt, error: &anyhow::Error) {
+        let (Some(direction), Some(filename)) = (attempt.direction, &attempt.filename) else {
+            return;
+        };
+        let peer = attempt.peer.as_ref();
+        self.log(TransferRecord {
+            transfer_id: attempt.transfer_id,
+            direction,
+            peer: peer.map_or("unknown", |p| p.device_id.as_str()).to_string(),
+            peer_fingerprint: peer.map(|p| hex::encode(p.public_key)),
+            filename: filename.clone(),
+            size: attempt.size,
+            reused_bytes: 0,
+            compression_saved_bytes: 0,
+            finished_at: self.unix_now(),
+            duration_ms: attempt.started.elapsed().as_millis() as u64,
+            error: Some(format!("{:#}", error)),
+        });
+    }
+
+    fn log(&self, record: TransferRecord) {
+        // History is a convenience; never fail a transfer over it.
+        if let Some(Err(e)) = self.history.as_ref().map(|h| h.append(&record)) {
+            tracing::warn!("Failed to record transfer history: {:#}", e);
+        }
+        if let Some(hub) = &self.notifications {
+            hub.publish(&record);
+        }
+    }
+
+    pub(crate) fn unix_now(&self) -> u64 {
+        self.env.now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+    }
+
+    fn emit(&self, event: TransferEvent) {
+        if let Some(observer) = &self.observer {
+            observer.on_event(&event);
+        }
+    }
+
+    /// Emit `TransferFailed` and record the failed `attempt` if `result`
+    /// is an error, then pass it on sorted into an [`OpenShareError`],
+    /// naming the transfer if it got that far.
+    fn report<R>(&self, result: Result<R>, attempt: &Attempt) -> Result<R, OpenShareError> {
+        let e = match result {
+            Ok(r) => return Ok(r),
+            Err(e) => e,
+        };
+        self.emit(TransferEvent::TransferFailed { transfer_id: attempt.transfer_id, error: format!("{:#}", e) });
+        self.record_failure(attempt, &e);
+        Err(match attempt.transfer_id {
+            Some(id) => e.context(format!("Transfer {}", id)),
+            None => e,
+        }.into())
+    }
+
+    /// Put a pushed manifest to the policy, tell a peer with
+    /// `transfer-consent` the verdict, and fail with [`Rejected`] if it
+    /// is a rejection; with the `space` shortfall as its cause, if any.
+    async fn review<T>(
+        &self,
+        session: &Session,
+        transport: &mut T,
+        peer: &PeerInfo,
+        listing: &Listing,
+        space: Option<InsufficientSpace>,
+    ) -> Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let trust = self.trust.lock().unwrap().get(&peer.device_id).map(|p| p.source);
+        let (files, preview) = match listing {
+            Listing::Whole(manifest) => (
+                manifest.files.iter().filter(|f| !f.is_dir).map(|f| f.path.clone()).collect(),
+                manifest.preview.clone(),
+            ),
+            Listing::Paged(_) => (Vec::new(), None),
+        };
+        let offer = Offer {
+            transfer_id: session.transfer_id(),
+            peer: peer.device_id.clone(),
+            trust,
+            filename: listing.filename().to_string(),
+            size: listing.size(),
+            files,
+            space,
+            preview,
+        };
+        let verdict = self.policy.review(&offer).await;
+        if peer.supports(FEATURE_TRANSFER_CONSENT) {
+            session.send_encrypted_frame(transport, &bincode::serialize(&verdict)?).await?;
+        }
+        match verdict {
+            Verdict::Accept => Ok(()),
+            Verdict::Reject(reason) => {
+                tracing::info!("Rejected {} from {}: {}", offer.filename, peer.device_id, reason);
+                let rejected = Rejected { by: None, reason };
+                match offer.space {
+                    Some(space) => Err(anyhow::Error::new(space).context(rejected)),
+                    None => Err(rejected.into()),
+                }
+            }
+        }
+    }
+
+    /// Whether a transfer of `listing` would run out of disk space: in the
+    /// local chunk store and in [`output_dir`](Self::output_dir), where
+    /// its file is assembled. Chunks picked by index are not checked, as
+    /// they go to a placeholder or a swarm fetch that checks the whole.
+    fn space_shortfall(&self, listing: &Listing, selection: ChunkSelection<'_>) -> Option<InsufficientSpace> {
+        if let ChunkSelection::Only(_) = selection {
+            return None;
+        }
+        let mut needs = Vec::new();
+        if let StorageConfig::Local = self.cfg.storage {
+            needs.push((self.cfg.data_dir.join("chunks"), listing.size()));
+        }
+        if let Some(dir) = &self.output_dir {
+            needs.push((dir.clone(), listing.size()));
+        }
+        space::check(&needs).err()
+    }
+
+    /// With `preallocate_files`, reserve the space for a single file in
+    /// [`output_dir`](Self::output_dir) as the part file it is assembled
+    /// in, and return that. Failing to is not fatal.
+    fn reserve_output(&self, listing: &Listing, selection: ChunkSelection<'_>) -> Option<PathBuf> {
+        let Listing::Whole(manifest) = listing else { return None };
+        let dir = self.output_dir.as_ref().filter(|_| self.cfg.preallocate_files)?;
+        if manifest.is_directory() || matches!(selection, ChunkSelection::Only(_)) {
+            return None;
+        }
+        let part = manifest.output_path(dir).and_then(|path| manifest::part_path(&path)).ok()?;
+        if part.exists() {
+            // Another transfer's, or one left by a failed write; it is
+            // written over in place either way.
+            return None;
+        }
+        match space::reserve(&part, manifest.size) {
+            Ok(()) => Some(part),
+            Err(e) => {
+                tracing::warn!("Cannot reserve space for {}: {}", part.display(), e);
+                let _ = std::fs::remove_file(&part);
+                None
+            }
+        }
+    }
+
+    /// Acknowledge a processed chunk to the sender, if it expects acks;
+    /// one that was not stored goes on `rejected`.
+    #[allow(clippy::too_many_arguments)]
+    async fn ack_chunk<T>(
+        &self,
+        session: &Session,
+        transport: &mut T,
+        (index, bytes, stored): (usize, usize, bool),
+        send_ack: bool,
+        total: usize,
+        done: &mut usize,
+        rejected: &mut Vec<usize>,
+    ) -> Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        if send_ack {
+            let ack = ChunkAck { index: index as u32, stored };
+            session.send_encrypted_frame(transport, &bincode::serialize(&ack)?).await?;
+        }
+        if stored {
+            self.emit(TransferEvent::ChunkReceived { transfer_id: session.transfer_id(), index, total, bytes });
+        } else {
+            rejected.push(index);
+        }
+
+        *done += 1;
+        if (*done).is_multiple_of(10) {
+            tracing::debug!("Received {}/{} chunks", done, total);
+        }
+        Ok(())
+    }
+
+    async fn send_ticket<T>(&self, session: &Session, transport: &mut T) -> Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let lifetime = Duration::from_secs(self.cfg.ticket_lifetime_secs);
+        let new = resumption::issue_ticket(session, &self.ticket_key, lifetime, &self.env)?;
+        session.send_encrypted_frame(transport, &bincode::serialize(&new)?).await?;
+        Ok(())
+    }
+}
+
+/// A signed manifest encoded for one peer.
+enum Sealed {
+    /// In one frame
+    Whole(Vec<u8>),
+    /// As a header and pages
+    Paged(Box<PagedManifest>),
+}
+
+/// Which chunks of a manifest a transfer carries.
+#[derive(Debug, Clone, Copy)]
+enum ChunkSelection<'a> {
+    All,
+    /// These indices, in this order (partial pulls; needs a storage source)
+    Only(&'a [u32]),
+    /// All but those the receiver reports holding in [`HaveChunks`]
+    Missing,
+}
+
+/// Chunk bytes moved by one transfer.
+#[derive(Debug, Clone, Copy, Default)]
+struct Traffic {
+    /// Chunk bytes sent or received, before compression
+    bytes: u64,
+    /// Received bytes that were already in storage, including chunks the
+    /// sender skipped
+    reused: u64,
+    /// Bytes compression kept off the wire
+    saved: u64,
+}
+
+/// Chunks of a send that may have to go out again.
+struct Resend {
+    /// Times one chunk may be sent again
+    retries: u32,
+    /// Whether a chunk that stays rejected fails the transfer, as in a
+    /// push. A pull leaves it missing for [`PeerChunkFetcher`] to retry.
+    strict: bool,
+    /// Whether to keep the data of chunks that cannot be read again, from
+    /// a streamed source
+    keep: bool,
+    /// Chunks awaiting an acknowledgment while retries are allowed, with
+    /// any kept data and how often each was sent again
+    unacked: HashMap<u32, (Option<Vec<u8>>, u32)>,
+    /// Chunk indices rejected since the last [`take_rejected`](Self::take_rejected)
+    rejected: Vec<u32>,
+}
+
+impl Resend {
+    fn new(retries: u32, strict: bool, keep: bool) -> Self {
+        Self { retries, strict, keep, unacked: HashMap::new(), rejected: Vec::new() }
+    }
+
+    /// Track chunk `index` until it is stored, if it could be sent again.
+    fn sent(&mut self, index: u32, data: &[u8]) {
+        if self.retries > 0 {
+            self.unacked.insert(index, (self.keep.then(|| data.to_vec()), 0));
+        }
+    }
+
+    fn acked(&mut self, ack: ChunkAck) -> Result<()> {
+        if ack.stored {
+            self.unacked.remove(&ack.index);
+        } else if self.unacked.contains_key(&ack.index) {
+            self.rejected.push(ack.index);
+        } else if self.strict {
+            anyhow::bail!("Receiver rejected chunk {}", ack.index);
+        }
+        Ok(())
+    }
+
+    /// The rejected chunks to send again, with their data if kept,
+    /// counting the attempt. Fails once a chunk has used up its retries.
+    fn take_rejected(&mut self) -> Result<Vec<(u32, Option<Vec<u8>>)>> {
+        let mut again = Vec::new();
+        for index in std::mem::take(&mut self.rejected) {
+            let (data, tries) = self.unacked.get_mut(&index).expect("rejected chunks are kept");
+            if *tries == self.retries {
+                anyhow::bail!("Receiver rejected chunk {} {} times", index, *tries + 1);
+            }
+            *tries += 1;
+            again.push((index, data.clone()));
+        }
+        Ok(again)
+    }
+}
+
+/// What is known about a transfer while it runs, so that a failure can be
+/// recorded with whatever was learned before it.
+struct Attempt {
+    started: Instant,
+    /// `None` until an incoming connection says what it wants
+    direction: Option<Direction>,
+    /// Both `None` until the handshake is done
+    transfer_id: Option<TransferId>,
+    peer: Option<PeerInfo>,
+    /// The manifest ID until the manifest itself is known
+    filename: Option<String>,
+    size: u64,
+    stage: Stage,
+    /// Chunks sent or received so far, and how many are due
+    chunks: (usize, usize),
+    /// Part file reserved for the output (see [`space::reserve`])
+    reserved: Option<PathBuf>,
+}
+
+impl Attempt {
+    fn new(direction: Option<Direction>) -> Self {
+        Self {
+            started: Instant::now(),
+            direction,
+            transfer_id: None,
+            peer: None,
+            filename: None,
+            size: 0,
+            stage: Stage::Manifest,
+            chunks: (0, 0),
+            reserved: None,
+        }
+    }
+
+    /// Take the transfer ID and peer from the new session, and tag the
+    /// rest of the [transfer span](transfer_span) with the ID.
+    fn session(&mut self, session: &Session) {
+        let id = session.transfer_id();
+        tracing::Span::current().record("id", tracing::field::display(id));
+        tracing::debug!("Transfer {} with {}", id, session.peer.as_ref().map_or("unknown", |p| p.device_id.as_str()));
+        self.transfer_id = Some(id);
+        self.peer = session.peer.clone();
+    }
+
+    fn manifest(&mut self, manifest: &Manifest) {
+        self.filename = Some(manifest.filename.clone());
+        self.size = manifest.size;
+    }
+
+    /// The `n`th of `total` chunks is on its way.
+    fn chunk(&mut self, n: usize, total: usize) {
+        self.stage = Stage::Chunks;
+        self.chunks = (n, total);
+    }
+
+    /// What to tell the peer about `error`.
+    fn abort(&self, error: &anyhow::Error) -> Abort {
+        Abort {
+            code: AbortCode::of(error),
+            stage: self.stage,
+            chunk: self.chunks.0 as u32,
+            chunks_total: self.chunks.1 as u32,
+        }
+    }
+}
+
+/// Span around one transfer; its `id` is filled in once the handshake is
+/// done, so every line logged after that names the transfer.
+/// `env` speaking `cfg`'s compat version, if one is set.
+fn compat(env: Env, cfg: &ClientConfig) -> Env {
+    match cfg.compat_version {
+        Some(version) => env.with_protocol_version(version),
+        None => env,
+    }
+}
+
+fn transfer_span() -> tracing::Span {
+    tracing::info_span!("transfer", id = tracing::field::Empty)
+}
+
+/// Where [`Client::stream_output`] writes.
+pub type StreamOutput = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Where a send reads chunk data from.
+enum ChunkSource {
+    /// Chunks previously imported into storage
+    Storage,
+    /// Straight from the source files, in manifest order
+    Files {
+        /// The file or directory being sent
+        root: PathBuf,
+        reader: Box<FileChunks>,
+        persist: bool,
+        /// Open the push with a [`SyncHeader`], as the manifest was cut
+        /// by content for a delta sync
+        sync: bool,
+    },
+}
+
+impl ChunkSource {
+    /// The chunk with hash `chunk_hash`, which must be the next one in
+    /// manifest order; read from files, its id is keyed with `key` if given.
+    async fn next_chunk<S: Storage + ?Sized>(&mut self, storage: &S, chunk_hash: &str, key: Option<&ChunkKey>) -> Result<Vec<u8>> {
+        let (reader, persist) = match self {
+            ChunkSource::Storage => {
+                return storage.get_chunk(chunk_hash).await?
+                    .ok_or_else(|| anyhow::anyhow!("Chunk {} missing locally", chunk_hash));
+            }
+            ChunkSource::Files { reader, persist, .. } => (reader, *persist),
+        };
+
+        let (path, buf) = reader.next().await?
+            .ok_or_else(|| anyhow::anyhow!("Source ended before chunk {}", chunk_hash))?;
+        if keyed::chunk_id(key, &buf) != chunk_hash {
+            anyhow::bail!("{} changed while it was being sent", path.display());
+        }
+        if persist {
+            store_chunk(storage, chunk_hash, &buf, key.is_some()).await?;
+        }
+        Ok(buf)
+    }
+}
+
+/// Store `data`, already checked to have id `id`. Stores name chunks by
+/// their plain hash themselves; a `keyed` id has to be given.
+async fn store_chunk<S: Storage + ?Sized>(storage: &S, id: &str, data: &[u8], keyed: bool) -> Result<()> {
+    if keyed {
+        return Ok(storage.put_chunk_as(id, data).await?);
+    }
+    let stored_id = storage.put_chunk(data).await?;
+    if stored_id != id {
+        tracing::warn!("Stored chunk ID mismatch: {} vs {}", stored_id, id);
+    }
+    Ok(())
+}
+
+/// Chunks of a list of files, cut as [`Manifest::from_file_chunked`]
+/// cuts them.
+struct FileChunks {
+    files: VecDeque<PathBuf>,
+    current: Option<(PathBuf, tokio::fs::File)>,
+    /// Read from `current` but not yet cut off
+    pending: Vec<u8>,
+    chunking: Chunking,
+}
+
+impl FileChunks {
+    fn new(files: Vec<PathBuf>, chunking: Chunking) -> Self {
+        Self { files: files.into(), current: None, pending: Vec::new(), chunking }
+    }
+
+    /// The next chunk and the file it is from, or `None` after the last.
+    async fn next(&mut self) -> Result<Option<(PathBuf, Vec<u8>)>> {
+        // Chunks never span files; an exhausted file moves on to the next.
+        let max = self.chunking.max_len();
+        loop {
+            let (path, file) = match &mut self.current {
+                Some(open) => open,
+                None => {
+                    let Some(path) = self.files.pop_front() else { return Ok(None) };
+                    let file = tokio::fs::File::open(&path).await
+                        .with_context(|| format!("Failed to open file: {}", path.display()))?;
+                    self.current.insert((path, file))
+                }
+            };
+            let held = self.pending.len();
+            self.pending.resize(max, 0);
+            let n = read_full(file, &mut self.pending[held..]).await?;
+            self.pending.truncate(held + n);
+            if !self.pending.is_empty() {
+                let len = self.chunking.cut(&self.pending);
+                return Ok(Some((path.clone(), self.pending.drain(..len).collect())));
+            }
+            self.current = None;
+        }
+    }
+}
+
+/// The regular files whose contents make up `manifest`'s chunks, in order,
+/// for a manifest built from `path`.
+/// A send's result as a [`TransferOutcome`], with a cancellation taken
+/// out of the error.
+fn outcome(result: Result<(), OpenShareError>) -> Result<TransferOutcome, OpenShareError> {
+    match result {
+        Ok(()) => Ok(TransferOutcome::Completed),
+        Err(OpenShareError::Cancelled(cancelled)) => Ok(TransferOutcome::Cancelled(cancelled)),
+        Err(e) => Err(e),
+    }
+}
+
+fn source_files(manifest: &Manifest, path: &Path) -> Vec<PathBuf> {
+    if !manifest.is_directory() {
+        return vec![path.to_path_buf()];
+    }
+    manifest.files.iter()
+        .filter(|e| !e.is_dir)
+        .map(|e| path.join(&e.path))
+        .collect()
+}
+
+/// A manifest being received: whole, or still arriving in pages.
+enum Listing {
+    Whole(Manifest),
+    Paged(PageAssembler),
+}
+
+impl Listing {
+    fn filename(&self) -> &str {
+        match self {
+            Listing::Whole(manifest) => &manifest.filename,
+            Listing::Paged(pages) => &pages.header().filename,
+        }
+    }
+
+    fn size(&self) -> u64 {
+        match self {
+            Listing::Whole(manifest) => manifest.size,
+            Listing::Paged(pages) => pages.header().size,
+        }
+    }
+
+    /// Chunk hashes listed so far.
+    fn chunk_hashes(&self) -> &[String] {
+        match self {
+            Listing::Whole(manifest) => &manifest.chunk_hashes,
+            Listing::Paged(pages) => pages.chunk_hashes(),
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        match self {
+            Listing::Whole(_) => true,
+            Listing::Paged(pages) => pages.is_complete(),
+        }
+    }
+}
+
+/// Refuse a push from `peer` keyed with a chunk key other than `own`.
+fn check_chunk_key(own: Option<&ChunkKey>, peer: &PeerInfo, header: &ChunkKeyHeader) -> Result<()> {
+    match own {
+        Some(key) if key.key_id() == header.key_id => Ok(()),
+        Some(_) => anyhow::bail!("{} keys chunk ids with another account secret ({})", peer.device_id, header.key_id),
+        None => anyhow::bail!("{} keys chunk ids with an account secret this device does not have ({})", peer.device_id, header.key_id),
+    }
+}
+
+/// Refuse a manifest from `peer` other than `expected_id`, if given.
+fn check_id(manifest: &Manifest, peer: &PeerInfo, expected_id: Option<&str>) -> Result<()> {
+    match expected_id {
+        Some(id) if manifest.id() != id => {
+            anyhow::bail!("{} sent manifest {} instead of {}", peer.device_id, manifest.id(), id)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Once the whole manifest is listed, check that `wanted` chunks cover
+/// every index `selection` names.
+fn check_selection(selection: ChunkSelection<'_>, wanted: usize) -> Result<()> {
+    match selection {
+        ChunkSelection::Only(indices) if indices.len() != wanted => anyhow::bail!("Requested chunk index out of range"),
+        _ => Ok(()),
+    }
+}
+
+/// Read the receiver's [`HaveChunks`] for the chunks in `range` and mark
+/// them in `skip`; returns how many it listed.
+async fn read_have<T>(session: &Session, transport: &mut T, range: std::ops::Range<usize>, skip: &mut [bool]) -> Result<usize>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let frame = session.read_encrypted_frame(transport).await?;
+    check_abort(session, &frame)?;
+    let have: HaveChunks = bincode::deserialize(&frame)?;
+    let mut listed = 0;
+    for i in have.indices {
+        let i = i as usize;
+        if !range.contains(&i) {
+            anyhow::bail!("Receiver reported an unknown chunk");
+        }
+        if !std::mem::replace(&mut skip[i], true) {
+            listed += 1;
+        }
+    }
+    Ok(listed)
+}
+
+/// Wait for the receiver's [`Verdict`] on a pushed manifest, failing with
+/// [`Rejected`] if it is a rejection.
+async fn read_verdict<T>(session: &Session, transport: &mut T, device_id: &str) -> Result<()>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let frame = session.read_encrypted_frame(transport).await?;
+    check_abort(session, &frame)?;
+    match bincode::deserialize(&frame).context("Malformed verdict")? {
+        Verdict::Accept => Ok(()),
+        Verdict::Reject(reason) => Err(Rejected { by: Some(device_id.to_string()), reason }.into()),
+    }
+}
+
+/// Read the acknowledgment of a stream chunk; all of them are stored.
+async fn read_stream_ack<T>(session: &Session, transport: &mut T) -> Result<()>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let frame = session.read_encrypted_frame(transport).await?;
+    check_abort(session, &frame)?;
+    let ack: ChunkAck = bincode::deserialize(&frame).context("Malformed acknowledgment")?;
+    if !ack.stored {
+        anyhow::bail!("Receiver did not take stream chunk {}", ack.index);
+    }
+    Ok(())
+}
+
+/// Fail with [`PeerAborted`] if `frame` is the peer's [`Abort`].
+fn check_abort(session: &Session, frame: &[u8]) -> Result<()> {
+    let Some(peer) = session.peer.as_ref().filter(|p| p.supports(FEATURE_ABORT_REASON)) else {
+        return Ok(());
+    };
+    match Abort::from_frame(frame) {
+        Some(abort) => Err(PeerAborted { device_id: peer.device_id.clone(), abort: abort? }.into()),
+        None => Ok(()),
+    }
+}
+
+/// Fill `buf` as far as possible; short only at EOF. A bare `read` may
+/// return early and would shift chunk boundaries away from the manifest.
+async fn read_full<R: AsyncRead + Unpin>(r: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = r.read(&mut buf[filled..]).await?;
+        if n == 0 { break; }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::fixture::client;
+    use crate::sim::{pair, LinkConfig};
+    use tempfile::TempDir;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_resend_skips_chunks_the_peer_has() {
+        use crate::TransferEvent;
+
+        let (src, dst) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let input = src.path().join("input.bin");
+        let mut payload: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&input, &payload).unwrap();
+
+        let receiver = client(&dst, 2);
+        let send = || {
+            let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+            let sender = client(&src, 1).with_observer(tx);
+            let (input, receiver) = (input.clone(), &receiver);
+            async move {
+                let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+                let (sent, received) = tokio::join!(
+                    sender.send_file_streaming(a, &input, false),
+                    receiver.accept_and_receive(b),
+                );
+                sent.unwrap();
+                let manifest = received.unwrap();
+                drop(sender);
+                let mut chunks = Vec::new();
+                while let Some(event) = rx.recv().await {
+                    if let TransferEvent::ChunkSent { index, .. } = event {
+                        chunks.push(index);
+                    }
+                }
+                (manifest, chunks)
+            }
+        };
+
+        let (_, chunks) = send().await;
+        assert_eq!(chunks, [0, 1, 2, 3]);
+
+        // Only the edited chunk crosses the wire, and the file still comes
+        // out whole.
+        payload[70_000] ^= 0xff;
+        std::fs::write(&input, &payload).unwrap();
+        let (manifest, chunks) = send().await;
+        assert_eq!(chunks, [1]);
+        let output = dst.path().join("out.bin");
+        receiver.write_file(&manifest, &output).await.unwrap();
+        assert_eq!(std::fs::read(output).unwrap(), payload);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_resend_moves_reorganized_files() {
+        use crate::TransferEvent;
+
+        let (src, dst, out) = (TempDir::new().unwrap(), TempDir::new().unwrap(), TempDir::new().unwrap());
+        let root = src.path().join("share");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        let big: Vec<u8> = (0..200_000u32).map(|i| (i % 241) as u8).collect();
+        std::fs::write(root.join("a.bin"), &big).unwrap();
+        std::fs::write(root.join("sub/b.txt"), b"stays put").unwrap();
+
+        let receiver = client(&dst, 2).with_output_dir(out.path());
+        let send = || {
+            let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+            let sender = client(&src, 1).with_observer(tx);
+            let (root, receiver, out) = (root.clone(), &receiver, out.path());
+            async move {
+                let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+                let (sent, received) = tokio::join!(
+                    sender.send_file_streaming(a, &root, false),
+                    receiver.accept_and_receive(b),
+                );
+                sent.unwrap();
+                let manifest = received.unwrap();
+                receiver.write_file(&manifest, &manifest.output_path(out).unwrap()).await.unwrap();
+                drop(sender);
+                let mut sent = 0;
+                while let Some(event) = rx.recv().await {
+                    if let TransferEvent::ChunkSent { .. } = event {
+                        sent += 1;
+                    }
+                }
+                sent
+            }
+        };
+        assert_eq!(send().await, 5);
+
+        // With the chunks gone from storage, only the new file is sent; the
+        // renamed one is moved on the receiving side.
+        receiver.collect_garbage().await.unwrap();
+        std::fs::create_dir_all(root.join("archive")).unwrap();
+        std::fs::rename(root.join("a.bin"), root.join("archive/a.bin")).unwrap();
+        std::fs::write(root.join("new.txt"), b"fresh").unwrap();
+        assert_eq!(send().await, 1);
+
+        let tree = out.path().join("share");
+        assert_eq!(std::fs::read(tree.join("archive/a.bin")).unwrap(), big);
+        assert!(!tree.join("a.bin").exists());
+        assert_eq!(std::fs::read(tree.join("sub/b.txt")).unwrap(), b"stays put");
+        assert_eq!(std::fs::read(tree.join("new.txt")).unwrap(), b"fresh");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pull_published_manifest() {
+        let (src, dst) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let input = src.path().join("input.bin");
+        let payload: Vec<u8> = (0..200_000u32).map(|i| (i % 253) as u8).collect();
+        std::fs::write(&input, &payload).unwrap();
+
+        let server = client(&src, 1);
+        let fetcher = client(&dst, 2);
+        let id = server.publish(&server.import_file(&input).await.unwrap()).unwrap();
+
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (fetched, served) = tokio::join!(fetcher.request_file(a, &id), server.accept(b));
+        let manifest = fetched.unwrap();
+        assert!(matches!(served.unwrap(), Accepted::Served(m) if m.id() == id));
+        assert_eq!(manifest.id(), id);
+        let output = dst.path().join("out.bin");
+        fetcher.write_file(&manifest, &output).await.unwrap();
+        assert_eq!(std::fs::read(output).unwrap(), payload);
+
+        // Unknown IDs are refused, and plain receivers refuse pulls entirely.
+        let unknown = "0".repeat(64);
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (fetched, served) = tokio::join!(fetcher.request_file(a, &unknown), server.accept(b));
+        assert!(fetched.is_err() && served.is_err());
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (fetched, received) = tokio::join!(fetcher.request_file(a, &id), server.accept_and_receive(b));
+        assert!(fetched.is_err() && received.is_err());
+
+        // Both sides record the failures next to the completed pull.
+        let fetches = fetcher.history.as_ref().unwrap().load().unwrap();
+        let served = server.history.as_ref().unwrap().load().unwrap();
+        assert_eq!(fetches.iter().map(|r| r.succeeded()).collect::<Vec<_>>(), [true, false, false]);
+        assert_eq!(served.iter().map(|r| r.succeeded()).collect::<Vec<_>>(), [true, false, false]);
+        assert_eq!(fetches[1].filename, unknown);
+        assert!(served[1].error.as_deref().unwrap().contains("unknown manifest"));
+        assert_eq!((served[0].filename.as_str(), served[2].filename.as_str()), ("input.bin", id.as_str()));
+        assert_eq!(served[2].peer_fingerprint, Some(hex::encode(fetcher.identity.public_key_bytes())));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_text_message() {
+        use crate::protocol::MAX_TEXT_LEN;
+
+        let (da, db) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let (sender, listener) = (client(&da, 1), client(&db, 2));
+
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (sent, accepted) = tokio::join!(sender.send_text(a, "https://example.com/a?b=c"), listener.accept(b));
+        sent.unwrap();
+        let Accepted::Text { device_id, text } = accepted.unwrap() else { panic!("not a text") };
+        assert_eq!((device_id.as_str(), text.as_str()), ("dev1", "https://example.com/a?b=c"));
+        assert!(listener.history.as_ref().unwrap().load().unwrap().is_empty());
+
+        // Too long for a text; refused before connecting.
+        let (a, _b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        assert!(sender.send_text(a, &"x".repeat(MAX_TEXT_LEN + 1)).await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_streaming_send() {
+        use storage::Storage;
+
+        let (src, dst) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let input = src.path().join("input.bin");
+        let payload: Vec<u8> = (0..150_000u32).map(|i| (i % 249) as u8).collect();
+        std::fs::write(&input, &payload).unwrap();
+
+        let sender = client(&src, 1);
+        let receiver = client(&dst, 2);
+        for persist in [false, true] {
+            let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+            let (sent, received) = tokio::join!(
+                sender.send_file_streaming(a, &input, persist),
+                receiver.accept_and_receive(b),
+            );
+            let (manifest, received) = (sent.unwrap(), received.unwrap());
+            assert_eq!(received.id(), manifest.id());
+            let stored = sender.storage.has_chunk(&manifest.chunk_hashes[0]).await.unwrap();
+            assert_eq!(stored, persist);
+
+            let output = dst.path().join("out.bin");
+            receiver.write_file(&received, &output).await.unwrap();
+            assert_eq!(std::fs::read(output).unwrap(), payload);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_batch_send() {
+        use crate::policy::Limits;
+
+        let (src, dst) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let paths = ["a.bin", "big.bin", "b.bin"].map(|name| src.path().join(name));
+        std::fs::write(&paths[0], vec![1u8; 70_000]).unwrap();
+        std::fs::write(&paths[1], vec![2u8; 150_000]).unwrap();
+        std::fs::write(&paths[2], vec![3u8; 20_000]).unwrap();
+
+        let sender = client(&src, 1);
+        let receiver = client(&dst, 2).with_policy(Limits { max_bytes: 100_000, ..Limits::default() });
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (sent, accepted) = tokio::join!(
+            sender.send_batch(a, paths.to_vec()),
+            receiver.accept(b),
+        );
+        // The rejected one is skipped on both sides; the rest arrive.
+        let sent = sent.unwrap();
+        let Accepted::ReceivedBatch(received) = accepted.unwrap() else { panic!("expected a batch") };
+        let names = |manifests: &[crate::Manifest]| manifests.iter().map(|m| m.filename.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&sent), ["a.bin", "b.bin"]);
+        assert_eq!(names(&received), ["a.bin", "b.bin"]);
+        for (manifest, fill) in received.iter().zip([1u8, 3]) {
+            let output = dst.path().join(&manifest.filename);
+            receiver.write_file(manifest, &output).await.unwrap();
+            assert_eq!(std::fs::read(output).unwrap(), vec![fill; manifest.size as usize]);
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! Chunk compression codecs and the heuristics for when to use them.
+//!
+//! Codecs are negotiated per transfer through handshake features: a sender
+//! only uses a codec the receiver lists (`codec-lz4`, ...), and only
+//! towards peers with `compression`, which understand
+//! [`PackedChunkFrame`](crate::protocol::PackedChunkFrame)s. Each chunk
+//! records its own codec, so the sender can skip compression for chunks
+//! that will not shrink: files whose extension marks them as already
+//! compressed, chunks whose sampled byte entropy is close to random, and
+//! any chunk whose compressed form turns out no smaller.
+//!
+//! LZ4 uses the standard block format (no frame), via the pure Rust
+//! `lz4_flex`; decoding is capped at the chunk's declared size, itself at
+//! most [`MAX_RAW_CHUNK`]. zstd, for a better ratio on text and logs,
+//! links the C library and so is only built with the `codec-zstd` cargo
+//! feature; builds without it neither offer `codec-zstd` nor accept it in
+//! their config. Peers without a codec's feature keep getting LZ4 or raw
+//! chunks.
+
+use crate::handshake::FEATURE_CODEC_LZ4;
+#[cfg(feature = "codec-zstd")]
+use crate::handshake::FEATURE_CODEC_ZSTD;
+use crate::manifest::Manifest;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Largest decompressed chunk accepted from a peer.
+pub const MAX_RAW_CHUNK: usize = 64 * 1024 * 1024;
+
+/// Bytes of each chunk sampled for the entropy check.
+const ENTROPY_SAMPLE: usize = 4096;
+
+/// zstd level for sent chunks; the library's default, fast enough to keep
+/// up with a LAN.
+#[cfg(feature = "codec-zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+/// Sampled entropy (bits per byte) above which a chunk is sent as is.
+const ENTROPY_LIMIT: f64 = 7.5;
+
+/// Extensions of formats that are compressed already.
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "aac", "apk", "avi", "avif", "br", "bz2", "docx", "flac", "gif", "gz", "heic", "jar",
+    "jpeg", "jpg", "lz4", "m4a", "m4v", "mkv", "mov", "mp3", "mp4", "odt", "ogg", "opus", "png",
+    "pptx", "rar", "tgz", "webm", "webp", "woff2", "xlsx", "xz", "zip", "zst",
+];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    /// Sent as is
+    None,
+    /// LZ4 block format; fast, modest ratio
+    #[default]
+    Lz4,
+    /// zstd frame; slower, better ratio
+    #[cfg(feature = "codec-zstd")]
+    Zstd,
+}
+
+impl Codec {
+    /// Every codec this build can decode.
+    pub const ALL: &'static [Codec] = &[
+        Codec::None,
+        Codec::Lz4,
+        #[cfg(feature = "codec-zstd")]
+        Codec::Zstd,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::None => "none",
+            Codec::Lz4 => "lz4",
+            #[cfg(feature = "codec-zstd")]
+            Codec::Zstd => "zstd",
+        }
+    }
+
+    /// Handshake feature a peer lists if it can decompress this codec.
+    pub fn feature(self) -> Option<&'static str> {
+        match self {
+            Codec::None => None,
+            Codec::Lz4 => Some(FEATURE_CODEC_LZ4),
+            #[cfg(feature = "codec-zstd")]
+            Codec::Zstd => Some(FEATURE_CODEC_ZSTD),
+        }
+    }
+
+    pub fn compress(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Codec::None => data.to_vec(),
+            Codec::Lz4 => lz4_flex::block::compress(data),
+            #[cfg(feature = "codec-zstd")]
+            Codec::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL).expect("zstd compresses any input"),
+        }
+    }
+
+    /// Decompress `data`, which must expand to exactly `raw_len` bytes.
+    pub fn decompress(self, data: Vec<u8>, raw_len: usize) -> Result<Vec<u8>> {
+        if raw_len > MAX_RAW_CHUNK {
+            anyhow::bail!("Chunk of {} bytes exceeds the {} byte limit", raw_len, MAX_RAW_CHUNK);
+        }
+        let out = match self {
+            Codec::None => data,
+            // Fails rather than writing past `raw_len`
+            Codec::Lz4 => lz4_flex::block::decompress(&data, raw_len).context("Invalid LZ4 block")?,
+            // Likewise capped at `raw_len`
+            #[cfg(feature = "codec-zstd")]
+            Codec::Zstd => zstd::bulk::decompress(&data, raw_len).context("Invalid zstd frame")?,
+        };
+        if out.len() != raw_len {
+            anyhow::bail!("Chunk decompressed to {} bytes, expected {}", out.len(), raw_len);
+        }
+        Ok(out)
+    }
+
+    /// Compress `data` if that is likely to pay off, returning the codec
+    /// actually used and the bytes to send.
+    pub fn pack(self, data: Vec<u8>, compressible: bool) -> (Codec, Vec<u8>) {
+        if self == Codec::None || !compressible || looks_random(&data) {
+            return (Codec::None, data);
+        }
+        let packed = self.compress(&data);
+        if packed.len() >= data.len() {
+            return (Codec::None, data);
+        }
+        (self, packed)
+    }
+}
+
+/// Per chunk of `manifest`, whether the file it belongs to is worth
+/// compressing judging by its extension.
+pub fn compressible_chunks(manifest: &Manifest) -> Vec<bool> {
+    if !manifest.is_directory() {
+        return vec![compressible_name(&manifest.filename); manifest.chunk_hashes.len()];
+    }
+    let mut out = vec![true; manifest.chunk_hashes.len()];
+    for entry in manifest.files.iter().filter(|e| !e.is_dir && !compressible_name(&e.path)) {
+        let end = (entry.first_chunk + entry.chunk_count).min(out.len());
+        out[entry.first_chunk.min(end)..end].fill(false);
+    }
+    out
+}
+
+fn compressible_name(name: &str) -> bool {
+    let Some((_, ext)) = name.rsplit_once('.') else { return true };
+    !COMPRESSED_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str())
+}
+
+/// Shannon entropy of a sample of `data`, compared to [`ENTROPY_LIMIT`].
+fn looks_random(data: &[u8]) -> bool {
+    let sample = &data[..data.len().min(ENTROPY_SAMPLE)];
+    if sample.is_empty() {
+        return false;
+    }
+    let mut counts = [0u32; 256];
+    for &b in sample {
+        counts[b as usize] += 1;
+    }
+    let n = sample.len() as f64;
+    let entropy: f64 = counts.iter()
+        .filter(|&&c| c > 0)
+        .map(|&c| {
+            let p = c as f64 / n;
+            -p * p.log2()
+        })
+        .sum();
+    entropy > ENTROPY_LIMIT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codecs_roundtrip_and_heuristics() -> Result<()> {
+        let text: Vec<u8> = b"the quick brown fox jumps over the lazy dog. ".iter().cycle().take(100_000).copied().collect();
+        let mut noise = vec![0u8; 50_000];
+        let mut x = 0x2545_f491u32;
+        for b in &mut noise {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            *b = x as u8;
+        }
+        for data in [&text[..], &noise, b"", b"short", &[7u8; 300]] {
+            let packed = Codec::Lz4.compress(data);
+            assert_eq!(Codec::Lz4.decompress(packed, data.len())?, data);
+        }
+        assert!(Codec::Lz4.compress(&text).len() < text.len() / 10);
+
+        // Lying about the size, or a corrupt block, fails cleanly.
+        let packed = Codec::Lz4.compress(&text);
+        assert!(Codec::Lz4.decompress(packed.clone(), text.len() - 1).is_err());
+        assert!(Codec::Lz4.decompress(packed[..packed.len() / 2].to_vec(), text.len()).is_err());
+
+        #[cfg(feature = "codec-zstd")]
+        {
+            let packed = Codec::Zstd.compress(&text);
+            assert!(packed.len() < text.len() / 10);
+            assert_eq!(Codec::Zstd.decompress(packed.clone(), text.len())?, text);
+            assert!(Codec::Zstd.decompress(packed, text.len() - 1).is_err());
+        }
+        assert_eq!(Codec::Lz4.pack(text.clone(), true).0, Codec::Lz4);
+        assert_eq!(Codec::Lz4.pack(text, false).0, Codec::None);
+        assert_eq!(Codec::Lz4.pack(noise, true).0, Codec::None);
+        assert!(compressible_name("notes.txt") && compressible_name("Makefile"));
+        assert!(!compressible_name("IMG_0001.JPG"));
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+//! Files written by earlier releases, read by this one.
+//!
+//! Each directory under `compat/` in this crate holds what an older build
+//! left in its data directory, named after the protocol version it spoke:
+//! its `config.json`, a `manifest.json` from `create-manifest`, and, from
+//! releases that had them, `trusted_peers.json` and `resume/` states. An
+//! in-place upgrade must read all of them; before changing one of these
+//! formats, add a directory written by the release before the change.
+
+use crate::config::{ClientConfig, CONFIG_VERSION};
+use crate::transfer::ResumeState;
+use crate::{Manifest, TrustStore};
+use anyhow::{Context, Result};
+use std::path::Path;
+use tempfile::TempDir;
+
+#[test]
+fn test_artifacts_of_earlier_releases() -> Result<()> {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("compat");
+    let mut releases = 0;
+    for entry in std::fs::read_dir(&root)? {
+        let dir = entry?.path();
+        check_release(&dir).with_context(|| format!("reading {}", dir.display()))?;
+        releases += 1;
+    }
+    assert!(releases >= 2, "{} releases in {}", releases, root.display());
+    Ok(())
+}
+
+fn check_release(dir: &Path) -> Result<()> {
+    // Loading migrates the config in place, so work on a copy.
+    let tmp = TempDir::new()?;
+    copy_dir(dir, tmp.path())?;
+
+    let cfg = ClientConfig::load(&tmp.path().join("config.json"))?;
+    assert_eq!((cfg.device_id.as_str(), cfg.config_version), ("laptop", CONFIG_VERSION));
+    assert_eq!(ClientConfig::load(&tmp.path().join("config.json"))?.account_hash, cfg.account_hash);
+
+    let manifest: Manifest = serde_json::from_str(&std::fs::read_to_string(tmp.path().join("manifest.json"))?)?;
+    manifest.verify()?;
+    assert_eq!((manifest.filename.as_str(), manifest.size), ("report.txt", 18));
+
+    let trust = TrustStore::load(tmp.path())?;
+    if tmp.path().join(crate::trust::TRUST_STORE_FILE).exists() {
+        let nas = trust.get("nas").context("nas is not trusted")?;
+        assert!(!nas.admin);
+    }
+    for state in ResumeState::list(tmp.path())? {
+        assert_eq!(ResumeState::load(tmp.path(), &state.manifest_id)?, Some(state.clone()));
+        assert!(state.acked.len() <= state.chunks_total);
+    }
+    Ok(())
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            std::fs::create_dir_all(&target)?;
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            std::fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
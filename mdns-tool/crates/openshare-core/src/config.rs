@@ -1,38 +1,681 @@
-use std::path::PathBuf;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use mdns_core::model::OverlayPolicy;
+use std::time::Duration;
+use crate::codec::Codec;
+use crate::encrypted::StorageEncryption;
+use crate::keyed::ChunkKey;
+use crate::index::IndexEntry;
+use crate::keys::KeyBackend;
+use crate::scheduler::QueueOrder;
+use crate::trash::Retention;
+use storage::{LocalStorage, MemoryStorage, Storage};
+#[cfg(feature = "s3")]
+use storage::{ObjectStorage, ObjectStorageConfig};
+use std::sync::Arc;
+
+/// Schema version written by this release. Bump it and append to
+/// [`MIGRATIONS`] whenever the on-disk format changes.
+pub const CONFIG_VERSION: u32 = 1;
+
+/// One step of the schema history: upgrades a config at version `from` to
+/// `from + 1`, returning a line per change for display.
+struct Migration {
+    from: u32,
+    apply: fn(&mut Map<String, Value>) -> Vec<String>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration { from: 0, apply: migrate_v0_fill_defaults },
+];
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClientConfig {
+    /// Schema version of the file this was loaded from; 0 for files
+    /// written before versioning
+    #[serde(default)]
+    pub config_version: u32,
+
     /// Directory for storing chunks, manifests, and local cache
     pub data_dir: PathBuf,
 
     /// Default chunk size for file splitting (256 KiB as per architecture)
     pub chunk_size: usize,
 
+    /// Cut multi-GB files and trees into larger chunks than `chunk_size`,
+    /// so their manifests stay short (see `Chunking::sized_for`)
+    #[serde(default)]
+    pub adaptive_chunk_size: bool,
+
+    /// Maximum concurrent CPU-bound jobs (hashing, manifest building);
+    /// 0 = one per CPU core
+    #[serde(default)]
+    pub compute_threads: usize,
+
+    /// Chunks kept in flight without an acknowledgment when sending, and
+    /// verified/stored concurrently when receiving
+    #[serde(default = "default_max_parallel_chunks")]
+    pub max_parallel_chunks: usize,
+
+    /// Manifests larger than this, encoded, are sent in pages of about
+    /// this size to peers that support it, so chunks start flowing before
+    /// the whole listing is out
+    #[serde(default = "default_manifest_page_size")]
+    pub manifest_page_size: usize,
+
+    /// Times a pushed chunk the receiver rejected is sent again before
+    /// the transfer fails (receivers with `chunk-retry`)
+    #[serde(default = "default_chunk_retries")]
+    pub chunk_retries: u32,
+
     /// Port to listen on for incoming connections
     pub listen_port: u16,
 
+    /// Addresses to listen on, each on `listen_port`: interface IPs, "::"
+    /// for all of them, IPv4 included, or "127.0.0.1" to stay local. Only
+    /// these are announced. Empty is the same as "::"
+    #[serde(default)]
+    pub listen_addresses: Vec<IpAddr>,
+
+    /// Port on 127.0.0.1 for the HTTP control API of a listener or daemon
+    /// (see the `openshare-api` crate); 0 to not serve it
+    #[serde(default)]
+    pub api_port: u16,
+
     /// mDNS service type
     pub service_type: String,
 
-    /// Account hash for discovery filtering
+    /// Additional service types to announce and browse alongside
+    /// `service_type` (e.g. "_opentab._tcp")
+    #[serde(default)]
+    pub extra_service_types: Vec<String>,
+
+    /// Account hash for discovery filtering; see [`account_hash`]
     pub account_hash: String,
 
     /// Device ID
     pub device_id: String,
+
+    /// Name of the profile this config belongs to, if it is not the
+    /// default one; see [`crate::profiles`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+
+    /// Accept and pin unknown peers on first contact instead of refusing
+    /// them; peers whose key changed are always refused
+    #[serde(default)]
+    pub trust_on_first_use: bool,
+
+    /// Whether VPN/overlay interfaces are preferred, allowed, or excluded
+    /// when announcing and dialing
+    #[serde(default)]
+    pub overlay_policy: OverlayPolicy,
+
+    /// Lifetime of session resumption tickets issued to senders;
+    /// 0 disables issuing them
+    #[serde(default = "default_ticket_lifetime")]
+    pub ticket_lifetime_secs: u64,
+
+    /// How far a peer's clock may be off from ours, in seconds, before it
+    /// is reported (in the log, and by `openshare capabilities --peer`)
+    #[serde(default = "default_clock_skew_tolerance")]
+    pub clock_skew_tolerance_secs: u64,
+
+    /// Older protocol version to speak, offering and using only its
+    /// features, while some devices still run the release that had it.
+    /// Set for one run with `--compat`; never saved
+    #[serde(skip)]
+    pub compat_version: Option<u16>,
+
+    /// Keep a local log of completed transfers for `openshare stats`
+    #[serde(default = "default_true")]
+    pub record_history: bool,
+
+    /// How often the daemon saves a snapshot of its transfer metrics for
+    /// `openshare stats --since`, in seconds; 0 disables them
+    #[serde(default = "default_metrics_snapshot")]
+    pub metrics_snapshot_secs: u64,
+
+    /// Jobs the daemon runs at once; 0 for no limit
+    #[serde(default = "default_max_concurrent_jobs")]
+    pub max_concurrent_jobs: usize,
+
+    /// Jobs the daemon runs at once for any one peer; 0 for no limit
+    #[serde(default = "default_max_jobs_per_peer")]
+    pub max_jobs_per_peer: usize,
+
+    /// Which of the daemon's queued jobs of the same priority start first;
+    /// see [`crate::scheduler`]
+    #[serde(default)]
+    pub queue_order: QueueOrder,
+
+    /// How `openshare daemon install` has the system run the daemon; see
+    /// [`ServiceConfig`]
+    #[serde(default)]
+    pub service: ServiceConfig,
+
+    /// Days to keep files replaced by incoming transfers in the output
+    /// directory's trash; 0 overwrites them without a backup
+    #[serde(default = "default_trash_retention_days")]
+    pub trash_retention_days: u64,
+
+    /// Size limit of each trash; the oldest entries are purged beyond it
+    #[serde(default = "default_trash_max_bytes")]
+    pub trash_max_bytes: u64,
+
+    /// Where `listen` and `daemon` write received files when no `--output`
+    /// is given; `listen` falls back to the working directory and `daemon`
+    /// to `received/` in the data directory
+    #[serde(default)]
+    pub output_dir: Option<PathBuf>,
+
+    /// Write a `<file>.oshare.json` checksum sidecar next to received files
+    #[serde(default)]
+    pub write_sidecars: bool,
+
+    /// Copy texts sent by peers to the system clipboard as well as
+    /// printing them (CLI builds with the `clipboard` feature)
+    #[serde(default)]
+    pub copy_received_text: bool,
+
+    /// Reserve the full size of an incoming file in the output directory
+    /// before its chunks arrive, so that writing it out cannot run out of
+    /// space halfway (see [`crate::space`])
+    #[serde(default)]
+    pub preallocate_files: bool,
+
+    /// Give received files the modification times and permissions recorded
+    /// in their manifest, and create the symlinks of received trees
+    #[serde(default = "default_true")]
+    pub preserve_metadata: bool,
+
+    /// Share the list of published files with trusted devices on the
+    /// same account (`openshare find` on those devices)
+    #[serde(default)]
+    pub share_index: bool,
+
+    /// Where the identity key is kept: "file" (identity.key) or
+    /// "keystore" (the OS keystore)
+    #[serde(default)]
+    pub key_backend: KeyBackend,
+
+    /// Size limit of the chunk store in bytes; least recently used chunks
+    /// not needed by published files are evicted beyond it. 0 = no limit
+    #[serde(default)]
+    pub storage_quota_bytes: u64,
+
+    /// Codec for sent chunks when the peer supports it: "lz4", "none", or
+    /// "zstd" in builds with the `codec-zstd` feature. Already-compressed
+    /// files and incompressible chunks are always sent as is
+    #[serde(default)]
+    pub compression: Codec,
+
+    /// Encryption of stored chunks: "off", "identity" (key derived from
+    /// the device identity) or "key_file" (random key in storage.key).
+    /// Chunks stored before a change cannot be read after it
+    #[serde(default)]
+    pub storage_encryption: StorageEncryption,
+
+    /// Hex secret shared by the devices of the account; when set, chunk
+    /// ids are keyed with it so the chunk store does not reveal which
+    /// files it holds (see [`crate::keyed`]). Only devices holding the
+    /// same key can be sent to
+    #[serde(default)]
+    pub chunk_key: Option<ChunkKey>,
+
+    /// Where chunks are kept; see [`StorageConfig`]
+    #[serde(default)]
+    pub storage: StorageConfig,
+
+    /// Accept announcements of shares newly published by trusted devices
+    /// on the same account, and pass them on as notifications
+    #[serde(default = "default_true")]
+    pub accept_announcements: bool,
+
+    /// Announced shares to fetch automatically; see [`Subscription`]
+    #[serde(default)]
+    pub subscriptions: Vec<Subscription>,
+
+    /// Refuse pushed shares larger than this; 0 for no limit
+    #[serde(default)]
+    pub max_incoming_bytes: u64,
+
+    /// File extensions refused in pushed shares (e.g. "exe"), without the
+    /// dot
+    #[serde(default)]
+    pub blocked_file_types: Vec<String>,
+
+    /// Refuse pushes from peers only trusted on first use, until they are
+    /// paired or added with `openshare trust add`
+    #[serde(default)]
+    pub verified_peers_only: bool,
+
+    /// Hex public key of the account root. When set, this device sends
+    /// its certificate and only accepts peers certified by the same root;
+    /// see [`crate::account`]
+    #[serde(default)]
+    pub account_root: Option<String>,
+
+    /// Attach a thumbnail to single images sent, for the receiver to show
+    /// before accepting. Images are decoded in a child process held to
+    /// the limits below, run from this executable, which must call
+    /// [`crate::preview::run_child`] first (see [`crate::preview`]).
+    /// Builds without the `previews` feature send none
+    #[serde(default)]
+    pub previews: bool,
+
+    /// Seconds a preview may take before its child process is killed
+    #[serde(default = "default_preview_timeout")]
+    pub preview_timeout_secs: u64,
+
+    /// Address space a preview's child process may use, in bytes
+    #[serde(default = "default_preview_memory")]
+    pub preview_memory_bytes: u64,
+}
+
+/// A rule for fetching announced shares automatically.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Subscription {
+    /// Only shares published by this device ID
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer: Option<String>,
+    /// Share or file name (or part of it), manifest ID or content hash,
+    /// matched like `openshare find`. With `*` or `?` it is a glob that has
+    /// to match the whole share name, or `<share>/<path>` of a file in it
+    pub query: String,
+    /// Skip shares larger than this; 0 for no limit
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub max_size: u64,
+    /// Only fetch within this time of day; matching shares announced
+    /// outside it wait until it opens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window: Option<TimeWindow>,
+}
+
+impl Subscription {
+    /// Parse `peer:pattern`, or a bare pattern for shares of any peer.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let (peer, query) = match spec.split_once(':') {
+            Some(("" | "*", query)) => (None, query),
+            Some((peer, query)) => (Some(peer.to_string()), query),
+            None => (None, spec),
+        };
+        anyhow::ensure!(!query.is_empty(), "Empty pattern in subscription {:?}", spec);
+        Ok(Self { peer, query: query.to_string(), ..Default::default() })
+    }
+
+    /// Whether a share announced by `device_id` should be fetched, at
+    /// whatever time [`Self::window`] allows.
+    pub fn matches(&self, device_id: &str, entry: &IndexEntry) -> bool {
+        if self.peer.as_ref().is_some_and(|p| p != device_id) {
+            return false;
+        }
+        if self.max_size > 0 && entry.size > self.max_size {
+            return false;
+        }
+        if !self.query.contains(['*', '?']) {
+            return entry.matches(&self.query);
+        }
+        glob_match(&self.query, &entry.filename)
+            || entry.files.iter().any(|f| glob_match(&self.query, &format!("{}/{}", entry.filename, f)))
+    }
+}
+
+impl std::fmt::Display for Subscription {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.peer.as_deref().unwrap_or("*"), self.query)
+    }
+}
+
+/// Case-insensitive glob: `*` matches any run of characters (including
+/// `/`), `?` any one character.
+pub(crate) fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position after the last `*` and the text position it was tried at
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((after, tried)) => {
+                    p = after;
+                    t = tried + 1;
+                    star = Some((after, tried + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// A daily time range in UTC, written `HH:MM-HH:MM`. It wraps past
+/// midnight when the end is before the start (`22:00-06:00`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeWindow {
+    /// Minutes after midnight
+    pub start: u32,
+    pub end: u32,
+}
+
+impl TimeWindow {
+    const DAY: u64 = 24 * 60 * 60;
+
+    /// Seconds from `now` (Unix time) until the window is open; 0 if it
+    /// is open now.
+    pub fn wait_secs(&self, now: u64) -> u64 {
+        let second = now % Self::DAY;
+        let (start, end) = (self.start as u64 * 60, self.end as u64 * 60);
+        let open = if start <= end {
+            (start..end).contains(&second)
+        } else {
+            second >= start || second < end
+        };
+        if open {
+            0
+        } else {
+            (start + Self::DAY - second) % Self::DAY
+        }
+    }
+}
+
+impl std::str::FromStr for TimeWindow {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let minutes = |t: &str| -> Option<u32> {
+            let (h, m) = t.trim().split_once(':')?;
+            let (h, m) = (h.parse::<u32>().ok()?, m.parse::<u32>().ok()?);
+            (h < 24 && m < 60).then_some(h * 60 + m)
+        };
+        let window = s
+            .split_once('-')
+            .and_then(|(start, end)| Some(Self { start: minutes(start)?, end: minutes(end)? }))
+            .with_context(|| format!("Invalid time window {:?}, expected HH:MM-HH:MM", s))?;
+        anyhow::ensure!(window.start != window.end, "Empty time window {:?}", s);
+        Ok(window)
+    }
+}
+
+impl TryFrom<String> for TimeWindow {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<TimeWindow> for String {
+    fn from(w: TimeWindow) -> String {
+        w.to_string()
+    }
+}
+
+impl std::fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:02}:{:02}-{:02}:{:02}", self.start / 60, self.start % 60, self.end / 60, self.end % 60)
+    }
+}
+
+/// Chunk store backend, selected by `"backend"`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum StorageConfig {
+    /// `chunks/` in the data directory
+    #[default]
+    Local,
+    /// In memory only, lost when the process exits; for relays and tests
+    Memory,
+    /// An S3-compatible bucket; needs the `s3` feature
+    S3 {
+        /// `https://host[:port]` (or `http://` for a local test server)
+        endpoint: String,
+        bucket: String,
+        #[serde(default = "default_s3_region")]
+        region: String,
+        /// Prepended to every object key, so several devices can share a bucket
+        #[serde(default)]
+        prefix: String,
+        /// Credentials; taken from `AWS_ACCESS_KEY_ID` and
+        /// `AWS_SECRET_ACCESS_KEY` when not set here
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        access_key_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        secret_access_key: Option<String>,
+        /// PEM bundle to verify the endpoint with instead of the system one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ca_file: Option<PathBuf>,
+    },
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
+}
+
+impl StorageConfig {
+    /// Open the configured store for the device with data directory `data_dir`.
+    pub fn open(&self, data_dir: &Path) -> Result<Arc<dyn Storage>> {
+        Ok(match self {
+            StorageConfig::Local => Arc::new(LocalStorage::new(data_dir.to_path_buf())?),
+            StorageConfig::Memory => Arc::new(MemoryStorage::new()),
+            #[cfg(feature = "s3")]
+            StorageConfig::S3 { endpoint, bucket, region, prefix, access_key_id, secret_access_key, ca_file } => {
+                let credential = |value: &Option<String>, var: &str| {
+                    value.clone().or_else(|| std::env::var(var).ok())
+                        .with_context(|| format!("No S3 credentials: set them in the config or {}", var))
+                };
+                Arc::new(ObjectStorage::new(ObjectStorageConfig {
+                    endpoint: endpoint.trim_end_matches('/').to_string(),
+                    bucket: bucket.clone(),
+                    region: region.clone(),
+                    prefix: prefix.clone(),
+                    access_key_id: credential(access_key_id, "AWS_ACCESS_KEY_ID")?,
+                    secret_access_key: credential(secret_access_key, "AWS_SECRET_ACCESS_KEY")?,
+                    ca_file: ca_file.clone(),
+                })?)
+            }
+            #[cfg(not(feature = "s3"))]
+            StorageConfig::S3 { .. } => anyhow::bail!("Built without the s3 feature, so chunks cannot be kept in S3"),
+        })
+    }
+}
+
+/// Restarts and logging of the daemon when it runs as a system service.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServiceConfig {
+    /// When the service manager starts the daemon again after it exits
+    #[serde(default)]
+    pub restart: RestartPolicy,
+    /// Seconds to wait before restarting it
+    #[serde(default = "default_restart_delay")]
+    pub restart_delay_secs: u64,
+    /// Log file of the service [default: daemon.log in the data directory]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_file: Option<PathBuf>,
+    /// Start a new log file when the current one reaches this size, in
+    /// bytes; 0 lets it grow
+    #[serde(default = "default_log_max_bytes")]
+    pub log_max_bytes: u64,
+    /// Earlier log files kept, as `daemon.log.1` (the newest) and up
+    #[serde(default = "default_log_files_kept")]
+    pub log_files_kept: u32,
+}
+
+impl Default for ServiceConfig {
+    fn default() -> Self {
+        Self {
+            restart: RestartPolicy::default(),
+            restart_delay_secs: default_restart_delay(),
+            log_file: None,
+            log_max_bytes: default_log_max_bytes(),
+            log_files_kept: default_log_files_kept(),
+        }
+    }
+}
+
+impl ServiceConfig {
+    /// The log file of the service of the device with data directory
+    /// `data_dir`.
+    pub fn log_path(&self, data_dir: &Path) -> PathBuf {
+        self.log_file.clone().unwrap_or_else(|| data_dir.join(crate::service::LOG_FILE))
+    }
+}
+
+/// When a service manager restarts the daemon.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RestartPolicy {
+    /// Whenever it exits, even cleanly, unless stopped through the
+    /// service manager
+    Always,
+    /// When it fails or crashes, not when it is stopped
+    #[default]
+    OnFailure,
+    Never,
+}
+
+fn default_restart_delay() -> u64 {
+    5
+}
+
+fn default_log_max_bytes() -> u64 {
+    10 * 1024 * 1024 // 10 MiB
+}
+
+fn default_log_files_kept() -> u32 {
+    5
+}
+
+fn default_max_parallel_chunks() -> usize {
+    8
+}
+
+fn default_manifest_page_size() -> usize {
+    1024 * 1024 // 1 MiB
+}
+
+/// Whether a socket bound to `bound` accepts connections to `ip`: "::"
+/// takes IPv4 too, as `listen` sets it up.
+fn covers(bound: IpAddr, ip: IpAddr) -> bool {
+    bound.is_unspecified() && (bound.is_ipv6() || ip.is_ipv4())
+}
+
+fn default_chunk_retries() -> u32 {
+    3
+}
+
+fn default_ticket_lifetime() -> u64 {
+    3600
+}
+
+fn default_clock_skew_tolerance() -> u64 {
+    120
+}
+
+fn default_metrics_snapshot() -> u64 {
+    3600
+}
+
+fn default_max_concurrent_jobs() -> usize {
+    3
+}
+
+fn default_max_jobs_per_peer() -> usize {
+    1
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+fn default_trash_retention_days() -> u64 {
+    30
+}
+
+fn default_trash_max_bytes() -> u64 {
+    1024 * 1024 * 1024 // 1 GiB
+}
+
+fn default_preview_timeout() -> u64 {
+    5
+}
+
+fn default_preview_memory() -> u64 {
+    512 * 1024 * 1024 // 512 MiB
 }
 
 impl Default for ClientConfig {
     fn default() -> Self {
         Self {
+            config_version: CONFIG_VERSION,
             data_dir: std::env::current_dir()
                 .unwrap_or_else(|_| PathBuf::from("."))
                 .join(".openshare"),
             chunk_size: 256 * 1024, // 256 KiB
+            adaptive_chunk_size: false,
+            compute_threads: 0,
+            max_parallel_chunks: default_max_parallel_chunks(),
+            manifest_page_size: default_manifest_page_size(),
+            chunk_retries: default_chunk_retries(),
             listen_port: 9876,
+            listen_addresses: Vec::new(),
+            api_port: 0,
             service_type: "_openshare._tcp.local.".to_string(),
+            extra_service_types: Vec::new(),
             account_hash: "".to_string(),
             device_id: "".to_string(),
+            profile: None,
+            trust_on_first_use: false,
+            overlay_policy: OverlayPolicy::default(),
+            ticket_lifetime_secs: default_ticket_lifetime(),
+            clock_skew_tolerance_secs: default_clock_skew_tolerance(),
+            compat_version: None,
+            record_history: true,
+            metrics_snapshot_secs: default_metrics_snapshot(),
+            max_concurrent_jobs: default_max_concurrent_jobs(),
+            max_jobs_per_peer: default_max_jobs_per_peer(),
+            queue_order: QueueOrder::default(),
+            service: ServiceConfig::default(),
+            trash_retention_days: default_trash_retention_days(),
+            trash_max_bytes: default_trash_max_bytes(),
+            output_dir: None,
+            write_sidecars: false,
+            copy_received_text: false,
+            preallocate_files: false,
+            preserve_metadata: true,
+            share_index: false,
+            key_backend: KeyBackend::File,
+            storage_quota_bytes: 0,
+            compression: Codec::default(),
+            storage_encryption: StorageEncryption::Off,
+            chunk_key: None,
+            storage: StorageConfig::Local,
+            accept_announcements: true,
+            subscriptions: Vec::new(),
+            max_incoming_bytes: 0,
+            blocked_file_types: Vec::new(),
+            verified_peers_only: false,
+            account_root: None,
+            previews: false,
+            preview_timeout_secs: default_preview_timeout(),
+            preview_memory_bytes: default_preview_memory(),
         }
     }
 }
@@ -44,10 +687,252 @@ impl ClientConfig {
         self
     }
 
+    /// Defaults for profile `name`, kept in `data_dir`: the first listen
+    /// port from the usual one up that is not in `taken` (the ports of
+    /// the other profiles), so that one daemon can serve them all.
+    pub fn for_profile(name: &str, data_dir: PathBuf, taken: &[u16]) -> Self {
+        let mut cfg = Self {
+            data_dir,
+            profile: (name != crate::profiles::DEFAULT_PROFILE).then(|| name.to_string()),
+            ..Self::default()
+        };
+        while taken.contains(&cfg.listen_port) {
+            cfg.listen_port += 1;
+        }
+        cfg
+    }
+
+    /// The sockets to accept connections on. Addresses a wildcard in the
+    /// list already covers are left out, as binding both would clash.
+    pub fn listen_endpoints(&self) -> Vec<SocketAddr> {
+        if self.listen_addresses.is_empty() {
+            return vec![SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), self.listen_port)];
+        }
+        let mut endpoints: Vec<SocketAddr> = Vec::new();
+        for &ip in &self.listen_addresses {
+            let covered = self.listen_addresses.iter().any(|&bound| bound != ip && covers(bound, ip));
+            let endpoint = SocketAddr::new(ip, self.listen_port);
+            if !covered && !endpoints.contains(&endpoint) {
+                endpoints.push(endpoint);
+            }
+        }
+        endpoints
+    }
+
+    /// Whether connections to `ip` reach a listener, so that it is worth
+    /// announcing. Anything is, unless listen addresses are configured.
+    pub fn listens_on(&self, ip: IpAddr) -> bool {
+        self.listen_addresses.is_empty() || self.listen_addresses.iter().any(|&bound| bound == ip || covers(bound, ip))
+    }
+
+    /// Trash retention for replaced files, `None` if the trash is off.
+    pub fn trash_retention(&self) -> Option<Retention> {
+        (self.trash_retention_days > 0).then(|| Retention {
+            max_age: Duration::from_secs(self.trash_retention_days * 24 * 60 * 60),
+            max_bytes: self.trash_max_bytes,
+        })
+    }
+
+    /// Extra service types from the config plus `more`, without duplicates.
+    pub fn additional_service_types(&self, more: &[String]) -> Vec<String> {
+        let mut out: Vec<String> = Vec::new();
+        for ty in self.extra_service_types.iter().chain(more) {
+            if ty != &self.service_type && !out.contains(ty) {
+                out.push(ty.clone());
+            }
+        }
+        out
+    }
+
+    /// Load a config file, migrating it to [`CONFIG_VERSION`] first. The
+    /// previous file is kept as a backup when anything changed.
+    pub fn load(path: &Path) -> Result<Self> {
+        let (report, value) = migrate(path, false)?;
+        if let Some(backup) = &report.backup {
+            tracing::info!(
+                "Migrated {} from version {} to {} (backup: {})",
+                path.display(), report.from_version, report.to_version, backup.display()
+            );
+        }
+        serde_json::from_value(value).with_context(|| format!("Invalid config {}", path.display()))
+    }
+
+    /// Write the config as pretty JSON, replacing `path` atomically and
+    /// keeping the previous version (see [`crate::state`]).
+    pub fn save(&self, path: &Path) -> Result<()> {
+        crate::state::write(path, serde_json::to_string_pretty(self)?.as_bytes())
+    }
+
     pub fn ensure_data_dir(&self) -> anyhow::Result<()> {
         std::fs::create_dir_all(&self.data_dir)?;
         std::fs::create_dir_all(self.data_dir.join("chunks"))?;
-        std::fs::create_dir_all(self.data_dir.join("manifests"))?;
+        std::fs::create_dir_all(self.data_dir.join(crate::manifests::MANIFESTS_DIR))?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Outcome of [`migrate_file`].
+#[derive(Debug, Clone)]
+pub struct MigrationReport {
+    pub from_version: u32,
+    pub to_version: u32,
+    /// Human-readable description of every change, in order
+    pub changes: Vec<String>,
+    /// Copy of the original file, if it was rewritten
+    pub backup: Option<PathBuf>,
+}
+
+/// The account hash devices of `account` advertise and match each other
+/// by: the first 8 bytes of its SHA-256, in hex.
+pub fn account_hash(account: &str) -> String {
+    use sha2::{Digest, Sha256};
+    hex::encode(&Sha256::digest(account.as_bytes())[..8])
+}
+
+/// Migrate the config file at `path` to [`CONFIG_VERSION`]. With `dry_run`
+/// the changes are only reported and nothing is written.
+pub fn migrate_file(path: &Path, dry_run: bool) -> Result<MigrationReport> {
+    migrate(path, dry_run).map(|(report, _)| report)
+}
+
+fn migrate(path: &Path, dry_run: bool) -> Result<(MigrationReport, Value)> {
+    let mut value: Value = crate::state::read(path, |json| Ok(serde_json::from_slice(json)?))?
+        .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound))
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let map = value.as_object_mut()
+        .ok_or_else(|| anyhow::anyhow!("Config {} is not a JSON object", path.display()))?;
+
+    let from_version = match map.get("config_version") {
+        None => 0,
+        Some(v) => v.as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or_else(|| anyhow::anyhow!("Invalid config_version {}", v))?,
+    };
+    if from_version > CONFIG_VERSION {
+        anyhow::bail!(
+            "{} is config version {}, newer than this release supports ({}); upgrade OpenShare",
+            path.display(), from_version, CONFIG_VERSION
+        );
+    }
+
+    let mut changes = Vec::new();
+    for step in MIGRATIONS.iter().filter(|m| m.from >= from_version) {
+        changes.extend((step.apply)(map));
+        map.insert("config_version".into(), (step.from + 1).into());
+    }
+    let mut report = MigrationReport { from_version, to_version: CONFIG_VERSION, changes, backup: None };
+    if dry_run || from_version == CONFIG_VERSION {
+        return Ok((report, value));
+    }
+
+    let mut backup = path.as_os_str().to_os_string();
+    backup.push(format!(".v{}.bak", from_version));
+    let backup = PathBuf::from(backup);
+    std::fs::copy(path, &backup)
+        .with_context(|| format!("Failed to back up {}", path.display()))?;
+    crate::state::write(path, serde_json::to_string_pretty(&value)?.as_bytes())?;
+    report.backup = Some(backup);
+    Ok((report, value))
+}
+
+/// Version 0 files relied on serde defaults for settings added after the
+/// first release; write them out so the file shows what is in effect.
+fn migrate_v0_fill_defaults(map: &mut Map<String, Value>) -> Vec<String> {
+    let Ok(Value::Object(defaults)) = serde_json::to_value(ClientConfig::default()) else {
+        unreachable!("ClientConfig serializes to an object");
+    };
+    let mut changes = Vec::new();
+    for (key, value) in defaults {
+        if key != "config_version" && !map.contains_key(&key) {
+            changes.push(format!("add {} = {}", key, value));
+            map.insert(key, value);
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_migrate_v0_config() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let path = tmp.path().join("config.json");
+        let v0 = r#"{"data_dir": "/data", "chunk_size": 1024, "listen_port": 9000,
+            "service_type": "_openshare._tcp.local.", "account_hash": "ab", "device_id": "laptop"}"#;
+        std::fs::write(&path, v0)?;
+
+        let preview = migrate_file(&path, true)?;
+        assert_eq!((preview.from_version, preview.to_version), (0, CONFIG_VERSION));
+        assert!(preview.changes.iter().any(|c| c.starts_with("add ticket_lifetime_secs")));
+        assert_eq!(std::fs::read_to_string(&path)?, v0);
+
+        let cfg = ClientConfig::load(&path)?;
+        assert_eq!((cfg.config_version, cfg.chunk_size, cfg.max_parallel_chunks), (CONFIG_VERSION, 1024, 8));
+        assert_eq!(std::fs::read_to_string(tmp.path().join("config.json.v0.bak"))?, v0);
+        assert!(migrate_file(&path, false)?.changes.is_empty());
+
+        std::fs::write(&path, format!(r#"{{"config_version": {}}}"#, CONFIG_VERSION + 1))?;
+        assert!(ClientConfig::load(&path).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_subscription_patterns() -> Result<()> {
+        let entry = |filename: &str, size, files: &[&str]| IndexEntry {
+            filename: filename.into(),
+            size,
+            manifest_id: "m".into(),
+            file_hash: String::new(),
+            files: files.iter().map(|f| f.to_string()).collect(),
+        };
+        let backups = entry("Backups", 5000, &["2024/db.tar", "notes.txt"]);
+
+        let sub = Subscription::parse("nas:backups/*")?;
+        assert_eq!((sub.peer.as_deref(), sub.query.as_str()), (Some("nas"), "backups/*"));
+        assert!(sub.matches("nas", &backups));
+        assert!(!sub.matches("laptop", &backups));
+        assert!(!sub.matches("nas", &entry("backups-old", 10, &["a"])));
+        assert!(Subscription::parse("*:*.TAR")?.matches("nas", &entry("db.tar", 10, &[])));
+        assert!(Subscription::parse("b?ckups/*/db.*")?.matches("x", &backups));
+        assert!(!Subscription::parse("backups")?.matches("x", &entry("db.tar", 10, &[])));
+        assert!(Subscription::parse("nas:").is_err());
+
+        let sub = Subscription { max_size: 4999, ..Subscription::parse("ackup")? };
+        assert!(!sub.matches("nas", &backups));
+
+        let window: TimeWindow = "22:00-06:30".parse()?;
+        assert_eq!(window.to_string(), "22:00-06:30");
+        assert_eq!(window.wait_secs(23 * 3600), 0);
+        assert_eq!(window.wait_secs(86400 + 6 * 3600), 0);
+        assert_eq!(window.wait_secs(12 * 3600), 10 * 3600);
+        assert_eq!("01:00-02:00".parse::<TimeWindow>()?.wait_secs(2 * 3600), 23 * 3600);
+        assert!("25:00-01:00".parse::<TimeWindow>().is_err());
+        assert!("01:00".parse::<TimeWindow>().is_err());
+
+        let json = serde_json::to_string(&Subscription { window: Some(window), ..sub.clone() })?;
+        assert_eq!(json, r#"{"query":"ackup","max_size":4999,"window":"22:00-06:30"}"#);
+        assert_eq!(serde_json::from_str::<Subscription>(&json)?.window, Some(window));
+        Ok(())
+    }
+
+    #[test]
+    fn test_listen_addresses() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let mut cfg = ClientConfig { listen_port: 9000, ..ClientConfig::default() };
+        assert_eq!(cfg.listen_endpoints(), vec!["[::]:9000".parse().unwrap()]);
+        assert!(cfg.listens_on(ip("fe80::1")));
+
+        cfg.listen_addresses = vec![ip("192.168.1.5"), ip("0.0.0.0")];
+        assert!(cfg.listens_on(ip("10.0.0.2")));
+        assert!(!cfg.listens_on(ip("fe80::1")));
+        cfg.listen_addresses = vec![ip("192.168.1.5"), ip("fd00::5")];
+        assert_eq!(cfg.listen_endpoints()[1], "[fd00::5]:9000".parse().unwrap());
+        assert!(cfg.listens_on(ip("fd00::5")) && !cfg.listens_on(ip("10.0.0.2")));
+        cfg.listen_addresses = vec![ip("127.0.0.1"), ip("0.0.0.0"), ip("::")];
+        assert!(cfg.listens_on(ip("10.0.0.2")) && cfg.listens_on(ip("fe80::1")));
+        assert_eq!(cfg.listen_endpoints(), vec!["[::]:9000".parse().unwrap()]);
+    }
+}
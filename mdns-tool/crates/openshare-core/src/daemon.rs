@@ -0,0 +1,1197 @@
+//! Orchestration for a long-running `openshare daemon`.
+//!
+//! A [`Daemon`] works through a queue of sends and of fetches for
+//! [subscriptions](crate::config::Subscription), several at a time as its
+//! [scheduler](crate::scheduler) allows, keeps the device announced
+//! and the peer registry fresh with [`run_discovery`](Daemon::run_discovery),
+//! saves [metrics snapshots](crate::metrics) with
+//! [`run_metrics`](Daemon::run_metrics), and answers other local processes
+//! over a control API. Accepting incoming
+//! transfers stays with the caller, which owns the listening sockets and
+//! passes announced shares to [`offer`](Daemon::offer).
+//!
+//! The control API is JSON-RPC 2.0, one message per line, over any byte
+//! stream the caller accepts (a Unix socket, a named pipe):
+//!
+//! - `peers`: the peers found by discovery
+//! - `send` with `{"path", "peer"}` and optionally `"priority"`: queue a
+//!   file or directory for a peer, given as a discovered device ID or
+//!   `host:port`; returns `{"job": id}`. A discovered device must present
+//!   the key fingerprint it advertised
+//! - `jobs`: queued, running and recently finished sends and fetches
+//! - `pause`, `resume` and `cancel` with `{"job": id}`: hold, continue or
+//!   stop a send through its [`TransferHandle`]; a queued job of either
+//!   kind can also be cancelled. Each returns the [`Job`]
+//! - `prioritize` with `{"job": id, "priority"}`: move a queued job ahead
+//!   of or behind others; returns the [`Job`]
+//! - `scheduler`: the [`SchedulerLimits`]; given any of
+//!   `max_concurrent_jobs`, `max_jobs_per_peer` and `queue_order`, first
+//!   applies them and saves them to the config file, if there is one
+//! - `watch` with an optional [`NotificationFilter`]: from then on the
+//!   connection also receives a `transfer` notification carrying a
+//!   [`TransferRecord`](crate::history::TransferRecord) for each finished
+//!   transfer that matches, and a `job` notification carrying a [`Job`]
+//!   whenever a job changes state or makes progress
+//!
+//! A first line without a `method` is taken as a bare filter, as written
+//! by earlier versions of `openshare watch`, and is answered with bare
+//! transfer records.
+//!
+//! The `openshare-api` crate serves the same over HTTP on localhost, for
+//! programs that cannot open a Unix socket.
+//!
+//! A daemon is also an [`AdminHandler`]: given to a listening client with
+//! [`Client::with_admin`], it runs the methods above that
+//! [`crate::admin`] allows for devices trusted as admins, plus `gc`, which
+//! collects garbage in storage, and `limits`, which returns the limits on
+//! incoming pushes and, given any of `max_incoming_bytes`,
+//! `blocked_file_types` and `verified_peers_only`, first saves them to the
+//! config file.
+
+use crate::admin::AdminHandler;
+use crate::client::Client;
+use crate::config::{ClientConfig, Subscription};
+use crate::discovery::{Discovery, Peer};
+use crate::events::TransferEvent;
+use crate::index::IndexEntry;
+use crate::metrics::{MetricsLog, MetricsSnapshot};
+use crate::notify::{NotificationFilter, NotificationHub};
+use crate::policy::Limits;
+use crate::registry::PeerEvent;
+use crate::scheduler::{Priority, Scheduler, SchedulerLimits};
+use crate::transfer::{TransferHandle, TransferId, TransferOutcome};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use mdns_core::net::InterfaceWatcher;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use storage::Storage;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::{broadcast, mpsc, Notify};
+use tracing::Instrument;
+
+/// How long each browse listens for answers.
+pub const BROWSE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Pause between browses.
+pub const BROWSE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Peers that missed three browses in a row are dropped.
+pub const PEER_EXPIRY: Duration = Duration::from_secs(3 * BROWSE_INTERVAL.as_secs() + BROWSE_TIMEOUT.as_secs());
+
+/// Finished jobs kept for `jobs`; older ones are forgotten.
+const FINISHED_JOBS_KEPT: usize = 100;
+
+// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+/// Opens connections for queued jobs.
+#[async_trait]
+pub trait Connector: Send + Sync {
+    type Transport: AsyncRead + AsyncWrite + Unpin + Send;
+
+    /// Connect to `address`, a `host:port`.
+    async fn connect(&self, address: &str) -> Result<Self::Transport>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Paused,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl JobState {
+    fn is_finished(self) -> bool {
+        matches!(self, JobState::Done | JobState::Failed | JobState::Cancelled)
+    }
+}
+
+/// What a [`Job`] does, tagged by `"kind"`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Task {
+    /// Send a local file or directory
+    Send { path: PathBuf },
+    /// Fetch an announced share into the client's output directory
+    Fetch { manifest_id: String, filename: String, size: u64 },
+}
+
+/// A queued send or fetch and how far it got.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Job {
+    pub id: u64,
+    #[serde(flatten)]
+    pub task: Task,
+    /// Device ID or `host:port`, as given; for a fetch, where the
+    /// publisher serves pulls
+    pub peer: String,
+    /// Decides, with the [scheduler](crate::scheduler), when it starts
+    #[serde(default)]
+    pub priority: Priority,
+    pub state: JobState,
+    /// Shared with the peer, once the job has connected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transfer_id: Option<TransferId>,
+    pub chunks_done: usize,
+    /// Chunks the receiving side did not already have; 0 until known
+    pub chunks_total: usize,
+    /// Unix time before which the job stays queued, for a fetch announced
+    /// outside its subscription's window
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_before: Option<u64>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl std::fmt::Display for Job {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.task {
+            Task::Send { path } => write!(f, "send {} to {}", path.display(), self.peer),
+            Task::Fetch { filename, .. } => write!(f, "fetch {} from {}", filename, self.peer),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Jobs {
+    next_id: u64,
+    all: BTreeMap<u64, Job>,
+    /// Control the running jobs, by id
+    handles: HashMap<u64, TransferHandle>,
+    scheduler: Scheduler,
+}
+
+/// The jobs, shared with the observers that track the running ones.
+struct Board {
+    jobs: Mutex<Jobs>,
+    wake: Notify,
+    updates: broadcast::Sender<Job>,
+}
+
+impl Board {
+    fn add(&self, task: Task, peer: String, priority: Priority, not_before: Option<u64>) -> Job {
+        let job = {
+            let mut jobs = self.jobs.lock().unwrap();
+            jobs.next_id += 1;
+            let job = Job {
+                id: jobs.next_id,
+                task,
+                peer,
+                priority,
+                state: JobState::Queued,
+                transfer_id: None,
+                chunks_done: 0,
+                chunks_total: 0,
+                not_before,
+                error: None,
+            };
+            jobs.all.insert(job.id, job.clone());
+            job
+        };
+        let _ = self.updates.send(job.clone());
+        self.wake.notify_one();
+        job
+    }
+
+    /// Mark the queued job the scheduler picks at `now` as running and
+    /// return it with its handle; otherwise, as [`Scheduler::next`] does,
+    /// the time the next waiting job may start.
+    fn start_next(&self, now: u64) -> Result<(Job, TransferHandle), Option<u64>> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let Jobs { all, scheduler, .. } = &mut *jobs;
+        let id = scheduler.next(all.values(), now)?;
+        let job = jobs.all.get_mut(&id).expect("queued job exists");
+        job.state = JobState::Running;
+        let job = job.clone();
+        let handle = TransferHandle::new();
+        jobs.handles.insert(job.id, handle.clone());
+        drop(jobs);
+        let _ = self.updates.send(job.clone());
+        Ok((job, handle))
+    }
+
+    fn finish(&self, id: u64, result: Result<TransferOutcome>) {
+        let job = {
+            let mut jobs = self.jobs.lock().unwrap();
+            jobs.handles.remove(&id);
+            let Some(job) = jobs.all.get_mut(&id) else { return };
+            match result {
+                Ok(TransferOutcome::Completed) => job.state = JobState::Done,
+                Ok(TransferOutcome::Cancelled(_)) => job.state = JobState::Cancelled,
+                Err(e) => {
+                    job.state = JobState::Failed;
+                    job.error = Some(format!("{:#}", e));
+                }
+            }
+            let job = job.clone();
+            let finished: Vec<u64> = jobs.all.values()
+                .filter(|j| j.state.is_finished())
+                .map(|j| j.id)
+                .collect();
+            for id in finished.iter().take(finished.len().saturating_sub(FINISHED_JOBS_KEPT)) {
+                jobs.all.remove(id);
+            }
+            job
+        };
+        let _ = self.updates.send(job);
+        // Its slot is free for the next job.
+        self.wake.notify_one();
+    }
+
+    /// Pause, resume or cancel job `id`. Only a running send can be paused
+    /// or resumed; a queued job is cancelled at once, a running one before
+    /// its next chunk, when [`Daemon::run_jobs`] finishes it.
+    fn control(&self, id: u64, action: Control) -> Result<Job> {
+        let job = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let handle = jobs.handles.get(&id).cloned();
+            let job = jobs.all.get_mut(&id).with_context(|| format!("No job {}", id))?;
+            if job.state.is_finished() {
+                anyhow::bail!("Job {} has already finished", id);
+            }
+            match (action, job.state) {
+                (Control::Cancel, JobState::Queued) => job.state = JobState::Cancelled,
+                (_, JobState::Queued) => anyhow::bail!("Job {} has not started", id),
+                _ if !matches!(job.task, Task::Send { .. }) => {
+                    anyhow::bail!("Job {} is a fetch, which cannot be paused or cancelled once started", id)
+                }
+                _ => {
+                    let handle = handle.context("Job is not running")?;
+                    match action {
+                        Control::Pause => {
+                            handle.pause();
+                            job.state = JobState::Paused;
+                        }
+                        Control::Resume => {
+                            handle.resume();
+                            job.state = JobState::Running;
+                        }
+                        Control::Cancel => handle.abort(),
+                    }
+                }
+            }
+            job.clone()
+        };
+        let _ = self.updates.send(job.clone());
+        Ok(job)
+    }
+
+    /// Change the priority of queued job `id`.
+    fn prioritize(&self, id: u64, priority: Priority) -> Result<Job> {
+        let job = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let job = jobs.all.get_mut(&id).with_context(|| format!("No job {}", id))?;
+            if job.state != JobState::Queued {
+                anyhow::bail!("Job {} has already started", id);
+            }
+            job.priority = priority;
+            job.clone()
+        };
+        let _ = self.updates.send(job.clone());
+        Ok(job)
+    }
+
+    fn set_limits(&self, limits: SchedulerLimits) {
+        self.jobs.lock().unwrap().scheduler.limits = limits;
+        // Higher limits may let waiting jobs start.
+        self.wake.notify_one();
+    }
+
+    /// Track progress of job `id`, which each job's events are tagged with.
+    fn on_event(&self, id: u64, event: &TransferEvent) {
+        let job = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let Some(job) = jobs.all.get_mut(&id) else { return };
+            match event {
+                TransferEvent::HandshakeComplete { transfer_id, .. } => job.transfer_id = Some(*transfer_id),
+                TransferEvent::ManifestSent { total_chunks, .. }
+                | TransferEvent::ManifestReceived { total_chunks, .. } => job.chunks_total = *total_chunks,
+                TransferEvent::ChunkSent { .. } | TransferEvent::ChunkReceived { .. } => job.chunks_done += 1,
+                TransferEvent::CompatibilityWarning { device_id, protocol_version, disabled_features, .. } => {
+                    tracing::warn!(
+                        "{} runs an older OpenShare (protocol {}); disabled: {}",
+                        device_id, protocol_version, disabled_features.join(", ")
+                    );
+                    return;
+                }
+                _ => return,
+            }
+            job.clone()
+        };
+        let _ = self.updates.send(job);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Control {
+    Pause,
+    Resume,
+    Cancel,
+}
+
+/// See the [module docs](self).
+pub struct Daemon<S, C> {
+    client: Client<S>,
+    discovery: Discovery,
+    connector: C,
+    hub: NotificationHub,
+    board: Arc<Board>,
+    /// Where subscriptions are re-read from; see [`Self::with_config_file`]
+    config_file: Option<PathBuf>,
+}
+
+impl<S, C> Daemon<S, C>
+where
+    S: Storage + Send + Sync + 'static,
+    C: Connector,
+{
+    /// Send and fetch with `client`, each job on a copy whose transfer
+    /// events are taken as its progress, and schedule them by the limits
+    /// in its config. Fetches are written to its output directory.
+    /// Finished transfers are watched on its notification hub, which is
+    /// created if it has none; give it the hub of the listener to watch
+    /// both.
+    pub fn new(client: Client<S>, discovery: Discovery, connector: C) -> Self {
+        let (updates, _) = broadcast::channel(256);
+        let jobs = Jobs { scheduler: Scheduler::new(SchedulerLimits::from_config(&client.cfg)), ..Jobs::default() };
+        let board = Arc::new(Board { jobs: Mutex::new(jobs), wake: Notify::new(), updates });
+        let hub = client.notifications.clone().unwrap_or_default();
+        let client = client.with_notifications(hub.clone());
+        Self { client, discovery, connector, hub, board, config_file: None }
+    }
+
+    /// Take subscriptions from the config file at `path` each time a share
+    /// is offered, rather than from the client's config, so that edits
+    /// apply without a restart.
+    pub fn with_config_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config_file = Some(path.into());
+        self
+    }
+
+    pub fn hub(&self) -> &NotificationHub {
+        &self.hub
+    }
+
+    /// Queue `path` to be sent to `peer`, a discovered device ID or a
+    /// `host:port`, with `priority`. The path must be absolute, as the
+    /// caller's working directory means nothing to the daemon.
+    pub fn enqueue(&self, path: PathBuf, peer: &str, priority: Priority) -> Result<Job> {
+        if !path.is_absolute() {
+            anyhow::bail!("{} is not an absolute path", path.display());
+        }
+        if !path.exists() {
+            anyhow::bail!("{} does not exist", path.display());
+        }
+        self.resolve(peer)?;
+        let job = self.board.add(Task::Send { path }, peer.to_string(), priority, None);
+        tracing::info!("Queued job {}: {}", job.id, job);
+        Ok(job)
+    }
+
+    /// Queue a fetch of a share announced by `device_id`, served at
+    /// `address`, if one of the subscriptions matches it. A share already
+    /// queued, being fetched or fetched is not queued again. Outside the
+    /// subscription's window the job waits for it to open.
+    pub fn offer(&self, device_id: &str, address: &str, entry: &IndexEntry) -> Option<Job> {
+        let subscriptions = self.subscriptions();
+        let subscription = subscriptions.iter().find(|s| s.matches(device_id, entry))?;
+        let known = self.board.jobs.lock().unwrap().all.values().any(|j| {
+            j.state != JobState::Failed
+                && matches!(&j.task, Task::Fetch { manifest_id, .. } if *manifest_id == entry.manifest_id)
+        });
+        if known {
+            tracing::debug!("{} from {} is already queued or fetched", entry.filename, device_id);
+            return None;
+        }
+        let now = self.client.unix_now();
+        let not_before = subscription.window
+            .map(|w| w.wait_secs(now))
+            .filter(|&wait| wait > 0)
+            .map(|wait| now + wait);
+        let task = Task::Fetch {
+            manifest_id: entry.manifest_id.clone(),
+            filename: entry.filename.clone(),
+            size: entry.size,
+        };
+        let job = self.board.add(task, address.to_string(), Priority::Normal, not_before);
+        tracing::info!("Queued job {} for subscription {}: {}", job.id, subscription, job);
+        Some(job)
+    }
+
+    /// Subscriptions from the config file if there is one and it loads,
+    /// otherwise from the client's config.
+    pub fn subscriptions(&self) -> Vec<Subscription> {
+        self.config().subscriptions
+    }
+
+    /// Limits on incoming pushes, from the config file like
+    /// [`subscriptions`](Self::subscriptions), so that a listener taking
+    /// them for each connection follows changes made remotely.
+    pub fn limits(&self) -> Limits {
+        Limits::from_config(&self.config())
+    }
+
+    fn config(&self) -> ClientConfig {
+        let Some(path) = &self.config_file else { return self.client.cfg.clone() };
+        match ClientConfig::load(path) {
+            Ok(cfg) => cfg,
+            Err(e) => {
+                tracing::warn!("Failed to reload the config: {:#}", e);
+                self.client.cfg.clone()
+            }
+        }
+    }
+
+    /// Save the limits given in `params` to the config file, and return
+    /// them all as they now are.
+    fn update_limits(&self, params: Value) -> Result<Value> {
+        #[derive(Deserialize)]
+        struct LimitParams {
+            max_incoming_bytes: Option<u64>,
+            blocked_file_types: Option<Vec<String>>,
+            verified_peers_only: Option<bool>,
+        }
+        let params: LimitParams = parse_params(params).map_err(|(_, e)| anyhow::anyhow!("invalid params: {}", e))?;
+        let mut cfg = self.config();
+        if params.max_incoming_bytes.is_some() || params.blocked_file_types.is_some() || params.verified_peers_only.is_some() {
+            let path = self.config_file.as_ref().context("No config file to save limits to")?;
+            cfg = ClientConfig::load(path)?;
+            cfg.max_incoming_bytes = params.max_incoming_bytes.unwrap_or(cfg.max_incoming_bytes);
+            cfg.blocked_file_types = params.blocked_file_types.unwrap_or(cfg.blocked_file_types);
+            cfg.verified_peers_only = params.verified_peers_only.unwrap_or(cfg.verified_peers_only);
+            cfg.save(path)?;
+            tracing::info!("Limits changed remotely");
+        }
+        Ok(json!({
+            "max_incoming_bytes": cfg.max_incoming_bytes,
+            "blocked_file_types": cfg.blocked_file_types,
+            "verified_peers_only": cfg.verified_peers_only,
+        }))
+    }
+
+    /// Apply the scheduler limits given in `params`, as
+    /// [`set_scheduling`](Self::set_scheduling) does, and return them all
+    /// as they now are.
+    fn update_scheduler(&self, params: Value) -> Result<Value> {
+        #[derive(Deserialize)]
+        struct SchedulerParams {
+            max_concurrent_jobs: Option<usize>,
+            max_jobs_per_peer: Option<usize>,
+            queue_order: Option<crate::scheduler::QueueOrder>,
+        }
+        let params: SchedulerParams = parse_params(params).map_err(|(_, e)| anyhow::anyhow!("invalid params: {}", e))?;
+        let mut limits = self.scheduling();
+        if params.max_concurrent_jobs.is_some() || params.max_jobs_per_peer.is_some() || params.queue_order.is_some() {
+            limits.max_concurrent_jobs = params.max_concurrent_jobs.unwrap_or(limits.max_concurrent_jobs);
+            limits.max_jobs_per_peer = params.max_jobs_per_peer.unwrap_or(limits.max_jobs_per_peer);
+            limits.queue_order = params.queue_order.unwrap_or(limits.queue_order);
+            self.set_scheduling(limits)?;
+        }
+        Ok(serde_json::to_value(limits)?)
+    }
+
+    /// How many jobs run at once, and in what order they start.
+    pub fn scheduling(&self) -> SchedulerLimits {
+        self.board.jobs.lock().unwrap().scheduler.limits
+    }
+
+    /// Schedule jobs by `limits` from now on, saving them to the config
+    /// file if there is one. Running jobs are not stopped if there are
+    /// now too many.
+    pub fn set_scheduling(&self, limits: SchedulerLimits) -> Result<()> {
+        if let Some(path) = &self.config_file {
+            let mut cfg = ClientConfig::load(path)?;
+            cfg.max_concurrent_jobs = limits.max_concurrent_jobs;
+            cfg.max_jobs_per_peer = limits.max_jobs_per_peer;
+            cfg.queue_order = limits.queue_order;
+            cfg.save(path)?;
+        }
+        self.board.set_limits(limits);
+        tracing::info!(
+            "Scheduling {} job(s) at once, {} per peer, {}",
+            limits.max_concurrent_jobs, limits.max_jobs_per_peer, limits.queue_order
+        );
+        Ok(())
+    }
+
+    /// Change the priority of queued job `id`.
+    pub fn prioritize(&self, id: u64, priority: Priority) -> Result<Job> {
+        self.board.prioritize(id, priority)
+    }
+
+    /// Hold running send `id` after the chunk in progress.
+    pub fn pause(&self, id: u64) -> Result<Job> {
+        self.board.control(id, Control::Pause)
+    }
+
+    /// Continue paused send `id`.
+    pub fn resume(&self, id: u64) -> Result<Job> {
+        self.board.control(id, Control::Resume)
+    }
+
+    /// Drop queued job `id`, or stop running send `id`. A stopped send
+    /// leaves a [resume state](crate::transfer::ResumeState) and shows as
+    /// cancelled once it has told the peer.
+    pub fn cancel(&self, id: u64) -> Result<Job> {
+        self.board.control(id, Control::Cancel)
+    }
+
+    /// All jobs still remembered, oldest first.
+    pub fn jobs(&self) -> Vec<Job> {
+        self.board.jobs.lock().unwrap().all.values().cloned().collect()
+    }
+
+    /// Job `id`, if it is still remembered.
+    pub fn job(&self, id: u64) -> Option<Job> {
+        self.board.jobs.lock().unwrap().all.get(&id).cloned()
+    }
+
+    /// Every job as it is queued, changes state or makes progress, from
+    /// now on; what the `watch` method sends as `job` notifications.
+    pub fn job_updates(&self) -> broadcast::Receiver<Job> {
+        self.board.updates.subscribe()
+    }
+
+    /// Discovered peers other than this device, by device ID.
+    pub fn peers(&self) -> Vec<Peer> {
+        let mut peers = self.discovery.registry().peers();
+        peers.retain(|p| p.device_id() != self.client.cfg.device_id);
+        peers
+    }
+
+    /// Work through the queue forever, starting each job once the
+    /// scheduler allows and running it alongside the others.
+    pub async fn run_jobs(self: Arc<Self>)
+    where
+        C: 'static,
+    {
+        loop {
+            let now = self.client.unix_now();
+            let (job, handle) = match self.board.start_next(now) {
+                Ok(started) => started,
+                Err(None) => {
+                    self.board.wake.notified().await;
+                    continue;
+                }
+                Err(Some(next)) => {
+                    let wait = Duration::from_secs(next.saturating_sub(now));
+                    let _ = tokio::time::timeout(wait, self.board.wake.notified()).await;
+                    continue;
+                }
+            };
+            tracing::info!("Starting job {}: {}", job.id, job);
+            let daemon = self.clone();
+            tokio::spawn(async move {
+                let result = daemon.run(&job, &handle).await;
+                match &result {
+                    Ok(TransferOutcome::Completed) => tracing::info!("Job {} done", job.id),
+                    Ok(TransferOutcome::Cancelled(cancelled)) => tracing::info!("Job {}: {}", job.id, cancelled),
+                    Err(e) => tracing::warn!("Job {} failed: {:#}", job.id, e),
+                }
+                daemon.board.finish(job.id, result);
+            }.in_current_span());
+        }
+    }
+
+    async fn run(&self, job: &Job, handle: &TransferHandle) -> Result<TransferOutcome> {
+        let (address, fingerprint) = self.resolve(&job.peer)?;
+        let transport = self.connector.connect(&address).await
+            .with_context(|| format!("Failed to connect to {}", address))?;
+        let (board, id) = (self.board.clone(), job.id);
+        let client = self.client.clone().with_observer(move |event: &TransferEvent| board.on_event(id, event));
+        match &job.task {
+            Task::Send { path } => {
+                let (_, outcome) = match &fingerprint {
+                    Some(fingerprint) => {
+                        client.send_file_streaming_pinned(transport, path, false, fingerprint, handle).await?
+                    }
+                    None => client.send_file_streaming_with(transport, path, false, handle).await?,
+                };
+                Ok(outcome)
+            }
+            Task::Fetch { manifest_id, .. } => {
+                let output_dir = client.output_dir.as_deref().context("No output directory to fetch into")?;
+                let manifest = client.request_file(transport, manifest_id).await?;
+                tokio::fs::create_dir_all(output_dir).await
+                    .with_context(|| format!("Failed to create {}", output_dir.display()))?;
+                let output_path = manifest.output_path(output_dir)?;
+                client.write_file(&manifest, &output_path).await?;
+                tracing::info!("Fetched {} to {}", manifest.filename, output_path.display());
+                Ok(TransferOutcome::Completed)
+            }
+        }
+    }
+
+    /// Address of a discovered device and the fingerprint it advertised,
+    /// or `peer` itself if it has a port.
+    fn resolve(&self, peer: &str) -> Result<(String, Option<String>)> {
+        if let Some(found) = self.discovery.registry().find_by_device_id(peer) {
+            let ip = found.addresses.first()
+                .with_context(|| format!("{} has no usable address", peer))?;
+            let address = std::net::SocketAddr::new(*ip, found.port).to_string();
+            return Ok((address, Some(found.advertisement.fingerprint)));
+        }
+        if peer.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok()) {
+            return Ok((peer.to_string(), None));
+        }
+        anyhow::bail!("{} has not been discovered; give it as host:port", peer)
+    }
+
+    /// Announce this device on `interface` for `port` and browse for peers
+    /// every [`BROWSE_INTERVAL`], forever. The announcement follows the
+    /// interface to a new address. Browse failures are logged and retried.
+    pub async fn run_discovery(&self, interface: &str, port: u16) -> Result<()> {
+        let mut announcement = self.discovery.announce(&[interface.to_string()], port, &[])?;
+        tracing::info!("Announcing: {}", announcement.fullnames().join(", "));
+        let (_watcher, changes) = InterfaceWatcher::spawn(Duration::from_secs(5))?;
+
+        loop {
+            while let Ok(change) = changes.try_recv() {
+                if let Some(addresses) = announcement.apply_change(&change)? {
+                    tracing::info!("Interface {} changed, re-announced on {:?}", interface, addresses);
+                }
+            }
+            match self.discovery.browse(interface, BROWSE_TIMEOUT, &[]).await {
+                Ok(peers) => tracing::debug!("Browse found {} peer(s)", peers.len()),
+                Err(e) => tracing::warn!("Browse on {} failed: {:#}", interface, e),
+            }
+            for lost in self.discovery.registry().expire(PEER_EXPIRY) {
+                if let PeerEvent::Lost { device_id } = lost {
+                    tracing::info!("Lost peer {}", device_id);
+                }
+            }
+            tokio::time::sleep(BROWSE_INTERVAL).await;
+        }
+    }
+
+    /// Total the transfers finished on the hub and append them to the
+    /// metrics log every `metrics_snapshot_secs`, forever. Periods without
+    /// transfers leave no snapshot. Does nothing if snapshots are off.
+    pub async fn run_metrics(&self) {
+        let period = self.client.cfg.metrics_snapshot_secs;
+        if period == 0 {
+            return;
+        }
+        let log = MetricsLog::new(&self.client.cfg.data_dir);
+        let mut finished = self.hub.subscribe(NotificationFilter::default());
+        let mut snapshot = MetricsSnapshot::new(self.client.unix_now());
+        let period = Duration::from_secs(period);
+        let mut due = tokio::time::Instant::now() + period;
+        loop {
+            match tokio::time::timeout_at(due, finished.recv()).await {
+                Ok(Some(record)) => snapshot.add(&record),
+                // The hub lives as long as the daemon
+                Ok(None) => return,
+                Err(_) => {
+                    due += period;
+                    let now = self.client.unix_now();
+                    let mut taken = std::mem::replace(&mut snapshot, MetricsSnapshot::new(now));
+                    if taken.is_empty() {
+                        continue;
+                    }
+                    taken.taken_at = now;
+                    // Metrics are a convenience; a lost snapshot only
+                    // leaves a gap in the trends.
+                    if let Err(e) = log.append(&taken) {
+                        tracing::warn!("Failed to save metrics snapshot: {:#}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Answer control requests read from `reader` on `writer` until the
+    /// reader ends and, for a watching client, until it goes away.
+    pub async fn serve_control<R, W>(&self, reader: R, mut writer: W) -> Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        // Replies and notifications share the writer.
+        let (out, mut outgoing) = mpsc::unbounded_channel::<String>();
+        let write = tokio::spawn(async move {
+            while let Some(mut line) = outgoing.recv().await {
+                line.push('\n');
+                if writer.write_all(line.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut lines = BufReader::new(reader).lines();
+        let mut first = true;
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let message: Value = match serde_json::from_str(&line) {
+                Ok(message) => message,
+                Err(e) => {
+                    let _ = out.send(error_reply(Value::Null, PARSE_ERROR, e.to_string()));
+                    continue;
+                }
+            };
+            if first && message.get("method").is_none() {
+                self.watch_bare(message, &out);
+                break;
+            }
+            first = false;
+            if let Some(reply) = self.handle(message, &out) {
+                let _ = out.send(reply);
+            }
+        }
+        drop(out);
+        let _ = write.await;
+        Ok(())
+    }
+
+    /// Answer one request; `None` for notifications, which get no reply.
+    fn handle(&self, message: Value, out: &mpsc::UnboundedSender<String>) -> Option<String> {
+        #[derive(Deserialize)]
+        struct Request {
+            #[serde(default)]
+            id: Option<Value>,
+            method: String,
+            #[serde(default)]
+            params: Value,
+        }
+        let request: Request = match serde_json::from_value(message) {
+            Ok(request) => request,
+            Err(e) => return Some(error_reply(Value::Null, INVALID_PARAMS, e.to_string())),
+        };
+        let result = self.call(&request.method, request.params, out);
+        let id = request.id?;
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }).to_string(),
+            Err((code, message)) => error_reply(id, code, message),
+        })
+    }
+
+    fn call(&self, method: &str, params: Value, out: &mpsc::UnboundedSender<String>) -> Result<Value, (i64, String)> {
+        let failed = |e: anyhow::Error| (SERVER_ERROR, format!("{:#}", e));
+        let encode = |value: serde_json::Result<Value>| value.map_err(|e| (SERVER_ERROR, e.to_string()));
+        match method {
+            "peers" => encode(serde_json::to_value(self.peers())),
+            "jobs" => encode(serde_json::to_value(self.jobs())),
+            "send" => {
+                #[derive(Deserialize)]
+                struct SendParams {
+                    path: PathBuf,
+                    peer: String,
+                    #[serde(default)]
+                    priority: Priority,
+                }
+                let params: SendParams = parse_params(params)?;
+                let job = self.enqueue(params.path, &params.peer, params.priority).map_err(failed)?;
+                Ok(json!({ "job": job.id }))
+            }
+            "pause" | "resume" | "cancel" => {
+                #[derive(Deserialize)]
+                struct JobParams {
+                    job: u64,
+                }
+                let params: JobParams = parse_params(params)?;
+                let job = match method {
+                    "pause" => self.pause(params.job),
+                    "resume" => self.resume(params.job),
+                    _ => self.cancel(params.job),
+                };
+                encode(serde_json::to_value(job.map_err(failed)?))
+            }
+            "prioritize" => {
+                #[derive(Deserialize)]
+                struct PriorityParams {
+                    job: u64,
+                    priority: Priority,
+                }
+                let params: PriorityParams = parse_params(params)?;
+                encode(serde_json::to_value(self.prioritize(params.job, params.priority).map_err(failed)?))
+            }
+            "scheduler" => self.update_scheduler(params).map_err(failed),
+            "watch" => {
+                let filter: NotificationFilter = parse_params(params)?;
+                self.watch(filter, out);
+                Ok(json!({ "watching": true }))
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("Unknown method '{}'", method))),
+        }
+    }
+
+    /// Forward matching transfers and all job updates to `out` as
+    /// notifications, until the connection goes away.
+    fn watch(&self, filter: NotificationFilter, out: &mpsc::UnboundedSender<String>) {
+        tracing::debug!("Control client watching: {:?}", filter);
+        let mut transfers = self.hub.subscribe(filter);
+        let sink = out.clone();
+        tokio::spawn(async move {
+            while let Some(record) = transfers.recv().await {
+                if sink.send(notification("transfer", &record)).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut updates = self.job_updates();
+        let sink = out.clone();
+        tokio::spawn(async move {
+            loop {
+                match updates.recv().await {
+                    Ok(job) => {
+                        if sink.send(notification("job", &job)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    /// The pre-JSON-RPC protocol: a filter, then one bare record per line.
+    fn watch_bare(&self, filter: Value, out: &mpsc::UnboundedSender<String>) {
+        let filter: NotificationFilter = match serde_json::from_value(filter) {
+            Ok(filter) => filter,
+            Err(e) => {
+                let _ = out.send(json!({ "error": e.to_string() }).to_string());
+                return;
+            }
+        };
+        let mut transfers = self.hub.subscribe(filter);
+        let sink = out.clone();
+        tokio::spawn(async move {
+            while let Some(record) = transfers.recv().await {
+                let line = serde_json::to_string(&record).expect("record serializes");
+                if sink.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl<S, C> AdminHandler for Daemon<S, C>
+where
+    S: Storage + Send + Sync + 'static,
+    C: Connector,
+{
+    async fn call(&self, device_id: &str, method: &str, params: Value) -> Result<Value> {
+        tracing::info!("Admin request from {}: {}", device_id, method);
+        match method {
+            "gc" => {
+                let freed = self.client.collect_garbage().await?;
+                Ok(json!({ "chunks": freed.chunks, "bytes": freed.bytes }))
+            }
+            "limits" => self.update_limits(params),
+            _ => {
+                // Only `watch` writes to the connection, and admins cannot watch.
+                let (out, _) = mpsc::unbounded_channel();
+                Daemon::call(self, method, params, &out).map_err(|(_, message)| anyhow::anyhow!(message))
+            }
+        }
+    }
+}
+
+/// Parse `params`, treating missing params as an empty object.
+fn parse_params<T: serde::de::DeserializeOwned>(params: Value) -> Result<T, (i64, String)> {
+    let params = if params.is_null() { json!({}) } else { params };
+    serde_json::from_value(params).map_err(|e| (INVALID_PARAMS, e.to_string()))
+}
+
+fn notification<T: Serialize>(method: &str, params: &T) -> String {
+    json!({ "jsonrpc": "2.0", "method": method, "params": params }).to_string()
+}
+
+fn error_reply(id: Value, code: i64, message: String) -> String {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } }).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{pair, LinkConfig, SimStream};
+    use crate::{ClientConfig, Identity};
+    use ed25519_dalek::SigningKey;
+    use storage::LocalStorage;
+    use tempfile::TempDir;
+
+    fn client(dir: &TempDir, seed: u8) -> Client<LocalStorage> {
+        let identity = Identity::from(SigningKey::from_bytes(&[seed; 32]));
+        let storage = LocalStorage::new(dir.path().to_path_buf()).unwrap();
+        let cfg = ClientConfig {
+            data_dir: dir.path().to_path_buf(),
+            device_id: format!("dev{}", seed),
+            trust_on_first_use: true,
+            ..ClientConfig::default()
+        };
+        Client::new(identity, storage, cfg)
+    }
+
+    /// Reaches one peer, at `receiver:9876`.
+    struct SimConnector(Arc<Client<LocalStorage>>);
+
+    #[async_trait]
+    impl Connector for SimConnector {
+        type Transport = SimStream;
+
+        async fn connect(&self, address: &str) -> Result<SimStream> {
+            anyhow::ensure!(address == "receiver:9876", "{} is unreachable", address);
+            let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+            let receiver = self.0.clone();
+            tokio::spawn(async move { receiver.accept(b).await });
+            Ok(a)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_control_api_queues_and_watches_sends() {
+        let (src, dst) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let input = src.path().join("input.bin");
+        std::fs::write(&input, vec![7u8; 300_000]).unwrap();
+
+        let sender = client(&src, 1);
+        let discovery = Discovery::new(sender.cfg.clone(), &sender.identity);
+        let daemon = Arc::new(Daemon::new(sender, discovery, SimConnector(Arc::new(client(&dst, 2)))));
+        let runner = daemon.clone();
+        tokio::spawn(async move { runner.run_jobs().await });
+
+        let (local, remote) = tokio::io::duplex(1 << 16);
+        let server = daemon.clone();
+        tokio::spawn(async move {
+            let (read, write) = tokio::io::split(remote);
+            server.serve_control(read, write).await
+        });
+        let (read, mut write) = tokio::io::split(local);
+        let mut lines = BufReader::new(read).lines();
+        let request = |id: u64, method: &str, params: Value| {
+            let line = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+            format!("{}\n", line)
+        };
+
+        let calls = [
+            request(1, "watch", Value::Null),
+            request(2, "send", json!({ "path": "input.bin", "peer": "receiver:9876" })),
+            request(3, "send", json!({ "path": input, "peer": "nobody" })),
+            request(4, "reboot", Value::Null),
+            request(5, "send", json!({ "path": input, "peer": "receiver:9876" })),
+        ];
+        for call in calls {
+            write.write_all(call.as_bytes()).await.unwrap();
+        }
+
+        let mut replies = BTreeMap::new();
+        let mut states = Vec::new();
+        let mut transfers = Vec::new();
+        while transfers.is_empty() || states.last() != Some(&JobState::Done) {
+            let message: Value = serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+            match message["method"].as_str() {
+                Some("job") => {
+                    let job: Job = serde_json::from_value(message["params"].clone()).unwrap();
+                    if states.last() != Some(&job.state) {
+                        states.push(job.state);
+                    }
+                }
+                Some("transfer") => transfers.push(message["params"].clone()),
+                _ => {
+                    replies.insert(message["id"].as_u64().unwrap(), message);
+                }
+            }
+        }
+        assert_eq!(replies[&1]["result"]["watching"], true);
+        assert!(replies[&2]["error"]["message"].as_str().unwrap().contains("not an absolute path"));
+        assert!(replies[&3]["error"]["message"].as_str().unwrap().contains("has not been discovered"));
+        assert_eq!(replies[&4]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(replies[&5]["result"]["job"], 1);
+        assert_eq!(states, [JobState::Queued, JobState::Running, JobState::Done]);
+        assert_eq!(transfers[0]["direction"], "sent");
+        assert_eq!(transfers[0]["filename"], "input.bin");
+
+        write.write_all(request(6, "jobs", Value::Null).as_bytes()).await.unwrap();
+        let reply = loop {
+            let message: Value = serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+            if message["id"] == 6 {
+                break message;
+            }
+        };
+        let jobs: Vec<Job> = serde_json::from_value(reply["result"].clone()).unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].state, JobState::Done);
+        assert!(jobs[0].chunks_total > 0 && jobs[0].chunks_done == jobs[0].chunks_total);
+    }
+
+    #[tokio::test]
+    async fn test_pause_resume_and_cancel_jobs() {
+        let (src, dst) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let input = src.path().join("input.bin");
+        std::fs::write(&input, vec![7u8; 1000]).unwrap();
+
+        let sender = client(&src, 1);
+        let discovery = Discovery::new(sender.cfg.clone(), &sender.identity);
+        let daemon = Daemon::new(sender, discovery, SimConnector(Arc::new(client(&dst, 2))));
+        let queued = daemon.enqueue(input.clone(), "receiver:9876", Priority::Normal).unwrap();
+        let running = daemon.enqueue(input, "receiver:9876", Priority::Normal).unwrap();
+
+        // A queued job can only be cancelled, and then it is gone for good.
+        assert!(daemon.pause(queued.id).is_err());
+        assert_eq!(daemon.cancel(queued.id).unwrap().state, JobState::Cancelled);
+        assert!(daemon.cancel(queued.id).is_err());
+        assert!(daemon.cancel(99).is_err());
+
+        let (job, handle) = daemon.board.start_next(0).unwrap();
+        assert_eq!(job.id, running.id);
+        assert_eq!(daemon.pause(job.id).unwrap().state, JobState::Paused);
+        assert!(handle.is_paused());
+        assert_eq!(daemon.resume(job.id).unwrap().state, JobState::Running);
+        assert!(!handle.is_paused());
+        daemon.cancel(job.id).unwrap();
+        assert!(handle.is_aborted());
+        handle.resume();
+        assert!(handle.is_aborted());
+    }
+
+    #[test]
+    fn test_priorities_and_scheduler_limits() {
+        let dir = TempDir::new().unwrap();
+        let input = dir.path().join("input.bin");
+        std::fs::write(&input, b"hello").unwrap();
+        let config_file = dir.path().join("config.json");
+        let sender = client(&dir, 1);
+        sender.cfg.save(&config_file).unwrap();
+        let discovery = Discovery::new(sender.cfg.clone(), &sender.identity);
+        let daemon = Daemon::new(sender, discovery, SimConnector(Arc::new(client(&dir, 2))))
+            .with_config_file(&config_file);
+
+        let first = daemon.enqueue(input.clone(), "receiver:9876", Priority::Normal).unwrap();
+        let second = daemon.enqueue(input.clone(), "receiver:9876", Priority::Low).unwrap();
+        let other = daemon.enqueue(input, "other:9876", Priority::Normal).unwrap();
+        assert_eq!(daemon.prioritize(second.id, Priority::High).unwrap().priority, Priority::High);
+        assert!(daemon.prioritize(99, Priority::High).is_err());
+
+        // One job per peer: the high one, then the other peer's.
+        assert_eq!(daemon.board.start_next(0).unwrap().0.id, second.id);
+        assert!(daemon.prioritize(second.id, Priority::Low).is_err());
+        assert_eq!(daemon.board.start_next(0).unwrap().0.id, other.id);
+        assert_eq!(daemon.board.start_next(0).err(), Some(None));
+
+        let limits = daemon.update_scheduler(json!({ "max_jobs_per_peer": 2, "queue_order": "fair_share" })).unwrap();
+        assert_eq!(limits, json!({ "max_concurrent_jobs": 3, "max_jobs_per_peer": 2, "queue_order": "fair_share" }));
+        assert_eq!(ClientConfig::load(&config_file).unwrap().max_jobs_per_peer, 2);
+        assert_eq!(daemon.board.start_next(0).unwrap().0.id, first.id);
+        assert!(daemon.update_scheduler(json!({ "queue_order": "random" })).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_subscriptions_queue_fetches() {
+        use crate::config::TimeWindow;
+        use crate::env::{Env, ManualClock};
+        use std::time::UNIX_EPOCH;
+
+        let (src, dst) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        // Both ends share a clock, set to noon UTC.
+        let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(20_000 * 86_400 + 12 * 3600));
+        let publisher = client(&src, 2).with_env(Env::seeded([2; 32], clock.clone()));
+        let mut entries = Vec::new();
+        for (name, len) in [("report.txt", 1000), ("nightly.bin", 2000), ("huge.bin", 5000)] {
+            let input = src.path().join(name);
+            std::fs::write(&input, vec![name.len() as u8; len]).unwrap();
+            let manifest = publisher.import_file(&input).await.unwrap();
+            publisher.publish(&manifest).unwrap();
+            entries.push(IndexEntry::from_manifest(&manifest));
+        }
+
+        let mut fetcher = client(&dst, 1).with_env(Env::seeded([1; 32], clock.clone()));
+        fetcher.cfg.subscriptions = vec![
+            Subscription::parse("dev2:report*").unwrap(),
+            Subscription { window: Some("01:00-02:00".parse::<TimeWindow>().unwrap()), ..Subscription::parse("dev2:nightly*").unwrap() },
+            Subscription { max_size: 4000, ..Subscription::parse("huge*").unwrap() },
+        ];
+        let output = dst.path().join("out");
+        let fetcher = fetcher.with_output_dir(&output);
+        let discovery = Discovery::new(fetcher.cfg.clone(), &fetcher.identity);
+        let daemon = Arc::new(Daemon::new(fetcher, discovery, SimConnector(Arc::new(publisher))));
+
+        assert!(daemon.offer("dev3", "receiver:9876", &entries[0]).is_none());
+        assert!(daemon.offer("dev2", "receiver:9876", &entries[2]).is_none());
+        let report = daemon.offer("dev2", "receiver:9876", &entries[0]).unwrap();
+        assert!(daemon.offer("dev2", "receiver:9876", &entries[0]).is_none());
+        let nightly = daemon.offer("dev2", "receiver:9876", &entries[1]).unwrap();
+        assert_eq!(report.not_before, None);
+        assert_eq!(nightly.not_before, Some(daemon.client.unix_now() + 13 * 3600));
+
+        let runner = daemon.clone();
+        tokio::spawn(async move { runner.run_jobs().await });
+        let state = |id| {
+            let job = daemon.jobs().into_iter().find(|j| j.id == id).unwrap();
+            assert_ne!(job.state, JobState::Failed, "{:?}", job.error);
+            job.state
+        };
+        while state(report.id) != JobState::Done {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        assert_eq!(std::fs::read(output.join("report.txt")).unwrap(), vec![10u8; 1000]);
+        assert_eq!(state(nightly.id), JobState::Queued);
+
+        // Once the window opens, the waiting fetch runs.
+        clock.advance(Duration::from_secs(13 * 3600));
+        tokio::time::sleep(Duration::from_secs(13 * 3600)).await;
+        while state(nightly.id) != JobState::Done {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        assert_eq!(std::fs::read(output.join("nightly.bin")).unwrap(), vec![11u8; 2000]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_admin_requests() {
+        use crate::client::Accepted;
+
+        let (laptop_dir, nas_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let laptop = client(&laptop_dir, 1);
+        let config_file = nas_dir.path().join("config.json");
+        let nas = client(&nas_dir, 2);
+        nas.cfg.save(&config_file).unwrap();
+        let discovery = Discovery::new(nas.cfg.clone(), &nas.identity);
+        let daemon = Arc::new(Daemon::new(nas, discovery, SimConnector(Arc::new(client(&laptop_dir, 1))))
+            .with_config_file(&config_file));
+        let listener = client(&nas_dir, 2).with_admin(daemon.clone());
+        let admin = |method: &'static str, params: Value| {
+            let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+            let (laptop, listener) = (&laptop, &listener);
+            async move { tokio::join!(laptop.administer(a, method, params), listener.accept(b)) }
+        };
+
+        // Being trusted is not enough.
+        let (result, accepted) = admin("jobs", Value::Null).await;
+        assert!(format!("{:#}", result.unwrap_err()).contains("not trusted as an admin"));
+        assert!(accepted.is_err());
+
+        listener.trust.lock().unwrap().set_admin("dev1", true);
+        let (result, accepted) = admin("jobs", Value::Null).await;
+        assert_eq!(result.unwrap(), json!([]));
+        assert!(matches!(accepted.unwrap(), Accepted::Administered { device_id, method } if device_id == "dev1" && method == "jobs"));
+
+        let (result, _) = admin("limits", json!({ "max_incoming_bytes": 5000 })).await;
+        assert_eq!(result.unwrap()["max_incoming_bytes"], 5000);
+        assert_eq!(daemon.limits().max_bytes, 5000);
+        assert_eq!(ClientConfig::load(&config_file).unwrap().max_incoming_bytes, 5000);
+
+        let (result, _) = admin("gc", Value::Null).await;
+        assert_eq!(result.unwrap(), json!({ "chunks": 0, "bytes": 0 }));
+        let (result, _) = admin("cancel", json!({ "job": 7 })).await;
+        assert!(result.is_err());
+        // Sends name paths on the daemon's machine; they stay local.
+        let (result, _) = admin("send", json!({ "path": "/etc/passwd", "peer": "receiver:9876" })).await;
+        assert!(format!("{:#}", result.unwrap_err()).contains("not available remotely"));
+    }
+}
@@ -0,0 +1,280 @@
+//! Discovery facade over mdns-core with OpenShare semantics.
+//!
+//! - Advertises the OpenShare TXT schema (`acct_hash`, `dev_id`, `fp`)
+//! - Filters browse results to peers of the same account
+//! - Keeps the peers seen in a [`PeerRegistry`], cleared on interface changes
+//! - Streams peers coming and going with [`Discovery::watch`]
+
+use crate::registry::{PeerEvent, PeerRegistry};
+use crate::{config::ClientConfig, keys::Identity};
+use anyhow::Result;
+use mdns_core::announce::Announcer;
+use mdns_core::discover::{browse_many_blocking, watch_many_blocking};
+use mdns_core::model::{BrowseEvent, DiscoveredService, InterfaceChange, ServiceAnnouncement, TxtRecord};
+use mdns_core::net::{list_interface_ips_result, rank_interfaces};
+use serde::Serialize;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// TXT keys of the OpenShare advertisement schema.
+pub const TXT_ACCOUNT_HASH: &str = "acct_hash";
+pub const TXT_DEVICE_ID: &str = "dev_id";
+pub const TXT_FINGERPRINT: &str = "fp";
+
+/// What a device advertises about itself over mDNS.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Advertisement {
+    pub account_hash: String,
+    pub device_id: String,
+    pub fingerprint: String,
+}
+
+impl Advertisement {
+    pub fn to_txt(&self) -> Vec<(String, String)> {
+        vec![
+            (TXT_ACCOUNT_HASH.to_string(), self.account_hash.clone()),
+            (TXT_DEVICE_ID.to_string(), self.device_id.clone()),
+            (TXT_FINGERPRINT.to_string(), self.fingerprint.clone()),
+        ]
+    }
+
+    /// Parse the schema back out of TXT records. Returns `None` if any
+    /// required key is missing (i.e. not an OpenShare endpoint).
+    pub fn from_txt(txt: &[(String, String)]) -> Option<Self> {
+        let get = |key: &str| txt.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone());
+        Some(Self {
+            account_hash: get(TXT_ACCOUNT_HASH)?,
+            device_id: get(TXT_DEVICE_ID)?,
+            fingerprint: get(TXT_FINGERPRINT)?,
+        })
+    }
+}
+
+/// A discovered OpenShare peer.
+#[derive(Debug, Clone, Serialize)]
+pub struct Peer {
+    #[serde(flatten)]
+    pub advertisement: Advertisement,
+    pub fullname: String,
+    pub host_name: String,
+    pub port: u16,
+    /// Addresses in dial preference order
+    pub addresses: Vec<IpAddr>,
+    pub txt: Vec<(String, String)>,
+    #[serde(skip)]
+    pub last_seen: Instant,
+}
+
+impl Peer {
+    pub(crate) fn from_service(svc: DiscoveredService) -> Option<Self> {
+        let advertisement = Advertisement::from_txt(&svc.txt)?;
+        Some(Self {
+            advertisement,
+            fullname: svc.fullname,
+            host_name: svc.host_name,
+            port: svc.port,
+            addresses: svc.addresses,
+            txt: svc.txt,
+            last_seen: Instant::now(),
+        })
+    }
+
+    pub fn device_id(&self) -> &str {
+        &self.advertisement.device_id
+    }
+}
+
+/// Announce and browse for OpenShare peers.
+#[derive(Clone)]
+pub struct Discovery {
+    cfg: ClientConfig,
+    advertisement: Advertisement,
+    registry: PeerRegistry,
+}
+
+impl Discovery {
+    pub fn new(cfg: ClientConfig, identity: &Identity) -> Self {
+        let advertisement = Advertisement {
+            account_hash: cfg.account_hash.clone(),
+            device_id: cfg.device_id.clone(),
+            fingerprint: identity.fingerprint(),
+        };
+        Self {
+            registry: PeerRegistry::new(&cfg),
+            cfg,
+            advertisement,
+        }
+    }
+
+    pub fn advertisement(&self) -> &Advertisement {
+        &self.advertisement
+    }
+
+    /// Announce this device with every IPv4 and IPv6 address of
+    /// `interfaces`, or of all non-loopback interfaces if none are given,
+    /// that it listens on (see [`ClientConfig::listens_on`]), under the
+    /// configured service type plus any extras from config and
+    /// `more_types`.
+    pub fn announce(&self, interfaces: &[String], port: u16, more_types: &[String]) -> Result<Announcement> {
+        let addresses = self.interface_ips(interfaces)?;
+        if addresses.is_empty() {
+            let named = if interfaces.is_empty() { "any".to_string() } else { interfaces.join(", ") };
+            anyhow::bail!(
+                "No usable interface found: {} (overlay policy: {:?}, listening on {:?})",
+                named, self.cfg.overlay_policy, self.cfg.listen_endpoints()
+            );
+        }
+
+        let ann = ServiceAnnouncement {
+            service_type: self.cfg.service_type.clone(),
+            instance_name: self.cfg.device_id.clone(),
+            host_name: format!("{}.local.", self.cfg.device_id),
+            addresses,
+            port,
+            txt: Some(TxtRecord(self.advertisement.to_txt())),
+        };
+
+        let extra_types = self.cfg.additional_service_types(more_types);
+        let announcer = Announcer::register_types(ann, &extra_types)?;
+        Ok(Announcement {
+            announcer,
+            interfaces: interfaces.to_vec(),
+            discovery: self.clone(),
+        })
+    }
+
+    /// Browse for peers of this account for `timeout`. Results are merged
+    /// into the registry, and the peers that answered are returned with
+    /// addresses ranked by the overlay policy.
+    pub async fn browse(&self, interface: &str, timeout: Duration, more_types: &[String]) -> Result<Vec<Peer>> {
+        let mut service_types = vec![self.cfg.service_type.clone()];
+        service_types.extend(self.cfg.additional_service_types(more_types));
+        let interface = interface.to_string();
+
+        let services = tokio::task::spawn_blocking(move || {
+            browse_many_blocking(&service_types, timeout, &interface)
+        }).await??;
+
+        // The same device may answer under several service types.
+        let mut seen: Vec<String> = Vec::new();
+        for svc in services {
+            let device_id = Advertisement::from_txt(&svc.txt).map(|ad| ad.device_id);
+            self.registry.apply(BrowseEvent::Resolved(svc));
+            seen.extend(device_id.filter(|id| !seen.contains(id)));
+        }
+        Ok(seen.iter().filter_map(|id| self.registry.find_by_device_id(id)).collect())
+    }
+
+    /// Browse like [`browse`](Self::browse), but send each change to the
+    /// peers to
+    /// `events` as soon as it resolves, and a [`PeerEvent::Lost`] when it
+    /// withdraws. Runs until `timeout`, if any, or until `events` is
+    /// closed. Browsing waits while `events` is full.
+    pub async fn watch(
+        &self,
+        interface: &str,
+        timeout: Option<Duration>,
+        more_types: &[String],
+        events: mpsc::Sender<PeerEvent>,
+    ) -> Result<()> {
+        let mut service_types = vec![self.cfg.service_type.clone()];
+        service_types.extend(self.cfg.additional_service_types(more_types));
+        let interface = interface.to_string();
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let registry = self.registry.clone();
+
+        tokio::task::spawn_blocking(move || {
+            watch_many_blocking(&service_types, deadline, &interface, &|event| {
+                match registry.apply(event) {
+                    Some(update) => events.blocking_send(update).is_ok(),
+                    None => !events.is_closed(),
+                }
+            })
+        }).await?
+    }
+
+    /// The peers seen by browses and watches so far.
+    pub fn registry(&self) -> &PeerRegistry {
+        &self.registry
+    }
+
+    /// Forget the peers seen after local addresses went away; they may
+    /// have been learned over the lost network.
+    pub fn invalidate(&self, change: &InterfaceChange) {
+        if !change.removed.is_empty() {
+            self.registry.clear();
+        }
+    }
+
+    /// Addresses of `interfaces` (all but loopback if empty) to announce,
+    /// leaving out those no listener is bound to.
+    fn interface_ips(&self, interfaces: &[String]) -> Result<Vec<IpAddr>> {
+        // Ranking drops interfaces excluded by the overlay policy.
+        Ok(rank_interfaces(&list_interface_ips_result()?, self.cfg.overlay_policy)
+            .into_iter()
+            .filter(|item| match interfaces {
+                [] => !item.is_loopback,
+                named => named.contains(&item.name),
+            })
+            .filter(|item| self.cfg.listens_on(item.ip))
+            .map(|item| item.ip)
+            .collect())
+    }
+}
+
+/// A live announcement; the records are withdrawn when this is dropped.
+pub struct Announcement {
+    announcer: Announcer,
+    /// Interfaces as given; empty for all of them
+    interfaces: Vec<String>,
+    discovery: Discovery,
+}
+
+impl Announcement {
+    pub fn fullnames(&self) -> &[String] {
+        self.announcer.fullnames()
+    }
+
+    pub fn addresses(&self) -> &[IpAddr] {
+        self.announcer.addresses()
+    }
+
+    /// React to an interface change: invalidate the registry and
+    /// re-announce if the addresses of our interfaces changed. Returns the
+    /// new addresses when a re-announcement happened.
+    pub fn apply_change(&mut self, change: &InterfaceChange) -> Result<Option<Vec<IpAddr>>> {
+        self.discovery.invalidate(change);
+        if !self.interfaces.is_empty() && !self.interfaces.iter().any(|i| change.touches(i)) {
+            return Ok(None);
+        }
+
+        let addresses = self.discovery.interface_ips(&self.interfaces)?;
+        if addresses.is_empty() {
+            tracing::warn!("No interface to announce on has an address; keeping last announcement");
+            return Ok(None);
+        }
+        if addresses == self.announcer.addresses() {
+            return Ok(None);
+        }
+        self.announcer.reregister(&addresses)?;
+        Ok(Some(addresses))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_advertisement_txt_roundtrip() {
+        let ad = Advertisement {
+            account_hash: "abcd".into(),
+            device_id: "laptop".into(),
+            fingerprint: "01020304".into(),
+        };
+        assert_eq!(Advertisement::from_txt(&ad.to_txt()), Some(ad));
+        assert_eq!(Advertisement::from_txt(&[("dev_id".into(), "x".into())]), None);
+    }
+
+}
@@ -0,0 +1,240 @@
+//! Encryption at rest for the chunk store.
+//!
+//! [`EncryptedStorage`] wraps another [`Storage`] and seals each chunk with
+//! XChaCha20-Poly1305 before handing it on, so the chunks directory holds
+//! only ciphertext. Chunks keep the ids of their plaintext (the SHA-256
+//! the manifests refer to), which are bound to the ciphertext as
+//! associated data so a chunk cannot be swapped for another. The ids
+//! themselves stay visible: someone who can read the store can tell
+//! whether it holds a file they already have, but not read anything else,
+//! unless the ids are keyed too (see [`crate::keyed`]).
+//!
+//! The key is derived from the device identity, or kept in its own
+//! `storage.key` file so that it can be backed up or rotated separately;
+//! see [`StorageEncryption`]. Chunks written under one key, or before
+//! encryption was turned on, cannot be read under another.
+
+use crate::Identity;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::path::Path;
+use storage::{ChunkInfo, GcStats, Storage, StorageStats};
+use zeroize::Zeroizing;
+
+/// File name of the separate storage key inside the data directory.
+pub const KEY_FILE: &str = "storage.key";
+
+/// HKDF info for the key derived from the identity.
+const STORAGE_KEY_LABEL: &[u8] = b"openshare storage key v1";
+
+const NONCE_LEN: usize = 24;
+
+/// Whether and with which key the chunk store is encrypted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageEncryption {
+    /// Chunks are stored as received
+    #[default]
+    Off,
+    /// Key derived from the device identity
+    Identity,
+    /// Random key kept in `storage.key`, created on first use
+    KeyFile,
+}
+
+impl StorageEncryption {
+    /// The key this setting calls for, or `None` if it is off.
+    pub fn key(self, data_dir: &Path, identity: &Identity) -> Result<Option<StorageKey>> {
+        Ok(match self {
+            StorageEncryption::Off => None,
+            StorageEncryption::Identity => Some(StorageKey::from_identity(identity)),
+            StorageEncryption::KeyFile => Some(StorageKey::load_or_generate(&data_dir.join(KEY_FILE))?),
+        })
+    }
+}
+
+/// Symmetric key for chunks at rest.
+pub struct StorageKey(Zeroizing<[u8; 32]>);
+
+impl StorageKey {
+    pub fn from_identity(identity: &Identity) -> Self {
+        let mut key = Zeroizing::new([0u8; 32]);
+        Hkdf::<Sha256>::new(None, &*identity.secret_bytes())
+            .expand(STORAGE_KEY_LABEL, &mut *key)
+            .expect("32 bytes is a valid HKDF length");
+        Self(key)
+    }
+
+    /// Load the key file at `path`, creating it with a random key (readable
+    /// only by its owner) if it does not exist.
+    pub fn load_or_generate(path: &Path) -> Result<Self> {
+        match std::fs::read(path) {
+            Ok(data) => {
+                let data = Zeroizing::new(data);
+                let key: [u8; 32] = data.as_slice().try_into()
+                    .map_err(|_| anyhow::anyhow!("Invalid storage key length: expected 32 bytes, got {}", data.len()))?;
+                Ok(Self(Zeroizing::new(key)))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let mut key = Zeroizing::new([0u8; 32]);
+                OsRng.fill_bytes(&mut *key);
+                write_private(path, &*key)
+                    .with_context(|| format!("writing {}", path.display()))?;
+                tracing::info!("Generated storage key at {:?}", path);
+                Ok(Self(key))
+            }
+            Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+        }
+    }
+}
+
+/// Create `path`, readable only by its owner, holding `data`. Fails with
+/// `AlreadyExists` rather than replacing a file that is there.
+pub fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut options = std::fs::File::options();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(data)
+}
+
+/// A [`Storage`] that encrypts chunk contents before delegating to `S`.
+///
+/// Everything but the chunk bytes passes straight through, so quota and
+/// garbage collection count ciphertext sizes, and streamed chunks are
+/// buffered whole since each one is sealed as a unit.
+pub struct EncryptedStorage<S> {
+    inner: S,
+    aead: XChaCha20Poly1305,
+}
+
+impl<S: Storage> EncryptedStorage<S> {
+    pub fn new(inner: S, key: &StorageKey) -> Self {
+        Self { inner, aead: XChaCha20Poly1305::new((&*key.0).into()) }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn seal(&self, id: &str, data: &[u8]) -> storage::Result<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let sealed = self.aead
+            .encrypt(XNonce::from_slice(&nonce), Payload { msg: data, aad: id.as_bytes() })
+            .map_err(|_| storage::Error::Other(format!("Failed to encrypt chunk {}", id).into()))?;
+        Ok([&nonce[..], &sealed].concat())
+    }
+
+    fn open(&self, id: &str, sealed: &[u8]) -> storage::Result<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return Err(storage::Error::Other(format!("Stored chunk {} is truncated", id).into()));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.aead
+            .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad: id.as_bytes() })
+            .map_err(|_| storage::Error::Other(format!("Cannot decrypt chunk {} (stored unencrypted or under another key?)", id).into()))
+    }
+}
+
+#[async_trait]
+impl<S: Storage> Storage for EncryptedStorage<S> {
+    async fn put_chunk(&self, data: &[u8]) -> storage::Result<String> {
+        let id = storage::chunk_id(data);
+        self.put_chunk_as(&id, data).await?;
+        Ok(id)
+    }
+
+    async fn get_chunk(&self, id: &str) -> storage::Result<Option<Vec<u8>>> {
+        match self.inner.get_chunk(id).await? {
+            Some(sealed) => self.open(id, &sealed).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    async fn put_chunk_as(&self, id: &str, data: &[u8]) -> storage::Result<()> {
+        let sealed = self.seal(id, data)?;
+        self.inner.put_chunk_as(id, &sealed).await
+    }
+
+    async fn has_chunk(&self, id: &str) -> storage::Result<bool> {
+        self.inner.has_chunk(id).await
+    }
+
+    async fn delete_chunk(&self, id: &str) -> storage::Result<bool> {
+        self.inner.delete_chunk(id).await
+    }
+
+    async fn list_chunks(&self, after: Option<&str>, limit: usize) -> storage::Result<Vec<String>> {
+        self.inner.list_chunks(after, limit).await
+    }
+
+    async fn add_manifest(&self, manifest_id: &str, chunks: &[String]) -> storage::Result<()> {
+        self.inner.add_manifest(manifest_id, chunks).await
+    }
+
+    async fn gc(&self, retain: &[String]) -> storage::Result<GcStats> {
+        self.inner.gc(retain).await
+    }
+
+    async fn evict(&self, max_bytes: u64, retain: &[String]) -> storage::Result<GcStats> {
+        self.inner.evict(max_bytes, retain).await
+    }
+
+    async fn stats(&self) -> storage::Result<StorageStats> {
+        self.inner.stats().await
+    }
+
+    async fn describe_chunks(&self, after: Option<&str>, limit: usize) -> storage::Result<Vec<ChunkInfo>> {
+        self.inner.describe_chunks(after, limit).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use storage::LocalStorage;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_chunks_are_encrypted_at_rest() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let key = StorageKey::load_or_generate(&tmp.path().join(KEY_FILE))?;
+        let store = EncryptedStorage::new(LocalStorage::new(tmp.path().join("store"))?, &key);
+
+        let data = b"the contents of a private file".repeat(100);
+        let id = store.put_chunk(&data).await?;
+        assert_eq!(id, storage::chunk_id(&data));
+        assert_eq!(store.get_chunk(&id).await?, Some(data.clone()));
+
+        // The inner store only ever sees ciphertext, under the plaintext id.
+        let raw = store.inner().get_chunk(&id).await?.expect("stored");
+        assert!(!raw.windows(30).any(|w| w == &data[..30]));
+        assert_eq!(raw.len(), data.len() + NONCE_LEN + 16);
+
+        // The key file is reused, and a different key cannot read the chunk.
+        let again = StorageKey::load_or_generate(&tmp.path().join(KEY_FILE))?;
+        let reopened = EncryptedStorage::new(LocalStorage::new(tmp.path().join("store"))?, &again);
+        assert_eq!(reopened.get_chunk(&id).await?, Some(data));
+        let other = StorageKey::from_identity(&Identity::generate_and_store(&tmp.path().join("id.key"))?);
+        let foreign = EncryptedStorage::new(LocalStorage::new(tmp.path().join("store"))?, &other);
+        assert!(foreign.get_chunk(&id).await.is_err());
+
+        // A sealed chunk moved to another id does not open.
+        let other_id = storage::chunk_id(b"other");
+        store.inner().put_chunk_as(&other_id, &raw).await?;
+        assert!(store.get_chunk(&other_id).await.is_err());
+        Ok(())
+    }
+}
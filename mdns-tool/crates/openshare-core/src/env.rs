@@ -0,0 +1,122 @@
+//! Injectable randomness and time.
+//!
+//! Protocol code takes an [`Env`] instead of reaching for `OsRng` and
+//! `SystemTime::now()` directly, so tests can run handshakes with seeded
+//! randomness and simulate clock skew.
+//!
+//! The `Env` also holds the protocol version the handshake announces,
+//! normally [`PROTOCOL_VERSION`]. Lowered (`--compat`), the device offers
+//! and uses only the features of that version, so a fleet can keep a
+//! newer build in step with older ones until all are upgraded, and tests
+//! can stand in for an older release.
+
+use crate::handshake::PROTOCOL_VERSION;
+use rand_chacha::ChaCha20Rng;
+use rand_core::{CryptoRng, OsRng, RngCore, SeedableRng};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Source of wall-clock time.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The real system clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<SystemTime>>,
+}
+
+impl ManualClock {
+    pub fn new(start: SystemTime) -> Self {
+        Self { now: Arc::new(Mutex::new(start)) }
+    }
+
+    pub fn set(&self, t: SystemTime) {
+        *self.now.lock().unwrap() = t;
+    }
+
+    pub fn advance(&self, d: Duration) {
+        *self.now.lock().unwrap() += d;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+}
+
+/// Cryptographically secure RNG usable behind a trait object.
+pub trait SecureRng: RngCore + CryptoRng + Send {}
+
+impl<T: RngCore + CryptoRng + Send> SecureRng for T {}
+
+/// Shared RNG and clock handed to protocol code. Cheap to clone.
+#[derive(Clone)]
+pub struct Env {
+    rng: Arc<Mutex<dyn SecureRng>>,
+    clock: Arc<dyn Clock>,
+    protocol_version: u16,
+}
+
+impl Env {
+    pub fn new(rng: impl SecureRng + 'static, clock: impl Clock + 'static) -> Self {
+        Self {
+            rng: Arc::new(Mutex::new(rng)),
+            clock: Arc::new(clock),
+            protocol_version: PROTOCOL_VERSION,
+        }
+    }
+
+    /// Speak protocol `version` (at most [`PROTOCOL_VERSION`]) in
+    /// handshakes, offering and using only the features it had.
+    pub fn with_protocol_version(mut self, version: u16) -> Self {
+        self.protocol_version = version.min(PROTOCOL_VERSION);
+        self
+    }
+
+    pub fn protocol_version(&self) -> u16 {
+        self.protocol_version
+    }
+
+    /// OS randomness and the system clock.
+    pub fn system() -> Self {
+        Self::new(OsRng, SystemClock)
+    }
+
+    /// Reproducible randomness from `seed`, for tests and simulations.
+    pub fn seeded(seed: [u8; 32], clock: impl Clock + 'static) -> Self {
+        Self::new(ChaCha20Rng::from_seed(seed), clock)
+    }
+
+    pub fn now(&self) -> SystemTime {
+        self.clock.now()
+    }
+
+    pub fn fill_bytes(&self, dest: &mut [u8]) {
+        self.rng.lock().unwrap().fill_bytes(dest);
+    }
+
+    /// Run `f` with exclusive access to the RNG.
+    pub fn with_rng<R>(&self, f: impl FnOnce(&mut dyn SecureRng) -> R) -> R {
+        let mut rng = self.rng.lock().unwrap();
+        f(&mut *rng)
+    }
+}
+
+impl Default for Env {
+    fn default() -> Self {
+        Self::system()
+    }
+}
@@ -0,0 +1,223 @@
+//! Telling failures apart.
+//!
+//! The public operations of [`Client`](crate::Client) and
+//! [`Manifest`](crate::Manifest) return an [`OpenShareError`], sorted by
+//! what went wrong: a [`Rejected`] push, a [`Cancelled`] or
+//! [`PeerAborted`] transfer, a failed handshake, a signature that does not
+//! check out, or an I/O error from the disk. The handshake has its own
+//! [`HandshakeError`] and chunk stores their [`storage::Error`]. The rest
+//! keeps the context of where it happened ("sending report.pdf to nas:
+//! ..."), so a consumer can match on the kind and still show the message:
+//!
+//! ```ignore
+//! match client.send_manifest_over(transport, manifest).await {
+//!     Err(OpenShareError::Rejected(r)) => ask_again(r.reason),
+//!     Err(OpenShareError::DiskFull(_)) => free_space(),
+//!     other => other?,
+//! }
+//! ```
+
+use crate::handshake::HandshakeError;
+use crate::protocol::AbortCode;
+use crate::space::InsufficientSpace;
+use crate::transfer::{Cancelled, PeerAborted, Rejected};
+use thiserror::Error;
+
+/// An error from another library, or a failure with its context.
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A library error sorted by its cause; see the [module docs](self).
+#[derive(Error, Debug)]
+pub enum OpenShareError {
+    /// The receiver declined the push
+    #[error(transparent)]
+    Rejected(Rejected),
+    #[error(transparent)]
+    Cancelled(Cancelled),
+    /// The peer gave up after a failure on its side
+    #[error(transparent)]
+    PeerAborted(PeerAborted),
+    /// The peer could not be authenticated or speaks an incompatible
+    /// protocol
+    #[error(transparent)]
+    Handshake(HandshakeError),
+    /// A manifest, header or signed request whose signature does not
+    /// verify against the key it should come from
+    #[error(transparent)]
+    InvalidSignature(BoxError),
+    /// Writing chunks or files ran out of space or quota, or an incoming
+    /// transfer was found not to fit beforehand
+    /// ([`InsufficientSpace`](crate::space::InsufficientSpace))
+    #[error(transparent)]
+    DiskFull(BoxError),
+    #[error(transparent)]
+    PermissionDenied(BoxError),
+    /// Any other I/O error, including lost connections
+    #[error(transparent)]
+    Io(BoxError),
+    #[error(transparent)]
+    Other(BoxError),
+}
+
+impl OpenShareError {
+    /// How to make the same kind of error for another error with this one
+    /// in its chain, for the kinds that keep their context; the typed
+    /// kinds are taken out of their context instead.
+    fn boxed_kind(&self) -> Option<fn(BoxError) -> Self> {
+        match self {
+            Self::InvalidSignature(_) => Some(Self::InvalidSignature),
+            Self::DiskFull(_) => Some(Self::DiskFull),
+            Self::PermissionDenied(_) => Some(Self::PermissionDenied),
+            Self::Io(_) => Some(Self::Io),
+            Self::Other(_) => Some(Self::Other),
+            _ => None,
+        }
+    }
+}
+
+impl From<anyhow::Error> for OpenShareError {
+    fn from(error: anyhow::Error) -> Self {
+        // Sorted once already, on the way out of another public call
+        let sorted = error.chain().find_map(|e| e.downcast_ref::<OpenShareError>()).map(Self::boxed_kind);
+        let error = match sorted {
+            Some(Some(kind)) => return kind(boxed(error)),
+            Some(None) => match error.downcast::<OpenShareError>() {
+                Ok(sorted) => return sorted,
+                Err(error) => error,
+            },
+            None => error,
+        };
+        // A push refused for lack of space is a full disk before it is a
+        // rejection.
+        if error.chain().any(|e| e.is::<InsufficientSpace>()) {
+            return Self::DiskFull(boxed(error));
+        }
+        // Typed errors are taken out of their context; the rest keep it.
+        let error = match error.downcast::<Rejected>() {
+            Ok(rejected) => return Self::Rejected(rejected),
+            Err(error) => error,
+        };
+        let error = match error.downcast::<Cancelled>() {
+            Ok(cancelled) => return Self::Cancelled(cancelled),
+            Err(error) => error,
+        };
+        let error = match error.downcast::<PeerAborted>() {
+            Ok(aborted) => return Self::PeerAborted(aborted),
+            Err(error) => error,
+        };
+        let error = match error.downcast::<HandshakeError>() {
+            Ok(handshake) => return Self::Handshake(handshake),
+            Err(error) => error,
+        };
+        if error.chain().any(|e| e.is::<ed25519_dalek::SignatureError>()) {
+            return Self::InvalidSignature(boxed(error));
+        }
+        match AbortCode::of(&error) {
+            AbortCode::DiskFull => Self::DiskFull(boxed(error)),
+            AbortCode::PermissionDenied => Self::PermissionDenied(boxed(error)),
+            AbortCode::Io => Self::Io(boxed(error)),
+            AbortCode::Other => Self::Other(boxed(error)),
+        }
+    }
+}
+
+/// Keep `error` whole, showing its context under `{:#}` like anyhow does.
+fn boxed(error: anyhow::Error) -> BoxError {
+    Box::new(Contextual(error))
+}
+
+#[derive(Debug)]
+struct Contextual(anyhow::Error);
+
+impl std::fmt::Display for Contextual {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if f.alternate() {
+            write!(f, "{:#}", self.0)
+        } else {
+            write!(f, "{}", self.0)
+        }
+    }
+}
+
+impl std::error::Error for Contextual {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.0.chain().nth(1)
+    }
+}
+
+impl From<storage::Error> for OpenShareError {
+    fn from(error: storage::Error) -> Self {
+        anyhow::Error::new(error).into()
+    }
+}
+
+impl From<HandshakeError> for OpenShareError {
+    fn from(error: HandshakeError) -> Self {
+        Self::Handshake(error)
+    }
+}
+
+impl From<bincode::Error> for OpenShareError {
+    fn from(error: bincode::Error) -> Self {
+        anyhow::Error::new(error).into()
+    }
+}
+
+impl From<std::io::Error> for OpenShareError {
+    fn from(error: std::io::Error) -> Self {
+        anyhow::Error::new(error).into()
+    }
+}
+
+/// The `T` in `error`'s chain, also if it was sorted into an
+/// [`OpenShareError`] on the way.
+pub(crate) fn find<T: std::error::Error + 'static>(error: &anyhow::Error) -> Option<&T> {
+    error.chain().find_map(|e| {
+        e.downcast_ref::<T>().or_else(|| match e.downcast_ref::<OpenShareError>()? {
+            OpenShareError::Rejected(r) => (r as &dyn std::error::Error).downcast_ref(),
+            OpenShareError::Cancelled(c) => (c as &dyn std::error::Error).downcast_ref(),
+            OpenShareError::PeerAborted(a) => (a as &dyn std::error::Error).downcast_ref(),
+            OpenShareError::Handshake(h) => (h as &dyn std::error::Error).downcast_ref(),
+            _ => None,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Identity, Manifest};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_error_classification() -> anyhow::Result<()> {
+        let rejected = anyhow::Error::new(Rejected { by: Some("nas".into()), reason: "too large".into() })
+            .context("sending a.txt")
+            .context("to nas");
+        assert!(matches!(OpenShareError::from(rejected), OpenShareError::Rejected(r) if r.reason == "too large"));
+
+        let handshake = anyhow::Error::new(HandshakeError::UnexpectedPeer("dev9".into())).context("connecting");
+        assert!(matches!(OpenShareError::from(handshake), OpenShareError::Handshake(HandshakeError::UnexpectedPeer(_))));
+
+        let full = anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::StorageFull)).context("storing chunk 3");
+        let error = OpenShareError::from(full);
+        assert!(matches!(error, OpenShareError::DiskFull(_)));
+        assert!(error.to_string().contains("storing chunk 3"), "{}", error);
+
+        let tmp = TempDir::new()?;
+        let file = tmp.path().join("a.txt");
+        std::fs::write(&file, b"hello world")?;
+        let mut manifest = Manifest::from_file(file.to_str().unwrap(), 4)?;
+        manifest.sign(&Identity::from(ed25519_dalek::SigningKey::from_bytes(&[3; 32])))?;
+        manifest.size += 1;
+        let tampered = manifest.verify().unwrap_err();
+        assert!(matches!(tampered, OpenShareError::InvalidSignature(_)));
+
+        let short = anyhow::Error::new(InsufficientSpace { path: "/data".into(), needed: 10, available: 1 })
+            .context(Rejected { by: None, reason: "not enough disk space".into() });
+        assert!(matches!(OpenShareError::from(short), OpenShareError::DiskFull(_)));
+
+        assert!(matches!(OpenShareError::from(anyhow::anyhow!("nope")), OpenShareError::Other(_)));
+        Ok(())
+    }
+}
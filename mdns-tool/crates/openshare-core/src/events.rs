@@ -0,0 +1,135 @@
+//! Structured transfer progress for embedders.
+//!
+//! A [`TransferObserver`] attached with [`Client::with_observer`] receives a
+//! [`TransferEvent`] at each step of a send or receive, so UIs can render
+//! progress without scraping tracing output. Observers are called inline on
+//! the transfer task and should return quickly; forward to a channel for
+//! anything slow. `mpsc::UnboundedSender<TransferEvent>` and plain closures
+//! implement the trait.
+//!
+//! [`Client::with_observer`]: crate::Client::with_observer
+
+use crate::handshake::PeerInfo;
+use crate::transfer::TransferId;
+use tokio::sync::mpsc;
+
+/// Every event names its transfer, with the ID the peer sees too.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferEvent {
+    /// The encrypted session is established, possibly from a ticket
+    HandshakeComplete { transfer_id: TransferId, peer: Option<PeerInfo>, resumed: bool },
+    /// The peer runs an older version; the listed features are off for
+    /// this transfer
+    CompatibilityWarning {
+        transfer_id: TransferId,
+        device_id: String,
+        protocol_version: u16,
+        app_version: Option<String>,
+        disabled_features: Vec<String>,
+    },
+    /// The signed manifest went out to the receiver
+    ManifestSent { transfer_id: TransferId, filename: String, size: u64, total_chunks: usize },
+    /// A manifest arrived and its signature checked out
+    ManifestReceived { transfer_id: TransferId, filename: String, size: u64, total_chunks: usize },
+    ChunkSent { transfer_id: TransferId, index: usize, total: usize, bytes: usize },
+    ChunkReceived { transfer_id: TransferId, index: usize, total: usize, bytes: usize },
+    TransferComplete { transfer_id: TransferId, filename: String, size: u64 },
+    /// The transfer was aborted; `error` is the rendered error chain.
+    /// There is no ID if it failed before the handshake
+    TransferFailed { transfer_id: Option<TransferId>, error: String },
+}
+
+impl TransferEvent {
+    pub fn transfer_id(&self) -> Option<TransferId> {
+        match self {
+            TransferEvent::HandshakeComplete { transfer_id, .. }
+            | TransferEvent::CompatibilityWarning { transfer_id, .. }
+            | TransferEvent::ManifestSent { transfer_id, .. }
+            | TransferEvent::ManifestReceived { transfer_id, .. }
+            | TransferEvent::ChunkSent { transfer_id, .. }
+            | TransferEvent::ChunkReceived { transfer_id, .. }
+            | TransferEvent::TransferComplete { transfer_id, .. } => Some(*transfer_id),
+            TransferEvent::TransferFailed { transfer_id, .. } => *transfer_id,
+        }
+    }
+}
+
+pub trait TransferObserver: Send + Sync {
+    fn on_event(&self, event: &TransferEvent);
+}
+
+impl<F> TransferObserver for F
+where
+    F: Fn(&TransferEvent) + Send + Sync,
+{
+    fn on_event(&self, event: &TransferEvent) {
+        self(event)
+    }
+}
+
+impl TransferObserver for mpsc::UnboundedSender<TransferEvent> {
+    fn on_event(&self, event: &TransferEvent) {
+        // A dropped receiver just means nobody is listening any more.
+        let _ = self.send(event.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::fixture::client;
+    use crate::sim::{pair, LinkConfig};
+    use tempfile::TempDir;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_transfer_events() {
+        use crate::history::History;
+
+        let (src, dst) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let input = src.path().join("input.bin");
+        std::fs::write(&input, vec![7u8; 150_000]).unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let sender = client(&src, 1).with_observer(tx);
+        let receiver = client(&dst, 2);
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+
+        let manifest = sender.import_file(&input).await.unwrap();
+        let (sent, received) = tokio::join!(
+            sender.send_manifest_over(a, manifest.clone()),
+            receiver.accept_and_receive(b),
+        );
+        sent.unwrap();
+        received.unwrap();
+
+        // The receiver issued a ticket, so the next send resumes.
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (sent, received) = tokio::join!(
+            sender.send_manifest_to(a, manifest, "dev2"),
+            receiver.accept_and_receive(b),
+        );
+        sent.unwrap();
+        received.unwrap();
+        drop(sender);
+
+        let mut events = Vec::new();
+        while let Some(event) = rx.recv().await {
+            events.push(event);
+        }
+        assert!(matches!(&events[0], TransferEvent::HandshakeComplete { peer: Some(p), resumed: false, .. } if p.device_id == "dev2"));
+        assert!(matches!(events[1], TransferEvent::ManifestSent { size: 150_000, total_chunks: 3, .. }));
+        let id = events[0].transfer_id().unwrap();
+        assert_eq!(events[4], TransferEvent::ChunkSent { transfer_id: id, index: 2, total: 3, bytes: 150_000 - 2 * 65_536 });
+        assert!(matches!(events[5], TransferEvent::TransferComplete { size: 150_000, .. }));
+        assert!(matches!(events[6], TransferEvent::HandshakeComplete { resumed: true, .. }));
+        // The receiver already holds every chunk, so none are sent again.
+        assert!(matches!(events[7], TransferEvent::ManifestSent { total_chunks: 0, .. }));
+        assert_eq!(events.len(), 9);
+        assert!(events[..6].iter().all(|e| e.transfer_id() == Some(id)));
+        assert_ne!(events[6].transfer_id(), Some(id));
+
+        // The receiver's history names the same transfers.
+        let received: Vec<_> = History::new(dst.path()).load().unwrap().iter().map(|r| r.transfer_id).collect();
+        assert_eq!(received, [Some(id), events[6].transfer_id()]);
+    }
+}
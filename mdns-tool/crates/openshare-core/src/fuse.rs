@@ -0,0 +1,603 @@
+//! Mounting a [`ShareView`] with FUSE (Linux, `fuse` feature).
+//!
+//! This speaks the kernel's FUSE protocol on `/dev/fuse` itself, without
+//! libfuse. Like libfuse, it mounts with mount(2) where it may, and else
+//! through the setuid `fusermount3` (or `fusermount`) helper, which opens
+//! `/dev/fuse`, mounts it read-only on the mountpoint and passes the
+//! descriptor back over a socket. [`Mount::serve`] then answers the
+//! kernel's requests one at a time from the view, so reading a chunk that
+//! is not stored waits for the view's fetcher.
+
+use crate::vfs::{Node, NodeKind, ShareView};
+use anyhow::{Context, Result};
+use rustix::io::Errno;
+use std::collections::HashMap;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use tokio::runtime::Handle;
+
+const LOOKUP: u32 = 1;
+const FORGET: u32 = 2;
+const GETATTR: u32 = 3;
+const OPEN: u32 = 14;
+const READ: u32 = 15;
+const STATFS: u32 = 17;
+const RELEASE: u32 = 18;
+const INIT: u32 = 26;
+const OPENDIR: u32 = 27;
+const READDIR: u32 = 28;
+const RELEASEDIR: u32 = 29;
+const INTERRUPT: u32 = 36;
+const DESTROY: u32 = 38;
+const BATCH_FORGET: u32 = 42;
+
+/// Protocol version spoken; the kernel settles on the lower minor.
+const KERNEL_VERSION: u32 = 7;
+const KERNEL_MINOR: u32 = 31;
+
+const ROOT: u64 = 1;
+const IN_HEADER_LEN: usize = 40;
+const OUT_HEADER_LEN: usize = 16;
+/// Largest read the kernel asks for without `FUSE_MAX_PAGES` (32 pages).
+const MAX_READ: usize = 128 * 1024;
+/// Room for a request; the kernel wants at least 8 KiB.
+const BUFFER_LEN: usize = MAX_READ + 4096;
+/// How long the kernel may cache names and attributes: shares do not change.
+const TTL_SECS: u64 = 3600;
+const BLOCK_SIZE: u64 = 4096;
+
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+/// Options for the helper: the view is read-only, and nothing in a share
+/// should be setuid or a device.
+const MOUNT_OPTIONS: &str = "ro,nosuid,nodev,fsname=openshare,subtype=openshare";
+
+/// A share mounted on a directory, unmounted when dropped.
+pub struct Mount {
+    fd: OwnedFd,
+    mountpoint: PathBuf,
+    /// The helper that mounted it, if mount(2) was not allowed
+    helper: Option<&'static str>,
+}
+
+impl Mount {
+    /// Mount on `mountpoint`, an existing directory. Nothing is served
+    /// until [`serve`](Self::serve) runs; until then, access to the
+    /// mountpoint blocks.
+    pub fn new(mountpoint: &Path) -> Result<Self> {
+        let mountpoint = mountpoint.canonicalize()
+            .with_context(|| format!("No mountpoint {}", mountpoint.display()))?;
+        if !mountpoint.is_dir() {
+            anyhow::bail!("Mountpoint {} is not a directory", mountpoint.display());
+        }
+        let (fd, helper) = match mount_directly(&mountpoint) {
+            Ok(fd) => (fd, None),
+            Err(Errno::PERM | Errno::ACCESS) => {
+                let (fd, helper) = mount_with_helper(&mountpoint)?;
+                (fd, Some(helper))
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to mount {}", mountpoint.display())),
+        };
+        Ok(Self { fd, mountpoint, helper })
+    }
+
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    /// Answer requests for files of `view` until the share is unmounted,
+    /// by [`unmount`](Self::unmount) or `fusermount3 -u`. Blocks; reads
+    /// run on `runtime`, which must not be the caller's.
+    pub fn serve(&self, view: &ShareView, runtime: &Handle) -> Result<()> {
+        let session = Session::new(view, runtime)?;
+        let mut buffer = vec![0u8; BUFFER_LEN];
+        loop {
+            let len = match rustix::io::read(&self.fd, &mut buffer) {
+                Ok(len) => len,
+                // Interrupted, or a request withdrawn before it was read
+                Err(Errno::INTR | Errno::NOENT | Errno::AGAIN) => continue,
+                // Unmounted
+                Err(Errno::NODEV) => return Ok(()),
+                Err(e) => return Err(e).context("Failed to read from /dev/fuse"),
+            };
+            let request = &buffer[..len];
+            if let Some(reply) = session.handle(request) {
+                match rustix::io::write(&self.fd, &reply) {
+                    // NOENT: the request was interrupted meanwhile
+                    Ok(_) | Err(Errno::NOENT) => {}
+                    Err(Errno::NODEV) => return Ok(()),
+                    Err(e) => return Err(e).context("Failed to write to /dev/fuse"),
+                }
+            }
+            if request.len() >= IN_HEADER_LEN && u32_at(request, 4) == DESTROY {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Unmount, which ends [`serve`](Self::serve). Lazily: files still
+    /// open under the mountpoint do not keep it busy.
+    pub fn unmount(&self) -> Result<()> {
+        let Some(helper) = self.helper else {
+            use rustix::mount::{unmount, UnmountFlags};
+            return unmount(&self.mountpoint, UnmountFlags::DETACH)
+                .with_context(|| format!("Failed to unmount {}", self.mountpoint.display()));
+        };
+        let status = Command::new(helper)
+            .args(["-u", "-z", "--"])
+            .arg(&self.mountpoint)
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .with_context(|| format!("Failed to run {}", helper))?;
+        if !status.success() {
+            anyhow::bail!("{} could not unmount {} ({})", helper, self.mountpoint.display(), status);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Mount {
+    fn drop(&mut self) {
+        // Usually unmounted already
+        let _ = self.unmount();
+    }
+}
+
+/// Open `/dev/fuse` and mount it on `mountpoint`, which takes
+/// `CAP_SYS_ADMIN`.
+fn mount_directly(mountpoint: &Path) -> rustix::io::Result<OwnedFd> {
+    use rustix::fs::{open, Mode, OFlags};
+    use rustix::mount::{mount, MountFlags};
+
+    let fd = open("/dev/fuse", OFlags::RDWR | OFlags::CLOEXEC, Mode::empty())?;
+    let options = format!(
+        "fd={},rootmode={:o},user_id={},group_id={}",
+        fd.as_raw_fd(), S_IFDIR, rustix::process::getuid().as_raw(), rustix::process::getgid().as_raw(),
+    );
+    let options = std::ffi::CString::new(options).expect("no NUL in mount options");
+    let flags = MountFlags::RDONLY | MountFlags::NOSUID | MountFlags::NODEV;
+    mount("openshare", mountpoint, "fuse.openshare", flags, options.as_c_str())?;
+    Ok(fd)
+}
+
+/// Have `fusermount3` mount `/dev/fuse` on `mountpoint` and pass it back.
+fn mount_with_helper(mountpoint: &Path) -> Result<(OwnedFd, &'static str)> {
+    use rustix::io::{fcntl_setfd, FdFlags};
+    use rustix::net::{socketpair, AddressFamily, SocketFlags, SocketType};
+
+    let (ours, theirs) = socketpair(AddressFamily::UNIX, SocketType::STREAM, SocketFlags::CLOEXEC, None)
+        .context("Failed to create a socket for fusermount")?;
+    // The helper finds its end by number, so it has to be inherited.
+    fcntl_setfd(&theirs, FdFlags::empty())?;
+    let mut spawned = None;
+    for helper in ["fusermount3", "fusermount"] {
+        let child = Command::new(helper)
+            .arg("-o").arg(MOUNT_OPTIONS)
+            .arg("--").arg(mountpoint)
+            .env("_FUSE_COMMFD", theirs.as_raw_fd().to_string())
+            .stdin(Stdio::null())
+            .spawn();
+        match child {
+            Ok(child) => {
+                spawned = Some((helper, child));
+                break;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("Failed to run {}", helper)),
+        }
+    }
+    drop(theirs);
+    let (helper, mut child) = spawned.context("fusermount3 not found; install fuse3")?;
+    let fd = receive_fd(&ours);
+    let status = child.wait()?;
+    let fd = fd.with_context(|| format!("{} could not mount {} ({})", helper, mountpoint.display(), status))?;
+    Ok((fd, helper))
+}
+
+/// The `/dev/fuse` descriptor the helper sends, if it mounted.
+fn receive_fd(socket: &OwnedFd) -> Option<OwnedFd> {
+    use rustix::net::{recvmsg, RecvAncillaryBuffer, RecvAncillaryMessage, RecvFlags};
+    use std::io::IoSliceMut;
+    use std::mem::MaybeUninit;
+
+    let mut byte = [0u8; 1];
+    let mut space = [MaybeUninit::uninit(); rustix::cmsg_space!(ScmRights(1))];
+    let mut control = RecvAncillaryBuffer::new(&mut space);
+    recvmsg(socket, &mut [IoSliceMut::new(&mut byte)], &mut control, RecvFlags::CMSG_CLOEXEC).ok()?;
+    let fd = control.drain().find_map(|message| match message {
+        RecvAncillaryMessage::ScmRights(mut fds) => fds.next(),
+        _ => None,
+    });
+    fd
+}
+
+/// The view's nodes by inode number, from [`ROOT`] up.
+struct Inodes {
+    nodes: Vec<Node>,
+    parents: Vec<u64>,
+    children: HashMap<u64, Vec<u64>>,
+    by_path: HashMap<String, u64>,
+}
+
+impl Inodes {
+    /// Number every node of `view`. The root is always a directory; a
+    /// single-file share is the one file in it.
+    fn new(view: &ShareView) -> Result<Self> {
+        let root = Node { path: String::new(), kind: NodeKind::Dir, size: 0 };
+        let mut inodes = Self {
+            nodes: vec![root],
+            parents: vec![ROOT],
+            children: HashMap::new(),
+            by_path: HashMap::new(),
+        };
+        let mut dirs = vec![ROOT];
+        while let Some(dir) = dirs.pop() {
+            let path = inodes.node(dir).map(|n| n.path.clone()).unwrap_or_default();
+            let mut children = Vec::new();
+            for child in view.read_dir(&path)? {
+                inodes.nodes.push(child.clone());
+                inodes.parents.push(dir);
+                let ino = inodes.nodes.len() as u64;
+                if child.kind == NodeKind::Dir {
+                    dirs.push(ino);
+                }
+                inodes.by_path.insert(child.path, ino);
+                children.push(ino);
+            }
+            inodes.children.insert(dir, children);
+        }
+        Ok(inodes)
+    }
+
+    fn node(&self, ino: u64) -> Option<&Node> {
+        self.nodes.get(ino.checked_sub(1)? as usize)
+    }
+
+    fn child(&self, parent: u64, name: &str) -> Option<u64> {
+        self.children.get(&parent)?.iter().copied()
+            .find(|&ino| self.node(ino).is_some_and(|n| n.name() == name))
+    }
+}
+
+/// Answers the kernel's requests from a view.
+struct Session<'a> {
+    view: &'a ShareView,
+    runtime: &'a Handle,
+    inodes: Inodes,
+    uid: u32,
+    gid: u32,
+}
+
+impl<'a> Session<'a> {
+    fn new(view: &'a ShareView, runtime: &'a Handle) -> Result<Self> {
+        Ok(Self {
+            view,
+            runtime,
+            inodes: Inodes::new(view)?,
+            uid: rustix::process::getuid().as_raw(),
+            gid: rustix::process::getgid().as_raw(),
+        })
+    }
+
+    /// The reply to `request`, or `None` for requests that get none.
+    fn handle(&self, request: &[u8]) -> Option<Vec<u8>> {
+        if request.len() < IN_HEADER_LEN {
+            return None;
+        }
+        let (opcode, unique, ino) = (u32_at(request, 4), u64_at(request, 8), u64_at(request, 16));
+        let end = (u32_at(request, 0) as usize).clamp(IN_HEADER_LEN, request.len());
+        let arg = &request[IN_HEADER_LEN..end];
+        let result = match opcode {
+            FORGET | BATCH_FORGET | INTERRUPT => return None,
+            INIT => self.init(arg),
+            LOOKUP => self.lookup(ino, arg),
+            GETATTR => self.getattr(ino),
+            OPEN => self.open(ino, NodeKind::File),
+            OPENDIR => self.open(ino, NodeKind::Dir),
+            READ => self.read(ino, arg),
+            READDIR => self.read_dir(ino, arg),
+            STATFS => Ok(self.statfs()),
+            RELEASE | RELEASEDIR | DESTROY => Ok(Vec::new()),
+            _ => Err(Errno::NOSYS),
+        };
+        Some(reply(unique, result))
+    }
+
+    fn init(&self, arg: &[u8]) -> Result<Vec<u8>, Errno> {
+        if arg.len() < 16 {
+            return Err(Errno::INVAL);
+        }
+        let (major, minor, readahead) = (u32_at(arg, 0), u32_at(arg, 4), u32_at(arg, 8));
+        if major < KERNEL_VERSION {
+            return Err(Errno::PROTO);
+        }
+        let mut out = Vec::with_capacity(64);
+        put_u32(&mut out, KERNEL_VERSION);
+        put_u32(&mut out, if major > KERNEL_VERSION { KERNEL_MINOR } else { minor.min(KERNEL_MINOR) });
+        put_u32(&mut out, readahead);
+        put_u32(&mut out, 0); // flags
+        put_u16(&mut out, 16); // max_background
+        put_u16(&mut out, 12); // congestion_threshold
+        put_u32(&mut out, 4096); // max_write
+        put_u32(&mut out, 1); // time_gran
+        out.resize(64, 0);
+        Ok(out)
+    }
+
+    fn lookup(&self, parent: u64, arg: &[u8]) -> Result<Vec<u8>, Errno> {
+        let name = arg.split(|&b| b == 0).next().unwrap_or_default();
+        let name = std::str::from_utf8(name).map_err(|_| Errno::NOENT)?;
+        let ino = self.inodes.child(parent, name).ok_or(Errno::NOENT)?;
+        let mut out = Vec::with_capacity(128);
+        put_u64(&mut out, ino);
+        put_u64(&mut out, 0); // generation
+        put_u64(&mut out, TTL_SECS); // entry_valid
+        put_u64(&mut out, TTL_SECS); // attr_valid
+        put_u32(&mut out, 0);
+        put_u32(&mut out, 0);
+        self.attr(&mut out, ino)?;
+        Ok(out)
+    }
+
+    fn getattr(&self, ino: u64) -> Result<Vec<u8>, Errno> {
+        let mut out = Vec::with_capacity(104);
+        put_u64(&mut out, TTL_SECS);
+        put_u32(&mut out, 0);
+        put_u32(&mut out, 0);
+        self.attr(&mut out, ino)?;
+        Ok(out)
+    }
+
+    /// Append `fuse_attr` for `ino`.
+    fn attr(&self, out: &mut Vec<u8>, ino: u64) -> Result<(), Errno> {
+        let node = self.inodes.node(ino).ok_or(Errno::NOENT)?;
+        let manifest = self.view.manifest();
+        // Metadata names the top of a share with an empty path.
+        let meta_path = if manifest.is_directory() { node.path.as_str() } else { "" };
+        let meta = manifest.metadata.iter().find(|m| m.path == meta_path);
+        let modified = meta.and_then(|m| m.modified).unwrap_or(0);
+        let (secs, nanos) = (modified / 1_000_000_000, (modified % 1_000_000_000) as u32);
+        let (mode, nlink) = match node.kind {
+            NodeKind::Dir => (S_IFDIR | 0o555, 2),
+            NodeKind::File => (S_IFREG | meta.and_then(|m| m.mode).map_or(0o444, |m| m & 0o555), 1),
+        };
+        put_u64(out, ino);
+        put_u64(out, node.size);
+        put_u64(out, node.size.div_ceil(512));
+        for _ in 0..3 {
+            put_u64(out, secs);
+        }
+        for _ in 0..3 {
+            put_u32(out, nanos);
+        }
+        put_u32(out, mode);
+        put_u32(out, nlink);
+        put_u32(out, self.uid);
+        put_u32(out, self.gid);
+        put_u32(out, 0); // rdev
+        put_u32(out, BLOCK_SIZE as u32);
+        put_u32(out, 0); // flags
+        Ok(())
+    }
+
+    fn open(&self, ino: u64, kind: NodeKind) -> Result<Vec<u8>, Errno> {
+        match self.inodes.node(ino) {
+            None => return Err(Errno::NOENT),
+            Some(n) if n.kind == kind => {}
+            Some(_) if kind == NodeKind::Dir => return Err(Errno::NOTDIR),
+            Some(_) => return Err(Errno::ISDIR),
+        }
+        let mut out = Vec::with_capacity(16);
+        put_u64(&mut out, 0); // fh
+        put_u32(&mut out, FOPEN_KEEP_CACHE);
+        put_u32(&mut out, 0);
+        Ok(out)
+    }
+
+    fn read(&self, ino: u64, arg: &[u8]) -> Result<Vec<u8>, Errno> {
+        if arg.len() < 20 {
+            return Err(Errno::INVAL);
+        }
+        let (offset, size) = (u64_at(arg, 8), (u32_at(arg, 16) as usize).min(MAX_READ));
+        let node = self.inodes.node(ino).ok_or(Errno::NOENT)?;
+        if node.kind == NodeKind::Dir {
+            return Err(Errno::ISDIR);
+        }
+        self.runtime.block_on(self.view.read(&node.path, offset, size)).map_err(|e| {
+            tracing::warn!("Failed to read {} at {}: {:#}", node.path, offset, e);
+            Errno::IO
+        })
+    }
+
+    /// Entries of a directory from the offset the kernel got to, as
+    /// `fuse_dirent`s that fit in the size asked for.
+    fn read_dir(&self, ino: u64, arg: &[u8]) -> Result<Vec<u8>, Errno> {
+        if arg.len() < 20 {
+            return Err(Errno::INVAL);
+        }
+        let (offset, size) = (u64_at(arg, 8), u32_at(arg, 16) as usize);
+        let children = self.inodes.children.get(&ino).ok_or(Errno::NOTDIR)?;
+        let parent = self.inodes.parents[ino as usize - 1];
+        let entries = [(ino, ".", NodeKind::Dir), (parent, "..", NodeKind::Dir)].into_iter()
+            .chain(children.iter().filter_map(|&c| self.inodes.node(c).map(|n| (c, n.name(), n.kind))));
+        let mut out = Vec::new();
+        for (i, (ino, name, kind)) in entries.enumerate().skip(offset as usize) {
+            let len = (24 + name.len()).next_multiple_of(8);
+            if out.len() + len > size {
+                break;
+            }
+            put_u64(&mut out, ino);
+            put_u64(&mut out, i as u64 + 1); // offset of the next entry
+            put_u32(&mut out, name.len() as u32);
+            put_u32(&mut out, if kind == NodeKind::Dir { S_IFDIR >> 12 } else { S_IFREG >> 12 });
+            out.extend_from_slice(name.as_bytes());
+            out.resize(out.len().next_multiple_of(8), 0);
+        }
+        Ok(out)
+    }
+
+    fn statfs(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(80);
+        put_u64(&mut out, self.view.manifest().size.div_ceil(BLOCK_SIZE)); // blocks
+        put_u64(&mut out, 0); // bfree
+        put_u64(&mut out, 0); // bavail
+        put_u64(&mut out, self.inodes.nodes.len() as u64); // files
+        put_u64(&mut out, 0); // ffree
+        put_u32(&mut out, BLOCK_SIZE as u32);
+        put_u32(&mut out, 255); // namelen
+        put_u32(&mut out, BLOCK_SIZE as u32); // frsize
+        out.resize(80, 0);
+        out
+    }
+}
+
+/// A reply: `fuse_out_header`, then the payload or nothing for an error.
+fn reply(unique: u64, result: Result<Vec<u8>, Errno>) -> Vec<u8> {
+    let (error, payload) = match result {
+        Ok(payload) => (0, payload),
+        Err(errno) => (-errno.raw_os_error(), Vec::new()),
+    };
+    let mut out = Vec::with_capacity(OUT_HEADER_LEN + payload.len());
+    put_u32(&mut out, (OUT_HEADER_LEN + payload.len()) as u32);
+    out.extend_from_slice(&error.to_ne_bytes());
+    put_u64(&mut out, unique);
+    out.extend_from_slice(&payload);
+    out
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_ne_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    u64::from_ne_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_ne_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_ne_bytes());
+}
+
+fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_ne_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Manifest;
+    use std::sync::Arc;
+    use storage::{LocalStorage, Storage};
+    use tempfile::TempDir;
+
+    fn request(opcode: u32, ino: u64, arg: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        put_u32(&mut out, (IN_HEADER_LEN + arg.len()) as u32);
+        put_u32(&mut out, opcode);
+        put_u64(&mut out, 7); // unique
+        put_u64(&mut out, ino);
+        out.resize(IN_HEADER_LEN, 0);
+        out.extend_from_slice(arg);
+        out
+    }
+
+    fn read_in(offset: u64, size: u32) -> Vec<u8> {
+        let mut arg = Vec::new();
+        put_u64(&mut arg, 0);
+        put_u64(&mut arg, offset);
+        put_u32(&mut arg, size);
+        arg.resize(40, 0);
+        arg
+    }
+
+    /// The error and payload of a reply to `request`.
+    fn call(session: &Session, request: Vec<u8>) -> (i32, Vec<u8>) {
+        let reply = session.handle(&request).expect("a reply");
+        assert_eq!(u32_at(&reply, 0) as usize, reply.len());
+        assert_eq!(u64_at(&reply, 8), 7);
+        (u32_at(&reply, 4) as i32, reply[OUT_HEADER_LEN..].to_vec())
+    }
+
+    fn names(dirents: &[u8]) -> Vec<String> {
+        let (mut names, mut at) = (Vec::new(), 0);
+        while at < dirents.len() {
+            let len = u32_at(dirents, at + 16) as usize;
+            names.push(String::from_utf8(dirents[at + 24..at + 24 + len].to_vec()).unwrap());
+            at += (24 + len).next_multiple_of(8);
+        }
+        names
+    }
+
+    #[test]
+    fn test_requests_answered_from_view() -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        let tmp = TempDir::new()?;
+        let root = tmp.path().join("share");
+        std::fs::create_dir_all(root.join("docs"))?;
+        std::fs::write(root.join("docs/a.txt"), b"0123456789")?;
+        std::fs::write(root.join("b.txt"), b"xyz")?;
+        let manifest = Manifest::from_dir(&root, 4)?;
+        let storage = Arc::new(LocalStorage::new(tmp.path().join("chunks"))?);
+        runtime.block_on(async {
+            for file in ["docs/a.txt", "b.txt"] {
+                for chunk in std::fs::read(root.join(file))?.chunks(4) {
+                    storage.put_chunk(chunk).await?;
+                }
+            }
+            anyhow::Ok(())
+        })?;
+        let view = ShareView::new(manifest, storage.clone());
+        let session = Session::new(&view, runtime.handle())?;
+
+        let mut init = Vec::new();
+        for value in [7, 38, 65536, 0] {
+            put_u32(&mut init, value);
+        }
+        let (error, out) = call(&session, request(INIT, 0, &init));
+        assert_eq!((error, out.len(), u32_at(&out, 4)), (0, 64, KERNEL_MINOR));
+
+        let (error, out) = call(&session, request(READDIR, ROOT, &read_in(0, 4096)));
+        assert_eq!(error, 0);
+        assert_eq!(names(&out), [".", "..", "b.txt", "docs"]);
+        let (_, out) = call(&session, request(READDIR, ROOT, &read_in(3, 4096)));
+        assert_eq!(names(&out), ["docs"]);
+
+        let (error, out) = call(&session, request(LOOKUP, ROOT, b"docs\0"));
+        assert_eq!(error, 0);
+        let docs = u64_at(&out, 0);
+        assert_eq!(u32_at(&out, 40 + 60) & S_IFDIR, S_IFDIR);
+        let (_, out) = call(&session, request(LOOKUP, docs, b"a.txt\0"));
+        let file = u64_at(&out, 0);
+        assert_eq!(u64_at(&out, 40 + 8), 10);
+        let (error, _) = call(&session, request(LOOKUP, docs, b"b.txt\0"));
+        assert_eq!(error, -Errno::NOENT.raw_os_error());
+
+        let (error, out) = call(&session, request(READ, file, &read_in(5, 4)));
+        assert_eq!((error, out.as_slice()), (0, &b"5678"[..]));
+        let (error, _) = call(&session, request(OPEN, docs, &[0; 8]));
+        assert_eq!(error, -Errno::ISDIR.raw_os_error());
+        let (error, _) = call(&session, request(OPENDIR, file, &[0; 8]));
+        assert_eq!(error, -Errno::NOTDIR.raw_os_error());
+        let (error, _) = call(&session, request(99, ROOT, &[]));
+        assert_eq!(error, -Errno::NOSYS.raw_os_error());
+        assert!(session.handle(&request(FORGET, file, &[0; 8])).is_none());
+
+        // A single file sits alone under the mountpoint.
+        let single = Manifest::from_file(root.join("b.txt").to_str().unwrap(), 4)?;
+        let view = ShareView::new(single, storage);
+        let session = Session::new(&view, runtime.handle())?;
+        let (_, out) = call(&session, request(READDIR, ROOT, &read_in(0, 4096)));
+        assert_eq!(names(&out), [".", "..", "b.txt"]);
+        let (_, out) = call(&session, request(LOOKUP, ROOT, b"b.txt\0"));
+        let (_, out) = call(&session, request(READ, u64_at(&out, 0), &read_in(0, 100)));
+        assert_eq!(out, b"xyz");
+        Ok(())
+    }
+}
@@ -1,43 +1,427 @@
 //! Handshake implementation.
 //!
 //! - Uses ephemeral X25519 keys + Ed25519 signatures over ephemeral pubkey||nonce
-//!   to prevent MitM in local discovery spoofing scenarios.
+//!   to prevent MitM in local discovery spoofing scenarios. From
+//!   `signed-hello` on, the signature covers the trailing hello as well, so
+//!   its features cannot be stripped in transit to turn off protections
+//!   such as `channel-binding`; a hello that claims such a build but only
+//!   signs pubkey||nonce fails the handshake.
 //! - Derives a 32-byte session key via HKDF-SHA256(shared_secret || transcripts)
 //! - Produces an XChaCha20-Poly1305 AEAD for subsequent encrypted framing.
+//! - Derives a separate exporter secret bound to the handshake transcript for
+//!   `Session::export_keying_material`.
+//! - Each message may carry a trailing `Hello` with the sender's identity key
+//!   and device ID; when present the signature is verified against that key
+//!   and the result is exposed on the `Session` for trust decisions.
+//! - A `HelloExt` after the `Hello` advertises protocol and app versions and
+//!   optional features. Peers that predate it send none and are treated as
+//!   protocol 0 with no optional features, so callers can fall back instead
+//!   of failing on frames the peer cannot decode.
+//! - Peers with `clock` follow the `HelloExt` with their wall-clock time,
+//!   from which each side learns how far the other's clock is off
+//!   (`PeerInfo::clock_skew_secs`).
+//! - When both sides offer `frame-counters`, each direction gets its own
+//!   key (HKDF with direction labels) and frames carry a 64-bit counter as
+//!   the nonce; a replayed, dropped or reordered frame fails to read. Older
+//!   peers keep the shared key with random per-frame nonces.
+//! - When both sides offer `channel-binding`, a full handshake re-keys the
+//!   session over the transcript, both static Ed25519 keys and both
+//!   protocol versions, then each side sends an HMAC key confirmation that
+//!   the other checks before any data flows. A peer that derived a
+//!   different key (or saw different identities) fails the handshake
+//!   instead of the first frame.
+//! - Peers that both offer `noise-xx` may run the full handshake as Noise
+//!   XX instead (see `noise`); the resulting `Session` frames the same way.
 
-use crate::keys::Identity;
+use crate::env::Env;
+use crate::account::DeviceCertificate;
+use crate::keys::{self, Identity, KeyRotation};
+use crate::noise::{self, NOISE_MAGIC};
+use crate::transfer::TransferId;
+use ed25519_dalek::Signature;
+use serde::{Deserialize, Serialize};
 use anyhow::Result;
 use chacha20poly1305::{XChaCha20Poly1305, KeyInit, XNonce};
 use chacha20poly1305::aead::AeadInPlace;
 use hkdf::Hkdf;
-use rand_core::{OsRng, RngCore};
-use sha2::Sha256;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
 use std::convert::TryInto;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
 use x25519_dalek::{EphemeralSecret, PublicKey as X25519Public};
 use tokio::io::{AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt};
 use thiserror::Error;
+use zeroize::Zeroizing;
 
 /// Fixed lengths
 const PUBKEY_LEN: usize = 32;
 const NONCE_LEN: usize = 32;
 const SIG_LEN: usize = 64;
 
-/// Session holds the AEAD and the raw derived key
+/// Session holds the AEAD and the raw derived key. Key material is
+/// cleared from memory when the session is dropped.
 pub struct Session {
     pub aead: XChaCha20Poly1305,
-    pub session_key: [u8; 32],
+    pub session_key: Zeroizing<[u8; 32]>,
+    /// SHA-256 over both handshake messages (initiator first), or the
+    /// Noise handshake hash
+    pub transcript_hash: [u8; 32],
+    exporter_secret: Zeroizing<[u8; 32]>,
+    /// Authenticated peer identity; `None` for peers that send no `Hello`
+    pub peer: Option<PeerInfo>,
+    /// Whether the session was established from a resumption ticket
+    pub resumed: bool,
+    /// Directional keys when counter framing was negotiated
+    counters: Option<FrameCounters>,
+    env: Env,
 }
 
+/// Per-direction keys and frame counters (`FEATURE_FRAME_COUNTERS`).
+struct FrameCounters {
+    send: Mutex<XChaCha20Poly1305>,
+    recv: Mutex<XChaCha20Poly1305>,
+    sent: AtomicU64,
+    received: AtomicU64,
+    /// Frames after which a direction's key is replaced; Noise sessions only
+    rekey_every: Option<u64>,
+}
+
+impl FrameCounters {
+    fn new(send: XChaCha20Poly1305, recv: XChaCha20Poly1305, rekey_every: Option<u64>) -> Self {
+        Self {
+            send: Mutex::new(send),
+            recv: Mutex::new(recv),
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            rekey_every,
+        }
+    }
+
+    /// Whether the key is due for replacement once frame `n` is through.
+    fn rekey_after(&self, n: u64) -> bool {
+        self.rekey_every.is_some_and(|every| (n + 1).is_multiple_of(every))
+    }
+}
+
+/// HKDF labels for the two traffic directions.
+const INITIATOR_TO_RESPONDER: &[u8] = b"openshare frames initiator->responder v1";
+const RESPONDER_TO_INITIATOR: &[u8] = b"openshare frames responder->initiator v1";
+
+/// Length of the explicit frame counter in counter framing.
+const COUNTER_LEN: usize = 8;
+
+/// Frames a Noise session sends in each direction under one key.
+const REKEY_INTERVAL: u64 = 1 << 20;
+
+/// Largest frame either side reads, nonce or counter and tag included.
+pub const MAX_FRAME_LEN: usize = 10 * 1024 * 1024;
+
+/// Largest plaintext sure to fit in a frame: room for a 24-byte nonce and
+/// the 16-byte tag.
+pub const MAX_FRAME_PLAINTEXT: usize = MAX_FRAME_LEN - 40;
+
+/// Domain separator for the channel binding hash.
+const CHANNEL_BINDING_LABEL: &[u8] = b"openshare channel binding v1";
+/// HKDF info for the bound traffic key.
+const SESSION_LABEL: &[u8] = b"openshare session v2";
+/// HKDF info for the key confirmation MAC key.
+const CONFIRM_LABEL: &[u8] = b"openshare key confirmation v1";
+/// What each side's confirmation MAC covers besides the transcript.
+const INITIATOR_FINISHED: &[u8] = b"initiator finished";
+const RESPONDER_FINISHED: &[u8] = b"responder finished";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Identity information appended after the fixed handshake fields.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hello {
+    pub identity_key: [u8; 32],
+    pub device_id: String,
+}
+
+/// Version information appended after the `Hello`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HelloExt {
+    pub protocol_version: u16,
+    pub app_version: String,
+    pub features: Vec<String>,
+}
+
+/// Wire protocol spoken by this build. Peers without a `HelloExt` are 0.
+pub const PROTOCOL_VERSION: u16 = 30;
+
+/// Oldest peer protocol this build can still transfer with.
+pub const MIN_PROTOCOL_VERSION: u16 = 0;
+
+/// Primitives of the only cipher suite; it is fixed, not negotiated.
+pub const CIPHER_SUITE: &[&str] = &["X25519", "Ed25519", "HKDF-SHA256", "XChaCha20-Poly1305"];
+
+/// Index-tagged chunk frames with per-chunk acknowledgments.
+pub const FEATURE_CHUNK_ACKS: &str = "chunk-acks";
+/// Resumption tickets and 0-RTT manifest offers.
+pub const FEATURE_RESUMPTION: &str = "resumption";
+/// Receiver-initiated transfers of published manifests.
+pub const FEATURE_PULL: &str = "pull";
+/// Manifests carry and sign a whole-file hash.
+pub const FEATURE_FILE_HASH: &str = "file-hash";
+/// Directional keys and counter nonces with replay rejection.
+pub const FEATURE_FRAME_COUNTERS: &str = "frame-counters";
+/// Session keys bound to both identities, plus key confirmation.
+pub const FEATURE_CHANNEL_BINDING: &str = "channel-binding";
+/// Pull requests for the manifest alone or selected chunks.
+pub const FEATURE_PARTIAL_PULL: &str = "partial-pull";
+/// Content index exchange between devices of one account.
+pub const FEATURE_CONTENT_INDEX: &str = "content-index";
+/// Receivers list the chunks of a pushed manifest they already hold, and
+/// senders skip them.
+pub const FEATURE_CHUNK_PROBE: &str = "chunk-probe";
+/// Chunk frames name a per-chunk codec (see `codec`).
+pub const FEATURE_COMPRESSION: &str = "compression";
+/// LZ4-compressed chunks can be decoded.
+pub const FEATURE_CODEC_LZ4: &str = "codec-lz4";
+/// zstd-compressed chunks can be decoded; only offered by builds with the
+/// `codec-zstd` cargo feature.
+pub const FEATURE_CODEC_ZSTD: &str = "codec-zstd";
+/// Announcements of newly published shares.
+pub const FEATURE_SHARE_ANNOUNCE: &str = "share-announce";
+/// Senders say so when they cancel a transfer.
+pub const FEATURE_CANCEL: &str = "cancel";
+/// Manifests may be signed with Ed25519ph over a streamed hash.
+pub const FEATURE_PREHASHED_MANIFESTS: &str = "manifest-ph";
+/// Large manifests may be sent as a signed header and pages (see `pages`).
+pub const FEATURE_MANIFEST_PAGES: &str = "manifest-pages";
+/// Either side says why it gave up on a failed transfer.
+pub const FEATURE_ABORT_REASON: &str = "abort-reason";
+/// Pushed chunks the receiver rejects are sent again.
+pub const FEATURE_CHUNK_RETRY: &str = "chunk-retry";
+/// The receiver of a push accepts or rejects the manifest before chunks flow.
+pub const FEATURE_TRANSFER_CONSENT: &str = "transfer-consent";
+/// A push may open with a sync header; the receiver then also counts the
+/// chunks of the files it would replace as held.
+pub const FEATURE_DELTA_SYNC: &str = "delta-sync";
+/// A push may open with a chunk key header; its chunk ids are then keyed
+/// with that account secret (see `keyed`).
+pub const FEATURE_KEYED_CHUNKS: &str = "keyed-chunks";
+/// Manifests may carry file metadata (times, permissions, symlinks) after
+/// the rest of their encoding, covered by the signature.
+pub const FEATURE_FILE_METADATA: &str = "file-metadata";
+/// The hello carries the sender's clock, so skew between devices shows.
+pub const FEATURE_CLOCK: &str = "clock";
+/// Several pushes may share one session, announced by a batch header.
+pub const FEATURE_BATCH: &str = "batch";
+/// Takes signed requests to manage its daemon from devices trusted as
+/// admins (see `admin`).
+pub const FEATURE_ADMIN: &str = "admin";
+/// The hello carries the history of a rotated identity key, so peers
+/// that pinned an earlier key can follow it (see `Identity::rotate`).
+pub const FEATURE_KEY_ROTATION: &str = "key-rotation";
+/// The hello carries the device's certificate from its account root key,
+/// if it has one (see `account`).
+pub const FEATURE_DEVICE_CERTS: &str = "device-certs";
+/// The full handshake may run as Noise XX, with REKEY on both directions'
+/// frame keys (see `noise`).
+pub const FEATURE_NOISE: &str = "noise-xx";
+/// Partial pulls may ask for a signed manifest root and Merkle proofs
+/// instead of the manifest (see `merkle`).
+pub const FEATURE_CHUNK_PROOFS: &str = "chunk-proofs";
+/// Manifests record the size of fixed chunks, covered by the signature.
+pub const FEATURE_CHUNK_SIZE: &str = "chunk-size";
+/// Takes short texts sent inline, without a file (see `TextMessage`).
+pub const FEATURE_TEXT: &str = "text";
+/// Takes pushes of unknown size, signed at the end (see `crate::stream`).
+pub const FEATURE_STREAM: &str = "stream";
+/// The handshake signature covers the hello, and with it these features.
+pub const FEATURE_SIGNED_HELLO: &str = "signed-hello";
+/// Manifests may carry a [`Preview`](crate::preview::Preview) of an image.
+pub const FEATURE_PREVIEWS: &str = "previews";
+
+/// Optional features this build offers.
+pub const FEATURES: &[&str] = &[
+    FEATURE_CHUNK_ACKS,
+    FEATURE_RESUMPTION,
+    FEATURE_PULL,
+    FEATURE_FILE_HASH,
+    FEATURE_FRAME_COUNTERS,
+    FEATURE_CHANNEL_BINDING,
+    FEATURE_PARTIAL_PULL,
+    FEATURE_CONTENT_INDEX,
+    FEATURE_CHUNK_PROBE,
+    FEATURE_COMPRESSION,
+    FEATURE_CODEC_LZ4,
+    FEATURE_SHARE_ANNOUNCE,
+    FEATURE_CANCEL,
+    FEATURE_PREHASHED_MANIFESTS,
+    FEATURE_MANIFEST_PAGES,
+    FEATURE_ABORT_REASON,
+    FEATURE_CHUNK_RETRY,
+    FEATURE_TRANSFER_CONSENT,
+    FEATURE_DELTA_SYNC,
+    FEATURE_KEYED_CHUNKS,
+    FEATURE_FILE_METADATA,
+    FEATURE_CLOCK,
+    FEATURE_BATCH,
+    FEATURE_ADMIN,
+    FEATURE_KEY_ROTATION,
+    FEATURE_DEVICE_CERTS,
+    FEATURE_NOISE,
+    FEATURE_CHUNK_PROOFS,
+    FEATURE_CHUNK_SIZE,
+    FEATURE_TEXT,
+    FEATURE_STREAM,
+    FEATURE_SIGNED_HELLO,
+    FEATURE_PREVIEWS,
+    #[cfg(feature = "codec-zstd")]
+    FEATURE_CODEC_ZSTD,
+];
+
+/// Protocol version that introduced `feature`, or `None` if this build
+/// does not know it. Every entry of [`FEATURES`] needs one.
+pub fn introduced_in(feature: &str) -> Option<u16> {
+    Some(match feature {
+        FEATURE_CHUNK_ACKS | FEATURE_RESUMPTION => 1,
+        FEATURE_PULL => 2,
+        FEATURE_FILE_HASH => 3,
+        FEATURE_FRAME_COUNTERS => 4,
+        FEATURE_CHANNEL_BINDING => 5,
+        FEATURE_PARTIAL_PULL => 6,
+        FEATURE_CONTENT_INDEX => 7,
+        FEATURE_CHUNK_PROBE => 8,
+        FEATURE_COMPRESSION | FEATURE_CODEC_LZ4 => 9,
+        FEATURE_SHARE_ANNOUNCE => 10,
+        FEATURE_CANCEL => 11,
+        FEATURE_PREHASHED_MANIFESTS => 12,
+        FEATURE_MANIFEST_PAGES => 13,
+        FEATURE_ABORT_REASON => 14,
+        FEATURE_CHUNK_RETRY => 15,
+        FEATURE_TRANSFER_CONSENT | FEATURE_CODEC_ZSTD => 16,
+        FEATURE_DELTA_SYNC | FEATURE_PREVIEWS => 17,
+        FEATURE_KEYED_CHUNKS => 18,
+        FEATURE_FILE_METADATA => 19,
+        FEATURE_CLOCK => 20,
+        FEATURE_BATCH => 21,
+        FEATURE_ADMIN => 22,
+        FEATURE_KEY_ROTATION => 23,
+        FEATURE_DEVICE_CERTS => 24,
+        FEATURE_NOISE => 25,
+        FEATURE_CHUNK_PROOFS => 26,
+        FEATURE_CHUNK_SIZE => 27,
+        FEATURE_TEXT => 28,
+        FEATURE_STREAM => 29,
+        FEATURE_SIGNED_HELLO => 30,
+        _ => return None,
+    })
+}
+
+/// Features a build speaking protocol `version` offers; see
+/// [`Env::with_protocol_version`].
+pub fn features_at(version: u16) -> Vec<&'static str> {
+    FEATURES.iter().copied().filter(|f| introduced_in(f).is_some_and(|v| v <= version)).collect()
+}
+
+/// Peer identity learned (and signature-checked) during the handshake.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub public_key: [u8; 32],
+    pub device_id: String,
+    /// 0 for peers that predate version negotiation
+    pub protocol_version: u16,
+    pub app_version: Option<String>,
+    pub features: Vec<String>,
+    /// Seconds the peer's clock was ahead of ours (negative if behind)
+    /// during the handshake; `None` for peers without `clock`
+    #[serde(default)]
+    pub clock_skew_secs: Option<i64>,
+    /// Verified rotations leading to `public_key`, oldest first; empty if
+    /// the key was never rotated or the peer predates `key-rotation`
+    #[serde(default)]
+    pub key_history: Vec<KeyRotation>,
+    /// Account certificate the peer presented, not yet checked against an
+    /// account root (see [`crate::account::verify_peer`])
+    #[serde(default)]
+    pub certificate: Option<DeviceCertificate>,
+}
+
+impl PeerInfo {
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+
+    /// Features this build offers that the peer lacks, and which are
+    /// therefore disabled for the session.
+    pub fn disabled_features(&self) -> Vec<&'static str> {
+        FEATURES.iter().copied().filter(|f| !self.supports(f)).collect()
+    }
+
+    /// Clock skew beyond `tolerance_secs`, which is left to network delay
+    /// and small drift.
+    pub fn clock_skew_beyond(&self, tolerance_secs: u64) -> Option<i64> {
+        self.clock_skew_secs.filter(|skew| skew.unsigned_abs() > tolerance_secs)
+    }
+
+    /// Features both this build and the peer offer, i.e. those in use.
+    pub fn shared_features(&self) -> Vec<&'static str> {
+        FEATURES.iter().copied().filter(|f| self.supports(f)).collect()
+    }
+
+    // Always passes while MIN_PROTOCOL_VERSION is 0; kept so raising it is
+    // the only change needed when legacy peers are dropped.
+    #[allow(clippy::absurd_extreme_comparisons)]
+    pub fn check_compatible(&self) -> Result<(), HandshakeError> {
+        if self.protocol_version < MIN_PROTOCOL_VERSION {
+            return Err(HandshakeError::IncompatibleVersion {
+                device_id: self.device_id.clone(),
+                version: self.protocol_version,
+                app_version: self.app_version.clone().unwrap_or_else(|| "unknown".into()),
+                min_version: MIN_PROTOCOL_VERSION,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Refuse a session unless the peer's identity key has `fingerprint`
+/// (see [`Identity::fingerprint_matches`]), e.g. the one it advertised
+/// over mDNS. Called right after the handshake, before anything else is
+/// exchanged, so a spoofed advertisement gets no data.
+pub fn expect_fingerprint(peer: Option<&PeerInfo>, fingerprint: &str) -> Result<(), HandshakeError> {
+    let peer = peer.ok_or_else(|| {
+        HandshakeError::UnexpectedPeer(format!("peer did not identify itself, expected fingerprint {}", fingerprint))
+    })?;
+    if !Identity::fingerprint_matches(&peer.public_key, fingerprint) {
+        return Err(HandshakeError::UnexpectedPeer(format!(
+            "{} has key {}, not fingerprint {}",
+            peer.device_id, hex::encode(peer.public_key), fingerprint,
+        )));
+    }
+    Ok(())
+}
+
+/// HKDF info prefix for the exporter secret.
+const EXPORTER_LABEL: &[u8] = b"openshare exporter v1";
+
+/// Exporter label for the short authentication string.
+const SAS_LABEL: &[u8] = b"openshare sas v1";
+
+/// Exporter label for the transfer ID.
+const TRANSFER_ID_LABEL: &[u8] = b"openshare transfer id v1";
+
 #[derive(Error, Debug)]
 pub enum HandshakeError {
     #[error("io error: {0}")]
     Io(#[from] std::io::Error),
     #[error("crypto error: {0}")]
     Crypto(String),
+    /// The peer's protocol is older than [`MIN_PROTOCOL_VERSION`]; newer
+    /// ones are always taken, with the features both offer
+    #[error("incompatible peer: {device_id} speaks protocol {version} (app {app_version}), this build needs at least {min_version}")]
+    IncompatibleVersion { device_id: String, version: u16, app_version: String, min_version: u16 },
+    #[error("unexpected peer: {0}")]
+    UnexpectedPeer(String),
 }
 
 /// Minimal length-prefixed frame helpers (u32 BE length).
-async fn write_lp<T: AsyncWrite + Unpin + Send>(
+pub(crate) async fn write_lp<T: AsyncWrite + Unpin + Send>(
     transport: &mut T,
     data: &[u8]
 ) -> std::io::Result<()> {
@@ -47,7 +431,7 @@ async fn write_lp<T: AsyncWrite + Unpin + Send>(
     Ok(())
 }
 
-async fn read_lp<T: AsyncRead + Unpin + Send>(
+pub(crate) async fn read_lp<T: AsyncRead + Unpin + Send>(
     transport: &mut T
 ) -> std::io::Result<Vec<u8>> {
     let mut lenb = [0u8; 4];
@@ -55,7 +439,7 @@ async fn read_lp<T: AsyncRead + Unpin + Send>(
     let len = u32::from_be_bytes(lenb) as usize;
 
     // Sanity check to prevent memory exhaustion
-    if len > 10 * 1024 * 1024 {
+    if len > MAX_FRAME_LEN {
         return Err(std::io::Error::new(
             std::io::ErrorKind::InvalidData,
             "message too large"
@@ -72,28 +456,34 @@ pub async fn initiator_handshake<T>(
     identity: &Identity,
     transport: &mut T
 ) -> Result<Session, HandshakeError>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    initiator_handshake_with(identity, "", transport, &Env::system()).await
+}
+
+/// Initiator side handshake announcing `device_id` and drawing ephemerals
+/// and nonces from `env`.
+pub async fn initiator_handshake_with<T>(
+    identity: &Identity,
+    device_id: &str,
+    transport: &mut T,
+    env: &Env,
+) -> Result<Session, HandshakeError>
 where
     T: AsyncRead + AsyncWrite + Unpin + Send,
 {
     // 1) Generate ephemeral x25519 keypair
-    let x_secret = EphemeralSecret::random_from_rng(OsRng);
+    let x_secret = env.with_rng(|rng| EphemeralSecret::random_from_rng(rng));
     let x_pub = X25519Public::from(&x_secret);
 
     // 2) Generate nonceA
     let mut nonce_a = [0u8; NONCE_LEN];
-    OsRng.fill_bytes(&mut nonce_a);
-
-    // 3) Sign x_pub || nonceA with ed25519 identity key
-    let mut to_sign = Vec::with_capacity(PUBKEY_LEN + NONCE_LEN);
-    to_sign.extend_from_slice(x_pub.as_bytes());
-    to_sign.extend_from_slice(&nonce_a);
-    let sig = identity.sign(&to_sign);
-
-    // 4) Send messageA = x_pub || nonceA || sig
-    let mut message_a = Vec::with_capacity(PUBKEY_LEN + NONCE_LEN + SIG_LEN);
-    message_a.extend_from_slice(x_pub.as_bytes());
-    message_a.extend_from_slice(&nonce_a);
-    message_a.extend_from_slice(&sig.to_bytes());
+    env.fill_bytes(&mut nonce_a);
+
+    // 3) Sign x_pub || nonceA (and the hello) with ed25519 identity key,
+    // 4) and send messageA = x_pub || nonceA || sig || hello
+    let message_a = signed_message(identity, device_id, x_pub.as_bytes(), &nonce_a, env)?;
     write_lp(transport, &message_a).await.map_err(HandshakeError::Io)?;
 
     // 5) Receive messageB
@@ -105,25 +495,15 @@ where
     let x_b_bytes: [u8; PUBKEY_LEN] = buf[0..PUBKEY_LEN].try_into().unwrap();
     let nonce_b: [u8; NONCE_LEN] = buf[PUBKEY_LEN..PUBKEY_LEN + NONCE_LEN]
         .try_into().unwrap();
-    let _sig_b_bytes: [u8; SIG_LEN] = buf[PUBKEY_LEN + NONCE_LEN..PUBKEY_LEN + NONCE_LEN + SIG_LEN]
-        .try_into().unwrap();
-
-    // NOTE: In production, verify peer's signature here with their certificate
+    let peer = verify_peer(&buf, env)?;
 
     // 6) Compute shared secret
     let x_b_pub = X25519Public::from(x_b_bytes);
     let shared = x_secret.diffie_hellman(&x_b_pub);
 
     // 7) Derive session key using HKDF-SHA256
-    let info = [&nonce_a[..], &nonce_b[..]].concat();
-    let hk = Hkdf::<Sha256>::new(None, shared.as_bytes());
-    let mut okm = [0u8; 32];
-    hk.expand(&info, &mut okm)
-        .map_err(|_| HandshakeError::Crypto("HKDF expand failed".into()))?;
-
-    let aead = XChaCha20Poly1305::new(&okm.into());
-
-    Ok(Session { aead, session_key: okm })
+    let session = derive_session(shared.as_bytes(), &nonce_a, &nonce_b, &message_a, &buf, peer, env)?;
+    finish_full(session, identity, transport, env, true).await
 }
 
 /// Responder handshake (symmetrical).
@@ -134,8 +514,38 @@ pub async fn responder_handshake<T>(
 where
     T: AsyncRead + AsyncWrite + Unpin + Send,
 {
-    // Read initiator message
+    responder_handshake_with(identity, "", transport, &Env::system()).await
+}
+
+/// Responder handshake announcing `device_id` and drawing ephemerals and
+/// nonces from `env`. Runs Noise if the initiator asks for it.
+pub async fn responder_handshake_with<T>(
+    identity: &Identity,
+    device_id: &str,
+    transport: &mut T,
+    env: &Env,
+) -> Result<Session, HandshakeError>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
     let buf = read_lp(transport).await.map_err(HandshakeError::Io)?;
+    if buf.starts_with(NOISE_MAGIC) {
+        return noise::respond(identity, device_id, transport, env, buf).await;
+    }
+    respond_full(identity, device_id, transport, env, buf).await
+}
+
+/// Responder side of the full handshake, given the initiator's message.
+pub(crate) async fn respond_full<T>(
+    identity: &Identity,
+    device_id: &str,
+    transport: &mut T,
+    env: &Env,
+    buf: Vec<u8>,
+) -> Result<Session, HandshakeError>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
     if buf.len() < PUBKEY_LEN + NONCE_LEN + SIG_LEN {
         return Err(HandshakeError::Crypto("initiator message too short".into()));
     }
@@ -143,30 +553,18 @@ where
     let x_a_bytes: [u8; PUBKEY_LEN] = buf[0..PUBKEY_LEN].try_into().unwrap();
     let nonce_a: [u8; NONCE_LEN] = buf[PUBKEY_LEN..PUBKEY_LEN + NONCE_LEN]
         .try_into().unwrap();
-    let _sig_a_bytes: [u8; SIG_LEN] = buf[PUBKEY_LEN + NONCE_LEN..PUBKEY_LEN + NONCE_LEN + SIG_LEN]
-        .try_into().unwrap();
-
-    // NOTE: In production, verify initiator's signature here
+    let peer = verify_peer(&buf, env)?;
 
     // Create responder ephemeral
-    let x_secret = EphemeralSecret::random_from_rng(OsRng);
+    let x_secret = env.with_rng(|rng| EphemeralSecret::random_from_rng(rng));
     let x_pub = X25519Public::from(&x_secret);
 
     // Generate nonceB
     let mut nonce_b = [0u8; NONCE_LEN];
-    OsRng.fill_bytes(&mut nonce_b);
-
-    // Sign x_pub || nonceB
-    let mut to_sign = Vec::with_capacity(PUBKEY_LEN + NONCE_LEN);
-    to_sign.extend_from_slice(x_pub.as_bytes());
-    to_sign.extend_from_slice(&nonce_b);
-    let sig = identity.sign(&to_sign);
-
-    // Send responder message
-    let mut message_b = Vec::with_capacity(PUBKEY_LEN + NONCE_LEN + SIG_LEN);
-    message_b.extend_from_slice(x_pub.as_bytes());
-    message_b.extend_from_slice(&nonce_b);
-    message_b.extend_from_slice(&sig.to_bytes());
+    env.fill_bytes(&mut nonce_b);
+
+    // Sign x_pub || nonceB (and the hello), and send responder message
+    let message_b = signed_message(identity, device_id, x_pub.as_bytes(), &nonce_b, env)?;
     write_lp(transport, &message_b).await.map_err(HandshakeError::Io)?;
 
     // Compute shared secret
@@ -174,45 +572,419 @@ where
     let shared = x_secret.diffie_hellman(&x_a_pub);
 
     // Derive session key
-    let info = [&nonce_a[..], &nonce_b[..]].concat();
-    let hk = Hkdf::<Sha256>::new(None, shared.as_bytes());
-    let mut okm = [0u8; 32];
-    hk.expand(&info, &mut okm)
+    let session = derive_session(shared.as_bytes(), &nonce_a, &nonce_b, &buf, &message_b, peer, env)?;
+    finish_full(session, identity, transport, env, false).await
+}
+
+/// Last step of a full handshake: bind the keys to both identities and
+/// confirm them if the peer supports it, then set up framing.
+async fn finish_full<T>(
+    mut session: Session,
+    identity: &Identity,
+    transport: &mut T,
+    env: &Env,
+    initiator: bool,
+) -> Result<Session, HandshakeError>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    if let Some(peer) = session.peer.clone().filter(|p| p.supports(FEATURE_CHANNEL_BINDING)) {
+        let ours = (identity.public_key_bytes(), env.protocol_version());
+        let theirs = (peer.public_key, peer.protocol_version);
+        let (i, r) = if initiator { (ours, theirs) } else { (theirs, ours) };
+        session.bind_channel(i, r)?;
+        session.confirm_key(transport, initiator).await?;
+    }
+    session.negotiate_framing(initiator)?;
+    Ok(session)
+}
+
+pub(crate) fn append_hello(message: &mut Vec<u8>, identity: &Identity, device_id: &str, env: &Env) -> Result<(), HandshakeError> {
+    let hello = Hello {
+        identity_key: identity.public_key_bytes(),
+        device_id: device_id.to_string(),
+    };
+    let version = env.protocol_version();
+    let ext = HelloExt {
+        protocol_version: version,
+        app_version: env!("CARGO_PKG_VERSION").to_string(),
+        features: features_at(version).iter().map(|f| f.to_string()).collect(),
+    };
+    // Held back to what a build of that version sent
+    let mut parts = vec![bincode::serialize(&hello)];
+    if version > 0 {
+        parts.push(bincode::serialize(&ext));
+    }
+    if ext.features.iter().any(|f| f == FEATURE_CLOCK) {
+        parts.push(bincode::serialize(&unix_secs(env)));
+    }
+    if ext.features.iter().any(|f| f == FEATURE_KEY_ROTATION) {
+        parts.push(bincode::serialize(&identity.history));
+    }
+    if ext.features.iter().any(|f| f == FEATURE_DEVICE_CERTS) {
+        parts.push(bincode::serialize(&identity.certificate));
+    }
+    for bytes in parts {
+        let bytes = bytes.map_err(|e| HandshakeError::Crypto(format!("hello encode failed: {}", e)))?;
+        message.extend_from_slice(&bytes);
+    }
+    Ok(())
+}
+
+/// A full handshake message: `x_pub || nonce || sig || hello`. The
+/// signature covers the hello too if `env` speaks `signed-hello`.
+fn signed_message(
+    identity: &Identity,
+    device_id: &str,
+    x_pub: &[u8; PUBKEY_LEN],
+    nonce: &[u8; NONCE_LEN],
+    env: &Env,
+) -> Result<Vec<u8>, HandshakeError> {
+    let mut head = Vec::with_capacity(PUBKEY_LEN + NONCE_LEN);
+    head.extend_from_slice(x_pub);
+    head.extend_from_slice(nonce);
+    let mut hello = Vec::new();
+    append_hello(&mut hello, identity, device_id, env)?;
+    let sig = if features_at(env.protocol_version()).contains(&FEATURE_SIGNED_HELLO) {
+        identity.sign(&hello_signed_bytes(&head, &hello))
+    } else {
+        identity.sign(&head)
+    };
+
+    let mut message = head;
+    message.extend_from_slice(&sig.to_bytes());
+    message.extend_from_slice(&hello);
+    Ok(message)
+}
+
+/// What a `signed-hello` signature covers: a context label, the ephemeral
+/// key and nonce, and a hash of the hello.
+fn hello_signed_bytes(head: &[u8], hello: &[u8]) -> Vec<u8> {
+    let mut bytes = b"openshare-signed-hello-v1".to_vec();
+    bytes.extend_from_slice(head);
+    bytes.extend_from_slice(&Sha256::digest(hello));
+    bytes
+}
+
+/// Parse the optional trailing `Hello` and verify the peer's signature over
+/// its ephemeral key, nonce and, for peers with `signed-hello`, the hello.
+fn verify_peer(message: &[u8], env: &Env) -> Result<Option<PeerInfo>, HandshakeError> {
+    let fixed = PUBKEY_LEN + NONCE_LEN + SIG_LEN;
+    if message.len() == fixed {
+        return Ok(None);
+    }
+    let (head, hello) = (&message[..PUBKEY_LEN + NONCE_LEN], &message[fixed..]);
+    let peer = parse_hello(hello, env)?;
+
+    let sig_bytes: [u8; SIG_LEN] = message[PUBKEY_LEN + NONCE_LEN..fixed].try_into().unwrap();
+    let sig = Signature::from_bytes(&sig_bytes);
+    let verify = |bytes: &[u8]| Identity::verify_with_pubkey(&peer.public_key, bytes, &sig).is_ok();
+    // Builds with `signed-hello` never sign without the hello, so one that
+    // claims such a build (or was rewritten to claim an older one) fails
+    // here unless its hello arrived as sent. Only older builds may sign
+    // the ephemeral key and nonce alone.
+    let signed_hello_from = introduced_in(FEATURE_SIGNED_HELLO).unwrap_or(u16::MAX);
+    if !verify(&hello_signed_bytes(head, hello))
+        && (peer.protocol_version >= signed_hello_from || !verify(head))
+    {
+        return Err(HandshakeError::Crypto("peer signature invalid".into()));
+    }
+    peer.check_compatible()?;
+    Ok(Some(peer))
+}
+
+/// Parse the hello parts written by `append_hello`. Only the key history is
+/// checked here; the caller must authenticate the identity key, then check
+/// the peer is compatible.
+pub(crate) fn parse_hello(mut rest: &[u8], env: &Env) -> Result<PeerInfo, HandshakeError> {
+    let hello: Hello = bincode::deserialize_from(&mut rest)
+        .map_err(|_| HandshakeError::Crypto("malformed hello".into()))?;
+    let ext: Option<HelloExt> = if rest.is_empty() {
+        None
+    } else {
+        Some(bincode::deserialize_from(&mut rest).map_err(|_| HandshakeError::Crypto("malformed hello extension".into()))?)
+    };
+    let clock: Option<u64> = if rest.is_empty() {
+        None
+    } else {
+        Some(bincode::deserialize_from(&mut rest).map_err(|_| HandshakeError::Crypto("malformed hello clock".into()))?)
+    };
+
+    let mut peer = PeerInfo {
+        public_key: hello.identity_key,
+        device_id: hello.device_id,
+        protocol_version: ext.as_ref().map_or(0, |e| e.protocol_version),
+        app_version: ext.as_ref().map(|e| e.app_version.clone()),
+        // Features newer than the version spoken here stay off, as with
+        // a build of that version; unknown ones are kept for display.
+        features: ext.map(|e| e.features).unwrap_or_default().into_iter()
+            .filter(|f| introduced_in(f).is_none_or(|v| v <= env.protocol_version()))
+            .collect(),
+        clock_skew_secs: clock.map(|t| t as i64 - unix_secs(env) as i64),
+        key_history: Vec::new(),
+        certificate: None,
+    };
+    if peer.supports(FEATURE_KEY_ROTATION) && !rest.is_empty() {
+        let history: Vec<KeyRotation> = bincode::deserialize_from(&mut rest)
+            .map_err(|_| HandshakeError::Crypto("malformed hello key history".into()))?;
+        keys::verify_history(&history, &peer.public_key)
+            .map_err(|e| HandshakeError::Crypto(format!("peer key history invalid: {:#}", e)))?;
+        peer.key_history = history;
+    }
+    if peer.supports(FEATURE_DEVICE_CERTS) && !rest.is_empty() {
+        peer.certificate = bincode::deserialize(rest)
+            .map_err(|_| HandshakeError::Crypto("malformed hello certificate".into()))?;
+    }
+    Ok(peer)
+}
+
+fn unix_secs(env: &Env) -> u64 {
+    env.now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Derive the traffic key and exporter secret shared by both sides.
+pub(crate) fn derive_session(
+    shared: &[u8],
+    nonce_a: &[u8],
+    nonce_b: &[u8],
+    message_a: &[u8],
+    message_b: &[u8],
+    peer: Option<PeerInfo>,
+    env: &Env,
+) -> Result<Session, HandshakeError> {
+    let info = [nonce_a, nonce_b].concat();
+    let hk = Hkdf::<Sha256>::new(None, shared);
+    let mut okm = Zeroizing::new([0u8; 32]);
+    hk.expand(&info, &mut *okm)
         .map_err(|_| HandshakeError::Crypto("HKDF expand failed".into()))?;
 
-    let aead = XChaCha20Poly1305::new(&okm.into());
+    let transcript_hash: [u8; 32] = Sha256::new()
+        .chain_update(message_a)
+        .chain_update(message_b)
+        .finalize()
+        .into();
+
+    let hk = Hkdf::<Sha256>::new(Some(&transcript_hash), shared);
+    let mut exporter_secret = Zeroizing::new([0u8; 32]);
+    hk.expand(EXPORTER_LABEL, &mut *exporter_secret)
+        .map_err(|_| HandshakeError::Crypto("HKDF expand failed".into()))?;
+
+    let aead = XChaCha20Poly1305::new((&*okm).into());
+
+    Ok(Session {
+        aead,
+        session_key: okm,
+        transcript_hash,
+        exporter_secret,
+        peer,
+        resumed: false,
+        counters: None,
+        env: env.clone(),
+    })
+}
+
+/// Key a session from a finished Noise handshake: each direction's frame
+/// key is its half of the split (`initiator_to_responder`, then the
+/// other), and the traffic key and exporter secret come from both halves
+/// salted with the handshake hash.
+pub(crate) fn noise_session(
+    split: (Zeroizing<[u8; 32]>, Zeroizing<[u8; 32]>),
+    handshake_hash: [u8; 32],
+    peer: PeerInfo,
+    initiator: bool,
+    env: &Env,
+) -> Result<Session, HandshakeError> {
+    let ikm = Zeroizing::new([&split.0[..], &split.1[..]].concat());
+    let hk = Hkdf::<Sha256>::new(Some(&handshake_hash), &ikm);
+    let mut session_key = Zeroizing::new([0u8; 32]);
+    hk.expand(SESSION_LABEL, &mut *session_key)
+        .map_err(|_| HandshakeError::Crypto("HKDF expand failed".into()))?;
+    let mut exporter_secret = Zeroizing::new([0u8; 32]);
+    hk.expand(EXPORTER_LABEL, &mut *exporter_secret)
+        .map_err(|_| HandshakeError::Crypto("HKDF expand failed".into()))?;
 
-    Ok(Session { aead, session_key: okm })
+    let (i, r) = (XChaCha20Poly1305::new((&*split.0).into()), XChaCha20Poly1305::new((&*split.1).into()));
+    let (send, recv) = if initiator { (i, r) } else { (r, i) };
+    Ok(Session {
+        aead: XChaCha20Poly1305::new((&*session_key).into()),
+        session_key,
+        transcript_hash: handshake_hash,
+        exporter_secret,
+        peer: Some(peer),
+        resumed: false,
+        counters: Some(FrameCounters::new(send, recv, Some(REKEY_INTERVAL))),
+        env: env.clone(),
+    })
 }
 
 //
 // Helper encrypted frame IO for Session
 //
 impl Session {
-    /// Send a length-prefixed encrypted frame. Nonce scheme: 24-byte random XNonce per-frame.
+    /// Re-derive the traffic key and exporter secret over the transcript
+    /// plus the initiator's and responder's identity key and protocol
+    /// version, so the keys only match if both sides agree on who spoke.
+    fn bind_channel(&mut self, initiator: ([u8; 32], u16), responder: ([u8; 32], u16)) -> Result<(), HandshakeError> {
+        let binding: [u8; 32] = Sha256::new()
+            .chain_update(CHANNEL_BINDING_LABEL)
+            .chain_update(self.transcript_hash)
+            .chain_update(initiator.0)
+            .chain_update(initiator.1.to_be_bytes())
+            .chain_update(responder.0)
+            .chain_update(responder.1.to_be_bytes())
+            .finalize()
+            .into();
+
+        let mut session_key = Zeroizing::new([0u8; 32]);
+        Hkdf::<Sha256>::new(Some(&binding), &*self.session_key)
+            .expand(SESSION_LABEL, &mut *session_key)
+            .map_err(|_| HandshakeError::Crypto("HKDF expand failed".into()))?;
+        let mut exporter_secret = Zeroizing::new([0u8; 32]);
+        Hkdf::<Sha256>::new(Some(&binding), &*self.exporter_secret)
+            .expand(EXPORTER_LABEL, &mut *exporter_secret)
+            .map_err(|_| HandshakeError::Crypto("HKDF expand failed".into()))?;
+
+        self.aead = XChaCha20Poly1305::new((&*session_key).into());
+        self.session_key = session_key;
+        self.exporter_secret = exporter_secret;
+        Ok(())
+    }
+
+    /// Exchange key confirmation MACs: send ours, then check the peer's.
+    async fn confirm_key<T>(&self, transport: &mut T, initiator: bool) -> Result<(), HandshakeError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let mut key = Zeroizing::new([0u8; 32]);
+        Hkdf::<Sha256>::new(Some(&self.transcript_hash), &*self.session_key)
+            .expand(CONFIRM_LABEL, &mut *key)
+            .map_err(|_| HandshakeError::Crypto("HKDF expand failed".into()))?;
+        let finished = |label: &[u8]| {
+            let mut mac = <HmacSha256 as Mac>::new_from_slice(&*key).expect("HMAC accepts any key length");
+            mac.update(label);
+            mac.update(&self.transcript_hash);
+            mac
+        };
+        let (ours, theirs) = if initiator {
+            (INITIATOR_FINISHED, RESPONDER_FINISHED)
+        } else {
+            (RESPONDER_FINISHED, INITIATOR_FINISHED)
+        };
+
+        write_lp(transport, &finished(ours).finalize().into_bytes()).await?;
+        let tag = read_lp(transport).await?;
+        finished(theirs)
+            .verify_slice(&tag)
+            .map_err(|_| HandshakeError::Crypto("key confirmation failed".into()))
+    }
+
+    /// Switch to directional keys and counter nonces if the peer supports
+    /// them. `initiator` says which end of the handshake this is.
+    pub(crate) fn negotiate_framing(&mut self, initiator: bool) -> Result<(), HandshakeError> {
+        if !self.peer.as_ref().is_some_and(|p| p.supports(FEATURE_FRAME_COUNTERS)) {
+            return Ok(());
+        }
+        let hk = Hkdf::<Sha256>::new(Some(&self.transcript_hash), &*self.session_key);
+        let key = |label: &[u8]| -> Result<XChaCha20Poly1305, HandshakeError> {
+            let mut okm = Zeroizing::new([0u8; 32]);
+            hk.expand(label, &mut *okm)
+                .map_err(|_| HandshakeError::Crypto("HKDF expand failed".into()))?;
+            Ok(XChaCha20Poly1305::new((&*okm).into()))
+        };
+        let (send, recv) = if initiator {
+            (key(INITIATOR_TO_RESPONDER)?, key(RESPONDER_TO_INITIATOR)?)
+        } else {
+            (key(RESPONDER_TO_INITIATOR)?, key(INITIATOR_TO_RESPONDER)?)
+        };
+        self.counters = Some(FrameCounters::new(send, recv, None));
+        Ok(())
+    }
+
+    /// Whether frames use directional keys and counter nonces.
+    pub fn uses_frame_counters(&self) -> bool {
+        self.counters.is_some()
+    }
+
+    /// Derive `len` bytes of keying material bound to this authenticated
+    /// session, in the spirit of the TLS exporter (RFC 5705). Both peers
+    /// get the same output for the same `label` and `context`; distinct
+    /// labels give independent keys. The traffic key itself is never
+    /// exposed through this path.
+    pub fn export_keying_material(
+        &self,
+        label: &[u8],
+        context: &[u8],
+        len: usize,
+    ) -> Result<Zeroizing<Vec<u8>>, HandshakeError> {
+        // Length-prefix both inputs so (label, context) splits are unambiguous.
+        let mut info = Vec::with_capacity(8 + label.len() + context.len());
+        info.extend_from_slice(&(label.len() as u32).to_be_bytes());
+        info.extend_from_slice(label);
+        info.extend_from_slice(&(context.len() as u32).to_be_bytes());
+        info.extend_from_slice(context);
+
+        let hk = Hkdf::<Sha256>::from_prk(&*self.exporter_secret)
+            .map_err(|_| HandshakeError::Crypto("invalid exporter secret".into()))?;
+        let mut out = Zeroizing::new(vec![0u8; len]);
+        hk.expand(&info, &mut out)
+            .map_err(|_| HandshakeError::Crypto(format!("cannot export {} bytes", len)))?;
+        Ok(out)
+    }
+
+    /// Six-digit short authentication string for pairing. It comes from
+    /// the exporter secret, so it depends on the DH result and the whole
+    /// transcript: a man in the middle ends up with different codes on the
+    /// two sides. Users compare it out of band.
+    pub fn sas_code(&self) -> Result<String, HandshakeError> {
+        let bytes = self.export_keying_material(SAS_LABEL, b"", 4)?;
+        let n = u32::from_be_bytes(bytes[..].try_into().unwrap()) % 1_000_000;
+        Ok(format!("{:03} {:03}", n / 1000, n % 1000))
+    }
+
+    /// ID of the transfer over this session. Both sides derive the same
+    /// one from the exporter secret, so it needs nothing on the wire and
+    /// is fresh for every connection.
+    pub fn transfer_id(&self) -> TransferId {
+        let bytes = self.export_keying_material(TRANSFER_ID_LABEL, b"", 16)
+            .expect("16 bytes is within the HKDF output limit");
+        TransferId::from_bytes(bytes[..].try_into().unwrap())
+    }
+
+    /// Send a length-prefixed encrypted frame. Nonce scheme: the frame
+    /// counter with counter framing, else a 24-byte random XNonce per frame.
     pub async fn send_encrypted_frame<T: AsyncWrite + Unpin + Send>(
         &self,
         transport: &mut T,
         plaintext: &[u8]
     ) -> Result<(), std::io::Error> {
-        // Generate random nonce
-        let mut nonce_bytes = [0u8; 24];
-        OsRng.fill_bytes(&mut nonce_bytes);
-        let nonce = XNonce::from(nonce_bytes);
-
         // Prepare ciphertext (in-place encryption)
         let mut buf = plaintext.to_vec();
 
-        self.aead.encrypt_in_place(&nonce, b"", &mut buf)
-            .map_err(|_| std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "aead encrypt failed"
-            ))?;
+        let frame = if let Some(counters) = &self.counters {
+            // Frame = counter || ciphertext
+            let mut key = counters.send.lock().unwrap();
+            let n = counters.sent.fetch_add(1, Ordering::SeqCst);
+            if n == u64::MAX {
+                return Err(std::io::Error::other("frame counter exhausted"));
+            }
+            key.encrypt_in_place(&counter_nonce(n), b"", &mut buf)
+                .map_err(|_| std::io::Error::other("aead encrypt failed"))?;
+            if counters.rekey_after(n) {
+                rekey(&mut key);
+            }
+            [&n.to_be_bytes()[..], &buf].concat()
+        } else {
+            // Generate random nonce
+            let mut nonce_bytes = [0u8; 24];
+            self.env.fill_bytes(&mut nonce_bytes);
+            let nonce = XNonce::from(nonce_bytes);
+
+            self.aead.encrypt_in_place(&nonce, b"", &mut buf)
+                .map_err(|_| std::io::Error::other("aead encrypt failed"))?;
 
-        // Frame = nonce || ciphertext
-        let mut frame = Vec::with_capacity(24 + buf.len());
-        frame.extend_from_slice(&nonce_bytes);
-        frame.extend_from_slice(&buf);
+            // Frame = nonce || ciphertext
+            [&nonce_bytes[..], &buf].concat()
+        };
 
         // Length-prefix and write
         transport.write_all(&(frame.len() as u32).to_be_bytes()).await?;
@@ -233,7 +1005,7 @@ impl Session {
         let len = u32::from_be_bytes(lenb) as usize;
 
         // Sanity check
-        if len > 10 * 1024 * 1024 {
+        if len > MAX_FRAME_LEN {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 "frame too large"
@@ -243,6 +1015,34 @@ impl Session {
         let mut frame = vec![0u8; len];
         transport.read_exact(&mut frame).await?;
 
+        if let Some(counters) = &self.counters {
+            if frame.len() < COUNTER_LEN {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "frame too small"
+                ));
+            }
+            // The transport is ordered, so anything but the next counter
+            // value is a replay, a drop or a reorder.
+            let n = u64::from_be_bytes(frame[..COUNTER_LEN].try_into().unwrap());
+            let mut key = counters.recv.lock().unwrap();
+            let expected = counters.received.load(Ordering::SeqCst);
+            if n != expected {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("replayed or out-of-order frame {} (expected {})", n, expected),
+                ));
+            }
+            let mut cipher = frame[COUNTER_LEN..].to_vec();
+            key.decrypt_in_place(&counter_nonce(n), b"", &mut cipher)
+                .map_err(|_| std::io::Error::other("aead decrypt failed"))?;
+            if counters.rekey_after(n) {
+                rekey(&mut key);
+            }
+            counters.received.store(n + 1, Ordering::SeqCst);
+            return Ok(cipher);
+        }
+
         if frame.len() < 24 {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
@@ -255,11 +1055,353 @@ impl Session {
         let mut cipher = frame[24..].to_vec();
 
         self.aead.decrypt_in_place(&nonce, b"", &mut cipher)
-            .map_err(|_| std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "aead decrypt failed"
-            ))?;
+            .map_err(|_| std::io::Error::other("aead decrypt failed"))?;
 
         Ok(cipher)
     }
-}
\ No newline at end of file
+}
+/// Nonce for frame `n` under counter framing. Each direction has its own
+/// key, so the counter alone keeps nonces unique.
+fn counter_nonce(n: u64) -> XNonce {
+    let mut nonce = [0u8; 24];
+    nonce[24 - COUNTER_LEN..].copy_from_slice(&n.to_be_bytes());
+    XNonce::from(nonce)
+}
+
+/// Noise's REKEY: the next key is 32 zero bytes encrypted under the
+/// current one with the nonce of frame `u64::MAX`, which is never sent.
+fn rekey(key: &mut XChaCha20Poly1305) {
+    let mut next = Zeroizing::new([0u8; 32]);
+    key.encrypt_in_place_detached(&counter_nonce(u64::MAX), b"", &mut next[..])
+        .expect("32 bytes are within the AEAD length limit");
+    *key = XChaCha20Poly1305::new((&*next).into());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::ManualClock;
+    use crate::sim::fixture::client;
+    use crate::sim::{pair, LinkConfig};
+    use ed25519_dalek::SigningKey;
+    use std::time::SystemTime;
+    use tempfile::TempDir;
+
+    fn identity(byte: u8) -> Identity {
+        Identity::from(SigningKey::from_bytes(&[byte; 32]))
+    }
+
+    async fn seeded_sessions(seed_a: u8, seed_b: u8) -> (Session, Session) {
+        let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+        let env_a = Env::seeded([seed_a; 32], clock.clone());
+        let env_b = Env::seeded([seed_b; 32], clock);
+        let (id_a, id_b) = (identity(1), identity(2));
+        let (mut a, mut b) = tokio::io::duplex(4096);
+
+        let (sa, sb) = tokio::join!(
+            initiator_handshake_with(&id_a, "a", &mut a, &env_a),
+            responder_handshake_with(&id_b, "b", &mut b, &env_b),
+        );
+        (sa.unwrap(), sb.unwrap())
+    }
+
+    async fn seeded_pair(seed_a: u8, seed_b: u8) -> ([u8; 32], [u8; 32]) {
+        let (sa, sb) = seeded_sessions(seed_a, seed_b).await;
+        (*sa.session_key, *sb.session_key)
+    }
+
+    #[tokio::test]
+    async fn test_seeded_handshake_is_reproducible() {
+        let (ka, kb) = seeded_pair(7, 9).await;
+        assert_eq!(ka, kb);
+
+        let (ka2, _) = seeded_pair(7, 9).await;
+        assert_eq!(ka, ka2);
+
+        let (ka3, _) = seeded_pair(7, 10).await;
+        assert_ne!(ka, ka3);
+    }
+
+    #[tokio::test]
+    async fn test_export_keying_material() {
+        let (sa, sb) = seeded_sessions(1, 2).await;
+        assert_eq!(sa.transcript_hash, sb.transcript_hash);
+        assert_eq!(sa.peer.as_ref().unwrap().device_id, "b");
+        assert_eq!(sb.peer.as_ref().unwrap().public_key, identity(1).public_key_bytes());
+
+        let ka = sa.export_keying_material(b"sidecar", b"ctx", 32).unwrap();
+        let kb = sb.export_keying_material(b"sidecar", b"ctx", 32).unwrap();
+        assert_eq!(ka, kb);
+        assert_ne!(*ka, sa.session_key.to_vec());
+        assert_ne!(ka, sa.export_keying_material(b"other", b"ctx", 32).unwrap());
+        assert_ne!(ka, sa.export_keying_material(b"sidecarc", b"tx", 32).unwrap());
+        assert!(sa.export_keying_material(b"x", b"", 255 * 32 + 1).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_transfer_id_matches_on_both_sides() {
+        let (sa, sb) = seeded_sessions(1, 2).await;
+        let id = sa.transfer_id();
+        assert_eq!(id, sb.transfer_id());
+        assert_ne!(id, seeded_sessions(1, 3).await.0.transfer_id());
+
+        let shown = id.to_string();
+        assert_eq!(shown.len(), 36);
+        assert_eq!(shown.as_bytes()[14], b'4');
+        assert_eq!(shown.parse::<TransferId>().unwrap(), id);
+        assert!("not-a-transfer".parse::<TransferId>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_hello_versions() {
+        let (sa, _) = seeded_sessions(1, 2).await;
+        let peer = sa.peer.unwrap();
+        assert_eq!(peer.protocol_version, PROTOCOL_VERSION);
+        assert!(peer.disabled_features().is_empty());
+        assert_eq!(peer.shared_features(), FEATURES);
+
+        // A peer predating HelloExt sends only the Hello.
+        let id = identity(3);
+        let mut legacy = vec![0u8; PUBKEY_LEN + NONCE_LEN];
+        legacy.extend_from_slice(&id.sign(&legacy).to_bytes());
+        let hello = Hello { identity_key: id.public_key_bytes(), device_id: "old".into() };
+        legacy.extend_from_slice(&bincode::serialize(&hello).unwrap());
+
+        let peer = verify_peer(&legacy, &Env::system()).unwrap().unwrap();
+        assert_eq!((peer.protocol_version, peer.app_version.as_deref(), peer.clock_skew_secs), (0, None, None));
+        assert_eq!(peer.disabled_features(), FEATURES);
+        assert!(peer.shared_features().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_clock_skew() {
+        let at = |secs| ManualClock::new(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs));
+        let (env_a, env_b) = (Env::seeded([1; 32], at(1000)), Env::seeded([2; 32], at(1300)));
+        let (id_a, id_b) = (identity(1), identity(2));
+        let (mut a, mut b) = tokio::io::duplex(4096);
+        let (sa, sb) = tokio::join!(
+            initiator_handshake_with(&id_a, "a", &mut a, &env_a),
+            responder_handshake_with(&id_b, "b", &mut b, &env_b),
+        );
+        let (ahead, behind) = (sa.unwrap().peer.unwrap(), sb.unwrap().peer.unwrap());
+        assert_eq!((ahead.clock_skew_secs, behind.clock_skew_secs), (Some(300), Some(-300)));
+
+        assert_eq!(ahead.clock_skew_beyond(60), Some(300));
+        assert_eq!(ahead.clock_skew_beyond(300), None);
+        assert_eq!(behind.clock_skew_beyond(60), Some(-300));
+    }
+
+    #[tokio::test]
+    async fn test_compat_version() {
+        assert!(FEATURES.iter().all(|f| introduced_in(f).is_some_and(|v| v <= PROTOCOL_VERSION)));
+        assert_eq!(features_at(PROTOCOL_VERSION), FEATURES);
+
+        for version in [20, 0] {
+            let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+            let env_a = Env::seeded([1; 32], clock.clone()).with_protocol_version(version);
+            let env_b = Env::seeded([2; 32], clock);
+            let (id_a, id_b) = (identity(1), identity(2));
+            let (mut a, mut b) = tokio::io::duplex(4096);
+            let (sa, sb) = tokio::join!(
+                initiator_handshake_with(&id_a, "a", &mut a, &env_a),
+                responder_handshake_with(&id_b, "b", &mut b, &env_b),
+            );
+            let (sa, sb) = (sa.unwrap(), sb.unwrap());
+
+            // Each side sees the other as a build of that version would.
+            let old = sb.peer.unwrap();
+            assert_eq!((old.protocol_version, old.shared_features()), (version, features_at(version)));
+            assert_eq!(old.app_version.is_some(), version > 0);
+            assert_eq!(old.clock_skew_secs.is_some(), version >= 20);
+            let new = sa.peer.unwrap();
+            assert_eq!((new.protocol_version, new.shared_features()), (PROTOCOL_VERSION, features_at(version)));
+            assert_eq!(sa.session_key, sb.session_key);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_frame_counters_reject_replay() {
+        let (sa, sb) = seeded_sessions(1, 2).await;
+        assert!(sa.uses_frame_counters() && sb.uses_frame_counters());
+
+        let mut wire = Vec::new();
+        sa.send_encrypted_frame(&mut wire, b"first").await.unwrap();
+        let first = wire.clone();
+        sa.send_encrypted_frame(&mut wire, b"second").await.unwrap();
+
+        let mut reader = &wire[..];
+        assert_eq!(sb.read_encrypted_frame(&mut reader).await.unwrap(), b"first");
+        assert_eq!(sb.read_encrypted_frame(&mut reader).await.unwrap(), b"second");
+        assert!(sb.read_encrypted_frame(&mut &first[..]).await.is_err());
+
+        // Directions use different keys, so a frame cannot be reflected back.
+        let (sa, _) = seeded_sessions(1, 2).await;
+        let mut wire = Vec::new();
+        sa.send_encrypted_frame(&mut wire, b"echo").await.unwrap();
+        assert!(sa.read_encrypted_frame(&mut &wire[..]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_noise_frames_rekey() {
+        let env = Env::seeded([3; 32], ManualClock::new(SystemTime::UNIX_EPOCH));
+        let peer = |id: &str| PeerInfo {
+            public_key: identity(1).public_key_bytes(),
+            device_id: id.to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            app_version: None,
+            features: Vec::new(),
+            clock_skew_secs: None,
+            key_history: Vec::new(),
+            certificate: None,
+        };
+        let split = || (Zeroizing::new([1; 32]), Zeroizing::new([2; 32]));
+        let mut sa = noise_session(split(), [4; 32], peer("b"), true, &env).unwrap();
+        let mut sb = noise_session(split(), [4; 32], peer("a"), false, &env).unwrap();
+        let stale = noise_session(split(), [4; 32], peer("a"), false, &env).unwrap();
+        for s in [&mut sa, &mut sb] {
+            s.counters.as_mut().unwrap().rekey_every = Some(2);
+        }
+
+        let mut wire = Vec::new();
+        for i in 0..5u8 {
+            sa.send_encrypted_frame(&mut wire, &[i]).await.unwrap();
+        }
+        let mut reader = &wire[..];
+        for i in 0..5u8 {
+            assert_eq!(sb.read_encrypted_frame(&mut reader).await.unwrap(), [i]);
+        }
+        // A receiver that did not rekey reads the first two frames only.
+        let mut reader = &wire[..];
+        assert!(stale.read_encrypted_frame(&mut reader).await.is_ok());
+        assert!(stale.read_encrypted_frame(&mut reader).await.is_ok());
+        assert!(stale.read_encrypted_frame(&mut reader).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_key_confirmation_failure_aborts_handshake() {
+        let env = Env::seeded([5; 32], ManualClock::new(SystemTime::UNIX_EPOCH));
+        let (id_a, id_b) = (identity(1), identity(2));
+        let (mut a, mut relay_a) = tokio::io::duplex(4096);
+        let (mut b, mut relay_b) = tokio::io::duplex(4096);
+
+        // Pass the handshake through, but corrupt the responder's confirmation.
+        let relay = async {
+            let message_a = read_lp(&mut relay_a).await.unwrap();
+            write_lp(&mut relay_b, &message_a).await.unwrap();
+            let message_b = read_lp(&mut relay_b).await.unwrap();
+            write_lp(&mut relay_a, &message_b).await.unwrap();
+            let mut finished = read_lp(&mut relay_b).await.unwrap();
+            finished[0] ^= 1;
+            write_lp(&mut relay_a, &finished).await.unwrap();
+            let finished = read_lp(&mut relay_a).await.unwrap();
+            write_lp(&mut relay_b, &finished).await.unwrap();
+        };
+        let (sa, sb, _) = tokio::join!(
+            initiator_handshake_with(&id_a, "a", &mut a, &env),
+            responder_handshake_with(&id_b, "b", &mut b, &env),
+            relay,
+        );
+        assert!(matches!(sa, Err(HandshakeError::Crypto(e)) if e.contains("key confirmation")));
+        assert!(sb.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_stripped_features_fail_handshake() {
+        // Rewrite the initiator's hello without `channel-binding`, also
+        // claiming a version that predates signed hellos.
+        for claimed in [PROTOCOL_VERSION, 5] {
+            let env = Env::seeded([6; 32], ManualClock::new(SystemTime::UNIX_EPOCH));
+            let (id_a, id_b) = (identity(1), identity(2));
+            let (mut a, mut relay_a) = tokio::io::duplex(4096);
+            let (mut b, mut relay_b) = tokio::io::duplex(4096);
+
+            let relay = async move {
+                let message_a = read_lp(&mut relay_a).await.unwrap();
+                let fixed = PUBKEY_LEN + NONCE_LEN + SIG_LEN;
+                let mut rest = &message_a[fixed..];
+                let hello: Hello = bincode::deserialize_from(&mut rest).unwrap();
+                let mut ext: HelloExt = bincode::deserialize_from(&mut rest).unwrap();
+                ext.features.retain(|f| f != FEATURE_CHANNEL_BINDING);
+                ext.protocol_version = claimed;
+                let mut forged = message_a[..fixed].to_vec();
+                forged.extend_from_slice(&bincode::serialize(&hello).unwrap());
+                forged.extend_from_slice(&bincode::serialize(&ext).unwrap());
+                forged.extend_from_slice(rest);
+                write_lp(&mut relay_b, &forged).await.unwrap();
+            };
+            let (sa, sb, _) = tokio::join!(
+                initiator_handshake_with(&id_a, "a", &mut a, &env),
+                responder_handshake_with(&id_b, "b", &mut b, &env),
+                relay,
+            );
+            assert!(matches!(sb, Err(HandshakeError::Crypto(e)) if e.contains("signature")));
+            assert!(sa.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_expect_fingerprint() {
+        let (sa, _) = seeded_sessions(1, 2).await;
+        let peer = sa.peer.as_ref();
+        let key = identity(2);
+        expect_fingerprint(peer, &key.fingerprint()).unwrap();
+        expect_fingerprint(peer, &key.full_fingerprint().to_uppercase()).unwrap();
+
+        let colons = key.fingerprint().as_bytes().chunks(2)
+            .map(|pair| std::str::from_utf8(pair).unwrap())
+            .collect::<Vec<_>>()
+            .join(":");
+        expect_fingerprint(peer, &colons).unwrap();
+
+        // Another key, a prefix too short to pin anything, or no peer at all
+        for fingerprint in [identity(1).fingerprint(), key.fingerprint()[..6].to_string(), String::new()] {
+            assert!(matches!(expect_fingerprint(peer, &fingerprint), Err(HandshakeError::UnexpectedPeer(_))));
+        }
+        assert!(expect_fingerprint(None, &key.fingerprint()).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_probe() {
+        use crate::Accepted;
+
+        let (da, db) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let (prober, listener) = (client(&da, 1), client(&db, 2));
+
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (probed, accepted) = tokio::join!(prober.probe(a), listener.accept(b));
+        let peer = probed.unwrap();
+        assert_eq!((peer.device_id.as_str(), peer.protocol_version), ("dev2", PROTOCOL_VERSION));
+        assert_eq!(peer.shared_features(), FEATURES);
+        assert!(matches!(accepted.unwrap(), Accepted::Probed { device_id } if device_id == "dev1"));
+        assert!(listener.history.as_ref().unwrap().load().unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mixed_version_fleet() {
+        use crate::Env;
+
+        let (src, dst) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let input = src.path().join("input.bin");
+        let payload: Vec<u8> = (0..150_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&input, &payload).unwrap();
+
+        // Each way round, for a device held at an older protocol.
+        for (version, old_sends) in [(0, true), (0, false), (11, true), (11, false)] {
+            let old = Env::system().with_protocol_version(version);
+            let (sender, receiver) = match old_sends {
+                true => (client(&src, 1).with_env(old), client(&dst, 2)),
+                false => (client(&src, 1), client(&dst, 2).with_env(old)),
+            };
+            let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+            let (sent, received) = tokio::join!(
+                sender.send_file_streaming(a, &input, false),
+                receiver.accept_and_receive(b),
+            );
+            let (manifest, received) = (sent.unwrap(), received.unwrap());
+            assert_eq!(received.id(), manifest.id());
+            let output = dst.path().join("out.bin");
+            receiver.write_file(&received, &output).await.unwrap();
+            assert_eq!(std::fs::read(output).unwrap(), payload, "protocol {}", version);
+        }
+    }
+}
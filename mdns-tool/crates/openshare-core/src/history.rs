@@ -0,0 +1,228 @@
+//! Local transfer history and usage statistics.
+//!
+//! Each finished transfer, completed or failed, appends one JSON line to
+//! `history.jsonl` under the data directory (unless `record_history` is
+//! off). The file never leaves the machine; `openshare history` lists it
+//! and `openshare stats` summarizes it on demand.
+
+use crate::transfer::TransferId;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// File name of the history log inside the data directory.
+pub const HISTORY_FILE: &str = "history.jsonl";
+
+/// Which way the data moved, from this device's point of view.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// Pushed to a peer
+    Sent,
+    /// Pushed to us by a peer
+    Received,
+    /// Pulled from us by a peer
+    Served,
+    /// Pulled by us from a peer
+    Fetched,
+    /// A peer told us it published a share; nothing moved yet. Only
+    /// seen in notifications, never in the history
+    Announced,
+}
+
+impl Direction {
+    /// Whether the file content flowed out of this device.
+    pub fn is_outgoing(self) -> bool {
+        matches!(self, Direction::Sent | Direction::Served)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferRecord {
+    /// Same on both peers; `None` for transfers that failed before the
+    /// handshake, and in entries older than transfer IDs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transfer_id: Option<TransferId>,
+    pub direction: Direction,
+    /// Device ID of the peer, "unknown" if it did not identify itself
+    pub peer: String,
+    /// Hex public key of the peer, if it identified itself
+    #[serde(default)]
+    pub peer_fingerprint: Option<String>,
+    pub filename: String,
+    pub size: u64,
+    /// Bytes of received chunks that were already in storage
+    #[serde(default)]
+    pub reused_bytes: u64,
+    /// Chunk bytes compression kept off the wire
+    #[serde(default)]
+    pub compression_saved_bytes: u64,
+    /// Unix timestamp (seconds) when the transfer completed or failed
+    pub finished_at: u64,
+    pub duration_ms: u64,
+    /// Why the transfer failed; `None` if it completed. `size` is then
+    /// the size of the file, not what moved before the failure
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl TransferRecord {
+    pub fn succeeded(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct History {
+    path: PathBuf,
+}
+
+impl History {
+    pub fn new(data_dir: &Path) -> Self {
+        Self { path: data_dir.join(HISTORY_FILE) }
+    }
+
+    pub fn append(&self, record: &TransferRecord) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut f| f.write_all(&line))
+            .with_context(|| format!("writing {}", self.path.display()))
+    }
+
+    /// All records, oldest first; a missing file is an empty history.
+    /// Unreadable lines (e.g. a torn write) are skipped.
+    pub fn load(&self) -> Result<Vec<TransferRecord>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let text = std::fs::read_to_string(&self.path)
+            .with_context(|| format!("reading {}", self.path.display()))?;
+        Ok(text.lines()
+            .filter(|l| !l.trim().is_empty())
+            .filter_map(|l| match serde_json::from_str(l) {
+                Ok(record) => Some(record),
+                Err(e) => {
+                    tracing::warn!("Skipping bad history line: {}", e);
+                    None
+                }
+            })
+            .collect())
+    }
+}
+
+/// Lifetime totals computed from the history.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct UsageStats {
+    /// Completed transfers; the totals below only count these
+    pub transfers: usize,
+    pub failed: usize,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Received bytes that did not need to be written again
+    pub reused_bytes: u64,
+    /// Bytes compression kept off the wire, both directions
+    pub compression_saved_bytes: u64,
+    /// Up to five peers by bytes exchanged, largest first
+    pub top_peers: Vec<(String, u64)>,
+    /// Mean throughput over all transfers, in bytes per second
+    pub average_speed: Option<f64>,
+    pub first_transfer_at: Option<u64>,
+}
+
+impl UsageStats {
+    pub fn from_records(records: &[TransferRecord]) -> Self {
+        let (completed, failed): (Vec<_>, Vec<_>) = records.iter().partition(|r| r.succeeded());
+        let mut stats = UsageStats { transfers: completed.len(), failed: failed.len(), ..Default::default() };
+        let mut per_peer: HashMap<&str, u64> = HashMap::new();
+        let mut total_ms = 0;
+        for r in &completed {
+            if r.direction.is_outgoing() {
+                stats.bytes_sent += r.size;
+            } else {
+                stats.bytes_received += r.size;
+            }
+            stats.reused_bytes += r.reused_bytes;
+            stats.compression_saved_bytes += r.compression_saved_bytes;
+            *per_peer.entry(&r.peer).or_default() += r.size;
+            total_ms += r.duration_ms;
+        }
+
+        let mut peers: Vec<(String, u64)> = per_peer.into_iter()
+            .map(|(peer, bytes)| (peer.to_string(), bytes))
+            .collect();
+        peers.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        peers.truncate(5);
+        stats.top_peers = peers;
+
+        if total_ms > 0 {
+            let bytes = stats.bytes_sent + stats.bytes_received;
+            stats.average_speed = Some(bytes as f64 * 1000.0 / total_ms as f64);
+        }
+        stats.first_transfer_at = completed.iter().map(|r| r.finished_at).min();
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_history_stats() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let history = History::new(tmp.path());
+        assert!(history.load()?.is_empty());
+
+        let record = |direction, peer: &str, size, reused_bytes| TransferRecord {
+            transfer_id: None,
+            direction,
+            peer: peer.into(),
+            peer_fingerprint: None,
+            filename: "f".into(),
+            size,
+            reused_bytes,
+            compression_saved_bytes: 0,
+            finished_at: 1_700_000_000 + size,
+            duration_ms: 500,
+            error: None,
+        };
+        history.append(&record(Direction::Sent, "phone", 1000, 0))?;
+        history.append(&record(Direction::Received, "laptop", 3000, 2000))?;
+        history.append(&record(Direction::Fetched, "phone", 500, 0))?;
+        history.append(&TransferRecord {
+            error: Some("connection reset".into()),
+            ..record(Direction::Sent, "laptop", 9000, 0)
+        })?;
+        std::fs::OpenOptions::new().append(true).open(tmp.path().join(HISTORY_FILE))?
+            .write_all(b"{\"torn\n")?;
+
+        let stats = UsageStats::from_records(&history.load()?);
+        assert_eq!((stats.transfers, stats.failed), (3, 1));
+        assert_eq!((stats.bytes_sent, stats.bytes_received, stats.reused_bytes), (1000, 3500, 2000));
+        assert_eq!(stats.top_peers, [("laptop".to_string(), 3000), ("phone".to_string(), 1500)]);
+        assert_eq!(stats.average_speed, Some(3000.0));
+        assert_eq!(stats.first_transfer_at, Some(1_700_000_500));
+        Ok(())
+    }
+
+    #[test]
+    fn test_records_from_older_versions_load() -> Result<()> {
+        let tmp = TempDir::new()?;
+        std::fs::write(
+            tmp.path().join(HISTORY_FILE),
+            "{\"direction\":\"sent\",\"peer\":\"phone\",\"filename\":\"f\",\"size\":1,\"finished_at\":0,\"duration_ms\":0}\n",
+        )?;
+        let records = History::new(tmp.path()).load()?;
+        assert_eq!(records.len(), 1);
+        assert!(records[0].succeeded());
+        assert_eq!(records[0].peer_fingerprint, None);
+        Ok(())
+    }
+}
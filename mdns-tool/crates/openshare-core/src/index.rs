@@ -0,0 +1,203 @@
+//! Account-wide content index.
+//!
+//! Each device can share the list of manifests it has published with the
+//! user's other devices (trusted peers with the same account hash, and
+//! only when `share_index` is on). Indexes collected from peers are cached
+//! in `content_index.bin`, encrypted under a key derived from the device
+//! identity, so `openshare find` can tell which device holds a file
+//! without asking every device again.
+
+use crate::{Identity, Manifest};
+use anyhow::Result;
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+/// File name of the cached index inside the data directory.
+pub const INDEX_FILE: &str = "content_index.bin";
+
+/// HKDF info for the at-rest encryption key.
+const INDEX_KEY_LABEL: &[u8] = b"openshare content index v1";
+
+const NONCE_LEN: usize = 24;
+
+/// One published share as seen in an index.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub filename: String,
+    pub size: u64,
+    pub manifest_id: String,
+    /// Whole-content hash; empty if the manifest has none
+    pub file_hash: String,
+    /// Paths of the files inside a directory share
+    pub files: Vec<String>,
+}
+
+impl IndexEntry {
+    pub fn from_manifest(manifest: &Manifest) -> Self {
+        Self {
+            filename: manifest.filename.clone(),
+            size: manifest.size,
+            manifest_id: manifest.id(),
+            file_hash: manifest.file_hash.clone(),
+            files: manifest.files.iter().filter(|e| !e.is_dir).map(|e| e.path.clone()).collect(),
+        }
+    }
+
+    /// Case-insensitive match on the share name or a file inside it, or an
+    /// exact match on the manifest ID or content hash.
+    pub fn matches(&self, query: &str) -> bool {
+        let query = query.to_lowercase();
+        self.filename.to_lowercase().contains(&query)
+            || self.files.iter().any(|f| f.to_lowercase().contains(&query))
+            || self.manifest_id == query
+            || (!self.file_hash.is_empty() && self.file_hash == query)
+    }
+}
+
+/// The shares one device published, as of `updated_at`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceIndex {
+    pub device_id: String,
+    /// Address the index was fetched from, for fetching files later
+    pub address: Option<String>,
+    /// Unix timestamp (seconds) of the last sync
+    pub updated_at: u64,
+    pub entries: Vec<IndexEntry>,
+}
+
+/// Cached indexes of the user's devices, keyed by device ID.
+pub struct ContentIndex {
+    path: PathBuf,
+    key: Zeroizing<[u8; 32]>,
+    pub devices: BTreeMap<String, DeviceIndex>,
+}
+
+impl ContentIndex {
+    /// Load the cache from `data_dir`; a missing file is an empty index.
+    pub fn load(data_dir: &Path, identity: &Identity) -> Result<Self> {
+        let mut key = Zeroizing::new([0u8; 32]);
+        Hkdf::<Sha256>::new(None, &*identity.secret_bytes())
+            .expand(INDEX_KEY_LABEL, &mut *key)
+            .expect("32 bytes is a valid HKDF length");
+        let path = data_dir.join(INDEX_FILE);
+        let devices = crate::state::read(&path, |sealed| {
+            if sealed.len() < NONCE_LEN {
+                anyhow::bail!("truncated");
+            }
+            let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+            let plain = XChaCha20Poly1305::new((&*key).into())
+                .decrypt(XNonce::from_slice(nonce), ciphertext)
+                .map_err(|_| anyhow::anyhow!("Cannot decrypt (written by another identity?)"))?;
+            Ok(serde_json::from_slice(&plain)?)
+        })?;
+        Ok(Self { path, key, devices: devices.unwrap_or_default() })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = XChaCha20Poly1305::new((&*self.key).into())
+            .encrypt(XNonce::from_slice(&nonce), serde_json::to_vec(&self.devices)?.as_slice())
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt content index"))?;
+        crate::state::write(&self.path, &[&nonce[..], &ciphertext].concat())
+    }
+
+    /// Replace what is known about `device.device_id`.
+    pub fn update(&mut self, device: DeviceIndex) {
+        self.devices.insert(device.device_id.clone(), device);
+    }
+
+    /// Every (device, entry) pair matching `query`.
+    pub fn search<'a>(&'a self, query: &'a str) -> impl Iterator<Item = (&'a DeviceIndex, &'a IndexEntry)> + 'a {
+        self.devices.values()
+            .flat_map(|d| d.entries.iter().map(move |e| (d, e)))
+            .filter(move |(_, e)| e.matches(query))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::fixture::client;
+    use crate::sim::{pair, LinkConfig};
+    use ed25519_dalek::SigningKey;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_index_search_and_encryption() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let identity = Identity::from(SigningKey::from_bytes(&[5; 32]));
+        let entry = |filename: &str, files: &[&str]| IndexEntry {
+            filename: filename.into(),
+            size: 1,
+            manifest_id: format!("id-{}", filename),
+            file_hash: String::new(),
+            files: files.iter().map(|f| f.to_string()).collect(),
+        };
+
+        let mut index = ContentIndex::load(tmp.path(), &identity)?;
+        index.update(DeviceIndex {
+            device_id: "desktop".into(),
+            address: Some("10.0.0.2:9876".into()),
+            updated_at: 0,
+            entries: vec![entry("Report.pdf", &[]), entry("photos", &["2024/beach.jpg"])],
+        });
+        index.save()?;
+
+        // Nothing readable on disk, and only this identity can open it.
+        let raw = std::fs::read(tmp.path().join(INDEX_FILE))?;
+        assert!(!String::from_utf8_lossy(&raw).contains("Report"));
+        let other = Identity::from(SigningKey::from_bytes(&[6; 32]));
+        assert!(ContentIndex::load(tmp.path(), &other).is_err());
+
+        let index = ContentIndex::load(tmp.path(), &identity)?;
+        let hits: Vec<_> = index.search("report").map(|(d, e)| (d.device_id.as_str(), e.filename.as_str())).collect();
+        assert_eq!(hits, [("desktop", "Report.pdf")]);
+        assert_eq!(index.search("beach").count(), 1);
+        assert_eq!(index.search("id-photos").count(), 1);
+        assert_eq!(index.search("missing").count(), 0);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_index_exchange() {
+        use crate::Accepted;
+
+        let (src, dst) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let input = src.path().join("report.pdf");
+        std::fs::write(&input, b"quarterly numbers").unwrap();
+
+        let mut server = client(&src, 1);
+        let mut asker = client(&dst, 2);
+        server.cfg.account_hash = "acct".into();
+        asker.cfg.account_hash = "acct".into();
+        let id = server.publish(&server.import_file(&input).await.unwrap()).unwrap();
+
+        // Sharing is opt-in.
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (asked, served) = tokio::join!(asker.request_index(a), server.accept(b));
+        assert!(asked.is_err() && served.is_err());
+
+        server.cfg.share_index = true;
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (asked, served) = tokio::join!(asker.request_index(a), server.accept(b));
+        let device = asked.unwrap();
+        assert!(matches!(served.unwrap(), Accepted::IndexShared { entries: 1, .. }));
+        assert_eq!(device.device_id, "dev1");
+        assert_eq!(device.entries[0].manifest_id, id);
+        assert!(device.entries[0].matches("REPORT"));
+
+        // Devices on another account get nothing.
+        asker.cfg.account_hash = "other".into();
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (asked, served) = tokio::join!(asker.request_index(a), server.accept(b));
+        assert!(asked.is_err() && served.is_err());
+    }
+}
@@ -0,0 +1,164 @@
+//! Checking the chunk store for damage.
+//!
+//! Bit rot, or a write cut short by a crash, can leave a stored chunk whose
+//! content no longer matches its id. Nothing notices until a peer pulls it
+//! and rejects it, or a file is assembled from it and fails its hash.
+//! [`verify_storage`] reads every chunk back and hashes it, with
+//! [`Storage::verify_chunk`] for plain ids and the chunk key for keyed ones.
+//!
+//! Corrupt chunks can be kept, only reported, moved out of the store into
+//! [`QUARANTINE_DIR`] for a closer look, or deleted. Either way the files
+//! that use them need publishing again to be served whole. Chunks that
+//! cannot be read at all (with the wrong storage key, say) are reported
+//! but never removed.
+
+use crate::keyed::ChunkKey;
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use storage::{ChunkHealth, Storage};
+
+/// Directory in the data directory that quarantined chunks are moved to,
+/// each in a file named after its id.
+pub const QUARANTINE_DIR: &str = "corrupt-chunks";
+
+/// Chunks listed from the store at a time.
+const PAGE: usize = 1000;
+
+/// What to do with the corrupt chunks [`verify_storage`] finds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CorruptChunks {
+    /// Only report them
+    #[default]
+    Keep,
+    /// Move them to [`QUARANTINE_DIR`]
+    Quarantine,
+    Delete,
+}
+
+/// What [`verify_storage`] found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StorageCheck {
+    /// Chunks read back
+    pub checked: u64,
+    /// Chunks whose content does not match their id
+    pub corrupt: Vec<String>,
+    /// Chunks that could not be read, and why
+    pub unreadable: Vec<(String, String)>,
+    /// Corrupt chunks quarantined or deleted
+    pub removed: u64,
+}
+
+impl StorageCheck {
+    pub fn is_clean(&self) -> bool {
+        self.corrupt.is_empty() && self.unreadable.is_empty()
+    }
+}
+
+/// Like [`Storage::verify_chunk`], also accepting an id keyed with `key`.
+pub async fn verify_chunk<S: Storage + ?Sized>(storage: &S, key: Option<&ChunkKey>, id: &str) -> Result<ChunkHealth> {
+    let health = storage.verify_chunk(id).await?;
+    let (ChunkHealth::Corrupt { .. }, Some(key)) = (&health, key) else { return Ok(health) };
+    Ok(match storage.get_chunk(id).await? {
+        Some(data) if key.chunk_id(&data) == id => ChunkHealth::Intact,
+        Some(_) => health,
+        // Deleted in between
+        None => ChunkHealth::Missing,
+    })
+}
+
+/// Verify every chunk in `storage`, and deal with corrupt ones as `action`
+/// says. Quarantined chunks go under `data_dir`.
+pub async fn verify_storage<S: Storage + ?Sized>(
+    storage: &S,
+    key: Option<&ChunkKey>,
+    data_dir: &Path,
+    action: CorruptChunks,
+) -> Result<StorageCheck> {
+    let mut check = StorageCheck::default();
+    let mut after = None;
+    loop {
+        let page = storage.list_chunks(after.as_deref(), PAGE).await?;
+        for id in &page {
+            match verify_chunk(storage, key, id).await {
+                Ok(ChunkHealth::Intact) => {}
+                // Collected since it was listed
+                Ok(ChunkHealth::Missing) => continue,
+                Ok(ChunkHealth::Corrupt { actual }) => {
+                    tracing::warn!("Chunk {} is corrupt: its content hashes to {}", id, actual);
+                    match remove(storage, data_dir, id, action).await {
+                        Ok(true) => check.removed += 1,
+                        Ok(false) => {}
+                        Err(e) => tracing::warn!("Cannot remove chunk {}: {:#}", id, e),
+                    }
+                    check.corrupt.push(id.clone());
+                }
+                Err(e) => {
+                    tracing::warn!("Cannot read chunk {}: {:#}", id, e);
+                    check.unreadable.push((id.clone(), format!("{:#}", e)));
+                }
+            }
+            check.checked += 1;
+            if check.checked.is_multiple_of(PAGE as u64) {
+                tracing::info!("Verified {} chunks", check.checked);
+            }
+        }
+        if page.len() < PAGE {
+            break;
+        }
+        after = page.last().cloned();
+    }
+    Ok(check)
+}
+
+/// Where a chunk quarantined from the store in `data_dir` is kept.
+pub fn quarantine_path(data_dir: &Path, id: &str) -> PathBuf {
+    data_dir.join(QUARANTINE_DIR).join(id)
+}
+
+/// Take a corrupt chunk out of the store as `action` says, returning
+/// whether it was.
+async fn remove<S: Storage + ?Sized>(storage: &S, data_dir: &Path, id: &str, action: CorruptChunks) -> Result<bool> {
+    if action == CorruptChunks::Keep {
+        return Ok(false);
+    }
+    if action == CorruptChunks::Quarantine {
+        let Some(data) = storage.get_chunk(id).await? else { return Ok(false) };
+        let path = quarantine_path(data_dir, id);
+        std::fs::create_dir_all(data_dir.join(QUARANTINE_DIR))
+            .and_then(|()| std::fs::write(&path, data))
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+    Ok(storage.delete_chunk(id).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn test_verify_storage() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let storage = MemoryStorage::new();
+        let key = ChunkKey::generate();
+        let plain = storage.put_chunk(b"plain").await?;
+        let keyed = key.chunk_id(b"keyed");
+        storage.put_chunk_as(&keyed, b"keyed").await?;
+        let bad = storage::chunk_id(b"original");
+        storage.put_chunk_as(&bad, b"rotted").await?;
+
+        // Keyed chunks verify only with the key.
+        let check = verify_storage(&storage, Some(&key), dir.path(), CorruptChunks::Keep).await?;
+        assert_eq!((check.checked, check.removed), (3, 0));
+        assert_eq!(check.corrupt, vec![bad.clone()]);
+        let check = verify_storage(&storage, None, dir.path(), CorruptChunks::Keep).await?;
+        assert_eq!(check.corrupt.len(), 2);
+
+        let check = verify_storage(&storage, Some(&key), dir.path(), CorruptChunks::Quarantine).await?;
+        assert_eq!(check.removed, 1);
+        assert!(!storage.has_chunk(&bad).await? && storage.has_chunk(&plain).await?);
+        assert_eq!(std::fs::read(quarantine_path(dir.path(), &bad))?, b"rotted");
+        assert!(verify_storage(&storage, Some(&key), dir.path(), CorruptChunks::Delete).await?.is_clean());
+        Ok(())
+    }
+}
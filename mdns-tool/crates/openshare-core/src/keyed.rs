@@ -0,0 +1,169 @@
+//! Chunk ids keyed with an account secret.
+//!
+//! Chunks are normally named by the SHA-256 of their bytes, so anyone who
+//! can list a chunk store (a NAS shared with other accounts, say) can tell
+//! whether it holds a file they have a copy of by hashing it the same way.
+//! With a [`ChunkKey`], the devices of an account name chunks by their
+//! BLAKE3 keyed hash under a secret they share instead. Such ids mean nothing
+//! without the key, at the cost of deduplicating only against chunks
+//! stored under the same key.
+//!
+//! A client with a key builds keyed manifests and opens every push with a
+//! [`ChunkKeyHeader`](crate::protocol::ChunkKeyHeader) naming the key by
+//! [`ChunkKey::key_id`]. The receiver refuses the transfer unless it holds
+//! the same key, and peers without `keyed-chunks` are not sent to at all.
+//! Chunks are accepted under either kind of id, so a device with a key
+//! still takes plain transfers. The whole-file hash in a manifest stays a
+//! plain SHA-256.
+
+use anyhow::Result;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+/// What [`ChunkKey::key_id`] authenticates.
+const KEY_ID_LABEL: &[u8] = b"openshare chunk key id v1";
+
+/// Secret shared by the devices of an account for keyed chunk ids.
+/// Serialized as hex, as it appears in the config.
+#[derive(Clone)]
+pub struct ChunkKey(Zeroizing<[u8; 32]>);
+
+impl ChunkKey {
+    /// A new random key, for the first device of an account.
+    pub fn generate() -> Self {
+        let mut key = Zeroizing::new([0u8; 32]);
+        OsRng.fill_bytes(&mut *key);
+        Self(key)
+    }
+
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let bytes = Zeroizing::new(hex::decode(hex.trim()).map_err(|_| anyhow::anyhow!("Chunk key is not hex"))?);
+        let key: [u8; 32] = bytes.as_slice().try_into()
+            .map_err(|_| anyhow::anyhow!("Invalid chunk key length: expected 32 bytes, got {}", bytes.len()))?;
+        Ok(Self(Zeroizing::new(key)))
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(*self.0)
+    }
+
+    /// Id of the chunk `data`: hex BLAKE3 keyed hash under this key.
+    pub fn chunk_id(&self, data: &[u8]) -> String {
+        blake3::keyed_hash(&self.0, data).to_hex().to_string()
+    }
+
+    /// Short public name of the key, so peers can tell whether they hold
+    /// the same one without giving it away.
+    pub fn key_id(&self) -> String {
+        hex::encode(&blake3::keyed_hash(&self.0, KEY_ID_LABEL).as_bytes()[..8])
+    }
+}
+
+impl std::fmt::Debug for ChunkKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ChunkKey").field(&self.key_id()).finish()
+    }
+}
+
+impl Serialize for ChunkKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_hex())
+    }
+}
+
+impl<'de> Deserialize<'de> for ChunkKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hex = Zeroizing::new(String::deserialize(deserializer)?);
+        Self::from_hex(&hex).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Id of `data`: keyed with `key` if given, its SHA-256 otherwise.
+pub fn chunk_id(key: Option<&ChunkKey>, data: &[u8]) -> String {
+    match key {
+        Some(key) => key.chunk_id(data),
+        None => hex::encode(Sha256::digest(data)),
+    }
+}
+
+/// Whether `id` names `data`, as a plain id or one keyed with `key`.
+pub fn matches(key: Option<&ChunkKey>, data: &[u8], id: &str) -> bool {
+    chunk_id(None, data) == id || key.is_some_and(|key| key.chunk_id(data) == id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::fixture::client;
+    use crate::sim::{pair, LinkConfig};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_keyed_ids() {
+        let key = ChunkKey::generate();
+        let other = ChunkKey::generate();
+        let plain = chunk_id(None, b"chunk");
+        let keyed = chunk_id(Some(&key), b"chunk");
+        assert_ne!(plain, keyed);
+        assert_ne!(keyed, other.chunk_id(b"chunk"));
+        assert_ne!(key.key_id(), other.key_id());
+
+        assert!(matches(Some(&key), b"chunk", &keyed));
+        assert!(matches(Some(&key), b"chunk", &plain));
+        assert!(!matches(None, b"chunk", &keyed));
+        assert!(!matches(Some(&other), b"chunk", &keyed));
+
+        let json = serde_json::to_string(&key).unwrap();
+        let back: ChunkKey = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.chunk_id(b"chunk"), keyed);
+        assert!(ChunkKey::from_hex("abcd").is_err());
+        assert!(!format!("{:?}", key).contains(&key.to_hex()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keyed_chunk_ids() {
+        use storage::Storage;
+
+        let (src, dst, other) = (TempDir::new().unwrap(), TempDir::new().unwrap(), TempDir::new().unwrap());
+        let input = src.path().join("input.bin");
+        let payload: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&input, &payload).unwrap();
+
+        let key = ChunkKey::generate();
+        let mut sender = client(&src, 1);
+        sender.cfg.chunk_key = Some(key.clone());
+        let mut receiver = client(&dst, 2);
+        receiver.cfg.chunk_key = Some(key.clone());
+
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (sent, received) = tokio::join!(
+            sender.send_file_streaming(a, &input, false),
+            receiver.accept_and_receive(b),
+        );
+        let manifest = received.unwrap();
+        assert_eq!(sent.unwrap().chunk_hashes, manifest.chunk_hashes);
+
+        // Chunks are stored under their keyed ids only.
+        let first = &payload[..64 * 1024];
+        assert_eq!(manifest.chunk_hashes[0], key.chunk_id(first));
+        assert!(receiver.storage.has_chunk(&key.chunk_id(first)).await.unwrap());
+        assert!(!receiver.storage.has_chunk(&chunk_id(None, first)).await.unwrap());
+        let output = dst.path().join("input.bin");
+        receiver.write_file(&manifest, &output).await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), payload);
+
+        // A device without the key refuses before taking any chunk.
+        let stranger = client(&other, 3);
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (sent, received) = tokio::join!(
+            sender.send_file_streaming(a, &input, false),
+            stranger.accept_and_receive(b),
+        );
+        let err = format!("{:#}", received.unwrap_err());
+        assert!(err.contains("does not have"), "{}", err);
+        assert!(sent.is_err());
+        assert!(stranger.storage.list_chunks(None, 10).await.unwrap().is_empty());
+    }
+}
@@ -1,19 +1,107 @@
 use ed25519_dalek::{SigningKey, VerifyingKey, Signature, Signer, Verifier};
 use rand_core::OsRng;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
 use anyhow::{Context, Result};
 use hex;
+use serde::{Deserialize, Serialize};
+use sha2::Sha512;
+use crate::account::DeviceCertificate;
+use crate::keystore;
+use zeroize::Zeroizing;
+
+/// Where the identity's private key is kept.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyBackend {
+    /// Plain 32-byte key file (`identity.key` in the data directory)
+    #[default]
+    File,
+    /// OS keystore (macOS Keychain, Windows Credential Manager, Secret
+    /// Service elsewhere); nothing secret is written to the data directory.
+    /// Needs the `keystore` cargo feature
+    Keystore,
+}
 
 /// Identity wrapper for Ed25519 keypair used for device identity.
 ///
-/// NOTE: Production should use OS keystore/secure enclave. This is a simple
-/// on-disk representation for development and testing.
+/// The key lives in a plain file or, with [`KeyBackend::Keystore`], in the
+/// OS keystore; see [`Identity::generate_with`] and [`Identity::load_with`].
+/// A rotated key carries the rotations that led to it, and a device of an
+/// account its certificate, both kept next to the key; see
+/// [`Identity::rotate`] and [`crate::account`].
 #[derive(Clone)]
 pub struct Identity {
     pub signing_key: SigningKey,
+    /// Rotations from the first key to this one, oldest first
+    pub history: Vec<KeyRotation>,
+    /// Certificate from the account root, for this key or an earlier one
+    pub certificate: Option<DeviceCertificate>,
+}
+
+impl From<SigningKey> for Identity {
+    fn from(signing_key: SigningKey) -> Self {
+        Self { signing_key, history: Vec::new(), certificate: None }
+    }
+}
+
+/// Signature context of a [`KeyRotation`].
+const ROTATION_CONTEXT: &[u8] = b"openshare key rotation v1";
+
+/// Longest key history accepted, from a peer or on disk.
+pub const MAX_KEY_HISTORY: usize = 32;
+
+/// A move from one identity key to the next. The old key signs it, so
+/// only its holder can rotate, and so does the new one, so a rotation
+/// cannot claim a key someone else holds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyRotation {
+    pub old_key: [u8; 32],
+    pub new_key: [u8; 32],
+    /// Unix timestamp (seconds) of the rotation
+    pub rotated_at: u64,
+    pub old_signature: Vec<u8>,
+    pub new_signature: Vec<u8>,
+}
+
+impl KeyRotation {
+    fn signed_bytes(old_key: &[u8; 32], new_key: &[u8; 32], rotated_at: u64) -> Vec<u8> {
+        [ROTATION_CONTEXT, old_key, new_key, &rotated_at.to_be_bytes()].concat()
+    }
+
+    /// Check the signatures of both keys.
+    pub fn verify(&self) -> Result<()> {
+        let signed = Self::signed_bytes(&self.old_key, &self.new_key, self.rotated_at);
+        for (key, sig, which) in [(&self.old_key, &self.old_signature, "old"), (&self.new_key, &self.new_signature, "new")] {
+            let sig = Signature::from_slice(sig)
+                .map_err(|_| anyhow::anyhow!("Malformed {} key signature on key rotation", which))?;
+            Identity::verify_with_pubkey(key, &signed, &sig)
+                .map_err(|_| anyhow::anyhow!("Invalid {} key signature on key rotation", which))?;
+        }
+        Ok(())
+    }
+}
+
+/// Check that `history` is a chain of valid rotations, each from the key
+/// the one before moved to, ending at `current`.
+pub fn verify_history(history: &[KeyRotation], current: &[u8; 32]) -> Result<()> {
+    if history.len() > MAX_KEY_HISTORY {
+        anyhow::bail!("Key history has {} rotations, more than {}", history.len(), MAX_KEY_HISTORY);
+    }
+    if history.windows(2).any(|pair| pair[0].new_key != pair[1].old_key) {
+        anyhow::bail!("Key history is not a chain");
+    }
+    if history.last().is_some_and(|last| last.new_key != *current) {
+        anyhow::bail!("Key history does not end at the current key");
+    }
+    history.iter().try_for_each(KeyRotation::verify)
 }
 
+/// Length of [`Identity::fingerprint`], the shortest fingerprint accepted
+/// by [`Identity::fingerprint_matches`].
+pub const MIN_FINGERPRINT_LEN: usize = 8;
+
 impl Identity {
     /// Generate a new identity keypair and persist to `path`.
     /// The file stores the 32-byte secret key.
@@ -26,22 +114,159 @@ impl Identity {
         }
 
         // Store the secret key bytes
-        fs::write(path, signing_key.to_bytes()).context("writing identity file")?;
+        let identity = Self::from(signing_key);
+        fs::write(path, *identity.secret_bytes()).context("writing identity file")?;
         tracing::info!("Generated new identity at {:?}", path);
-        Ok(Self { signing_key })
+        Ok(identity)
     }
 
     /// Load an identity from path.
     pub fn load(path: &Path) -> Result<Self> {
-        let data = fs::read(path).context("reading identity file")?;
+        let data = Zeroizing::new(fs::read(path).context("reading identity file")?);
         if data.len() != 32 {
             anyhow::bail!("Invalid key file length: expected 32 bytes, got {}", data.len());
         }
-        let mut key_bytes = [0u8; 32];
+        let mut key_bytes = Zeroizing::new([0u8; 32]);
         key_bytes.copy_from_slice(&data);
         let signing_key = SigningKey::from_bytes(&key_bytes);
         tracing::info!("Loaded identity from {:?}", path);
-        Ok(Self { signing_key })
+        Self::from(signing_key).with_records(path)
+    }
+
+    /// Generate a new identity and keep it in `backend`. `path` is the key
+    /// file for [`KeyBackend::File`]; for the keystore it only names the
+    /// entry, so each data directory has its own identity.
+    pub fn generate_with(backend: KeyBackend, path: &Path) -> Result<Self> {
+        match backend {
+            KeyBackend::File => Self::generate_and_store(path),
+            KeyBackend::Keystore => {
+                let identity = Self::from(SigningKey::generate(&mut OsRng));
+                keystore::store(&keystore_account(path), &*identity.secret_bytes())?;
+                tracing::info!("Generated new identity in the OS keystore for {:?}", path);
+                Ok(identity)
+            }
+        }
+    }
+
+    /// Load an identity kept in `backend` (see [`Identity::generate_with`]).
+    pub fn load_with(backend: KeyBackend, path: &Path) -> Result<Self> {
+        match backend {
+            KeyBackend::File => Self::load(path),
+            KeyBackend::Keystore => {
+                let account = keystore_account(path);
+                let secret = keystore::load(&account)?
+                    .with_context(|| format!("No identity for {} in the OS keystore", account))?;
+                let key_bytes: Zeroizing<[u8; 32]> = Zeroizing::new(secret.as_slice().try_into()
+                    .map_err(|_| anyhow::anyhow!("Invalid keystore entry length: expected 32 bytes, got {}", secret.len()))?);
+                tracing::info!("Loaded identity from the OS keystore for {:?}", path);
+                Self::from(SigningKey::from_bytes(&key_bytes)).with_records(path)
+            }
+        }
+    }
+
+    /// Move the key file at `path` into the OS keystore, deleting the file
+    /// once the keystore returns the same key.
+    pub fn move_to_keystore(path: &Path) -> Result<Self> {
+        let identity = Self::load(path)?;
+        keystore::store(&keystore_account(path), &*identity.secret_bytes())?;
+        let stored = Self::load_with(KeyBackend::Keystore, path)?;
+        if stored.public_key_bytes() != identity.public_key_bytes() {
+            anyhow::bail!("OS keystore returned a different key; keeping {}", path.display());
+        }
+        // Overwrite before unlinking so the key is not left in freed blocks
+        // on simple filesystems.
+        fs::write(path, [0u8; 32]).context("clearing identity file")?;
+        fs::remove_file(path).context("removing identity file")?;
+        Ok(identity)
+    }
+
+    /// Generate the next identity key. The old and the new key both sign
+    /// the rotation, which ends the history of the returned identity, so
+    /// peers that pinned any earlier key can follow it to the new one.
+    pub fn rotate(&self) -> Self {
+        let next = SigningKey::generate(&mut OsRng);
+        let old_key = self.public_key_bytes();
+        let new_key = next.verifying_key().to_bytes();
+        let rotated_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let signed = KeyRotation::signed_bytes(&old_key, &new_key, rotated_at);
+        let rotation = KeyRotation {
+            old_key,
+            new_key,
+            rotated_at,
+            old_signature: self.sign(&signed).to_bytes().to_vec(),
+            new_signature: next.sign(&signed).to_bytes().to_vec(),
+        };
+        let mut history = self.history.clone();
+        history.push(rotation);
+        Self { signing_key: next, history, certificate: self.certificate.clone() }
+    }
+
+    /// Rotate the identity kept in `backend` (see [`Identity::generate_with`])
+    /// and keep the new key and its history in place of the old key.
+    pub fn rotate_with(backend: KeyBackend, path: &Path) -> Result<Self> {
+        let next = Self::load_with(backend, path)?.rotate();
+        if next.history.len() > MAX_KEY_HISTORY {
+            anyhow::bail!("The identity key was rotated {} times already", MAX_KEY_HISTORY);
+        }
+        // History first: loading ignores a rotation to a key that was
+        // never stored.
+        crate::state::write(&history_path(path), serde_json::to_string_pretty(&next.history)?.as_bytes())?;
+        match backend {
+            KeyBackend::File => crate::state::write_atomic(path, &*next.secret_bytes())?,
+            KeyBackend::Keystore => keystore::store(&keystore_account(path), &*next.secret_bytes())?,
+        }
+        tracing::info!("Rotated identity to {}", next.fingerprint());
+        Ok(next)
+    }
+
+    /// Keep this identity, with its history and certificate, in `backend`
+    /// (see [`Identity::generate_with`]) in place of whatever is there.
+    pub fn store_with(&self, backend: KeyBackend, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Records first, as in `rotate_with`. Any left from another key
+        // are ignored on loading.
+        crate::state::write(&history_path(path), serde_json::to_string_pretty(&self.history)?.as_bytes())?;
+        if let Some(certificate) = &self.certificate {
+            crate::state::write(&certificate_path(path), serde_json::to_string_pretty(certificate)?.as_bytes())?;
+        }
+        match backend {
+            KeyBackend::File => crate::state::write_atomic(path, &*self.secret_bytes())?,
+            KeyBackend::Keystore => keystore::store(&keystore_account(path), &*self.secret_bytes())?,
+        }
+        tracing::info!("Stored identity {}", self.fingerprint());
+        Ok(())
+    }
+
+    /// Keep `certificate` for the key at `path` (see [`crate::account`]).
+    pub fn store_certificate(&mut self, path: &Path, certificate: DeviceCertificate) -> Result<()> {
+        if !certificate.covers(&self.public_key_bytes(), &self.history) {
+            anyhow::bail!("The certificate is for another key ({})", hex::encode(certificate.public_key));
+        }
+        crate::state::write(&certificate_path(path), serde_json::to_string_pretty(&certificate)?.as_bytes())?;
+        self.certificate = Some(certificate);
+        Ok(())
+    }
+
+    /// Attach the history and certificate kept for the key at `path`. The
+    /// history ends at the rotation to this key, and a certificate for a
+    /// key this one did not come from is left out.
+    fn with_records(mut self, path: &Path) -> Result<Self> {
+        let mut history: Vec<KeyRotation> = crate::state::read(&history_path(path), |json| Ok(serde_json::from_slice(json)?))?
+            .unwrap_or_default();
+        let current = self.public_key_bytes();
+        match history.iter().rposition(|r| r.new_key == current) {
+            Some(i) => history.truncate(i + 1),
+            None => history.clear(),
+        }
+        self.history = history;
+        self.certificate = crate::state::read(&certificate_path(path), |json| Ok(serde_json::from_slice::<DeviceCertificate>(json)?))?
+            .filter(|cert| cert.covers(&current, &self.history));
+        Ok(self)
     }
 
     /// Load existing identity or generate a new one if not found.
@@ -53,6 +278,18 @@ impl Identity {
         }
     }
 
+    /// The 32-byte secret key, cleared from memory when dropped. The
+    /// `SigningKey` clears its own copy.
+    pub fn secret_bytes(&self) -> Zeroizing<[u8; 32]> {
+        Zeroizing::new(self.signing_key.to_bytes())
+    }
+
+    /// The X25519 form of the secret key, used as the static key of the
+    /// Noise handshake. Its public key is the Montgomery form of ours.
+    pub fn x25519_secret_bytes(&self) -> Zeroizing<[u8; 32]> {
+        Zeroizing::new(self.signing_key.to_scalar_bytes())
+    }
+
     pub fn public_key_bytes(&self) -> [u8; 32] {
         self.signing_key.verifying_key().to_bytes()
     }
@@ -72,10 +309,26 @@ impl Identity {
         hex::encode(self.public_key_bytes())
     }
 
+    /// Whether `public_key` has `fingerprint`: a hex prefix of the key of
+    /// at least [`MIN_FINGERPRINT_LEN`] characters, such as the short
+    /// fingerprint advertised over mDNS or the full one. Longer prefixes
+    /// are harder to forge. Case and `:` separators are ignored.
+    pub fn fingerprint_matches(public_key: &[u8; 32], fingerprint: &str) -> bool {
+        let wanted = fingerprint.replace(':', "").to_ascii_lowercase();
+        wanted.len() >= MIN_FINGERPRINT_LEN && hex::encode(public_key).starts_with(&wanted)
+    }
+
     pub fn sign(&self, msg: &[u8]) -> Signature {
         self.signing_key.sign(msg)
     }
 
+    /// Sign with Ed25519ph: `prehash` holds the message, already hashed,
+    /// and `context` separates this use of the key from others.
+    pub fn sign_prehashed(&self, prehash: Sha512, context: &[u8]) -> Result<Signature> {
+        self.signing_key.sign_prehashed(prehash, Some(context))
+            .context("Failed to sign prehashed message")
+    }
+
     /// Verify a signature by a public key.
     pub fn verify_with_pubkey(
         pubkey: &[u8; 32],
@@ -85,4 +338,85 @@ impl Identity {
         let pk = VerifyingKey::from_bytes(pubkey)?;
         pk.verify(msg, sig)
     }
-}
\ No newline at end of file
+}
+
+/// Where the history of the identity key at `path` is kept.
+fn history_path(path: &Path) -> PathBuf {
+    path.with_extension("history.json")
+}
+
+/// Where the account certificate of the identity key at `path` is kept.
+fn certificate_path(path: &Path) -> PathBuf {
+    path.with_extension("cert.json")
+}
+
+/// Keystore entry name for the identity that would live at `path`.
+/// The file itself need not exist, so only its directory is canonicalized.
+fn keystore_account(path: &Path) -> String {
+    let dir = path.parent()
+        .and_then(|p| p.canonicalize().ok())
+        .unwrap_or_else(|| path.parent().unwrap_or(Path::new("")).to_path_buf());
+    dir.join(path.file_name().unwrap_or_default()).display().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::fixture::client;
+    use crate::sim::{pair, LinkConfig};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_key_rotation() {
+        use crate::trust::{TrustSource, TrustStatus};
+
+        let (src, dst) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let input = src.path().join("input.bin");
+        std::fs::write(&input, vec![5u8; 10_000]).unwrap();
+
+        let mut sender = client(&src, 1);
+        let mut receiver = client(&dst, 2);
+        receiver.cfg.trust_on_first_use = false;
+        {
+            let mut trust = receiver.trust.lock().unwrap();
+            trust.add("dev1", &sender.identity.public_key_bytes(), TrustSource::Paired);
+            trust.set_admin("dev1", true);
+        }
+        let manifest = sender.import_file(&input).await.unwrap();
+
+        // Rotated twice: the pin follows both steps at once.
+        sender.identity = Arc::new(sender.identity.rotate().rotate());
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (sent, received) = tokio::join!(
+            sender.send_manifest_over(a, manifest.clone()),
+            receiver.accept_and_receive(b),
+        );
+        sent.unwrap();
+        received.unwrap();
+        let new_key = sender.identity.public_key_bytes();
+        {
+            let trust = receiver.trust.lock().unwrap();
+            assert_eq!(trust.check("dev1", &new_key), TrustStatus::Trusted);
+            let pinned = trust.get("dev1").unwrap();
+            assert!(pinned.admin && pinned.source == TrustSource::Paired);
+        }
+
+        // Another key claiming dev1 has no rotation from the pinned key.
+        let mut impostor = client(&TempDir::new().unwrap(), 3);
+        impostor.cfg.device_id = "dev1".into();
+        impostor.identity = Arc::new(impostor.identity.rotate());
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (sent, received) = tokio::join!(
+            impostor.send_manifest_over(a, manifest.clone()),
+            receiver.accept_and_receive(b),
+        );
+        assert!(sent.is_err());
+        assert!(format!("{:#}", received.unwrap_err()).contains("Key mismatch"));
+
+        // Nor does a forged one.
+        let mut forged = sender.identity.history[0].clone();
+        forged.rotated_at += 1;
+        assert!(verify_history(&[forged], &sender.identity.history[0].new_key).is_err());
+    }
+}
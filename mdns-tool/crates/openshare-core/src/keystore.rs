@@ -0,0 +1,71 @@
+//! Platform keystore access for identity keys.
+//!
+//! Secrets are stored as hex under service [`SERVICE`] and an account name
+//! chosen by the caller, through the `keyring` crate: the macOS Keychain,
+//! the Windows Credential Manager, and the freedesktop Secret Service
+//! (GNOME Keyring or KWallet) elsewhere. It talks to each through its own
+//! API, so secrets never pass through another process.
+//!
+//! Keystore access needs the `keystore` cargo feature; without it every
+//! call fails.
+
+use anyhow::Result;
+#[cfg(feature = "keystore")]
+use anyhow::Context;
+#[cfg(feature = "keystore")]
+use keyring::{Entry, Error};
+use zeroize::Zeroizing;
+
+/// Keystore service name for OpenShare entries.
+pub const SERVICE: &str = "openshare";
+
+#[cfg(feature = "keystore")]
+fn entry(account: &str) -> Result<Entry> {
+    Entry::new(SERVICE, account).with_context(|| format!("Invalid keystore entry {}", account))
+}
+
+/// Store `secret` under `account`, replacing any previous value.
+#[cfg(feature = "keystore")]
+pub fn store(account: &str, secret: &[u8]) -> Result<()> {
+    let encoded = Zeroizing::new(hex::encode(secret));
+    entry(account)?
+        .set_password(&encoded)
+        .with_context(|| format!("Failed to add {} to the OS keystore (is a keyring unlocked?)", account))
+}
+
+/// The secret stored under `account`, or `None` if there is none.
+#[cfg(feature = "keystore")]
+pub fn load(account: &str) -> Result<Option<Zeroizing<Vec<u8>>>> {
+    let encoded = match entry(account)?.get_password() {
+        Ok(encoded) => Zeroizing::new(encoded),
+        Err(Error::NoEntry) => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {} from the OS keystore", account)),
+    };
+    let secret = hex::decode(encoded.trim())
+        .with_context(|| format!("Keystore entry {} is not hex", account))?;
+    Ok(Some(Zeroizing::new(secret)))
+}
+
+/// Remove the secret stored under `account`, if any.
+#[cfg(feature = "keystore")]
+pub fn delete(account: &str) -> Result<()> {
+    match entry(account)?.delete_credential() {
+        Ok(()) | Err(Error::NoEntry) => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("Failed to remove {} from the OS keystore", account)),
+    }
+}
+
+#[cfg(not(feature = "keystore"))]
+pub fn store(_account: &str, _secret: &[u8]) -> Result<()> {
+    anyhow::bail!("Built without the keystore feature")
+}
+
+#[cfg(not(feature = "keystore"))]
+pub fn load(_account: &str) -> Result<Option<Zeroizing<Vec<u8>>>> {
+    anyhow::bail!("Built without the keystore feature")
+}
+
+#[cfg(not(feature = "keystore"))]
+pub fn delete(_account: &str) -> Result<()> {
+    anyhow::bail!("Built without the keystore feature")
+}
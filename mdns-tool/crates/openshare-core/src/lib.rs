@@ -3,14 +3,75 @@
 //! A secure, local-first file transfer system with strong cryptographic
 //! guarantees and minimal server dependencies.
 
+pub mod account;
+pub mod admin;
+pub mod audit;
+pub mod backup;
+pub mod chunking;
 pub mod config;
+pub mod daemon;
+pub mod env;
+pub mod error;
+pub mod events;
+pub mod history;
+pub mod index;
+pub mod integrity;
+pub mod keyed;
 pub mod keys;
+pub mod keystore;
 pub mod manifest;
+pub mod manifests;
+pub mod merkle;
+pub mod metrics;
+pub mod notify;
+pub mod offers;
+pub mod pages;
+pub mod placeholder;
+pub mod policy;
+pub mod pool;
+pub mod preview;
+pub mod profiles;
+pub mod protocol;
+pub mod quarantine;
+pub mod registry;
+pub mod replicate;
+pub mod scheduler;
 pub mod handshake;
+pub mod resumption;
+pub mod noise;
 pub mod client;
+pub mod codec;
+#[cfg(test)]
+mod compat;
+pub mod discovery;
+pub mod encrypted;
+pub mod service;
+pub mod sidecar;
+pub mod space;
+pub mod state;
+pub mod stream;
+pub mod swarm;
+pub mod sync;
+pub mod transfer;
+pub mod trash;
+pub mod trust;
+pub mod vfs;
+pub mod watcher;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+#[cfg(all(feature = "fuse", target_os = "linux"))]
+pub mod fuse;
+#[cfg(any(test, feature = "sim"))]
+pub mod sim;
 
 // Re-export commonly used types
 pub use config::ClientConfig;
+pub use env::Env;
+pub use error::OpenShareError;
+pub use events::{TransferEvent, TransferObserver};
 pub use keys::Identity;
 pub use manifest::Manifest;
-pub use client::Client;
\ No newline at end of file
+pub use client::{Accepted, Client, Pairing};
+pub use discovery::Discovery;
+pub use registry::{PeerEvent, PeerRegistry};
+pub use trust::TrustStore;
\ No newline at end of file
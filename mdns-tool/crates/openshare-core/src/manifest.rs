@@ -1,44 +1,276 @@
 use serde::{Serialize, Deserialize};
-use sha2::{Digest, Sha256};
+use sha2::{Digest, Sha256, Sha512};
 use anyhow::{Result, Context};
 use std::fs::File;
 use std::io::{Read, Seek, SeekFrom};
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
 use hex::encode as hex_encode;
 use crate::Identity;
+use crate::error::OpenShareError;
+use crate::chunking::Chunking;
+use crate::keyed::{self, ChunkKey};
+use crate::preview::Preview;
+use crate::sync::Move;
+use crate::trash::Trash;
 use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use storage::Storage;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 /// Manifest describing a file transfer: filename, size, ordered chunk hashes,
 /// and an optional sender signature over the manifest.
+///
+/// For a directory transfer `filename` is the directory name, `size` the
+/// total of all files, `chunk_hashes` the chunks of every file in `files`
+/// order, and `files` maps each entry onto its slice of `chunk_hashes`.
+///
+/// Peers older than protocol 3 know neither `file_hash` nor its place in
+/// the encoding. A manifest with an empty `file_hash` is encoded and signed
+/// in their layout, so it round-trips through such peers unchanged.
+/// The [`Preview`], for peers with `previews`, likewise follows the rest
+/// of the encoding only when there is one, as do `metadata`, for peers
+/// with `file-metadata`, and the chunk size.
+///
+/// The signature covers that encoding without `sender_sig`. A plain
+/// Ed25519 signature needs it in memory in full; for peers with the
+/// `manifest-ph` feature, [`sign_prehashed`](Self::sign_prehashed) instead
+/// signs a SHA-512 of it with Ed25519ph, hashed as it is encoded, so a
+/// manifest of millions of entries signs and verifies without a copy. Such
+/// a signature is [`PREHASHED_SIG_TAG`] followed by the 64 signature bytes.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Manifest {
     pub filename: String,
     pub size: u64,
+    /// Hex SHA-256 of the full content (all files in `files` order for a
+    /// directory); empty if the sender predates whole-file hashes
+    #[serde(default)]
+    pub file_hash: String,
     pub chunk_hashes: Vec<String>,
+    /// Entries of a directory; empty for a single file, and in manifests
+    /// from before directories could be sent
+    #[serde(default)]
+    pub files: Vec<FileEntry>,
     pub sender_sig: Option<Vec<u8>>,
     pub sender_pubkey: Option<Vec<u8>>, // Store sender's public key for verification
+    /// Times, permissions and symlinks of the file or tree, if recorded
+    #[serde(default)]
+    pub metadata: Vec<FileMeta>,
+    /// Length of every chunk but each file's last, if cut to a fixed size;
+    /// `None` for content-defined chunks and manifests that do not say
+    #[serde(default)]
+    pub chunk_size: Option<u32>,
+    /// What a single media file looks like, for the receiver to show
+    /// before accepting it (see [`crate::preview`])
+    #[serde(default)]
+    pub preview: Option<Preview>,
+}
+
+/// First byte of an Ed25519ph manifest signature.
+pub const PREHASHED_SIG_TAG: u8 = 1;
+
+/// Ed25519ph context of manifest signatures.
+const PREHASH_CONTEXT: &[u8] = b"openshare manifest";
+
+/// A manifest's signed content, borrowed: encodes as
+/// [`Manifest::to_bytes`] does with `sender_sig` unset.
+#[derive(Serialize)]
+struct Unsigned<'a> {
+    filename: &'a str,
+    size: u64,
+    file_hash: &'a str,
+    chunk_hashes: &'a [String],
+    files: &'a [FileEntry],
+    sender_sig: Option<()>,
+    sender_pubkey: &'a Option<Vec<u8>>,
+}
+
+/// [`Unsigned`] in the [`LegacyManifest`] layout.
+#[derive(Serialize)]
+struct UnsignedLegacy<'a> {
+    filename: &'a str,
+    size: u64,
+    chunk_hashes: &'a [String],
+    files: &'a [FileEntry],
+    sender_sig: Option<()>,
+    sender_pubkey: &'a Option<Vec<u8>>,
+}
+
+/// [`UnsignedLegacy`] from before directories could be sent.
+#[derive(Serialize)]
+struct UnsignedFlat<'a> {
+    filename: &'a str,
+    size: u64,
+    chunk_hashes: &'a [String],
+    sender_sig: Option<()>,
+    sender_pubkey: &'a Option<Vec<u8>>,
+}
+
+/// Encoding of [`Manifest`] before `preview` was added.
+#[derive(Serialize, Deserialize)]
+struct PlainManifest {
+    filename: String,
+    size: u64,
+    file_hash: String,
+    chunk_hashes: Vec<String>,
+    files: Vec<FileEntry>,
+    sender_sig: Option<Vec<u8>>,
+    sender_pubkey: Option<Vec<u8>>,
+}
+
+/// Encoding of [`Manifest`] before `file_hash` was added.
+#[derive(Serialize, Deserialize)]
+struct LegacyManifest {
+    filename: String,
+    size: u64,
+    chunk_hashes: Vec<String>,
+    files: Vec<FileEntry>,
+    sender_sig: Option<Vec<u8>>,
+    sender_pubkey: Option<Vec<u8>>,
+}
+
+/// One entry of a directory manifest.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    /// Path relative to the transferred directory, `/`-separated
+    pub path: String,
+    pub size: u64,
+    /// Index of the entry's first chunk in `Manifest::chunk_hashes`
+    pub first_chunk: usize,
+    pub chunk_count: usize,
+    /// Directories are recorded so empty ones survive the transfer
+    pub is_dir: bool,
+}
+
+impl FileEntry {
+    /// Resolve the entry under `root`, rejecting absolute paths and `..`
+    /// so a malicious manifest cannot write outside the target directory.
+    pub fn resolve(&self, root: &Path) -> Result<PathBuf, OpenShareError> {
+        Ok(resolve_path(root, &self.path)?)
+    }
+}
+
+/// Metadata of the transferred file, or of one path of a tree.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FileMeta {
+    /// Path as in `files`; empty for the transferred file or directory itself
+    pub path: String,
+    /// Modification time in nanoseconds since the Unix epoch
+    pub modified: Option<u64>,
+    /// Unix permission bits (`0o777` at most)
+    pub mode: Option<u32>,
+    /// Whether the file is executable, for receivers that cannot apply `mode`
+    pub executable: bool,
+    /// Target of a symbolic link, relative and inside the tree. Links have
+    /// no entry in `files`.
+    pub symlink: Option<String>,
+}
+
+impl FileMeta {
+    /// Metadata of `meta`, found at `path`.
+    fn capture(path: String, meta: &std::fs::Metadata) -> Self {
+        let modified = meta.modified().ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .and_then(|d| u64::try_from(d.as_nanos()).ok());
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            Some(meta.permissions().mode() & 0o777)
+        };
+        #[cfg(not(unix))]
+        let mode = None;
+        Self {
+            path,
+            modified,
+            mode,
+            executable: mode.is_some_and(|m| m & 0o111 != 0),
+            symlink: None,
+        }
+    }
+
+    /// Where the metadata applies under `root`; see [`FileEntry::resolve`].
+    pub fn resolve(&self, root: &Path) -> Result<PathBuf, OpenShareError> {
+        if self.path.is_empty() {
+            return Ok(root.to_path_buf());
+        }
+        Ok(resolve_path(root, &self.path)?)
+    }
+}
+
+fn resolve_path(root: &Path, path: &str) -> Result<PathBuf> {
+    let rel = Path::new(path);
+    if rel.as_os_str().is_empty()
+        || !rel.components().all(|c| matches!(c, Component::Normal(_)))
+    {
+        anyhow::bail!("Unsafe path in manifest: {}", path);
+    }
+    Ok(root.join(rel))
+}
+
+/// Whether a symlink target stays inside the tree from a link `depth`
+/// directories below its root: it may climb out of the link's directory
+/// with leading `..`, but not past the root, and not after descending,
+/// where a link on the way could have moved it.
+fn link_stays_inside(depth: usize, target: &str) -> bool {
+    let path = Path::new(target);
+    if target.is_empty() || path.has_root() {
+        return false;
+    }
+    let mut up = 0;
+    let mut descended = false;
+    for c in path.components() {
+        match c {
+            Component::CurDir => {}
+            Component::ParentDir if !descended => up += 1,
+            Component::Normal(_) => descended = true,
+            _ => return false,
+        }
+    }
+    up <= depth
+}
+
+/// Hash a reader in chunks cut by `chunking`, also feeding everything
+/// into `whole`. Chunks are cut as when sending them (see
+/// [`crate::chunking`]); fixed ones are always full except the last,
+/// matching how chunks are cut when storing. With `key`, chunk ids are
+/// keyed (see [`crate::keyed`]).
+fn hash_chunks<R: Read>(r: &mut R, chunking: Chunking, key: Option<&ChunkKey>, whole: &mut Sha256) -> Result<Vec<String>> {
+    let mut chunk_hashes = Vec::new();
+    let mut buf = vec![0u8; chunking.max_len()];
+    let mut len = 0;
+    loop {
+        while len < buf.len() {
+            let read = r.read(&mut buf[len..])?;
+            if read == 0 { break; }
+            len += read;
+        }
+        if len == 0 { break; }
+
+        let n = chunking.cut(&buf[..len]);
+        whole.update(&buf[..n]);
+        chunk_hashes.push(keyed::chunk_id(key, &buf[..n]));
+        buf.copy_within(n..len, 0);
+        len -= n;
+    }
+    Ok(chunk_hashes)
 }
 
 impl Manifest {
     /// Build a manifest by chunking a file from disk using chunk_size.
-    pub fn from_file(path: &str, chunk_size: usize) -> Result<Self> {
+    pub fn from_file(path: &str, chunk_size: usize) -> Result<Self, OpenShareError> {
+        Self::from_file_chunked(path, Chunking::Fixed(chunk_size), None)
+    }
+
+    /// Like [`from_file`](Self::from_file), cutting chunks by `chunking`
+    /// and keying their ids with `key` if given.
+    pub fn from_file_chunked(path: &str, chunking: Chunking, key: Option<&ChunkKey>) -> Result<Self, OpenShareError> {
         let mut f = File::open(path)
             .with_context(|| format!("Failed to open file: {}", path))?;
 
         let size = f.seek(SeekFrom::End(0))?;
         f.seek(SeekFrom::Start(0))?;
 
-        let mut chunk_hashes = Vec::new();
-        let mut buf = vec![0u8; chunk_size];
-
-        loop {
-            let n = f.read(&mut buf)?;
-            if n == 0 { break; }
-
-            let mut hasher = Sha256::new();
-            hasher.update(&buf[..n]);
-            let h = hasher.finalize();
-            chunk_hashes.push(hex_encode(h));
-        }
+        let mut whole = Sha256::new();
+        let chunk_hashes = hash_chunks(&mut f, chunking, key, &mut whole)?;
 
         // Extract just the filename, not the full path
         let filename = std::path::Path::new(path)
@@ -50,23 +282,385 @@ impl Manifest {
         Ok(Self {
             filename,
             size,
+            file_hash: hex_encode(whole.finalize()),
             chunk_hashes,
+            files: Vec::new(),
             sender_sig: None,
             sender_pubkey: None,
+            metadata: vec![FileMeta::capture(String::new(), &f.metadata()?)],
+            chunk_size: chunking.fixed_size(),
+            preview: None,
         })
     }
 
+    /// Build a manifest for a directory tree. Entries are sorted by path;
+    /// symlinks pointing inside the tree go into `metadata`, others and
+    /// special files are skipped.
+    pub fn from_dir(root: &Path, chunk_size: usize) -> Result<Self, OpenShareError> {
+        Self::from_dir_chunked(root, Chunking::Fixed(chunk_size), None)
+    }
+
+    /// Like [`from_dir`](Self::from_dir), cutting chunks by `chunking`
+    /// and keying their ids with `key` if given.
+    pub fn from_dir_chunked(root: &Path, chunking: Chunking, key: Option<&ChunkKey>) -> Result<Self, OpenShareError> {
+        let (mut paths, mut links) = (Vec::new(), Vec::new());
+        collect_entries(root, root, &mut paths, &mut links)?;
+        paths.sort();
+
+        let mut files = Vec::with_capacity(paths.len());
+        let mut chunk_hashes = Vec::new();
+        let mut size = 0;
+        let mut whole = Sha256::new();
+        let mut metadata = vec![FileMeta::capture(String::new(), &std::fs::metadata(root)?)];
+
+        for rel in paths {
+            let full = root.join(&rel);
+            let path = slash_path(&rel);
+            metadata.push(FileMeta::capture(path.clone(), &std::fs::metadata(&full)?));
+
+            if full.is_dir() {
+                files.push(FileEntry { path, size: 0, first_chunk: chunk_hashes.len(), chunk_count: 0, is_dir: true });
+                continue;
+            }
+
+            let mut f = File::open(&full)
+                .with_context(|| format!("Failed to open file: {}", full.display()))?;
+            let hashes = hash_chunks(&mut f, chunking, key, &mut whole)?;
+            let file_size = f.metadata()?.len();
+
+            files.push(FileEntry {
+                path,
+                size: file_size,
+                first_chunk: chunk_hashes.len(),
+                chunk_count: hashes.len(),
+                is_dir: false,
+            });
+            chunk_hashes.extend(hashes);
+            size += file_size;
+        }
+
+        let filename = root.file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| anyhow::anyhow!("Invalid directory name: {}", root.display()))?
+            .to_string();
+
+        for rel in links {
+            let full = root.join(&rel);
+            let target = slash_path(&std::fs::read_link(&full)?);
+            if !link_stays_inside(rel.components().count() - 1, &target) {
+                tracing::warn!("Skipping symlink leading out of the tree: {}", full.display());
+                continue;
+            }
+            let meta = std::fs::symlink_metadata(&full)?;
+            metadata.push(FileMeta { symlink: Some(target), ..FileMeta::capture(slash_path(&rel), &meta) });
+        }
+        metadata.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(Self {
+            filename,
+            size,
+            file_hash: hex_encode(whole.finalize()),
+            chunk_hashes,
+            files,
+            sender_sig: None,
+            sender_pubkey: None,
+            metadata,
+            chunk_size: chunking.fixed_size(),
+            preview: None,
+        })
+    }
+
+    /// Where the received file or tree lands inside `dir`. The filename must
+    /// be a single plain path component.
+    pub fn output_path(&self, dir: &Path) -> Result<PathBuf, OpenShareError> {
+        Ok(output_path(&self.filename, dir)?)
+    }
+
+    /// How the chunks were cut, if to a fixed size the manifest records.
+    pub fn chunking(&self) -> Option<Chunking> {
+        self.chunk_size.map(|size| Chunking::Fixed(size as usize))
+    }
+
+    /// Whether this manifest describes a directory tree.
+    pub fn is_directory(&self) -> bool {
+        !self.files.is_empty()
+    }
+
+    /// Chunk hashes belonging to one directory entry.
+    pub fn entry_chunks(&self, entry: &FileEntry) -> Result<&[String], OpenShareError> {
+        self.chunk_hashes
+            .get(entry.first_chunk..entry.first_chunk + entry.chunk_count)
+            .ok_or_else(|| anyhow::anyhow!("Chunk range out of bounds for {}", entry.path).into())
+    }
+
+    /// Reassemble the file (or directory tree) described by the manifest
+    /// from chunks in `storage` into `path`.
+    ///
+    /// Every file is written to a `.part` sibling first, checked against the
+    /// manifest's size and chunk hashes, synced, and only then renamed into
+    /// place, so a failed or corrupt transfer never leaves a partial file
+    /// under the final name. The written content is also checked against
+    /// `file_hash` when the manifest has one; for a directory that check
+    /// can only run once every file is in place. Existing files are
+    /// overwritten.
+    pub async fn assemble_to<S: Storage + ?Sized>(&self, path: &Path, storage: &S) -> Result<(), OpenShareError> {
+        Ok(self.assemble(path, storage, None, &[], None).await?)
+    }
+
+    /// Like [`assemble_to`](Self::assemble_to), but files about to be
+    /// overwritten are moved into `trash` first.
+    pub async fn assemble_to_with_trash<S: Storage + ?Sized>(&self, path: &Path, storage: &S, trash: &Trash) -> Result<(), OpenShareError> {
+        Ok(self.assemble(path, storage, Some(trash), &[], None).await?)
+    }
+
+    /// Assemble a directory over an earlier version of it at `path`: the
+    /// files in `moves` (see [`SyncRecord::plan`](crate::sync::SyncRecord::plan))
+    /// are renamed into place or left where they are instead of being
+    /// written from storage, which need not hold their chunks. A file
+    /// manifest takes no moves. Chunk ids keyed with `key` are accepted as
+    /// well as plain ones.
+    pub async fn sync_to<S: Storage + ?Sized>(
+        &self,
+        path: &Path,
+        storage: &S,
+        trash: Option<&Trash>,
+        moves: &[Move],
+        key: Option<&ChunkKey>,
+    ) -> Result<()> {
+        self.assemble(path, storage, trash, moves, key).await
+    }
+
+    async fn assemble<S: Storage + ?Sized>(
+        &self,
+        path: &Path,
+        storage: &S,
+        trash: Option<&Trash>,
+        moves: &[Move],
+        key: Option<&ChunkKey>,
+    ) -> Result<()> {
+        let expected = Some(self.file_hash.as_str()).filter(|h| !h.is_empty());
+        let mut whole = Sha256::new();
+        if !self.is_directory() {
+            return assemble_file(&self.chunk_hashes, self.size, expected, &mut whole, path, storage, trash, key).await;
+        }
+
+        let total: u64 = self.files.iter().map(|e| e.size).sum();
+        if total != self.size {
+            anyhow::bail!("Manifest entries add up to {} bytes, expected {}", total, self.size);
+        }
+        tokio::fs::create_dir_all(path).await?;
+        let mut staged = stage_moves(path, moves).await?;
+        let written = self.assemble_entries(path, storage, trash, key, &mut staged, &mut whole).await;
+        unstage_moves(path, &staged).await;
+        written?;
+        if let Some(expected) = expected {
+            let actual = hex_encode(whole.finalize());
+            if actual != expected {
+                anyhow::bail!("{} does not match the manifest file hash ({} vs {})", path.display(), actual, expected);
+            }
+        }
+        Ok(())
+    }
+
+    async fn assemble_entries<S: Storage + ?Sized>(
+        &self,
+        path: &Path,
+        storage: &S,
+        trash: Option<&Trash>,
+        key: Option<&ChunkKey>,
+        staged: &mut HashMap<String, Staged>,
+        whole: &mut Sha256,
+    ) -> Result<()> {
+        for entry in &self.files {
+            let target = entry.resolve(path)?;
+            if entry.is_dir {
+                tokio::fs::create_dir_all(&target).await?;
+                continue;
+            }
+            if let Some(parent) = target.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            match staged.remove(&entry.path) {
+                Some(source) => place_file(source, entry.size, whole, &target, trash).await?,
+                None => assemble_file(self.entry_chunks(entry)?, entry.size, None, whole, &target, storage, trash, key).await?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Hex SHA-256 of the content at `path` as laid out by this manifest
+    /// (a directory's files in `files` order), comparable to `file_hash`.
+    pub fn content_hash(&self, path: &Path) -> Result<String, OpenShareError> {
+        let mut whole = Sha256::new();
+        let paths = if self.is_directory() {
+            self.files.iter()
+                .filter(|e| !e.is_dir)
+                .map(|e| e.resolve(path))
+                .collect::<Result<Vec<_>, _>>()?
+        } else {
+            vec![path.to_path_buf()]
+        };
+        for p in paths {
+            let mut f = File::open(&p).with_context(|| format!("Failed to open {}", p.display()))?;
+            std::io::copy(&mut f, &mut whole)?;
+        }
+        Ok(hex_encode(whole.finalize()))
+    }
+
+    /// Apply `metadata` to the file or tree assembled at `path`: create the
+    /// symlinks it lists, then set modification times and permissions,
+    /// entries before the directories holding them so that creating links
+    /// does not undo a directory's time. Entries that cannot be applied are
+    /// logged and skipped; the content is in place either way.
+    pub fn apply_metadata(&self, path: &Path) {
+        let mut metadata: Vec<&FileMeta> = self.metadata.iter()
+            .filter(|m| self.is_directory() || m.path.is_empty())
+            .collect();
+        metadata.sort_by(|a, b| b.path.cmp(&a.path));
+        for meta in &metadata {
+            if let Some(target) = &meta.symlink {
+                if let Err(e) = create_link(path, meta, target) {
+                    tracing::warn!("Failed to create symlink {}: {:#}", meta.path, e);
+                }
+            }
+        }
+        for meta in metadata.iter().filter(|m| m.symlink.is_none()) {
+            if let Err(e) = meta.resolve(path).map_err(Into::into).and_then(|p| set_meta(&p, meta)) {
+                tracing::warn!("Failed to apply metadata to {}: {:#}", path.join(&meta.path).display(), e);
+            }
+        }
+    }
+
+    /// Content address of the manifest: SHA-256 over the manifest without
+    /// signer fields, so it stays the same whoever signs it. `file_hash`,
+    /// `preview`, `metadata` and `chunk_size` are left out too, as older
+    /// peers drop them.
+    pub fn id(&self) -> String {
+        let mut legacy = self.legacy();
+        legacy.sender_sig = None;
+        legacy.sender_pubkey = None;
+        let ser = bincode::serialize(&legacy).expect("manifest serializes");
+        hex_encode(Sha256::digest(&ser))
+    }
+
+    /// Encode for the wire, in the pre-`file_hash` layout if the manifest
+    /// has no file hash. The preview and metadata, if any, follow in the
+    /// current layout, and the chunk size, if recorded, after them.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, OpenShareError> {
+        if self.file_hash.is_empty() {
+            return Ok(bincode::serialize(&self.legacy()).context("Failed to serialize manifest")?);
+        }
+        let mut ser = bincode::serialize(&self.plain()).context("Failed to serialize manifest")?;
+        self.write_trailer(&mut ser).context("Failed to serialize manifest trailer")?;
+        Ok(ser)
+    }
+
+    /// Write what follows the manifest in the current layout: the preview,
+    /// as an `Option`, if anything follows the manifest, then metadata
+    /// (possibly empty) if there is metadata or a chunk size, then the
+    /// chunk size.
+    fn write_trailer(&self, mut out: impl std::io::Write) -> Result<()> {
+        if self.preview.is_some() || !self.metadata.is_empty() || self.chunk_size.is_some() {
+            bincode::serialize_into(&mut out, &self.preview)?;
+        }
+        if !self.metadata.is_empty() || self.chunk_size.is_some() {
+            bincode::serialize_into(&mut out, &self.metadata)?;
+        }
+        if let Some(size) = self.chunk_size {
+            bincode::serialize_into(&mut out, &size)?;
+        }
+        Ok(())
+    }
+
+    /// Decode a manifest from a peer; `file_hash` says whether the peer
+    /// speaks the layout with a whole-file hash. Anything after the
+    /// manifest in that layout is its preview, then its metadata, then its
+    /// chunk size.
+    pub fn from_bytes(bytes: &[u8], file_hash: bool) -> Result<Self, OpenShareError> {
+        if file_hash {
+            let mut cursor = std::io::Cursor::new(bytes);
+            let plain: PlainManifest = bincode::deserialize_from(&mut cursor)?;
+            let at_end = |cursor: &std::io::Cursor<&[u8]>| cursor.position() as usize >= bytes.len();
+            let preview: Option<Preview> = if at_end(&cursor) {
+                None
+            } else {
+                bincode::deserialize_from(&mut cursor).context("Invalid manifest preview")?
+            };
+            if let Some(preview) = &preview {
+                preview.check()?;
+            }
+            let metadata = if at_end(&cursor) {
+                Vec::new()
+            } else {
+                bincode::deserialize_from(&mut cursor).context("Invalid manifest metadata")?
+            };
+            let chunk_size = if at_end(&cursor) {
+                None
+            } else {
+                match bincode::deserialize_from(&mut cursor).context("Invalid manifest chunk size")? {
+                    0 => return Err(anyhow::anyhow!("Manifest chunk size must not be zero").into()),
+                    size => Some(size),
+                }
+            };
+            return Ok(Self {
+                filename: plain.filename,
+                size: plain.size,
+                file_hash: plain.file_hash,
+                chunk_hashes: plain.chunk_hashes,
+                files: plain.files,
+                sender_sig: plain.sender_sig,
+                sender_pubkey: plain.sender_pubkey,
+                metadata,
+                chunk_size,
+                preview,
+            });
+        }
+        let legacy: LegacyManifest = bincode::deserialize(bytes)?;
+        Ok(Self {
+            filename: legacy.filename,
+            size: legacy.size,
+            file_hash: String::new(),
+            chunk_hashes: legacy.chunk_hashes,
+            files: legacy.files,
+            sender_sig: legacy.sender_sig,
+            sender_pubkey: legacy.sender_pubkey,
+            metadata: Vec::new(),
+            chunk_size: None,
+            preview: None,
+        })
+    }
+
+    fn plain(&self) -> PlainManifest {
+        PlainManifest {
+            filename: self.filename.clone(),
+            size: self.size,
+            file_hash: self.file_hash.clone(),
+            chunk_hashes: self.chunk_hashes.clone(),
+            files: self.files.clone(),
+            sender_sig: self.sender_sig.clone(),
+            sender_pubkey: self.sender_pubkey.clone(),
+        }
+    }
+
+    fn legacy(&self) -> LegacyManifest {
+        LegacyManifest {
+            filename: self.filename.clone(),
+            size: self.size,
+            chunk_hashes: self.chunk_hashes.clone(),
+            files: self.files.clone(),
+            sender_sig: self.sender_sig.clone(),
+            sender_pubkey: self.sender_pubkey.clone(),
+        }
+    }
+
     /// Sign the manifest using identity (the signature covers the manifest with
-    /// sender_sig set to None).
-    pub fn sign(&mut self, identity: &Identity) -> Result<()> {
+    /// sender_sig set to None, in the encoding of [`to_bytes`](Self::to_bytes)).
+    pub fn sign(&mut self, identity: &Identity) -> Result<(), OpenShareError> {
         // Store the sender's public key
         self.sender_pubkey = Some(identity.public_key_bytes().to_vec());
 
-        // Create a copy without signature for signing
-        let mut copy = self.clone();
-        copy.sender_sig = None;
-
-        let ser = bincode::serialize(&copy)
+        let mut ser = Vec::new();
+        self.write_unsigned(&mut ser)
             .context("Failed to serialize manifest for signing")?;
         let sig = identity.sign(&ser);
 
@@ -74,8 +668,65 @@ impl Manifest {
         Ok(())
     }
 
+    /// Sign like [`sign`](Self::sign), but with Ed25519ph over a SHA-512
+    /// computed while encoding, in constant memory. Only peers with the
+    /// `manifest-ph` feature can verify it.
+    pub fn sign_prehashed(&mut self, identity: &Identity) -> Result<(), OpenShareError> {
+        self.sender_pubkey = Some(identity.public_key_bytes().to_vec());
+        let sig = identity.sign_prehashed(self.prehash()?, PREHASH_CONTEXT)?;
+        let mut tagged = Vec::with_capacity(65);
+        tagged.push(PREHASHED_SIG_TAG);
+        tagged.extend_from_slice(&sig.to_bytes());
+        self.sender_sig = Some(tagged);
+        Ok(())
+    }
+
+    /// Whether the signature is an Ed25519ph one.
+    pub fn is_prehashed(&self) -> bool {
+        self.sender_sig.as_ref().is_some_and(|sig| sig.len() == 65 && sig[0] == PREHASHED_SIG_TAG)
+    }
+
+    /// Encode the signed content, as [`to_bytes`](Self::to_bytes) would
+    /// without a signature, into `out`.
+    fn write_unsigned(&self, mut out: impl std::io::Write) -> Result<()> {
+        if self.file_hash.is_empty() {
+            bincode::serialize_into(out, &UnsignedLegacy {
+                filename: &self.filename,
+                size: self.size,
+                chunk_hashes: &self.chunk_hashes,
+                files: &self.files,
+                sender_sig: None,
+                sender_pubkey: &self.sender_pubkey,
+            })?;
+        } else {
+            bincode::serialize_into(&mut out, &Unsigned {
+                filename: &self.filename,
+                size: self.size,
+                file_hash: &self.file_hash,
+                chunk_hashes: &self.chunk_hashes,
+                files: &self.files,
+                sender_sig: None,
+                sender_pubkey: &self.sender_pubkey,
+            })?;
+            self.write_trailer(&mut out)?;
+        }
+        Ok(())
+    }
+
+    fn prehash(&self) -> Result<Sha512> {
+        use std::io::Write;
+
+        let mut hash = Sha512::new();
+        let mut writer = std::io::BufWriter::new(&mut hash);
+        self.write_unsigned(&mut writer)
+            .context("Failed to hash manifest for signing")?;
+        writer.flush()?;
+        drop(writer);
+        Ok(hash)
+    }
+
     /// Verify the manifest signature using the stored public key.
-    pub fn verify(&self) -> Result<()> {
+    pub fn verify(&self) -> Result<(), OpenShareError> {
         let _sig_bytes = self.sender_sig.as_ref()
             .ok_or_else(|| anyhow::anyhow!("Missing signature"))?;
 
@@ -83,7 +734,7 @@ impl Manifest {
             .ok_or_else(|| anyhow::anyhow!("Missing sender public key"))?;
 
         if pubkey_bytes.len() != 32 {
-            anyhow::bail!("Invalid public key length");
+            return Err(anyhow::anyhow!("Invalid public key length").into());
         }
 
         let mut pubkey_arr = [0u8; 32];
@@ -93,36 +744,61 @@ impl Manifest {
     }
 
     /// Verify the manifest signature using a specific public key.
-    pub fn verify_with_pubkey(&self, pubkey_bytes: &[u8; 32]) -> Result<()> {
+    pub fn verify_with_pubkey(&self, pubkey_bytes: &[u8; 32]) -> Result<(), OpenShareError> {
         let sig_bytes = self.sender_sig.as_ref()
             .ok_or_else(|| anyhow::anyhow!("Missing signature"))?;
 
-        // Convert Vec<u8> to [u8; 64] for Signature
-        if sig_bytes.len() != 64 {
-            anyhow::bail!("Invalid signature length: expected 64 bytes, got {}", sig_bytes.len());
-        }
-        let mut sig_arr = [0u8; 64];
-        sig_arr.copy_from_slice(sig_bytes);
-
+        // A prehashed signature carries a tag byte in front.
+        let prehashed = self.is_prehashed();
+        let sig_bytes = if prehashed { &sig_bytes[1..] } else { &sig_bytes[..] };
+        let sig_arr: [u8; 64] = sig_bytes.try_into().map_err(|_| {
+            anyhow::anyhow!("Invalid signature length: expected 64 bytes, got {}", sig_bytes.len())
+        })?;
         let sig = Signature::from_bytes(&sig_arr);
 
-        let mut copy = self.clone();
-        copy.sender_sig = None;
-
-        let ser = bincode::serialize(&copy)
-            .context("Failed to serialize manifest for verification")?;
-
         let pk = VerifyingKey::from_bytes(pubkey_bytes)
             .context("Invalid public key")?;
 
-        pk.verify(&ser, &sig)
-            .context("Signature verification failed")?;
+        if prehashed {
+            let prehash = self.prehash()?;
+            pk.verify_prehashed(prehash, Some(PREHASH_CONTEXT), &sig)
+                .context("Signature verification failed")?;
+        } else {
+            let mut ser = Vec::new();
+            self.write_unsigned(&mut ser)
+                .context("Failed to serialize manifest for verification")?;
+            let verified = pk.verify(&ser, &sig);
+            // Manifests from before directory transfers had no `files`.
+            if verified.is_err() && self.file_hash.is_empty() && self.files.is_empty() {
+                let flat = bincode::serialize(&UnsignedFlat {
+                    filename: &self.filename,
+                    size: self.size,
+                    chunk_hashes: &self.chunk_hashes,
+                    sender_sig: None,
+                    sender_pubkey: &self.sender_pubkey,
+                })?;
+                if pk.verify(&flat, &sig).is_ok() {
+                    return Ok(());
+                }
+            }
+            verified.context("Signature verification failed")?;
+        }
 
         Ok(())
     }
 
     /// Get a summary string for display.
     pub fn summary(&self) -> String {
+        if self.is_directory() {
+            let file_count = self.files.iter().filter(|f| !f.is_dir).count();
+            return format!(
+                "{}/ ({} files, {} bytes, {} chunks)",
+                self.filename,
+                file_count,
+                self.size,
+                self.chunk_hashes.len()
+            );
+        }
         format!(
             "{} ({} bytes, {} chunks)",
             self.filename,
@@ -130,4 +806,555 @@ impl Manifest {
             self.chunk_hashes.len()
         )
     }
-}
\ No newline at end of file
+}
+
+/// A file of an earlier version of a tree that is reused for an entry.
+enum Staged {
+    /// Already at the entry's path
+    InPlace,
+    /// Moved aside from `from` until its entry is written
+    Aside { from: String, path: PathBuf },
+}
+
+/// Move the sources of `moves` out of the way under `root`, so that files
+/// swapping places do not overwrite each other. Returns them by target.
+async fn stage_moves(root: &Path, moves: &[Move]) -> Result<HashMap<String, Staged>> {
+    let mut staged = HashMap::new();
+    for (i, m) in moves.iter().enumerate() {
+        if m.from == m.to {
+            staged.insert(m.to.clone(), Staged::InPlace);
+            continue;
+        }
+        let source = FileEntry { path: m.from.clone(), size: 0, first_chunk: 0, chunk_count: 0, is_dir: false }.resolve(root)?;
+        let aside = root.join(format!(".oshare-move-{}", i));
+        if let Err(e) = tokio::fs::rename(&source, &aside).await {
+            unstage_moves(root, &staged).await;
+            return Err(e).with_context(|| format!("Failed to move {} aside", source.display()));
+        }
+        staged.insert(m.to.clone(), Staged::Aside { from: m.from.clone(), path: aside });
+    }
+    Ok(staged)
+}
+
+/// Put files staged but never placed back where they came from; best
+/// effort, after a failed assembly.
+async fn unstage_moves(root: &Path, staged: &HashMap<String, Staged>) {
+    for source in staged.values() {
+        if let Staged::Aside { from, path } = source {
+            let _ = tokio::fs::rename(path, root.join(from)).await;
+        }
+    }
+}
+
+/// Put a reused file at `path`, checking its size and feeding it into
+/// `whole`. A different file already at `path` goes to `trash`.
+async fn place_file(source: Staged, size: u64, whole: &mut Sha256, path: &Path, trash: Option<&Trash>) -> Result<()> {
+    let current = match &source {
+        Staged::InPlace => path,
+        Staged::Aside { path: aside, .. } => aside.as_path(),
+    };
+    let mut f = tokio::fs::File::open(current).await
+        .with_context(|| format!("Failed to open {}", current.display()))?;
+    if f.metadata().await?.len() != size {
+        anyhow::bail!("{} changed size since it was written", current.display());
+    }
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = f.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        whole.update(&buf[..n]);
+    }
+    drop(f);
+
+    if let Staged::Aside { path: aside, .. } = &source {
+        if let Some(trash) = trash.filter(|_| path.is_file()) {
+            trash.move_in(path)?;
+        }
+        tokio::fs::rename(aside, path).await
+            .with_context(|| format!("Failed to move {} into place", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Write one file through a `.part` temp file and rename it into place.
+/// The written content is fed into `whole` and, with `expected`, must
+/// hash to it before the rename. A file already at `path` goes to `trash`.
+#[allow(clippy::too_many_arguments)]
+async fn assemble_file<S: Storage + ?Sized>(
+    chunk_hashes: &[String],
+    size: u64,
+    expected: Option<&str>,
+    whole: &mut Sha256,
+    path: &Path,
+    storage: &S,
+    trash: Option<&Trash>,
+    key: Option<&ChunkKey>,
+) -> Result<()> {
+    let part = part_path(path)?;
+    if let Err(e) = write_part(chunk_hashes, size, expected, whole, &part, storage, key).await {
+        let _ = tokio::fs::remove_file(&part).await;
+        return Err(e.context(format!("Failed to assemble {}", path.display())));
+    }
+    if let Some(trash) = trash.filter(|_| path.is_file()) {
+        trash.move_in(path)?;
+    }
+    tokio::fs::rename(&part, path).await
+        .with_context(|| format!("Failed to move {} into place", part.display()))?;
+
+    // Persist the rename itself; best effort, not every platform can
+    // sync a directory handle.
+    #[cfg(unix)]
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        if let Ok(dir) = File::open(parent) {
+            let _ = dir.sync_all();
+        }
+    }
+    Ok(())
+}
+
+/// Where a file for `path` is written before it is moved into place. A
+/// part file already there (one [reserved](crate::space::reserve) for it)
+/// is written over.
+pub fn part_path(path: &Path) -> Result<PathBuf, OpenShareError> {
+    let name = path.file_name()
+        .ok_or_else(|| anyhow::anyhow!("Invalid output path: {}", path.display()))?;
+    let mut part_name = name.to_os_string();
+    part_name.push(".part");
+    Ok(path.with_file_name(part_name))
+}
+
+async fn write_part<S: Storage + ?Sized>(
+    chunk_hashes: &[String],
+    size: u64,
+    expected: Option<&str>,
+    whole: &mut Sha256,
+    part: &Path,
+    storage: &S,
+    key: Option<&ChunkKey>,
+) -> Result<()> {
+    // Not truncated, so space reserved for it stays allocated.
+    let mut out = tokio::fs::OpenOptions::new().write(true).create(true).truncate(false).open(part).await
+        .with_context(|| format!("Failed to create {}", part.display()))?;
+    let mut lengths = Vec::with_capacity(chunk_hashes.len());
+    for chunk_hash in chunk_hashes {
+        let mut chunk = storage.get_chunk_stream(chunk_hash).await?
+            .ok_or_else(|| anyhow::anyhow!("Missing chunk: {}", chunk_hash))?;
+        let len = tokio::io::copy(&mut chunk, &mut out).await?;
+        lengths.push(len as usize);
+    }
+    out.flush().await?;
+    out.set_len(lengths.iter().map(|&len| len as u64).sum()).await?;
+    out.sync_all().await?;
+    drop(out);
+
+    let written = tokio::fs::metadata(part).await?.len();
+    if written != size {
+        anyhow::bail!("Size mismatch: wrote {} bytes, manifest says {}", written, size);
+    }
+
+    // Hash what actually reached the disk, chunk by chunk and as a whole.
+    let mut f = tokio::fs::File::open(part).await?;
+    let mut buf = Vec::new();
+    for (chunk_hash, len) in chunk_hashes.iter().zip(lengths) {
+        buf.resize(len, 0);
+        f.read_exact(&mut buf).await?;
+        if !keyed::matches(key, &buf, chunk_hash) {
+            anyhow::bail!("Written data does not match chunk {}", chunk_hash);
+        }
+        whole.update(&buf);
+    }
+    if let Some(expected) = expected {
+        let actual = hex_encode(whole.clone().finalize());
+        if actual != expected {
+            anyhow::bail!("File hash mismatch: wrote {}, manifest says {}", actual, expected);
+        }
+    }
+    Ok(())
+}
+
+/// Create the symlink `meta` at its path under `root`, pointing at
+/// `target`, replacing a symlink already there. The link's directory is
+/// resolved on disk, links included, to check that the target stays inside
+/// the tree.
+fn create_link(root: &Path, meta: &FileMeta, target: &str) -> Result<()> {
+    let link = resolve_path(root, &meta.path)?;
+    let parent = link.parent().context("Symlink has no directory")?;
+    let depth = std::fs::canonicalize(parent)?
+        .strip_prefix(std::fs::canonicalize(root)?)
+        .map(|rel| rel.components().count())
+        .map_err(|_| anyhow::anyhow!("{} is outside the tree", parent.display()))?;
+    if !link_stays_inside(depth, target) {
+        anyhow::bail!("Target {} leads out of the tree", target);
+    }
+    match std::fs::symlink_metadata(&link) {
+        Ok(existing) if existing.file_type().is_symlink() => std::fs::remove_file(&link)?,
+        Ok(_) => anyhow::bail!("{} already exists", link.display()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    symlink(target, &link)
+}
+
+#[cfg(unix)]
+fn symlink(target: &str, link: &Path) -> Result<()> {
+    Ok(std::os::unix::fs::symlink(target, link)?)
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &str, _link: &Path) -> Result<()> {
+    anyhow::bail!("Symlinks are not supported on this platform")
+}
+
+/// Set the modification time and permissions in `meta` on `path`. The
+/// time goes first, as the permissions may not allow opening it.
+fn set_meta(path: &Path, meta: &FileMeta) -> Result<()> {
+    if let Some(nanos) = meta.modified {
+        let time = std::time::UNIX_EPOCH + std::time::Duration::from_nanos(nanos);
+        File::open(path)?.set_modified(time)?;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let current = std::fs::metadata(path)?.permissions().mode() & 0o777;
+        let mode = match meta.mode {
+            Some(mode) => mode & 0o777,
+            // Executable wherever it is readable
+            None if meta.executable => current | (current & 0o444) >> 2,
+            None => return Ok(()),
+        };
+        if mode != current {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+    }
+    Ok(())
+}
+
+/// Where a transfer named `filename` lands inside `dir`; see
+/// [`Manifest::output_path`].
+pub(crate) fn output_path(filename: &str, dir: &Path) -> Result<PathBuf> {
+    let mut components = Path::new(filename).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) => Ok(dir.join(name)),
+        _ => anyhow::bail!("Unsafe filename in manifest: {}", filename),
+    }
+}
+
+/// Regular files anywhere below `root`, in no particular order.
+pub(crate) fn tree_files(root: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    collect_entries(root, root, &mut paths, &mut Vec::new())?;
+    Ok(paths.into_iter().map(|rel| root.join(rel)).filter(|p| p.is_file()).collect())
+}
+
+/// `rel` with `/` separators, as paths appear in manifests.
+fn slash_path(rel: &Path) -> String {
+    rel.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Bytes of content a manifest of the file or tree at `path` would list,
+/// without hashing it.
+pub fn content_size(path: &Path) -> Result<u64, OpenShareError> {
+    if !path.is_dir() {
+        return Ok(std::fs::metadata(path).with_context(|| format!("Failed to read {}", path.display()))?.len());
+    }
+    let (mut paths, mut links) = (Vec::new(), Vec::new());
+    collect_entries(path, path, &mut paths, &mut links)?;
+    let mut size = 0;
+    for rel in paths {
+        let meta = std::fs::metadata(path.join(rel))?;
+        if meta.is_file() {
+            size += meta.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Recursively collect paths (relative to `root`) of regular files and
+/// directories below `dir`, and of symlinks into `links`.
+fn collect_entries(root: &Path, dir: &Path, out: &mut Vec<PathBuf>, links: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory: {}", dir.display()))?
+    {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let path = entry.path();
+        let rel = path.strip_prefix(root)?.to_path_buf();
+
+        if file_type.is_dir() {
+            out.push(rel);
+            collect_entries(root, &path, out, links)?;
+        } else if file_type.is_file() {
+            out.push(rel);
+        } else if file_type.is_symlink() {
+            links.push(rel);
+        } else {
+            tracing::warn!("Skipping special file: {}", path.display());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_from_dir_entries() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let root = tmp.path().join("photos");
+        std::fs::create_dir_all(root.join("2024/empty"))?;
+        std::fs::write(root.join("a.txt"), vec![1u8; 10])?;
+        std::fs::write(root.join("2024/b.bin"), vec![2u8; 25])?;
+
+        let m = Manifest::from_dir(&root, 8)?;
+        let paths: Vec<_> = m.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, ["2024", "2024/b.bin", "2024/empty", "a.txt"]);
+        assert_eq!(m.filename, "photos");
+        assert_eq!(m.size, 35);
+        assert_eq!(m.chunk_hashes.len(), 4 + 2);
+        assert_eq!(m.entry_chunks(&m.files[1])?.len(), 4);
+        assert!(m.files[2].is_dir);
+
+        let evil = FileEntry { path: "../etc/passwd".into(), size: 0, first_chunk: 0, chunk_count: 0, is_dir: false };
+        assert!(evil.resolve(&root).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_assemble_to_is_atomic() -> Result<()> {
+        use storage::LocalStorage;
+
+        let tmp = TempDir::new()?;
+        let input = tmp.path().join("input.bin");
+        let data: Vec<u8> = (0..100u8).collect();
+        std::fs::write(&input, &data)?;
+
+        let storage = LocalStorage::new(tmp.path().join("store"))?;
+        for chunk in data.chunks(32) {
+            storage.put_chunk(chunk).await?;
+        }
+        let m = Manifest::from_file(input.to_str().unwrap(), 32)?;
+
+        let out = tmp.path().join("out.bin");
+        m.assemble_to(&out, &storage).await?;
+        assert_eq!(std::fs::read(&out)?, data);
+        assert!(!tmp.path().join("out.bin.part").exists());
+
+        // A manifest that disagrees with the chunks leaves nothing behind.
+        let out = tmp.path().join("bad.bin");
+        for bad in [Manifest { size: 99, ..m.clone() }, Manifest { file_hash: "00".repeat(32), ..m }] {
+            assert!(bad.assemble_to(&out, &storage).await.is_err());
+            assert!(!out.exists() && !tmp.path().join("bad.bin.part").exists());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_hash_and_legacy_encoding() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let input = tmp.path().join("input.bin");
+        std::fs::write(&input, b"hello world")?;
+        let identity = Identity::from(ed25519_dalek::SigningKey::from_bytes(&[3; 32]));
+
+        let mut m = Manifest::from_file(input.to_str().unwrap(), 4)?;
+        assert_eq!(m.file_hash, hex_encode(Sha256::digest(b"hello world")));
+        m.sign(&identity)?;
+        let decoded = Manifest::from_bytes(&m.to_bytes()?, true)?;
+        decoded.verify()?;
+
+        // The file hash is signed.
+        let tampered = Manifest { file_hash: "00".repeat(32), ..decoded.clone() };
+        assert!(tampered.verify().is_err());
+
+        // Without a file hash the manifest uses the old layout, which older
+        // peers decode and verify as-is.
+        let mut legacy = decoded.clone();
+        legacy.file_hash.clear();
+        legacy.sign(&identity)?;
+        let bytes = legacy.to_bytes()?;
+        let old: LegacyManifest = bincode::deserialize(&bytes)?;
+        assert_eq!(bincode::serialize(&old)?, bytes);
+        Manifest::from_bytes(&bytes, false)?.verify()?;
+        assert_eq!(legacy.id(), decoded.id());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_file_metadata() -> Result<()> {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+        use std::time::{Duration, UNIX_EPOCH};
+        use storage::LocalStorage;
+
+        let tmp = TempDir::new()?;
+        let root = tmp.path().join("tree");
+        std::fs::create_dir_all(root.join("bin"))?;
+        std::fs::write(root.join("bin/run.sh"), b"#!/bin/sh\n")?;
+        std::fs::set_permissions(root.join("bin/run.sh"), std::fs::Permissions::from_mode(0o750))?;
+        std::fs::write(root.join("notes.txt"), b"notes")?;
+        let when = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        File::open(root.join("notes.txt"))?.set_modified(when)?;
+        symlink("../notes.txt", root.join("bin/notes"))?;
+        symlink("../../outside", root.join("bin/escape"))?;
+
+        let mut m = Manifest::from_dir(&root, 8)?;
+        let paths: Vec<_> = m.metadata.iter().map(|meta| meta.path.as_str()).collect();
+        assert_eq!(paths, ["", "bin", "bin/notes", "bin/run.sh", "notes.txt"]);
+        assert_eq!(m.metadata[2].symlink.as_deref(), Some("../notes.txt"));
+        assert_eq!(m.metadata[3].mode, Some(0o750));
+
+        // Metadata is signed, and follows the old encoding only when there
+        // is some; the id ignores it.
+        let identity = Identity::from(ed25519_dalek::SigningKey::from_bytes(&[3; 32]));
+        m.sign(&identity)?;
+        let decoded = Manifest::from_bytes(&m.to_bytes()?, true)?;
+        decoded.verify()?;
+        assert_eq!(decoded.metadata, m.metadata);
+        let mut tampered = decoded.clone();
+        tampered.metadata[3].mode = Some(0o4777);
+        assert!(tampered.verify().is_err());
+        let mut bare = decoded.clone();
+        bare.metadata.clear();
+        bare.chunk_size = None;
+        bare.sign(&identity)?;
+        let bytes = bare.to_bytes()?;
+        assert_eq!(bincode::serialize(&bincode::deserialize::<PlainManifest>(&bytes)?)?, bytes);
+        Manifest::from_bytes(&bytes, true)?.verify()?;
+        assert_eq!(bare.id(), m.id());
+
+        let storage = LocalStorage::new(tmp.path().join("store"))?;
+        for entry in m.files.iter().filter(|e| !e.is_dir) {
+            for chunk in std::fs::read(entry.resolve(&root)?)?.chunks(8) {
+                storage.put_chunk(chunk).await?;
+            }
+        }
+        let out = tmp.path().join("out");
+        m.assemble_to(&out, &storage).await?;
+        m.apply_metadata(&out);
+        assert_eq!(std::fs::read_link(out.join("bin/notes"))?, Path::new("../notes.txt"));
+        assert_eq!(std::fs::read(out.join("bin/notes"))?, b"notes");
+        assert_eq!(std::fs::metadata(out.join("bin/run.sh"))?.permissions().mode() & 0o777, 0o750);
+        assert_eq!(std::fs::metadata(out.join("notes.txt"))?.modified()?, when);
+
+        // A link out of the tree is not created, whatever the manifest says.
+        m.metadata[2].symlink = Some("../../outside".into());
+        m.apply_metadata(&out);
+        assert_eq!(std::fs::read_link(out.join("bin/notes"))?, Path::new("../notes.txt"));
+        assert!(!link_stays_inside(1, "sub/../../x") && !link_stays_inside(0, "/etc"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_prehashed_signatures() -> Result<()> {
+        let identity = Identity::from(ed25519_dalek::SigningKey::from_bytes(&[5; 32]));
+        let mut m = Manifest {
+            filename: "big".into(),
+            size: 3 << 20,
+            file_hash: "ab".repeat(32),
+            chunk_hashes: (0..3).map(|i| format!("{:064x}", i)).collect(),
+            files: Vec::new(),
+            sender_sig: None,
+            sender_pubkey: None,
+            metadata: Vec::new(),
+            chunk_size: None,
+            preview: None,
+        };
+
+        // Plain signatures are unchanged: over the encoding without one.
+        for file_hash in ["ab".repeat(32), String::new()] {
+            m.file_hash = file_hash;
+            m.sign(&identity)?;
+            let unsigned = Manifest { sender_sig: None, ..m.clone() };
+            let expected = identity.sign(&unsigned.to_bytes()?).to_bytes().to_vec();
+            assert_eq!(m.sender_sig.as_deref(), Some(&expected[..]));
+            assert!(!m.is_prehashed());
+            Manifest::from_bytes(&m.to_bytes()?, !m.file_hash.is_empty())?.verify()?;
+        }
+
+        m.sign_prehashed(&identity)?;
+        assert!(m.is_prehashed());
+        assert_eq!(m.sender_sig.as_ref().unwrap().len(), 65);
+        Manifest::from_bytes(&m.to_bytes()?, false)?.verify()?;
+        m.file_hash = "cd".repeat(32);
+        assert!(m.verify().is_err());
+        m.sign_prehashed(&identity)?;
+        m.verify()?;
+
+        // Tampering with either the content or the tag is caught.
+        let mut tampered = m.clone();
+        tampered.chunk_hashes[1] = "ff".repeat(32);
+        assert!(tampered.verify().is_err());
+        let mut untagged = m.clone();
+        untagged.sender_sig.as_mut().unwrap().remove(0);
+        assert!(untagged.verify().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunk_size_is_signed() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let path = tmp.path().join("data.bin");
+        std::fs::write(&path, vec![7u8; 10_000])?;
+        let identity = Identity::from(ed25519_dalek::SigningKey::from_bytes(&[6; 32]));
+        let mut m = Manifest::from_file(path.to_str().unwrap(), 4096)?;
+        assert_eq!((m.chunk_size, m.chunk_hashes.len()), (Some(4096), 3));
+        let id = m.id();
+
+        // With and without metadata ahead of it
+        for metadata in [m.metadata.clone(), Vec::new()] {
+            m.metadata = metadata;
+            m.sign(&identity)?;
+            let decoded = Manifest::from_bytes(&m.to_bytes()?, true)?;
+            assert_eq!((decoded.chunk_size, decoded.metadata.len()), (Some(4096), m.metadata.len()));
+            decoded.verify()?;
+            assert_eq!(decoded.id(), id);
+        }
+
+        let mut tampered = m.clone();
+        tampered.chunk_size = Some(8192);
+        assert!(tampered.verify().is_err());
+        let mut dropped = m.clone();
+        dropped.chunk_size = None;
+        assert!(dropped.verify().is_err());
+        dropped.sign(&identity)?;
+        assert_eq!(Manifest::from_bytes(&dropped.to_bytes()?, true)?.chunk_size, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_preview_is_signed() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let path = tmp.path().join("photo.png");
+        std::fs::write(&path, vec![3u8; 5000])?;
+        let identity = Identity::from(ed25519_dalek::SigningKey::from_bytes(&[8; 32]));
+        let mut m = Manifest::from_file(path.to_str().unwrap(), 4096)?;
+        let id = m.id();
+        let preview = Preview { media_type: "image/png".into(), width: 640, height: 480, thumbnail: vec![1, 2, 3] };
+        m.preview = Some(preview.clone());
+
+        // With and without a chunk size after it
+        for chunk_size in [Some(4096), None] {
+            m.chunk_size = chunk_size;
+            m.sign(&identity)?;
+            let decoded = Manifest::from_bytes(&m.to_bytes()?, true)?;
+            assert_eq!((decoded.chunk_size, decoded.preview.as_ref()), (chunk_size, Some(&preview)));
+            decoded.verify()?;
+            assert_eq!(decoded.id(), id);
+        }
+
+        let mut tampered = m.clone();
+        tampered.preview.as_mut().unwrap().thumbnail.push(4);
+        assert!(tampered.verify().is_err());
+
+        let mut oversized = m.clone();
+        oversized.preview.as_mut().unwrap().thumbnail = vec![0; crate::preview::MAX_THUMBNAIL_BYTES + 1];
+        assert!(Manifest::from_bytes(&oversized.to_bytes()?, true).is_err());
+        Ok(())
+    }
+}
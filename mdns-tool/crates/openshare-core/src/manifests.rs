@@ -0,0 +1,156 @@
+//! Manifests kept by this device.
+//!
+//! [`ManifestStore`] holds one pretty-printed JSON file per manifest under
+//! `manifests/` in the data directory, named by [`Manifest::id`]. The id
+//! leaves out signer fields, so the same content saved again lands in the
+//! same file. Everything in the store is published: peers pull manifests
+//! by id, and garbage collection keeps their chunks.
+
+use crate::Manifest;
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+/// Directory of the store inside the data directory.
+pub const MANIFESTS_DIR: &str = "manifests";
+
+#[derive(Debug, Clone)]
+pub struct ManifestStore {
+    dir: PathBuf,
+}
+
+impl ManifestStore {
+    pub fn new(data_dir: &Path) -> Self {
+        Self { dir: data_dir.join(MANIFESTS_DIR) }
+    }
+
+    /// Save `manifest`, replacing any copy with the same id. Returns the id.
+    pub fn save(&self, manifest: &Manifest) -> Result<String> {
+        let id = manifest.id();
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create {}", self.dir.display()))?;
+        crate::state::write_atomic(&self.path(&id), serde_json::to_string_pretty(manifest)?.as_bytes())?;
+        Ok(id)
+    }
+
+    /// The manifest saved under `id`, if any.
+    pub fn load(&self, id: &str) -> Result<Option<Manifest>> {
+        if !is_id(id) {
+            return Ok(None);
+        }
+        let path = self.path(id);
+        let json = match std::fs::read_to_string(&path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+        };
+        let manifest = serde_json::from_str(&json).with_context(|| format!("Invalid manifest {}", path.display()))?;
+        Ok(Some(manifest))
+    }
+
+    /// Ids of all saved manifests, sorted.
+    pub fn ids(&self) -> Result<Vec<String>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", self.dir.display())),
+        };
+        let mut ids = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_none_or(|e| e != "json") {
+                continue;
+            }
+            if let Some(id) = path.file_stem().and_then(|s| s.to_str()).filter(|s| is_id(s)) {
+                ids.push(id.to_string());
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    /// All saved manifests with their ids, in id order.
+    pub fn list(&self) -> Result<Vec<(String, Manifest)>> {
+        let mut manifests = Vec::new();
+        for id in self.ids()? {
+            // Deleted since it was listed
+            if let Some(manifest) = self.load(&id)? {
+                manifests.push((id, manifest));
+            }
+        }
+        Ok(manifests)
+    }
+
+    /// Delete the manifest saved under `id`, returning whether there was
+    /// one. Its chunks stay until the next garbage collection.
+    pub fn delete(&self, id: &str) -> Result<bool> {
+        if !is_id(id) {
+            return Ok(false);
+        }
+        match std::fs::remove_file(self.path(id)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e).with_context(|| format!("Failed to delete manifest {}", id)),
+        }
+    }
+
+    /// The one saved id starting with `prefix`, so ids can be shortened
+    /// like commit hashes.
+    pub fn resolve(&self, prefix: &str) -> Result<String> {
+        let prefix = prefix.to_ascii_lowercase();
+        let matching: Vec<String> = self.ids()?.into_iter().filter(|id| id.starts_with(&prefix)).collect();
+        match &matching[..] {
+            [id] => Ok(id.clone()),
+            [] => anyhow::bail!("No manifest {}", prefix),
+            _ => anyhow::bail!("{} matches {} manifests; give more of the id", prefix, matching.len()),
+        }
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+}
+
+/// Whether `id` looks like a manifest id. Ids become file names, so
+/// anything but a lowercase SHA-256 hex digest is unknown.
+fn is_id(id: &str) -> bool {
+    id.len() == 64 && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_manifest_store() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let file = tmp.path().join("a.txt");
+        std::fs::write(&file, b"hello world")?;
+        let store = ManifestStore::new(tmp.path());
+        assert!(store.list()?.is_empty());
+
+        let manifest = Manifest::from_file(file.to_str().unwrap(), 4)?;
+        let id = store.save(&manifest)?;
+        assert_eq!(id, manifest.id());
+        // Saving it again signed keeps one copy.
+        let mut signed = manifest.clone();
+        signed.sign(&crate::Identity::from(ed25519_dalek::SigningKey::from_bytes(&[3; 32])))?;
+        assert_eq!(store.save(&signed)?, id);
+        assert_eq!(store.ids()?, vec![id.clone()]);
+        assert_eq!(store.load(&id)?.unwrap().chunk_hashes, manifest.chunk_hashes);
+        assert_eq!(store.resolve(&id[..6])?, id);
+        assert!(store.resolve("").is_ok());
+
+        std::fs::write(&file, b"something else")?;
+        let other = store.save(&Manifest::from_file(file.to_str().unwrap(), 4)?)?;
+        assert!(store.resolve("").is_err());
+        assert_eq!(store.list()?.len(), 2);
+
+        assert!(store.load("../a")?.is_none());
+        assert!(store.delete(&id)?);
+        assert!(!store.delete(&id)?);
+        assert!(store.load(&id)?.is_none());
+        assert_eq!(store.ids()?, [other]);
+        Ok(())
+    }
+}
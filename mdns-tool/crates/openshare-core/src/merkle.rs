@@ -0,0 +1,270 @@
+//! Merkle trees over chunk hashes.
+//!
+//! A manifest lists every chunk hash, which for a large file runs to
+//! megabytes. Its chunk hashes are also the leaves of a Merkle tree, and a
+//! [`ManifestRoot`] carries just the root of that tree and the manifest's
+//! totals, signed by the peer serving it. A device holding only the root
+//! can still verify any single chunk: the peer sends the chunk's hash
+//! along with the sibling hashes from its leaf up to the root.
+//!
+//! The manifest itself is unchanged, so the leaves stay available to
+//! anything that needs the whole listing; the root is derived from them
+//! with [`chunk_root`].
+
+use crate::manifest::Manifest;
+use crate::Identity;
+use anyhow::{Context, Result};
+use ed25519_dalek::Signature;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Domain separator for manifest root signatures.
+const ROOT_CONTEXT: &[u8] = b"openshare manifest root v1";
+
+/// Prefixes of leaf and inner node hashes, so neither passes for the other.
+pub(crate) const LEAF: u8 = 0;
+const NODE: u8 = 1;
+
+pub(crate) fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    Sha256::new().chain_update([NODE]).chain_update(left).chain_update(right).finalize().into()
+}
+
+/// The root reached from leaf `index` of `width` through `proof`, or
+/// `None` if the proof has the wrong length. A node without a sibling
+/// moves up a level unchanged.
+pub(crate) fn root_from_proof(mut hash: [u8; 32], mut index: usize, mut width: usize, proof: &[[u8; 32]]) -> Option<[u8; 32]> {
+    let mut proof = proof.iter();
+    while width > 1 {
+        if index % 2 == 1 {
+            hash = node_hash(proof.next()?, &hash);
+        } else if index + 1 < width {
+            hash = node_hash(&hash, proof.next()?);
+        }
+        index /= 2;
+        width = width.div_ceil(2);
+    }
+    proof.next().is_none().then_some(hash)
+}
+
+/// Leaf hash of chunk `index` with id `hash`.
+pub fn chunk_leaf(index: u32, hash: &str) -> [u8; 32] {
+    Sha256::new().chain_update([LEAF]).chain_update(index.to_be_bytes()).chain_update(hash.as_bytes()).finalize().into()
+}
+
+/// All levels of a Merkle tree, leaves first.
+#[derive(Debug, Clone)]
+pub struct Tree {
+    levels: Vec<Vec<[u8; 32]>>,
+}
+
+impl Tree {
+    pub fn new(leaves: Vec<[u8; 32]>) -> Self {
+        let mut levels = vec![leaves];
+        while let Some(level) = levels.last().filter(|level| level.len() > 1) {
+            let next: Vec<[u8; 32]> = level.chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => node_hash(left, right),
+                    [single] => *single,
+                    _ => unreachable!("chunks of two"),
+                })
+                .collect();
+            levels.push(next);
+        }
+        Self { levels }
+    }
+
+    /// Tree over `chunk_hashes`, as listed in a manifest.
+    pub fn over_chunks(chunk_hashes: &[String]) -> Self {
+        Self::new(chunk_hashes.iter().enumerate().map(|(i, hash)| chunk_leaf(i as u32, hash)).collect())
+    }
+
+    /// The root; all zeros for a tree without leaves.
+    pub fn root(&self) -> [u8; 32] {
+        self.levels.last().and_then(|level| level.first()).copied().unwrap_or([0; 32])
+    }
+
+    /// Sibling hashes from leaf `index` up to the root.
+    pub fn proof(&self, index: usize) -> Vec<[u8; 32]> {
+        let mut proof = Vec::new();
+        let mut i = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            if let Some(sibling) = level.get(i ^ 1) {
+                proof.push(*sibling);
+            }
+            i /= 2;
+        }
+        proof
+    }
+}
+
+/// Merkle root over the chunk hashes of `manifest`.
+pub fn chunk_root(manifest: &Manifest) -> [u8; 32] {
+    Tree::over_chunks(&manifest.chunk_hashes).root()
+}
+
+/// A manifest without its listing: totals and the Merkle root over its
+/// chunk hashes, signed by the peer that serves it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ManifestRoot {
+    /// `Manifest::id` of the manifest described
+    pub manifest_id: String,
+    pub filename: String,
+    pub size: u64,
+    pub file_hash: String,
+    pub chunk_count: u64,
+    pub file_count: u64,
+    /// Merkle root over the chunk hashes
+    pub root: [u8; 32],
+    pub sender_pubkey: Vec<u8>,
+    /// Ed25519 signature over the fields above
+    pub signature: Vec<u8>,
+}
+
+impl ManifestRoot {
+    /// Describe `manifest`, signed by `identity`.
+    pub fn new(manifest: &Manifest, identity: &Identity) -> Result<Self> {
+        let mut root = Self {
+            manifest_id: manifest.id(),
+            filename: manifest.filename.clone(),
+            size: manifest.size,
+            file_hash: manifest.file_hash.clone(),
+            chunk_count: manifest.chunk_hashes.len() as u64,
+            file_count: manifest.files.len() as u64,
+            root: chunk_root(manifest),
+            sender_pubkey: identity.public_key_bytes().to_vec(),
+            signature: Vec::new(),
+        };
+        root.signature = identity.sign(&root.signed_bytes()?).to_bytes().to_vec();
+        Ok(root)
+    }
+
+    fn signed_bytes(&self) -> Result<Vec<u8>> {
+        let fields = bincode::serialize(&(
+            &self.manifest_id,
+            &self.filename,
+            self.size,
+            &self.file_hash,
+            self.chunk_count,
+            self.file_count,
+            self.root,
+            &self.sender_pubkey,
+        ))?;
+        Ok([ROOT_CONTEXT, &fields].concat())
+    }
+
+    pub fn verify(&self, public_key: &[u8; 32]) -> Result<()> {
+        if self.sender_pubkey != public_key {
+            anyhow::bail!("Manifest root is not from the authenticated peer");
+        }
+        let sig: [u8; 64] = self.signature.as_slice().try_into()
+            .map_err(|_| anyhow::anyhow!("Invalid manifest root signature length"))?;
+        Identity::verify_with_pubkey(public_key, &self.signed_bytes()?, &Signature::from_bytes(&sig))
+            .context("Manifest root signature invalid")
+    }
+
+    /// Check that chunk `index` has id `hash`, given the sibling hashes
+    /// from its leaf up to the root.
+    pub fn check_chunk(&self, index: u32, hash: &str, proof: &[[u8; 32]]) -> Result<()> {
+        if u64::from(index) >= self.chunk_count {
+            anyhow::bail!("Chunk {} is beyond the {} chunks of {}", index, self.chunk_count, self.filename);
+        }
+        let width = usize::try_from(self.chunk_count).context("Chunk count too large")?;
+        if root_from_proof(chunk_leaf(index, hash), index as usize, width, proof) != Some(self.root) {
+            anyhow::bail!("Chunk {} of {} does not match the signed root", index, self.filename);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::fixture::client;
+    use crate::sim::{pair, LinkConfig};
+    use ed25519_dalek::SigningKey;
+    use std::sync::atomic::Ordering;
+    use tempfile::TempDir;
+
+    fn manifest(chunks: usize) -> Manifest {
+        Manifest {
+            filename: "big.bin".into(),
+            size: chunks as u64 * 1000,
+            file_hash: hex::encode([9u8; 32]),
+            chunk_hashes: (0..chunks).map(|i| hex::encode(Sha256::digest(i.to_be_bytes()))).collect(),
+            files: Vec::new(),
+            sender_sig: None,
+            sender_pubkey: None,
+            metadata: Vec::new(),
+            chunk_size: None,
+            preview: None,
+        }
+    }
+
+    #[test]
+    fn test_chunk_proofs() {
+        let identity = Identity::from(SigningKey::from_bytes(&[4; 32]));
+        let public_key = identity.public_key_bytes();
+        for chunks in [1, 2, 5, 8, 13] {
+            let manifest = manifest(chunks);
+            let root = ManifestRoot::new(&manifest, &identity).unwrap();
+            root.verify(&public_key).unwrap();
+            let tree = Tree::over_chunks(&manifest.chunk_hashes);
+            for (i, hash) in manifest.chunk_hashes.iter().enumerate() {
+                root.check_chunk(i as u32, hash, &tree.proof(i)).unwrap();
+            }
+
+            // A hash at another position, or beyond the end, does not verify.
+            let last = chunks - 1;
+            if chunks > 1 {
+                assert!(root.check_chunk(0, &manifest.chunk_hashes[last], &tree.proof(0)).is_err());
+            }
+            assert!(root.check_chunk(chunks as u32, &manifest.chunk_hashes[last], &tree.proof(last)).is_err());
+        }
+
+        let mut root = ManifestRoot::new(&manifest(3), &identity).unwrap();
+        root.chunk_count += 1;
+        assert!(root.verify(&public_key).is_err());
+        assert!(ManifestRoot::new(&manifest(3), &identity).unwrap().verify(&[5; 32]).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_proof_pull_leaves_out_listing() {
+        use crate::handshake::{introduced_in, FEATURE_CHUNK_PROOFS};
+
+        let (src, dst) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let input = src.path().join("input.bin");
+        let payload: Vec<u8> = (0..2_000_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect();
+        std::fs::write(&input, &payload).unwrap();
+
+        let mut server = client(&src, 1);
+        server.cfg.chunk_size = 1024;
+        let mut fetcher = client(&dst, 2);
+        let manifest = server.import_file(&input).await.unwrap();
+        server.publish(&manifest).unwrap();
+        assert!(manifest.chunk_hashes.len() > 1900);
+
+        // Chunks of a manifest the fetcher holds come with proofs against
+        // its root, and without the listing; an older peer sends the
+        // listing along.
+        let mut served_bytes = Vec::new();
+        for version in [None, introduced_in(FEATURE_CHUNK_PROOFS).map(|v| v - 1)] {
+            if let Some(version) = version {
+                fetcher.env = fetcher.env.clone().with_protocol_version(version);
+            }
+            let indices = vec![7, 1500];
+            let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+            let stats = b.stats.clone();
+            let (fetched, served) = tokio::join!(fetcher.request_unquarantined(a, &manifest, indices.clone()), server.accept(b));
+            assert_eq!(fetched.unwrap().id(), manifest.id());
+            served.unwrap();
+            assert_eq!(fetcher.missing_chunks(&manifest).await.unwrap().len(), manifest.chunk_hashes.len() - 2);
+            for index in indices {
+                let hash = &manifest.chunk_hashes[index as usize];
+                storage::Storage::delete_chunk(&*fetcher.storage, hash).await.unwrap();
+            }
+            served_bytes.push(stats.bytes.load(Ordering::Relaxed));
+        }
+        assert!(served_bytes[0] < 20_000, "{:?}", served_bytes);
+        assert!(served_bytes[1] > 100_000, "{:?}", served_bytes);
+    }
+}
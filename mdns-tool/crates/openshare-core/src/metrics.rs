@@ -0,0 +1,255 @@
+//! Periodic metrics snapshots and the trends they show.
+//!
+//! A running daemon totals the transfers it sees on its notification hub
+//! and, every `metrics_snapshot_secs`, appends the totals for that period
+//! as one JSON line to `metrics.jsonl` under the data directory. Unlike
+//! the history, snapshots are kept even with `record_history` off, and
+//! hold nothing but counts per peer. `openshare stats --since` reads them
+//! back as [`Trends`]: bytes per day, failure rates and speed per peer, so
+//! a link that is getting worse shows without a metrics server.
+
+use crate::history::{Direction, TransferRecord};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// File name of the snapshot log inside the data directory.
+pub const METRICS_FILE: &str = "metrics.jsonl";
+
+const DAY_SECS: u64 = 86400;
+
+/// Transfers with one peer over a snapshot period.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerMetrics {
+    /// Completed transfers; bytes and time only count these
+    pub transfers: u64,
+    pub failed: u64,
+    pub bytes: u64,
+    pub duration_ms: u64,
+}
+
+/// Totals of the transfers that finished between `since` and `taken_at`
+/// (Unix seconds), by peer device ID.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetricsSnapshot {
+    pub since: u64,
+    pub taken_at: u64,
+    pub peers: BTreeMap<String, PeerMetrics>,
+}
+
+impl MetricsSnapshot {
+    pub fn new(since: u64) -> Self {
+        Self { since, ..Default::default() }
+    }
+
+    /// Count a finished transfer. Announcements moved nothing and are
+    /// left out.
+    pub fn add(&mut self, record: &TransferRecord) {
+        if record.direction == Direction::Announced {
+            return;
+        }
+        let peer = self.peers.entry(record.peer.clone()).or_default();
+        if record.succeeded() {
+            peer.transfers += 1;
+            peer.bytes += record.size;
+            peer.duration_ms += record.duration_ms;
+        } else {
+            peer.failed += 1;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MetricsLog {
+    path: PathBuf,
+}
+
+impl MetricsLog {
+    pub fn new(data_dir: &Path) -> Self {
+        Self { path: data_dir.join(METRICS_FILE) }
+    }
+
+    pub fn append(&self, snapshot: &MetricsSnapshot) -> Result<()> {
+        let mut line = serde_json::to_vec(snapshot)?;
+        line.push(b'\n');
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut f| f.write_all(&line))
+            .with_context(|| format!("writing {}", self.path.display()))
+    }
+
+    /// Snapshots taken at or after `since`, oldest first. Unreadable
+    /// lines are skipped, as in the history.
+    pub fn load_since(&self, since: u64) -> Result<Vec<MetricsSnapshot>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let text = std::fs::read_to_string(&self.path)
+            .with_context(|| format!("reading {}", self.path.display()))?;
+        Ok(text.lines()
+            .filter(|l| !l.trim().is_empty())
+            .filter_map(|l| match serde_json::from_str::<MetricsSnapshot>(l) {
+                Ok(snapshot) => Some(snapshot),
+                Err(e) => {
+                    tracing::warn!("Skipping bad metrics line: {}", e);
+                    None
+                }
+            })
+            .filter(|s| s.taken_at >= since)
+            .collect())
+    }
+}
+
+/// One UTC day of [`Trends`].
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DayTrend {
+    /// Unix timestamp of the day's midnight, UTC
+    pub day: u64,
+    pub transfers: u64,
+    pub failed: u64,
+    pub bytes: u64,
+}
+
+/// One peer of [`Trends`], over the whole range.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct PeerTrend {
+    pub peer: String,
+    pub transfers: u64,
+    pub failed: u64,
+    pub bytes: u64,
+    /// Share of this peer's transfers that failed, from 0 to 1
+    pub failure_rate: f64,
+    /// In bytes per second; `None` if no time was spent
+    pub average_speed: Option<f64>,
+    /// Average speed on the first and the last day with completed
+    /// transfers, if those differ; a falling speed is a degrading link
+    pub speed_change: Option<(f64, f64)>,
+}
+
+/// What snapshots show over a range of days.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Trends {
+    /// Every day of the range, oldest first, including quiet ones
+    pub days: Vec<DayTrend>,
+    /// By bytes exchanged, largest first
+    pub peers: Vec<PeerTrend>,
+    /// Share of all transfers that failed, if there were any
+    pub failure_rate: Option<f64>,
+}
+
+impl Trends {
+    /// Trends of `snapshots` from `since` to `now`, counting each snapshot
+    /// on the day it was taken.
+    pub fn from_snapshots(snapshots: &[MetricsSnapshot], since: u64, now: u64) -> Self {
+        let first_day = since / DAY_SECS * DAY_SECS;
+        let mut days: Vec<DayTrend> = (first_day..=now.max(since))
+            .step_by(DAY_SECS as usize)
+            .map(|day| DayTrend { day, ..Default::default() })
+            .collect();
+        // Per peer, the totals over the range and by day
+        let mut peers: BTreeMap<&str, (PeerMetrics, BTreeMap<u64, PeerMetrics>)> = BTreeMap::new();
+        for snapshot in snapshots.iter().filter(|s| (since..=now).contains(&s.taken_at)) {
+            let day = snapshot.taken_at / DAY_SECS * DAY_SECS;
+            let trend = &mut days[((day - first_day) / DAY_SECS) as usize];
+            for (peer, m) in &snapshot.peers {
+                trend.transfers += m.transfers;
+                trend.failed += m.failed;
+                trend.bytes += m.bytes;
+                let (total, by_day) = peers.entry(peer).or_default();
+                for sum in [total, by_day.entry(day).or_default()] {
+                    sum.transfers += m.transfers;
+                    sum.failed += m.failed;
+                    sum.bytes += m.bytes;
+                    sum.duration_ms += m.duration_ms;
+                }
+            }
+        }
+
+        let mut peers: Vec<PeerTrend> = peers.into_iter()
+            .map(|(peer, (total, by_day))| {
+                let speeds: Vec<f64> = by_day.values().filter_map(speed).collect();
+                PeerTrend {
+                    peer: peer.to_string(),
+                    transfers: total.transfers,
+                    failed: total.failed,
+                    bytes: total.bytes,
+                    failure_rate: total.failed as f64 / (total.transfers + total.failed).max(1) as f64,
+                    average_speed: speed(&total),
+                    speed_change: match speeds[..] {
+                        [first, .., last] => Some((first, last)),
+                        _ => None,
+                    },
+                }
+            })
+            .collect();
+        peers.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.peer.cmp(&b.peer)));
+
+        let (transfers, failed) = days.iter().fold((0, 0), |(t, f), d| (t + d.transfers, f + d.failed));
+        let failure_rate = (transfers + failed > 0).then(|| failed as f64 / (transfers + failed) as f64);
+        Self { days, peers, failure_rate }
+    }
+}
+
+fn speed(m: &PeerMetrics) -> Option<f64> {
+    (m.duration_ms > 0).then(|| m.bytes as f64 * 1000.0 / m.duration_ms as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_metrics_trends() -> Result<()> {
+        let record = |peer: &str, size, duration_ms, error: Option<&str>| TransferRecord {
+            transfer_id: None,
+            direction: Direction::Sent,
+            peer: peer.into(),
+            peer_fingerprint: None,
+            filename: "f".into(),
+            size,
+            reused_bytes: 0,
+            compression_saved_bytes: 0,
+            finished_at: 0,
+            duration_ms,
+            error: error.map(Into::into),
+        };
+        let start = 10 * DAY_SECS;
+        let mut monday = MetricsSnapshot::new(start);
+        monday.add(&record("nas", 4000, 1000, None));
+        monday.add(&record("phone", 100, 100, None));
+        monday.add(&TransferRecord { direction: Direction::Announced, ..record("phone", 9, 0, None) });
+        monday.taken_at = start + 3600;
+        let mut tuesday = MetricsSnapshot::new(monday.taken_at);
+        tuesday.add(&record("nas", 1000, 1000, None));
+        tuesday.add(&record("nas", 1000, 0, Some("connection reset")));
+        tuesday.taken_at = start + DAY_SECS + 60;
+
+        let tmp = TempDir::new()?;
+        let log = MetricsLog::new(tmp.path());
+        assert!(log.load_since(0)?.is_empty());
+        log.append(&monday)?;
+        log.append(&tuesday)?;
+        assert_eq!(log.load_since(start + DAY_SECS)?, [tuesday.clone()]);
+
+        let trends = Trends::from_snapshots(&log.load_since(start)?, start, start + 2 * DAY_SECS);
+        let bytes: Vec<u64> = trends.days.iter().map(|d| d.bytes).collect();
+        assert_eq!(bytes, [4100, 1000, 0]);
+        assert_eq!(trends.failure_rate, Some(0.25));
+        let nas = &trends.peers[0];
+        assert_eq!((nas.peer.as_str(), nas.transfers, nas.failed, nas.bytes), ("nas", 2, 1, 5000));
+        assert_eq!(nas.average_speed, Some(2500.0));
+        // Slower on the second day
+        assert_eq!(nas.speed_change, Some((4000.0, 1000.0)));
+        assert_eq!(trends.peers[1].speed_change, None);
+        Ok(())
+    }
+}
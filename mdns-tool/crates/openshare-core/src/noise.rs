@@ -0,0 +1,346 @@
+//! Noise XX handshake.
+//!
+//! Peers that both offer `noise-xx` can run the full handshake as
+//! `Noise_XX_25519_ChaChaPoly_SHA256` (via `snow`) instead of the signed
+//! ephemeral exchange in `handshake`:
+//!
+//! ```text
+//! -> MAGIC || e
+//! <- e, ee, s, es, {hello}
+//! -> s, se, {hello}
+//! ```
+//!
+//! Each side's static key is the X25519 form of its Ed25519 identity key,
+//! so the DHs themselves authenticate both peers; the encrypted hello (the
+//! same parts the classic handshake appends) must name the identity key
+//! the static came from. The initiator only reveals who it is once it has
+//! authenticated the responder.
+//!
+//! The `Session` frames as with `frame-counters`: each direction's key is
+//! its half of the Noise split, replaced by Noise REKEY every so many
+//! frames. The exporter secret, and so the SAS and transfer ID, are keyed
+//! from both halves salted with the handshake hash.
+//!
+//! A responder tells the handshakes apart by [`NOISE_MAGIC`], as it does
+//! for resumption. One speaking an older protocol (e.g. under `compat`)
+//! answers with an empty frame and both sides fall back to the classic
+//! handshake on the same transport. Builds that predate `noise-xx` cannot
+//! answer at all, so initiators only try it with peers seen offering it.
+//! Since anyone on the path can forge that empty frame, the initiator
+//! aborts if the classic handshake then shows the peer does offer
+//! `noise-xx` (its signed hello says so).
+
+use crate::env::Env;
+use crate::handshake::{
+    self, append_hello, parse_hello, read_lp, write_lp, HandshakeError, PeerInfo, Session, FEATURE_NOISE,
+};
+use crate::keys::Identity;
+use ed25519_dalek::VerifyingKey;
+use rand_chacha::ChaCha20Rng;
+use rand_core::{CryptoRng, RngCore, SeedableRng};
+use snow::params::{CipherChoice, DHChoice, HashChoice};
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+use snow::types::{Cipher, Dh, Hash, Random};
+use snow::{Builder, HandshakeState};
+use tokio::io::{AsyncRead, AsyncWrite};
+use zeroize::Zeroizing;
+
+/// Prefix distinguishing a Noise first flight from a classic one (which
+/// starts with a random X25519 key) and a resumption attempt.
+pub const NOISE_MAGIC: &[u8; 8] = b"OSNOISE1";
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_SHA256";
+
+/// Largest Noise handshake message.
+const MAX_MESSAGE_LEN: usize = 65535;
+
+/// Whether `env`'s protocol version runs Noise.
+pub fn enabled(env: &Env) -> bool {
+    handshake::introduced_in(FEATURE_NOISE).is_some_and(|v| v <= env.protocol_version())
+}
+
+/// Initiator side. Falls back to the classic handshake if `env` speaks a
+/// protocol without Noise or the responder declines.
+pub async fn initiator_handshake<T>(
+    identity: &Identity,
+    device_id: &str,
+    transport: &mut T,
+    env: &Env,
+) -> Result<Session, HandshakeError>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    if !enabled(env) {
+        return handshake::initiator_handshake_with(identity, device_id, transport, env).await;
+    }
+    let mut noise = build(identity, env, true)?;
+    let mut buf = vec![0u8; MAX_MESSAGE_LEN];
+    let len = noise.write_message(&[], &mut buf).map_err(crypto)?;
+    write_lp(transport, &[&NOISE_MAGIC[..], &buf[..len]].concat()).await.map_err(HandshakeError::Io)?;
+
+    let message_b = read_lp(transport).await.map_err(HandshakeError::Io)?;
+    if message_b.is_empty() {
+        tracing::debug!("Peer declined the Noise handshake");
+        let session = handshake::initiator_handshake_with(identity, device_id, transport, env).await?;
+        if session.peer.as_ref().is_some_and(|p| p.supports(FEATURE_NOISE)) {
+            return Err(HandshakeError::Crypto("Noise handshake declined by a peer that offers it (downgrade)".into()));
+        }
+        return Ok(session);
+    }
+    let mut payload = vec![0u8; MAX_MESSAGE_LEN];
+    let len = noise.read_message(&message_b, &mut payload).map_err(crypto)?;
+    let peer = authenticate(&noise, &payload[..len], env)?;
+
+    let mut hello = Vec::new();
+    append_hello(&mut hello, identity, device_id, env)?;
+    let len = noise.write_message(&hello, &mut buf).map_err(crypto)?;
+    write_lp(transport, &buf[..len]).await.map_err(HandshakeError::Io)?;
+    finish(noise, peer, env)
+}
+
+/// Responder side, given the initiator's first flight (which starts with
+/// [`NOISE_MAGIC`]).
+pub(crate) async fn respond<T>(
+    identity: &Identity,
+    device_id: &str,
+    transport: &mut T,
+    env: &Env,
+    message_a: Vec<u8>,
+) -> Result<Session, HandshakeError>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    if !enabled(env) {
+        tracing::debug!("Declining Noise handshake at protocol {}", env.protocol_version());
+        write_lp(transport, &[]).await.map_err(HandshakeError::Io)?;
+        let buf = read_lp(transport).await.map_err(HandshakeError::Io)?;
+        return handshake::respond_full(identity, device_id, transport, env, buf).await;
+    }
+    let mut noise = build(identity, env, false)?;
+    let mut payload = vec![0u8; MAX_MESSAGE_LEN];
+    noise.read_message(&message_a[NOISE_MAGIC.len()..], &mut payload).map_err(crypto)?;
+
+    let mut hello = Vec::new();
+    append_hello(&mut hello, identity, device_id, env)?;
+    let mut buf = vec![0u8; MAX_MESSAGE_LEN];
+    let len = noise.write_message(&hello, &mut buf).map_err(crypto)?;
+    write_lp(transport, &buf[..len]).await.map_err(HandshakeError::Io)?;
+
+    let message_c = read_lp(transport).await.map_err(HandshakeError::Io)?;
+    let len = noise.read_message(&message_c, &mut payload).map_err(crypto)?;
+    let peer = authenticate(&noise, &payload[..len], env)?;
+    finish(noise, peer, env)
+}
+
+fn build(identity: &Identity, env: &Env, initiator: bool) -> Result<HandshakeState, HandshakeError> {
+    let params = NOISE_PARAMS.parse().expect("valid Noise parameters");
+    let static_key = identity.x25519_secret_bytes();
+    let builder = Builder::with_resolver(params, Box::new(EnvResolver(env.clone())))
+        .prologue(NOISE_MAGIC)
+        .local_private_key(&static_key[..]);
+    let state = if initiator { builder.build_initiator() } else { builder.build_responder() };
+    state.map_err(crypto)
+}
+
+/// Parse the peer's hello and check that its static key is the X25519
+/// form of the identity key the hello names.
+fn authenticate(noise: &HandshakeState, payload: &[u8], env: &Env) -> Result<PeerInfo, HandshakeError> {
+    let peer = parse_hello(payload, env)?;
+    let expected = VerifyingKey::from_bytes(&peer.public_key)
+        .map_err(|_| HandshakeError::Crypto("malformed peer identity key".into()))?
+        .to_montgomery();
+    if noise.get_remote_static() != Some(&expected.as_bytes()[..]) {
+        return Err(HandshakeError::Crypto("peer static key does not match its identity".into()));
+    }
+    peer.check_compatible()?;
+    Ok(peer)
+}
+
+fn finish(mut noise: HandshakeState, peer: PeerInfo, env: &Env) -> Result<Session, HandshakeError> {
+    let hash: [u8; 32] = noise.get_handshake_hash().try_into().expect("SHA-256 handshake hash");
+    let (i, r) = noise.dangerously_get_raw_split();
+    handshake::noise_session((Zeroizing::new(i), Zeroizing::new(r)), hash, peer, noise.is_initiator(), env)
+}
+
+fn crypto(e: snow::Error) -> HandshakeError {
+    HandshakeError::Crypto(format!("noise: {}", e))
+}
+
+/// `snow`'s default primitives with randomness from the `Env`, so a
+/// seeded `Env` gives reproducible handshakes.
+struct EnvResolver(Env);
+
+impl CryptoResolver for EnvResolver {
+    fn resolve_rng(&self) -> Option<Box<dyn Random>> {
+        let mut seed = Zeroizing::new([0u8; 32]);
+        self.0.fill_bytes(&mut *seed);
+        Some(Box::new(EnvRng(ChaCha20Rng::from_seed(*seed))))
+    }
+
+    fn resolve_dh(&self, choice: &DHChoice) -> Option<Box<dyn Dh>> {
+        DefaultResolver.resolve_dh(choice)
+    }
+
+    fn resolve_hash(&self, choice: &HashChoice) -> Option<Box<dyn Hash>> {
+        DefaultResolver.resolve_hash(choice)
+    }
+
+    fn resolve_cipher(&self, choice: &CipherChoice) -> Option<Box<dyn Cipher>> {
+        DefaultResolver.resolve_cipher(choice)
+    }
+}
+
+struct EnvRng(ChaCha20Rng);
+
+impl RngCore for EnvRng {
+    fn next_u32(&mut self) -> u32 {
+        self.0.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.0.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.0.try_fill_bytes(dest)
+    }
+}
+
+impl CryptoRng for EnvRng {}
+
+impl Random for EnvRng {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::ManualClock;
+    use crate::handshake::{responder_handshake_with, PROTOCOL_VERSION};
+    use crate::sim::fixture::client;
+    use crate::sim::{pair, LinkConfig};
+    use ed25519_dalek::SigningKey;
+    use std::time::SystemTime;
+    use tempfile::TempDir;
+
+    fn identity(byte: u8) -> Identity {
+        Identity::from(SigningKey::from_bytes(&[byte; 32]))
+    }
+
+    async fn sessions(env_a: Env, env_b: Env) -> (Session, Session) {
+        let (id_a, id_b) = (identity(1), identity(2));
+        let (mut a, mut b) = tokio::io::duplex(4096);
+        let (sa, sb) = tokio::join!(
+            initiator_handshake(&id_a, "a", &mut a, &env_a),
+            responder_handshake_with(&id_b, "b", &mut b, &env_b),
+        );
+        (sa.unwrap(), sb.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_noise_handshake() {
+        let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+        let (sa, sb) = sessions(Env::seeded([1; 32], clock.clone()), Env::seeded([2; 32], clock.clone())).await;
+        assert_eq!(sa.transcript_hash, sb.transcript_hash);
+        assert_eq!(sa.sas_code().unwrap(), sb.sas_code().unwrap());
+        assert_eq!(sa.transfer_id(), sb.transfer_id());
+        let (pa, pb) = (sa.peer.as_ref().unwrap(), sb.peer.as_ref().unwrap());
+        assert_eq!((pa.device_id.as_str(), pa.public_key), ("b", identity(2).public_key_bytes()));
+        assert_eq!((pb.device_id.as_str(), pb.public_key), ("a", identity(1).public_key_bytes()));
+        assert!(pa.supports(FEATURE_NOISE) && sa.uses_frame_counters());
+
+        let mut wire = Vec::new();
+        sa.send_encrypted_frame(&mut wire, b"to b").await.unwrap();
+        assert_eq!(sb.read_encrypted_frame(&mut &wire[..]).await.unwrap(), b"to b");
+        let mut wire = Vec::new();
+        sb.send_encrypted_frame(&mut wire, b"to a").await.unwrap();
+        assert_eq!(sa.read_encrypted_frame(&mut &wire[..]).await.unwrap(), b"to a");
+
+        // Seeded ends reproduce the handshake
+        let (again, _) = sessions(Env::seeded([1; 32], clock.clone()), Env::seeded([2; 32], clock)).await;
+        assert_eq!(again.transcript_hash, sa.transcript_hash);
+    }
+
+    #[tokio::test]
+    async fn test_older_responder_declines() {
+        let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+        let old = handshake::introduced_in(FEATURE_NOISE).unwrap() - 1;
+        let env_b = Env::seeded([2; 32], clock.clone()).with_protocol_version(old);
+        let (sa, sb) = sessions(Env::seeded([1; 32], clock), env_b).await;
+        assert_eq!(sa.transcript_hash, sb.transcript_hash);
+        assert_eq!(sa.peer.as_ref().unwrap().protocol_version, old);
+        assert_eq!(sb.peer.as_ref().unwrap().protocol_version, PROTOCOL_VERSION);
+
+        let mut wire = Vec::new();
+        sa.send_encrypted_frame(&mut wire, b"classic").await.unwrap();
+        assert_eq!(sb.read_encrypted_frame(&mut &wire[..]).await.unwrap(), b"classic");
+    }
+
+    #[tokio::test]
+    async fn test_forged_decline_aborts() {
+        let env = Env::seeded([3; 32], ManualClock::new(SystemTime::UNIX_EPOCH));
+        let (id_a, id_b) = (identity(1), identity(2));
+        let (mut a, mut relay_a) = tokio::io::duplex(4096);
+        let (mut b, mut relay_b) = tokio::io::duplex(4096);
+
+        // Answer the Noise flight with a decline the responder never sent,
+        // then pass the classic handshake through.
+        let relay = async move {
+            read_lp(&mut relay_a).await.unwrap();
+            write_lp(&mut relay_a, &[]).await.unwrap();
+            let message_a = read_lp(&mut relay_a).await.unwrap();
+            write_lp(&mut relay_b, &message_a).await.unwrap();
+            let message_b = read_lp(&mut relay_b).await.unwrap();
+            write_lp(&mut relay_a, &message_b).await.unwrap();
+            let finished = read_lp(&mut relay_b).await.unwrap();
+            write_lp(&mut relay_a, &finished).await.unwrap();
+            let finished = read_lp(&mut relay_a).await.unwrap();
+            write_lp(&mut relay_b, &finished).await.unwrap();
+        };
+        let (sa, _, _) = tokio::join!(
+            initiator_handshake(&id_a, "a", &mut a, &env),
+            responder_handshake_with(&id_b, "b", &mut b, &env),
+            relay,
+        );
+        assert!(matches!(sa, Err(HandshakeError::Crypto(e)) if e.contains("downgrade")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_noise_after_first_contact() {
+        let (src, dst) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let input = src.path().join("input.bin");
+        std::fs::write(&input, vec![6u8; 10_000]).unwrap();
+        let sender = client(&src, 1);
+        let mut receiver = client(&dst, 2);
+        let manifest = sender.import_file(&input).await.unwrap();
+
+        // The first push is classic; the second, without a ticket, is Noise.
+        for _ in 0..2 {
+            let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+            let (sent, received) = tokio::join!(
+                sender.send_manifest_to(a, manifest.clone(), "dev2"),
+                receiver.accept_and_receive(b),
+            );
+            sent.unwrap();
+            received.unwrap();
+            assert!(sender.noise_peers.lock().unwrap().contains("dev2"));
+            assert!(receiver.noise_peers.lock().unwrap().contains("dev1"));
+            sender.tickets.lock().unwrap().clear();
+        }
+
+        // Held back to an older protocol, the receiver declines and the
+        // push falls back to the classic handshake.
+        let old = crate::handshake::introduced_in(crate::handshake::FEATURE_NOISE).unwrap() - 1;
+        receiver.env = receiver.env.clone().with_protocol_version(old);
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (sent, received) = tokio::join!(
+            sender.send_manifest_to(a, manifest.clone(), "dev2"),
+            receiver.accept_and_receive(b),
+        );
+        sent.unwrap();
+        received.unwrap();
+        assert!(!sender.noise_peers.lock().unwrap().contains("dev2"));
+    }
+}
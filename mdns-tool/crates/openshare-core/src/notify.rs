@@ -0,0 +1,156 @@
+//! Filtered notifications of completed transfers.
+//!
+//! Where a [`TransferObserver`](crate::TransferObserver) sees every step of
+//! every transfer, a [`NotificationHub`] pushes one [`TransferRecord`] per
+//! finished transfer, and only to subscribers whose [`NotificationFilter`]
+//! matches it. That is what a tray app wants for toasts. The control API of
+//! [`crate::daemon`] exposes the hub to other processes.
+
+use crate::history::{Direction, TransferRecord};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+/// Which transfers a subscriber wants to hear about. The default matches
+/// everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NotificationFilter {
+    /// Only transfers with this peer device ID
+    #[serde(default)]
+    pub peer: Option<String>,
+    #[serde(default)]
+    pub direction: Option<Direction>,
+    /// Only transfers of at least this many bytes
+    #[serde(default)]
+    pub min_size: u64,
+}
+
+impl NotificationFilter {
+    pub fn matches(&self, record: &TransferRecord) -> bool {
+        self.peer.as_ref().is_none_or(|p| *p == record.peer)
+            && self.direction.is_none_or(|d| d == record.direction)
+            && record.size >= self.min_size
+    }
+}
+
+type Subscriber = (NotificationFilter, mpsc::UnboundedSender<TransferRecord>);
+
+/// Fans completed transfers out to subscribers. Cheap to clone; clones
+/// share subscribers.
+#[derive(Clone, Default)]
+pub struct NotificationHub {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+impl NotificationHub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Receive every future record matching `filter`. Dropping the
+    /// receiver ends the subscription.
+    pub fn subscribe(&self, filter: NotificationFilter) -> mpsc::UnboundedReceiver<TransferRecord> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.subscribers.lock().unwrap().push((filter, tx));
+        rx
+    }
+
+    pub fn publish(&self, record: &TransferRecord) {
+        self.subscribers.lock().unwrap().retain(|(filter, tx)| {
+            !filter.matches(record) || tx.send(record.clone()).is_ok()
+        });
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|(_, tx)| !tx.is_closed());
+        subscribers.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::fixture::client;
+    use crate::sim::{pair, LinkConfig};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_filtered_subscriptions() {
+        let hub = NotificationHub::new();
+        let mut all = hub.subscribe(NotificationFilter::default());
+        let mut big_from_phone = hub.subscribe(NotificationFilter {
+            peer: Some("phone".into()),
+            direction: Some(Direction::Received),
+            min_size: 1000,
+        });
+
+        let record = |peer: &str, direction, size| TransferRecord {
+            transfer_id: None,
+            direction,
+            peer: peer.into(),
+            peer_fingerprint: None,
+            filename: "f".into(),
+            size,
+            reused_bytes: 0,
+            compression_saved_bytes: 0,
+            finished_at: 0,
+            duration_ms: 0,
+            error: None,
+        };
+        hub.publish(&record("phone", Direction::Received, 10));
+        hub.publish(&record("laptop", Direction::Received, 5000));
+        hub.publish(&record("phone", Direction::Sent, 5000));
+        hub.publish(&record("phone", Direction::Received, 5000));
+
+        assert_eq!(std::iter::from_fn(|| all.try_recv().ok()).count(), 4);
+        assert_eq!(big_from_phone.try_recv().unwrap(), record("phone", Direction::Received, 5000));
+        assert!(big_from_phone.try_recv().is_err());
+
+        drop(all);
+        assert_eq!(hub.subscriber_count(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_share_announcement() {
+        use crate::config::Subscription;
+        use crate::history::Direction;
+        use crate::Accepted;
+
+        let (src, dst) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let input = src.path().join("photos-2024");
+        std::fs::write(&input, b"holiday pictures").unwrap();
+
+        let mut publisher = client(&src, 1);
+        let hub = NotificationHub::new();
+        let mut listener = client(&dst, 2).with_notifications(hub.clone());
+        publisher.cfg.account_hash = "acct".into();
+        listener.cfg.account_hash = "acct".into();
+        publisher.cfg.listen_port = 9100;
+        let manifest = publisher.import_file(&input).await.unwrap();
+        let mut notes = hub.subscribe(NotificationFilter::default());
+
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (announced, accepted) = tokio::join!(publisher.announce(a, &manifest), listener.accept(b));
+        announced.unwrap();
+        let Accepted::Announced { device_id, announcement } = accepted.unwrap() else { panic!("not an announcement") };
+        assert_eq!((device_id.as_str(), announcement.port), ("dev1", 9100));
+        assert_eq!(announcement.entry.manifest_id, manifest.id());
+        let note = notes.try_recv().unwrap();
+        assert_eq!((note.direction, note.peer.as_str(), note.filename.as_str()), (Direction::Announced, "dev1", "photos-2024"));
+
+        let subscription = Subscription { peer: Some("dev1".into()), query: "PHOTOS".into(), ..Default::default() };
+        assert!(subscription.matches("dev1", &announcement.entry));
+        assert!(!subscription.matches("dev3", &announcement.entry));
+
+        // Other accounts, and listeners that opted out, refuse.
+        for (account, accept) in [("other", true), ("acct", false)] {
+            publisher.cfg.account_hash = account.into();
+            listener.cfg.accept_announcements = accept;
+            let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+            let (announced, accepted) = tokio::join!(publisher.announce(a, &manifest), listener.accept(b));
+            assert!(announced.is_err() && accepted.is_err());
+        }
+        assert!(notes.try_recv().is_err());
+    }
+}
@@ -0,0 +1,346 @@
+//! Share codes: offering a file to whoever is given the code.
+//!
+//! `openshare offer` publishes a file, records an offer for it in
+//! [`OFFERS_FILE`] and prints a [`ShareCode`]: the manifest ID, a prefix
+//! of the offering device's key and the addresses it listens on. On
+//! another device `openshare get <code>` looks the device up by its key
+//! over mDNS, falls back to the addresses, and pulls the file.
+//!
+//! The code stands in for trust both ways. The getting device accepts
+//! the offering one if its key matches the code, and the offering device
+//! serves an offered manifest to any peer that asks while the offer is
+//! active, trusted or not. Everything else still needs a trusted peer.
+
+use crate::config::ClientConfig;
+use anyhow::{Context, Result};
+use mdns_core::model::InterfaceKind;
+use mdns_core::net::list_interface_ips_result;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// File name of the offers inside the data directory.
+pub const OFFERS_FILE: &str = "offers.json";
+
+/// Start of every share code.
+pub const CODE_PREFIX: &str = "os-";
+
+const CODE_VERSION: u8 = 1;
+
+/// Bytes of the offering device's key in a code: 80 bits, as hard to
+/// match with another key as the full one is in practice.
+pub const KEY_PREFIX_LEN: usize = 10;
+
+/// Most addresses put in a code.
+pub const MAX_HINTS: usize = 4;
+
+const BASE32: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// What `openshare get` needs to find and authenticate an offered file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShareCode {
+    pub manifest_id: String,
+    /// Start of the offering device's public key
+    pub key_prefix: [u8; KEY_PREFIX_LEN],
+    /// Addresses the device listened on when offering, best first
+    pub hints: Vec<SocketAddr>,
+}
+
+impl ShareCode {
+    pub fn new(manifest_id: &str, public_key: &[u8; 32], hints: Vec<SocketAddr>) -> Result<Self> {
+        anyhow::ensure!(
+            manifest_id.len() == 64 && manifest_id.bytes().all(|b| b.is_ascii_hexdigit()),
+            "Invalid manifest ID {:?}", manifest_id
+        );
+        let mut key_prefix = [0u8; KEY_PREFIX_LEN];
+        key_prefix.copy_from_slice(&public_key[..KEY_PREFIX_LEN]);
+        Ok(Self { manifest_id: manifest_id.to_ascii_lowercase(), key_prefix, hints })
+    }
+
+    /// The key prefix as a fingerprint (see
+    /// [`Identity::fingerprint_matches`](crate::Identity::fingerprint_matches)).
+    pub fn fingerprint(&self) -> String {
+        hex::encode(self.key_prefix)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![CODE_VERSION];
+        bytes.extend(hex::decode(&self.manifest_id).expect("checked in ShareCode::new"));
+        bytes.extend(self.key_prefix);
+        for hint in self.hints.iter().take(MAX_HINTS) {
+            match hint.ip() {
+                IpAddr::V4(ip) => {
+                    bytes.push(4);
+                    bytes.extend(ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    bytes.push(6);
+                    bytes.extend(ip.octets());
+                }
+            }
+            bytes.extend(hint.port().to_be_bytes());
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let (&version, rest) = bytes.split_first().context("Empty share code")?;
+        anyhow::ensure!(version == CODE_VERSION, "Share code version {} is not supported", version);
+        anyhow::ensure!(rest.len() >= 32 + KEY_PREFIX_LEN, "Share code is cut off");
+        let (id, rest) = rest.split_at(32);
+        let (key, mut rest) = rest.split_at(KEY_PREFIX_LEN);
+        let mut hints = Vec::new();
+        while let Some((&family, tail)) = rest.split_first() {
+            let len = match family {
+                4 => 4,
+                6 => 16,
+                _ => anyhow::bail!("Invalid address in share code"),
+            };
+            anyhow::ensure!(tail.len() >= len + 2 && hints.len() < MAX_HINTS, "Invalid address in share code");
+            let ip = match family {
+                4 => IpAddr::from(<[u8; 4]>::try_from(&tail[..4]).unwrap()),
+                _ => IpAddr::from(<[u8; 16]>::try_from(&tail[..16]).unwrap()),
+            };
+            let port = u16::from_be_bytes([tail[len], tail[len + 1]]);
+            hints.push(SocketAddr::new(ip, port));
+            rest = &tail[len + 2..];
+        }
+        Ok(Self { manifest_id: hex::encode(id), key_prefix: key.try_into().unwrap(), hints })
+    }
+}
+
+impl fmt::Display for ShareCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", CODE_PREFIX, base32_encode(&self.to_bytes()))
+    }
+}
+
+impl FromStr for ShareCode {
+    type Err = anyhow::Error;
+
+    /// Case, spaces and dashes after the prefix are ignored, so a code
+    /// survives being read out or wrapped.
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim().to_ascii_lowercase();
+        let body = s.strip_prefix(CODE_PREFIX).context("Not a share code (they start with 'os-')")?;
+        let body: String = body.chars().filter(|c| !c.is_whitespace() && *c != '-').collect();
+        Self::from_bytes(&base32_decode(&body).context("Not a share code")?)
+    }
+}
+
+/// RFC 4648 base32 in lower case, without padding.
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for &byte in bytes {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in s.bytes() {
+        let value = BASE32.iter().position(|&b| b == c)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Addresses peers can reach this device at on `port`: those in
+/// `listen_addresses` unless it listens on all, otherwise those of its
+/// network interfaces, local network before overlay and IPv4 first.
+/// Loopback and link-local IPv6 addresses, which mean nothing elsewhere,
+/// are left out.
+pub fn address_hints(cfg: &ClientConfig, port: u16) -> Vec<SocketAddr> {
+    let mut ips: Vec<(InterfaceKind, IpAddr)> = cfg.listen_endpoints().iter()
+        .map(|addr| addr.ip())
+        .filter(|ip| !ip.is_unspecified())
+        .map(|ip| (InterfaceKind::Lan, ip))
+        .collect();
+    if ips.is_empty() {
+        ips = list_interface_ips_result().unwrap_or_default().into_iter().map(|ifa| (ifa.kind, ifa.ip)).collect();
+    }
+    ips.retain(|(_, ip)| !ip.is_loopback() && !matches!(ip, IpAddr::V6(v6) if v6.segments()[0] & 0xffc0 == 0xfe80));
+    ips.sort_by_key(|(kind, ip)| (*kind != InterfaceKind::Lan, ip.is_ipv6()));
+    let mut hints: Vec<SocketAddr> = Vec::new();
+    for (_, ip) in ips {
+        let hint = SocketAddr::new(ip, port);
+        if !hints.contains(&hint) && hints.len() < MAX_HINTS {
+            hints.push(hint);
+        }
+    }
+    hints
+}
+
+/// A file offered with a share code.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OfferRecord {
+    pub manifest_id: String,
+    pub filename: String,
+    /// Unix timestamp (seconds) of the offer
+    pub created_at: u64,
+    /// Unix timestamp (seconds) after which the code stops working;
+    /// `None` until revoked
+    pub expires_at: Option<u64>,
+}
+
+impl OfferRecord {
+    pub fn is_active(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|expires_at| now < expires_at)
+    }
+}
+
+/// The offers of one device, kept in [`OFFERS_FILE`].
+#[derive(Debug, Clone)]
+pub struct Offers {
+    path: PathBuf,
+    offers: Vec<OfferRecord>,
+}
+
+impl Offers {
+    /// Load the offers from `data_dir`; a missing file is none.
+    pub fn load(data_dir: &Path) -> Result<Self> {
+        let path = data_dir.join(OFFERS_FILE);
+        let offers = crate::state::read(&path, |json| Ok(serde_json::from_slice(json)?))?.unwrap_or_default();
+        Ok(Self { path, offers })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        crate::state::write(&self.path, serde_json::to_string_pretty(&self.offers)?.as_bytes())
+    }
+
+    /// Record `offer`, replacing any earlier offer of the same manifest.
+    pub fn add(&mut self, offer: OfferRecord) {
+        self.offers.retain(|o| o.manifest_id != offer.manifest_id);
+        self.offers.push(offer);
+    }
+
+    /// Withdraw the offer of `manifest_id`; `false` if there was none.
+    pub fn remove(&mut self, manifest_id: &str) -> bool {
+        let before = self.offers.len();
+        self.offers.retain(|o| o.manifest_id != manifest_id);
+        self.offers.len() != before
+    }
+
+    /// The offer of `manifest_id`, if it is active at `now`.
+    pub fn active(&self, manifest_id: &str, now: u64) -> Option<&OfferRecord> {
+        self.offers.iter().find(|o| o.manifest_id == manifest_id && o.is_active(now))
+    }
+
+    pub fn list(&self) -> impl Iterator<Item = &OfferRecord> {
+        self.offers.iter()
+    }
+
+    /// Forget offers that expired before `now`; returns how many.
+    pub fn prune(&mut self, now: u64) -> usize {
+        let before = self.offers.len();
+        self.offers.retain(|o| o.is_active(now));
+        before - self.offers.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Identity;
+    use crate::sim::fixture::client;
+    use crate::sim::{pair, LinkConfig};
+    use ed25519_dalek::SigningKey;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_share_code() -> Result<()> {
+        let id = "ab".repeat(32);
+        let hints = vec!["192.168.1.20:9876".parse()?, "[fd00::1]:9877".parse()?];
+        let code = ShareCode::new(&id, &[7; 32], hints)?;
+        let text = code.to_string();
+        assert!(text.starts_with(CODE_PREFIX));
+        assert_eq!(text.parse::<ShareCode>()?, code);
+        assert_eq!(format!("  {} ", text.to_uppercase()).parse::<ShareCode>()?, code);
+        assert_eq!(code.fingerprint(), "07".repeat(KEY_PREFIX_LEN));
+
+        assert!(text[..text.len() - 3].parse::<ShareCode>().is_err());
+        assert!("os-0000".parse::<ShareCode>().is_err());
+        assert!(ShareCode::new("nothex", &[7; 32], Vec::new()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_offers() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut offers = Offers::load(dir.path())?;
+        let record = |id: &str, expires_at| OfferRecord {
+            manifest_id: id.to_string(), filename: "f".into(), created_at: 100, expires_at,
+        };
+        offers.add(record("a", Some(200)));
+        offers.add(record("b", None));
+        offers.save()?;
+
+        let mut offers = Offers::load(dir.path())?;
+        assert!(offers.active("a", 150).is_some() && offers.active("a", 200).is_none());
+        assert!(offers.active("b", u64::MAX).is_some() && offers.active("c", 0).is_none());
+        assert_eq!(offers.prune(300), 1);
+        assert!(offers.remove("b") && !offers.remove("b"));
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pull_offered_share() {
+        let (src, dst) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let input = src.path().join("input.bin");
+        std::fs::write(&input, vec![5u8; 150_000]).unwrap();
+
+        // Strangers: neither trusts the other.
+        let mut server = client(&src, 1);
+        server.cfg.trust_on_first_use = false;
+        let mut fetcher = client(&dst, 2);
+        fetcher.cfg.trust_on_first_use = false;
+        let manifest = server.import_file(&input).await.unwrap();
+        let id = server.publish(&manifest).unwrap();
+        let fingerprint = ShareCode::new(&id, &server.identity.public_key_bytes(), Vec::new()).unwrap().fingerprint();
+
+        // Published is not enough for a stranger, and the fetcher will not
+        // take the file from an unknown device without the code.
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (fetched, served) = tokio::join!(fetcher.request_file_pinned(a, &id, &fingerprint), server.accept(b));
+        assert!(fetched.is_err() && format!("{:#}", served.unwrap_err()).contains("Unknown peer"));
+
+        let mut offers = Offers::load(src.path()).unwrap();
+        offers.add(OfferRecord { manifest_id: id.clone(), filename: manifest.filename.clone(), created_at: 0, expires_at: None });
+        offers.save().unwrap();
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (fetched, served) = tokio::join!(fetcher.request_file(a, &id), server.accept(b));
+        assert!(format!("{:#}", fetched.unwrap_err()).contains("Unknown peer") && served.is_err());
+
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (fetched, served) = tokio::join!(fetcher.request_file_pinned(a, &id, &fingerprint), server.accept(b));
+        assert_eq!(fetched.unwrap().id(), id);
+        served.unwrap();
+
+        // The code does not stand in for a wrong key, nor open up pushes.
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let other = Identity::from(SigningKey::from_bytes(&[3; 32])).full_fingerprint();
+        let (fetched, served) = tokio::join!(fetcher.request_file_pinned(a, &id, &other), server.accept(b));
+        assert!(fetched.is_err() && served.is_err());
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (sent, received) = tokio::join!(fetcher.send_manifest_over(a, manifest.clone()), server.accept(b));
+        assert!(sent.is_err() && received.is_err());
+    }
+}
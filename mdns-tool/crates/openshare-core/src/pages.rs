@@ -0,0 +1,487 @@
+//! Manifests sent in pages.
+//!
+//! A manifest normally goes out as one encrypted frame, and frames are
+//! limited to 10 MiB: about 140 000 chunks, fewer for a tree with long
+//! paths. To peers with the `manifest-pages` feature, a manifest whose
+//! encoding exceeds `manifest_page_size` goes out as a [`ManifestHeader`]
+//! followed by [`ManifestPage`]s of about that size instead: first the
+//! chunk hashes, in order, then the file entries.
+//!
+//! The header holds the manifest's totals and the root of a Merkle tree
+//! over the pages, and is signed by the sender. Each page carries the
+//! sibling hashes from its leaf up to the root, so the receiver verifies
+//! every page as it arrives rather than once the listing is complete. The
+//! sender follows each page with its chunks (after the receiver's
+//! [`HaveChunks`](crate::protocol::HaveChunks) for the page, with
+//! `chunk-probe`), so chunks flow long before the last page is out.
+//!
+//! The header also carries the signature of the whole manifest, which
+//! the receiver checks once it has every page, so the assembled manifest
+//! verifies like one sent in a single frame. Pages carry no metadata, so a
+//! manifest is stripped of it and signed again before paging.
+//!
+//! Peers without `manifest-pages` still get the manifest whole, so one
+//! too large for a frame cannot reach them at all; [`check_whole`] refuses
+//! it before anything is sent.
+
+use crate::handshake::MAX_FRAME_PLAINTEXT;
+use crate::manifest::{FileEntry, Manifest};
+use crate::merkle::{root_from_proof, Tree, LEAF};
+use crate::Identity;
+use anyhow::{Context, Result};
+use ed25519_dalek::Signature;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::ops::Range;
+
+/// Prefix marking a manifest header frame. A bincode manifest starts with
+/// the filename length as a u64, which can never spell this.
+pub const HEADER_MAGIC: &[u8; 8] = b"OSMHEAD1";
+
+/// Prefix marking a manifest page frame. Like [`CANCEL_MAGIC`], a chunk
+/// frame starting with it would name a chunk index beyond any manifest.
+///
+/// [`CANCEL_MAGIC`]: crate::protocol::CANCEL_MAGIC
+pub const PAGE_MAGIC: &[u8; 8] = b"OSMPAGE1";
+
+/// Refuse to send `manifest`, encoded as `bytes`, in one frame if it does
+/// not fit: the receiver, a peer without `manifest-pages`, would drop the
+/// connection at the oversized frame.
+pub fn check_whole(manifest: &Manifest, bytes: &[u8]) -> Result<()> {
+    if bytes.len() > MAX_FRAME_PLAINTEXT {
+        anyhow::bail!(
+            "Manifest of {} is {} bytes, too large for one frame; it can only go to peers with manifest-pages",
+            manifest.filename, bytes.len(),
+        );
+    }
+    Ok(())
+}
+
+/// Domain separator for header signatures.
+const HEADER_CONTEXT: &[u8] = b"openshare manifest pages v1";
+
+/// Everything about a paged manifest but its chunk hashes and files.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ManifestHeader {
+    pub filename: String,
+    pub size: u64,
+    pub file_hash: String,
+    pub chunk_count: u64,
+    pub file_count: u64,
+    pub pages: u32,
+    /// Merkle root over the pages
+    pub root: [u8; 32],
+    pub sender_pubkey: Vec<u8>,
+    /// `sender_sig` of the whole manifest
+    pub manifest_sig: Vec<u8>,
+    /// Ed25519 signature over the fields above
+    pub signature: Vec<u8>,
+}
+
+impl ManifestHeader {
+    fn signed_bytes(&self) -> Result<Vec<u8>> {
+        let fields = bincode::serialize(&(
+            &self.filename,
+            self.size,
+            &self.file_hash,
+            self.chunk_count,
+            self.file_count,
+            self.pages,
+            self.root,
+            &self.sender_pubkey,
+            &self.manifest_sig,
+        ))?;
+        Ok([HEADER_CONTEXT, &fields].concat())
+    }
+
+    pub fn verify(&self, public_key: &[u8; 32]) -> Result<()> {
+        if self.sender_pubkey != public_key {
+            anyhow::bail!("Manifest header is not from the authenticated peer");
+        }
+        let sig: [u8; 64] = self.signature.as_slice().try_into()
+            .map_err(|_| anyhow::anyhow!("Invalid manifest header signature length"))?;
+        Identity::verify_with_pubkey(public_key, &self.signed_bytes()?, &Signature::from_bytes(&sig))
+            .context("Manifest header signature invalid")
+    }
+
+    pub fn to_frame(&self) -> Result<Vec<u8>> {
+        Ok([&HEADER_MAGIC[..], &bincode::serialize(self)?].concat())
+    }
+
+    /// Decode `frame` if it is a manifest header; `None` if it is not one.
+    pub fn from_frame(frame: &[u8]) -> Option<Result<Self>> {
+        let body = frame.strip_prefix(HEADER_MAGIC)?;
+        Some(bincode::deserialize(body).context("Malformed manifest header"))
+    }
+}
+
+/// The next slice of a paged manifest.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ManifestPage {
+    pub index: u32,
+    pub chunk_hashes: Vec<String>,
+    /// Only once all chunk hashes are out
+    pub files: Vec<FileEntry>,
+    /// Sibling hashes from this page's leaf up to the root
+    pub proof: Vec<[u8; 32]>,
+}
+
+impl ManifestPage {
+    pub fn to_frame(&self) -> Result<Vec<u8>> {
+        Ok([&PAGE_MAGIC[..], &bincode::serialize(self)?].concat())
+    }
+
+    /// Decode `frame` if it is a manifest page; `None` if it is not one.
+    pub fn from_frame(frame: &[u8]) -> Option<Result<Self>> {
+        let body = frame.strip_prefix(PAGE_MAGIC)?;
+        Some(bincode::deserialize(body).context("Malformed manifest page"))
+    }
+}
+
+fn leaf_hash(index: u32, chunk_hashes: &[String], files: &[FileEntry]) -> Result<[u8; 32]> {
+    use std::io::Write;
+
+    let mut hash = Sha256::new();
+    hash.update([LEAF]);
+    hash.update(index.to_be_bytes());
+    let mut writer = std::io::BufWriter::new(&mut hash);
+    bincode::serialize_into(&mut writer, &(chunk_hashes, files))?;
+    writer.flush()?;
+    drop(writer);
+    Ok(hash.finalize().into())
+}
+
+/// A signed manifest split into pages, ready to send.
+#[derive(Debug)]
+pub struct PagedManifest {
+    pub header: ManifestHeader,
+    /// Chunk and file ranges of each page
+    bounds: Vec<(Range<usize>, Range<usize>)>,
+    /// Merkle tree over the pages
+    tree: Tree,
+}
+
+impl PagedManifest {
+    /// Split `manifest`, already signed, into pages of about `page_size`
+    /// encoded bytes, and sign the header. `None` if it fits in one.
+    pub fn new(manifest: &Manifest, identity: &Identity, page_size: usize) -> Result<Option<Self>> {
+        let bounds = split(manifest, page_size as u64)?;
+        if bounds.len() < 2 {
+            return Ok(None);
+        }
+        let mut level = Vec::with_capacity(bounds.len());
+        for (index, (chunks, files)) in bounds.iter().enumerate() {
+            level.push(leaf_hash(index as u32, &manifest.chunk_hashes[chunks.clone()], &manifest.files[files.clone()])?);
+        }
+        let tree = Tree::new(level);
+
+        let mut header = ManifestHeader {
+            filename: manifest.filename.clone(),
+            size: manifest.size,
+            file_hash: manifest.file_hash.clone(),
+            chunk_count: manifest.chunk_hashes.len() as u64,
+            file_count: manifest.files.len() as u64,
+            pages: bounds.len() as u32,
+            root: tree.root(),
+            sender_pubkey: identity.public_key_bytes().to_vec(),
+            manifest_sig: manifest.sender_sig.clone().context("Manifest must be signed before paging")?,
+            signature: Vec::new(),
+        };
+        header.signature = identity.sign(&header.signed_bytes()?).to_bytes().to_vec();
+        Ok(Some(Self { header, bounds, tree }))
+    }
+
+    /// Chunk indices on page `index`.
+    pub fn chunks(&self, index: usize) -> Range<usize> {
+        self.bounds[index].0.clone()
+    }
+
+    /// Page `index` of `manifest`, which must be the one paged.
+    pub fn page(&self, manifest: &Manifest, index: usize) -> ManifestPage {
+        let (chunks, files) = self.bounds[index].clone();
+        let proof = self.tree.proof(index);
+        ManifestPage {
+            index: index as u32,
+            chunk_hashes: manifest.chunk_hashes[chunks].to_vec(),
+            files: manifest.files[files].to_vec(),
+            proof,
+        }
+    }
+}
+
+/// Cut `manifest` into pages of about `page_size` encoded bytes each, at
+/// least one chunk hash or file entry per page.
+fn split(manifest: &Manifest, page_size: u64) -> Result<Vec<(Range<usize>, Range<usize>)>> {
+    let mut bounds = Vec::new();
+    let (mut chunk, mut file) = (0, 0);
+    let mut page = (0..0, 0..0);
+    let mut bytes = 0;
+    while chunk < manifest.chunk_hashes.len() || file < manifest.files.len() {
+        let size = match manifest.chunk_hashes.get(chunk) {
+            Some(hash) => 8 + hash.len() as u64,
+            None => bincode::serialized_size(&manifest.files[file])?,
+        };
+        if bytes > 0 && bytes + size > page_size {
+            bounds.push(std::mem::replace(&mut page, (chunk..chunk, file..file)));
+            bytes = 0;
+        }
+        bytes += size;
+        if chunk < manifest.chunk_hashes.len() {
+            chunk += 1;
+            page.0.end = chunk;
+        } else {
+            file += 1;
+            page.1 = page.1.start..file;
+        }
+    }
+    bounds.push(page);
+    Ok(bounds)
+}
+
+/// Collects the pages of a manifest as they arrive, checking each one.
+#[derive(Debug)]
+pub struct PageAssembler {
+    header: ManifestHeader,
+    next: u32,
+    chunk_hashes: Vec<String>,
+    files: Vec<FileEntry>,
+}
+
+impl PageAssembler {
+    /// Start on the manifest described by `header`, whose signature must
+    /// be by `public_key`.
+    pub fn new(header: ManifestHeader, public_key: &[u8; 32]) -> Result<Self> {
+        header.verify(public_key)?;
+        if header.pages == 0 {
+            anyhow::bail!("Manifest header announces no pages");
+        }
+        Ok(Self { header, next: 0, chunk_hashes: Vec::new(), files: Vec::new() })
+    }
+
+    pub fn header(&self) -> &ManifestHeader {
+        &self.header
+    }
+
+    /// Chunk hashes received so far.
+    pub fn chunk_hashes(&self) -> &[String] {
+        &self.chunk_hashes
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.next == self.header.pages
+    }
+
+    /// Check `page` against the root and append it; returns the indices
+    /// of the chunks it added.
+    pub fn add(&mut self, page: ManifestPage) -> Result<Range<usize>> {
+        if self.is_complete() || page.index != self.next {
+            anyhow::bail!("Unexpected manifest page {} (expected {})", page.index, self.next);
+        }
+        let leaf = leaf_hash(page.index, &page.chunk_hashes, &page.files)?;
+        if root_from_proof(leaf, page.index as usize, self.header.pages as usize, &page.proof) != Some(self.header.root) {
+            anyhow::bail!("Manifest page {} does not match the signed root", page.index);
+        }
+        let start = self.chunk_hashes.len();
+        let chunks = start as u64 + page.chunk_hashes.len() as u64;
+        let files = self.files.len() as u64 + page.files.len() as u64;
+        if chunks > self.header.chunk_count || files > self.header.file_count {
+            anyhow::bail!("Manifest page {} exceeds the announced totals", page.index);
+        }
+        self.chunk_hashes.extend(page.chunk_hashes);
+        self.files.extend(page.files);
+        self.next += 1;
+        if self.is_complete() && (chunks != self.header.chunk_count || files != self.header.file_count) {
+            anyhow::bail!("Manifest pages fall short of the announced totals");
+        }
+        Ok(start..self.chunk_hashes.len())
+    }
+
+    /// The assembled manifest, once every page is in, with the signature
+    /// of the whole checked.
+    pub fn finish(self) -> Result<Manifest> {
+        if !self.is_complete() {
+            anyhow::bail!("Manifest incomplete: {} of {} pages", self.next, self.header.pages);
+        }
+        let public_key: [u8; 32] = self.header.sender_pubkey.as_slice().try_into()
+            .context("Invalid public key length")?;
+        let manifest = Manifest {
+            filename: self.header.filename,
+            size: self.header.size,
+            file_hash: self.header.file_hash,
+            chunk_hashes: self.chunk_hashes,
+            files: self.files,
+            sender_sig: Some(self.header.manifest_sig),
+            sender_pubkey: Some(self.header.sender_pubkey),
+            metadata: Vec::new(),
+            chunk_size: None,
+            preview: None,
+        };
+        manifest.verify_with_pubkey(&public_key)?;
+        Ok(manifest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::fixture::client;
+    use crate::sim::{pair, LinkConfig};
+    use ed25519_dalek::SigningKey;
+    use tempfile::TempDir;
+
+    fn manifest(chunks: usize, files: usize) -> Manifest {
+        Manifest {
+            filename: "tree".into(),
+            size: chunks as u64 * 1000,
+            file_hash: hex::encode([9u8; 32]),
+            chunk_hashes: (0..chunks).map(|i| hex::encode(Sha256::digest(i.to_be_bytes()))).collect(),
+            files: (0..files)
+                .map(|i| FileEntry { path: format!("dir/file-{}", i), size: 1000, first_chunk: i, chunk_count: 1, is_dir: false })
+                .collect(),
+            sender_sig: None,
+            sender_pubkey: None,
+            metadata: Vec::new(),
+            chunk_size: None,
+            preview: None,
+        }
+    }
+
+    #[test]
+    fn test_check_whole() {
+        let small = manifest(300, 70);
+        assert!(check_whole(&small, &small.to_bytes().unwrap()).is_ok());
+        let huge = manifest(170_000, 0);
+        let err = check_whole(&huge, &huge.to_bytes().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("only go to peers with manifest-pages"));
+    }
+
+    #[test]
+    fn test_pages_roundtrip() {
+        let identity = Identity::from(SigningKey::from_bytes(&[4; 32]));
+        let key = identity.public_key_bytes();
+        let mut original = manifest(300, 70);
+        original.sign_prehashed(&identity).unwrap();
+        assert!(PagedManifest::new(&original, &identity, 1 << 20).unwrap().is_none());
+
+        // Pages of every count from 2 up, including odd ones whose last
+        // leaf has no sibling
+        for page_size in [20_000, 4_000, 2_500, 1_000, 100] {
+            let paged = PagedManifest::new(&original, &identity, page_size).unwrap().unwrap();
+            let header = ManifestHeader::from_frame(&paged.header.to_frame().unwrap()).unwrap().unwrap();
+            let mut assembler = PageAssembler::new(header, &key).unwrap();
+            for index in 0..paged.header.pages as usize {
+                let frame = paged.page(&original, index).to_frame().unwrap();
+                let page = ManifestPage::from_frame(&frame).unwrap().unwrap();
+                assert_eq!(assembler.add(page).unwrap(), paged.chunks(index));
+            }
+            let assembled = assembler.finish().unwrap();
+            assert_eq!(assembled.id(), original.id());
+            assembled.verify().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_pages_are_checked() {
+        let identity = Identity::from(SigningKey::from_bytes(&[4; 32]));
+        let key = identity.public_key_bytes();
+        let mut original = manifest(200, 0);
+        original.sign(&identity).unwrap();
+        let paged = PagedManifest::new(&original, &identity, 2_000).unwrap().unwrap();
+        assert_eq!(paged.header.pages, 8);
+
+        // A header from someone else, or with a changed root
+        let other = Identity::from(SigningKey::from_bytes(&[5; 32]));
+        assert!(PageAssembler::new(paged.header.clone(), &other.public_key_bytes()).is_err());
+        let mut forged = paged.header.clone();
+        forged.root[0] ^= 1;
+        assert!(PageAssembler::new(forged, &key).is_err());
+
+        // A tampered page, a page out of order, another page's proof
+        let mut assembler = PageAssembler::new(paged.header.clone(), &key).unwrap();
+        let mut page = paged.page(&original, 0);
+        page.chunk_hashes[3] = hex::encode([0u8; 32]);
+        assert!(assembler.add(page).is_err());
+        assert!(assembler.add(paged.page(&original, 1)).is_err());
+        let mut page = paged.page(&original, 0);
+        page.proof = paged.page(&original, 1).proof;
+        assert!(assembler.add(page).is_err());
+
+        assembler.add(paged.page(&original, 0)).unwrap();
+        assert!(assembler.finish().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_paged_manifest() {
+        use crate::TransferEvent;
+
+        let (src, dst, out) = (TempDir::new().unwrap(), TempDir::new().unwrap(), TempDir::new().unwrap());
+        let root = src.path().join("share");
+        std::fs::create_dir_all(root.join("notes")).unwrap();
+        let big: Vec<u8> = (0..300_000u32).map(|i| (i % 239) as u8).collect();
+        std::fs::write(root.join("big.bin"), &big).unwrap();
+        for i in 0..40 {
+            std::fs::write(root.join(format!("notes/{}.txt", i)), format!("note {}", i)).unwrap();
+        }
+
+        // Pages of a few entries each, so the listing spans many of them.
+        let mut sender = client(&src, 1);
+        sender.cfg.manifest_page_size = 500;
+        let receiver = client(&dst, 2).with_output_dir(out.path());
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (sent, received) = tokio::join!(
+            sender.send_file_streaming(a, &root, false),
+            receiver.accept_and_receive(b),
+        );
+        let (manifest, received) = (sent.unwrap(), received.unwrap());
+        assert_eq!(received.id(), manifest.id());
+        let mut signed = manifest.clone();
+        signed.sign(&sender.identity).unwrap();
+        let paged = PagedManifest::new(&signed, &sender.identity, 500).unwrap().unwrap();
+        assert!(paged.header.pages > 5);
+
+        receiver.write_file(&received, &received.output_path(out.path()).unwrap()).await.unwrap();
+        let tree = out.path().join("share");
+        assert_eq!(std::fs::read(tree.join("big.bin")).unwrap(), big);
+        assert_eq!(std::fs::read(tree.join("notes/39.txt")).unwrap(), b"note 39");
+
+        // Each page is probed as it arrives, so sending again moves no chunks.
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let sender = sender.with_observer(tx);
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (sent, received) = tokio::join!(
+            sender.send_file_streaming(a, &root, false),
+            receiver.accept_and_receive(b),
+        );
+        sent.unwrap();
+        received.unwrap();
+        drop(sender);
+        while let Some(event) = rx.recv().await {
+            assert!(!matches!(event, TransferEvent::ChunkSent { .. }));
+        }
+
+        // Pulls page the manifest too.
+        let mut server = client(&src, 1);
+        server.cfg.manifest_page_size = 500;
+        let id = server.publish(&server.import_file(&root).await.unwrap()).unwrap();
+        let elsewhere = TempDir::new().unwrap();
+        let puller = client(&elsewhere, 3);
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (fetched, served) = tokio::join!(puller.request_chunks(a, &id, vec![0, 4]), server.accept(b));
+        served.unwrap();
+        let fetched = fetched.unwrap();
+        assert_eq!(fetched.id(), id);
+        assert_eq!(puller.missing_chunks(&fetched).await.unwrap().len(), fetched.chunk_hashes.len() - 2);
+
+        // A listing too large for one frame is refused up front for a
+        // receiver without pages, rather than cut off mid-transfer.
+        let mut huge = fetched.clone();
+        huge.chunk_hashes = (0..170_000u32).map(|i| format!("{:064x}", i)).collect();
+        let mut older = client(&elsewhere, 3);
+        let version = crate::handshake::introduced_in(crate::handshake::FEATURE_MANIFEST_PAGES).unwrap() - 1;
+        older.env = older.env.clone().with_protocol_version(version);
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (sent, received) = tokio::join!(server.send_manifest_to(a, huge, "dev3"), older.accept_and_receive(b));
+        assert!(format!("{:#}", sent.unwrap_err()).contains("too large for one frame"));
+        assert!(received.is_err());
+    }
+}
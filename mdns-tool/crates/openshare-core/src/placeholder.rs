@@ -0,0 +1,85 @@
+//! Placeholders for shares that are fetched on demand.
+//!
+//! `openshare fetch --placeholder` pulls only the manifest of a published
+//! share and leaves a `<name>.oshare-placeholder` stub where the file (or
+//! directory) would go. The stub records the peer and the signed manifest,
+//! which is all a [`ShareView`](crate::vfs::ShareView) with a
+//! [`PeerChunkFetcher`](crate::client::PeerChunkFetcher) needs to fetch
+//! chunks on first read. `openshare pin` materializes the share in full
+//! and removes the stub.
+
+use crate::Manifest;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Appended to the target path to name its stub.
+pub const PLACEHOLDER_SUFFIX: &str = ".oshare-placeholder";
+
+/// Layout version of the stub file.
+pub const PLACEHOLDER_FORMAT: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Placeholder {
+    pub format: u32,
+    /// Address (host:port) of the peer publishing the share
+    pub peer: String,
+    pub manifest_id: String,
+    pub manifest: Manifest,
+    /// Unix timestamp (seconds) when the stub was created
+    pub created_at: u64,
+}
+
+impl Placeholder {
+    pub fn new(peer: &str, manifest: Manifest, created_at: u64) -> Self {
+        Self {
+            format: PLACEHOLDER_FORMAT,
+            peer: peer.to_string(),
+            manifest_id: manifest.id(),
+            manifest,
+            created_at,
+        }
+    }
+
+    /// Where the stub for `target` lives.
+    pub fn path_for(target: &Path) -> PathBuf {
+        let mut name = target.as_os_str().to_os_string();
+        name.push(PLACEHOLDER_SUFFIX);
+        PathBuf::from(name)
+    }
+
+    /// The share path a stub stands in for; `None` if `stub` is not one.
+    pub fn target_of(stub: &Path) -> Option<PathBuf> {
+        let name = stub.to_str()?.strip_suffix(PLACEHOLDER_SUFFIX)?;
+        Some(PathBuf::from(name))
+    }
+
+    pub fn write(&self, target: &Path) -> Result<()> {
+        let stub = Self::path_for(target);
+        std::fs::write(&stub, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("writing {}", stub.display()))
+    }
+
+    /// Load the placeholder for `path`, which may name either the stub or
+    /// the share it stands in for. Returns it with the target path.
+    pub fn load(path: &Path) -> Result<(Self, PathBuf)> {
+        let (stub, target) = match Self::target_of(path) {
+            Some(target) => (path.to_path_buf(), target),
+            None => (Self::path_for(path), path.to_path_buf()),
+        };
+        let json = std::fs::read_to_string(&stub)
+            .with_context(|| format!("No placeholder at {}", stub.display()))?;
+        let placeholder: Self = serde_json::from_str(&json)
+            .with_context(|| format!("parsing {}", stub.display()))?;
+        if placeholder.manifest.id() != placeholder.manifest_id {
+            anyhow::bail!("{} does not match its manifest ID", stub.display());
+        }
+        Ok((placeholder, target))
+    }
+
+    /// Delete the stub for `target`, once the share is materialized.
+    pub fn remove(target: &Path) -> Result<()> {
+        let stub = Self::path_for(target);
+        std::fs::remove_file(&stub).with_context(|| format!("removing {}", stub.display()))
+    }
+}
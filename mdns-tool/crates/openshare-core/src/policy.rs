@@ -0,0 +1,194 @@
+//! Deciding which pushed transfers to take.
+//!
+//! Once the manifest of a push is in, and before any chunk is sent, the
+//! receiver's [`TransferPolicy`] reviews it as an [`Offer`]. Peers with
+//! `transfer-consent` are told the [`Verdict`], and a rejected push fails
+//! with [`Rejected`](crate::transfer::Rejected) on both sides; older
+//! peers only learn that the transfer was aborted. Pulls are not
+//! reviewed, as this side asked for them. Of a manifest sent in pages,
+//! only the header is known at that point, so the files it lists later
+//! are not reviewed either.
+//!
+//! A [`Client`](crate::Client) applies the [`Limits`] of its config,
+//! unless given another policy with
+//! [`Client::with_policy`](crate::Client::with_policy).
+//!
+//! Besides names and sizes, an offer of a single image may carry the
+//! [`Preview`] its sender made, to show with the question.
+
+use crate::config::ClientConfig;
+use crate::preview::Preview;
+use crate::space::InsufficientSpace;
+use crate::transfer::TransferId;
+use crate::trust::TrustSource;
+use async_trait::async_trait;
+
+pub use crate::protocol::Verdict;
+
+/// A pushed transfer awaiting a decision.
+#[derive(Debug, Clone)]
+pub struct Offer {
+    pub transfer_id: TransferId,
+    /// Device ID of the sender
+    pub peer: String,
+    /// How the sender's key came to be trusted
+    pub trust: Option<TrustSource>,
+    pub filename: String,
+    pub size: u64,
+    /// Paths of the files of a directory, if the manifest came whole
+    pub files: Vec<String>,
+    /// Set if the transfer would not fit on disk (see [`crate::space`])
+    pub space: Option<InsufficientSpace>,
+    /// What the image offered looks like, if the sender said so
+    pub preview: Option<Preview>,
+}
+
+impl Offer {
+    /// Lower-case extensions of the files offered, or of the share itself
+    /// if no files are listed.
+    pub fn file_types(&self) -> Vec<String> {
+        let names: Vec<&str> = match self.files.is_empty() {
+            true => vec![self.filename.as_str()],
+            false => self.files.iter().map(String::as_str).collect(),
+        };
+        let mut types: Vec<String> = names.into_iter()
+            .filter_map(|name| std::path::Path::new(name).extension()?.to_str())
+            .map(str::to_lowercase)
+            .collect();
+        types.sort();
+        types.dedup();
+        types
+    }
+}
+
+#[async_trait]
+pub trait TransferPolicy: Send + Sync {
+    /// Decide on `offer`. This may take a while, e.g. to ask someone; the
+    /// sender waits for the verdict.
+    async fn review(&self, offer: &Offer) -> Verdict;
+}
+
+/// Limits on incoming pushes, as set in a [`ClientConfig`], and the room
+/// on disk for them. The default accepts everything that fits.
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    /// Largest share accepted; 0 for any size
+    pub max_bytes: u64,
+    /// File extensions refused, lower case and without the dot
+    pub blocked_types: Vec<String>,
+    /// Refuse peers only trusted on first use
+    pub verified_peers_only: bool,
+}
+
+impl Limits {
+    pub fn from_config(cfg: &ClientConfig) -> Self {
+        Self {
+            max_bytes: cfg.max_incoming_bytes,
+            blocked_types: cfg.blocked_file_types.iter()
+                .map(|t| t.trim_start_matches('.').to_lowercase())
+                .collect(),
+            verified_peers_only: cfg.verified_peers_only,
+        }
+    }
+
+    /// Why `offer` is over a limit, if it is.
+    pub fn check(&self, offer: &Offer) -> Option<String> {
+        if self.verified_peers_only && !matches!(offer.trust, Some(TrustSource::Manual | TrustSource::Paired | TrustSource::Account)) {
+            return Some(format!("{} is not a verified device", offer.peer));
+        }
+        if self.max_bytes > 0 && offer.size > self.max_bytes {
+            return Some(format!("{} bytes is over the limit of {}", offer.size, self.max_bytes));
+        }
+        if let Some(space) = &offer.space {
+            // Without the path, which is none of the sender's business
+            return Some(format!("not enough disk space ({} bytes needed, {} available)", space.needed, space.available));
+        }
+        let blocked = offer.file_types().into_iter().find(|t| self.blocked_types.contains(t))?;
+        Some(format!(".{} files are not accepted", blocked))
+    }
+}
+
+#[async_trait]
+impl TransferPolicy for Limits {
+    async fn review(&self, offer: &Offer) -> Verdict {
+        match self.check(offer) {
+            Some(reason) => Verdict::Reject(reason),
+            None => Verdict::Accept,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OpenShareError;
+    use crate::sim::fixture::client;
+    use crate::sim::{pair, LinkConfig};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_limits() {
+        let offer = Offer {
+            transfer_id: TransferId::from_bytes([7; 16]),
+            peer: "laptop".into(),
+            trust: Some(TrustSource::FirstUse),
+            filename: "photos".into(),
+            size: 5000,
+            files: vec!["a/IMG_1.JPG".into(), "a/setup.exe".into(), "README".into()],
+            space: None,
+            preview: None,
+        };
+        assert_eq!(offer.file_types(), ["exe", "jpg"]);
+        assert_eq!(Limits::default().check(&offer), None);
+
+        let cfg = ClientConfig { blocked_file_types: vec![".EXE".into()], ..ClientConfig::default() };
+        assert_eq!(Limits::from_config(&cfg).check(&offer).unwrap(), ".exe files are not accepted");
+        let limits = Limits { max_bytes: 4999, ..Limits::default() };
+        assert_eq!(limits.check(&offer).unwrap(), "5000 bytes is over the limit of 4999");
+        let limits = Limits { verified_peers_only: true, ..Limits::default() };
+        assert!(limits.check(&offer).is_some());
+        assert!(limits.check(&Offer { trust: Some(TrustSource::Paired), ..offer.clone() }).is_none());
+
+        let space = InsufficientSpace { path: "/media/usb".into(), needed: 5000, available: 100 };
+        let reason = Limits::default().check(&Offer { space: Some(space), ..offer }).unwrap();
+        assert_eq!(reason, "not enough disk space (5000 bytes needed, 100 available)");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_receiver_rejects_push() {
+        use crate::transfer::Rejected;
+        use storage::Storage;
+
+        let (src, dst) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let (big, small) = (src.path().join("big.bin"), src.path().join("small.bin"));
+        std::fs::write(&big, vec![3u8; 150_000]).unwrap();
+        std::fs::write(&small, vec![4u8; 50_000]).unwrap();
+
+        let sender = client(&src, 1);
+        let receiver = client(&dst, 2).with_policy(Limits { max_bytes: 100_000, ..Limits::default() });
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let manifest = sender.import_file(&big).await.unwrap();
+        let (sent, received) = tokio::join!(
+            sender.send_manifest_over(a, manifest),
+            receiver.accept_and_receive(b),
+        );
+        let reason = "150000 bytes is over the limit of 100000".to_string();
+        let Err(OpenShareError::Rejected(rejected)) = sent else { panic!("not rejected") };
+        assert_eq!(rejected, Rejected { by: Some("dev2".into()), reason: reason.clone() });
+        let Err(OpenShareError::Rejected(rejected)) = received else { panic!("not rejected") };
+        assert_eq!(rejected, Rejected { by: None, reason });
+        assert!(receiver.storage.list_chunks(None, 10).await.unwrap().is_empty());
+
+        // What the policy allows still arrives.
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let manifest = sender.import_file(&small).await.unwrap();
+        let (sent, received) = tokio::join!(
+            sender.send_manifest_over(a, manifest),
+            receiver.accept_and_receive(b),
+        );
+        sent.unwrap();
+        let output = dst.path().join("small.bin");
+        receiver.write_file(&received.unwrap(), &output).await.unwrap();
+        assert_eq!(std::fs::read(output).unwrap(), vec![4u8; 50_000]);
+    }
+}
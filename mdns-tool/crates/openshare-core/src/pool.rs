@@ -0,0 +1,48 @@
+//! Bounded pool for CPU-heavy work such as chunk hashing.
+//!
+//! Jobs run on tokio's blocking threads, but at most `size` at a time, so
+//! small boxes (e.g. 2-core ARM NAS units) can cap how much CPU a transfer
+//! takes away from everything else.
+
+use anyhow::Result;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+#[derive(Clone)]
+pub struct ComputePool {
+    permits: Arc<Semaphore>,
+    size: usize,
+}
+
+impl ComputePool {
+    /// Create a pool running up to `size` jobs concurrently; 0 means one
+    /// per available CPU core.
+    pub fn new(size: usize) -> Self {
+        let size = if size == 0 {
+            std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+        } else {
+            size
+        };
+        Self { permits: Arc::new(Semaphore::new(size)), size }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Run `f` on a blocking thread once a slot is free.
+    pub async fn run<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let _permit = self.permits.acquire().await?;
+        Ok(tokio::task::spawn_blocking(f).await?)
+    }
+}
+
+impl Default for ComputePool {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
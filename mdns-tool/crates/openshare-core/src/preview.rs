@@ -0,0 +1,291 @@
+//! Previews of sent images.
+//!
+//! With `previews` on, a single image sent gets a [`Preview`] in its
+//! manifest: its type, dimensions and a small PNG thumbnail, for the
+//! receiver to show before accepting the transfer. Peers without the
+//! `previews` feature get the manifest without it.
+//!
+//! Image decoders see whatever file the user picks, so the sender does not
+//! run them in its own process. An [`Extractor`] starts a child process
+//! (by default this executable again, which calls [`run_child`] first
+//! thing) that decodes the image under an address space and CPU time limit
+//! and writes the preview to a file; the parent kills it after a timeout.
+//! A malformed image can then at worst cost a preview, never the daemon.
+//! Decoding needs the `previews` cargo feature; without it every extraction
+//! fails and files are sent without a preview.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+/// Largest side of a thumbnail, in pixels.
+pub const THUMBNAIL_SIZE: u32 = 128;
+
+/// Largest encoded thumbnail accepted in a manifest.
+pub const MAX_THUMBNAIL_BYTES: usize = 64 * 1024;
+
+/// Longest media type accepted in a manifest.
+const MAX_MEDIA_TYPE_LEN: usize = 64;
+
+/// Extensions of the files previews are made of.
+pub const EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "webp"];
+
+/// Set in the child to the image to preview.
+const ENV_SOURCE: &str = "OPENSHARE_PREVIEW_SOURCE";
+/// Set in the child to where the preview goes.
+const ENV_OUTPUT: &str = "OPENSHARE_PREVIEW_OUTPUT";
+/// Set in the child to its address space limit in bytes.
+const ENV_MEMORY: &str = "OPENSHARE_PREVIEW_MEMORY";
+/// Set in the child to its CPU time limit in seconds.
+const ENV_CPU: &str = "OPENSHARE_PREVIEW_CPU";
+
+/// What an image looks like, as carried in its manifest.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Preview {
+    /// e.g. "image/png"
+    pub media_type: String,
+    /// Dimensions of the full image
+    pub width: u32,
+    pub height: u32,
+    /// PNG of at most [`THUMBNAIL_SIZE`] pixels a side
+    pub thumbnail: Vec<u8>,
+}
+
+impl Preview {
+    /// Check that a received preview is within the limits a sender keeps
+    /// to.
+    pub fn check(&self) -> Result<()> {
+        if self.media_type.len() > MAX_MEDIA_TYPE_LEN {
+            anyhow::bail!("Preview media type too long");
+        }
+        if self.thumbnail.len() > MAX_THUMBNAIL_BYTES {
+            anyhow::bail!("Preview thumbnail too large: {} bytes", self.thumbnail.len());
+        }
+        Ok(())
+    }
+}
+
+/// Whether `path` looks like a file a preview is made of.
+pub fn is_previewable(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| EXTENSIONS.iter().any(|e| ext.eq_ignore_ascii_case(e)))
+}
+
+/// Makes previews in a child process.
+#[derive(Debug, Clone)]
+pub struct Extractor {
+    program: PathBuf,
+    args: Vec<String>,
+    timeout: Duration,
+    memory_bytes: u64,
+}
+
+impl Extractor {
+    /// Run this executable again for each preview, with the given limits.
+    /// Its `main` must call [`run_child`] before anything else.
+    pub fn new(timeout: Duration, memory_bytes: u64) -> Result<Self> {
+        let program = std::env::current_exe().context("Failed to locate the running executable")?;
+        Ok(Self { program, args: Vec::new(), timeout, memory_bytes })
+    }
+
+    /// Run `program` with `args` instead, e.g. a test binary.
+    pub fn with_program(mut self, program: PathBuf, args: Vec<String>) -> Self {
+        self.program = program;
+        self.args = args;
+        self
+    }
+
+    /// The preview of the file at `path`, or `None` if it is not an image
+    /// previews are made of. Blocks until the child is done or killed.
+    pub fn extract(&self, path: &Path) -> Result<Option<Preview>> {
+        if !is_previewable(path) {
+            return Ok(None);
+        }
+        let output = Output::new()?;
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .env(ENV_SOURCE, path)
+            .env(ENV_OUTPUT, &output.0)
+            .env(ENV_MEMORY, self.memory_bytes.to_string())
+            .env(ENV_CPU, self.timeout.as_secs().max(1).to_string())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .with_context(|| format!("Failed to start preview process {}", self.program.display()))?;
+        let deadline = Instant::now() + self.timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                anyhow::bail!("Preview of {} timed out after {:?}", path.display(), self.timeout);
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        };
+        if !status.success() {
+            anyhow::bail!("Preview of {} failed ({})", path.display(), status);
+        }
+        let len = std::fs::metadata(&output.0)?.len();
+        if len > (MAX_THUMBNAIL_BYTES + MAX_MEDIA_TYPE_LEN + 64) as u64 {
+            anyhow::bail!("Preview of {} too large", path.display());
+        }
+        let preview: Preview = bincode::deserialize(&std::fs::read(&output.0)?)
+            .context("Invalid preview from child process")?;
+        preview.check()?;
+        Ok(Some(preview))
+    }
+}
+
+/// A file for the child to write to, removed when dropped.
+struct Output(PathBuf);
+
+impl Output {
+    fn new() -> Result<Self> {
+        let mut name = [0u8; 8];
+        OsRng.fill_bytes(&mut name);
+        let path = std::env::temp_dir().join(format!("openshare-preview-{}", hex::encode(name)));
+        std::fs::OpenOptions::new().write(true).create_new(true).open(&path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// If this process was started by an [`Extractor`], make the preview and
+/// return the exit code; otherwise return `None` at once.
+pub fn run_child() -> Option<i32> {
+    let source = std::env::var_os(ENV_SOURCE)?;
+    let output = std::env::var_os(ENV_OUTPUT)?;
+    let memory = std::env::var(ENV_MEMORY).ok().and_then(|v| v.parse().ok());
+    let cpu = std::env::var(ENV_CPU).ok().and_then(|v| v.parse().ok());
+    let result = limit(memory, cpu)
+        .and_then(|()| decode(Path::new(&source), memory))
+        .and_then(|preview| Ok(std::fs::write(&output, bincode::serialize(&preview)?)?));
+    Some(match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Preview of {} failed: {:#}", Path::new(&source).display(), e);
+            1
+        }
+    })
+}
+
+/// Hold this process to `memory` bytes of address space and `cpu` seconds.
+#[cfg(unix)]
+fn limit(memory: Option<u64>, cpu: Option<u64>) -> Result<()> {
+    use rustix::process::{setrlimit, Resource, Rlimit};
+
+    if let Some(bytes) = memory {
+        setrlimit(Resource::As, Rlimit { current: Some(bytes), maximum: Some(bytes) })
+            .context("Failed to limit preview memory")?;
+    }
+    if let Some(secs) = cpu {
+        setrlimit(Resource::Cpu, Rlimit { current: Some(secs), maximum: Some(secs) })
+            .context("Failed to limit preview CPU time")?;
+    }
+    Ok(())
+}
+
+/// Elsewhere only the parent's timeout applies.
+#[cfg(not(unix))]
+fn limit(_memory: Option<u64>, _cpu: Option<u64>) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(feature = "previews")]
+fn decode(path: &Path, memory: Option<u64>) -> Result<Preview> {
+    use image::{ImageFormat, ImageReader, Limits};
+
+    let mut reader = ImageReader::open(path)?.with_guessed_format()?;
+    let format = reader.format().context("Unknown image format")?;
+    let mut limits = Limits::default();
+    // Leave room for the thumbnail and the process itself.
+    limits.max_alloc = memory.map(|bytes| bytes / 2);
+    reader.limits(limits);
+    let image = reader.decode().context("Failed to decode image")?;
+    let mut thumbnail = Vec::new();
+    image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        .write_to(&mut std::io::Cursor::new(&mut thumbnail), ImageFormat::Png)?;
+    let preview = Preview {
+        media_type: format.to_mime_type().to_string(),
+        width: image.width(),
+        height: image.height(),
+        thumbnail,
+    };
+    preview.check()?;
+    Ok(preview)
+}
+
+#[cfg(not(feature = "previews"))]
+fn decode(_path: &Path, _memory: Option<u64>) -> Result<Preview> {
+    anyhow::bail!("Built without the previews feature")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// The child side, when the tests below run this binary as one.
+    #[test]
+    fn child() {
+        if let Some(code) = run_child() {
+            std::process::exit(code);
+        }
+    }
+
+    fn extractor(timeout: Duration) -> Extractor {
+        let args = ["--exact", "preview::tests::child", "--test-threads=1"].map(String::from).to_vec();
+        Extractor::new(timeout, 512 * 1024 * 1024).unwrap()
+            .with_program(std::env::current_exe().unwrap(), args)
+    }
+
+    #[test]
+    fn test_only_images_spawn() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let path = tmp.path().join("notes.txt");
+        std::fs::write(&path, "text")?;
+        let extractor = extractor(Duration::from_secs(5)).with_program(tmp.path().join("missing"), Vec::new());
+        assert_eq!(extractor.extract(&path)?, None);
+        assert!(is_previewable(Path::new("a/B.JPEG")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_malformed_image_fails_in_child() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let path = tmp.path().join("broken.png");
+        let mut bytes = b"\x89PNG\r\n\x1a\n".to_vec();
+        bytes.extend_from_slice(&[0xff; 512]);
+        std::fs::write(&path, bytes)?;
+        assert!(extractor(Duration::from_secs(30)).extract(&path).is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "previews")]
+    #[test]
+    fn test_preview_of_image() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let path = tmp.path().join("photo.png");
+        image::RgbImage::from_fn(640, 320, |x, y| image::Rgb([x as u8, y as u8, 0]))
+            .save(&path)?;
+        let preview = extractor(Duration::from_secs(30)).extract(&path)?.expect("a preview");
+        assert_eq!((preview.media_type.as_str(), preview.width, preview.height), ("image/png", 640, 320));
+        let thumbnail = image::load_from_memory(&preview.thumbnail)?;
+        assert_eq!((thumbnail.width(), thumbnail.height()), (THUMBNAIL_SIZE, THUMBNAIL_SIZE / 2));
+        Ok(())
+    }
+}
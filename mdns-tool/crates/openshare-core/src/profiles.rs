@@ -0,0 +1,194 @@
+//! Named profiles: separate identities on one machine.
+//!
+//! A profile is a data directory of its own, so it has its own identity,
+//! config, trust store, chunks and history, e.g. one for work and one for
+//! personal use, or one per person on a shared computer. The data
+//! directory (`~/.openshare` unless `--data-dir` says otherwise) is the
+//! [`DEFAULT_PROFILE`] itself, and named profiles live in [`PROFILES_DIR`]
+//! inside it. [`CURRENT_FILE`] names the profile commands use when none is
+//! given.
+//!
+//! A new profile gets [profile-aware defaults](ClientConfig::for_profile),
+//! so that one daemon can serve all of them at once.
+
+use crate::config::ClientConfig;
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+/// The profile kept in the data directory itself.
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Directory in the data directory holding named profiles, one directory
+/// each.
+pub const PROFILES_DIR: &str = "profiles";
+
+/// File in the data directory naming the current profile; without it the
+/// current profile is the [`DEFAULT_PROFILE`].
+pub const CURRENT_FILE: &str = "current-profile";
+
+/// Longest profile name.
+const MAX_NAME: usize = 64;
+
+/// A profile found by [`Profiles::list`].
+#[derive(Debug, Clone)]
+pub struct ProfileInfo {
+    pub name: String,
+    pub data_dir: PathBuf,
+    /// Its config, if it has been initialized and the config loads
+    pub config: Option<ClientConfig>,
+    pub current: bool,
+}
+
+/// The profiles under one data directory.
+#[derive(Debug, Clone)]
+pub struct Profiles {
+    root: PathBuf,
+}
+
+impl Profiles {
+    /// Profiles kept in the data directory `root`.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Data directory of profile `name`.
+    pub fn data_dir(&self, name: &str) -> Result<PathBuf> {
+        validate_name(name)?;
+        Ok(if name == DEFAULT_PROFILE { self.root.clone() } else { self.root.join(PROFILES_DIR).join(name) })
+    }
+
+    /// Data directory of `spec`: a profile name, or the data directory of
+    /// a profile elsewhere, told apart by containing a path separator.
+    pub fn resolve(&self, spec: &str) -> Result<PathBuf> {
+        if spec.contains(['/', std::path::MAIN_SEPARATOR]) {
+            return Ok(PathBuf::from(spec));
+        }
+        self.data_dir(spec)
+    }
+
+    /// Whether profile `name` has been initialized.
+    pub fn exists(&self, name: &str) -> bool {
+        self.data_dir(name).is_ok_and(|dir| dir.join("config.json").exists())
+    }
+
+    /// The profile used when none is given.
+    pub fn current(&self) -> Result<String> {
+        let path = self.root.join(CURRENT_FILE);
+        let name = match std::fs::read_to_string(&path) {
+            Ok(name) => name.trim().to_string(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(DEFAULT_PROFILE.to_string()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        validate_name(&name).with_context(|| format!("Invalid profile in {}", path.display()))?;
+        Ok(name)
+    }
+
+    /// Make `name`, which has to be initialized, the current profile.
+    pub fn switch(&self, name: &str) -> Result<()> {
+        anyhow::ensure!(self.exists(name), "No profile {}; create it with 'openshare profile create'", name);
+        let path = self.root.join(CURRENT_FILE);
+        if name == DEFAULT_PROFILE {
+            return match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    Err(e).with_context(|| format!("Failed to remove {}", path.display()))
+                }
+                _ => Ok(()),
+            };
+        }
+        std::fs::write(&path, format!("{}\n", name)).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// The default profile, if initialized or current, then named profiles
+    /// by name.
+    pub fn list(&self) -> Result<Vec<ProfileInfo>> {
+        let current = self.current()?;
+        let mut names = Vec::new();
+        if self.exists(DEFAULT_PROFILE) || current == DEFAULT_PROFILE {
+            names.push(DEFAULT_PROFILE.to_string());
+        }
+        let dir = self.root.join(PROFILES_DIR);
+        let mut named = Vec::new();
+        match std::fs::read_dir(&dir) {
+            Ok(entries) => {
+                for entry in entries {
+                    let entry = entry?;
+                    let Ok(name) = entry.file_name().into_string() else { continue };
+                    if entry.file_type()?.is_dir() && validate_name(&name).is_ok() && name != DEFAULT_PROFILE {
+                        named.push(name);
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", dir.display())),
+        }
+        named.sort();
+        names.extend(named);
+
+        names.into_iter()
+            .map(|name| {
+                let data_dir = self.data_dir(&name)?;
+                let config = ClientConfig::load(&data_dir.join("config.json")).ok();
+                Ok(ProfileInfo { current: name == current, name, data_dir, config })
+            })
+            .collect()
+    }
+
+    /// Config defaults for new profile `name`: see
+    /// [`ClientConfig::for_profile`]. The ports of the other profiles
+    /// here are taken.
+    pub fn new_config(&self, name: &str) -> Result<ClientConfig> {
+        let taken: Vec<u16> = self.list()?.iter()
+            .filter(|p| p.name != name)
+            .filter_map(|p| p.config.as_ref().map(|cfg| cfg.listen_port))
+            .collect();
+        Ok(ClientConfig::for_profile(name, self.data_dir(name)?, &taken))
+    }
+}
+
+/// Profile names are letters, digits, `-` and `_`, so that they make safe
+/// directory names everywhere.
+pub fn validate_name(name: &str) -> Result<()> {
+    anyhow::ensure!(
+        !name.is_empty() && name.len() <= MAX_NAME && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+        "Invalid profile name {:?}: use up to {} letters, digits, '-' and '_'", name, MAX_NAME
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_profiles() -> Result<()> {
+        let root = TempDir::new()?;
+        let profiles = Profiles::new(root.path());
+        assert_eq!(profiles.current()?, DEFAULT_PROFILE);
+        assert_eq!(profiles.resolve("work")?, root.path().join("profiles/work"));
+        assert_eq!(profiles.resolve("./work")?, PathBuf::from("./work"));
+        assert!(profiles.resolve("../work").is_ok() && profiles.data_dir("..").is_err());
+        assert!(profiles.switch("work").is_err());
+
+        let home = profiles.new_config(DEFAULT_PROFILE)?;
+        assert_eq!((home.data_dir.as_path(), home.profile.as_deref()), (root.path(), None));
+        std::fs::create_dir_all(&home.data_dir)?;
+        home.save(&home.data_dir.join("config.json"))?;
+        let work = profiles.new_config("work")?;
+        assert_eq!((work.profile.as_deref(), work.listen_port), (Some("work"), home.listen_port + 1));
+        std::fs::create_dir_all(&work.data_dir)?;
+        work.save(&work.data_dir.join("config.json"))?;
+
+        profiles.switch("work")?;
+        assert_eq!(profiles.current()?, "work");
+        let listed: Vec<(String, bool)> = profiles.list()?.into_iter().map(|p| (p.name, p.current)).collect();
+        assert_eq!(listed, [(DEFAULT_PROFILE.to_string(), false), ("work".to_string(), true)]);
+        profiles.switch(DEFAULT_PROFILE)?;
+        assert_eq!(profiles.current()?, DEFAULT_PROFILE);
+        Ok(())
+    }
+}
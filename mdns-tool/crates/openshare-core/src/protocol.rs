@@ -0,0 +1,664 @@
+//! Messages exchanged over an established session.
+//!
+//! After the manifest, the sender streams [`ChunkFrame`]s tagged with their
+//! manifest index and the receiver answers each with a [`ChunkAck`] once the
+//! chunk is verified and stored. The sender keeps up to
+//! `max_parallel_chunks` chunks unacknowledged, so chunks are in flight on
+//! the wire while earlier ones are still being hashed and written; the
+//! receiver processes up to its own `max_parallel_chunks` concurrently and
+//! may acknowledge out of order.
+//!
+//! In pull mode the connecting side opens with a [`PullRequest`] instead of
+//! a manifest. The listener answers with a [`PullReply`] and, if serving,
+//! continues exactly like a push in the other direction: manifest, chunk
+//! frames, acknowledgments. A partial pull (peers with `partial-pull`)
+//! names the chunk indices it wants and gets the manifest plus only those;
+//! an empty list fetches just the manifest.
+//!
+//! A proof pull (peers with `chunk-proofs`) names chunk indices too, but
+//! the listener sends a signed [`ManifestRoot`](crate::merkle::ManifestRoot)
+//! in place of the manifest, then one [`ProvenChunk`] per index: the chunk
+//! with its id and the Merkle proof tying that id to the root. The puller
+//! checks each chunk against the root alone, so pulling a few chunks of a
+//! huge manifest does not send its whole listing again; an empty list
+//! fetches just the root.
+//!
+//! Peers with `compression` send [`PackedChunkFrame`]s instead, whose data
+//! is compressed with a codec the receiver announced support for.
+//!
+//! When both sides have `chunk-probe`, the receiver of a push answers the
+//! manifest with [`HaveChunks`] before any chunk is sent, and the sender
+//! skips the chunks listed there. Sending overlapping content to the same
+//! peer again thus only transfers what changed.
+//!
+//! A manifest larger than `manifest_page_size` goes to peers with
+//! `manifest-pages` (and `chunk-acks`) as a signed
+//! [`ManifestHeader`](crate::pages::ManifestHeader) followed by
+//! [`ManifestPage`](crate::pages::ManifestPage)s interleaved with the chunk
+//! frames. Each page is checked against the Merkle root in the header as it
+//! arrives, so chunks it lists can be stored (and, with `chunk-probe`,
+//! skipped) before the rest of the listing is in.
+//!
+//! An [`IndexRequest`] as the first frame asks for the listener's content
+//! index instead; the [`IndexReply`] ends the exchange.
+//!
+//! A [`ShareAnnouncement`] as the first frame (peers with `share-announce`)
+//! tells the listener that the connecting device published a share; the
+//! [`AnnounceReply`] ends the exchange.
+//!
+//! An [`AdminRequest`] as the first frame (peers with `admin`) asks the
+//! listener's daemon to run one management method, signed over the session
+//! transcript; the [`AdminReply`] ends the exchange. See [`crate::admin`].
+//!
+//! A [`TextMessage`] as the first frame (peers with `text`) carries a short
+//! text inline, up to [`MAX_TEXT_LEN`] bytes; the [`TextReply`] ends the
+//! exchange.
+//!
+//! With `transfer-consent`, the receiver of a push reviews the manifest
+//! (see [`crate::policy`]) and answers it with a [`Verdict`], ahead of
+//! any [`HaveChunks`]; chunks only follow an accepted one.
+//!
+//! A delta sync (peers with `delta-sync` and `chunk-probe`) opens the
+//! push with a [`SyncHeader`] saying how the sender cut its chunks. The
+//! receiver cuts the file or tree the push would replace the same way and
+//! stores the chunks the manifest lists, so its [`HaveChunks`] covers
+//! everything that did not change.
+//!
+//! A sender with keyed chunk ids (peers with `keyed-chunks`, see
+//! [`crate::keyed`]) opens the push with a [`ChunkKeyHeader`] naming its
+//! key, ahead of any [`SyncHeader`]. A receiver without that key refuses
+//! the transfer before the manifest.
+//!
+//! A [`StreamHeader`](crate::stream::StreamHeader) as the first frame
+//! (peers with `stream`) opens a push whose size is not known up front:
+//! chunks as the source is read, then the signed manifest. See
+//! [`crate::stream`].
+//!
+//! A [`BatchHeader`] as the first frame (peers with `batch`) announces
+//! several pushes over the one session. Each then runs in turn exactly like
+//! a push of its own, from any headers through the last acknowledgment. A
+//! push the receiver rejects is skipped and the batch carries on.
+//!
+//! A sender whose transfer is cancelled sends a [`Cancel`] in place of the
+//! next chunk frame (peers with `cancel`) and hangs up.
+//!
+//! A side whose transfer fails once the session is up sends an [`Abort`]
+//! in place of its next frame (peers with `abort-reason`), then waits for
+//! the other side to hang up. The other side fails with what it says,
+//! e.g. "disk full at chunk 512/2048", rather than a reset connection.
+
+use crate::Identity;
+use crate::chunking::Chunking;
+use crate::codec::Codec;
+use crate::index::IndexEntry;
+use anyhow::{Context, Result};
+use ed25519_dalek::Signature;
+use serde::{Deserialize, Serialize};
+
+/// Prefix marking a pull request frame. A bincode manifest starts with the
+/// filename length as a u64, which can never spell this.
+pub const PULL_MAGIC: &[u8; 8] = b"OSPULL01";
+
+/// Prefix of a pull request that selects chunks. Peers without
+/// `partial-pull` only ever see [`PULL_MAGIC`].
+pub const PARTIAL_PULL_MAGIC: &[u8; 8] = b"OSPULL02";
+
+/// Prefix of a partial pull asking for chunk proofs (peers with
+/// `chunk-proofs`): the reply carries a
+/// [`ManifestRoot`](crate::merkle::ManifestRoot) instead of the manifest.
+pub const PROOF_PULL_MAGIC: &[u8; 8] = b"OSPULL03";
+
+/// Domain separator for pull request signatures.
+const PULL_CONTEXT: &[u8] = b"openshare pull v1";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChunkFrame {
+    /// Position of the chunk in `Manifest::chunk_hashes`
+    pub index: u32,
+    pub data: Vec<u8>,
+}
+
+/// A chunk sent in answer to a proof pull, with what it takes to check it
+/// against the manifest root alone.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProvenChunk {
+    pub index: u32,
+    /// Id of the chunk, the leaf being proven
+    pub hash: String,
+    /// Sibling hashes from the leaf up to the root
+    pub proof: Vec<[u8; 32]>,
+    pub data: Vec<u8>,
+}
+
+/// [`ChunkFrame`] for peers with `compression`: the data may be encoded.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PackedChunkFrame {
+    pub index: u32,
+    pub codec: Codec,
+    /// Length of the chunk once decoded
+    pub raw_len: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkAck {
+    pub index: u32,
+    /// False if the chunk failed verification and was dropped
+    pub stored: bool,
+}
+
+/// Chunks of a pushed manifest the receiver already stores.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct HaveChunks {
+    /// Indices into `Manifest::chunk_hashes`
+    pub indices: Vec<u32>,
+}
+
+/// The receiver's answer to a pushed manifest.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    /// The reason is for display
+    Reject(String),
+}
+
+/// Prefix marking a cancel frame. A chunk frame starting with it would name
+/// a chunk index beyond any manifest.
+pub const CANCEL_MAGIC: &[u8; 8] = b"OSCANCL1";
+
+/// The sender stops the transfer; chunks already acknowledged stay valid.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Cancel {
+    /// For display
+    pub reason: String,
+}
+
+impl Cancel {
+    pub fn to_frame(&self) -> Result<Vec<u8>> {
+        Ok([&CANCEL_MAGIC[..], &bincode::serialize(self)?].concat())
+    }
+
+    /// Decode `frame` if it is a cancel frame; `None` if it is not one.
+    pub fn from_frame(frame: &[u8]) -> Option<Result<Self>> {
+        let body = frame.strip_prefix(CANCEL_MAGIC)?;
+        Some(bincode::deserialize(body).context("Malformed cancel frame"))
+    }
+}
+
+/// Prefix marking an abort frame. Like [`CANCEL_MAGIC`], no chunk frame,
+/// acknowledgment or [`HaveChunks`] can start with it.
+pub const ABORT_MAGIC: &[u8; 8] = b"OSABORT1";
+
+/// Why a transfer failed, for the peer. Only a code and counters go out:
+/// error messages may name local paths.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Abort {
+    pub code: AbortCode,
+    pub stage: Stage,
+    /// Chunks sent or received when it failed, this one included
+    pub chunk: u32,
+    pub chunks_total: u32,
+}
+
+impl Abort {
+    pub fn to_frame(&self) -> Result<Vec<u8>> {
+        Ok([&ABORT_MAGIC[..], &bincode::serialize(self)?].concat())
+    }
+
+    /// Decode `frame` if it is an abort frame; `None` if it is not one.
+    pub fn from_frame(frame: &[u8]) -> Option<Result<Self>> {
+        let body = frame.strip_prefix(ABORT_MAGIC)?;
+        Some(bincode::deserialize(body).context("Malformed abort frame"))
+    }
+}
+
+impl std::fmt::Display for Abort {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.code)?;
+        match self.stage {
+            Stage::Manifest => write!(f, " on the manifest"),
+            Stage::Chunks => write!(f, " at chunk {}/{}", self.chunk, self.chunks_total),
+            Stage::Finishing => write!(f, " after the last chunk"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AbortCode {
+    DiskFull,
+    PermissionDenied,
+    /// Any other I/O error
+    Io,
+    Other,
+}
+
+impl AbortCode {
+    /// Classify `error` by the I/O error behind it, if there is one.
+    pub fn of(error: &anyhow::Error) -> Self {
+        use crate::error::OpenShareError;
+        use std::io::ErrorKind;
+
+        // Sorted already, by a public call it came out of
+        match error.chain().find_map(|e| e.downcast_ref::<OpenShareError>()) {
+            Some(OpenShareError::DiskFull(_)) => return AbortCode::DiskFull,
+            Some(OpenShareError::PermissionDenied(_)) => return AbortCode::PermissionDenied,
+            Some(OpenShareError::Io(_)) => return AbortCode::Io,
+            _ => {}
+        }
+        let Some(io) = error.chain().find_map(|e| e.downcast_ref::<std::io::Error>()) else {
+            return AbortCode::Other;
+        };
+        match io.kind() {
+            ErrorKind::StorageFull | ErrorKind::QuotaExceeded => AbortCode::DiskFull,
+            ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem => AbortCode::PermissionDenied,
+            _ => AbortCode::Io,
+        }
+    }
+}
+
+impl std::fmt::Display for AbortCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            AbortCode::DiskFull => "disk full",
+            AbortCode::PermissionDenied => "permission denied",
+            AbortCode::Io => "I/O error",
+            AbortCode::Other => "unexpected error",
+        })
+    }
+}
+
+/// How far a transfer got.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Before the first chunk
+    Manifest,
+    Chunks,
+    /// All chunks are in; the manifest is being stored
+    Finishing,
+}
+
+/// Request for a published manifest, signed over the session transcript
+/// so it cannot be replayed on another connection.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PullRequest {
+    /// `Manifest::id` of the requested file
+    pub manifest_id: String,
+    /// Indices into `Manifest::chunk_hashes` to send; `None` for all
+    pub chunks: Option<Vec<u32>>,
+    /// Send the manifest root and [`ProvenChunk`]s instead of the
+    /// manifest and chunk frames; only with `chunks`
+    #[serde(skip)]
+    pub proofs: bool,
+    pub signature: Vec<u8>,
+}
+
+/// Encoding of a [`PullRequest`] behind [`PULL_MAGIC`].
+#[derive(Serialize, Deserialize)]
+struct FullPullRequest {
+    manifest_id: String,
+    signature: Vec<u8>,
+}
+
+impl PullRequest {
+    pub fn new(identity: &Identity, transcript_hash: &[u8; 32], manifest_id: &str, chunks: Option<Vec<u32>>) -> Self {
+        let sig = identity.sign(&Self::signed_bytes(transcript_hash, manifest_id, chunks.as_deref(), false));
+        Self { manifest_id: manifest_id.to_string(), chunks, proofs: false, signature: sig.to_bytes().to_vec() }
+    }
+
+    /// Request for the chunks at `chunks` with their proofs against the
+    /// manifest root.
+    pub fn proven(identity: &Identity, transcript_hash: &[u8; 32], manifest_id: &str, chunks: Vec<u32>) -> Self {
+        let sig = identity.sign(&Self::signed_bytes(transcript_hash, manifest_id, Some(&chunks), true));
+        Self { manifest_id: manifest_id.to_string(), chunks: Some(chunks), proofs: true, signature: sig.to_bytes().to_vec() }
+    }
+
+    pub fn verify(&self, public_key: &[u8; 32], transcript_hash: &[u8; 32]) -> Result<()> {
+        let sig: [u8; 64] = self.signature.as_slice().try_into()
+            .map_err(|_| anyhow::anyhow!("Invalid pull request signature length"))?;
+        Identity::verify_with_pubkey(
+            public_key,
+            &Self::signed_bytes(transcript_hash, &self.manifest_id, self.chunks.as_deref(), self.proofs),
+            &Signature::from_bytes(&sig),
+        ).context("Pull request signature invalid")
+    }
+
+    fn signed_bytes(transcript_hash: &[u8; 32], manifest_id: &str, chunks: Option<&[u32]>, proofs: bool) -> Vec<u8> {
+        let mut bytes = [PULL_CONTEXT, transcript_hash, manifest_id.as_bytes()].concat();
+        if let Some(chunks) = chunks {
+            bytes.extend_from_slice(&(chunks.len() as u32).to_be_bytes());
+            for index in chunks {
+                bytes.extend_from_slice(&index.to_be_bytes());
+            }
+        }
+        if proofs {
+            bytes.extend_from_slice(PROOF_PULL_MAGIC);
+        }
+        bytes
+    }
+
+    pub fn to_frame(&self) -> Result<Vec<u8>> {
+        if self.proofs {
+            return Ok([&PROOF_PULL_MAGIC[..], &bincode::serialize(self)?].concat());
+        }
+        if self.chunks.is_some() {
+            return Ok([&PARTIAL_PULL_MAGIC[..], &bincode::serialize(self)?].concat());
+        }
+        let full = FullPullRequest { manifest_id: self.manifest_id.clone(), signature: self.signature.clone() };
+        Ok([&PULL_MAGIC[..], &bincode::serialize(&full)?].concat())
+    }
+
+    /// Decode `frame` if it is a pull request; `None` if it is not one.
+    pub fn from_frame(frame: &[u8]) -> Option<Result<Self>> {
+        if let Some(body) = frame.strip_prefix(PROOF_PULL_MAGIC) {
+            let request = bincode::deserialize(body).map(|request| Self { proofs: true, ..request });
+            return Some(request.context("Malformed pull request"));
+        }
+        if let Some(body) = frame.strip_prefix(PARTIAL_PULL_MAGIC) {
+            return Some(bincode::deserialize(body).context("Malformed pull request"));
+        }
+        let body = frame.strip_prefix(PULL_MAGIC)?;
+        Some(bincode::deserialize(body)
+            .map(|full: FullPullRequest| Self { manifest_id: full.manifest_id, chunks: None, proofs: false, signature: full.signature })
+            .context("Malformed pull request"))
+    }
+}
+
+/// Prefix marking a sync header frame.
+pub const SYNC_MAGIC: &[u8; 8] = b"OSSYNC01";
+
+/// Opens a push whose manifest follows in the next frame, asking the
+/// receiver to reuse what it holds at the target path.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SyncHeader {
+    pub chunking: Chunking,
+}
+
+impl SyncHeader {
+    pub fn to_frame(&self) -> Result<Vec<u8>> {
+        Ok([&SYNC_MAGIC[..], &bincode::serialize(self)?].concat())
+    }
+
+    /// Decode `frame` if it is a sync header; `None` if it is not one.
+    pub fn from_frame(frame: &[u8]) -> Option<Result<Self>> {
+        let body = frame.strip_prefix(SYNC_MAGIC)?;
+        Some(bincode::deserialize(body).context("Malformed sync header"))
+    }
+}
+
+/// Prefix marking a chunk key header frame.
+pub const CHUNK_KEY_MAGIC: &[u8; 8] = b"OSCKEY01";
+
+/// Opens a push whose chunk ids are keyed with the account secret that
+/// [`ChunkKey::key_id`](crate::keyed::ChunkKey::key_id) names.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChunkKeyHeader {
+    pub key_id: String,
+}
+
+impl ChunkKeyHeader {
+    pub fn to_frame(&self) -> Result<Vec<u8>> {
+        Ok([&CHUNK_KEY_MAGIC[..], &bincode::serialize(self)?].concat())
+    }
+
+    /// Decode `frame` if it is a chunk key header; `None` if it is not one.
+    pub fn from_frame(frame: &[u8]) -> Option<Result<Self>> {
+        let body = frame.strip_prefix(CHUNK_KEY_MAGIC)?;
+        Some(bincode::deserialize(body).context("Malformed chunk key header"))
+    }
+}
+
+/// Prefix marking an admin request frame.
+pub const ADMIN_MAGIC: &[u8; 8] = b"OSADMIN1";
+
+/// Domain separator for admin request signatures.
+const ADMIN_CONTEXT: &[u8] = b"openshare admin v1";
+
+/// A management method for the listener's daemon, signed over the session
+/// transcript like a [`ShareAnnouncement`]. `params` is JSON, as in the
+/// local control API.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AdminRequest {
+    pub method: String,
+    pub params: String,
+    pub signature: Vec<u8>,
+}
+
+impl AdminRequest {
+    pub fn new(identity: &Identity, transcript_hash: &[u8; 32], method: &str, params: String) -> Result<Self> {
+        let signed = Self::signed_bytes(transcript_hash, method, &params)?;
+        let signature = identity.sign(&signed).to_bytes().to_vec();
+        Ok(Self { method: method.to_string(), params, signature })
+    }
+
+    pub fn verify(&self, public_key: &[u8; 32], transcript_hash: &[u8; 32]) -> Result<()> {
+        let sig: [u8; 64] = self.signature.as_slice().try_into()
+            .map_err(|_| anyhow::anyhow!("Invalid admin request signature length"))?;
+        Identity::verify_with_pubkey(
+            public_key,
+            &Self::signed_bytes(transcript_hash, &self.method, &self.params)?,
+            &Signature::from_bytes(&sig),
+        ).context("Admin request signature invalid")
+    }
+
+    fn signed_bytes(transcript_hash: &[u8; 32], method: &str, params: &str) -> Result<Vec<u8>> {
+        let fields = bincode::serialize(&(method, params))?;
+        Ok([ADMIN_CONTEXT, transcript_hash, &fields].concat())
+    }
+
+    pub fn to_frame(&self) -> Result<Vec<u8>> {
+        Ok([&ADMIN_MAGIC[..], &bincode::serialize(self)?].concat())
+    }
+
+    /// Decode `frame` if it is an admin request; `None` if it is not one.
+    pub fn from_frame(frame: &[u8]) -> Option<Result<Self>> {
+        let body = frame.strip_prefix(ADMIN_MAGIC)?;
+        Some(bincode::deserialize(body).context("Malformed admin request"))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum AdminReply {
+    /// The method ran; its result as JSON
+    Done(String),
+    /// The request was refused or the method failed; the reason is for
+    /// display
+    Refused(String),
+}
+
+/// Prefix marking a batch header frame.
+pub const BATCH_MAGIC: &[u8; 8] = b"OSBATCH1";
+
+/// Opens a session carrying `count` pushes, one after another.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BatchHeader {
+    pub count: u32,
+}
+
+impl BatchHeader {
+    pub fn to_frame(&self) -> Result<Vec<u8>> {
+        Ok([&BATCH_MAGIC[..], &bincode::serialize(self)?].concat())
+    }
+
+    /// Decode `frame` if it is a batch header; `None` if it is not one.
+    pub fn from_frame(frame: &[u8]) -> Option<Result<Self>> {
+        let body = frame.strip_prefix(BATCH_MAGIC)?;
+        Some(bincode::deserialize(body).context("Malformed batch header"))
+    }
+}
+
+/// Prefix marking a content index request frame.
+pub const INDEX_MAGIC: &[u8; 8] = b"OSINDEX1";
+
+/// Request for the peer's list of published shares. Only answered for a
+/// device on the same account.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IndexRequest {
+    pub account_hash: String,
+}
+
+impl IndexRequest {
+    pub fn to_frame(&self) -> Result<Vec<u8>> {
+        Ok([&INDEX_MAGIC[..], &bincode::serialize(self)?].concat())
+    }
+
+    /// Decode `frame` if it is an index request; `None` if it is not one.
+    pub fn from_frame(frame: &[u8]) -> Option<Result<Self>> {
+        let body = frame.strip_prefix(INDEX_MAGIC)?;
+        Some(bincode::deserialize(body).context("Malformed index request"))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum IndexReply {
+    Entries(Vec<IndexEntry>),
+    /// The request was refused; the reason is for display
+    Unavailable(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum PullReply {
+    /// The manifest and its chunks follow
+    Serving,
+    /// The request was refused; the reason is for display
+    Unavailable(String),
+}
+
+/// Prefix marking a share announcement frame.
+pub const ANNOUNCE_MAGIC: &[u8; 8] = b"OSANNC01";
+
+/// Domain separator for share announcement signatures.
+const ANNOUNCE_CONTEXT: &[u8] = b"openshare announce v1";
+
+/// Notice that the connecting device published a share, signed over the
+/// session transcript like a [`PullRequest`]. Only accepted from a device
+/// on the same account.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ShareAnnouncement {
+    pub account_hash: String,
+    pub entry: IndexEntry,
+    /// Port the publisher serves pulls on
+    pub port: u16,
+    pub signature: Vec<u8>,
+}
+
+impl ShareAnnouncement {
+    pub fn new(identity: &Identity, transcript_hash: &[u8; 32], account_hash: &str, entry: IndexEntry, port: u16) -> Result<Self> {
+        let signed = Self::signed_bytes(transcript_hash, account_hash, &entry, port)?;
+        let signature = identity.sign(&signed).to_bytes().to_vec();
+        Ok(Self { account_hash: account_hash.to_string(), entry, port, signature })
+    }
+
+    pub fn verify(&self, public_key: &[u8; 32], transcript_hash: &[u8; 32]) -> Result<()> {
+        let sig: [u8; 64] = self.signature.as_slice().try_into()
+            .map_err(|_| anyhow::anyhow!("Invalid announcement signature length"))?;
+        Identity::verify_with_pubkey(
+            public_key,
+            &Self::signed_bytes(transcript_hash, &self.account_hash, &self.entry, self.port)?,
+            &Signature::from_bytes(&sig),
+        ).context("Announcement signature invalid")
+    }
+
+    fn signed_bytes(transcript_hash: &[u8; 32], account_hash: &str, entry: &IndexEntry, port: u16) -> Result<Vec<u8>> {
+        let fields = bincode::serialize(&(account_hash, entry, port))?;
+        Ok([ANNOUNCE_CONTEXT, transcript_hash, &fields].concat())
+    }
+
+    pub fn to_frame(&self) -> Result<Vec<u8>> {
+        Ok([&ANNOUNCE_MAGIC[..], &bincode::serialize(self)?].concat())
+    }
+
+    /// Decode `frame` if it is a share announcement; `None` if it is not one.
+    pub fn from_frame(frame: &[u8]) -> Option<Result<Self>> {
+        let body = frame.strip_prefix(ANNOUNCE_MAGIC)?;
+        Some(bincode::deserialize(body).context("Malformed share announcement"))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum AnnounceReply {
+    Noted,
+    /// The announcement was refused; the reason is for display
+    Unavailable(String),
+}
+
+/// Prefix marking a text message frame.
+pub const TEXT_MAGIC: &[u8; 8] = b"OSTEXT01";
+
+/// Longest text message, in bytes of UTF-8. Anything bigger is a file.
+pub const MAX_TEXT_LEN: usize = 64 * 1024;
+
+/// A short text, such as a link or a snippet, sent inline instead of as
+/// a file. Nothing is stored; the receiver shows it or copies it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TextMessage {
+    pub text: String,
+}
+
+impl TextMessage {
+    pub fn to_frame(&self) -> Result<Vec<u8>> {
+        Ok([&TEXT_MAGIC[..], &bincode::serialize(self)?].concat())
+    }
+
+    /// Decode `frame` if it is a text message; `None` if it is not one.
+    pub fn from_frame(frame: &[u8]) -> Option<Result<Self>> {
+        let body = frame.strip_prefix(TEXT_MAGIC)?;
+        Some(bincode::deserialize(body).context("Malformed text message"))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum TextReply {
+    Received,
+    /// The message was refused; the reason is for display
+    Refused(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::fixture::{client, Faulty};
+    use crate::sim::{pair, LinkConfig};
+    use crate::{Client, OpenShareError};
+    use tempfile::TempDir;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failed_side_tells_peer_why() {
+        use crate::history::History;
+        use crate::transfer::TransferHandle;
+
+        let (src, dst) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let input = src.path().join("input.bin");
+        std::fs::write(&input, vec![3u8; 150_000]).unwrap();
+
+        // The receiver's disk fills up after the first chunk.
+        let sender = client(&src, 1);
+        let full = client(&dst, 2);
+        let receiver = Client::new((*full.identity).clone(), Faulty::new(1), full.cfg.clone());
+        let manifest = sender.import_file(&input).await.unwrap();
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (sent, received) = tokio::join!(
+            sender.send_manifest_over(a, manifest),
+            receiver.accept_and_receive(b),
+        );
+        assert!(received.is_err());
+        let Err(OpenShareError::PeerAborted(aborted)) = sent else { panic!("not aborted") };
+        assert_eq!(aborted.device_id, "dev2");
+        assert_eq!((aborted.abort.code, aborted.abort.stage, aborted.abort.chunks_total), (AbortCode::DiskFull, Stage::Chunks, 3));
+        let error = History::new(src.path()).load().unwrap().pop().unwrap().error.unwrap();
+        assert!(error.contains("dev2 aborted the transfer: disk full at chunk"), "{}", error);
+
+        // The sender loses its source file before the first chunk.
+        let manifest = sender.build_manifest(&input, crate::chunking::Chunking::Fixed(sender.cfg.chunk_size)).await.unwrap();
+        std::fs::remove_file(&input).unwrap();
+        let (receiver, handle) = (client(&dst, 2), TransferHandle::new());
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (sent, received) = tokio::join!(
+            sender.send_built(a, &manifest, &input, &handle),
+            receiver.accept_and_receive(b),
+        );
+        assert!(sent.is_err());
+        let Err(OpenShareError::PeerAborted(aborted)) = received else { panic!("not aborted") };
+        assert_eq!(aborted.abort, Abort { code: AbortCode::Io, stage: Stage::Chunks, chunk: 1, chunks_total: 3 });
+        assert_eq!(aborted.to_string(), "dev1 aborted the transfer: I/O error at chunk 1/3");
+    }
+}
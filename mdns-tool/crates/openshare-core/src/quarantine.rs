@@ -0,0 +1,149 @@
+//! Quarantine of chunks that keep failing verification from a peer.
+//!
+//! Every chunk that arrives corrupt is recorded against the peer that sent
+//! it in the [audit log](crate::audit). Once the same peer has sent a bad
+//! copy of the same chunk [`QUARANTINE_AFTER`] times within
+//! [`QUARANTINE_FOR`], the pair is quarantined: pulls from that peer leave
+//! the chunk out until the failures age out. Other chunks from the peer,
+//! and the chunk from other peers, are unaffected.
+//!
+//! The state is rebuilt from the audit log on load, so a quarantine
+//! outlives the process that imposed it.
+
+use crate::audit::{AuditEvent, AuditLog};
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Failures of one (peer, chunk) pair that put it in quarantine.
+pub const QUARANTINE_AFTER: usize = 3;
+
+/// How long failures count, and so how long a quarantine lasts after the
+/// last of them.
+pub const QUARANTINE_FOR: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub struct Quarantine {
+    log: AuditLog,
+    /// Failure times (Unix seconds) by peer device ID and chunk hash
+    failures: HashMap<(String, String), Vec<u64>>,
+}
+
+impl Quarantine {
+    /// Load past failures from the audit log in `data_dir`.
+    pub fn load(data_dir: &Path) -> Self {
+        let log = AuditLog::new(data_dir);
+        let events = log.load().unwrap_or_else(|e| {
+            tracing::warn!("Failed to load audit log: {:#}", e);
+            Vec::new()
+        });
+        let mut failures: HashMap<_, Vec<u64>> = HashMap::new();
+        for event in events {
+            if let AuditEvent::VerificationFailed { peer, chunk, at, .. } = event {
+                failures.entry((peer, chunk)).or_default().push(at);
+            }
+        }
+        Self { log, failures }
+    }
+
+    pub fn is_quarantined(&self, peer: &str, chunk: &str, now: SystemTime) -> bool {
+        self.recent(peer, chunk, unix_secs(now)) >= QUARANTINE_AFTER
+    }
+
+    /// Record that `chunk` from `peer` failed verification. Returns whether
+    /// this failure put the pair in quarantine.
+    pub fn record_failure(&mut self, peer: &str, chunk: &str, reason: &str, now: SystemTime) -> bool {
+        let at = unix_secs(now);
+        self.failures.entry((peer.to_string(), chunk.to_string())).or_default().push(at);
+        self.audit(AuditEvent::VerificationFailed {
+            peer: peer.to_string(),
+            chunk: chunk.to_string(),
+            reason: reason.to_string(),
+            at,
+        });
+
+        let quarantined = self.recent(peer, chunk, at) == QUARANTINE_AFTER;
+        if quarantined {
+            tracing::warn!("Quarantined chunk {} from {} after {} bad copies", chunk, peer, QUARANTINE_AFTER);
+            self.audit(AuditEvent::Quarantined {
+                peer: peer.to_string(),
+                chunk: chunk.to_string(),
+                until: at + QUARANTINE_FOR.as_secs(),
+                at,
+            });
+        }
+        quarantined
+    }
+
+    fn recent(&self, peer: &str, chunk: &str, now: u64) -> usize {
+        let since = now.saturating_sub(QUARANTINE_FOR.as_secs());
+        self.failures.get(&(peer.to_string(), chunk.to_string()))
+            .map_or(0, |times| times.iter().filter(|&&t| t > since).count())
+    }
+
+    fn audit(&self, event: AuditEvent) {
+        // The in-memory state still applies if the log cannot be written.
+        if let Err(e) = self.log.append(&event) {
+            tracing::warn!("Failed to write audit log: {:#}", e);
+        }
+    }
+}
+
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sim::fixture::client;
+    use crate::sim::{pair, LinkConfig};
+    use tempfile::TempDir;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_corrupt_chunks_are_retried_then_quarantined() {
+        use crate::audit::{AuditEvent, AuditLog};
+        use crate::client::PeerChunkFetcher;
+        use crate::vfs::ChunkFetcher;
+        use std::sync::Arc;
+        use storage::Storage;
+
+        let (src, dst) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let input = src.path().join("input.bin");
+        let payload: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&input, &payload).unwrap();
+
+        let server = Arc::new(client(&src, 1));
+        let fetcher = Arc::new(client(&dst, 2));
+        let manifest = server.import_file(&input).await.unwrap();
+        server.publish(&manifest).unwrap();
+        let bad = manifest.chunk_hashes[1].clone();
+        server.storage.put_chunk_as(&bad, b"not the chunk").await.unwrap();
+
+        let connect = {
+            let server = server.clone();
+            move || {
+                let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+                let server = server.clone();
+                tokio::spawn(async move { server.accept(b).await });
+                async move { Ok(a) }
+            }
+        };
+        let peer = PeerChunkFetcher::new(fetcher.clone(), connect);
+
+        // The bad chunk is asked for once per round and then quarantined;
+        // the good ones arrive the first time.
+        let missing = manifest.chunk_hashes[..3].to_vec();
+        let err = peer.fetch(&manifest, &missing).await.unwrap_err();
+        assert!(err.to_string().contains("failed verification"), "{:#}", err);
+        assert_eq!(fetcher.missing_chunks(&manifest).await.unwrap(), [1, 3]);
+        let events = AuditLog::new(dst.path()).load().unwrap();
+        assert_eq!(events.len(), 4);
+        assert!(matches!(&events[3], AuditEvent::Quarantined { peer, chunk, .. } if peer == "dev1" && *chunk == bad));
+
+        // The quarantine outlives the client: nothing is requested again.
+        let fresh = Arc::new(client(&dst, 2));
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (fetched, _) = tokio::join!(fresh.request_unquarantined(a, &manifest, vec![1]), server.accept(b));
+        assert!(format!("{:#}", fetched.unwrap_err()).contains("quarantined"));
+        assert_eq!(AuditLog::new(dst.path()).load().unwrap().len(), 4);
+    }
+}
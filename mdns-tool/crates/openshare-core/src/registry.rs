@@ -0,0 +1,194 @@
+//! The devices of this account currently seen on the network.
+//!
+//! A [`PeerRegistry`] is fed the events of a browse or watch and keeps
+//! one [`Peer`] per device ID. Services of other accounts, and services
+//! without an address the overlay policy allows, are ignored. A device
+//! advertised under several service types shows once, with the addresses
+//! of all of them, and is only gone once every one of its services is.
+//!
+//! Each service remembers when it was last resolved; [`PeerRegistry::expire`]
+//! drops the ones not seen for a while, e.g. devices that went away without
+//! withdrawing their records.
+
+use crate::config::ClientConfig;
+use crate::discovery::Peer;
+use mdns_core::model::{BrowseEvent, DiscoveredService, OverlayPolicy};
+use mdns_core::net::rank_addresses;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A peer appearing, changing or going away.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum PeerEvent {
+    /// Seen for the first time, or its addresses or port changed
+    Found(Peer),
+    /// None of its services are advertised any more
+    Lost { device_id: String },
+}
+
+/// Peers of one account by device ID; see the [module docs](self). Clones
+/// share the same registry.
+#[derive(Clone)]
+pub struct PeerRegistry {
+    account_hash: String,
+    policy: OverlayPolicy,
+    /// Admitted peers by service full name
+    services: Arc<Mutex<BTreeMap<String, Peer>>>,
+}
+
+impl PeerRegistry {
+    /// An empty registry for the account of `cfg`. With no account hash
+    /// configured, every OpenShare device is admitted.
+    pub fn new(cfg: &ClientConfig) -> Self {
+        Self {
+            account_hash: cfg.account_hash.clone(),
+            policy: cfg.overlay_policy,
+            services: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    /// Take in one browse event, returning how it changed the peers, if
+    /// it did.
+    pub fn apply(&self, event: BrowseEvent) -> Option<PeerEvent> {
+        let mut services = self.services.lock().unwrap();
+        let (fullname, peer) = match event {
+            BrowseEvent::Resolved(svc) => (svc.fullname.clone(), Some(self.admit(svc)?)),
+            BrowseEvent::Removed { fullname } => (fullname, None),
+        };
+        let device_id = match &peer {
+            Some(peer) => peer.device_id(),
+            None => services.get(&fullname)?.device_id(),
+        }.to_string();
+
+        let before = merged(&services, &device_id);
+        match peer {
+            Some(peer) => services.insert(fullname, peer),
+            None => services.remove(&fullname),
+        };
+        match merged(&services, &device_id) {
+            None => Some(PeerEvent::Lost { device_id }),
+            Some(after) if before.is_some_and(|b| b.port == after.port && b.addresses == after.addresses) => None,
+            Some(after) => Some(PeerEvent::Found(after)),
+        }
+    }
+
+    /// All peers, ordered by device ID.
+    pub fn peers(&self) -> Vec<Peer> {
+        let services = self.services.lock().unwrap();
+        let mut device_ids: Vec<&str> = services.values().map(|p| p.device_id()).collect();
+        device_ids.sort();
+        device_ids.dedup();
+        device_ids.into_iter().filter_map(|id| merged(&services, id)).collect()
+    }
+
+    pub fn find_by_device_id(&self, device_id: &str) -> Option<Peer> {
+        merged(&self.services.lock().unwrap(), device_id)
+    }
+
+    /// Drop services not resolved within `max_age`, returning the peers
+    /// that went away with them.
+    pub fn expire(&self, max_age: Duration) -> Vec<PeerEvent> {
+        let mut services = self.services.lock().unwrap();
+        let mut lost: Vec<String> = services.values().map(|p| p.device_id().to_string()).collect();
+        services.retain(|_, p| p.last_seen.elapsed() < max_age);
+        lost.retain(|id| !services.values().any(|p| p.device_id() == id));
+        lost.sort();
+        lost.dedup();
+        lost.into_iter().map(|device_id| PeerEvent::Lost { device_id }).collect()
+    }
+
+    /// Forget every peer, e.g. after the network changed.
+    pub fn clear(&self) {
+        self.services.lock().unwrap().clear();
+    }
+
+    /// The peer behind `svc`, if it belongs to this account and has an
+    /// address the overlay policy allows.
+    fn admit(&self, svc: DiscoveredService) -> Option<Peer> {
+        let mut peer = Peer::from_service(svc)?;
+        if !self.account_hash.is_empty() && peer.advertisement.account_hash != self.account_hash {
+            return None;
+        }
+        peer.addresses = rank_addresses(&peer.addresses, self.policy);
+        (!peer.addresses.is_empty()).then_some(peer)
+    }
+}
+
+/// One peer from all services of `device_id`: the first by full name, with
+/// the addresses of the others added and the latest sighting.
+fn merged(services: &BTreeMap<String, Peer>, device_id: &str) -> Option<Peer> {
+    let mut found = services.values().filter(|p| p.device_id() == device_id);
+    let mut peer = found.next()?.clone();
+    for other in found {
+        for addr in &other.addresses {
+            if !peer.addresses.contains(addr) {
+                peer.addresses.push(*addr);
+            }
+        }
+        peer.last_seen = peer.last_seen.max(other.last_seen);
+    }
+    Some(peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discovery::Advertisement;
+
+    fn service(fullname: &str, account: &str, addr: &str) -> DiscoveredService {
+        DiscoveredService {
+            fullname: fullname.into(),
+            instance_name: "laptop.local.".into(),
+            service_type: "_openshare._tcp.local.".into(),
+            host_name: "laptop.local.".into(),
+            port: 9876,
+            addresses: vec![addr.parse().unwrap()],
+            txt: Advertisement {
+                account_hash: account.into(),
+                device_id: "laptop".into(),
+                fingerprint: "01020304".into(),
+            }.to_txt(),
+        }
+    }
+
+    #[test]
+    fn test_registry_merges_and_loses_peers() {
+        let registry = PeerRegistry::new(&ClientConfig::default().with_account("abcd".into(), "me".into()));
+        let resolve = |fullname, account, addr| registry.apply(BrowseEvent::Resolved(service(fullname, account, addr)));
+
+        let found = resolve("laptop._openshare", "abcd", "192.168.1.5");
+        assert!(matches!(found, Some(PeerEvent::Found(ref p)) if p.device_id() == "laptop"));
+        assert!(resolve("laptop._openshare", "abcd", "192.168.1.5").is_none());
+        assert!(resolve("laptop._other", "ffff", "192.168.1.7").is_none());
+
+        // A second service type adds an address to the same device.
+        let Some(PeerEvent::Found(peer)) = resolve("laptop._extra", "abcd", "192.168.1.6") else {
+            panic!("expected an update")
+        };
+        assert_eq!(peer.addresses.len(), 2);
+        assert_eq!(registry.peers().len(), 1);
+        assert_eq!(registry.find_by_device_id("laptop").unwrap().addresses.len(), 2);
+
+        // It is lost only once every service is gone.
+        let removed = registry.apply(BrowseEvent::Removed { fullname: "laptop._openshare".into() });
+        assert!(matches!(removed, Some(PeerEvent::Found(ref p)) if p.addresses.len() == 1));
+        let lost = registry.apply(BrowseEvent::Removed { fullname: "laptop._extra".into() });
+        assert!(matches!(lost, Some(PeerEvent::Lost { ref device_id }) if device_id == "laptop"));
+        assert!(registry.find_by_device_id("laptop").is_none());
+    }
+
+    #[test]
+    fn test_registry_expires_silent_peers() {
+        let registry = PeerRegistry::new(&ClientConfig::default());
+        registry.apply(BrowseEvent::Resolved(service("laptop._openshare", "abcd", "192.168.1.5")));
+        registry.apply(BrowseEvent::Resolved(service("laptop._extra", "ffff", "192.168.1.6")));
+
+        assert!(registry.expire(Duration::from_secs(60)).is_empty());
+        let lost = registry.expire(Duration::ZERO);
+        assert!(matches!(&lost[..], [PeerEvent::Lost { device_id }] if device_id == "laptop"));
+        assert!(registry.peers().is_empty());
+    }
+}
@@ -0,0 +1,240 @@
+//! Sending one share to several of the account's devices.
+//!
+//! A [`Replicator`] delivers a file or directory to a quorum of targets,
+//! e.g. the laptop and the NAS, in one go. Targets are tried in the order
+//! given: it starts as many sends at once as copies are still needed, and
+//! when one fails the next untried target takes its place. The manifest is
+//! built once and chunks are streamed from the files to every target, as
+//! with [`Client::send_file_streaming`].
+//!
+//! Replication is complete as soon as the quorum holds the share; targets
+//! not needed by then are left alone. Each target's [`Replica`] is passed
+//! to the observer whenever its state changes, and the returned
+//! [`Replication`] has the final state of all of them.
+
+use crate::client::Client;
+use crate::manifest::Manifest;
+use crate::transfer::{TransferHandle, TransferOutcome};
+use anyhow::Result;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use storage::Storage;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::task::JoinSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplicaState {
+    /// Not tried yet
+    Waiting,
+    Sending,
+    Done,
+    Failed,
+    Cancelled,
+    /// Not tried, as the quorum was reached without it
+    NotNeeded,
+}
+
+/// One target of a [`Replicator`] and how its send went.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Replica {
+    /// Name of the target, as given (e.g. its device ID)
+    pub target: String,
+    pub state: ReplicaState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The outcome of [`Replicator::replicate`].
+#[derive(Debug, Clone)]
+pub struct Replication {
+    pub manifest: Manifest,
+    /// Copies required
+    pub quorum: usize,
+    /// Every target, in the order given
+    pub replicas: Vec<Replica>,
+}
+
+impl Replication {
+    /// Targets that received the whole share.
+    pub fn copies(&self) -> usize {
+        self.replicas.iter().filter(|r| r.state == ReplicaState::Done).count()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.copies() >= self.quorum
+    }
+}
+
+type Observer = Arc<dyn Fn(&Replica) + Send + Sync>;
+
+/// Sends to a quorum of targets; see the [module docs](self). Each target
+/// is a name and a way to connect to it.
+pub struct Replicator<S, C> {
+    client: Arc<Client<S>>,
+    targets: Vec<(String, Arc<C>)>,
+    quorum: usize,
+    observer: Option<Observer>,
+}
+
+impl<S, C> Replicator<S, C> {
+    /// Deliver to `quorum` of `targets`, preferring earlier ones.
+    pub fn new(client: Arc<Client<S>>, targets: Vec<(String, C)>, quorum: usize) -> Self {
+        Self {
+            client,
+            targets: targets.into_iter().map(|(name, connect)| (name, Arc::new(connect))).collect(),
+            quorum,
+            observer: None,
+        }
+    }
+
+    /// Report each target whenever its state changes.
+    pub fn with_observer(mut self, observer: impl Fn(&Replica) + Send + Sync + 'static) -> Self {
+        self.observer = Some(Arc::new(observer));
+        self
+    }
+}
+
+impl<S, C, F, T> Replicator<S, C>
+where
+    S: Storage + Send + Sync + 'static,
+    C: Fn() -> F + Send + Sync + 'static,
+    F: std::future::Future<Output = Result<T>> + Send + 'static,
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    /// Send `path` until the quorum holds it, all targets were tried, or
+    /// `handle` aborts it; sends in progress follow `handle` too. Fails
+    /// only if the share cannot be read or the quorum exceeds the targets.
+    pub async fn replicate(&self, path: &Path, handle: &TransferHandle) -> Result<Replication> {
+        if self.quorum == 0 || self.quorum > self.targets.len() {
+            anyhow::bail!("Cannot make {} copies with {} target(s)", self.quorum, self.targets.len());
+        }
+        let manifest = Arc::new(self.client.build_manifest(path, self.client.chunking_for(path).await?).await?);
+        let path = Arc::new(path.to_path_buf());
+        let mut replicas: Vec<Replica> = self.targets.iter()
+            .map(|(target, _)| Replica { target: target.clone(), state: ReplicaState::Waiting, error: None })
+            .collect();
+        tracing::info!("Replicating {} to {} of {} target(s)", manifest.filename, self.quorum, replicas.len());
+
+        let mut sends = JoinSet::new();
+        let mut next = 0;
+        let mut done = 0;
+        loop {
+            while done + sends.len() < self.quorum && next < self.targets.len() && !handle.is_aborted() {
+                let (_, connect) = &self.targets[next];
+                let send = send(self.client.clone(), connect.clone(), manifest.clone(), path.clone(), handle.clone());
+                sends.spawn(async move { (next, send.await) });
+                self.update(&mut replicas[next], ReplicaState::Sending, None);
+                next += 1;
+            }
+            let Some(joined) = sends.join_next().await else { break };
+            let (i, result) = joined?;
+            match result {
+                Ok(TransferOutcome::Completed) => {
+                    done += 1;
+                    self.update(&mut replicas[i], ReplicaState::Done, None);
+                }
+                Ok(TransferOutcome::Cancelled(cancelled)) => {
+                    self.update(&mut replicas[i], ReplicaState::Cancelled, Some(cancelled.to_string()));
+                }
+                Err(e) => {
+                    tracing::warn!("Replica on {} failed: {:#}", replicas[i].target, e);
+                    self.update(&mut replicas[i], ReplicaState::Failed, Some(format!("{:#}", e)));
+                }
+            }
+        }
+
+        let left = if done >= self.quorum { ReplicaState::NotNeeded } else { ReplicaState::Cancelled };
+        for replica in replicas.iter_mut().filter(|r| r.state == ReplicaState::Waiting) {
+            self.update(replica, left, None);
+        }
+        let manifest = Arc::unwrap_or_clone(manifest);
+        Ok(Replication { manifest, quorum: self.quorum, replicas })
+    }
+
+    fn update(&self, replica: &mut Replica, state: ReplicaState, error: Option<String>) {
+        replica.state = state;
+        replica.error = error;
+        if let Some(observer) = &self.observer {
+            observer(replica);
+        }
+    }
+}
+
+async fn send<S, C, F, T>(
+    client: Arc<Client<S>>,
+    connect: Arc<C>,
+    manifest: Arc<Manifest>,
+    path: Arc<PathBuf>,
+    handle: TransferHandle,
+) -> Result<TransferOutcome>
+where
+    S: Storage + Send + Sync + 'static,
+    C: Fn() -> F + Send + Sync,
+    F: std::future::Future<Output = Result<T>> + Send,
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let transport = connect().await?;
+    Ok(client.send_built(transport, &manifest, &path, &handle).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::fixture::client;
+    use crate::sim::{pair, LinkConfig};
+    use storage::LocalStorage;
+    use tempfile::TempDir;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_replicate_to_quorum() {
+        use crate::transfer::TransferHandle;
+
+        let (src, dst, nas_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap(), TempDir::new().unwrap());
+        let input = src.path().join("input.bin");
+        let payload: Vec<u8> = (0..150_000u32).map(|i| (i % 241) as u8).collect();
+        std::fs::write(&input, &payload).unwrap();
+
+        // A target is a receiving client, or unreachable without one.
+        let target = |receiver: Option<Arc<Client<LocalStorage>>>| move || {
+            let receiver = receiver.clone();
+            async move {
+                let receiver = receiver.ok_or_else(|| anyhow::anyhow!("unreachable"))?;
+                let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+                tokio::spawn(async move { receiver.accept_and_receive(b).await });
+                Ok(a)
+            }
+        };
+        let (laptop, nas) = (Arc::new(client(&dst, 2)), Arc::new(client(&nas_dir, 3)));
+        let targets = vec![
+            ("laptop".to_string(), target(Some(laptop.clone()))),
+            ("phone".to_string(), target(None)),
+            ("nas".to_string(), target(Some(nas))),
+            ("tv".to_string(), target(Some(laptop.clone()))),
+        ];
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let replicator = Replicator::new(Arc::new(client(&src, 1)), targets, 2)
+            .with_observer(move |replica| drop(tx.send((replica.target.clone(), replica.state))));
+        let replication = replicator.replicate(&input, &TransferHandle::new()).await.unwrap();
+        drop(replicator);
+
+        // The phone fails, so the NAS makes the second copy; the TV is
+        // never needed.
+        assert!(replication.is_complete());
+        let states: Vec<_> = replication.replicas.iter().map(|r| r.state).collect();
+        use ReplicaState::*;
+        assert_eq!(states, [Done, Failed, Done, NotNeeded]);
+        assert!(replication.replicas[1].error.as_deref().unwrap().contains("unreachable"));
+        let mut updates = Vec::new();
+        while let Some(update) = rx.recv().await {
+            updates.push(update);
+        }
+        assert_eq!(updates.iter().filter(|(_, state)| *state == Sending).count(), 3);
+        assert_eq!(updates.last().unwrap(), &("tv".to_string(), NotNeeded));
+
+        let output = dst.path().join("out.bin");
+        laptop.write_file(&replication.manifest, &output).await.unwrap();
+        assert_eq!(std::fs::read(output).unwrap(), payload);
+    }
+}
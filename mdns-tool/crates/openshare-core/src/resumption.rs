@@ -0,0 +1,424 @@
+//! Session resumption tickets.
+//!
+//! After a full handshake the responder may hand the initiator a ticket: the
+//! session's resumption secret and the initiator's identity, sealed under a
+//! key only the responder knows. Presenting the ticket later allows an
+//! abbreviated handshake that skips both Ed25519 signatures and the trust
+//! prompt while still running a fresh X25519 exchange, so every resumed
+//! session has its own forward-secret traffic key.
+//!
+//! Resumed first flight:
+//! `MAGIC || x_pub || nonce_a || binder || len(ticket) || ticket || early`,
+//! where `binder` is an HMAC under the resumption secret over every other
+//! field and `early` is optional 0-RTT data (the manifest offer) sealed
+//! under a key derived from the secret and `nonce_a`. The responder answers
+//! `x_pub || nonce_b || HMAC(secret, msgA || x_pub || nonce_b)` and both
+//! sides key the session from `DH || secret`.
+//!
+//! Tickets are single use: the initiator drops a ticket once presented and
+//! the responder remembers redeemed tickets until they expire, so a replayed
+//! first flight (and its early data) is refused. A responder that cannot
+//! accept a ticket (expired, replayed, or restarted with a new key) replies
+//! with an empty frame; both sides then fall back to the full handshake on
+//! the same transport and the early data is discarded.
+//!
+//! Expiry never compares two devices' clocks: the responder checks the time
+//! it sealed into the ticket against its own, and the initiator counts the
+//! lifetime from when the ticket arrived, so a skewed clock cannot make a
+//! ticket expire early on one side only.
+
+use crate::env::Env;
+use crate::handshake::{
+    self, derive_session, read_lp, write_lp, HandshakeError, PeerInfo, Session,
+};
+use crate::keys::Identity;
+use crate::noise::{self, NOISE_MAGIC};
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite};
+use x25519_dalek::{EphemeralSecret, PublicKey as X25519Public};
+use zeroize::Zeroizing;
+
+/// Prefix distinguishing a resumption attempt from a full handshake message
+/// (which starts with a random X25519 key).
+pub const RESUME_MAGIC: &[u8; 8] = b"OSRESUME";
+
+/// Exporter label for the secret carried in tickets.
+const RESUMPTION_LABEL: &[u8] = b"openshare resumption";
+
+/// HKDF info for the 0-RTT data key.
+const EARLY_DATA_LABEL: &[u8] = b"openshare early data v1";
+
+const KEY_LEN: usize = 32;
+const MAC_LEN: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Responder-side key sealing tickets, plus the replay cache of tickets
+/// already redeemed under it. Tickets issued under one key cannot be opened
+/// with another, so a fresh key invalidates all earlier tickets.
+#[derive(Clone)]
+pub struct TicketKey {
+    aead: XChaCha20Poly1305,
+    /// SHA-256 of redeemed tickets mapped to their expiry
+    redeemed: Arc<Mutex<HashMap<[u8; 32], u64>>>,
+}
+
+impl TicketKey {
+    pub fn generate(env: &Env) -> Self {
+        let mut key = Zeroizing::new([0u8; 32]);
+        env.fill_bytes(&mut *key);
+        Self::from_bytes(&key)
+    }
+
+    pub fn from_bytes(key: &[u8; 32]) -> Self {
+        Self {
+            aead: XChaCha20Poly1305::new(key.into()),
+            redeemed: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Record `ticket` as used; false if it was redeemed before.
+    fn redeem(&self, ticket: &[u8], expires_at: u64, now: u64) -> bool {
+        let id: [u8; 32] = Sha256::digest(ticket).into();
+        let mut redeemed = self.redeemed.lock().unwrap();
+        redeemed.retain(|_, exp| *exp > now);
+        redeemed.insert(id, expires_at).is_none()
+    }
+
+    fn seal(&self, contents: &TicketContents, env: &Env) -> Result<Vec<u8>, HandshakeError> {
+        let plain = Zeroizing::new(bincode::serialize(contents)
+            .map_err(|e| HandshakeError::Crypto(format!("ticket encode failed: {}", e)))?);
+        let mut nonce = [0u8; 24];
+        env.fill_bytes(&mut nonce);
+        let sealed = self.aead.encrypt(&XNonce::from(nonce), plain.as_slice())
+            .map_err(|_| HandshakeError::Crypto("ticket encrypt failed".into()))?;
+        Ok([&nonce[..], &sealed].concat())
+    }
+
+    fn open(&self, ticket: &[u8]) -> Option<TicketContents> {
+        if ticket.len() < 24 {
+            return None;
+        }
+        let nonce = XNonce::from_slice(&ticket[..24]);
+        let plain = Zeroizing::new(self.aead.decrypt(nonce, &ticket[24..]).ok()?);
+        bincode::deserialize(&plain).ok()
+    }
+}
+
+/// What the responder seals into a ticket.
+#[derive(Serialize, Deserialize)]
+struct TicketContents {
+    secret: Zeroizing<[u8; KEY_LEN]>,
+    /// The initiator the ticket was issued to
+    peer: PeerInfo,
+    expires_at: u64,
+}
+
+/// Ticket message sent by the responder over an established session.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NewTicket {
+    pub ticket: Vec<u8>,
+    pub lifetime_secs: u64,
+}
+
+/// A ticket held by the initiator, ready for [`initiator_resume`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResumptionTicket {
+    /// Opaque sealed ticket
+    pub ticket: Vec<u8>,
+    secret: Zeroizing<[u8; KEY_LEN]>,
+    /// The responder the ticket is valid for
+    pub peer: PeerInfo,
+    /// Unix timestamp (seconds) after which the responder rejects it
+    pub expires_at: u64,
+}
+
+impl ResumptionTicket {
+    pub fn is_expired(&self, env: &Env) -> bool {
+        unix_secs(env) >= self.expires_at
+    }
+}
+
+fn unix_secs(env: &Env) -> u64 {
+    env.now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn resumption_secret(session: &Session) -> Result<Zeroizing<[u8; KEY_LEN]>, HandshakeError> {
+    let bytes = session.export_keying_material(RESUMPTION_LABEL, b"", KEY_LEN)?;
+    Ok(Zeroizing::new(bytes[..].try_into().unwrap()))
+}
+
+fn mac(secret: &[u8; KEY_LEN], parts: &[&[u8]]) -> HmacSha256 {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(secret).expect("HMAC accepts any key length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac
+}
+
+/// Responder: issue a ticket for the initiator of `session`.
+pub fn issue_ticket(
+    session: &Session,
+    key: &TicketKey,
+    lifetime: Duration,
+    env: &Env,
+) -> Result<NewTicket, HandshakeError> {
+    let peer = session.peer.clone()
+        .ok_or_else(|| HandshakeError::Crypto("cannot issue a ticket to an anonymous peer".into()))?;
+    let contents = TicketContents {
+        secret: resumption_secret(session)?,
+        peer,
+        expires_at: unix_secs(env) + lifetime.as_secs(),
+    };
+    Ok(NewTicket { ticket: key.seal(&contents, env)?, lifetime_secs: lifetime.as_secs() })
+}
+
+/// Initiator: turn a received ticket into a [`ResumptionTicket`] for the
+/// responder of `session`.
+pub fn accept_ticket(
+    session: &Session,
+    new: NewTicket,
+    env: &Env,
+) -> Result<ResumptionTicket, HandshakeError> {
+    let peer = session.peer.clone()
+        .ok_or_else(|| HandshakeError::Crypto("ticket from an anonymous peer".into()))?;
+    Ok(ResumptionTicket {
+        ticket: new.ticket,
+        secret: resumption_secret(session)?,
+        peer,
+        expires_at: unix_secs(env) + new.lifetime_secs,
+    })
+}
+
+/// AEAD for the initiator's 0-RTT data. The key is unique per `nonce_a`
+/// and seals a single message, so a fixed nonce is safe.
+fn early_data_aead(secret: &[u8; KEY_LEN], nonce_a: &[u8]) -> XChaCha20Poly1305 {
+    let hk = Hkdf::<Sha256>::new(Some(secret), nonce_a);
+    let mut key = Zeroizing::new([0u8; 32]);
+    hk.expand(EARLY_DATA_LABEL, &mut *key).expect("32 bytes is a valid HKDF length");
+    XChaCha20Poly1305::new((&*key).into())
+}
+
+/// Initiator: resume with `ticket`, sending `early_data` in the first
+/// flight. Falls back to a full handshake as `identity`/`device_id` if the
+/// responder rejects the ticket; `Session::resumed` then reads false and
+/// the early data was not delivered.
+pub async fn initiator_resume<T>(
+    ticket: &ResumptionTicket,
+    early_data: Option<&[u8]>,
+    identity: &Identity,
+    device_id: &str,
+    transport: &mut T,
+    env: &Env,
+) -> Result<Session, HandshakeError>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let x_secret = env.with_rng(|rng| EphemeralSecret::random_from_rng(rng));
+    let x_pub = X25519Public::from(&x_secret);
+    let mut nonce_a = [0u8; 32];
+    env.fill_bytes(&mut nonce_a);
+
+    let early = match early_data {
+        Some(data) => early_data_aead(&ticket.secret, &nonce_a)
+            .encrypt(&XNonce::default(), data)
+            .map_err(|_| HandshakeError::Crypto("early data encrypt failed".into()))?,
+        None => Vec::new(),
+    };
+    let ticket_len = (ticket.ticket.len() as u32).to_be_bytes();
+    let binder = mac(&ticket.secret, &[RESUME_MAGIC, x_pub.as_bytes(), &nonce_a, &ticket_len, &ticket.ticket, &early])
+        .finalize()
+        .into_bytes();
+    let message_a = [
+        &RESUME_MAGIC[..], x_pub.as_bytes(), &nonce_a, &binder[..], &ticket_len, &ticket.ticket, &early,
+    ].concat();
+    write_lp(transport, &message_a).await.map_err(HandshakeError::Io)?;
+
+    let message_b = read_lp(transport).await.map_err(HandshakeError::Io)?;
+    if message_b.is_empty() {
+        tracing::debug!("Resumption rejected by peer; running full handshake");
+        return handshake::initiator_handshake_with(identity, device_id, transport, env).await;
+    }
+    if message_b.len() != KEY_LEN * 2 + MAC_LEN {
+        return Err(HandshakeError::Crypto("malformed resumption reply".into()));
+    }
+
+    let (fields, tag) = message_b.split_at(KEY_LEN * 2);
+    mac(&ticket.secret, &[&message_a, fields])
+        .verify_slice(tag)
+        .map_err(|_| HandshakeError::Crypto("resumption reply not authenticated".into()))?;
+
+    let x_b: [u8; KEY_LEN] = fields[..KEY_LEN].try_into().unwrap();
+    let nonce_b = &fields[KEY_LEN..];
+    let shared = x_secret.diffie_hellman(&X25519Public::from(x_b));
+
+    let ikm = Zeroizing::new([shared.as_bytes(), &ticket.secret[..]].concat());
+    let mut session = derive_session(&ikm, &nonce_a, nonce_b, &message_a, &message_b, Some(ticket.peer.clone()), env)?;
+    session.negotiate_framing(true)?;
+    session.resumed = true;
+    Ok(session)
+}
+
+/// Responder: accept either a full handshake (classic or Noise) or, when
+/// `key` is given, a resumption attempt. Returns the initiator's 0-RTT data if a resumption
+/// carrying some was accepted.
+pub async fn responder_accept<T>(
+    identity: &Identity,
+    device_id: &str,
+    transport: &mut T,
+    env: &Env,
+    key: Option<&TicketKey>,
+) -> Result<(Session, Option<Vec<u8>>), HandshakeError>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let message_a = read_lp(transport).await.map_err(HandshakeError::Io)?;
+    if message_a.starts_with(NOISE_MAGIC) {
+        let session = noise::respond(identity, device_id, transport, env, message_a).await?;
+        return Ok((session, None));
+    }
+    if !message_a.starts_with(RESUME_MAGIC) {
+        let session = handshake::respond_full(identity, device_id, transport, env, message_a).await?;
+        return Ok((session, None));
+    }
+
+    let Some(attempt) = key.and_then(|k| open_resumption(k, &message_a, env)) else {
+        tracing::debug!("Rejecting resumption attempt");
+        write_lp(transport, &[]).await.map_err(HandshakeError::Io)?;
+        let session = handshake::responder_handshake_with(identity, device_id, transport, env).await?;
+        return Ok((session, None));
+    };
+    let Resumption { contents, x_a, nonce_a, early_data } = attempt;
+
+    let x_secret = env.with_rng(|rng| EphemeralSecret::random_from_rng(rng));
+    let x_pub = X25519Public::from(&x_secret);
+    let mut nonce_b = [0u8; 32];
+    env.fill_bytes(&mut nonce_b);
+
+    let tag = mac(&contents.secret, &[&message_a, x_pub.as_bytes(), &nonce_b])
+        .finalize()
+        .into_bytes();
+    let message_b = [x_pub.as_bytes(), &nonce_b[..], &tag[..]].concat();
+    write_lp(transport, &message_b).await.map_err(HandshakeError::Io)?;
+
+    let shared = x_secret.diffie_hellman(&X25519Public::from(x_a));
+    let ikm = Zeroizing::new([shared.as_bytes(), &contents.secret[..]].concat());
+    let mut session = derive_session(&ikm, &nonce_a, &nonce_b, &message_a, &message_b, Some(contents.peer), env)?;
+    session.negotiate_framing(false)?;
+    session.resumed = true;
+    Ok((session, early_data))
+}
+
+/// A validated resumption first flight.
+struct Resumption {
+    contents: TicketContents,
+    x_a: [u8; KEY_LEN],
+    nonce_a: [u8; 32],
+    early_data: Option<Vec<u8>>,
+}
+
+/// Parse a resumption message, open its ticket, check binder, expiry and
+/// the replay cache, and decrypt any early data.
+fn open_resumption(key: &TicketKey, message: &[u8], env: &Env) -> Option<Resumption> {
+    let fields = RESUME_MAGIC.len() + KEY_LEN * 2;
+    let header = fields + MAC_LEN + 4;
+    if message.len() < header {
+        return None;
+    }
+    let x_pub_nonce = &message[RESUME_MAGIC.len()..fields];
+    let binder = &message[fields..fields + MAC_LEN];
+    let ticket_len_bytes = &message[fields + MAC_LEN..header];
+    let ticket_len = u32::from_be_bytes(ticket_len_bytes.try_into().unwrap()) as usize;
+    if message.len() - header < ticket_len {
+        return None;
+    }
+    let (ticket, early) = message[header..].split_at(ticket_len);
+
+    let contents = key.open(ticket)?;
+    let now = unix_secs(env);
+    if now >= contents.expires_at {
+        return None;
+    }
+    mac(&contents.secret, &[RESUME_MAGIC, x_pub_nonce, ticket_len_bytes, ticket, early])
+        .verify_slice(binder)
+        .ok()?;
+    if !key.redeem(ticket, contents.expires_at, now) {
+        tracing::warn!("Refusing replayed resumption ticket from {}", contents.peer.device_id);
+        return None;
+    }
+
+    let x_a: [u8; KEY_LEN] = x_pub_nonce[..KEY_LEN].try_into().unwrap();
+    let nonce_a: [u8; 32] = x_pub_nonce[KEY_LEN..].try_into().unwrap();
+    let early_data = if early.is_empty() {
+        None
+    } else {
+        Some(early_data_aead(&contents.secret, &nonce_a).decrypt(&XNonce::default(), early).ok()?)
+    };
+    Some(Resumption { contents, x_a, nonce_a, early_data })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::ManualClock;
+    use ed25519_dalek::SigningKey;
+    use std::time::SystemTime;
+
+    #[tokio::test]
+    async fn test_resume_replay_and_fallback() {
+        let clock = ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000));
+        let (env_a, env_b) = (Env::seeded([1; 32], clock.clone()), Env::seeded([2; 32], clock.clone()));
+        let id_a = Identity::from(SigningKey::from_bytes(&[1; 32]));
+        let id_b = Identity::from(SigningKey::from_bytes(&[2; 32]));
+        let key = TicketKey::generate(&env_b);
+
+        let (mut a, mut b) = tokio::io::duplex(4096);
+        let (sa, sb) = tokio::join!(
+            handshake::initiator_handshake_with(&id_a, "a", &mut a, &env_a),
+            responder_accept(&id_b, "b", &mut b, &env_b, Some(&key)),
+        );
+        let (sa, (sb, _)) = (sa.unwrap(), sb.unwrap());
+        let issued = issue_ticket(&sb, &key, Duration::from_secs(60), &env_b).unwrap();
+        let ticket = accept_ticket(&sa, issued, &env_a).unwrap();
+
+        let (ra, rb) = tokio::join!(
+            initiator_resume(&ticket, Some(b"offer"), &id_a, "a", &mut a, &env_a),
+            responder_accept(&id_b, "b", &mut b, &env_b, Some(&key)),
+        );
+        let (ra, (rb, early)) = (ra.unwrap(), rb.unwrap());
+        assert!(ra.resumed && rb.resumed);
+        assert_eq!(early.as_deref(), Some(&b"offer"[..]));
+        assert_eq!(ra.session_key, rb.session_key);
+        assert_ne!(ra.session_key, sa.session_key);
+        assert_eq!(rb.peer.unwrap().device_id, "a");
+        assert_eq!(ra.peer.unwrap().device_id, "b");
+
+        // Presenting the same ticket again is a replay: full handshake, and
+        // the early data is dropped.
+        let (fa, fb) = tokio::join!(
+            initiator_resume(&ticket, Some(b"offer"), &id_a, "a", &mut a, &env_a),
+            responder_accept(&id_b, "b", &mut b, &env_b, Some(&key)),
+        );
+        let (fa, (fb, early)) = (fa.unwrap(), fb.unwrap());
+        assert!(!fa.resumed && !fb.resumed && early.is_none());
+        assert_eq!(fa.session_key, fb.session_key);
+
+        // A fresh ticket is refused once expired.
+        let issued = issue_ticket(&fb, &key, Duration::from_secs(60), &env_b).unwrap();
+        let ticket = accept_ticket(&fa, issued, &env_a).unwrap();
+        clock.advance(Duration::from_secs(61));
+        let (ea, eb) = tokio::join!(
+            initiator_resume(&ticket, None, &id_a, "a", &mut a, &env_a),
+            responder_accept(&id_b, "b", &mut b, &env_b, Some(&key)),
+        );
+        assert!(!ea.unwrap().resumed && !eb.unwrap().0.resumed);
+    }
+}
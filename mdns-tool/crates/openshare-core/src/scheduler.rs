@@ -0,0 +1,257 @@
+//! Which queued [`Job`] the daemon starts next.
+//!
+//! The daemon runs several jobs at once, but not without bound: too many
+//! transfers only split the link between them, and a single slow peer
+//! should not hold every slot. A [`Scheduler`] starts a job only while
+//! fewer than `max_concurrent_jobs` run, and fewer than
+//! `max_jobs_per_peer` for its peer (as the job names it). A paused send
+//! keeps its slot, since it holds its connection.
+//!
+//! Among the jobs that may start, the highest [`Priority`] goes first.
+//! Within a priority, [`QueueOrder::Fifo`] takes the oldest job, while
+//! [`QueueOrder::FairShare`] takes turns between peers: the peer with the
+//! fewest running jobs first, then the one served longest ago, so that a
+//! hundred files queued for one device do not keep another waiting.
+
+use crate::config::ClientConfig;
+use crate::daemon::{Job, JobState};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl std::fmt::Display for Priority {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        })
+    }
+}
+
+impl std::str::FromStr for Priority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => anyhow::bail!("Invalid priority {:?}, expected low, normal or high", s),
+        }
+    }
+}
+
+/// How jobs of the same priority take turns; see the [module docs](self).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueOrder {
+    /// Oldest first
+    #[default]
+    Fifo,
+    /// Peers in turn, oldest first for each
+    FairShare,
+}
+
+impl std::fmt::Display for QueueOrder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            QueueOrder::Fifo => "fifo",
+            QueueOrder::FairShare => "fair_share",
+        })
+    }
+}
+
+impl std::str::FromStr for QueueOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fifo" => Ok(QueueOrder::Fifo),
+            "fair_share" | "fair-share" => Ok(QueueOrder::FairShare),
+            _ => anyhow::bail!("Invalid queue order {:?}, expected fifo or fair_share", s),
+        }
+    }
+}
+
+/// How many jobs run at once, and in what order they start. Named as in
+/// the config, and in the daemon's `scheduler` method.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SchedulerLimits {
+    /// 0 for no limit
+    pub max_concurrent_jobs: usize,
+    /// 0 for no limit
+    pub max_jobs_per_peer: usize,
+    pub queue_order: QueueOrder,
+}
+
+impl SchedulerLimits {
+    pub fn from_config(cfg: &ClientConfig) -> Self {
+        Self {
+            max_concurrent_jobs: cfg.max_concurrent_jobs,
+            max_jobs_per_peer: cfg.max_jobs_per_peer,
+            queue_order: cfg.queue_order,
+        }
+    }
+}
+
+impl Default for SchedulerLimits {
+    fn default() -> Self {
+        Self::from_config(&ClientConfig::default())
+    }
+}
+
+/// Picks the next job to start; see the [module docs](self).
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    pub limits: SchedulerLimits,
+    /// Jobs started so far
+    started: u64,
+    /// The value of `started` when each peer last had a job started
+    served: HashMap<String, u64>,
+}
+
+impl Scheduler {
+    pub fn new(limits: SchedulerLimits) -> Self {
+        Self { limits, ..Self::default() }
+    }
+
+    /// The id of the job among `jobs` to start at `now`, which is taken as
+    /// started. If none may start, the time the next job waiting for its
+    /// `not_before` may, if any; a job held back by the limits waits for
+    /// a running one to finish.
+    pub fn next<'a>(&mut self, jobs: impl IntoIterator<Item = &'a Job>, now: u64) -> Result<u64, Option<u64>> {
+        let jobs: Vec<&Job> = jobs.into_iter().collect();
+        let mut running: HashMap<&str, usize> = HashMap::new();
+        for job in jobs.iter().filter(|j| matches!(j.state, JobState::Running | JobState::Paused)) {
+            *running.entry(&job.peer).or_default() += 1;
+        }
+        let queued = || jobs.iter().filter(|j| j.state == JobState::Queued);
+        let waiting = || queued().filter_map(|j| j.not_before).filter(|&t| t > now).min();
+        let under = |limit: usize, count: usize| limit == 0 || count < limit;
+
+        if !under(self.limits.max_concurrent_jobs, running.values().sum()) {
+            return Err(None);
+        }
+        let ready = queued().filter(|j| {
+            j.not_before.is_none_or(|t| t <= now)
+                && under(self.limits.max_jobs_per_peer, running.get(j.peer.as_str()).copied().unwrap_or(0))
+        });
+        let next = match self.limits.queue_order {
+            QueueOrder::Fifo => ready.min_by_key(|j| (std::cmp::Reverse(j.priority), j.id)),
+            QueueOrder::FairShare => ready.min_by_key(|j| {
+                let load = running.get(j.peer.as_str()).copied().unwrap_or(0);
+                let served = self.served.get(&j.peer).copied().unwrap_or(0);
+                (std::cmp::Reverse(j.priority), load, served, j.id)
+            }),
+        };
+        let Some(job) = next else { return Err(waiting()) };
+        self.started += 1;
+        self.served.insert(job.peer.clone(), self.started);
+        Ok(job.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon::Task;
+
+    fn job(id: u64, peer: &str, priority: Priority) -> Job {
+        Job {
+            id,
+            task: Task::Send { path: format!("/data/{}", id).into() },
+            peer: peer.into(),
+            priority,
+            state: JobState::Queued,
+            transfer_id: None,
+            chunks_done: 0,
+            chunks_total: 0,
+            not_before: None,
+            error: None,
+        }
+    }
+
+    /// Start jobs from `jobs` until the scheduler refuses, returning their
+    /// ids in order.
+    fn drain(scheduler: &mut Scheduler, jobs: &mut [Job]) -> Vec<u64> {
+        let mut started = Vec::new();
+        while let Ok(id) = scheduler.next(jobs.iter(), 0) {
+            jobs.iter_mut().find(|j| j.id == id).unwrap().state = JobState::Running;
+            started.push(id);
+        }
+        started
+    }
+
+    fn finish(jobs: &mut [Job], id: u64) {
+        jobs.iter_mut().find(|j| j.id == id).unwrap().state = JobState::Done;
+    }
+
+    #[test]
+    fn test_limits_and_priorities() {
+        let limits = SchedulerLimits { max_concurrent_jobs: 2, max_jobs_per_peer: 1, queue_order: QueueOrder::Fifo };
+        let mut scheduler = Scheduler::new(limits);
+        let mut jobs = vec![
+            job(1, "nas", Priority::Normal),
+            job(2, "nas", Priority::Normal),
+            job(3, "laptop", Priority::Low),
+            job(4, "phone", Priority::High),
+        ];
+        // The high one first, then the oldest whose peer has a slot.
+        assert_eq!(drain(&mut scheduler, &mut jobs), [4, 1]);
+        finish(&mut jobs, 4);
+        assert_eq!(drain(&mut scheduler, &mut jobs), [3]);
+        // A paused job keeps its slot; a finished one frees it.
+        jobs[2].state = JobState::Paused;
+        finish(&mut jobs, 1);
+        assert_eq!(drain(&mut scheduler, &mut jobs), [2]);
+
+        // A job waiting for its window is not started before it opens.
+        let mut later = job(5, "phone", Priority::High);
+        later.not_before = Some(100);
+        jobs.push(later);
+        finish(&mut jobs, 2);
+        assert_eq!(scheduler.next(jobs.iter(), 0), Err(Some(100)));
+        assert_eq!(scheduler.next(jobs.iter(), 100), Ok(5));
+
+        // Without limits, everything starts.
+        let mut scheduler = Scheduler::new(SchedulerLimits { max_concurrent_jobs: 0, max_jobs_per_peer: 0, ..limits });
+        let mut jobs: Vec<Job> = (1..=5).map(|id| job(id, "nas", Priority::Normal)).collect();
+        assert_eq!(drain(&mut scheduler, &mut jobs), [1, 2, 3, 4, 5]);
+        assert_eq!(scheduler.next(jobs.iter(), 0), Err(None));
+    }
+
+    #[test]
+    fn test_fair_share_takes_turns() {
+        let limits = SchedulerLimits { max_concurrent_jobs: 1, max_jobs_per_peer: 0, queue_order: QueueOrder::FairShare };
+        let mut jobs: Vec<Job> = (1..=4).map(|id| job(id, "nas", Priority::Normal)).collect();
+        jobs.push(job(5, "laptop", Priority::Normal));
+        jobs.push(job(6, "laptop", Priority::Normal));
+        let run = |order| {
+            let mut scheduler = Scheduler::new(SchedulerLimits { queue_order: order, ..limits });
+            let mut jobs = jobs.clone();
+            let mut started = Vec::new();
+            for _ in 0..jobs.len() {
+                let [id] = drain(&mut scheduler, &mut jobs)[..] else { panic!("one job at a time") };
+                finish(&mut jobs, id);
+                started.push(id);
+            }
+            started
+        };
+        assert_eq!(run(QueueOrder::Fifo), [1, 2, 3, 4, 5, 6]);
+        assert_eq!(run(QueueOrder::FairShare), [1, 5, 2, 6, 3, 4]);
+
+        assert_eq!("fair-share".parse::<QueueOrder>().unwrap(), QueueOrder::FairShare);
+        assert_eq!("high".parse::<Priority>().unwrap(), Priority::High);
+        assert!("urgent".parse::<Priority>().is_err());
+    }
+}
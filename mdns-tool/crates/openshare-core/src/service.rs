@@ -0,0 +1,294 @@
+//! Running the daemon as a system service.
+//!
+//! `openshare daemon install` registers the daemon with the platform's
+//! service manager: a systemd user unit on Linux, a launchd agent on
+//! macOS and a Windows service on Windows. A [`ServiceSpec`] holds what
+//! every manager needs (the command line and the restart policy from
+//! [`ServiceConfig`]) and renders the systemd unit and launchd property
+//! list; Windows takes the same through `sc.exe`.
+//!
+//! Service managers stop the daemon with a signal (`SIGTERM`) or a stop
+//! request, which it handles like Ctrl+C. With no terminal to write to, it
+//! logs to a [`RotatingLog`] instead.
+
+use crate::config::{RestartPolicy, ServiceConfig};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Log file of the service in the data directory, unless `log_file` in
+/// [`ServiceConfig`] says otherwise.
+pub const LOG_FILE: &str = "daemon.log";
+
+/// File the service manager sends anything written outside the log to,
+/// such as a panic message.
+pub const STDERR_FILE: &str = "daemon.stderr.log";
+
+/// Name of the systemd user unit.
+pub const SYSTEMD_UNIT: &str = "openshare.service";
+
+/// Label of the launchd agent, and the name of its property list.
+pub const LAUNCHD_LABEL: &str = "org.openshare.daemon";
+
+/// Name of the Windows service.
+pub const WINDOWS_SERVICE: &str = "OpenShare";
+
+/// How to run the daemon as a service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceSpec {
+    pub program: PathBuf,
+    pub args: Vec<String>,
+    pub restart: RestartPolicy,
+    pub restart_delay: Duration,
+    /// Where anything not logged goes
+    pub stderr: PathBuf,
+}
+
+impl ServiceSpec {
+    /// Run `program` with `args` as `cfg` says, for the device with data
+    /// directory `data_dir`.
+    pub fn new(program: PathBuf, args: Vec<String>, cfg: &ServiceConfig, data_dir: &Path) -> Self {
+        Self {
+            program,
+            args,
+            restart: cfg.restart,
+            restart_delay: Duration::from_secs(cfg.restart_delay_secs),
+            stderr: data_dir.join(STDERR_FILE),
+        }
+    }
+
+    /// A systemd unit to install in the user's `systemd/user` directory.
+    pub fn systemd_unit(&self) -> String {
+        let command: Vec<String> = std::iter::once(self.program.to_string_lossy().into_owned())
+            .chain(self.args.iter().cloned())
+            .map(|arg| systemd_quote(&arg))
+            .collect();
+        let restart = match self.restart {
+            RestartPolicy::Always => "always",
+            RestartPolicy::OnFailure => "on-failure",
+            RestartPolicy::Never => "no",
+        };
+        format!(
+            "[Unit]\n\
+             Description=OpenShare daemon\n\
+             After=network-online.target\n\
+             Wants=network-online.target\n\
+             \n\
+             [Service]\n\
+             ExecStart={}\n\
+             Restart={}\n\
+             RestartSec={}\n\
+             KillSignal=SIGTERM\n\
+             TimeoutStopSec=30\n\
+             StandardError=append:{}\n\
+             \n\
+             [Install]\n\
+             WantedBy=default.target\n",
+            command.join(" "),
+            restart,
+            self.restart_delay.as_secs(),
+            systemd_escape(&self.stderr.to_string_lossy()),
+        )
+    }
+
+    /// A launchd property list to install in `~/Library/LaunchAgents`.
+    pub fn launchd_plist(&self) -> String {
+        let arguments: String = std::iter::once(self.program.to_string_lossy().into_owned())
+            .chain(self.args.iter().cloned())
+            .map(|arg| format!("        <string>{}</string>\n", xml_escape(&arg)))
+            .collect();
+        let keep_alive = match self.restart {
+            RestartPolicy::Always => "<true/>",
+            RestartPolicy::OnFailure => "<dict>\n        <key>SuccessfulExit</key>\n        <false/>\n    </dict>",
+            RestartPolicy::Never => "<false/>",
+        };
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n\
+             <plist version=\"1.0\">\n\
+             <dict>\n    \
+                 <key>Label</key>\n    \
+                 <string>{}</string>\n    \
+                 <key>ProgramArguments</key>\n    \
+                 <array>\n{}    </array>\n    \
+                 <key>RunAtLoad</key>\n    \
+                 <true/>\n    \
+                 <key>KeepAlive</key>\n    \
+                 {}\n    \
+                 <key>ThrottleInterval</key>\n    \
+                 <integer>{}</integer>\n    \
+                 <key>StandardErrorPath</key>\n    \
+                 <string>{}</string>\n\
+             </dict>\n\
+             </plist>\n",
+            LAUNCHD_LABEL,
+            arguments,
+            keep_alive,
+            self.restart_delay.as_secs(),
+            xml_escape(&self.stderr.to_string_lossy()),
+        )
+    }
+
+    /// The command line for a Windows service, quoted as `CreateProcess`
+    /// splits it.
+    pub fn windows_command_line(&self) -> String {
+        std::iter::once(self.program.to_string_lossy().into_owned())
+            .chain(self.args.iter().cloned())
+            .map(|arg| windows_quote(&arg))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// `%` and `$` escaped, which systemd would expand.
+fn systemd_escape(s: &str) -> String {
+    s.replace('%', "%%").replace('$', "$$")
+}
+
+/// One argument of a systemd `ExecStart`, quoted if it needs to be.
+fn systemd_quote(arg: &str) -> String {
+    let arg = systemd_escape(arg);
+    if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | ';')) {
+        return arg;
+    }
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn windows_quote(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains([' ', '\t', '"']) {
+        return arg.to_string();
+    }
+    // Backslashes only escape when they come before a quote.
+    let mut quoted = String::from("\"");
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            '"' => {
+                quoted.push_str(&"\\".repeat(2 * backslashes + 1));
+                backslashes = 0;
+            }
+            _ => backslashes = 0,
+        }
+        quoted.push(c);
+    }
+    quoted.push_str(&"\\".repeat(backslashes));
+    quoted.push('"');
+    quoted
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// A log file that is moved aside once it reaches a size: `daemon.log`
+/// becomes `daemon.log.1`, the previous `.1` becomes `.2`, and so on, and
+/// the oldest beyond the number kept is deleted.
+pub struct RotatingLog {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: u32,
+    file: File,
+    size: u64,
+}
+
+impl RotatingLog {
+    /// Append to the log at `path`, rotating it as `cfg` says.
+    pub fn open(path: &Path, cfg: &ServiceConfig) -> std::io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = File::options().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path: path.to_path_buf(), max_bytes: cfg.log_max_bytes, keep: cfg.log_files_kept, file, size })
+    }
+
+    /// `path` with `.n` appended.
+    fn numbered(&self, n: u32) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let result = (1..=self.keep).rev().try_for_each(|n| {
+            let from = if n == 1 { self.path.clone() } else { self.numbered(n - 1) };
+            match std::fs::rename(&from, self.numbered(n)) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                result => result,
+            }
+        });
+        // Truncated when none are kept, or when moving failed (on Windows,
+        // say, where another process has it open), so it still stops growing
+        self.file = File::options().create(true).write(true).truncate(true).open(&self.path)?;
+        self.size = 0;
+        result
+    }
+}
+
+impl Write for RotatingLog {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.max_bytes > 0 && self.size > 0 && self.size + buf.len() as u64 > self.max_bytes {
+            // Losing the old lines beats losing the new one.
+            let _ = self.rotate();
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_service_definitions() {
+        let cfg = ServiceConfig { restart_delay_secs: 7, ..ServiceConfig::default() };
+        let args = vec!["--data-dir".to_string(), "/home/ana/My Share".to_string(), "daemon".to_string()];
+        let spec = ServiceSpec::new(PathBuf::from("/usr/bin/openshare"), args, &cfg, Path::new("/home/ana/.openshare"));
+
+        let unit = spec.systemd_unit();
+        assert!(unit.contains("\nExecStart=/usr/bin/openshare --data-dir \"/home/ana/My Share\" daemon\n"));
+        assert!(unit.contains("\nRestart=on-failure\nRestartSec=7\n"));
+        assert!(unit.contains("\nStandardError=append:/home/ana/.openshare/daemon.stderr.log\n"));
+        assert_eq!(systemd_quote("100%"), "100%%");
+
+        let plist = spec.launchd_plist();
+        assert!(plist.contains("<string>/home/ana/My Share</string>"));
+        assert!(plist.contains("<key>SuccessfulExit</key>"));
+        let always = ServiceSpec { restart: RestartPolicy::Always, ..spec.clone() };
+        assert!(always.launchd_plist().contains("<key>KeepAlive</key>\n    <true/>"));
+
+        assert_eq!(spec.windows_command_line(), r#"/usr/bin/openshare --data-dir "/home/ana/My Share" daemon"#);
+        assert_eq!(windows_quote(r#"C:\a "b"\"#), r#""C:\a \"b\"\\""#);
+    }
+
+    #[test]
+    fn test_rotating_log() -> std::io::Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join(LOG_FILE);
+        let cfg = ServiceConfig { log_max_bytes: 10, log_files_kept: 2, ..ServiceConfig::default() };
+        let mut log = RotatingLog::open(&path, &cfg)?;
+        for line in ["one\n", "two\n", "three\n", "four\n", "five\n"] {
+            log.write_all(line.as_bytes())?;
+        }
+        assert_eq!(std::fs::read_to_string(&path)?, "four\nfive\n");
+        assert_eq!(std::fs::read_to_string(dir.path().join("daemon.log.1"))?, "three\n");
+        assert_eq!(std::fs::read_to_string(dir.path().join("daemon.log.2"))?, "one\ntwo\n");
+
+        // Reopening carries on where the file left off.
+        let mut log = RotatingLog::open(&path, &cfg)?;
+        log.write_all(b"six\n")?;
+        assert_eq!(std::fs::read_to_string(&path)?, "six\n");
+        assert_eq!(std::fs::read_to_string(dir.path().join("daemon.log.2"))?, "three\n");
+        assert!(!dir.path().join("daemon.log.3").exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+//! Checksum sidecars for received files.
+//!
+//! With `write_sidecars` on, every file (or directory) written from a
+//! transfer gets a `<name>.oshare.json` next to it holding the verified
+//! manifest, the sender's key fingerprint and when it arrived. Backup or
+//! provenance tooling can check the file against it later without the
+//! OpenShare data directory; `openshare verify-sidecar` does just that.
+
+use crate::Manifest;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Appended to the received path to name its sidecar.
+pub const SIDECAR_SUFFIX: &str = ".oshare.json";
+
+/// Layout version of the sidecar file.
+pub const SIDECAR_FORMAT: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sidecar {
+    pub format: u32,
+    pub manifest_id: String,
+    /// Hex SHA-256 of the content; taken from the manifest, or computed
+    /// after assembly when the sender predates whole-file hashes
+    pub file_hash: String,
+    /// Full hex public key that signed the manifest
+    pub sender_fingerprint: Option<String>,
+    /// Unix timestamp (seconds) when the transfer was written out
+    pub received_at: u64,
+    /// Modification time of the written file, to spot later edits cheaply
+    pub modified_at: Option<u64>,
+    pub manifest: Manifest,
+}
+
+impl Sidecar {
+    /// Describe `manifest`, just assembled at `path`.
+    pub fn new(manifest: &Manifest, path: &Path, received_at: SystemTime) -> Result<Self> {
+        let file_hash = if manifest.file_hash.is_empty() {
+            manifest.content_hash(path)?
+        } else {
+            manifest.file_hash.clone()
+        };
+        Ok(Self {
+            format: SIDECAR_FORMAT,
+            manifest_id: manifest.id(),
+            file_hash,
+            sender_fingerprint: manifest.sender_pubkey.as_deref().map(hex::encode),
+            received_at: unix_secs(received_at),
+            modified_at: std::fs::metadata(path)?.modified().ok().map(unix_secs),
+            manifest: manifest.clone(),
+        })
+    }
+
+    /// Where the sidecar for `path` lives.
+    pub fn path_for(path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_os_string();
+        name.push(SIDECAR_SUFFIX);
+        PathBuf::from(name)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let target = Self::path_for(path);
+        let tmp = target.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("writing {}", tmp.display()))?;
+        std::fs::rename(&tmp, &target)
+            .with_context(|| format!("writing {}", target.display()))
+    }
+
+    /// Read the sidecar of `path`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let source = Self::path_for(path);
+        let json = std::fs::read_to_string(&source)
+            .with_context(|| format!("reading {}", source.display()))?;
+        serde_json::from_str(&json).with_context(|| format!("parsing {}", source.display()))
+    }
+
+    /// Check the manifest signature and that the content at `path` still
+    /// hashes to `file_hash`.
+    pub fn verify(&self, path: &Path) -> Result<()> {
+        if self.manifest.sender_sig.is_some() {
+            self.manifest.verify().context("Manifest signature is invalid")?;
+        }
+        if self.manifest.id() != self.manifest_id {
+            anyhow::bail!("Manifest does not match its recorded ID");
+        }
+        let actual = self.manifest.content_hash(path)?;
+        if actual != self.file_hash {
+            anyhow::bail!("Content hash mismatch for {}: expected {}, found {}",
+                path.display(), self.file_hash, actual);
+        }
+        Ok(())
+    }
+}
+
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::Identity;
+    use ed25519_dalek::SigningKey;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_sidecar_roundtrip_and_tamper() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let file = tmp.path().join("report.txt");
+        std::fs::write(&file, b"quarterly numbers")?;
+        let mut manifest = Manifest::from_file(file.to_str().unwrap(), 4)?;
+        manifest.sign(&Identity::from(SigningKey::from_bytes(&[4; 32])))?;
+
+        Sidecar::new(&manifest, &file, SystemTime::now())?.write(&file)?;
+        assert!(tmp.path().join("report.txt.oshare.json").exists());
+        let sidecar = Sidecar::load(&file)?;
+        assert_eq!(sidecar.file_hash, manifest.file_hash);
+        sidecar.verify(&file)?;
+
+        std::fs::write(&file, b"quarterly numbers!")?;
+        assert!(sidecar.verify(&file).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,345 @@
+//! Simulated network links for exercising the protocol under bad conditions.
+//!
+//! [`pair`] returns two connected in-memory streams. Every write is treated
+//! as one segment and delivered to the other side after a delay derived from
+//! the [`LinkConfig`]: base latency, jitter, serialization time at the
+//! bandwidth cap, and retransmission penalties for lost or reordered
+//! segments. Like TCP, the stream stays reliable and in order, so loss and
+//! reordering surface as head-of-line stalls rather than corruption.
+//! `fail_after` hard-resets the link after a byte budget to exercise error
+//! and resume paths.
+//!
+//! All randomness comes from a seeded RNG; combine with tokio's paused
+//! clock (`#[tokio::test(start_paused = true)]`) for reproducible runs.
+//!
+//! The tests that drive clients over these links sit next to the features
+//! they cover and share the clients made by `sim::fixture`.
+
+use rand_chacha::ChaCha20Rng;
+use rand_core::{RngCore, SeedableRng};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// Characteristics of one direction of a simulated link.
+#[derive(Debug, Clone)]
+pub struct LinkConfig {
+    /// One-way base latency
+    pub latency: Duration,
+    /// Uniform extra delay in `[0, jitter)`
+    pub jitter: Duration,
+    /// Bandwidth cap in bytes per second
+    pub bandwidth: Option<u64>,
+    /// Probability that a segment is lost and must be retransmitted
+    pub loss: f64,
+    /// Probability that a segment arrives out of order and is held back
+    pub reorder: f64,
+    /// Penalty added per loss or reorder event
+    pub retransmit_delay: Duration,
+    /// Reset the link once this many bytes have been written
+    pub fail_after: Option<u64>,
+    pub seed: u64,
+}
+
+impl LinkConfig {
+    /// No delay, no loss.
+    pub fn ideal() -> Self {
+        Self {
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            bandwidth: None,
+            loss: 0.0,
+            reorder: 0.0,
+            retransmit_delay: Duration::ZERO,
+            fail_after: None,
+            seed: 0,
+        }
+    }
+
+    /// Wired gigabit LAN.
+    pub fn lan() -> Self {
+        Self {
+            latency: Duration::from_micros(300),
+            jitter: Duration::from_micros(100),
+            bandwidth: Some(110 * 1024 * 1024),
+            ..Self::ideal()
+        }
+    }
+
+    /// Congested Wi-Fi with noticeable loss.
+    pub fn lossy_wifi() -> Self {
+        Self {
+            latency: Duration::from_millis(8),
+            jitter: Duration::from_millis(12),
+            bandwidth: Some(4 * 1024 * 1024),
+            loss: 0.05,
+            reorder: 0.02,
+            retransmit_delay: Duration::from_millis(40),
+            ..Self::ideal()
+        }
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn with_fail_after(mut self, bytes: u64) -> Self {
+        self.fail_after = Some(bytes);
+        self
+    }
+
+    /// Delivery delay for a segment of `len` bytes.
+    fn delay(&self, len: usize, rng: &mut ChaCha20Rng, stats: &SimStats) -> Duration {
+        let mut delay = self.latency;
+        if !self.jitter.is_zero() {
+            let frac = rng.next_u32() as f64 / u32::MAX as f64;
+            delay += self.jitter.mul_f64(frac);
+        }
+        if let Some(bw) = self.bandwidth {
+            delay += Duration::from_secs_f64(len as f64 / bw as f64);
+        }
+        // Each loss costs one retransmission; losses may repeat.
+        while chance(rng, self.loss) {
+            stats.lost.fetch_add(1, Ordering::Relaxed);
+            delay += self.retransmit_delay;
+        }
+        if chance(rng, self.reorder) {
+            stats.reordered.fetch_add(1, Ordering::Relaxed);
+            delay += self.retransmit_delay / 2;
+        }
+        delay
+    }
+}
+
+fn chance(rng: &mut ChaCha20Rng, p: f64) -> bool {
+    p > 0.0 && (rng.next_u32() as f64 / u32::MAX as f64) < p
+}
+
+/// Counters for one direction of a link.
+#[derive(Debug, Default)]
+pub struct SimStats {
+    pub segments: AtomicU64,
+    pub bytes: AtomicU64,
+    pub lost: AtomicU64,
+    pub reordered: AtomicU64,
+}
+
+/// One end of a simulated link.
+pub struct SimStream {
+    reader: DuplexStream,
+    writer: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    written: u64,
+    fail_after: Option<u64>,
+    /// Stats for traffic written by this end
+    pub stats: Arc<SimStats>,
+}
+
+/// Create two connected streams. `a_to_b` shapes what `a` writes and `b`
+/// reads; `b_to_a` the reverse. Must be called inside a tokio runtime.
+pub fn pair(a_to_b: LinkConfig, b_to_a: LinkConfig) -> (SimStream, SimStream) {
+    let (a_read, b_sink) = tokio::io::duplex(1 << 20);
+    let (b_read, a_sink) = tokio::io::duplex(1 << 20);
+    (direction(a_to_b, a_read, a_sink), direction(b_to_a, b_read, b_sink))
+}
+
+fn direction(cfg: LinkConfig, reader: DuplexStream, mut sink: DuplexStream) -> SimStream {
+    let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+    let stats = Arc::new(SimStats::default());
+    let fail_after = cfg.fail_after;
+
+    let pump_stats = stats.clone();
+    tokio::spawn(async move {
+        let mut rng = ChaCha20Rng::seed_from_u64(cfg.seed);
+        // Segments queue behind each other like on a real link.
+        let mut link_free_at = Instant::now();
+        while let Some(segment) = rx.recv().await {
+            let delay = cfg.delay(segment.len(), &mut rng, &pump_stats);
+            link_free_at = link_free_at.max(Instant::now()) + delay;
+            tokio::time::sleep_until(link_free_at).await;
+
+            pump_stats.segments.fetch_add(1, Ordering::Relaxed);
+            pump_stats.bytes.fetch_add(segment.len() as u64, Ordering::Relaxed);
+            if sink.write_all(&segment).await.is_err() {
+                break;
+            }
+        }
+        // Dropping the sink delivers EOF to the reader.
+    });
+
+    SimStream {
+        reader,
+        writer: Some(tx),
+        written: 0,
+        fail_after,
+        stats,
+    }
+}
+
+impl AsyncRead for SimStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.reader).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SimStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let reset = || io::Error::new(io::ErrorKind::ConnectionReset, "simulated link reset");
+
+        let mut len = buf.len();
+        if let Some(limit) = self.fail_after {
+            if self.written >= limit {
+                self.writer = None;
+                return Poll::Ready(Err(reset()));
+            }
+            len = len.min((limit - self.written) as usize);
+        }
+
+        let Some(writer) = &self.writer else {
+            return Poll::Ready(Err(reset()));
+        };
+        if writer.send(buf[..len].to_vec()).is_err() {
+            return Poll::Ready(Err(reset()));
+        }
+        self.written += len as u64;
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.writer = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Clients and storage shared by the tests that run the protocol over
+/// simulated links, here and next to the features they cover.
+#[cfg(test)]
+pub(crate) mod fixture {
+    use crate::{Client, ClientConfig, Identity};
+    use ed25519_dalek::SigningKey;
+    use std::sync::atomic::Ordering;
+    use storage::LocalStorage;
+    use tempfile::TempDir;
+
+    /// A client keeping its data and storage in `dir`, known as "dev<seed>"
+    /// with a key derived from `seed`, that trusts peers on first use.
+    pub(crate) fn client(dir: &TempDir, seed: u8) -> Client<LocalStorage> {
+        let identity = Identity::from(SigningKey::from_bytes(&[seed; 32]));
+        let storage = LocalStorage::new(dir.path().to_path_buf()).unwrap();
+        let cfg = ClientConfig {
+            data_dir: dir.path().to_path_buf(),
+            chunk_size: 64 * 1024,
+            device_id: format!("dev{}", seed),
+            trust_on_first_use: true,
+            ..ClientConfig::default()
+        };
+        Client::new(identity, storage, cfg)
+    }
+
+    /// Storage that runs out of space after `room` chunks, and returns
+    /// garbage for the next reads of the chunks in `corrupt`.
+    pub(crate) struct Faulty {
+        inner: storage::MemoryStorage,
+        room: std::sync::atomic::AtomicUsize,
+        pub(crate) corrupt: std::sync::Mutex<std::collections::HashMap<String, usize>>,
+    }
+
+    impl Faulty {
+        pub(crate) fn new(room: usize) -> Self {
+            Self { inner: storage::MemoryStorage::new(), room: room.into(), corrupt: Default::default() }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl storage::Storage for Faulty {
+        async fn put_chunk(&self, data: &[u8]) -> storage::Result<String> {
+            if self.room.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_err() {
+                return Err(std::io::Error::from(std::io::ErrorKind::StorageFull).into());
+            }
+            self.inner.put_chunk(data).await
+        }
+
+        async fn get_chunk(&self, id: &str) -> storage::Result<Option<Vec<u8>>> {
+            if let Some(reads) = self.corrupt.lock().unwrap().get_mut(id).filter(|n| **n > 0) {
+                *reads -= 1;
+                return Ok(Some(b"not the chunk".to_vec()));
+            }
+            self.inner.get_chunk(id).await
+        }
+
+        async fn delete_chunk(&self, id: &str) -> storage::Result<bool> {
+            self.inner.delete_chunk(id).await
+        }
+
+        async fn list_chunks(&self, after: Option<&str>, limit: usize) -> storage::Result<Vec<String>> {
+            self.inner.list_chunks(after, limit).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixture::client;
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Run a full send/receive over the given link and return the received
+    /// file bytes plus elapsed simulated time.
+    async fn transfer(link: LinkConfig, payload: &[u8]) -> anyhow::Result<(Vec<u8>, Duration)> {
+        let (src, dst, out) = (TempDir::new()?, TempDir::new()?, TempDir::new()?);
+        let input = src.path().join("input.bin");
+        std::fs::write(&input, payload)?;
+
+        let sender = client(&src, 1);
+        let receiver = client(&dst, 2);
+        let (a, b) = pair(link.clone(), link.with_seed(99));
+
+        let start = Instant::now();
+        let manifest = sender.import_file(&input).await?;
+        let (sent, received) = tokio::join!(
+            sender.send_manifest_over(a, manifest),
+            receiver.accept_and_receive(b),
+        );
+        sent?;
+        let manifest = received?;
+        let elapsed = start.elapsed();
+
+        let output = out.path().join(&manifest.filename);
+        receiver.write_file(&manifest, &output).await?;
+        Ok((std::fs::read(output)?, elapsed))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pipeline_under_simulated_links() {
+        let payload: Vec<u8> = (0..600_000u32).map(|i| (i * 31 % 251) as u8).collect();
+
+        let (lan, lan_time) = transfer(LinkConfig::lan(), &payload).await.unwrap();
+        assert_eq!(lan, payload);
+
+        let (wifi, wifi_time) = transfer(LinkConfig::lossy_wifi().with_seed(7), &payload).await.unwrap();
+        assert_eq!(wifi, payload);
+        assert!(wifi_time > lan_time);
+
+        // A link that dies mid-transfer must surface an error, not hang.
+        let broken = LinkConfig::lan().with_fail_after(100_000);
+        assert!(transfer(broken, &payload).await.is_err());
+    }
+}
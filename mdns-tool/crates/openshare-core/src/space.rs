@@ -0,0 +1,150 @@
+//! Disk space for incoming transfers.
+//!
+//! Before a pushed transfer is put to the
+//! [`TransferPolicy`](crate::policy::TransferPolicy), and before a pull
+//! takes any chunk, the receiving client works out what the transfer will
+//! take: its chunks in a local chunk store, and the assembled file in the
+//! output directory, each with some [`overhead`]. Needs on the same
+//! filesystem add up. If one does not fit, a push's
+//! [`Offer`](crate::policy::Offer) carries the [`InsufficientSpace`], and
+//! [`Limits`](crate::policy::Limits) reject it; a pull fails with it at
+//! once. Either way it is a
+//! [`DiskFull`](crate::error::OpenShareError::DiskFull) error.
+//!
+//! Free space is only known on Unix; elsewhere transfers are not checked.
+//! With `preallocate_files`, a single file also has its full size
+//! [reserved](reserve) in the output directory before its chunks arrive.
+
+use std::path::{Path, PathBuf};
+
+/// Space left free on a filesystem after a transfer.
+pub const RESERVE: u64 = 16 * 1024 * 1024;
+
+/// Space a transfer of `size` bytes takes beyond its content on one
+/// filesystem: chunk index entries, encryption tags and partly used
+/// blocks, plus the [`RESERVE`].
+pub fn overhead(size: u64) -> u64 {
+    size / 64 + RESERVE
+}
+
+/// An incoming transfer that would not fit on disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InsufficientSpace {
+    /// Where it would have been written
+    pub path: PathBuf,
+    /// Bytes needed there, overhead included
+    pub needed: u64,
+    pub available: u64,
+}
+
+impl std::fmt::Display for InsufficientSpace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Not enough space on {}: {} bytes needed, {} available", self.path.display(), self.needed, self.available)
+    }
+}
+
+impl std::error::Error for InsufficientSpace {}
+
+/// Bytes free for unprivileged use on the filesystem holding `path`, or
+/// its nearest existing ancestor; `None` where that is not known.
+pub fn available(path: &Path) -> Option<u64> {
+    let path = existing_ancestor(path)?;
+    #[cfg(unix)]
+    {
+        let stat = rustix::fs::statvfs(path).ok()?;
+        Some(stat.f_bavail.saturating_mul(stat.f_frsize))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        None
+    }
+}
+
+/// Check that `needs`, each a directory and the bytes a transfer writes
+/// under it, fit with their [`overhead`]. Needs on the same filesystem are
+/// added up and reported at the first of them.
+pub fn check(needs: &[(PathBuf, u64)]) -> Result<(), InsufficientSpace> {
+    let mut filesystems: Vec<(Option<u64>, &Path, u64)> = Vec::new();
+    for (path, size) in needs {
+        let id = filesystem(path);
+        match filesystems.iter_mut().find(|(fs, _, _)| id.is_some() && *fs == id) {
+            Some((_, _, needed)) => *needed += size + overhead(*size),
+            None => filesystems.push((id, path, size + overhead(*size))),
+        }
+    }
+    for (_, path, needed) in filesystems {
+        let Some(available) = available(path) else { continue };
+        if needed > available {
+            return Err(InsufficientSpace { path: path.to_path_buf(), needed, available });
+        }
+    }
+    Ok(())
+}
+
+/// Create `path` if need be and reserve `len` bytes for it on disk, so
+/// that writing it cannot run out of space halfway. Its content is left
+/// as it is. Where the platform cannot reserve space, only the file is
+/// created.
+pub fn reserve(path: &Path, len: u64) -> std::io::Result<()> {
+    let file = std::fs::OpenOptions::new().write(true).create(true).truncate(false).open(path)?;
+    #[cfg(unix)]
+    if len > 0 {
+        match rustix::fs::fallocate(&file, rustix::fs::FallocateFlags::empty(), 0, len) {
+            // Filesystems without the call still get the file.
+            Err(rustix::io::Errno::OPNOTSUPP) => {}
+            result => result?,
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (file, len);
+    Ok(())
+}
+
+/// Identifies the filesystem holding `path`, where known.
+fn filesystem(path: &Path) -> Option<u64> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        std::fs::metadata(existing_ancestor(path)?).ok().map(|m| m.dev())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        None
+    }
+}
+
+fn existing_ancestor(path: &Path) -> Option<&Path> {
+    path.ancestors().find(|p| !p.as_os_str().is_empty() && p.exists())
+        .or_else(|| path.is_relative().then_some(Path::new(".")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_space_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let free = available(dir.path()).unwrap();
+        let missing = dir.path().join("not/yet/there");
+        assert_eq!(available(&missing).map(|a| a > 0), Some(true));
+
+        assert!(check(&[(missing.clone(), 1000)]).is_ok());
+        let short = check(&[(dir.path().to_path_buf(), free)]).unwrap_err();
+        assert_eq!(short.path, dir.path());
+        assert!(short.needed > short.available);
+
+        // Two needs on one filesystem are added up.
+        let half = free / 2;
+        assert!(check(&[(dir.path().to_path_buf(), half)]).is_ok());
+        assert!(check(&[(dir.path().to_path_buf(), half), (missing, half)]).is_err());
+
+        let part = dir.path().join("big.bin.part");
+        std::fs::write(&part, b"kept").unwrap();
+        reserve(&part, 1 << 20).unwrap();
+        assert_eq!(std::fs::metadata(&part).unwrap().len(), 1 << 20);
+        assert_eq!(&std::fs::read(&part).unwrap()[..4], b"kept");
+    }
+}
@@ -0,0 +1,159 @@
+//! Crash-safe files for persistent state.
+//!
+//! The config, the trust store, the content index and the trash index are
+//! rewritten whole. [`write`] puts the new contents in `<file>.tmp`, syncs
+//! it to disk and renames it over the file, so a crash leaves the old or
+//! the new version and never part of one. The SHA-256 of each version it
+//! writes goes to `<file>.sha256`, and the version being replaced is kept
+//! as `<file>.bak` if it still matches its checksum: the backup is always
+//! one this code wrote in full.
+//!
+//! [`read`] falls back to that backup, with a warning, when the file is
+//! missing, unreadable or does not parse, as after a crash on a filesystem
+//! that does not rename atomically or damage on disk. A file that parses
+//! is used even if it no longer matches its checksum, since the config is
+//! meant to be edited by hand.
+//!
+//! Files kept per item (manifests, resume states, sync records) are only
+//! replaced atomically with [`write_atomic`]; losing one costs a transfer
+//! at most. Append-only logs (history, audit log, metrics) are never
+//! rewritten and skip lines they cannot parse.
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::ffi::OsString;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Replace `path` with `contents`, keeping the version it held as the
+/// last good one.
+pub fn write(path: &Path, contents: &[u8]) -> Result<()> {
+    let checksum = sibling(path, ".sha256");
+    let tmp = sibling(path, ".tmp");
+    write_synced(&tmp, contents).with_context(|| format!("Failed to write {}", tmp.display()))?;
+    if intact(path, &checksum) {
+        // The checksum moves first, so a crash in between leaves the file
+        // in place rather than only a backup failing a stale checksum.
+        let backup_checksum = sibling(path, ".bak.sha256");
+        if std::fs::rename(&checksum, &backup_checksum).is_err() {
+            let _ = std::fs::remove_file(&backup_checksum);
+        }
+        std::fs::rename(path, sibling(path, ".bak"))
+            .with_context(|| format!("Failed to back up {}", path.display()))?;
+    }
+    std::fs::rename(&tmp, path).with_context(|| format!("Failed to replace {}", path.display()))?;
+    write_synced(&checksum, hex::encode(Sha256::digest(contents)).as_bytes())
+        .with_context(|| format!("Failed to write {}", checksum.display()))?;
+    sync_dir(path);
+    Ok(())
+}
+
+/// Parse the file at `path` with `parse`, or its last good version if it
+/// is missing or damaged. `None` if neither exists.
+pub fn read<T>(path: &Path, parse: impl Fn(&[u8]) -> Result<T>) -> Result<Option<T>> {
+    let error = match std::fs::read(path) {
+        Ok(bytes) => match parse(&bytes) {
+            Ok(value) => return Ok(Some(value)),
+            Err(e) => Some(e.context(format!("Invalid {}", path.display()))),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => Some(anyhow::Error::new(e).context(format!("Failed to read {}", path.display()))),
+    };
+
+    let backup = sibling(path, ".bak");
+    if intact(&backup, &sibling(path, ".bak.sha256")) {
+        if let Some(value) = std::fs::read(&backup).ok().and_then(|bytes| parse(&bytes).ok()) {
+            match &error {
+                Some(e) => tracing::warn!("{:#}; using the last good version {}", e, backup.display()),
+                None => tracing::warn!("{} is missing; using the last good version {}", path.display(), backup.display()),
+            }
+            return Ok(Some(value));
+        }
+    }
+    error.map_or(Ok(None), Err)
+}
+
+/// Replace `path` with `contents` through a synced temporary file, without
+/// a checksum or backup.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp = sibling(path, ".tmp");
+    write_synced(&tmp, contents).with_context(|| format!("Failed to write {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("Failed to replace {}", path.display()))?;
+    sync_dir(path);
+    Ok(())
+}
+
+/// Whether `path` exists and matches the checksum in `checksum`. Files
+/// from before checksums were kept have none and count as intact.
+fn intact(path: &Path, checksum: &Path) -> bool {
+    let Ok(bytes) = std::fs::read(path) else { return false };
+    match std::fs::read_to_string(checksum) {
+        Ok(expected) => expected.trim() == hex::encode(Sha256::digest(&bytes)),
+        Err(e) => e.kind() == std::io::ErrorKind::NotFound,
+    }
+}
+
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn write_synced(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut file = std::fs::File::create(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+/// Make a rename in `path`'s directory durable. Best effort: not every
+/// platform can open a directory.
+fn sync_dir(path: &Path) {
+    #[cfg(unix)]
+    if let Some(dir) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        let _ = std::fs::File::open(dir).and_then(|d| d.sync_all());
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn parse(bytes: &[u8]) -> Result<u32> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+
+    #[test]
+    fn test_fallback_to_last_good_version() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let path = tmp.path().join("state.json");
+        assert_eq!(read(&path, parse)?, None);
+
+        write(&path, b"1")?;
+        write(&path, b"2")?;
+        assert_eq!(read(&path, parse)?, Some(2));
+        assert_eq!(std::fs::read(sibling(&path, ".bak"))?, b"1");
+
+        // Cut short: the previous version is used, and the damaged one is
+        // not kept as a backup when it is replaced.
+        std::fs::write(&path, b"\"tru")?;
+        assert_eq!(read(&path, parse)?, Some(1));
+        write(&path, b"3")?;
+        assert_eq!(std::fs::read(sibling(&path, ".bak"))?, b"1");
+
+        // Edited by hand: used as it is, though the checksum differs.
+        std::fs::write(&path, b"4")?;
+        assert_eq!(read(&path, parse)?, Some(4));
+
+        std::fs::remove_file(&path)?;
+        assert_eq!(read(&path, parse)?, Some(1));
+        // A backup that does not match its checksum is no fallback.
+        std::fs::write(sibling(&path, ".bak"), b"5")?;
+        assert_eq!(read(&path, parse)?, None);
+        std::fs::write(&path, b"x")?;
+        assert!(read(&path, parse).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,251 @@
+//! Pushes of content whose size is not known up front, such as a pipe.
+//!
+//! A push normally opens with the manifest, which lists every chunk and so
+//! needs the whole content hashed first. A stream (peers with `stream`)
+//! opens with a [`StreamHeader`] naming it instead, then sends
+//! [`StreamFrame::Chunk`]s in order as the source is read, each hashed on
+//! the way out. The receiver hashes each on the way in and acknowledges it
+//! with a [`ChunkAck`](crate::protocol::ChunkAck), which keeps the sender
+//! within `max_parallel_chunks` of it.
+//!
+//! Once the source ends, [`StreamFrame::End`] carries the complete
+//! manifest, signed by the sender. The receiver checks it against what it
+//! hashed ([`StreamHasher::check`]) and answers with a [`StreamReply`].
+//! Until then the chunks are only vouched for by the session: a receiver
+//! writing them out as they come, e.g. to standard output, only learns at
+//! the end that they add up to what the sender signed.
+//!
+//! Chunks are cut to a fixed size and their ids are never keyed. The
+//! manifest must still fit one frame: about 140 000 chunks, or 36 GiB at
+//! the default 256 KiB chunk size.
+
+use crate::chunking::MAX_FIXED_SIZE;
+use crate::handshake::MAX_FRAME_PLAINTEXT;
+use crate::keyed;
+use crate::manifest::Manifest;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Prefix marking a stream header frame. A bincode manifest starts with
+/// the filename length as a u64, which can never spell this.
+pub const STREAM_MAGIC: &[u8; 8] = b"OSSTRM01";
+
+/// Most chunks one stream can have: each takes 72 bytes of its manifest,
+/// which must fit a frame along with the rest.
+pub const MAX_STREAM_CHUNKS: usize = (MAX_FRAME_PLAINTEXT - 64 * 1024) / 72;
+
+/// Longest name a stream can be opened with, in bytes.
+const MAX_NAME_LEN: usize = 4096;
+
+/// Opens a stream in place of a manifest.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StreamHeader {
+    /// Name the content is received under
+    pub filename: String,
+    /// Length of every chunk but the last
+    pub chunk_size: u32,
+}
+
+impl StreamHeader {
+    /// Refuse a chunk size whose chunks would not fit a frame, or a name
+    /// that would crowd out the manifest, e.g. from a peer.
+    pub fn check(&self) -> Result<()> {
+        if self.chunk_size == 0 || self.chunk_size as usize > MAX_FIXED_SIZE {
+            anyhow::bail!("Stream chunk size {} is outside 1..={}", self.chunk_size, MAX_FIXED_SIZE);
+        }
+        if self.filename.len() > MAX_NAME_LEN {
+            anyhow::bail!("Stream name is longer than {} bytes", MAX_NAME_LEN);
+        }
+        Ok(())
+    }
+
+    pub fn to_frame(&self) -> Result<Vec<u8>> {
+        Ok([&STREAM_MAGIC[..], &bincode::serialize(self)?].concat())
+    }
+
+    /// Decode `frame` if it is a stream header; `None` if it is not one.
+    pub fn from_frame(frame: &[u8]) -> Option<Result<Self>> {
+        let body = frame.strip_prefix(STREAM_MAGIC)?;
+        Some(bincode::deserialize(body).context("Malformed stream header"))
+    }
+}
+
+/// What follows a [`StreamHeader`], until the end.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum StreamFrame {
+    /// The next chunk
+    Chunk(Vec<u8>),
+    /// The source ended; the signed manifest of everything sent, encoded
+    /// as by [`Manifest::to_bytes`]
+    End(Vec<u8>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum StreamReply {
+    Received,
+    /// The manifest did not match the chunks; the reason is for display
+    Refused(String),
+}
+
+/// The manifest of a stream, built up a chunk at a time on either side.
+#[derive(Debug, Clone)]
+pub struct StreamHasher {
+    filename: String,
+    chunk_size: u32,
+    size: u64,
+    whole: Sha256,
+    chunk_hashes: Vec<String>,
+}
+
+impl StreamHasher {
+    pub fn new(header: &StreamHeader) -> Self {
+        Self {
+            filename: header.filename.clone(),
+            chunk_size: header.chunk_size,
+            size: 0,
+            whole: Sha256::new(),
+            chunk_hashes: Vec::new(),
+        }
+    }
+
+    /// Note the next chunk and return its id.
+    pub fn add(&mut self, data: &[u8]) -> String {
+        self.whole.update(data);
+        self.size += data.len() as u64;
+        let id = keyed::chunk_id(None, data);
+        self.chunk_hashes.push(id.clone());
+        id
+    }
+
+    /// Bytes added so far.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Chunks added so far.
+    pub fn chunks(&self) -> usize {
+        self.chunk_hashes.len()
+    }
+
+    /// The unsigned manifest of everything added.
+    pub fn manifest(&self) -> Manifest {
+        Manifest {
+            filename: self.filename.clone(),
+            size: self.size,
+            file_hash: hex::encode(self.whole.clone().finalize()),
+            chunk_hashes: self.chunk_hashes.clone(),
+            files: Vec::new(),
+            sender_sig: None,
+            sender_pubkey: None,
+            metadata: Vec::new(),
+            chunk_size: Some(self.chunk_size),
+            preview: None,
+        }
+    }
+
+    /// Check that the sender's `manifest` lists exactly the chunks added,
+    /// under the name the stream was opened with. Its signature is the
+    /// caller's to check.
+    pub fn check(&self, manifest: &Manifest) -> Result<()> {
+        let ours = self.manifest();
+        if manifest.filename != ours.filename {
+            anyhow::bail!("Stream was opened as {} but its manifest names {}", ours.filename, manifest.filename);
+        }
+        if manifest.size != ours.size || manifest.chunk_hashes != ours.chunk_hashes {
+            anyhow::bail!(
+                "Stream manifest lists {} bytes in {} chunks, but {} bytes in {} chunks arrived",
+                manifest.size, manifest.chunk_hashes.len(), ours.size, ours.chunk_hashes.len(),
+            );
+        }
+        if manifest.file_hash != ours.file_hash || !manifest.files.is_empty() || manifest.chunk_size != ours.chunk_size {
+            anyhow::bail!("Stream manifest does not match the content sent");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Identity;
+    use crate::sim::fixture::client;
+    use crate::sim::{pair, LinkConfig};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_hashed_stream_matches_manifest() -> Result<()> {
+        let header = StreamHeader { filename: "backup.tar".into(), chunk_size: 4 };
+        header.check()?;
+        let (mut sender, mut receiver) = (StreamHasher::new(&header), StreamHasher::new(&header));
+        for chunk in [&b"abcd"[..], b"efgh", b"ij"] {
+            assert_eq!(sender.add(chunk), receiver.add(chunk));
+        }
+        assert_eq!((receiver.size(), receiver.chunks()), (10, 3));
+
+        // The same manifest as a file of that content, once signed.
+        let mut manifest = sender.manifest();
+        manifest.sign_prehashed(&Identity::from(ed25519_dalek::SigningKey::from_bytes(&[6; 32])))?;
+        manifest.verify()?;
+        receiver.check(&Manifest::from_bytes(&manifest.to_bytes()?, true)?)?;
+        assert_eq!(manifest.chunking(), Some(crate::chunking::Chunking::Fixed(4)));
+
+        // Anything else is refused.
+        let mut short = receiver.clone();
+        short.chunk_hashes.pop();
+        assert!(short.check(&manifest).is_err());
+        let renamed = Manifest { filename: "other.tar".into(), ..manifest.clone() };
+        assert!(receiver.check(&renamed).is_err());
+        let rehashed = Manifest { file_hash: "00".repeat(32), ..manifest };
+        assert!(receiver.check(&rehashed).is_err());
+
+        assert!(StreamHeader { chunk_size: 0, ..header.clone() }.check().is_err());
+        assert!(StreamHeader { chunk_size: MAX_FIXED_SIZE as u32 + 1, ..header.clone() }.check().is_err());
+        assert!(StreamHeader { filename: "x".repeat(MAX_NAME_LEN + 1), ..header }.check().is_err());
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stream_of_unknown_size() {
+        use crate::transfer::{TransferHandle, TransferOutcome};
+        use crate::Accepted;
+        use tokio::io::AsyncReadExt;
+
+        let (da, db) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let sender = client(&da, 1);
+        let payload: Vec<u8> = (0..200_000u32).map(|i| (i * 7 % 251) as u8).collect();
+
+        // Stored like a push, then written out as a file.
+        let receiver = client(&db, 2);
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let handle = TransferHandle::new();
+        let (sent, accepted) = tokio::join!(sender.send_stream(a, &payload[..], "backup.tar", &handle), receiver.accept(b));
+        let (manifest, outcome) = sent.unwrap();
+        assert!(matches!(outcome, TransferOutcome::Completed));
+        assert_eq!((manifest.size, manifest.chunk_hashes.len()), (200_000, 4));
+        let Accepted::Received(received) = accepted.unwrap() else { panic!("not stored") };
+        assert_eq!(received.id(), manifest.id());
+        let output = db.path().join("backup.tar");
+        receiver.write_file(&received, &output).await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), payload);
+
+        // Or written to the stream output as it arrives.
+        let (out, mut read_back) = tokio::io::duplex(1 << 20);
+        let receiver = client(&db, 2).with_stream_output(out);
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (sent, accepted) = tokio::join!(sender.send_stream(a, &payload[..], "backup.tar", &handle), receiver.accept(b));
+        sent.unwrap();
+        assert!(matches!(accepted.unwrap(), Accepted::Streamed(m) if m.size == 200_000));
+        drop(receiver);
+        let mut streamed = Vec::new();
+        read_back.read_to_end(&mut streamed).await.unwrap();
+        assert_eq!(streamed, payload);
+
+        // A receiver with a size limit stops the stream once it is over.
+        let mut receiver = client(&db, 2);
+        receiver.cfg.max_incoming_bytes = 100_000;
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (sent, accepted) = tokio::join!(sender.send_stream(a, &payload[..], "backup.tar", &handle), receiver.accept(b));
+        assert!(sent.is_err() && accepted.is_err());
+    }
+}
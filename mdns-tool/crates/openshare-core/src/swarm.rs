@@ -0,0 +1,428 @@
+//! Multi-source download of a known manifest.
+//!
+//! Any trusted peer that publishes a manifest can serve partial pulls of
+//! it: the manifest ID leaves out the signature, and each peer signs the
+//! copy it serves. A [`SwarmFetcher`] spreads the missing chunks of a
+//! manifest over several such peers. Each peer takes runs of consecutive
+//! chunk indices from a shared queue, sized by its [`PeerScore`] relative
+//! to the others, and is dropped mid-download once it falls far behind the
+//! best peer or fails most of what it is asked for. Chunks a peer did not
+//! deliver go to the next round, which the remaining peers share.
+//!
+//! A score combines throughput, error rate and connection time, each a
+//! moving average over that peer's requests. Scores older than
+//! [`STALE_AFTER`] no longer count, so a peer that was slow yesterday
+//! starts the next download as an equal.
+
+use crate::client::{Client, FETCH_RETRY_DELAY, FETCH_ROUNDS};
+use crate::manifest::Manifest;
+use crate::vfs::ChunkFetcher;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use storage::Storage;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+
+/// Chunks per request for a peer scoring as well as the average.
+pub const SWARM_BATCH: usize = 8;
+
+/// Largest batch a fast peer is given at once.
+const MAX_BATCH: usize = 4 * SWARM_BATCH;
+
+/// Weight of the newest request in the moving averages.
+const SMOOTHING: f64 = 0.3;
+
+/// Requests a peer makes before it can be dropped.
+const MIN_REQUESTS: u32 = 2;
+
+/// A peer scoring below this fraction of the best peer is dropped...
+const DROP_BELOW: f64 = 0.25;
+
+/// ...as is one failing more than this share of the chunks it is asked for.
+const MAX_ERROR_RATE: f64 = 0.5;
+
+/// Age after which a peer's score is forgotten.
+pub const STALE_AFTER: Duration = Duration::from_secs(10 * 60);
+
+/// How one source has been doing, as shown by [`SwarmFetcher::stats`].
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct PeerScore {
+    /// Chunk bytes per second of request time
+    pub throughput: f64,
+    /// Share of requested chunks that did not arrive intact
+    pub error_rate: f64,
+    /// Time to open a connection, in milliseconds
+    pub rtt_ms: f64,
+    pub requests: u32,
+    /// Chunks and chunk bytes received in total
+    pub chunks: u64,
+    pub bytes: u64,
+    /// Unix timestamp of the last request
+    pub last_seen: u64,
+    /// Left out of the rest of the current download
+    pub dropped: bool,
+}
+
+/// One request to a source, as fed to [`Scoreboard::record`].
+#[derive(Debug, Clone, Copy)]
+pub struct Outcome {
+    pub requested: usize,
+    pub delivered: usize,
+    pub bytes: u64,
+    pub elapsed: Duration,
+    /// Time to connect; `None` if the connection failed
+    pub rtt: Option<Duration>,
+}
+
+impl PeerScore {
+    fn record(&mut self, outcome: &Outcome, now: u64) {
+        let fresh = self.requests == 0;
+        let blend = |avg: &mut f64, sample: f64| {
+            *avg = if fresh { sample } else { *avg + SMOOTHING * (sample - *avg) };
+        };
+        let secs = outcome.elapsed.as_secs_f64().max(1e-3);
+        blend(&mut self.throughput, outcome.bytes as f64 / secs);
+        let failed = outcome.requested.saturating_sub(outcome.delivered);
+        blend(&mut self.error_rate, failed as f64 / outcome.requested.max(1) as f64);
+        if let Some(rtt) = outcome.rtt {
+            blend(&mut self.rtt_ms, rtt.as_secs_f64() * 1000.0);
+        }
+        self.requests += 1;
+        self.chunks += outcome.delivered as u64;
+        self.bytes += outcome.bytes;
+        self.last_seen = now;
+    }
+
+    /// Worth as a source; higher is better.
+    pub fn value(&self) -> f64 {
+        self.throughput * (1.0 - self.error_rate) / (1.0 + self.rtt_ms / 1000.0)
+    }
+
+    fn is_current(&self, now: u64) -> bool {
+        self.requests > 0 && now.saturating_sub(self.last_seen) < STALE_AFTER.as_secs()
+    }
+}
+
+/// Scores of the sources of a [`SwarmFetcher`], by source name.
+#[derive(Debug, Default)]
+pub struct Scoreboard {
+    peers: BTreeMap<String, PeerScore>,
+}
+
+impl Scoreboard {
+    pub fn record(&mut self, peer: &str, outcome: &Outcome, now: u64) {
+        let score = self.peers.entry(peer.to_string()).or_default();
+        if !score.is_current(now) {
+            // Start over rather than average with old conditions.
+            *score = PeerScore { dropped: score.dropped, ..PeerScore::default() };
+        }
+        score.record(outcome, now);
+    }
+
+    pub fn get(&self, peer: &str) -> Option<&PeerScore> {
+        self.peers.get(peer)
+    }
+
+    /// Chunks to give `peer` in its next request: [`SWARM_BATCH`] scaled
+    /// by how its score compares with the average of the active peers.
+    pub fn batch_len(&self, peer: &str, now: u64) -> usize {
+        let Some(score) = self.peers.get(peer).filter(|s| s.is_current(now)) else {
+            return SWARM_BATCH;
+        };
+        let active: Vec<f64> = self.peers.values()
+            .filter(|s| !s.dropped && s.is_current(now))
+            .map(PeerScore::value)
+            .collect();
+        let mean = active.iter().sum::<f64>() / active.len().max(1) as f64;
+        if mean <= 0.0 {
+            return SWARM_BATCH;
+        }
+        ((SWARM_BATCH as f64 * score.value() / mean).round() as usize).clamp(1, MAX_BATCH)
+    }
+
+    /// Whether `peer` should be left out of the rest of the download. The
+    /// last active peer is never dropped.
+    pub fn should_drop(&self, peer: &str, now: u64) -> bool {
+        let Some(score) = self.peers.get(peer).filter(|s| s.is_current(now)) else {
+            return false;
+        };
+        let others: Vec<&PeerScore> = self.peers.iter()
+            .filter(|(name, s)| name.as_str() != peer && !s.dropped)
+            .map(|(_, s)| s)
+            .collect();
+        if score.requests < MIN_REQUESTS || others.is_empty() {
+            return false;
+        }
+        let best = others.iter().filter(|s| s.is_current(now)).map(|s| s.value()).fold(0.0, f64::max);
+        score.error_rate > MAX_ERROR_RATE || score.value() < DROP_BELOW * best
+    }
+
+    fn set_dropped(&mut self, peer: &str, dropped: bool) {
+        self.peers.entry(peer.to_string()).or_default().dropped = dropped;
+    }
+
+    /// Every source with its score, by name.
+    pub fn snapshot(&self) -> Vec<(String, PeerScore)> {
+        self.peers.iter().map(|(name, s)| (name.clone(), s.clone())).collect()
+    }
+}
+
+/// Fetches missing chunks of a manifest from several peers at once; see
+/// the [module docs](self). Each source is a name (used in logs and
+/// stats, e.g. the peer's address) and a way to connect to it.
+pub struct SwarmFetcher<S, C> {
+    client: Arc<Client<S>>,
+    sources: Vec<(String, Arc<C>)>,
+    scores: Arc<Mutex<Scoreboard>>,
+}
+
+impl<S, C> SwarmFetcher<S, C> {
+    pub fn new(client: Arc<Client<S>>, sources: Vec<(String, C)>) -> Self {
+        Self {
+            client,
+            sources: sources.into_iter().map(|(name, connect)| (name, Arc::new(connect))).collect(),
+            scores: Arc::default(),
+        }
+    }
+
+    /// Scores of all sources so far, for finding out why a fetch was slow.
+    pub fn stats(&self) -> Vec<(String, PeerScore)> {
+        self.scores.lock().unwrap().snapshot()
+    }
+}
+
+/// Work shared by the sources during one round.
+#[derive(Default)]
+struct Round {
+    queue: Mutex<VecDeque<u32>>,
+    /// Chunks that did not arrive, for the next round
+    missed: Mutex<Vec<u32>>,
+}
+
+#[async_trait::async_trait]
+impl<S, C, F, T> ChunkFetcher for SwarmFetcher<S, C>
+where
+    S: Storage + Send + Sync + 'static,
+    C: Fn() -> F + Send + Sync + 'static,
+    F: std::future::Future<Output = Result<T>> + Send + 'static,
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    async fn fetch(&self, manifest: &Manifest, missing: &[String]) -> Result<()> {
+        if self.sources.is_empty() {
+            anyhow::bail!("No peers to fetch {} chunk(s) from", missing.len());
+        }
+        let mut pending = Vec::new();
+        for hash in missing {
+            let index = manifest.chunk_hashes.iter().position(|h| h == hash)
+                .with_context(|| format!("Chunk {} is not part of the manifest", hash))?;
+            pending.push(index as u32);
+        }
+        pending.sort_unstable();
+        pending.dedup();
+        {
+            let mut scores = self.scores.lock().unwrap();
+            for (name, _) in &self.sources {
+                scores.set_dropped(name, false);
+            }
+        }
+
+        let manifest = Arc::new(manifest.clone());
+        for round in 0..FETCH_ROUNDS {
+            if round > 0 {
+                tracing::info!("Retrying {} chunk(s) that did not arrive intact", pending.len());
+                tokio::time::sleep(FETCH_RETRY_DELAY * 2u32.pow(round - 1)).await;
+            }
+            let shared = Arc::new(Round { queue: Mutex::new(pending.into()), ..Round::default() });
+            let mut workers = JoinSet::new();
+            for (name, connect) in &self.sources {
+                if self.scores.lock().unwrap().get(name).is_some_and(|s| s.dropped) {
+                    continue;
+                }
+                workers.spawn(work(
+                    self.client.clone(), name.clone(), connect.clone(),
+                    manifest.clone(), shared.clone(), self.scores.clone(),
+                ));
+            }
+            while let Some(result) = workers.join_next().await {
+                result??;
+            }
+
+            pending = std::mem::take(&mut *shared.missed.lock().unwrap());
+            pending.extend(shared.queue.lock().unwrap().drain(..));
+            pending.sort_unstable();
+            if pending.is_empty() {
+                return Ok(());
+            }
+        }
+        anyhow::bail!("{} chunk(s) failed verification {} times", pending.len(), FETCH_ROUNDS)
+    }
+}
+
+/// Take batches from the round's queue and fetch them from one source
+/// until the queue is empty or the source is dropped.
+async fn work<S, C, F, T>(
+    client: Arc<Client<S>>,
+    name: String,
+    connect: Arc<C>,
+    manifest: Arc<Manifest>,
+    round: Arc<Round>,
+    scores: Arc<Mutex<Scoreboard>>,
+) -> Result<()>
+where
+    S: Storage + Send + Sync + 'static,
+    C: Fn() -> F + Send + Sync,
+    F: std::future::Future<Output = Result<T>> + Send,
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    loop {
+        let now = unix_secs(client.env.now());
+        let batch: Vec<u32> = {
+            let mut scores = scores.lock().unwrap();
+            if scores.should_drop(&name, now) {
+                tracing::info!("Dropping slow or failing source {} from the download", name);
+                scores.set_dropped(&name, true);
+                return Ok(());
+            }
+            let len = scores.batch_len(&name, now);
+            let mut queue = round.queue.lock().unwrap();
+            let len = len.min(queue.len());
+            queue.drain(..len).collect()
+        };
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let started = Instant::now();
+        let (result, rtt) = match connect().await {
+            Ok(transport) => {
+                let rtt = started.elapsed();
+                (client.request_known(transport, &manifest, batch.clone()).await, Some(rtt))
+            }
+            Err(e) => (Err(e.into()), None),
+        };
+        let elapsed = started.elapsed();
+        if let Err(e) = &result {
+            tracing::warn!("{} did not serve {} chunk(s): {:#}", name, batch.len(), e);
+        }
+
+        let mut missed = Vec::new();
+        for &index in &batch {
+            if !client.storage.has_chunk(&manifest.chunk_hashes[index as usize]).await? {
+                missed.push(index);
+            }
+        }
+        let outcome = Outcome {
+            requested: batch.len(),
+            delivered: batch.len() - missed.len(),
+            bytes: result.map_or(0, |(_, bytes)| bytes),
+            elapsed,
+            rtt,
+        };
+        scores.lock().unwrap().record(&name, &outcome, unix_secs(client.env.now()));
+        round.missed.lock().unwrap().extend(missed);
+    }
+}
+
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::fixture::client;
+    use crate::sim::{pair, LinkConfig};
+    use storage::LocalStorage;
+    use tempfile::TempDir;
+
+    fn outcome(delivered: usize, bytes: u64, millis: u64) -> Outcome {
+        Outcome {
+            requested: 8,
+            delivered,
+            bytes,
+            elapsed: Duration::from_millis(millis),
+            rtt: Some(Duration::from_millis(10)),
+        }
+    }
+
+    #[test]
+    fn test_scores_size_batches_and_drop_laggards() {
+        let mut board = Scoreboard::default();
+        let now = 1_000_000;
+        assert_eq!(board.batch_len("a", now), SWARM_BATCH);
+
+        // "a" is four times as fast as "b" and gets bigger batches.
+        for _ in 0..2 {
+            board.record("a", &outcome(8, 800_000, 1000), now);
+            board.record("b", &outcome(8, 200_000, 1000), now);
+        }
+        assert!(board.batch_len("a", now) > SWARM_BATCH && board.batch_len("b", now) < SWARM_BATCH);
+        assert!(!board.should_drop("a", now) && !board.should_drop("b", now));
+
+        // "c" delivers nothing and is dropped once it has had its chances;
+        // "b" alone is never dropped.
+        board.record("c", &outcome(0, 0, 1000), now);
+        assert!(!board.should_drop("c", now));
+        board.record("c", &outcome(0, 0, 1000), now);
+        assert!(board.should_drop("c", now));
+        board.set_dropped("a", true);
+        board.set_dropped("c", true);
+        board.record("b", &outcome(0, 0, 1000), now);
+        assert!(!board.should_drop("b", now));
+
+        // Old scores are forgotten.
+        let later = now + STALE_AFTER.as_secs();
+        assert!(!board.should_drop("c", later));
+        assert_eq!(board.batch_len("b", later), SWARM_BATCH);
+        board.record("b", &outcome(8, 100, 1000), later);
+        assert_eq!(board.get("b").unwrap().requests, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_swarm_fetch_routes_around_bad_source() {
+        use crate::vfs::ChunkFetcher;
+        use std::sync::Arc;
+        use storage::Storage;
+
+        let (good_dir, bad_dir, dst) = (TempDir::new().unwrap(), TempDir::new().unwrap(), TempDir::new().unwrap());
+        let input = good_dir.path().join("input.bin");
+        let payload: Vec<u8> = (0..1_300_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&input, &payload).unwrap();
+
+        // Both peers publish the same manifest; only one has the real chunks.
+        let good = Arc::new(client(&good_dir, 1));
+        let bad = Arc::new(client(&bad_dir, 3));
+        let manifest = good.import_file(&input).await.unwrap();
+        good.publish(&manifest).unwrap();
+        bad.publish(&manifest).unwrap();
+        for hash in &manifest.chunk_hashes {
+            bad.storage.put_chunk_as(hash, b"not the chunk").await.unwrap();
+        }
+
+        let source = |server: Arc<Client<LocalStorage>>| {
+            move || {
+                let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+                let server = server.clone();
+                tokio::spawn(async move { server.accept(b).await });
+                async move { Ok(a) }
+            }
+        };
+        let fetcher = Arc::new(client(&dst, 2));
+        let swarm = SwarmFetcher::new(fetcher.clone(), vec![
+            ("good".to_string(), source(good.clone())),
+            ("bad".to_string(), source(bad.clone())),
+        ]);
+
+        swarm.fetch(&manifest, &manifest.chunk_hashes).await.unwrap();
+        assert!(fetcher.missing_chunks(&manifest).await.unwrap().is_empty());
+        let stats = swarm.stats();
+        let (good_score, bad_score) = (&stats[1].1, &stats[0].1);
+        assert_eq!(good_score.chunks as usize, manifest.chunk_hashes.len());
+        assert!(good_score.throughput > 0.0 && good_score.error_rate < 0.5);
+        assert_eq!((bad_score.chunks, bad_score.error_rate), (0, 1.0));
+    }
+}
@@ -0,0 +1,248 @@
+//! Renames and moves when a directory is received again.
+//!
+//! After a directory manifest is written out, a [`SyncRecord`] of it is
+//! kept in the data directory: the manifest plus the size and modification
+//! time of each file as written. When a newer version of the tree arrives
+//! at the same place, [`SyncRecord::plan`] pairs files of the new manifest
+//! with files on disk holding the same chunks, at the same path or, for
+//! files that were renamed or moved, at another one. Those files are
+//! renamed into place (or left alone) rather than written again, and the
+//! receiver reports their chunks as held in the chunk probe, so
+//! reorganizing a large folder sends little more than the new manifest.
+//!
+//! A file only qualifies while its size and modification time are still
+//! those recorded, so local edits are never taken for the old content, and
+//! reused files count towards the manifest's `file_hash` check like any
+//! written file.
+
+use crate::Manifest;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// Directory of sync records inside the data directory.
+pub const SYNC_DIR: &str = "sync";
+
+/// The directory manifest last written at `path`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncRecord {
+    pub path: PathBuf,
+    pub manifest: Manifest,
+    /// Each file as written, by path relative to `path`
+    pub files: BTreeMap<String, FileStamp>,
+}
+
+/// What a file looked like when it was written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub size: u64,
+    /// Modification time in nanoseconds since the Unix epoch
+    pub modified: u64,
+}
+
+impl FileStamp {
+    /// Stamp of the regular file at `path`, or `None` if there is none.
+    pub fn of(path: &Path) -> Result<Option<Self>> {
+        let meta = match std::fs::symlink_metadata(path) {
+            Ok(meta) if meta.is_file() => meta,
+            Ok(_) => return Ok(None),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+        };
+        let modified = meta.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+        Ok(Some(Self { size: meta.len(), modified }))
+    }
+}
+
+/// A file of the new manifest already on disk at `from` (both relative to
+/// the tree root); `from == to` for a file that stays where it is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Move {
+    pub from: String,
+    pub to: String,
+}
+
+impl SyncRecord {
+    /// Describe `manifest`, just written at `path`.
+    pub fn capture(manifest: &Manifest, path: &Path) -> Result<Self> {
+        let mut files = BTreeMap::new();
+        for entry in manifest.files.iter().filter(|e| !e.is_dir) {
+            if let Some(stamp) = FileStamp::of(&entry.resolve(path)?)? {
+                files.insert(entry.path.clone(), stamp);
+            }
+        }
+        Ok(Self { path: normalize(path), manifest: manifest.clone(), files })
+    }
+
+    /// The record for the tree at `path`, if one was kept.
+    pub fn load(data_dir: &Path, path: &Path) -> Result<Option<Self>> {
+        let source = record_path(data_dir, path);
+        let json = match std::fs::read_to_string(&source) {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", source.display())),
+        };
+        let record: Self = serde_json::from_str(&json)
+            .with_context(|| format!("parsing {}", source.display()))?;
+        Ok(Some(record))
+    }
+
+    pub fn save(&self, data_dir: &Path) -> Result<()> {
+        let target = record_path(data_dir, &self.path);
+        std::fs::create_dir_all(target.parent().expect("record has a parent"))?;
+        crate::state::write_atomic(&target, &serde_json::to_vec(self)?)
+    }
+
+    /// Files of `manifest` that can be taken from the tree on disk. A file
+    /// that stays at its path is kept there before any other file may be
+    /// moved onto it, and each file on disk is used at most once.
+    pub fn plan(&self, manifest: &Manifest) -> Vec<Move> {
+        let mut sources: HashMap<&[String], Vec<&str>> = HashMap::new();
+        for entry in self.manifest.files.iter().filter(|e| !e.is_dir && e.chunk_count > 0) {
+            let (Ok(chunks), Ok(path)) = (self.manifest.entry_chunks(entry), entry.resolve(&self.path)) else {
+                continue;
+            };
+            if self.files.get(&entry.path).is_some_and(|s| FileStamp::of(&path).ok().flatten() == Some(*s)) {
+                sources.entry(chunks).or_default().push(&entry.path);
+            }
+        }
+
+        let wanted: Vec<_> = manifest.files.iter()
+            .filter(|e| !e.is_dir && e.chunk_count > 0)
+            .filter_map(|e| Some((e.path.as_str(), manifest.entry_chunks(e).ok()?)))
+            .collect();
+        let mut moves = Vec::new();
+        let mut placed = vec![false; wanted.len()];
+        for (i, (path, chunks)) in wanted.iter().enumerate() {
+            let Some(found) = sources.get_mut(chunks) else { continue };
+            if let Some(pos) = found.iter().position(|from| from == path) {
+                found.swap_remove(pos);
+                moves.push(Move { from: path.to_string(), to: path.to_string() });
+                placed[i] = true;
+            }
+        }
+        for (i, (path, chunks)) in wanted.iter().enumerate() {
+            if placed[i] {
+                continue;
+            }
+            if let Some(from) = sources.get_mut(chunks).and_then(|found| found.pop()) {
+                moves.push(Move { from: from.to_string(), to: path.to_string() });
+            }
+        }
+        moves
+    }
+}
+
+/// Absolute form of `path` for keying records; the parent directory is
+/// resolved since the tree itself may not exist yet.
+fn normalize(path: &Path) -> PathBuf {
+    let parent = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    match (std::fs::canonicalize(parent), path.file_name()) {
+        (Ok(parent), Some(name)) => parent.join(name),
+        _ => path.to_path_buf(),
+    }
+}
+
+fn record_path(data_dir: &Path, path: &Path) -> PathBuf {
+    let key = hex::encode(Sha256::digest(normalize(path).to_string_lossy().as_bytes()));
+    data_dir.join(SYNC_DIR).join(format!("{}.json", key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::fixture::client;
+    use crate::sim::{pair, LinkConfig};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_plan_prefers_keeping_files_and_skips_edited_ones() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let root = tmp.path().join("share");
+        std::fs::create_dir_all(root.join("docs"))?;
+        std::fs::write(root.join("docs/a.txt"), b"alpha")?;
+        std::fs::write(root.join("b.txt"), b"beta")?;
+        std::fs::write(root.join("c.txt"), b"alpha")?;
+        std::fs::write(root.join("d.txt"), b"delta")?;
+        let old = Manifest::from_dir(&root, 16)?;
+        let record = SyncRecord::capture(&old, &root)?;
+        record.save(tmp.path())?;
+        let record = SyncRecord::load(tmp.path(), &root)?.expect("saved");
+
+        // b moves into docs/, one copy of "alpha" stays put while the other
+        // goes to e.txt, and an edited d.txt is not reused.
+        let new_root = tmp.path().join("new");
+        std::fs::create_dir_all(new_root.join("docs"))?;
+        std::fs::write(new_root.join("docs/a.txt"), b"alpha")?;
+        std::fs::write(new_root.join("docs/b.txt"), b"beta")?;
+        std::fs::write(new_root.join("e.txt"), b"alpha")?;
+        std::fs::write(new_root.join("d.txt"), b"delta")?;
+        let mut new = Manifest::from_dir(&new_root, 16)?;
+        new.filename = old.filename.clone();
+        std::fs::write(root.join("d.txt"), b"DELTA!")?;
+
+        let mut moves = record.plan(&new);
+        moves.sort_by(|a, b| a.to.cmp(&b.to));
+        let mv = |from: &str, to: &str| Move { from: from.into(), to: to.into() };
+        assert_eq!(moves, [mv("docs/a.txt", "docs/a.txt"), mv("b.txt", "docs/b.txt"), mv("c.txt", "e.txt")]);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_delta_sync_sends_only_edits() {
+        use crate::transfer::{TransferHandle, TransferOutcome};
+        use crate::TransferEvent;
+
+        let (src, dst, out) = (TempDir::new().unwrap(), TempDir::new().unwrap(), TempDir::new().unwrap());
+        let input = src.path().join("notes.db");
+        let mut state = 7u64;
+        let mut data: Vec<u8> = (0..1_000_000).map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 56) as u8
+        }).collect();
+        std::fs::write(&input, &data).unwrap();
+
+        let receiver = client(&dst, 2).with_output_dir(out.path());
+        let sync = || {
+            let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+            let sender = client(&src, 1).with_observer(tx);
+            let (input, receiver, out) = (input.clone(), &receiver, out.path());
+            async move {
+                let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+                let handle = TransferHandle::new();
+                let (sent, received) = tokio::join!(
+                    sender.sync_file(a, &input, &handle),
+                    receiver.accept_and_receive(b),
+                );
+                assert!(matches!(sent.unwrap().1, TransferOutcome::Completed));
+                let manifest = received.unwrap();
+                receiver.write_file(&manifest, &manifest.output_path(out).unwrap()).await.unwrap();
+                // Only what is at the destination may be reused.
+                receiver.collect_garbage().await.unwrap();
+                drop(sender);
+                let (mut chunks, mut bytes) = (0, 0);
+                while let Some(event) = rx.recv().await {
+                    if let TransferEvent::ChunkSent { bytes: n, .. } = event {
+                        chunks += 1;
+                        bytes += n;
+                    }
+                }
+                (chunks, bytes, manifest.chunk_hashes.len())
+            }
+        };
+        let (sent, bytes, total) = sync().await;
+        assert_eq!((sent, bytes), (total, data.len()));
+
+        // Bytes inserted near the start and overwritten further on only
+        // cost the chunks around them.
+        data.splice(1000..1000, *b"a new paragraph");
+        data[600_000..600_010].fill(0);
+        std::fs::write(&input, &data).unwrap();
+        let (sent, bytes, total) = sync().await;
+        assert!(sent <= 4 && bytes < data.len() / 4, "{} of {} chunks, {} bytes", sent, total, bytes);
+        assert_eq!(std::fs::read(out.path().join("notes.db")).unwrap(), data);
+    }
+}
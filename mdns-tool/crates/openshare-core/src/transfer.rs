@@ -0,0 +1,418 @@
+//! Pausing and cancelling sends in flight.
+//!
+//! A [`TransferHandle`] passed to
+//! [`Client::send_manifest_with`](crate::Client::send_manifest_with) or
+//! [`Client::send_file_streaming_with`](crate::Client::send_file_streaming_with)
+//! is checked before each chunk goes out. While paused the sender holds
+//! the connection open and sends nothing; on abort it tells peers with the
+//! `cancel` feature with a [`Cancel`](crate::protocol::Cancel) frame, so the
+//! receiver stops waiting and keeps the chunks it already stored, and saves
+//! a [`ResumeState`]. Sending the manifest again later skips the chunks the
+//! receiver holds (with `chunk-probe`), which picks the transfer up where
+//! it stopped.
+//!
+//! A cancelled transfer is not an error: the `_with` methods return
+//! [`TransferOutcome::Cancelled`], and keep `Err` for transfers that
+//! failed. Elsewhere (a receiver whose sender cancelled, or the plain send
+//! methods) it fails with [`OpenShareError::Cancelled`].
+//!
+//! A transfer the peer gave up on fails with a [`PeerAborted`] naming its
+//! reason, for peers that send one. A push the receiver declined fails
+//! with [`Rejected`] on both sides.
+//!
+//! [`OpenShareError::Cancelled`]: crate::OpenShareError::Cancelled
+//!
+//! Every transfer has a [`TransferId`] that both peers know, carried by
+//! its events, log lines, history entry and errors.
+
+use crate::protocol::Abort;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Directory of resume states inside the data directory.
+pub const RESUME_DIR: &str = "resume";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Control {
+    Run,
+    Pause,
+    Abort,
+}
+
+/// Pauses, resumes or aborts a send. Clones control the same transfer.
+#[derive(Debug, Clone)]
+pub struct TransferHandle {
+    control: Arc<watch::Sender<Control>>,
+}
+
+impl Default for TransferHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TransferHandle {
+    pub fn new() -> Self {
+        Self { control: Arc::new(watch::Sender::new(Control::Run)) }
+    }
+
+    /// Stop sending after the chunk in progress, keeping the connection.
+    pub fn pause(&self) {
+        self.set(Control::Pause);
+    }
+
+    /// Continue a paused transfer.
+    pub fn resume(&self) {
+        self.set(Control::Run);
+    }
+
+    /// Cancel the transfer before its next chunk, paused or not. Final.
+    pub fn abort(&self) {
+        self.control.send_replace(Control::Abort);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.control.borrow() == Control::Pause
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        *self.control.borrow() == Control::Abort
+    }
+
+    fn set(&self, control: Control) {
+        // Nothing undoes an abort.
+        self.control.send_if_modified(|current| {
+            let changed = *current != Control::Abort && *current != control;
+            if changed {
+                *current = control;
+            }
+            changed
+        });
+    }
+
+    /// Wait while paused; `true` if the transfer should be cancelled.
+    pub(crate) async fn checkpoint(&self) -> bool {
+        let mut control = self.control.subscribe();
+        let Ok(state) = control.wait_for(|c| *c != Control::Pause).await else {
+            return false;
+        };
+        *state == Control::Abort
+    }
+}
+
+/// Names one transfer on both ends, so that "transfer 5f0c…" in one
+/// machine's logs can be found in the other's. Derived from the session by
+/// [`Session::transfer_id`](crate::handshake::Session::transfer_id) and
+/// shown as a (version 4) UUID.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct TransferId([u8; 16]);
+
+impl TransferId {
+    /// Shape 16 random bytes as a version 4 UUID.
+    pub fn from_bytes(mut bytes: [u8; 16]) -> Self {
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        Self(bytes)
+    }
+}
+
+impl fmt::Display for TransferId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex = hex::encode(self.0);
+        write!(f, "{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+    }
+}
+
+impl fmt::Debug for TransferId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TransferId({})", self)
+    }
+}
+
+impl FromStr for TransferId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let bytes = hex::decode(s.replace('-', ""))
+            .ok()
+            .and_then(|bytes| <[u8; 16]>::try_from(bytes).ok())
+            .with_context(|| format!("Not a transfer ID: {}", s))?;
+        Ok(Self(bytes))
+    }
+}
+
+impl From<TransferId> for String {
+    fn from(id: TransferId) -> Self {
+        id.to_string()
+    }
+}
+
+impl TryFrom<String> for TransferId {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+/// How a send with a [`TransferHandle`] ended, if it did not fail.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferOutcome {
+    Completed,
+    Cancelled(Cancelled),
+}
+
+/// Who cancelled a transfer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CancelledBy {
+    /// This side, through its [`TransferHandle`]
+    Local,
+    /// The other side, by device ID
+    Peer(String),
+}
+
+/// A transfer stopped on request rather than by a failure.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cancelled {
+    pub by: CancelledBy,
+    /// Chunks delivered before it stopped
+    pub chunks_done: usize,
+    pub chunks_total: usize,
+}
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.by {
+            CancelledBy::Local => write!(f, "Transfer cancelled")?,
+            CancelledBy::Peer(device_id) => write!(f, "Transfer cancelled by {}", device_id)?,
+        }
+        write!(f, " after {} of {} chunks", self.chunks_done, self.chunks_total)
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// The peer gave up on a transfer after a failure on its side.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerAborted {
+    pub device_id: String,
+    pub abort: Abort,
+}
+
+impl std::fmt::Display for PeerAborted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} aborted the transfer: {}", self.device_id, self.abort)
+    }
+}
+
+impl std::error::Error for PeerAborted {}
+
+/// A push its receiver declined before any chunk was sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejected {
+    /// Device ID of the receiver, on the sending side; `None` on the
+    /// receiving side
+    pub by: Option<String>,
+    pub reason: String,
+}
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.by {
+            Some(device_id) => write!(f, "{} rejected the transfer: {}", device_id, self.reason),
+            None => write!(f, "Transfer rejected: {}", self.reason),
+        }
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+/// An aborted send, kept until the manifest is sent to the peer in full.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeState {
+    pub manifest_id: String,
+    pub filename: String,
+    /// Device ID of the receiver
+    pub peer: String,
+    /// File or directory the chunks were read from, for streamed sends
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<PathBuf>,
+    /// Chunk indices the receiver acknowledged storing
+    pub acked: Vec<u32>,
+    pub chunks_total: usize,
+    /// Unix time of the abort
+    pub saved_at: u64,
+}
+
+impl ResumeState {
+    /// The state saved for `manifest_id`, if any.
+    pub fn load(data_dir: &Path, manifest_id: &str) -> Result<Option<Self>> {
+        let source = state_path(data_dir, manifest_id);
+        let json = match std::fs::read_to_string(&source) {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", source.display())),
+        };
+        let state = serde_json::from_str(&json).with_context(|| format!("parsing {}", source.display()))?;
+        Ok(Some(state))
+    }
+
+    /// Every saved state, oldest first.
+    pub fn list(data_dir: &Path) -> Result<Vec<Self>> {
+        let dir = data_dir.join(RESUME_DIR);
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", dir.display())),
+        };
+        let mut states = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            let Some(id) = path.file_stem().and_then(|s| s.to_str()) else { continue };
+            if path.extension().is_some_and(|e| e == "json") {
+                states.extend(Self::load(data_dir, id)?);
+            }
+        }
+        states.sort_by_key(|s| s.saved_at);
+        Ok(states)
+    }
+
+    pub fn save(&self, data_dir: &Path) -> Result<()> {
+        let target = state_path(data_dir, &self.manifest_id);
+        std::fs::create_dir_all(target.parent().expect("state has a parent"))?;
+        crate::state::write_atomic(&target, &serde_json::to_vec_pretty(self)?)
+    }
+
+    /// Forget the state for `manifest_id`; `false` if there was none.
+    pub fn remove(data_dir: &Path, manifest_id: &str) -> Result<bool> {
+        let path = state_path(data_dir, manifest_id);
+        match std::fs::remove_file(&path) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e).with_context(|| format!("removing {}", path.display())),
+        }
+    }
+}
+
+fn state_path(data_dir: &Path, manifest_id: &str) -> PathBuf {
+    // Manifest IDs are hex, but the name must not escape the directory.
+    let name: String = manifest_id.chars().filter(|c| c.is_ascii_alphanumeric()).collect();
+    data_dir.join(RESUME_DIR).join(format!("{}.json", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::fixture::{client, Faulty};
+    use crate::sim::{pair, LinkConfig};
+    use crate::{Client, OpenShareError};
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pause_and_cancel_send() {
+        use crate::TransferEvent;
+        use std::sync::atomic::AtomicUsize;
+
+        let (src, dst) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let input = src.path().join("input.bin");
+        let payload: Vec<u8> = (0..200_000u32).map(|i| (i % 247) as u8).collect();
+        std::fs::write(&input, &payload).unwrap();
+
+        // Pause before the first chunk, cancel once two are out.
+        let handle = TransferHandle::new();
+        let sent_chunks = Arc::new(AtomicUsize::new(0));
+        let (control, count) = (handle.clone(), sent_chunks.clone());
+        let sender = client(&src, 1).with_observer(move |event: &TransferEvent| match event {
+            TransferEvent::ManifestSent { .. } => control.pause(),
+            TransferEvent::ChunkSent { .. } if count.fetch_add(1, Ordering::SeqCst) == 1 => control.abort(),
+            _ => {}
+        });
+        let receiver = client(&dst, 2);
+        let resumer = {
+            let (handle, sent_chunks) = (handle.clone(), sent_chunks.clone());
+            async move {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                assert!(handle.is_paused());
+                assert_eq!(sent_chunks.load(Ordering::SeqCst), 0);
+                handle.resume();
+            }
+        };
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (sent, received, ()) = tokio::join!(
+            sender.send_file_streaming_with(a, &input, false, &handle),
+            receiver.accept_and_receive(b),
+            resumer,
+        );
+        let (manifest, outcome) = sent.unwrap();
+        let expected = Cancelled { by: CancelledBy::Local, chunks_done: 2, chunks_total: 4 };
+        assert_eq!(outcome, TransferOutcome::Cancelled(expected));
+        let Err(OpenShareError::Cancelled(cancelled)) = received else { panic!("not cancelled") };
+        assert_eq!(cancelled.by, CancelledBy::Peer("dev1".into()));
+        assert_eq!(cancelled.chunks_done, 2);
+
+        let state = ResumeState::load(src.path(), &manifest.id()).unwrap().unwrap();
+        assert_eq!((state.peer.as_str(), state.acked.as_slice()), ("dev2", &[0, 1][..]));
+        assert_eq!(state.source.as_deref(), Some(input.as_path()));
+
+        // Sending again picks up where it stopped and forgets the state.
+        let sender = client(&src, 1);
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (sent, received) = tokio::join!(
+            sender.send_file_streaming(a, &input, false),
+            receiver.accept_and_receive(b),
+        );
+        sent.unwrap();
+        let received = received.unwrap();
+        assert!(ResumeState::load(src.path(), &manifest.id()).unwrap().is_none());
+        let output = dst.path().join("out.bin");
+        receiver.write_file(&received, &output).await.unwrap();
+        assert_eq!(std::fs::read(output).unwrap(), payload);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rejected_chunks_are_sent_again() {
+        let (src, dst, elsewhere) = (TempDir::new().unwrap(), TempDir::new().unwrap(), TempDir::new().unwrap());
+        let input = src.path().join("input.bin");
+        let payload: Vec<u8> = (0..200_000u32).map(|i| (i % 241) as u8).collect();
+        std::fs::write(&input, &payload).unwrap();
+
+        // The sender reads chunk 1 wrong the first time.
+        let plain = client(&src, 1);
+        let sender = Client::new((*plain.identity).clone(), Faulty::new(usize::MAX), plain.cfg.clone());
+        let receiver = client(&dst, 2);
+        let manifest = sender.import_file(&input).await.unwrap();
+        let bad = manifest.chunk_hashes[1].clone();
+        sender.storage.corrupt.lock().unwrap().insert(bad.clone(), 1);
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (sent, received) = tokio::join!(
+            sender.send_manifest_over(a, manifest.clone()),
+            receiver.accept_and_receive(b),
+        );
+        sent.unwrap();
+        let output = dst.path().join("out.bin");
+        receiver.write_file(&received.unwrap(), &output).await.unwrap();
+        assert_eq!(std::fs::read(output).unwrap(), payload);
+
+        // A chunk that never arrives intact fails the push once its
+        // retries are used up.
+        let retries = sender.cfg.chunk_retries as usize;
+        sender.storage.corrupt.lock().unwrap().insert(bad, retries + 1);
+        let receiver = client(&elsewhere, 2);
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (sent, received) = tokio::join!(
+            sender.send_manifest_over(a, manifest),
+            receiver.accept_and_receive(b),
+        );
+        let err = format!("{:#}", sent.unwrap_err());
+        assert!(err.contains(&format!("Receiver rejected chunk 1 {} times", retries + 1)), "{}", err);
+        assert!(matches!(received, Err(OpenShareError::PeerAborted(_))));
+    }
+}
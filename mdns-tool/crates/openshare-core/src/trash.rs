@@ -0,0 +1,177 @@
+//! Soft-delete area for received files that get replaced.
+//!
+//! Before a transfer overwrites an existing file, the old version is moved
+//! into `.openshare-trash` next to the received files, together with an
+//! `index.json` recording where it came from. Old entries are purged once
+//! they exceed the retention age or the trash exceeds its size limit;
+//! `openshare trash list/restore` browses and recovers them.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Directory name of the trash inside an output directory.
+pub const TRASH_DIR: &str = ".openshare-trash";
+
+const INDEX_FILE: &str = "index.json";
+
+/// Serializes index updates from concurrent transfers in this process.
+static INDEX_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrashEntry {
+    pub id: u64,
+    /// Path of the replaced file relative to the output directory
+    pub original: PathBuf,
+    pub size: u64,
+    /// Unix timestamp (seconds) when the file was moved to the trash
+    pub trashed_at: u64,
+}
+
+/// How long trashed files are kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retention {
+    pub max_age: Duration,
+    pub max_bytes: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Trash {
+    root: PathBuf,
+    retention: Retention,
+}
+
+impl Trash {
+    /// Trash for files received into `root`.
+    pub fn new(root: &Path, retention: Retention) -> Self {
+        Self { root: root.to_path_buf(), retention }
+    }
+
+    fn dir(&self) -> PathBuf {
+        self.root.join(TRASH_DIR)
+    }
+
+    fn entry_path(&self, id: u64) -> PathBuf {
+        self.dir().join(id.to_string())
+    }
+
+    pub fn list(&self) -> Result<Vec<TrashEntry>> {
+        let path = self.dir().join(INDEX_FILE);
+        Ok(crate::state::read(&path, |json| Ok(serde_json::from_slice(json)?))?.unwrap_or_default())
+    }
+
+    fn save(&self, entries: &[TrashEntry]) -> Result<()> {
+        crate::state::write(&self.dir().join(INDEX_FILE), serde_json::to_string_pretty(entries)?.as_bytes())
+    }
+
+    /// Move `path` (a file under the trash root) into the trash, then apply
+    /// retention to older entries.
+    pub fn move_in(&self, path: &Path) -> Result<TrashEntry> {
+        let _guard = INDEX_LOCK.lock().unwrap();
+        std::fs::create_dir_all(self.dir())?;
+
+        let mut entries = self.list()?;
+        let entry = TrashEntry {
+            id: entries.iter().map(|e| e.id + 1).max().unwrap_or(1),
+            original: path.strip_prefix(&self.root).unwrap_or(path).to_path_buf(),
+            size: std::fs::metadata(path)?.len(),
+            trashed_at: now_secs(),
+        };
+        std::fs::rename(path, self.entry_path(entry.id))
+            .with_context(|| format!("Failed to move {} to the trash", path.display()))?;
+        tracing::info!("Moved replaced {} to the trash (#{})", entry.original.display(), entry.id);
+        entries.push(entry.clone());
+
+        self.enforce(&mut entries, entry.id);
+        self.save(&entries)?;
+        Ok(entry)
+    }
+
+    /// Put entry `id` back where it came from. A file now occupying that
+    /// place is trashed in turn, so nothing is lost. Returns the restored
+    /// path.
+    pub fn restore(&self, id: u64) -> Result<PathBuf> {
+        let entry = self.list()?.into_iter().find(|e| e.id == id)
+            .ok_or_else(|| anyhow::anyhow!("No trash entry #{}", id))?;
+        let target = self.root.join(&entry.original);
+        if target.exists() {
+            self.move_in(&target)?;
+        }
+
+        let _guard = INDEX_LOCK.lock().unwrap();
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::rename(self.entry_path(id), &target)
+            .with_context(|| format!("Failed to restore {}", target.display()))?;
+        let mut entries = self.list()?;
+        entries.retain(|e| e.id != id);
+        self.save(&entries)?;
+        Ok(target)
+    }
+
+    /// Drop entries past the retention age, then the oldest ones until the
+    /// trash fits its size limit. Entry `keep` (the one just added) is
+    /// never dropped.
+    fn enforce(&self, entries: &mut Vec<TrashEntry>, keep: u64) {
+        let cutoff = now_secs().saturating_sub(self.retention.max_age.as_secs());
+        let mut total: u64 = entries.iter().map(|e| e.size).sum();
+        entries.sort_by_key(|e| e.id);
+        entries.retain(|e| {
+            let expired = e.trashed_at < cutoff || total > self.retention.max_bytes;
+            if e.id == keep || !expired {
+                return true;
+            }
+            total -= e.size;
+            if let Err(e) = std::fs::remove_file(self.entry_path(e.id)) {
+                tracing::warn!("Failed to purge trash entry: {}", e);
+            }
+            false
+        });
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_trash_restore_and_retention() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let retention = Retention { max_age: Duration::from_secs(3600), max_bytes: 10 };
+        let trash = Trash::new(tmp.path(), retention);
+        let file = tmp.path().join("dir/a.txt");
+        std::fs::create_dir_all(file.parent().unwrap())?;
+
+        std::fs::write(&file, b"old")?;
+        let first = trash.move_in(&file)?;
+        assert_eq!(first.original, Path::new("dir/a.txt"));
+        assert!(!file.exists());
+
+        // Restoring over a newer version trashes that one instead.
+        std::fs::write(&file, b"newer")?;
+        trash.restore(first.id)?;
+        assert_eq!(std::fs::read(&file)?, b"old");
+        let listed = trash.list()?;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].size, 5);
+
+        // Over the size limit the oldest entries go, but never the newest.
+        std::fs::write(&file, b"0123456789")?;
+        trash.move_in(&file)?;
+        let listed = trash.list()?;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].size, 10);
+        Ok(())
+    }
+}
@@ -0,0 +1,290 @@
+//! Persistent trust store pinning device IDs to Ed25519 public keys.
+//!
+//! Stored as `trusted_peers.json` under the data directory. Peers are added
+//! explicitly (`openshare trust add`) or, when `trust_on_first_use` is
+//! enabled, the first time they connect. A known device presenting a
+//! different key is rejected, unless it shows a verified rotation from the
+//! pinned key (see [`crate::keys::KeyRotation`]); the pin then moves on.
+//!
+//! Being trusted lets a device transfer; managing this device's daemon
+//! remotely (see [`crate::admin`]) takes the separate admin permission,
+//! granted with `openshare trust admin`.
+
+use crate::keys::{self, KeyRotation};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// File name of the trust store inside the data directory.
+pub const TRUST_STORE_FILE: &str = "trusted_peers.json";
+
+/// How a peer came to be trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrustSource {
+    Manual,
+    FirstUse,
+    /// Both users compared the short authentication string
+    Paired,
+    /// Certified by the account root key (see [`crate::account`])
+    Account,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrustedPeer {
+    pub device_id: String,
+    /// Hex-encoded Ed25519 public key
+    pub public_key: String,
+    pub source: TrustSource,
+    /// Unix timestamp (seconds) when the peer was pinned
+    pub added_at: u64,
+    /// May manage this device's daemon remotely
+    #[serde(default)]
+    pub admin: bool,
+}
+
+/// Result of checking a peer against the store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrustStatus {
+    Trusted,
+    Unknown,
+    /// The device is pinned to a different key
+    Mismatch { pinned: String },
+    /// The device is pinned to a key that was rotated to the one presented
+    Rotated { pinned: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct TrustStore {
+    path: PathBuf,
+    peers: BTreeMap<String, TrustedPeer>,
+}
+
+impl TrustStore {
+    /// An empty store that will be saved under `data_dir`.
+    pub fn empty(data_dir: &Path) -> Self {
+        Self { path: data_dir.join(TRUST_STORE_FILE), peers: BTreeMap::new() }
+    }
+
+    /// Load the store from `data_dir`; a missing file is an empty store.
+    pub fn load(data_dir: &Path) -> Result<Self> {
+        let path = data_dir.join(TRUST_STORE_FILE);
+        let list: Vec<TrustedPeer> = crate::state::read(&path, |json| Ok(serde_json::from_slice(json)?))?
+            .unwrap_or_default();
+        let peers = list.into_iter().map(|p| (p.device_id.clone(), p)).collect();
+        Ok(Self { path, peers })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let list: Vec<&TrustedPeer> = self.peers.values().collect();
+        crate::state::write(&self.path, serde_json::to_string_pretty(&list)?.as_bytes())
+    }
+
+    /// Pin `device_id` to `public_key`, replacing any previous pin. The
+    /// admin permission is kept only if the key is unchanged.
+    pub fn add(&mut self, device_id: &str, public_key: &[u8; 32], source: TrustSource) {
+        let added_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let public_key = hex::encode(public_key);
+        let admin = self.peers.get(device_id).is_some_and(|p| p.admin && p.public_key == public_key);
+        self.peers.insert(device_id.to_string(), TrustedPeer {
+            device_id: device_id.to_string(),
+            public_key,
+            source,
+            added_at,
+            admin,
+        });
+    }
+
+    /// Pin a peer as recorded elsewhere, keeping its source, time and
+    /// permission.
+    pub fn insert(&mut self, peer: TrustedPeer) {
+        self.peers.insert(peer.device_id.clone(), peer);
+    }
+
+    /// Grant or revoke the admin permission of a pinned peer; returns
+    /// whether it is pinned.
+    pub fn set_admin(&mut self, device_id: &str, admin: bool) -> bool {
+        match self.peers.get_mut(device_id) {
+            Some(peer) => {
+                peer.admin = admin;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove a pin; returns whether it existed.
+    pub fn remove(&mut self, device_id: &str) -> bool {
+        self.peers.remove(device_id).is_some()
+    }
+
+    pub fn get(&self, device_id: &str) -> Option<&TrustedPeer> {
+        self.peers.get(device_id)
+    }
+
+    pub fn list(&self) -> impl Iterator<Item = &TrustedPeer> {
+        self.peers.values()
+    }
+
+    pub fn check(&self, device_id: &str, public_key: &[u8; 32]) -> TrustStatus {
+        match self.peers.get(device_id) {
+            None => TrustStatus::Unknown,
+            Some(p) if p.public_key == hex::encode(public_key) => TrustStatus::Trusted,
+            Some(p) => TrustStatus::Mismatch { pinned: p.public_key.clone() },
+        }
+    }
+
+    /// Like [`check`](Self::check), but a device pinned to a key that
+    /// `history` shows rotating, in one step or several, to `public_key`
+    /// is [`TrustStatus::Rotated`] rather than a mismatch.
+    pub fn check_with_history(&self, device_id: &str, public_key: &[u8; 32], history: &[KeyRotation]) -> TrustStatus {
+        match self.check(device_id, public_key) {
+            TrustStatus::Mismatch { pinned }
+                if history.iter().any(|r| hex::encode(r.old_key) == pinned)
+                    && keys::verify_history(history, public_key).is_ok() =>
+            {
+                TrustStatus::Rotated { pinned }
+            }
+            status => status,
+        }
+    }
+
+    /// Move the pin of `device_id` to its rotated key (see
+    /// [`check_with_history`](Self::check_with_history)); returns whether
+    /// it is pinned. How the peer came to be trusted and its admin
+    /// permission carry over, since the pinned key signed the rotation.
+    pub fn follow_rotation(&mut self, device_id: &str, public_key: &[u8; 32]) -> bool {
+        match self.peers.get_mut(device_id) {
+            Some(peer) => {
+                peer.public_key = hex::encode(public_key);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Parse a hex-encoded 32-byte public key.
+pub fn parse_public_key(hex_key: &str) -> Result<[u8; 32]> {
+    let bytes = hex::decode(hex_key.trim()).context("public key is not valid hex")?;
+    bytes
+        .try_into()
+        .map_err(|b: Vec<u8>| anyhow::anyhow!("public key must be 32 bytes, got {}", b.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::fixture::client;
+    use crate::sim::{pair, LinkConfig};
+    use crate::{Identity, OpenShareError};
+    use ed25519_dalek::SigningKey;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_trust_store_roundtrip() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let mut store = TrustStore::load(tmp.path())?;
+        assert_eq!(store.check("laptop", &[1; 32]), TrustStatus::Unknown);
+
+        store.add("laptop", &[1; 32], TrustSource::Manual);
+        store.save()?;
+
+        let mut store = TrustStore::load(tmp.path())?;
+        assert_eq!(store.check("laptop", &[1; 32]), TrustStatus::Trusted);
+        assert!(matches!(store.check("laptop", &[2; 32]), TrustStatus::Mismatch { .. }));
+
+        // Admin is granted separately, and lost with the key.
+        assert!(!store.get("laptop").unwrap().admin);
+        assert!(!store.set_admin("phone", true));
+        assert!(store.set_admin("laptop", true));
+        store.add("laptop", &[1; 32], TrustSource::Paired);
+        assert!(store.get("laptop").unwrap().admin);
+        store.add("laptop", &[2; 32], TrustSource::Manual);
+        assert!(!store.get("laptop").unwrap().admin);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pairing_sas() {
+        let (dir_a, dir_b) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let (mut a, mut b) = (client(&dir_a, 1), client(&dir_b, 2));
+        a.cfg.trust_on_first_use = false;
+        b.cfg.trust_on_first_use = false;
+
+        let (link_a, link_b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (pa, pb) = tokio::join!(a.pair(link_a, true), b.pair(link_b, false));
+        let (pa, pb) = (pa.unwrap(), pb.unwrap());
+        assert_eq!(pa.code, pb.code);
+        assert_eq!(pa.code.len(), 7);
+        let key_b = pa.peer.public_key;
+
+        // One side declining leaves both unpaired.
+        let (ra, rb) = tokio::join!(a.complete_pairing(pa, true), b.complete_pairing(pb, false));
+        assert!(!ra.unwrap() && !rb.unwrap());
+        assert_eq!(a.trust.lock().unwrap().check("dev2", &key_b), TrustStatus::Unknown);
+
+        let (link_a, link_b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (pa, pb) = tokio::join!(a.pair(link_a, true), b.pair(link_b, false));
+        let (ra, rb) = tokio::join!(a.complete_pairing(pa.unwrap(), true), b.complete_pairing(pb.unwrap(), true));
+        assert!(ra.unwrap() && rb.unwrap());
+        let trust = a.trust.lock().unwrap();
+        assert_eq!(trust.check("dev2", &key_b), TrustStatus::Trusted);
+        assert_eq!(trust.get("dev2").unwrap().source, TrustSource::Paired);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pinned_fingerprint() {
+        use crate::handshake::HandshakeError;
+        use crate::TransferEvent;
+
+        let (src, dst) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let input = src.path().join("input.bin");
+        std::fs::write(&input, vec![3u8; 100_000]).unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let sender = client(&src, 1).with_observer(tx);
+        let receiver = client(&dst, 2);
+        let manifest = sender.import_file(&input).await.unwrap();
+
+        // An advertisement for dev2 with someone else's fingerprint: the
+        // handshake completes, but the manifest never goes out.
+        let spoofed = Identity::from(SigningKey::from_bytes(&[3; 32])).fingerprint();
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (sent, received) = tokio::join!(
+            sender.send_manifest_pinned(a, manifest.clone(), &spoofed),
+            receiver.accept_and_receive(b),
+        );
+        let error = sent.unwrap_err();
+        assert!(matches!(error, OpenShareError::Handshake(HandshakeError::UnexpectedPeer(_))));
+        assert!(received.is_err());
+        assert!(matches!(rx.try_recv(), Ok(TransferEvent::HandshakeComplete { .. })));
+        assert!(!std::iter::from_fn(|| rx.try_recv().ok()).any(|e| matches!(e, TransferEvent::ManifestSent { .. })));
+
+        // The receiver can pin the sender the same way.
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (sent, received) = tokio::join!(
+            sender.send_manifest_over(a, manifest.clone()),
+            receiver.accept_and_receive_pinned(b, &spoofed),
+        );
+        assert!(sent.is_err());
+        assert!(received.is_err());
+
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (advertised, full) = (receiver.identity.fingerprint(), sender.identity.full_fingerprint());
+        let (sent, received) = tokio::join!(
+            sender.send_manifest_pinned(a, manifest.clone(), &advertised),
+            receiver.accept_and_receive_pinned(b, &full),
+        );
+        sent.unwrap();
+        assert_eq!(received.unwrap().id(), manifest.id());
+    }
+}
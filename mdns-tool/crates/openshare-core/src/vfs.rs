@@ -0,0 +1,311 @@
+//! Read-only filesystem view of a share, backed by the chunk store.
+//!
+//! A [`ShareView`] answers the calls a FUSE or WinFsp binding makes
+//! (lookup, readdir, read at an offset) straight from the chunks named by a
+//! manifest, so a large share can be browsed without assembling it to
+//! disk. Reads load only the chunks they cover. Chunks missing from storage
+//! are handed to an optional [`ChunkFetcher`], which is expected to get
+//! them from a peer into storage; without one such reads fail. On Linux,
+//! [`crate::fuse`] mounts a view.
+
+use crate::manifest::{FileEntry, Manifest};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::sync::{Arc, Mutex};
+use storage::Storage;
+
+/// Source of chunks that are not in local storage yet.
+#[async_trait]
+pub trait ChunkFetcher: Send + Sync {
+    /// Put the chunks `missing` (hashes from `manifest`) into storage.
+    async fn fetch(&self, manifest: &Manifest, missing: &[String]) -> Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    File,
+    Dir,
+}
+
+/// A file or directory in the view.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    /// Path relative to the share root, `/`-separated; empty for the root
+    pub path: String,
+    pub kind: NodeKind,
+    pub size: u64,
+}
+
+impl Node {
+    /// Last path component; empty for the root.
+    pub fn name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or("")
+    }
+}
+
+pub struct ShareView {
+    manifest: Manifest,
+    storage: Arc<dyn Storage>,
+    fetcher: Option<Arc<dyn ChunkFetcher>>,
+    /// Size of every chunk but a file's last, as the manifest records it
+    /// or else learned from the first full chunk read
+    chunk_size: Mutex<Option<u64>>,
+}
+
+impl ShareView {
+    pub fn new(manifest: Manifest, storage: Arc<dyn Storage>) -> Self {
+        let chunk_size = Mutex::new(manifest.chunk_size.map(u64::from));
+        Self { manifest, storage, fetcher: None, chunk_size }
+    }
+
+    /// Fetch missing chunks through `fetcher` instead of failing the read.
+    pub fn with_fetcher(mut self, fetcher: Arc<dyn ChunkFetcher>) -> Self {
+        self.fetcher = Some(fetcher);
+        self
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// The share root: the file itself for a single-file manifest (named
+    /// after it), otherwise the top directory.
+    pub fn root(&self) -> Node {
+        if self.manifest.is_directory() {
+            Node { path: String::new(), kind: NodeKind::Dir, size: 0 }
+        } else {
+            Node { path: self.manifest.filename.clone(), kind: NodeKind::File, size: self.manifest.size }
+        }
+    }
+
+    /// Find the node at `path` (relative, `/`-separated).
+    pub fn lookup(&self, path: &str) -> Option<Node> {
+        let path = path.trim_matches('/');
+        if path.is_empty() || !self.manifest.is_directory() {
+            let root = self.root();
+            return (path.is_empty() || path == root.path).then_some(root);
+        }
+        self.manifest.files.iter().find(|e| e.path == path).map(node)
+    }
+
+    /// Direct children of the directory at `path`. A single-file share
+    /// lists just the file at the top.
+    pub fn read_dir(&self, path: &str) -> Result<Vec<Node>> {
+        let path = path.trim_matches('/');
+        if path.is_empty() && !self.manifest.is_directory() {
+            return Ok(vec![self.root()]);
+        }
+        match self.lookup(path) {
+            Some(n) if n.kind == NodeKind::Dir => {}
+            Some(_) => anyhow::bail!("Not a directory: {}", path),
+            None => anyhow::bail!("No such directory: {}", path),
+        }
+        Ok(self.manifest.files.iter()
+            .filter(|e| parent(&e.path) == path)
+            .map(node)
+            .collect())
+    }
+
+    /// Read up to `len` bytes of the file at `path` starting at `offset`.
+    /// Short reads only happen at the end of the file.
+    pub async fn read(&self, path: &str, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let (size, chunks) = self.file_chunks(path.trim_matches('/'))?;
+        let end = size.min(offset.saturating_add(len as u64));
+        let mut out = Vec::with_capacity(end.saturating_sub(offset) as usize);
+        if offset >= end {
+            return Ok(out);
+        }
+
+        let chunk_size = match chunks.len() {
+            1 => size,
+            _ => self.chunk_size(&chunks[0]).await?,
+        };
+        let mut index = (offset / chunk_size) as usize;
+        let mut pos = offset;
+        while pos < end {
+            let hash = chunks.get(index).context("Read past the last chunk")?;
+            let data = self.chunk(hash).await?;
+            let start = (pos - index as u64 * chunk_size) as usize;
+            let take = ((end - pos) as usize).min(data.len().saturating_sub(start));
+            if take == 0 {
+                anyhow::bail!("Chunk {} is shorter than expected", hash);
+            }
+            out.extend_from_slice(&data[start..start + take]);
+            pos += take as u64;
+            index += 1;
+        }
+        Ok(out)
+    }
+
+    fn file_chunks(&self, path: &str) -> Result<(u64, &[String])> {
+        if !self.manifest.is_directory() {
+            if !path.is_empty() && path != self.manifest.filename {
+                anyhow::bail!("No such file: {}", path);
+            }
+            return Ok((self.manifest.size, &self.manifest.chunk_hashes));
+        }
+        let entry = self.manifest.files.iter()
+            .find(|e| e.path == path)
+            .with_context(|| format!("No such file: {}", path))?;
+        if entry.is_dir {
+            anyhow::bail!("Is a directory: {}", path);
+        }
+        Ok((entry.size, self.manifest.entry_chunks(entry)?))
+    }
+
+    async fn chunk_size(&self, first: &str) -> Result<u64> {
+        if let Some(size) = *self.chunk_size.lock().unwrap() {
+            return Ok(size);
+        }
+        let size = self.chunk(first).await?.len() as u64;
+        *self.chunk_size.lock().unwrap() = Some(size);
+        Ok(size)
+    }
+
+    async fn chunk(&self, hash: &str) -> Result<Vec<u8>> {
+        if let Some(data) = self.storage.get_chunk(hash).await? {
+            return Ok(data);
+        }
+        let fetcher = self.fetcher.as_ref()
+            .with_context(|| format!("Chunk {} is not stored locally", hash))?;
+        fetcher.fetch(&self.manifest, &[hash.to_string()]).await?;
+        self.storage.get_chunk(hash).await?
+            .with_context(|| format!("Chunk {} still missing after fetch", hash))
+    }
+}
+
+fn node(entry: &FileEntry) -> Node {
+    Node {
+        path: entry.path.clone(),
+        kind: if entry.is_dir { NodeKind::Dir } else { NodeKind::File },
+        size: entry.size,
+    }
+}
+
+fn parent(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(dir, _)| dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::fixture::client;
+    use crate::sim::{pair, LinkConfig};
+    use storage::LocalStorage;
+    use tempfile::TempDir;
+
+    /// Serves chunks from a second store, counting how many it handed out.
+    struct CopyFetcher {
+        from: LocalStorage,
+        into: Arc<LocalStorage>,
+        fetched: Mutex<usize>,
+    }
+
+    #[async_trait]
+    impl ChunkFetcher for CopyFetcher {
+        async fn fetch(&self, _manifest: &Manifest, missing: &[String]) -> Result<()> {
+            for hash in missing {
+                let data = self.from.get_chunk(hash).await?.context("peer lacks chunk")?;
+                self.into.put_chunk(&data).await?;
+                *self.fetched.lock().unwrap() += 1;
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_share_view_reads_and_fetches_lazily() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let root = tmp.path().join("share");
+        std::fs::create_dir_all(root.join("docs/empty"))?;
+        std::fs::write(root.join("docs/a.txt"), b"0123456789")?;
+        std::fs::write(root.join("b.txt"), b"xyz")?;
+        let manifest = Manifest::from_dir(&root, 4)?;
+
+        // The "peer" has every chunk; the local store starts empty.
+        let peer = LocalStorage::new(tmp.path().join("peer"))?;
+        for file in ["docs/a.txt", "b.txt"] {
+            for chunk in std::fs::read(root.join(file))?.chunks(4) {
+                peer.put_chunk(chunk).await?;
+            }
+        }
+        let local = Arc::new(LocalStorage::new(tmp.path().join("local"))?);
+        let fetcher = Arc::new(CopyFetcher { from: peer, into: local.clone(), fetched: Mutex::new(0) });
+        let view = ShareView::new(manifest, local.clone()).with_fetcher(fetcher.clone());
+
+        let names: Vec<_> = view.read_dir("")?.iter().map(|n| n.name().to_string()).collect();
+        assert_eq!(names, ["b.txt", "docs"]);
+        assert_eq!(view.read_dir("docs")?.len(), 2);
+        assert_eq!(view.lookup("docs/a.txt").unwrap().size, 10);
+        assert!(view.read_dir("b.txt").is_err());
+
+        // A read spanning chunks 1 and 2 fetches the two it covers, nothing
+        // else: the manifest records the chunk size.
+        assert_eq!(view.read("docs/a.txt", 5, 4).await?, b"5678");
+        assert_eq!(*fetcher.fetched.lock().unwrap(), 2);
+        assert_eq!(view.read("docs/a.txt", 8, 100).await?, b"89");
+        assert_eq!(view.read("docs/a.txt", 10, 1).await?, b"");
+        assert_eq!(*fetcher.fetched.lock().unwrap(), 2);
+
+        // Without a fetcher, missing chunks fail the read, and so does
+        // learning the chunk size from a first chunk that is not here.
+        let offline = ShareView::new(view.manifest().clone(), local.clone());
+        assert!(offline.read("b.txt", 0, 3).await.is_err());
+        assert_eq!(offline.read("docs/a.txt", 4, 6).await?, b"456789");
+        let mut undeclared = view.manifest().clone();
+        undeclared.chunk_size = None;
+        assert!(ShareView::new(undeclared, local).read("docs/a.txt", 4, 6).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_partial_pull_fetch_on_read() {
+        use crate::client::PeerChunkFetcher;
+        use std::sync::Arc;
+
+        let (src, dst) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let input = src.path().join("input.bin");
+        let payload: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&input, &payload).unwrap();
+
+        let server = Arc::new(client(&src, 1));
+        let fetcher = Arc::new(client(&dst, 2));
+        let id = server.publish(&server.import_file(&input).await.unwrap()).unwrap();
+
+        // A placeholder fetch brings the manifest and no chunks.
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (fetched, served) = tokio::join!(fetcher.request_chunks(a, &id, Vec::new()), server.accept(b));
+        let manifest = fetched.unwrap();
+        served.unwrap();
+        assert_eq!(fetcher.missing_chunks(&manifest).await.unwrap().len(), 5);
+
+        let connect = {
+            let server = server.clone();
+            move || {
+                let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+                let server = server.clone();
+                tokio::spawn(async move { server.accept(b).await });
+                async move { Ok(a) }
+            }
+        };
+        let view = ShareView::new(manifest.clone(), fetcher.storage.clone())
+            .with_fetcher(Arc::new(PeerChunkFetcher::new(fetcher.clone(), connect)));
+
+        // Reading inside the third chunk fetches only it, as the manifest
+        // records the chunk size, leaving the rest remote.
+        let offset = 2 * 64 * 1024 + 10;
+        let read = view.read("input.bin", offset as u64, 100).await.unwrap();
+        assert_eq!(read, &payload[offset..offset + 100]);
+        assert_eq!(fetcher.missing_chunks(&manifest).await.unwrap(), [0, 1, 3, 4]);
+
+        // Pinning fetches what is left.
+        let missing = fetcher.missing_chunks(&manifest).await.unwrap();
+        let (a, b) = pair(LinkConfig::lan(), LinkConfig::lan());
+        let (fetched, served) = tokio::join!(fetcher.request_chunks(a, &id, missing), server.accept(b));
+        fetched.unwrap();
+        served.unwrap();
+        let output = dst.path().join("out.bin");
+        fetcher.write_file(&manifest, &output).await.unwrap();
+        assert_eq!(std::fs::read(output).unwrap(), payload);
+    }
+}
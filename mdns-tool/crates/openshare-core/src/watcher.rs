@@ -0,0 +1,410 @@
+//! Watching a folder for files to send.
+//!
+//! A [`Watcher`] follows a directory tree through the OS's change
+//! notifications (`notify`'s recommended watcher: inotify, FSEvents,
+//! ReadDirectoryChangesW, ...) and, each time it is polled, reports the
+//! files that are new or changed since they were last reported, once their
+//! size and modification time have held still for the debounce period, so
+//! that a file still being written goes out only when it is done. Files
+//! matching an ignore pattern are left alone.
+//!
+//! The caller sends each reported file and hands it back with
+//! [`Watcher::sent`] or [`Watcher::failed`]. A failed file (the peer is
+//! offline, say) is reported again after a backoff that doubles with each
+//! failure, or once it settles again if it changes in the meantime.
+//!
+//! A poll only re-reads the paths notifications named. Where they cannot
+//! be set up (an unsupported filesystem, the inotify watch limit reached),
+//! when the OS says it dropped some, or with
+//! [`with_polling`](Watcher::with_polling) for network shares whose remote
+//! changes raise none, the watcher falls back to walking the whole tree on
+//! every poll.
+
+use crate::config::glob_match;
+use anyhow::{Context, Result};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher as _};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Patterns ignored unless others are given: hidden files and folders,
+/// and the temporary files of editors and browsers.
+pub const DEFAULT_IGNORES: &[&str] = &[".*", "*~", "*.tmp", "*.part", "*.crdownload", "*.swp"];
+
+/// How long a file has to stay unchanged before it is reported.
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// Pause between polls that `openshare watch --dir` uses.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Wait before the first retry of a failed file; each further failure
+/// doubles it, up to [`MAX_RETRY`].
+pub const FIRST_RETRY: Duration = Duration::from_secs(5);
+
+pub const MAX_RETRY: Duration = Duration::from_secs(300);
+
+/// What a file looked like when scanned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+/// A file changed since it was last reported.
+#[derive(Debug)]
+struct Pending {
+    stamp: Stamp,
+    /// When `stamp` was first seen
+    changed_at: Instant,
+    /// Not before this, after a failed send
+    retry_at: Option<Instant>,
+}
+
+/// Change notifications for the tree, while they work.
+struct Notifications {
+    _watcher: RecommendedWatcher,
+    events: mpsc::Receiver<notify::Result<notify::Event>>,
+}
+
+impl std::fmt::Debug for Notifications {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Notifications")
+    }
+}
+
+/// See the [module docs](self).
+#[derive(Debug)]
+pub struct Watcher {
+    root: PathBuf,
+    ignore: Vec<String>,
+    debounce: Duration,
+    /// `None` when polling by walking the tree
+    notifications: Option<Notifications>,
+    /// Files in the tree as last seen
+    files: HashMap<PathBuf, Stamp>,
+    /// Files as last reported, or as found when watching started
+    reported: HashMap<PathBuf, Stamp>,
+    pending: HashMap<PathBuf, Pending>,
+    /// Failed sends in a row, per file
+    failures: HashMap<PathBuf, u32>,
+}
+
+impl Watcher {
+    /// Watch the directory `root`. Files already in it count as sent,
+    /// unless [`with_existing_files`](Self::with_existing_files) is used.
+    pub fn new(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        if !root.is_dir() {
+            anyhow::bail!("{} is not a directory", root.display());
+        }
+        // Watched before the first walk, so nothing between the two is lost
+        let notifications = match notifications(&root) {
+            Ok(notifications) => Some(notifications),
+            Err(e) => {
+                tracing::warn!("Cannot watch {} for changes, scanning it instead: {}", root.display(), e);
+                None
+            }
+        };
+        let mut watcher = Self {
+            root,
+            ignore: DEFAULT_IGNORES.iter().map(|p| p.to_string()).collect(),
+            debounce: DEFAULT_DEBOUNCE,
+            notifications,
+            files: HashMap::new(),
+            reported: HashMap::new(),
+            pending: HashMap::new(),
+            failures: HashMap::new(),
+        };
+        watcher.files = watcher.walk(&watcher.root)?;
+        watcher.reported = watcher.files.clone();
+        Ok(watcher)
+    }
+
+    /// Ignore files matching `patterns` instead of [`DEFAULT_IGNORES`].
+    /// A pattern is a case-insensitive glob (`*` and `?`) matched against
+    /// the path relative to the root and against each name along it, so
+    /// `build` skips a folder anywhere in the tree and `*.log` any log.
+    pub fn with_ignore(mut self, patterns: Vec<String>) -> Self {
+        self.ignore = patterns;
+        // Notifications only name what changes from here on
+        if let Ok(files) = self.walk(&self.root) {
+            self.files = files;
+        }
+        self
+    }
+
+    /// Walk the tree on every poll instead of following notifications,
+    /// e.g. for a network share, where changes made by other machines
+    /// raise none.
+    pub fn with_polling(mut self) -> Self {
+        self.notifications = None;
+        self
+    }
+
+    /// Whether polls follow change notifications rather than walk the tree.
+    pub fn notified(&self) -> bool {
+        self.notifications.is_some()
+    }
+
+    pub fn with_debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+        self
+    }
+
+    /// Report the files already in the folder too, as if just added.
+    pub fn with_existing_files(mut self) -> Self {
+        self.reported.clear();
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Whether `relative`, a path under the root, matches an ignore
+    /// pattern.
+    pub fn ignores(&self, relative: &Path) -> bool {
+        let whole = relative.to_string_lossy().replace('\\', "/");
+        self.ignore.iter().any(|pattern| {
+            glob_match(pattern, &whole)
+                || relative.iter().any(|name| glob_match(pattern, &name.to_string_lossy()))
+        })
+    }
+
+    /// Catch up with the folder and return the files to send now, oldest
+    /// change first. Each has to be handed back with [`sent`](Self::sent)
+    /// or [`failed`](Self::failed). Deleted files are forgotten.
+    pub fn poll(&mut self, now: Instant) -> Result<Vec<PathBuf>> {
+        self.refresh()?;
+        let current = self.files.clone();
+        self.reported.retain(|path, _| current.contains_key(path));
+        self.pending.retain(|path, _| current.contains_key(path));
+        self.failures.retain(|path, _| current.contains_key(path));
+
+        let mut ready = Vec::new();
+        for (path, stamp) in current {
+            if self.reported.get(&path) == Some(&stamp) {
+                continue;
+            }
+            let pending = self.pending.entry(path.clone())
+                .or_insert(Pending { stamp, changed_at: now, retry_at: None });
+            if pending.stamp != stamp {
+                // Changed again: wait for it to settle, but not for the
+                // rest of a retry backoff.
+                *pending = Pending { stamp, changed_at: now, retry_at: None };
+            }
+            let settled = now.duration_since(pending.changed_at) >= self.debounce;
+            if settled && pending.retry_at.is_none_or(|at| now >= at) {
+                ready.push((pending.changed_at, path));
+            }
+        }
+        ready.sort();
+        Ok(ready.into_iter()
+            .map(|(_, path)| {
+                let stamp = self.pending.remove(&path).expect("ready files are pending").stamp;
+                self.reported.insert(path.clone(), stamp);
+                path
+            })
+            .collect())
+    }
+
+    /// A reported file was sent.
+    pub fn sent(&mut self, path: &Path) {
+        self.failures.remove(path);
+    }
+
+    /// A reported file could not be sent: report it again after a backoff,
+    /// which is returned.
+    pub fn failed(&mut self, path: &Path, now: Instant) -> Duration {
+        let failures = self.failures.entry(path.to_path_buf()).or_insert(0);
+        *failures += 1;
+        let backoff = FIRST_RETRY.saturating_mul(1 << (*failures - 1).min(16)).min(MAX_RETRY);
+        if let Some(stamp) = self.reported.remove(path) {
+            self.pending.insert(path.to_path_buf(), Pending { stamp, changed_at: now, retry_at: Some(now + backoff) });
+        }
+        backoff
+    }
+
+    /// Files changed but not reported yet, settling or waiting to retry.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Bring `files` up to date: re-read the paths notifications named
+    /// since the last poll, or walk the whole tree without them.
+    fn refresh(&mut self) -> Result<()> {
+        let Some(notifications) = &self.notifications else {
+            self.files = self.walk(&self.root)?;
+            return Ok(());
+        };
+        let mut changed = Vec::new();
+        let mut rescan = false;
+        for event in notifications.events.try_iter() {
+            match event {
+                Ok(event) if !event.need_rescan() => changed.extend(event.paths),
+                // Events were dropped, or the watch broke
+                _ => rescan = true,
+            }
+        }
+        // Paths outside the root, as FSEvents reports through symlinked
+        // parents, are not worth matching up.
+        if rescan || changed.iter().any(|path| !path.starts_with(&self.root)) {
+            self.files = self.walk(&self.root)?;
+            return Ok(());
+        }
+        changed.sort();
+        changed.dedup();
+        for path in changed {
+            self.files.retain(|file, _| !file.starts_with(&path));
+            let found = self.walk(&path)?;
+            self.files.extend(found);
+        }
+        Ok(())
+    }
+
+    /// Files at or under `top` that are not ignored, with their stamps.
+    /// Symlinks are not followed.
+    fn walk(&self, top: &Path) -> Result<HashMap<PathBuf, Stamp>> {
+        let mut files = HashMap::new();
+        // Records `path` if it is a file to watch; whether it is a folder
+        // to descend into
+        let visit = |path: &Path, files: &mut HashMap<PathBuf, Stamp>| {
+            let relative = path.strip_prefix(&self.root).unwrap_or(path);
+            if self.ignores(relative) {
+                return false;
+            }
+            let Ok(metadata) = std::fs::symlink_metadata(path) else { return false };
+            if metadata.is_file() {
+                files.insert(path.to_path_buf(), Stamp { size: metadata.len(), modified: metadata.modified().ok() });
+            }
+            metadata.is_dir()
+        };
+        let mut dirs = Vec::new();
+        if top == self.root || visit(top, &mut files) {
+            dirs.push(top.to_path_buf());
+        }
+        while let Some(dir) = dirs.pop() {
+            let entries = match std::fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                // Removed since it was listed
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound && dir != self.root => continue,
+                Err(e) => return Err(e).with_context(|| format!("Failed to read {}", dir.display())),
+            };
+            for entry in entries {
+                let path = entry?.path();
+                if visit(&path, &mut files) {
+                    dirs.push(path);
+                }
+            }
+        }
+        Ok(files)
+    }
+}
+
+/// Watch `root` recursively, queueing events for [`Watcher::refresh`].
+fn notifications(root: &Path) -> notify::Result<Notifications> {
+    let (tx, events) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = tx.send(event);
+    })?;
+    watcher.watch(root, RecursiveMode::Recursive)?;
+    Ok(Notifications { _watcher: watcher, events })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watcher_debounce_ignore_and_retry() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("old.txt"), b"there before").unwrap();
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let mut watcher = Watcher::new(dir.path()).unwrap()
+            .with_ignore(vec!["*.tmp".into(), "build".into()])
+            .with_polling();
+
+        // Existing files are not reported, ignored ones never are, and a
+        // new file only once it has settled.
+        std::fs::create_dir(dir.path().join("build")).unwrap();
+        std::fs::write(dir.path().join("build/out.bin"), b"artifact").unwrap();
+        std::fs::write(dir.path().join("draft.tmp"), b"scratch").unwrap();
+        std::fs::write(dir.path().join("new.txt"), b"hello").unwrap();
+        assert!(watcher.poll(at(0)).unwrap().is_empty());
+        assert!(watcher.poll(at(1)).unwrap().is_empty());
+        let new = dir.path().join("new.txt");
+        assert_eq!(watcher.poll(at(2)).unwrap(), vec![new.clone()]);
+        assert!(watcher.poll(at(3)).unwrap().is_empty());
+
+        // A failed send is retried after the backoff, which doubles.
+        assert_eq!(watcher.failed(&new, at(3)), FIRST_RETRY);
+        assert!(watcher.poll(at(5)).unwrap().is_empty());
+        assert_eq!(watcher.pending(), 1);
+        assert_eq!(watcher.poll(at(8)).unwrap(), vec![new.clone()]);
+        assert_eq!(watcher.failed(&new, at(8)), FIRST_RETRY * 2);
+
+        // A change cuts the backoff short, and the file goes out once it
+        // settles.
+        std::fs::write(&new, b"hello again").unwrap();
+        assert!(watcher.poll(at(9)).unwrap().is_empty());
+        assert_eq!(watcher.poll(at(11)).unwrap(), vec![new.clone()]);
+        watcher.sent(&new);
+
+        // Deleted files are forgotten.
+        std::fs::remove_file(&new).unwrap();
+        assert!(watcher.poll(at(12)).unwrap().is_empty());
+        assert_eq!(watcher.pending(), 0);
+
+        let mut existing = Watcher::new(dir.path()).unwrap()
+            .with_ignore(vec!["*.tmp".into(), "build".into()])
+            .with_existing_files()
+            .with_debounce(Duration::ZERO);
+        assert_eq!(existing.poll(at(0)).unwrap(), vec![dir.path().join("old.txt")]);
+        assert!(Watcher::new(dir.path().join("old.txt")).is_err());
+    }
+
+    #[test]
+    fn test_watcher_follows_notifications() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut watcher = Watcher::new(dir.path()).unwrap().with_debounce(Duration::ZERO);
+        assert!(watcher.notified());
+        // Notifications arrive in their own time.
+        let mut wait_for = |expected: Vec<PathBuf>| {
+            let mut seen = Vec::new();
+            for _ in 0..100 {
+                seen.extend(watcher.poll(Instant::now()).unwrap());
+                seen.sort();
+                if seen == expected {
+                    return;
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            panic!("saw {:?}, expected {:?}", seen, expected);
+        };
+
+        // A folder moved in whole is walked; ignored files stay out.
+        let staging = tempfile::tempdir().unwrap();
+        std::fs::create_dir(staging.path().join("album")).unwrap();
+        std::fs::write(staging.path().join("album/cover.txt"), b"cover").unwrap();
+        std::fs::write(staging.path().join("album/.DS_Store"), b"junk").unwrap();
+        std::fs::rename(staging.path().join("album"), dir.path().join("album")).unwrap();
+        std::fs::write(dir.path().join("notes.txt"), b"notes").unwrap();
+        wait_for(vec![dir.path().join("album/cover.txt"), dir.path().join("notes.txt")]);
+
+        std::fs::write(dir.path().join("notes.txt"), b"more notes").unwrap();
+        wait_for(vec![dir.path().join("notes.txt")]);
+
+        // Deleted files are forgotten.
+        std::fs::remove_dir_all(dir.path().join("album")).unwrap();
+        for _ in 0..100 {
+            assert!(watcher.poll(Instant::now()).unwrap().is_empty());
+            if watcher.files.len() == 1 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(watcher.files.keys().collect::<Vec<_>>(), [&dir.path().join("notes.txt")]);
+    }
+}
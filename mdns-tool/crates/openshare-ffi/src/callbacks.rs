@@ -0,0 +1,94 @@
+//! Interfaces the apps implement, and their adapters to the core traits.
+
+use crate::records::{DiscoveredPeer, IncomingOffer, ReceivedFile, TransferEvent};
+use async_trait::async_trait;
+use openshare_core::events::{TransferEvent as CoreEvent, TransferObserver};
+use openshare_core::policy::{Limits, Offer, TransferPolicy, Verdict};
+use std::sync::{Arc, RwLock};
+
+/// Told of the progress of every send and receive.
+#[uniffi::export(callback_interface)]
+pub trait TransferListener: Send + Sync {
+    fn on_event(&self, event: TransferEvent);
+}
+
+/// Decides on pushed transfers and learns of the files received.
+#[uniffi::export(callback_interface)]
+pub trait IncomingHandler: Send + Sync {
+    /// Whether to take `offer`, which is within the limits of the config.
+    /// May block, e.g. to ask the user; the sender waits for the answer.
+    fn review(&self, offer: IncomingOffer) -> bool;
+
+    /// A received file was verified and written out.
+    fn on_received(&self, file: ReceivedFile);
+}
+
+/// Told of devices of the account appearing and going away.
+#[uniffi::export(callback_interface)]
+pub trait PeerListener: Send + Sync {
+    /// Seen for the first time, or its addresses or port changed
+    fn on_found(&self, peer: DiscoveredPeer);
+
+    fn on_lost(&self, device_id: String);
+}
+
+/// Asks the user whether the other device shows the same pairing code.
+#[uniffi::export(callback_interface)]
+pub trait PairingConfirmer: Send + Sync {
+    /// May block until the user answers.
+    fn confirm(&self, device_id: String, code: String) -> bool;
+}
+
+/// The listener and handler the app set, read on every event so they can
+/// be replaced while transfers run.
+#[derive(Default)]
+pub(crate) struct Callbacks {
+    pub transfers: RwLock<Option<Arc<dyn TransferListener>>>,
+    pub incoming: RwLock<Option<Arc<dyn IncomingHandler>>>,
+}
+
+impl Callbacks {
+    pub fn incoming(&self) -> Option<Arc<dyn IncomingHandler>> {
+        self.incoming.read().unwrap().clone()
+    }
+}
+
+/// Forwards core events to the [`TransferListener`], if any.
+pub(crate) struct Observer(pub Arc<Callbacks>);
+
+impl TransferObserver for Observer {
+    fn on_event(&self, event: &CoreEvent) {
+        let listener = self.0.transfers.read().unwrap().clone();
+        if let Some(listener) = listener {
+            listener.on_event(event.into());
+        }
+    }
+}
+
+/// Applies the config's [`Limits`], then asks the [`IncomingHandler`];
+/// without one, pushes within the limits are accepted.
+pub(crate) struct Policy {
+    pub limits: Limits,
+    pub callbacks: Arc<Callbacks>,
+}
+
+#[async_trait]
+impl TransferPolicy for Policy {
+    async fn review(&self, offer: &Offer) -> Verdict {
+        if let Some(reason) = self.limits.check(offer) {
+            return Verdict::Reject(reason);
+        }
+        let Some(handler) = self.callbacks.incoming() else {
+            return Verdict::Accept;
+        };
+        let offer = IncomingOffer::from(offer);
+        match tokio::task::spawn_blocking(move || handler.review(offer)).await {
+            Ok(true) => Verdict::Accept,
+            Ok(false) => Verdict::Reject("Declined".into()),
+            Err(e) => {
+                tracing::warn!("Reviewing a transfer failed: {}", e);
+                Verdict::Reject("No one to ask".into())
+            }
+        }
+    }
+}
@@ -0,0 +1,446 @@
+//! The client object the apps hold.
+
+use crate::callbacks::{Callbacks, IncomingHandler, Observer, PairingConfirmer, PeerListener, Policy, TransferListener};
+use crate::error::Result;
+use crate::records::{DeviceInfo, DiscoveredPeer, ReceivedFile, SentFile, TrustedPeer};
+use anyhow::{Context, anyhow};
+use mdns_core::net::rank_addresses;
+use openshare_core::discovery::Announcement;
+use openshare_core::encrypted::EncryptedStorage;
+use openshare_core::keys::KeyBackend;
+use openshare_core::policy::Limits;
+use openshare_core::registry::PeerEvent;
+use openshare_core::transfer::{TransferHandle, TransferOutcome};
+use openshare_core::trust::{parse_public_key, TrustSource};
+use openshare_core::{config, Accepted, Client, ClientConfig, Discovery, Identity};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use storage::Storage;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+
+/// The configured chunk store, encrypted at rest if the config asks for it.
+type Store = Arc<dyn Storage>;
+
+/// One device, rooted in a directory of the app's sandbox; see the
+/// [crate docs](crate).
+#[derive(uniffi::Object)]
+pub struct OpenShareClient {
+    client: Arc<Client<Store>>,
+    discovery: Discovery,
+    callbacks: Arc<Callbacks>,
+    /// Bound by [`listen`](Self::listen), until [`serve`](Self::serve)
+    /// takes it
+    listener: Mutex<Option<std::net::TcpListener>>,
+    announcement: Mutex<Option<Announcement>>,
+    /// Bumped by [`stop`](Self::stop)
+    stop: watch::Sender<u64>,
+    output_dir: PathBuf,
+}
+
+#[uniffi::export]
+impl OpenShareClient {
+    /// Set up a new device of `account` in `data_dir`: generate its
+    /// identity and write its config. Fails if the directory already
+    /// holds one, so an identity is never replaced by accident.
+    #[uniffi::constructor]
+    pub fn create(data_dir: String, device_id: String, account: String) -> Result<Arc<Self>> {
+        let data_dir = PathBuf::from(data_dir);
+        let cfg_path = data_dir.join("config.json");
+        if cfg_path.exists() {
+            return Err(anyhow!("{} is already set up; open it instead", data_dir.display()).into());
+        }
+        std::fs::create_dir_all(&data_dir)
+            .with_context(|| format!("Failed to create {}", data_dir.display()))?;
+        let identity = Identity::generate_with(KeyBackend::File, &data_dir.join("identity.key"))?;
+        let cfg = ClientConfig {
+            data_dir,
+            device_id,
+            account_hash: config::account_hash(&account),
+            ..ClientConfig::default()
+        };
+        cfg.ensure_data_dir()?;
+        cfg.save(&cfg_path)?;
+        Ok(Arc::new(Self::start(identity, cfg)?))
+    }
+
+    /// Open the device set up in `data_dir` by [`create`](Self::create).
+    #[uniffi::constructor]
+    pub fn open(data_dir: String) -> Result<Arc<Self>> {
+        let data_dir = PathBuf::from(data_dir);
+        let cfg_path = data_dir.join("config.json");
+        if !cfg_path.exists() {
+            return Err(anyhow!("{} is not set up; create it first", data_dir.display()).into());
+        }
+        let mut cfg = ClientConfig::load(&cfg_path)?;
+        // iOS moves an app's container on updates, so the path it was
+        // created under may be gone.
+        cfg.data_dir = data_dir;
+        let identity = Identity::load_with(cfg.key_backend, &cfg.data_dir.join("identity.key"))?;
+        Ok(Arc::new(Self::start(identity, cfg)?))
+    }
+
+    pub fn device(&self) -> DeviceInfo {
+        DeviceInfo {
+            device_id: self.client.cfg.device_id.clone(),
+            account_hash: self.client.cfg.account_hash.clone(),
+            fingerprint: self.client.identity.fingerprint(),
+            full_fingerprint: self.client.identity.full_fingerprint(),
+        }
+    }
+
+    /// Report the progress of every transfer to `listener`, replacing any
+    /// listener set before; `None` stops reporting.
+    pub fn set_transfer_listener(&self, listener: Option<Box<dyn TransferListener>>) {
+        *self.callbacks.transfers.write().unwrap() = listener.map(Arc::from);
+    }
+
+    /// Let `handler` decide on pushes and learn of received files. Without
+    /// one, every push within the config's limits is taken.
+    pub fn set_incoming_handler(&self, handler: Option<Box<dyn IncomingHandler>>) {
+        *self.callbacks.incoming.write().unwrap() = handler.map(Arc::from);
+    }
+
+    pub fn trusted_peers(&self) -> Vec<TrustedPeer> {
+        self.client.trust.lock().unwrap().list().map(TrustedPeer::from).collect()
+    }
+
+    /// Pin `device_id` to `public_key`, its full fingerprint in hex, as
+    /// `openshare trust add` does.
+    pub fn trust_peer(&self, device_id: String, public_key: String) -> Result<()> {
+        let key = parse_public_key(&public_key)?;
+        let mut trust = self.client.trust.lock().unwrap();
+        trust.add(&device_id, &key, TrustSource::Manual);
+        trust.save()?;
+        Ok(())
+    }
+
+    /// Unpin `device_id`; `false` if it was not trusted.
+    pub fn forget_peer(&self, device_id: String) -> Result<bool> {
+        let mut trust = self.client.trust.lock().unwrap();
+        if !trust.remove(&device_id) {
+            return Ok(false);
+        }
+        trust.save()?;
+        Ok(true)
+    }
+
+    /// Bind the port transfers are received on, 0 for any, and return it.
+    /// Receiving starts with [`serve`](Self::serve).
+    pub fn listen(&self, port: u16) -> Result<u16> {
+        let listener = std::net::TcpListener::bind(("::", port))
+            .or_else(|_| std::net::TcpListener::bind(("0.0.0.0", port)))
+            .with_context(|| format!("Failed to listen on port {}", port))?;
+        listener.set_nonblocking(true).context("Failed to listen")?;
+        let port = listener.local_addr().context("Failed to listen")?.port();
+        *self.listener.lock().unwrap() = Some(listener);
+        Ok(port)
+    }
+
+    /// Advertise this device on `interfaces` (all of them if empty) as
+    /// reachable on `port`, until [`stop`](Self::stop).
+    pub fn announce(&self, interfaces: Vec<String>, port: u16) -> Result<()> {
+        let announcement = self.discovery.announce(&interfaces, port, &[])?;
+        tracing::info!("Announcing: {}", announcement.fullnames().join(", "));
+        *self.announcement.lock().unwrap() = Some(announcement);
+        Ok(())
+    }
+
+    /// Stop serving, announcing and watching for devices. Transfers in
+    /// progress run to completion.
+    pub fn stop(&self) {
+        self.listener.lock().unwrap().take();
+        self.announcement.lock().unwrap().take();
+        self.stop.send_modify(|generation| *generation += 1);
+    }
+}
+
+#[uniffi::export(async_runtime = "tokio")]
+impl OpenShareClient {
+    /// Receive transfers on the port bound by [`listen`](Self::listen)
+    /// until [`stop`](Self::stop). Each received file is written out and
+    /// passed to the [`IncomingHandler`].
+    pub async fn serve(&self) -> Result<()> {
+        let listener = self.listener.lock().unwrap().take()
+            .ok_or_else(|| anyhow!("Not listening; call listen first"))?;
+        let listener = TcpListener::from_std(listener).context("Failed to listen")?;
+        let mut stop = self.stop.subscribe();
+        loop {
+            let (stream, remote) = tokio::select! {
+                accepted = listener.accept() => accepted.context("Failed to accept a connection")?,
+                _ = stop.changed() => return Ok(()),
+            };
+            tracing::info!("Incoming connection from {}", remote);
+            let client = self.client.clone();
+            let callbacks = self.callbacks.clone();
+            let output_dir = self.output_dir.clone();
+            tokio::spawn(async move {
+                if let Err(e) = receive(&client, &callbacks, &output_dir, stream).await {
+                    tracing::warn!("Transfer from {} failed: {:#}", remote, e);
+                }
+            });
+        }
+    }
+
+    /// Browse for devices of the account on `interface_name` (e.g.
+    /// "wlan0" or "en0") for `timeout_ms`, and return all seen so far.
+    pub async fn discover(&self, interface_name: String, timeout_ms: u64) -> Result<Vec<DiscoveredPeer>> {
+        self.discovery.browse(&interface_name, Duration::from_millis(timeout_ms), &[]).await?;
+        Ok(self.discovery.registry().peers().into_iter().map(Into::into).collect())
+    }
+
+    /// Tell `listener` of devices of the account coming and going on
+    /// `interface_name` until [`stop`](Self::stop).
+    pub async fn watch_peers(&self, interface_name: String, listener: Box<dyn PeerListener>) -> Result<()> {
+        let (tx, mut rx) = mpsc::channel(16);
+        let discovery = self.discovery.clone();
+        // Browsing notices the channel closed at its next event, so it is
+        // left to finish on its own.
+        let watch = tokio::spawn(async move { discovery.watch(&interface_name, None, &[], tx).await });
+        let mut stop = self.stop.subscribe();
+        loop {
+            let event = tokio::select! {
+                event = rx.recv() => event,
+                _ = stop.changed() => return Ok(()),
+            };
+            match event {
+                Some(PeerEvent::Found(peer)) => listener.on_found(peer.into()),
+                Some(PeerEvent::Lost { device_id }) => listener.on_lost(device_id),
+                None => break,
+            }
+        }
+        watch.await.context("Browsing failed")??;
+        Ok(())
+    }
+
+    /// Send the file at `path` to `address` ("host:port"). `control`, if
+    /// given, pauses or cancels it; a cancelled send fails with
+    /// `Cancelled` and continues where it stopped when sent again.
+    pub async fn send_file(&self, path: String, address: String, control: Option<Arc<TransferControl>>) -> Result<SentFile> {
+        let resolved: Vec<SocketAddr> = tokio::net::lookup_host(&address).await
+            .with_context(|| format!("Could not resolve {}", address))?
+            .collect();
+        let port = resolved.first().map(|a| a.port())
+            .ok_or_else(|| anyhow!("Could not resolve {}", address))?;
+        let ips: Vec<_> = resolved.iter().map(|a| a.ip()).collect();
+        let addrs = rank_addresses(&ips, self.client.cfg.overlay_policy).into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect();
+        self.send(Path::new(&path), addrs, control).await
+    }
+
+    /// Like [`send_file`](Self::send_file), to a device found by
+    /// [`discover`](Self::discover) or [`watch_peers`](Self::watch_peers).
+    pub async fn send_file_to_device(&self, path: String, device_id: String, control: Option<Arc<TransferControl>>) -> Result<SentFile> {
+        let peer = self.discovery.registry().find_by_device_id(&device_id)
+            .ok_or_else(|| anyhow!("{} has not been discovered", device_id))?;
+        let addrs = peer.addresses.iter().map(|ip| SocketAddr::new(*ip, peer.port)).collect();
+        self.send(Path::new(&path), addrs, control).await
+    }
+
+    /// Pair with the device at `address`, or with the first to connect on
+    /// `port` if `address` is `None`. Both users compare the code passed
+    /// to `confirmer`; the peer is pinned only if both confirm it, and
+    /// then `true` is returned.
+    pub async fn pair(&self, address: Option<String>, port: u16, confirmer: Box<dyn PairingConfirmer>) -> Result<bool> {
+        let pairing = match address {
+            Some(address) => {
+                let stream = TcpStream::connect(&address).await
+                    .with_context(|| format!("Failed to connect to {}", address))?;
+                self.client.pair(stream, true).await?
+            }
+            None => {
+                let listener = TcpListener::bind(("0.0.0.0", port)).await
+                    .with_context(|| format!("Failed to listen on port {}", port))?;
+                let (stream, _) = listener.accept().await.context("Failed to accept a connection")?;
+                self.client.pair(stream, false).await?
+            }
+        };
+        let (device_id, code) = (pairing.peer.device_id.clone(), pairing.code.clone());
+        let confirmed = tokio::task::spawn_blocking(move || confirmer.confirm(device_id, code)).await
+            .context("Confirming the pairing code failed")?;
+        Ok(self.client.complete_pairing(pairing, confirmed).await?)
+    }
+}
+
+impl OpenShareClient {
+    fn start(identity: Identity, cfg: ClientConfig) -> anyhow::Result<Self> {
+        let store = cfg.storage.open(&cfg.data_dir)?;
+        let storage: Store = match cfg.storage_encryption.key(&cfg.data_dir, &identity)? {
+            Some(key) => Arc::new(EncryptedStorage::new(store, &key)),
+            None => store,
+        };
+        let output_dir = cfg.output_dir.clone().unwrap_or_else(|| cfg.data_dir.join("received"));
+        std::fs::create_dir_all(&output_dir)
+            .with_context(|| format!("Failed to create {}", output_dir.display()))?;
+        let callbacks = Arc::new(Callbacks::default());
+        let discovery = Discovery::new(cfg.clone(), &identity);
+        let policy = Policy { limits: Limits::from_config(&cfg), callbacks: callbacks.clone() };
+        let client = Client::new(identity, storage, cfg)
+            .with_observer(Observer(callbacks.clone()))
+            .with_output_dir(&output_dir)
+            .with_policy(policy);
+        Ok(Self {
+            client: Arc::new(client),
+            discovery,
+            callbacks,
+            listener: Mutex::new(None),
+            announcement: Mutex::new(None),
+            stop: watch::Sender::new(0),
+            output_dir,
+        })
+    }
+
+    /// Stream `path` to the first of `addrs` that answers.
+    async fn send(&self, path: &Path, addrs: Vec<SocketAddr>, control: Option<Arc<TransferControl>>) -> Result<SentFile> {
+        if addrs.is_empty() {
+            return Err(anyhow!("No usable address (overlay policy: {:?})", self.client.cfg.overlay_policy).into());
+        }
+        let mut last_err = None;
+        let mut stream = None;
+        for addr in addrs {
+            match TcpStream::connect(addr).await {
+                Ok(connected) => {
+                    stream = Some(connected);
+                    break;
+                }
+                Err(e) => {
+                    tracing::debug!("Connect to {} failed: {}", addr, e);
+                    last_err = Some(e);
+                }
+            }
+        }
+        let stream = match (stream, last_err) {
+            (Some(stream), _) => stream,
+            (None, e) => return Err(anyhow::Error::from(e.expect("at least one address attempted"))
+                .context("Failed to connect to peer")
+                .into()),
+        };
+
+        let handle = control.map(|c| c.0.clone()).unwrap_or_default();
+        let (manifest, outcome) = self.client.send_file_streaming_with(stream, path, false, &handle).await?;
+        if let TransferOutcome::Cancelled(cancelled) = outcome {
+            return Err(anyhow::Error::new(cancelled).into());
+        }
+        Ok(SentFile { manifest_id: manifest.id(), filename: manifest.filename, size: manifest.size })
+    }
+}
+
+/// Take what the peer on `stream` sends and write it out.
+async fn receive(client: &Client<Store>, callbacks: &Callbacks, output_dir: &Path, stream: TcpStream) -> anyhow::Result<()> {
+    let manifests = match client.accept(stream).await? {
+        Accepted::Received(manifest) => vec![manifest],
+        Accepted::ReceivedBatch(manifests) => manifests,
+        // Pulls, probes and the like need nothing more
+        _ => return Ok(()),
+    };
+    for manifest in manifests {
+        manifest.verify().context("Invalid manifest signature")?;
+        let path = manifest.output_path(output_dir)?;
+        client.write_file(&manifest, &path).await?;
+        tracing::info!("Received {}", path.display());
+        if let Some(handler) = callbacks.incoming() {
+            handler.on_received(ReceivedFile {
+                manifest_id: manifest.id(),
+                filename: manifest.filename.clone(),
+                size: manifest.size,
+                path: path.display().to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Pauses, resumes or cancels a send it is passed to.
+#[derive(Default, uniffi::Object)]
+pub struct TransferControl(TransferHandle);
+
+#[uniffi::export]
+impl TransferControl {
+    #[uniffi::constructor]
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Stop sending after the chunk in progress, keeping the connection.
+    pub fn pause(&self) {
+        self.0.pause();
+    }
+
+    pub fn resume(&self) {
+        self.0.resume();
+    }
+
+    /// Cancel the send before its next chunk. Final.
+    pub fn cancel(&self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::records::IncomingOffer;
+    use crate::OpenShareError;
+    use tempfile::TempDir;
+
+    /// Accepts pushes of files named `keep*` and hands over what arrives.
+    struct Handler(std::sync::mpsc::Sender<ReceivedFile>);
+
+    impl IncomingHandler for Handler {
+        fn review(&self, offer: IncomingOffer) -> bool {
+            offer.filename.starts_with("keep")
+        }
+
+        fn on_received(&self, file: ReceivedFile) {
+            self.0.send(file).unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_send_between_apps() -> anyhow::Result<()> {
+        let (phone_dir, tablet_dir) = (TempDir::new()?, TempDir::new()?);
+        let path = |dir: &TempDir| dir.path().to_str().unwrap().to_string();
+        let phone = OpenShareClient::create(path(&phone_dir), "phone".into(), "alice".into())?;
+        let tablet = OpenShareClient::create(path(&tablet_dir), "tablet".into(), "alice".into())?;
+        assert!(OpenShareClient::create(path(&phone_dir), "phone".into(), "alice".into()).is_err());
+        assert_eq!(phone.device().account_hash, tablet.device().account_hash);
+        phone.trust_peer("tablet".into(), tablet.device().full_fingerprint)?;
+        tablet.trust_peer("phone".into(), phone.device().full_fingerprint)?;
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        tablet.set_incoming_handler(Some(Box::new(Handler(tx))));
+        let port = tablet.listen(0)?;
+        let server = tokio::spawn({
+            let tablet = tablet.clone();
+            async move { tablet.serve().await }
+        });
+
+        let input = phone_dir.path().join("keep.txt");
+        std::fs::write(&input, b"hello from the phone")?;
+        let address = format!("127.0.0.1:{}", port);
+        let sent = phone.send_file(path_str(&input), address.clone(), None).await?;
+        let received = rx.recv_timeout(Duration::from_secs(10))?;
+        assert_eq!(received.manifest_id, sent.manifest_id);
+        assert_eq!(std::fs::read(&received.path)?, b"hello from the phone");
+
+        let declined = phone_dir.path().join("other.txt");
+        std::fs::write(&declined, b"not wanted")?;
+        let error = phone.send_file(path_str(&declined), address, None).await.unwrap_err();
+        assert!(matches!(error, OpenShareError::Rejected(_)), "{}", error);
+
+        tablet.stop();
+        server.await??;
+        // Reopened, it is the same device.
+        drop(tablet);
+        let reopened = OpenShareClient::open(path(&tablet_dir))?;
+        assert_eq!(reopened.trusted_peers().len(), 1);
+        Ok(())
+    }
+
+    fn path_str(path: &Path) -> String {
+        path.to_str().unwrap().to_string()
+    }
+}
@@ -0,0 +1,68 @@
+//! Errors as the apps see them.
+
+use openshare_core::OpenShareError as CoreError;
+use thiserror::Error;
+
+/// A failed call, sorted as by [`openshare_core::OpenShareError`]. The
+/// message is the whole error chain, e.g. "sending a.txt: nas rejected
+/// the transfer: too large". It becomes `OpenShareException` in Kotlin
+/// and is thrown as `OpenShareError` in Swift.
+#[derive(Error, Debug, uniffi::Error)]
+#[uniffi(flat_error)]
+pub enum OpenShareError {
+    /// The receiver declined the push
+    #[error("{0}")]
+    Rejected(String),
+    #[error("{0}")]
+    Cancelled(String),
+    /// The peer gave up after a failure on its side
+    #[error("{0}")]
+    PeerAborted(String),
+    /// The peer could not be authenticated or speaks an incompatible
+    /// protocol
+    #[error("{0}")]
+    Handshake(String),
+    #[error("{0}")]
+    InvalidSignature(String),
+    #[error("{0}")]
+    DiskFull(String),
+    #[error("{0}")]
+    PermissionDenied(String),
+    /// Any other I/O error, including lost connections
+    #[error("{0}")]
+    Io(String),
+    #[error("{0}")]
+    Other(String),
+}
+
+impl From<anyhow::Error> for OpenShareError {
+    fn from(error: anyhow::Error) -> Self {
+        let message = format!("{:#}", error);
+        Self::sorted(CoreError::from(error), message)
+    }
+}
+
+impl From<CoreError> for OpenShareError {
+    fn from(error: CoreError) -> Self {
+        let message = format!("{:#}", error);
+        Self::sorted(error, message)
+    }
+}
+
+impl OpenShareError {
+    fn sorted(error: CoreError, message: String) -> Self {
+        match error {
+            CoreError::Rejected(_) => Self::Rejected(message),
+            CoreError::Cancelled(_) => Self::Cancelled(message),
+            CoreError::PeerAborted(_) => Self::PeerAborted(message),
+            CoreError::Handshake(_) => Self::Handshake(message),
+            CoreError::InvalidSignature(_) => Self::InvalidSignature(message),
+            CoreError::DiskFull(_) => Self::DiskFull(message),
+            CoreError::PermissionDenied(_) => Self::PermissionDenied(message),
+            CoreError::Io(_) => Self::Io(message),
+            CoreError::Other(_) => Self::Other(message),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, OpenShareError>;
@@ -0,0 +1,45 @@
+//! OpenShare for Android and iOS apps.
+//!
+//! UniFFI bindings over `openshare-core`, so an app can be a peer like any
+//! other device of its account: it keeps an identity, finds and announces
+//! devices with mDNS, sends files, receives them and pairs.
+//!
+//! An app opens one [`OpenShareClient`] on a directory of its sandbox
+//! (`Context.filesDir` on Android, Application Support on iOS). The
+//! identity key, config, chunk store, trust store and history all live
+//! there, laid out as the CLI lays out its data directory. Received files
+//! go to `received/` in it unless the config names another `output_dir`.
+//!
+//! Network calls are async functions: suspend functions in Kotlin, `async`
+//! in Swift. Events reach the app through callback interfaces the app
+//! implements: [`TransferListener`] for progress, [`IncomingHandler`] to
+//! decide on pushes and learn of received files, [`PeerListener`] while
+//! watching for devices and [`PairingConfirmer`] for the code shown when
+//! pairing. Callbacks are made on a runtime thread. Reviewing an offer and
+//! confirming a code may block to wait for the user, and the peer waits
+//! with them; the others should return quickly.
+//!
+//! Platform requirements the library cannot meet itself: Android apps
+//! must hold a `WifiManager.MulticastLock` while discovering or
+//! announcing, and iOS apps need the multicast networking entitlement and
+//! a Bonjour services entry for `_openshare._tcp` in their Info.plist.
+//!
+//! The bindings are generated from the built library:
+//!
+//! ```text
+//! cargo build --release -p openshare-ffi
+//! cargo run -p openshare-ffi --features bindgen --bin uniffi-bindgen -- \
+//!     generate --library target/release/libopenshare_ffi.so --language kotlin --out-dir out
+//! ```
+
+mod callbacks;
+mod client;
+mod error;
+mod records;
+
+pub use callbacks::{IncomingHandler, PairingConfirmer, PeerListener, TransferListener};
+pub use client::{OpenShareClient, TransferControl};
+pub use error::OpenShareError;
+pub use records::{DeviceInfo, DiscoveredPeer, IncomingOffer, PeerTrust, ReceivedFile, SentFile, TransferEvent, TrustedPeer};
+
+uniffi::setup_scaffolding!();
@@ -0,0 +1,235 @@
+//! Plain values passed to and from the apps.
+//!
+//! Counts and sizes are `u64` and transfer IDs are strings, since the
+//! bindings have no `usize` or UUID type.
+
+use openshare_core::discovery::Peer;
+use openshare_core::events::TransferEvent as CoreEvent;
+use openshare_core::policy::Offer;
+use openshare_core::preview::Preview;
+use openshare_core::trust::{TrustSource, TrustedPeer as CorePeer};
+
+/// This device, as other devices see it.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct DeviceInfo {
+    pub device_id: String,
+    pub account_hash: String,
+    /// Short fingerprint of the identity key, as advertised
+    pub fingerprint: String,
+    /// The whole public key in hex, to compare when pinning
+    pub full_fingerprint: String,
+}
+
+/// A device of the account found on the network.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct DiscoveredPeer {
+    pub device_id: String,
+    pub fingerprint: String,
+    pub host_name: String,
+    pub port: u16,
+    /// Addresses in dial preference order
+    pub addresses: Vec<String>,
+}
+
+impl From<Peer> for DiscoveredPeer {
+    fn from(peer: Peer) -> Self {
+        Self {
+            device_id: peer.device_id().to_string(),
+            fingerprint: peer.advertisement.fingerprint,
+            host_name: peer.host_name,
+            port: peer.port,
+            addresses: peer.addresses.iter().map(ToString::to_string).collect(),
+        }
+    }
+}
+
+/// How a peer came to be trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum PeerTrust {
+    Manual,
+    FirstUse,
+    /// Both users compared the pairing code
+    Paired,
+    /// Certified by the account root key
+    Account,
+}
+
+impl From<TrustSource> for PeerTrust {
+    fn from(source: TrustSource) -> Self {
+        match source {
+            TrustSource::Manual => Self::Manual,
+            TrustSource::FirstUse => Self::FirstUse,
+            TrustSource::Paired => Self::Paired,
+            TrustSource::Account => Self::Account,
+        }
+    }
+}
+
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct TrustedPeer {
+    pub device_id: String,
+    /// Hex-encoded Ed25519 public key
+    pub public_key: String,
+    pub trust: PeerTrust,
+    /// Unix timestamp (seconds) when the peer was pinned
+    pub added_at: u64,
+}
+
+impl From<&CorePeer> for TrustedPeer {
+    fn from(peer: &CorePeer) -> Self {
+        Self {
+            device_id: peer.device_id.clone(),
+            public_key: peer.public_key.clone(),
+            trust: peer.source.into(),
+            added_at: peer.added_at,
+        }
+    }
+}
+
+/// A pushed transfer awaiting a decision; see
+/// [`IncomingHandler::review`](crate::IncomingHandler::review).
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct IncomingOffer {
+    pub transfer_id: String,
+    /// Device ID of the sender
+    pub peer: String,
+    /// How the sender's key came to be trusted; `None` if it is not pinned
+    pub trust: Option<PeerTrust>,
+    pub filename: String,
+    pub size: u64,
+    /// Paths of the files of a directory, if the manifest came whole
+    pub files: Vec<String>,
+    /// Why the transfer would not fit on disk, if it would not
+    pub insufficient_space: Option<String>,
+    /// What the image offered looks like, if the sender said so
+    pub preview: Option<ImagePreview>,
+}
+
+/// A sender's preview of an offered image.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct ImagePreview {
+    pub media_type: String,
+    pub width: u32,
+    pub height: u32,
+    /// PNG thumbnail
+    pub thumbnail: Vec<u8>,
+}
+
+impl From<&Preview> for ImagePreview {
+    fn from(preview: &Preview) -> Self {
+        Self {
+            media_type: preview.media_type.clone(),
+            width: preview.width,
+            height: preview.height,
+            thumbnail: preview.thumbnail.clone(),
+        }
+    }
+}
+
+impl From<&Offer> for IncomingOffer {
+    fn from(offer: &Offer) -> Self {
+        Self {
+            transfer_id: offer.transfer_id.to_string(),
+            peer: offer.peer.clone(),
+            trust: offer.trust.map(Into::into),
+            filename: offer.filename.clone(),
+            size: offer.size,
+            files: offer.files.clone(),
+            insufficient_space: offer.space.as_ref().map(ToString::to_string),
+            preview: offer.preview.as_ref().map(Into::into),
+        }
+    }
+}
+
+/// A file or directory written out after a transfer.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct ReceivedFile {
+    pub manifest_id: String,
+    pub filename: String,
+    pub size: u64,
+    /// Where it was written
+    pub path: String,
+}
+
+/// A completed send.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct SentFile {
+    pub manifest_id: String,
+    pub filename: String,
+    pub size: u64,
+}
+
+/// Progress of a send or receive, as
+/// [`openshare_core::TransferEvent`].
+#[derive(Debug, Clone, uniffi::Enum)]
+pub enum TransferEvent {
+    /// The encrypted session is established, possibly from a ticket
+    HandshakeComplete { transfer_id: String, peer: Option<String>, resumed: bool },
+    /// The peer runs an older version; the listed features are off for
+    /// this transfer
+    CompatibilityWarning {
+        transfer_id: String,
+        device_id: String,
+        protocol_version: u16,
+        app_version: Option<String>,
+        disabled_features: Vec<String>,
+    },
+    ManifestSent { transfer_id: String, filename: String, size: u64, total_chunks: u64 },
+    ManifestReceived { transfer_id: String, filename: String, size: u64, total_chunks: u64 },
+    ChunkSent { transfer_id: String, index: u64, total: u64, bytes: u64 },
+    ChunkReceived { transfer_id: String, index: u64, total: u64, bytes: u64 },
+    TransferComplete { transfer_id: String, filename: String, size: u64 },
+    /// There is no ID if it failed before the handshake
+    TransferFailed { transfer_id: Option<String>, error: String },
+}
+
+impl From<&CoreEvent> for TransferEvent {
+    fn from(event: &CoreEvent) -> Self {
+        match event.clone() {
+            CoreEvent::HandshakeComplete { transfer_id, peer, resumed } => Self::HandshakeComplete {
+                transfer_id: transfer_id.to_string(),
+                peer: peer.map(|p| p.device_id),
+                resumed,
+            },
+            CoreEvent::CompatibilityWarning { transfer_id, device_id, protocol_version, app_version, disabled_features } => {
+                Self::CompatibilityWarning {
+                    transfer_id: transfer_id.to_string(),
+                    device_id,
+                    protocol_version,
+                    app_version,
+                    disabled_features,
+                }
+            }
+            CoreEvent::ManifestSent { transfer_id, filename, size, total_chunks } => Self::ManifestSent {
+                transfer_id: transfer_id.to_string(),
+                filename,
+                size,
+                total_chunks: total_chunks as u64,
+            },
+            CoreEvent::ManifestReceived { transfer_id, filename, size, total_chunks } => Self::ManifestReceived {
+                transfer_id: transfer_id.to_string(),
+                filename,
+                size,
+                total_chunks: total_chunks as u64,
+            },
+            CoreEvent::ChunkSent { transfer_id, index, total, bytes } => Self::ChunkSent {
+                transfer_id: transfer_id.to_string(),
+                index: index as u64,
+                total: total as u64,
+                bytes: bytes as u64,
+            },
+            CoreEvent::ChunkReceived { transfer_id, index, total, bytes } => Self::ChunkReceived {
+                transfer_id: transfer_id.to_string(),
+                index: index as u64,
+                total: total as u64,
+                bytes: bytes as u64,
+            },
+            CoreEvent::TransferComplete { transfer_id, filename, size } => {
+                Self::TransferComplete { transfer_id: transfer_id.to_string(), filename, size }
+            }
+            CoreEvent::TransferFailed { transfer_id, error } => {
+                Self::TransferFailed { transfer_id: transfer_id.map(|id| id.to_string()), error }
+            }
+        }
+    }
+}
@@ -0,0 +1,65 @@
+//! Chunk write/read throughput for LocalStorage.
+//!
+//! Compare the default path against io_uring with:
+//!   cargo bench -p storage --bench chunk_io
+//!   cargo bench -p storage --bench chunk_io --features io-uring
+
+use std::sync::Arc;
+use std::time::Instant;
+use storage::{LocalStorage, Storage};
+use tokio::task::JoinSet;
+
+const CHUNK_SIZE: usize = 256 * 1024;
+const CHUNKS: usize = 512;
+const CONCURRENCY: usize = 32;
+
+fn main() -> anyhow::Result<()> {
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(run())
+}
+
+async fn run() -> anyhow::Result<()> {
+    let temp = tempfile::TempDir::new()?;
+    let storage = LocalStorage::new(temp.path().to_path_buf())?;
+    let chunks: Vec<Arc<Vec<u8>>> = (0..CHUNKS)
+        .map(|i| Arc::new((0..CHUNK_SIZE).map(|j| (i * 7 + j) as u8).collect()))
+        .collect();
+    let total_mb = (CHUNKS * CHUNK_SIZE) as f64 / (1024.0 * 1024.0);
+
+    let start = Instant::now();
+    let mut ids = Vec::with_capacity(CHUNKS);
+    for batch in chunks.chunks(CONCURRENCY) {
+        let mut set = JoinSet::new();
+        for chunk in batch {
+            let (storage, chunk) = (storage.clone(), chunk.clone());
+            set.spawn(async move { storage.put_chunk(&chunk).await });
+        }
+        while let Some(id) = set.join_next().await {
+            ids.push(id??);
+        }
+    }
+    let write = start.elapsed();
+
+    let start = Instant::now();
+    for batch in ids.chunks(CONCURRENCY) {
+        let mut set = JoinSet::new();
+        for id in batch {
+            let (storage, id) = (storage.clone(), id.clone());
+            set.spawn(async move { storage.get_chunk(&id).await });
+        }
+        while let Some(data) = set.join_next().await {
+            assert!(data??.is_some());
+        }
+    }
+    let read = start.elapsed();
+
+    println!(
+        "io_uring={} write {:.0} MiB/s, read {:.0} MiB/s ({} x {} KiB)",
+        storage.uses_io_uring(),
+        total_mb / write.as_secs_f64(),
+        total_mb / read.as_secs_f64(),
+        CHUNKS,
+        CHUNK_SIZE / 1024,
+    );
+    Ok(())
+}
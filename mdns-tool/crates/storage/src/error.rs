@@ -0,0 +1,93 @@
+//! Errors of chunk stores.
+
+use thiserror::Error;
+
+/// A failed [`Storage`](crate::Storage) operation.
+///
+/// I/O, index and TLS errors keep the error that caused them as their
+/// source, so callers can still find e.g. a full disk's
+/// [`std::io::ErrorKind`] in the chain.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// Reading or writing chunk files, or talking to an object store
+    #[error("{context}")]
+    Io { context: String, #[source] source: std::io::Error },
+    /// Reading or updating the chunk index of a
+    /// [`LocalStorage`](crate::LocalStorage)
+    #[error("{context}")]
+    Index { context: String, #[source] source: rusqlite::Error },
+    /// Setting up a TLS connection to an object store
+    #[error("{context}")]
+    Tls { context: String, #[source] source: Box<dyn std::error::Error + Send + Sync> },
+    /// The object store refused a request, or answered with something that
+    /// could not be read
+    #[error("{0}")]
+    Remote(String),
+    /// A chunk id that is not hex, which could name paths outside the store
+    #[error("Invalid chunk id {0:?}")]
+    InvalidId(String),
+    /// A manifest to keep chunks for was never recorded with
+    /// [`Storage::add_manifest`](crate::Storage::add_manifest)
+    #[error("Manifest {0} is not recorded in storage")]
+    UnknownManifest(String),
+    /// This store does not support the operation
+    #[error("This store cannot {0}")]
+    Unsupported(String),
+    /// The store is not configured correctly
+    #[error("{0}")]
+    Config(String),
+    /// From a store wrapping another, e.g. one encrypting its chunks
+    #[error(transparent)]
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl From<std::io::Error> for Error {
+    fn from(source: std::io::Error) -> Self {
+        Self::Io { context: "Chunk store I/O failed".into(), source }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(source: rusqlite::Error) -> Self {
+        Self::Index { context: "Chunk index query failed".into(), source }
+    }
+}
+
+impl From<tokio::task::JoinError> for Error {
+    fn from(e: tokio::task::JoinError) -> Self {
+        Self::Io { context: "Storage task failed".into(), source: std::io::Error::other(e) }
+    }
+}
+
+impl Error {
+    /// Say what was being done when this happened: the context of I/O,
+    /// index and TLS errors is replaced, remote ones are prefixed, and the
+    /// rest explain themselves.
+    fn within(self, context: String) -> Self {
+        match self {
+            Self::Io { source, .. } => Self::Io { context, source },
+            Self::Index { source, .. } => Self::Index { context, source },
+            Self::Tls { source, .. } => Self::Tls { context, source },
+            Self::Remote(message) => Self::Remote(format!("{}: {}", context, message)),
+            other => other,
+        }
+    }
+}
+
+/// Adds what was being done to errors on their way out of the store.
+pub(crate) trait Context<T> {
+    fn context(self, context: &str) -> Result<T>;
+    fn with_context<C: Into<String>>(self, context: impl FnOnce() -> C) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> Context<T> for std::result::Result<T, E> {
+    fn context(self, context: &str) -> Result<T> {
+        self.map_err(|e| e.into().within(context.to_string()))
+    }
+
+    fn with_context<C: Into<String>>(self, context: impl FnOnce() -> C) -> Result<T> {
+        self.map_err(|e| e.into().within(context().into()))
+    }
+}
@@ -0,0 +1,282 @@
+//! SQLite index of the chunks and manifests in a [`LocalStorage`](crate::LocalStorage).
+//!
+//! `chunks.db` records each chunk's size, when it was stored and when it
+//! was last used, and which chunks each added manifest uses and how often.
+//! Existence checks, listings, garbage collection, eviction and statistics
+//! are answered from it instead of walking the chunk directories.
+//!
+//! The chunk files stay authoritative: the index is updated after a file
+//! is written and before it is deleted, so a crash can at worst leave the
+//! index naming a chunk that is gone (read as missing) or missing one that
+//! is there (received again). A missing index is rebuilt from the files.
+
+use crate::{ChunkInfo, StorageStats};
+use crate::error::{Context, Error, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// File name of the index inside the storage directory.
+pub const INDEX_FILE: &str = "chunks.db";
+
+/// Bumped whenever the schema changes; older indexes are rebuilt.
+const SCHEMA_VERSION: i32 = 1;
+
+const SCHEMA: &str = "
+    CREATE TABLE chunks (
+        id TEXT PRIMARY KEY,
+        size INTEGER NOT NULL,
+        created INTEGER NOT NULL,
+        used INTEGER NOT NULL
+    );
+    CREATE INDEX chunks_by_use ON chunks (used);
+    CREATE TABLE manifests (
+        id TEXT PRIMARY KEY,
+        added INTEGER NOT NULL
+    );
+    CREATE TABLE manifest_chunks (
+        manifest TEXT NOT NULL REFERENCES manifests (id) ON DELETE CASCADE,
+        chunk TEXT NOT NULL,
+        count INTEGER NOT NULL,
+        PRIMARY KEY (manifest, chunk)
+    );
+    CREATE INDEX manifest_chunks_by_chunk ON manifest_chunks (chunk);
+";
+
+pub(crate) struct ChunkIndex {
+    conn: Mutex<Connection>,
+}
+
+/// A chunk chosen by [`ChunkIndex::unused`] for deletion.
+pub(crate) struct Unused {
+    pub id: String,
+    pub size: u64,
+}
+
+impl ChunkIndex {
+    /// Open the index in `base_dir`, building it from `chunks_dir` (and
+    /// the manifest lists older versions kept in `refs_dir`, which are
+    /// imported and removed) if it is missing or outdated.
+    pub fn open(base_dir: &Path, chunks_dir: &Path, refs_dir: &Path) -> Result<Self> {
+        let path = base_dir.join(INDEX_FILE);
+        let mut conn = Connection::open(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        // Other processes (e.g. `storage gc` next to a listener) share the file.
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+
+        let version: i32 = conn.pragma_query_value(None, "user_version", |r| r.get(0))?;
+        if version != SCHEMA_VERSION {
+            rebuild(&mut conn, chunks_dir, refs_dir)
+                .with_context(|| format!("Failed to build {}", path.display()))?;
+        }
+        Ok(Self { conn: Mutex::new(conn) })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap()
+    }
+
+    /// Record a chunk that was just written, as most recently used.
+    pub fn insert(&self, id: &str, size: u64) -> Result<()> {
+        self.lock().execute(
+            "INSERT INTO chunks (id, size, created, used)
+             VALUES (?1, ?2, ?3, (SELECT COALESCE(MAX(used), 0) + 1 FROM chunks))
+             ON CONFLICT (id) DO UPDATE SET size = excluded.size, used = excluded.used",
+            params![id, size as i64, unix_secs(SystemTime::now()) as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Mark a chunk as most recently used.
+    pub fn touch(&self, id: &str) -> Result<()> {
+        self.lock().execute(
+            "UPDATE chunks SET used = (SELECT MAX(used) + 1 FROM chunks) WHERE id = ?1",
+            [id],
+        )?;
+        Ok(())
+    }
+
+    pub fn contains(&self, id: &str) -> Result<bool> {
+        Ok(self.lock()
+            .query_row("SELECT 1 FROM chunks WHERE id = ?1", [id], |_| Ok(()))
+            .optional()?
+            .is_some())
+    }
+
+    /// Forget chunks whose files were deleted.
+    pub fn remove(&self, ids: &[String]) -> Result<()> {
+        let mut conn = self.lock();
+        let tx = conn.transaction()?;
+        {
+            let mut delete = tx.prepare("DELETE FROM chunks WHERE id = ?1")?;
+            for id in ids {
+                delete.execute([id])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn list(&self, after: Option<&str>, limit: usize) -> Result<Vec<String>> {
+        let conn = self.lock();
+        let mut query = conn.prepare("SELECT id FROM chunks WHERE id > ?1 ORDER BY id LIMIT ?2")?;
+        let ids = query.query_map(params![after.unwrap_or(""), limit as i64], |r| r.get(0))?;
+        Ok(ids.collect::<rusqlite::Result<_>>()?)
+    }
+
+    pub fn describe(&self, after: Option<&str>, limit: usize) -> Result<Vec<ChunkInfo>> {
+        let conn = self.lock();
+        let mut query = conn.prepare(
+            "SELECT id, size, created,
+                    (SELECT COUNT(*) FROM manifest_chunks WHERE chunk = chunks.id)
+             FROM chunks WHERE id > ?1 ORDER BY id LIMIT ?2",
+        )?;
+        let chunks = query.query_map(params![after.unwrap_or(""), limit as i64], |r| {
+            Ok(ChunkInfo {
+                id: r.get(0)?,
+                size: r.get::<_, i64>(1)? as u64,
+                created: r.get::<_, i64>(2)? as u64,
+                manifests: r.get::<_, i64>(3)? as u64,
+            })
+        })?;
+        Ok(chunks.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Record (or replace) the chunk list of a manifest.
+    pub fn add_manifest(&self, manifest_id: &str, chunks: &[String]) -> Result<()> {
+        let mut conn = self.lock();
+        let tx = conn.transaction()?;
+        insert_manifest(&tx, manifest_id, chunks, unix_secs(SystemTime::now()))?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Chunks not used by a manifest in `retain`, least recently used
+    /// first. Fails for a manifest that was never added, rather than
+    /// letting its chunks be deleted. With `forget_others`, manifests not
+    /// in `retain` are forgotten as well.
+    pub fn unused(&self, retain: &[String], forget_others: bool) -> Result<Vec<Unused>> {
+        let mut conn = self.lock();
+        let tx = conn.transaction()?;
+        tx.execute("CREATE TEMP TABLE IF NOT EXISTS retained (id TEXT PRIMARY KEY)", [])?;
+        tx.execute("DELETE FROM retained", [])?;
+        {
+            let mut known = tx.prepare("SELECT 1 FROM manifests WHERE id = ?1")?;
+            let mut add = tx.prepare("INSERT OR IGNORE INTO retained (id) VALUES (?1)")?;
+            for id in retain {
+                if !known.exists([id])? {
+                    return Err(Error::UnknownManifest(id.clone()));
+                }
+                add.execute([id])?;
+            }
+        }
+        if forget_others {
+            tx.execute("DELETE FROM manifests WHERE id NOT IN (SELECT id FROM retained)", [])?;
+        }
+        let unused = {
+            let mut query = tx.prepare(
+                "SELECT id, size FROM chunks WHERE id NOT IN (
+                     SELECT chunk FROM manifest_chunks WHERE manifest IN (SELECT id FROM retained)
+                 ) ORDER BY used",
+            )?;
+            let rows = query.query_map([], |r| {
+                Ok(Unused { id: r.get(0)?, size: r.get::<_, i64>(1)? as u64 })
+            })?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()?
+        };
+        tx.commit()?;
+        Ok(unused)
+    }
+
+    pub fn total_bytes(&self) -> Result<u64> {
+        let total: i64 = self.lock().query_row("SELECT COALESCE(SUM(size), 0) FROM chunks", [], |r| r.get(0))?;
+        Ok(total as u64)
+    }
+
+    pub fn stats(&self) -> Result<StorageStats> {
+        let conn = self.lock();
+        let (chunks, bytes): (i64, i64) = conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(size), 0) FROM chunks", [], |r| Ok((r.get(0)?, r.get(1)?)),
+        )?;
+        let manifests: i64 = conn.query_row("SELECT COUNT(*) FROM manifests", [], |r| r.get(0))?;
+        let referenced: i64 = conn.query_row(
+            "SELECT COALESCE(SUM(m.count * c.size), 0)
+             FROM manifest_chunks m JOIN chunks c ON c.id = m.chunk",
+            [], |r| r.get(0),
+        )?;
+        Ok(StorageStats {
+            chunks: chunks as u64,
+            bytes: bytes as u64,
+            manifests: manifests as u64,
+            referenced_bytes: referenced as u64,
+        })
+    }
+}
+
+fn insert_manifest(tx: &rusqlite::Transaction, manifest_id: &str, chunks: &[String], added: u64) -> Result<()> {
+    let mut counts: HashMap<&str, i64> = HashMap::new();
+    for chunk in chunks {
+        *counts.entry(chunk).or_default() += 1;
+    }
+    tx.execute("DELETE FROM manifests WHERE id = ?1", [manifest_id])?;
+    tx.execute("INSERT INTO manifests (id, added) VALUES (?1, ?2)", params![manifest_id, added as i64])?;
+    let mut add = tx.prepare("INSERT INTO manifest_chunks (manifest, chunk, count) VALUES (?1, ?2, ?3)")?;
+    for (chunk, count) in counts {
+        add.execute(params![manifest_id, chunk, count])?;
+    }
+    Ok(())
+}
+
+/// Fill a fresh index from the chunk files, ordering use by modification
+/// time as earlier versions did, and import the old `refs/` manifest lists.
+fn rebuild(conn: &mut Connection, chunks_dir: &Path, refs_dir: &Path) -> Result<()> {
+    let mut chunks = crate::scan_chunks(chunks_dir)?;
+    chunks.sort_by_key(|c| c.modified);
+
+    let tx = conn.transaction()?;
+    tx.execute_batch(
+        "DROP TABLE IF EXISTS manifest_chunks;
+         DROP TABLE IF EXISTS manifests;
+         DROP TABLE IF EXISTS chunks;",
+    )?;
+    tx.execute_batch(SCHEMA)?;
+    {
+        let mut add = tx.prepare("INSERT INTO chunks (id, size, created, used) VALUES (?1, ?2, ?3, ?4)")?;
+        for (used, chunk) in chunks.iter().enumerate() {
+            add.execute(params![chunk.id, chunk.size as i64, unix_secs(chunk.modified) as i64, used as i64 + 1])?;
+        }
+    }
+
+    let mut imported = 0;
+    if refs_dir.exists() {
+        for entry in std::fs::read_dir(refs_dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.ends_with(".tmp") {
+                continue;
+            }
+            let list = std::fs::read_to_string(entry.path())?;
+            let ids: Vec<String> = list.lines().map(str::to_string).collect();
+            let added = entry.metadata()?.modified().map(unix_secs).unwrap_or(0);
+            insert_manifest(&tx, &name, &ids, added)?;
+            imported += 1;
+        }
+    }
+    tx.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+    tx.commit()?;
+
+    if refs_dir.exists() {
+        std::fs::remove_dir_all(refs_dir)?;
+    }
+    tracing::info!("Indexed {} stored chunks and {} manifests", chunks.len(), imported);
+    Ok(())
+}
+
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
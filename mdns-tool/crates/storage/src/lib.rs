@@ -1,21 +1,181 @@
-use anyhow::{Result, Context};
 use async_trait::async_trait;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
 use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 use sha2::{Digest, Sha256};
-use hex;
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+mod error;
+mod index;
+mod memory;
+#[cfg(feature = "s3")]
+mod object;
+
+use error::Context;
+
+pub use error::{Error, Result};
+pub use index::INDEX_FILE;
+pub use memory::MemoryStorage;
+#[cfg(feature = "s3")]
+pub use object::{ObjectStorage, ObjectStorageConfig};
+
+/// A stored chunk being read with [`Storage::get_chunk_stream`].
+pub type ChunkReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Content address of a chunk: hex SHA-256 of its bytes.
+pub fn chunk_id(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
 
 /// Storage trait for chunk persistence.
 #[async_trait]
 pub trait Storage: Send + Sync {
     async fn put_chunk(&self, data: &[u8]) -> Result<String>;
     async fn get_chunk(&self, id: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Store `data` under `id` without deriving the id from it, for
+    /// wrappers that transform chunk contents but keep the ids of the
+    /// original data.
+    async fn put_chunk_as(&self, id: &str, _data: &[u8]) -> Result<()> {
+        Err(Error::Unsupported(format!("put chunk {} under a given id", id)))
+    }
+
+    /// Whether a chunk is already stored.
+    async fn has_chunk(&self, id: &str) -> Result<bool> {
+        Ok(self.get_chunk(id).await?.is_some())
+    }
+
+    /// Delete a chunk, returning whether it was stored. Manifests that use
+    /// it are not checked; see [`gc`](Self::gc) for that.
+    async fn delete_chunk(&self, id: &str) -> Result<bool>;
+
+    /// Up to `limit` chunk ids in ascending order, starting after `after`.
+    /// Pass the last id of one page as `after` to get the next; a page
+    /// shorter than `limit` is the last.
+    async fn list_chunks(&self, after: Option<&str>, limit: usize) -> Result<Vec<String>>;
+
+    /// Store a chunk read to the end from `reader`. The default buffers
+    /// the whole chunk and calls [`put_chunk`](Self::put_chunk).
+    async fn put_chunk_stream(&self, reader: &mut (dyn AsyncRead + Send + Unpin)) -> Result<String> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await?;
+        self.put_chunk(&data).await
+    }
+
+    /// A reader over a stored chunk, or `None` if it is not stored. The
+    /// default reads the whole chunk with [`get_chunk`](Self::get_chunk).
+    async fn get_chunk_stream(&self, id: &str) -> Result<Option<ChunkReader>> {
+        Ok(self.get_chunk(id).await?.map(|data| Box::new(std::io::Cursor::new(data)) as ChunkReader))
+    }
+
+    /// Record that manifest `manifest_id` uses `chunks`, so [`gc`](Self::gc)
+    /// and [`evict`](Self::evict) know which chunks to keep for it.
+    async fn add_manifest(&self, _manifest_id: &str, _chunks: &[String]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Delete every chunk not used by a manifest in `retain`, and forget
+    /// all other manifests. Stores that cannot enumerate their chunks keep
+    /// everything.
+    async fn gc(&self, _retain: &[String]) -> Result<GcStats> {
+        Ok(GcStats::default())
+    }
+
+    /// Delete least recently used chunks not used by a manifest in
+    /// `retain` until the store holds at most `max_bytes`.
+    async fn evict(&self, _max_bytes: u64, _retain: &[String]) -> Result<GcStats> {
+        Ok(GcStats::default())
+    }
+
+    async fn stats(&self) -> Result<StorageStats> {
+        Ok(StorageStats::default())
+    }
+
+    /// Like [`list_chunks`](Self::list_chunks), with what the store knows
+    /// about each chunk.
+    async fn describe_chunks(&self, _after: Option<&str>, _limit: usize) -> Result<Vec<ChunkInfo>> {
+        Err(Error::Unsupported("describe its chunks".into()))
+    }
+
+    /// Read a chunk back and check that it still hashes to its id, which
+    /// catches bit rot and partly written chunks. An error means it could
+    /// not be read at all. The default reads it with
+    /// [`get_chunk`](Self::get_chunk).
+    async fn verify_chunk(&self, id: &str) -> Result<ChunkHealth> {
+        Ok(ChunkHealth::of(id, self.get_chunk(id).await?.as_deref()))
+    }
+}
+
+/// What [`Storage::verify_chunk`] found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChunkHealth {
+    Intact,
+    /// Not stored (any more)
+    Missing,
+    /// Stored, but its content hashes to `actual`
+    Corrupt { actual: String },
+}
+
+impl ChunkHealth {
+    /// Check `data`, as read back for `id`.
+    pub fn of(id: &str, data: Option<&[u8]>) -> Self {
+        match data.map(chunk_id) {
+            None => ChunkHealth::Missing,
+            Some(actual) if actual == id => ChunkHealth::Intact,
+            Some(actual) => ChunkHealth::Corrupt { actual },
+        }
+    }
+}
+
+/// Chunks removed by [`Storage::gc`] or [`Storage::evict`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcStats {
+    pub chunks: u64,
+    pub bytes: u64,
+}
+
+/// Contents of a store.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StorageStats {
+    pub chunks: u64,
+    pub bytes: u64,
+    /// Manifests recorded with [`Storage::add_manifest`]
+    pub manifests: u64,
+    /// Stored bytes those manifests use, counting a chunk once per use;
+    /// the difference to `bytes` is what deduplication saves.
+    pub referenced_bytes: u64,
+}
+
+/// One chunk as listed by [`Storage::describe_chunks`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkInfo {
+    pub id: String,
+    pub size: u64,
+    /// When the chunk was first stored, as a Unix timestamp
+    pub created: u64,
+    /// Recorded manifests that use the chunk
+    pub manifests: u64,
 }
 
 /// Local filesystem-based storage implementation.
+///
+/// Chunks live under `chunks/`. Their sizes and use, and the chunk lists
+/// of manifests added with [`Storage::add_manifest`], are kept in the
+/// SQLite index [`INDEX_FILE`], which answers existence checks, listings,
+/// [`Storage::gc`], [`Storage::evict`] (least recently read or written
+/// first) and [`Storage::stats`] without walking the chunk files. Streamed
+/// chunks are written to a temporary file in `chunks/` while being hashed,
+/// then renamed into place.
 #[derive(Clone)]
 pub struct LocalStorage {
     chunks_dir: PathBuf,
+    index: Arc<index::ChunkIndex>,
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    uring: Option<uring::UringFiles>,
 }
 
 impl LocalStorage {
@@ -24,7 +184,67 @@ impl LocalStorage {
         std::fs::create_dir_all(&chunks_dir)
             .context("Failed to create chunks directory")?;
 
-        Ok(Self { chunks_dir })
+        let index = index::ChunkIndex::open(&base_dir, &chunks_dir, &base_dir.join("refs"))?;
+
+        Ok(Self {
+            chunks_dir,
+            index: Arc::new(index),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            uring: match uring::UringFiles::new() {
+                Ok(u) => Some(u),
+                Err(e) => {
+                    tracing::warn!("io_uring unavailable ({}); using standard file IO", e);
+                    None
+                }
+            },
+        })
+    }
+
+    /// Whether chunk IO goes through io_uring.
+    pub fn uses_io_uring(&self) -> bool {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        return self.uring.is_some();
+        #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+        false
+    }
+
+    async fn write_file(&self, path: &std::path::Path, data: &[u8]) -> std::io::Result<()> {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if let Some(u) = &self.uring {
+            return u.write(path.to_path_buf(), data.to_vec()).await;
+        }
+        fs::write(path, data).await
+    }
+
+    async fn read_file(&self, path: &std::path::Path) -> std::io::Result<Option<Vec<u8>>> {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if let Some(u) = &self.uring {
+            return u.read(path.to_path_buf()).await;
+        }
+        if !path.exists() {
+            return Ok(None);
+        }
+        fs::read(path).await.map(Some)
+    }
+
+    /// Copy `reader` into the new file `tmp`, returning the chunk id.
+    async fn write_stream(&self, tmp: &Path, reader: &mut (dyn AsyncRead + Send + Unpin)) -> Result<(String, u64)> {
+        let mut out = fs::File::create(tmp).await
+            .context("Failed to create incoming chunk file")?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; STREAM_BUFFER];
+        let mut len = 0u64;
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            out.write_all(&buf[..n]).await?;
+            len += n as u64;
+        }
+        out.flush().await?;
+        Ok((hex::encode(hasher.finalize()), len))
     }
 
     fn chunk_path(&self, chunk_id: &str) -> PathBuf {
@@ -32,45 +252,299 @@ impl LocalStorage {
         let prefix = &chunk_id[..2.min(chunk_id.len())];
         self.chunks_dir.join(prefix).join(chunk_id)
     }
+
+    /// Run `f` on the index off the async runtime.
+    async fn with_index<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&index::ChunkIndex) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let index = self.index.clone();
+        tokio::task::spawn_blocking(move || f(&index)).await?
+    }
+
+    /// Mark a chunk as recently used for eviction; best effort.
+    async fn touch(&self, id: &str) {
+        let id = id.to_string();
+        if let Err(e) = self.with_index(move |index| index.touch(&id)).await {
+            tracing::warn!("Failed to record chunk use: {:#}", e);
+        }
+    }
+
+    fn gc_blocking(&self, retain: &[String]) -> Result<GcStats> {
+        let unused = self.index.unused(retain, true)?;
+        self.delete_blocking(&unused)
+    }
+
+    fn evict_blocking(&self, max_bytes: u64, retain: &[String]) -> Result<GcStats> {
+        let mut total = self.index.total_bytes()?;
+        let mut victims = Vec::new();
+        for chunk in self.index.unused(retain, false)? {
+            if total <= max_bytes {
+                break;
+            }
+            total -= chunk.size;
+            victims.push(chunk);
+        }
+        if total > max_bytes {
+            tracing::warn!("Storage holds {} bytes of retained chunks, over the {} byte quota", total, max_bytes);
+        }
+        self.delete_blocking(&victims)
+    }
+
+    /// Delete chunks, dropping them from the index first so it never
+    /// names a chunk whose file is gone.
+    fn delete_blocking(&self, chunks: &[index::Unused]) -> Result<GcStats> {
+        let ids: Vec<String> = chunks.iter().map(|c| c.id.clone()).collect();
+        self.index.remove(&ids)?;
+
+        let mut stats = GcStats::default();
+        for chunk in chunks {
+            match std::fs::remove_file(self.chunk_path(&chunk.id)) {
+                Ok(()) => {
+                    stats.chunks += 1;
+                    stats.bytes += chunk.size;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e).with_context(|| format!("Failed to delete chunk {}", chunk.id)),
+            }
+        }
+        Ok(stats)
+    }
+}
+
+/// Read size used when streaming a chunk in.
+const STREAM_BUFFER: usize = 64 * 1024;
+
+/// Distinguishes concurrent streamed writes to temporary files.
+static NEXT_INCOMING: AtomicU64 = AtomicU64::new(0);
+
+/// Refuse ids that are not hex, which could name paths outside the store.
+fn check_id(id: &str) -> Result<()> {
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Error::InvalidId(id.to_string()));
+    }
+    Ok(())
+}
+
+/// A chunk file found by [`scan_chunks`] when (re)building the index.
+struct StoredChunk {
+    id: String,
+    size: u64,
+    /// Last write, or last read under earlier versions
+    modified: SystemTime,
+}
+
+fn scan_chunks(chunks_dir: &Path) -> Result<Vec<StoredChunk>> {
+    let mut chunks = Vec::new();
+    for prefix in std::fs::read_dir(chunks_dir)? {
+        let prefix = prefix?;
+        if !prefix.file_type()?.is_dir() {
+            continue;
+        }
+        for entry in std::fs::read_dir(prefix.path())? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            chunks.push(StoredChunk {
+                id: entry.file_name().to_string_lossy().into_owned(),
+                size: meta.len(),
+                modified: meta.modified()?,
+            });
+        }
+    }
+    Ok(chunks)
 }
 
 #[async_trait]
 impl Storage for LocalStorage {
     async fn put_chunk(&self, data: &[u8]) -> Result<String> {
-        // Compute chunk ID as SHA-256 hash
-        let mut hasher = Sha256::new();
-        hasher.update(data);
-        let hash = hasher.finalize();
-        let chunk_id = hex::encode(hash);
+        let chunk_id = chunk_id(data);
+        self.put_chunk_as(&chunk_id, data).await?;
+        Ok(chunk_id)
+    }
 
-        let path = self.chunk_path(&chunk_id);
+    async fn get_chunk(&self, id: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.chunk_path(id);
+
+        let Some(data) = self.read_file(&path).await
+            .with_context(|| format!("Failed to read chunk {}", id))? else {
+            return Ok(None);
+        };
+
+        self.touch(id).await;
+        tracing::debug!("Retrieved chunk {} ({} bytes)", id, data.len());
+        Ok(Some(data))
+    }
 
-        // Create parent directory if needed
+    async fn put_chunk_as(&self, id: &str, data: &[u8]) -> Result<()> {
+        check_id(id)?;
+        let path = self.chunk_path(id);
         if let Some(parent) = path.parent() {
             fs::create_dir_all(parent).await
                 .context("Failed to create chunk subdirectory")?;
         }
+        self.write_file(&path, data).await
+            .with_context(|| format!("Failed to write chunk {}", id))?;
+        let (id_owned, size) = (id.to_string(), data.len() as u64);
+        self.with_index(move |index| index.insert(&id_owned, size)).await?;
 
-        // Write chunk to disk
-        fs::write(&path, data).await
+        tracing::debug!("Stored chunk {} ({} bytes)", id, data.len());
+        Ok(())
+    }
+
+    async fn has_chunk(&self, id: &str) -> Result<bool> {
+        let id = id.to_string();
+        self.with_index(move |index| index.contains(&id)).await
+    }
+
+    async fn delete_chunk(&self, id: &str) -> Result<bool> {
+        check_id(id)?;
+        let owned = id.to_string();
+        self.with_index(move |index| index.remove(&[owned])).await?;
+        match fs::remove_file(self.chunk_path(id)).await {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e).with_context(|| format!("Failed to delete chunk {}", id)),
+        }
+    }
+
+    async fn list_chunks(&self, after: Option<&str>, limit: usize) -> Result<Vec<String>> {
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+        let after = after.map(str::to_string);
+        self.with_index(move |index| index.list(after.as_deref(), limit)).await
+    }
+
+    async fn put_chunk_stream(&self, reader: &mut (dyn AsyncRead + Send + Unpin)) -> Result<String> {
+        let tmp = self.chunks_dir.join(format!(
+            ".incoming-{}-{}",
+            std::process::id(),
+            NEXT_INCOMING.fetch_add(1, Ordering::Relaxed)
+        ));
+        let (chunk_id, len) = match self.write_stream(&tmp, reader).await {
+            Ok(written) => written,
+            Err(e) => {
+                let _ = fs::remove_file(&tmp).await;
+                return Err(e);
+            }
+        };
+
+        let path = self.chunk_path(&chunk_id);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await
+                .context("Failed to create chunk subdirectory")?;
+        }
+        fs::rename(&tmp, &path).await
             .with_context(|| format!("Failed to write chunk {}", chunk_id))?;
+        let id = chunk_id.clone();
+        self.with_index(move |index| index.insert(&id, len)).await?;
 
-        tracing::debug!("Stored chunk {} ({} bytes)", chunk_id, data.len());
+        tracing::debug!("Stored streamed chunk {} ({} bytes)", chunk_id, len);
         Ok(chunk_id)
     }
 
-    async fn get_chunk(&self, id: &str) -> Result<Option<Vec<u8>>> {
+    async fn get_chunk_stream(&self, id: &str) -> Result<Option<ChunkReader>> {
         let path = self.chunk_path(id);
+        let file = match fs::File::open(&path).await {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read chunk {}", id)),
+        };
+        self.touch(id).await;
+        Ok(Some(Box::new(file)))
+    }
 
-        if !path.exists() {
-            return Ok(None);
-        }
+    async fn add_manifest(&self, manifest_id: &str, chunks: &[String]) -> Result<()> {
+        let (id, chunks) = (manifest_id.to_string(), chunks.to_vec());
+        self.with_index(move |index| index.add_manifest(&id, &chunks)).await
+            .with_context(|| format!("Failed to record manifest {}", manifest_id))
+    }
+
+    async fn gc(&self, retain: &[String]) -> Result<GcStats> {
+        let (this, retain) = (self.clone(), retain.to_vec());
+        tokio::task::spawn_blocking(move || this.gc_blocking(&retain)).await?
+    }
+
+    async fn evict(&self, max_bytes: u64, retain: &[String]) -> Result<GcStats> {
+        let (this, retain) = (self.clone(), retain.to_vec());
+        tokio::task::spawn_blocking(move || this.evict_blocking(max_bytes, &retain)).await?
+    }
+
+    async fn stats(&self) -> Result<StorageStats> {
+        self.with_index(|index| index.stats()).await
+    }
+
+    async fn describe_chunks(&self, after: Option<&str>, limit: usize) -> Result<Vec<ChunkInfo>> {
+        let after = after.map(str::to_string);
+        self.with_index(move |index| index.describe(after.as_deref(), limit)).await
+    }
 
-        let data = fs::read(&path).await
+    async fn verify_chunk(&self, id: &str) -> Result<ChunkHealth> {
+        // Not through get_chunk: checking a chunk is no use of it.
+        let data = self.read_file(&self.chunk_path(id)).await
             .with_context(|| format!("Failed to read chunk {}", id))?;
+        Ok(ChunkHealth::of(id, data.as_deref()))
+    }
+}
 
-        tracing::debug!("Retrieved chunk {} ({} bytes)", id, data.len());
-        Ok(Some(data))
+/// Shared stores, including `Arc<dyn Storage>` for a backend chosen at
+/// runtime.
+#[async_trait]
+impl<T: Storage + ?Sized> Storage for Arc<T> {
+    async fn put_chunk(&self, data: &[u8]) -> Result<String> {
+        (**self).put_chunk(data).await
+    }
+
+    async fn get_chunk(&self, id: &str) -> Result<Option<Vec<u8>>> {
+        (**self).get_chunk(id).await
+    }
+
+    async fn put_chunk_as(&self, id: &str, data: &[u8]) -> Result<()> {
+        (**self).put_chunk_as(id, data).await
+    }
+
+    async fn has_chunk(&self, id: &str) -> Result<bool> {
+        (**self).has_chunk(id).await
+    }
+
+    async fn delete_chunk(&self, id: &str) -> Result<bool> {
+        (**self).delete_chunk(id).await
+    }
+
+    async fn list_chunks(&self, after: Option<&str>, limit: usize) -> Result<Vec<String>> {
+        (**self).list_chunks(after, limit).await
+    }
+
+    async fn put_chunk_stream(&self, reader: &mut (dyn AsyncRead + Send + Unpin)) -> Result<String> {
+        (**self).put_chunk_stream(reader).await
+    }
+
+    async fn get_chunk_stream(&self, id: &str) -> Result<Option<ChunkReader>> {
+        (**self).get_chunk_stream(id).await
+    }
+
+    async fn add_manifest(&self, manifest_id: &str, chunks: &[String]) -> Result<()> {
+        (**self).add_manifest(manifest_id, chunks).await
+    }
+
+    async fn gc(&self, retain: &[String]) -> Result<GcStats> {
+        (**self).gc(retain).await
+    }
+
+    async fn evict(&self, max_bytes: u64, retain: &[String]) -> Result<GcStats> {
+        (**self).evict(max_bytes, retain).await
+    }
+
+    async fn stats(&self) -> Result<StorageStats> {
+        (**self).stats().await
+    }
+
+    async fn describe_chunks(&self, after: Option<&str>, limit: usize) -> Result<Vec<ChunkInfo>> {
+        (**self).describe_chunks(after, limit).await
+    }
+
+    async fn verify_chunk(&self, id: &str) -> Result<ChunkHealth> {
+        (**self).verify_chunk(id).await
     }
 }
 
@@ -95,4 +569,105 @@ mod tests {
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_gc_and_eviction() -> Result<()> {
+        let temp = TempDir::new()?;
+        let storage = LocalStorage::new(temp.path().to_path_buf())?;
+        let mut ids = Vec::new();
+        for i in 0..4u8 {
+            ids.push(storage.put_chunk(&[i; 100]).await?);
+        }
+        storage.add_manifest("kept", &ids[..1]).await?;
+        storage.add_manifest("dropped", &ids[1..2]).await?;
+
+        // Unknown manifests are refused rather than treated as empty.
+        assert!(storage.gc(&["unknown".into()]).await.is_err());
+
+        // Reading chunk 1 marks it used, leaving chunks 2 and 3 as the
+        // least recently used; chunk 0 is retained however old it is.
+        storage.get_chunk(&ids[1]).await?;
+        let evicted = storage.evict(250, &["kept".into()]).await?;
+        assert_eq!(evicted, GcStats { chunks: 2, bytes: 200 });
+        assert!(storage.has_chunk(&ids[0]).await? && storage.has_chunk(&ids[1]).await?);
+        assert!(!storage.chunk_path(&ids[2]).exists());
+
+        let collected = storage.gc(&["kept".into()]).await?;
+        assert_eq!(collected, GcStats { chunks: 1, bytes: 100 });
+        assert_eq!(storage.stats().await?, StorageStats { chunks: 1, bytes: 100, manifests: 1, referenced_bytes: 100 });
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_streaming_listing_and_delete() -> Result<()> {
+        let temp = TempDir::new()?;
+        let storage = LocalStorage::new(temp.path().to_path_buf())?;
+
+        // A streamed chunk gets the same id as a buffered one, and no
+        // temporary file is left behind.
+        let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let id = storage.put_chunk_stream(&mut &data[..]).await?;
+        assert_eq!(id, chunk_id(&data));
+        let mut read = Vec::new();
+        storage.get_chunk_stream(&id).await?.expect("stored").read_to_end(&mut read).await?;
+        assert_eq!(read, data);
+        assert!(storage.get_chunk_stream("00ff").await?.is_none());
+        assert_eq!(storage.stats().await?.chunks, 1);
+        assert!(std::fs::read_dir(&storage.chunks_dir)?.all(|e| e.unwrap().file_type().unwrap().is_dir()));
+
+        for i in 0..9u8 {
+            storage.put_chunk(&[i; 10]).await?;
+        }
+        let mut all = Vec::new();
+        let mut after = None;
+        loop {
+            let page = storage.list_chunks(after.as_deref(), 4).await?;
+            all.extend(page.iter().cloned());
+            if page.len() < 4 {
+                break;
+            }
+            after = page.last().cloned();
+        }
+        let mut expected = storage.list_chunks(None, 100).await?;
+        assert_eq!(expected.len(), 10);
+        expected.sort();
+        assert_eq!(all, expected);
+
+        // A chunk damaged on disk no longer verifies.
+        assert_eq!(storage.verify_chunk(&id).await?, ChunkHealth::Intact);
+        std::fs::write(storage.chunk_path(&id), &data[..1000])?;
+        assert_eq!(storage.verify_chunk(&id).await?, ChunkHealth::Corrupt { actual: chunk_id(&data[..1000]) });
+
+        assert!(storage.delete_chunk(&id).await?);
+        assert!(!storage.delete_chunk(&id).await?);
+        assert_eq!(storage.verify_chunk(&id).await?, ChunkHealth::Missing);
+        assert!(storage.delete_chunk("../refs").await.is_err());
+        assert!(!storage.has_chunk(&id).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_index_built_from_older_store() -> Result<()> {
+        // A store written before the index: chunk files plus `refs/` lists.
+        let temp = TempDir::new()?;
+        let data = [7u8; 64];
+        let id = chunk_id(&data);
+        std::fs::create_dir_all(temp.path().join("chunks").join(&id[..2]))?;
+        std::fs::write(temp.path().join("chunks").join(&id[..2]).join(&id), data)?;
+        std::fs::create_dir_all(temp.path().join("refs"))?;
+        std::fs::write(temp.path().join("refs").join("m1"), format!("{id}\n{id}"))?;
+
+        let storage = LocalStorage::new(temp.path().to_path_buf())?;
+        assert!(!temp.path().join("refs").exists());
+        assert!(storage.has_chunk(&id).await?);
+        assert_eq!(storage.stats().await?, StorageStats { chunks: 1, bytes: 64, manifests: 1, referenced_bytes: 128 });
+        let listed = storage.describe_chunks(None, 10).await?;
+        assert_eq!((listed.len(), listed[0].size, listed[0].manifests), (1, 64, 1));
+
+        // Reopening uses the index as is, and the imported manifest is known.
+        drop(storage);
+        let storage = LocalStorage::new(temp.path().to_path_buf())?;
+        assert_eq!(storage.gc(&["m1".into()]).await?, GcStats::default());
+        Ok(())
+    }
+}
@@ -0,0 +1,199 @@
+//! In-memory chunk store, for tests and relays that only pass chunks on.
+
+use crate::{chunk_id, ChunkInfo, GcStats, Storage, StorageStats};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::{Error, Result};
+use async_trait::async_trait;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+/// Chunks held in memory and lost when the last clone is dropped.
+///
+/// Clones share the same chunks. Reads count as use for
+/// [`Storage::evict`], like in [`LocalStorage`](crate::LocalStorage).
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    chunks: BTreeMap<String, Chunk>,
+    refs: HashMap<String, Vec<String>>,
+    /// Logical clock for least-recently-used ordering
+    clock: u64,
+}
+
+struct Chunk {
+    data: Vec<u8>,
+    /// Unix timestamp of the first put
+    created: u64,
+    used: u64,
+}
+
+impl Inner {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn referenced(&self, retain: &[String]) -> Result<HashSet<String>> {
+        let mut keep = HashSet::new();
+        for id in retain {
+            let chunks = self.refs.get(id).ok_or_else(|| Error::UnknownManifest(id.clone()))?;
+            keep.extend(chunks.iter().cloned());
+        }
+        Ok(keep)
+    }
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap()
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn put_chunk(&self, data: &[u8]) -> Result<String> {
+        let id = chunk_id(data);
+        self.put_chunk_as(&id, data).await?;
+        Ok(id)
+    }
+
+    async fn get_chunk(&self, id: &str) -> Result<Option<Vec<u8>>> {
+        let mut inner = self.lock();
+        let now = inner.tick();
+        Ok(inner.chunks.get_mut(id).map(|chunk| {
+            chunk.used = now;
+            chunk.data.clone()
+        }))
+    }
+
+    async fn put_chunk_as(&self, id: &str, data: &[u8]) -> Result<()> {
+        let mut inner = self.lock();
+        let used = inner.tick();
+        let created = inner.chunks.get(id).map_or_else(
+            || SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            |c| c.created,
+        );
+        inner.chunks.insert(id.to_string(), Chunk { data: data.to_vec(), created, used });
+        Ok(())
+    }
+
+    async fn has_chunk(&self, id: &str) -> Result<bool> {
+        Ok(self.lock().chunks.contains_key(id))
+    }
+
+    async fn delete_chunk(&self, id: &str) -> Result<bool> {
+        Ok(self.lock().chunks.remove(id).is_some())
+    }
+
+    async fn list_chunks(&self, after: Option<&str>, limit: usize) -> Result<Vec<String>> {
+        let inner = self.lock();
+        let ids = inner.chunks.keys().filter(|id| after.is_none_or(|a| id.as_str() > a));
+        Ok(ids.take(limit).cloned().collect())
+    }
+
+    async fn add_manifest(&self, manifest_id: &str, chunks: &[String]) -> Result<()> {
+        self.lock().refs.insert(manifest_id.to_string(), chunks.to_vec());
+        Ok(())
+    }
+
+    async fn gc(&self, retain: &[String]) -> Result<GcStats> {
+        let mut inner = self.lock();
+        let keep = inner.referenced(retain)?;
+        inner.refs.retain(|id, _| retain.contains(id));
+
+        let mut stats = GcStats::default();
+        inner.chunks.retain(|id, chunk| {
+            if keep.contains(id) {
+                return true;
+            }
+            stats.chunks += 1;
+            stats.bytes += chunk.data.len() as u64;
+            false
+        });
+        Ok(stats)
+    }
+
+    async fn evict(&self, max_bytes: u64, retain: &[String]) -> Result<GcStats> {
+        let mut inner = self.lock();
+        let keep = inner.referenced(retain)?;
+        let mut total: u64 = inner.chunks.values().map(|c| c.data.len() as u64).sum();
+        let mut candidates: Vec<_> = inner.chunks.iter()
+            .filter(|(id, _)| !keep.contains(*id))
+            .map(|(id, c)| (c.used, id.clone()))
+            .collect();
+        candidates.sort();
+
+        let mut stats = GcStats::default();
+        for (_, id) in candidates {
+            if total <= max_bytes {
+                break;
+            }
+            let size = inner.chunks.remove(&id).expect("listed above").data.len() as u64;
+            total -= size;
+            stats.chunks += 1;
+            stats.bytes += size;
+        }
+        Ok(stats)
+    }
+
+    async fn stats(&self) -> Result<StorageStats> {
+        let inner = self.lock();
+        Ok(StorageStats {
+            chunks: inner.chunks.len() as u64,
+            bytes: inner.chunks.values().map(|c| c.data.len() as u64).sum(),
+            manifests: inner.refs.len() as u64,
+            referenced_bytes: inner.refs.values().flatten()
+                .filter_map(|id| inner.chunks.get(id))
+                .map(|c| c.data.len() as u64)
+                .sum(),
+        })
+    }
+
+    async fn describe_chunks(&self, after: Option<&str>, limit: usize) -> Result<Vec<ChunkInfo>> {
+        let inner = self.lock();
+        let chunks = inner.chunks.iter().filter(|(id, _)| after.is_none_or(|a| id.as_str() > a));
+        Ok(chunks.take(limit).map(|(id, c)| ChunkInfo {
+            id: id.clone(),
+            size: c.data.len() as u64,
+            created: c.created,
+            manifests: inner.refs.values().filter(|ids| ids.contains(id)).count() as u64,
+        }).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_storage() -> Result<()> {
+        let storage = MemoryStorage::new();
+        let mut ids = Vec::new();
+        for i in 0..4u8 {
+            ids.push(storage.put_chunk(&[i; 100]).await?);
+        }
+        assert_eq!(storage.get_chunk(&ids[2]).await?, Some(vec![2; 100]));
+        assert_eq!(storage.get_chunk("missing").await?, None);
+        let mut sorted = ids.clone();
+        sorted.sort();
+        assert_eq!(storage.list_chunks(Some(&sorted[1]), 10).await?, &sorted[2..]);
+
+        // Chunk 0 is retained and chunk 2 was just read, so 1 and 3 go.
+        storage.add_manifest("kept", &ids[..1]).await?;
+        assert!(storage.gc(&["unknown".into()]).await.is_err());
+        assert_eq!(storage.evict(200, &["kept".into()]).await?, GcStats { chunks: 2, bytes: 200 });
+        assert!(storage.has_chunk(&ids[2]).await? && !storage.has_chunk(&ids[3]).await?);
+        assert_eq!(storage.gc(&["kept".into()]).await?, GcStats { chunks: 1, bytes: 100 });
+        assert_eq!(storage.stats().await?, StorageStats { chunks: 1, bytes: 100, manifests: 1, referenced_bytes: 100 });
+        assert!(storage.delete_chunk(&ids[0]).await?);
+        Ok(())
+    }
+}
@@ -0,0 +1,191 @@
+//! io_uring-backed whole-file reads and writes (Linux, `io-uring` feature).
+//!
+//! A dedicated thread owns the ring. Requests from async callers are queued
+//! over a channel, submitted in batches of up to `QUEUE_DEPTH`, and answered
+//! through oneshot channels, so one `io_uring_enter` covers many chunk reads
+//! or writes. Opening files still uses ordinary syscalls.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::sync::mpsc;
+
+use io_uring::{opcode, types, IoUring};
+use tokio::sync::oneshot;
+
+const QUEUE_DEPTH: usize = 64;
+
+enum Op {
+    Read(PathBuf),
+    Write(PathBuf, Vec<u8>),
+}
+
+struct Job {
+    op: Op,
+    reply: oneshot::Sender<io::Result<Option<Vec<u8>>>>,
+}
+
+/// Handle to the ring thread. Dropping the last clone stops the thread.
+#[derive(Clone)]
+pub(crate) struct UringFiles {
+    tx: mpsc::Sender<Job>,
+}
+
+impl UringFiles {
+    /// Start the ring thread. Fails if the kernel does not support io_uring
+    /// (or it is blocked, e.g. by a seccomp profile).
+    pub(crate) fn new() -> io::Result<Self> {
+        let ring = IoUring::new(QUEUE_DEPTH as u32)?;
+        let (tx, rx) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("storage-uring".into())
+            .spawn(move || run(ring, rx))?;
+        Ok(Self { tx })
+    }
+
+    /// Read a whole file; `None` if it does not exist.
+    pub(crate) async fn read(&self, path: PathBuf) -> io::Result<Option<Vec<u8>>> {
+        self.call(Op::Read(path)).await
+    }
+
+    pub(crate) async fn write(&self, path: PathBuf, data: Vec<u8>) -> io::Result<()> {
+        self.call(Op::Write(path, data)).await.map(|_| ())
+    }
+
+    async fn call(&self, op: Op) -> io::Result<Option<Vec<u8>>> {
+        let (reply, rx) = oneshot::channel();
+        self.tx
+            .send(Job { op, reply })
+            .map_err(|_| io::Error::other("uring thread stopped"))?;
+        rx.await.map_err(|_| io::Error::other("uring thread stopped"))?
+    }
+}
+
+/// A job whose file is open and whose buffer is ready for submission.
+struct Pending {
+    file: File,
+    buf: Vec<u8>,
+    is_read: bool,
+    reply: oneshot::Sender<io::Result<Option<Vec<u8>>>>,
+}
+
+fn run(mut ring: IoUring, rx: mpsc::Receiver<Job>) {
+    while let Ok(first) = rx.recv() {
+        let mut batch = vec![first];
+        while batch.len() < QUEUE_DEPTH {
+            match rx.try_recv() {
+                Ok(job) => batch.push(job),
+                Err(_) => break,
+            }
+        }
+
+        let mut pending: Vec<Option<Pending>> = Vec::with_capacity(batch.len());
+        for job in batch {
+            match prepare(job.op) {
+                Ok(Some((file, buf, is_read))) => {
+                    pending.push(Some(Pending { file, buf, is_read, reply: job.reply }));
+                }
+                Ok(None) => {
+                    let _ = job.reply.send(Ok(None));
+                }
+                Err(e) => {
+                    let _ = job.reply.send(Err(e));
+                }
+            }
+        }
+        if pending.is_empty() {
+            continue;
+        }
+
+        if let Err(e) = submit(&mut ring, &mut pending) {
+            for p in pending.into_iter().flatten() {
+                let _ = p.reply.send(Err(io::Error::new(e.kind(), e.to_string())));
+            }
+        }
+    }
+}
+
+/// Open the file and size the buffer. `Ok(None)` means a read of a missing
+/// file.
+fn prepare(op: Op) -> io::Result<Option<(File, Vec<u8>, bool)>> {
+    match op {
+        Op::Read(path) => {
+            let file = match File::open(&path) {
+                Ok(f) => f,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            let len = file.metadata()?.len() as usize;
+            Ok(Some((file, vec![0u8; len], true)))
+        }
+        Op::Write(path, data) => {
+            let file = OpenOptions::new().write(true).create(true).truncate(true).open(&path)?;
+            Ok(Some((file, data, false)))
+        }
+    }
+}
+
+fn submit(ring: &mut IoUring, pending: &mut [Option<Pending>]) -> io::Result<()> {
+    for (i, p) in pending.iter_mut().enumerate() {
+        let p = p.as_mut().expect("slot filled");
+        let fd = types::Fd(p.file.as_raw_fd());
+        let entry = if p.is_read {
+            opcode::Read::new(fd, p.buf.as_mut_ptr(), p.buf.len() as u32).offset(0).build()
+        } else {
+            opcode::Write::new(fd, p.buf.as_ptr(), p.buf.len() as u32).offset(0).build()
+        };
+        // SAFETY: the buffer and file stay alive in `pending` until the
+        // matching completion has been reaped below.
+        unsafe {
+            ring.submission()
+                .push(&entry.user_data(i as u64))
+                .map_err(|_| io::Error::other("submission queue full"))?;
+        }
+    }
+
+    let mut remaining = pending.len();
+    ring.submit_and_wait(remaining)?;
+    while remaining > 0 {
+        let completed: Vec<(usize, i32)> = ring
+            .completion()
+            .map(|cqe| (cqe.user_data() as usize, cqe.result()))
+            .collect();
+        if completed.is_empty() {
+            ring.submit_and_wait(1)?;
+            continue;
+        }
+        for (i, res) in completed {
+            remaining -= 1;
+            if let Some(p) = pending[i].take() {
+                finish(p, res);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Deliver a completion. Short transfers are rare for regular files; the
+/// remainder is finished with plain positional IO.
+fn finish(mut p: Pending, res: i32) {
+    use std::os::unix::fs::FileExt;
+
+    let result = if res < 0 {
+        Err(io::Error::from_raw_os_error(-res))
+    } else {
+        let done = res as usize;
+        if p.is_read {
+            if done < p.buf.len() {
+                p.file.read_exact_at(&mut p.buf[done..], done as u64)
+                    .map(|_| Some(std::mem::take(&mut p.buf)))
+            } else {
+                Ok(Some(std::mem::take(&mut p.buf)))
+            }
+        } else if done < p.buf.len() {
+            p.file.write_all_at(&p.buf[done..], done as u64).map(|_| None)
+        } else {
+            Ok(None)
+        }
+    };
+    let _ = p.reply.send(result);
+}
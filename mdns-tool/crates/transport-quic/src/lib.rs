@@ -1,10 +1,29 @@
-//! QUIC transport layer (placeholder for future implementation)
+//! QUIC transport layer built on quinn.
 //!
-//! This module will provide QUIC-based transport using quinn or similar.
-//! For now, we use TCP as the primary transport.
+//! Each endpoint presents a self-signed Ed25519 certificate generated from
+//! the device identity key, so the TLS layer is bound to the same key the
+//! OpenShare handshake signs with. Certificates are not checked against a
+//! CA: both sides accept any well-formed self-signed Ed25519 certificate,
+//! optionally pinned to an expected public key, and expose the peer key via
+//! [`QuicConnection::peer_public_key`].
+//!
+//! A [`QuicConnection`] wraps one bidirectional stream and implements
+//! `AsyncRead + AsyncWrite`, so it can be passed straight to
+//! `Client::send_manifest_over` / `accept_and_receive`.
 
-use tokio::io::{AsyncRead, AsyncWrite};
+use anyhow::{Context, Result};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::{DigitallySignedStruct, DistinguishedName, SignatureScheme};
+use std::io;
+use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 /// A trait object combining AsyncRead + AsyncWrite + Unpin + Send
 /// We use a custom trait to avoid the E0225 error with multiple non-auto traits
@@ -16,21 +35,329 @@ impl<T> StreamTrait for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
 /// Type alias for dynamic stream (avoids trait object issues)
 pub type DynStream = Pin<Box<dyn StreamTrait>>;
 
-/// Placeholder for QUIC connection
+/// SNI name used for all connections; peers are identified by key, not name.
+const SERVER_NAME: &str = "openshare";
+
+/// ALPN protocol identifier.
+const ALPN: &[u8] = b"openshare/1";
+
+/// Keeps connections alive while a transfer is paused, well inside quinn's
+/// default 30 second idle timeout.
+const KEEP_ALIVE: Duration = Duration::from_secs(10);
+
+/// DER prefix of a PKCS#8 v1 Ed25519 private key (RFC 8410).
+const ED25519_PKCS8_PREFIX: [u8; 16] = [
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+
+/// DER prefix of an Ed25519 SubjectPublicKeyInfo.
+const ED25519_SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+/// Listening QUIC endpoint.
+pub struct QuicListener {
+    endpoint: quinn::Endpoint,
+}
+
+impl QuicListener {
+    /// Bind a server endpoint presenting a certificate for `secret_key`
+    /// (the 32-byte Ed25519 identity secret).
+    pub fn bind(addr: SocketAddr, secret_key: &[u8; 32]) -> Result<Self> {
+        let socket = std::net::UdpSocket::bind(addr)
+            .with_context(|| format!("Failed to bind QUIC endpoint on {}", addr))?;
+        Self::from_socket(socket, secret_key)
+    }
+
+    /// Like [`bind`](Self::bind), on a socket the caller set up, e.g. a
+    /// dual-stack one.
+    pub fn from_socket(socket: std::net::UdpSocket, secret_key: &[u8; 32]) -> Result<Self> {
+        let (cert, key) = self_signed_cert(secret_key)?;
+        let provider = provider();
+
+        let mut tls = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_client_cert_verifier(Arc::new(PeerKeyVerifier::new(provider, None)))
+            .with_single_cert(vec![cert], key)?;
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+
+        let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(tls)?;
+        let server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+        let runtime = quinn::default_runtime().context("No async runtime for QUIC")?;
+        let endpoint = quinn::Endpoint::new(quinn::EndpointConfig::default(), Some(server_config), socket, runtime)
+            .context("Failed to start QUIC endpoint")?;
+        Ok(Self { endpoint })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.endpoint.local_addr()?)
+    }
+
+    /// Wait for the next connection and its first bidirectional stream.
+    pub async fn accept(&self) -> Result<QuicConnection> {
+        let incoming = self.endpoint.accept().await
+            .ok_or_else(|| anyhow::anyhow!("QUIC endpoint closed"))?;
+        let conn = incoming.await.context("QUIC handshake failed")?;
+        let (send, recv) = conn.accept_bi().await.context("Failed to accept stream")?;
+        tracing::debug!("Accepted QUIC connection from {}", conn.remote_address());
+        Ok(QuicConnection { conn, send, recv })
+    }
+}
+
+/// One QUIC connection carrying a single bidirectional stream.
 pub struct QuicConnection {
-    // Future: quinn connection
+    conn: quinn::Connection,
+    send: quinn::SendStream,
+    recv: quinn::RecvStream,
 }
 
 impl QuicConnection {
-    /// Create a new QUIC connection (not yet implemented)
-    pub async fn connect(_addr: &str) -> anyhow::Result<Self> {
-        anyhow::bail!("QUIC transport not yet implemented")
+    /// Connect to `addr`, authenticating with `secret_key`. If
+    /// `expected_peer` is set, the peer certificate must carry that key.
+    pub async fn connect(
+        addr: SocketAddr,
+        secret_key: &[u8; 32],
+        expected_peer: Option<[u8; 32]>,
+    ) -> Result<Self> {
+        let (cert, key) = self_signed_cert(secret_key)?;
+        let provider = provider();
+
+        let mut tls = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PeerKeyVerifier::new(provider, expected_peer)))
+            .with_client_auth_cert(vec![cert], key)?;
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+
+        let crypto = quinn::crypto::rustls::QuicClientConfig::try_from(tls)?;
+        let bind: SocketAddr = if addr.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" }.parse()?;
+        let mut endpoint = quinn::Endpoint::client(bind)?;
+        let mut transport = quinn::TransportConfig::default();
+        transport.keep_alive_interval(Some(KEEP_ALIVE));
+        let mut config = quinn::ClientConfig::new(Arc::new(crypto));
+        config.transport_config(Arc::new(transport));
+        endpoint.set_default_client_config(config);
+
+        let conn = endpoint.connect(addr, SERVER_NAME)?.await
+            .with_context(|| format!("QUIC connect to {} failed", addr))?;
+        let (send, recv) = conn.open_bi().await.context("Failed to open stream")?;
+        Ok(Self { conn, send, recv })
+    }
+
+    pub fn remote_address(&self) -> SocketAddr {
+        self.conn.remote_address()
+    }
+
+    /// Ed25519 public key from the peer's certificate.
+    pub fn peer_public_key(&self) -> Option<[u8; 32]> {
+        let identity = self.conn.peer_identity()?;
+        let certs = identity.downcast::<Vec<CertificateDer<'static>>>().ok()?;
+        certs.first().and_then(|c| ed25519_key_from_cert(c))
+    }
+
+    /// Finish the send side and close the connection gracefully.
+    pub async fn finish(mut self) -> Result<()> {
+        self.send.finish()?;
+        let _ = self.send.stopped().await;
+        self.conn.close(0u32.into(), b"done");
+        Ok(())
+    }
+}
+
+impl AsyncRead for QuicConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.send), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_flush(Pin::new(&mut self.send), cx)
     }
 
-    /// Accept a QUIC connection (not yet implemented)
-    pub async fn accept() -> anyhow::Result<Self> {
-        anyhow::bail!("QUIC transport not yet implemented")
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_shutdown(Pin::new(&mut self.send), cx)
     }
 }
 
-// Future: Implement AsyncRead and AsyncWrite for QuicConnection
\ No newline at end of file
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Build a self-signed certificate whose key is the identity key.
+fn self_signed_cert(secret_key: &[u8; 32]) -> Result<(CertificateDer<'static>, PrivateKeyDer<'static>)> {
+    let mut pkcs8 = ED25519_PKCS8_PREFIX.to_vec();
+    pkcs8.extend_from_slice(secret_key);
+
+    let key_pair = rcgen::KeyPair::try_from(pkcs8.as_slice())
+        .context("Invalid identity key for certificate")?;
+    let cert = rcgen::CertificateParams::new(vec![SERVER_NAME.to_string()])?
+        .self_signed(&key_pair)?;
+
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(pkcs8));
+    Ok((cert.der().clone(), key))
+}
+
+/// Locate the Ed25519 SPKI in a DER certificate and return the raw key.
+fn ed25519_key_from_cert(cert: &[u8]) -> Option<[u8; 32]> {
+    let pos = cert.windows(ED25519_SPKI_PREFIX.len()).position(|w| w == ED25519_SPKI_PREFIX)?;
+    let start = pos + ED25519_SPKI_PREFIX.len();
+    cert.get(start..start + 32)?.try_into().ok()
+}
+
+/// Accepts self-signed Ed25519 certificates, optionally pinned to a key.
+/// Handshake signatures are still verified against the presented cert.
+#[derive(Debug)]
+struct PeerKeyVerifier {
+    provider: Arc<CryptoProvider>,
+    expected: Option<[u8; 32]>,
+}
+
+impl PeerKeyVerifier {
+    fn new(provider: Arc<CryptoProvider>, expected: Option<[u8; 32]>) -> Self {
+        Self { provider, expected }
+    }
+
+    fn check(&self, cert: &CertificateDer<'_>) -> Result<(), rustls::Error> {
+        let key = ed25519_key_from_cert(cert)
+            .ok_or(rustls::Error::InvalidCertificate(rustls::CertificateError::BadEncoding))?;
+        match self.expected {
+            Some(expected) if expected != key => Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl ServerCertVerifier for PeerKeyVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.check(end_entity)?;
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        vec![SignatureScheme::ED25519]
+    }
+}
+
+impl ClientCertVerifier for PeerKeyVerifier {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.check(end_entity)?;
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        vec![SignatureScheme::ED25519]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_quic_roundtrip_exposes_peer_keys() -> Result<()> {
+        let server_key = [3u8; 32];
+        let client_key = [4u8; 32];
+
+        let listener = QuicListener::bind("127.0.0.1:0".parse()?, &server_key)?;
+        let addr = listener.local_addr()?;
+
+        let server = tokio::spawn(async move {
+            let mut conn = listener.accept().await?;
+            let mut buf = [0u8; 5];
+            conn.read_exact(&mut buf).await?;
+            conn.write_all(&buf).await?;
+            conn.flush().await?;
+            let peer = conn.peer_public_key();
+            conn.finish().await?;
+            anyhow::Ok((buf, peer))
+        });
+
+        let mut conn = QuicConnection::connect(addr, &client_key, None).await?;
+        conn.write_all(b"hello").await?;
+        conn.flush().await?;
+        let mut echo = [0u8; 5];
+        conn.read_exact(&mut echo).await?;
+        assert_eq!(&echo, b"hello");
+        assert!(conn.peer_public_key().is_some());
+
+        let (received, client_seen) = server.await??;
+        assert_eq!(&received, b"hello");
+        assert!(client_seen.is_some());
+        assert_ne!(client_seen, conn.peer_public_key());
+
+        // Pinning to the wrong key must fail the TLS handshake.
+        let listener = QuicListener::bind("127.0.0.1:0".parse()?, &server_key)?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { let _ = listener.accept().await; });
+        assert!(QuicConnection::connect(addr, &client_key, Some([0u8; 32])).await.is_err());
+        Ok(())
+    }
+}
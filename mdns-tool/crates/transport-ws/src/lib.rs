@@ -0,0 +1,175 @@
+//! WebSocket transport layer built on tokio-tungstenite.
+//!
+//! For networks that only let HTTP through, and for relays that pass
+//! connections on between peers that cannot reach each other. A
+//! [`WsConnection`] carries the byte stream of a session in binary
+//! messages and implements `AsyncRead + AsyncWrite`, so it can be passed
+//! straight to `Client::send_manifest_over` / `accept_and_receive` like a
+//! TCP stream. Messages do not line up with protocol frames; a relay must
+//! pass them on unchanged and in order.
+//!
+//! The WebSocket layer adds no authentication: peers authenticate each
+//! other in the OpenShare handshake run over it, as over TCP. `wss://`
+//! URLs are dialed over TLS checked against the Mozilla root store, which
+//! is what a relay behind a public certificate needs.
+
+use anyhow::{Context, Result};
+use futures_util::{Sink, Stream};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{ready, Context as TaskContext, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio_tungstenite::tungstenite::{Bytes, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+/// Listening WebSocket endpoint, answering upgrades on any path.
+pub struct WsListener {
+    listener: TcpListener,
+}
+
+impl WsListener {
+    pub async fn bind(addr: impl ToSocketAddrs) -> Result<Self> {
+        let listener = TcpListener::bind(addr).await.context("Failed to bind WebSocket listener")?;
+        Ok(Self { listener })
+    }
+
+    /// Like [`bind`](Self::bind), on a listener the caller set up, e.g. a
+    /// dual-stack one.
+    pub fn from_listener(listener: TcpListener) -> Self {
+        Self { listener }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Accept the next connection and its upgrade to a WebSocket. Upgrade
+    /// with [`WsConnection::accept`] instead where one slow client must
+    /// not hold up the others.
+    pub async fn accept(&self) -> Result<(WsConnection<TcpStream>, SocketAddr)> {
+        let (stream, remote) = self.listener.accept().await?;
+        Ok((WsConnection::accept(stream).await?, remote))
+    }
+}
+
+/// One session's byte stream over a WebSocket.
+pub struct WsConnection<S = MaybeTlsStream<TcpStream>> {
+    ws: WebSocketStream<S>,
+    /// The rest of the last message read
+    pending: Bytes,
+}
+
+impl WsConnection {
+    /// Connect to `url`, a `ws://` or `wss://` URL, e.g. a relay's.
+    pub async fn connect(url: &str) -> Result<Self> {
+        let (ws, _) = tokio_tungstenite::connect_async(url).await
+            .with_context(|| format!("Failed to connect to {}", url))?;
+        Ok(Self::new(ws))
+    }
+}
+
+impl<S> WsConnection<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Answer the WebSocket upgrade of a connection accepted elsewhere.
+    pub async fn accept(stream: S) -> Result<Self> {
+        let ws = tokio_tungstenite::accept_async(stream).await.context("WebSocket upgrade failed")?;
+        Ok(Self::new(ws))
+    }
+
+    fn new(ws: WebSocketStream<S>) -> Self {
+        Self { ws, pending: Bytes::new() }
+    }
+
+    /// Close the WebSocket cleanly after everything written is sent.
+    pub async fn finish(mut self) -> Result<()> {
+        self.ws.close(None).await?;
+        Ok(())
+    }
+}
+
+impl<S> AsyncRead for WsConnection<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        // A message written while the socket was full is only sent when
+        // the sink is polled again, and the peer may be waiting for it.
+        if let Poll::Ready(Err(e)) = Pin::new(&mut self.ws).poll_flush(cx) {
+            return Poll::Ready(Err(io::Error::other(e)));
+        }
+        while self.pending.is_empty() {
+            match ready!(Pin::new(&mut self.ws).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => self.pending = data,
+                // Pings are answered by tungstenite
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => {}
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, "Text message on a binary stream")));
+                }
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Err(e)) => return Poll::Ready(Err(io::Error::other(e))),
+            }
+        }
+        let n = self.pending.len().min(buf.remaining());
+        let data = self.pending.split_to(n);
+        buf.put_slice(&data);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for WsConnection<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let ws = Pin::new(&mut self.ws);
+        ready!(ws.poll_ready(cx)).map_err(io::Error::other)?;
+        Pin::new(&mut self.ws).start_send(Message::binary(buf.to_vec())).map_err(io::Error::other)?;
+        // Send it now rather than when the write buffer fills up: the peer
+        // may not answer before it has this message.
+        if let Poll::Ready(Err(e)) = Pin::new(&mut self.ws).poll_flush(cx) {
+            return Poll::Ready(Err(io::Error::other(e)));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.ws).poll_flush(cx).map_err(io::Error::other)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.ws).poll_close(cx).map_err(io::Error::other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_ws_roundtrip() -> Result<()> {
+        let listener = WsListener::bind("127.0.0.1:0").await?;
+        let url = format!("ws://{}/relay", listener.local_addr()?);
+
+        let server = tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await?;
+            let mut request = [0u8; 5];
+            conn.read_exact(&mut request).await?;
+            // Larger than one read, so it is split across them.
+            let reply = vec![request[0]; 200_000];
+            conn.write_all(&reply).await?;
+            conn.finish().await
+        });
+
+        let mut client = WsConnection::connect(&url).await?;
+        client.write_all(b"hello").await?;
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await?;
+        assert_eq!(reply, vec![b'h'; 200_000]);
+        server.await?
+    }
+}